@@ -0,0 +1,46 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    #[cfg(feature = "grpc")]
+    compile_protos();
+
+    #[cfg(feature = "ffi")]
+    generate_c_header();
+}
+
+/// Generate the C header of the `extern "C"` API in src/ffi.rs into
+/// `OUT_DIR`, which a test compares with the committed
+/// include/dangerzone_rs.h, since build scripts must not write to the source
+/// tree
+#[cfg(feature = "ffi")]
+fn generate_c_header() {
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR is set");
+    let out_dir = std::env::var("OUT_DIR").expect("OUT_DIR is set");
+    let config = cbindgen::Config::from_file(format!("{crate_dir}/cbindgen.toml"))
+        .expect("Failed to read cbindgen.toml");
+
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(format!("{crate_dir}/src/ffi.rs"))
+        .generate()
+        .expect("Failed to generate C bindings")
+        .write_to_file(format!("{out_dir}/dangerzone_rs.h"));
+}
+
+/// Generate the gRPC server and client stubs from proto/dangerzone.proto
+#[cfg(feature = "grpc")]
+fn compile_protos() {
+    println!("cargo:rerun-if-changed=proto/dangerzone.proto");
+
+    let protoc = protoc_bin_vendored::protoc_bin_path().expect("vendored protoc is available");
+    std::env::set_var("PROTOC", protoc);
+
+    tonic_prost_build::configure()
+        .build_server(true)
+        .build_client(true)
+        .compile_protos(&["proto/dangerzone.proto"], &["proto"])
+        .expect("Failed to compile proto/dangerzone.proto");
+}
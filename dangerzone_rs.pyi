@@ -0,0 +1,118 @@
+"""Type stubs for the dangerzone_rs extension module
+
+Written by hand from `src/python.rs`: `tests/python/test_stubs.py` checks that
+they have the same functions, classes, members and signatures as the built
+module, and mypy checks `tests/python` against them.
+"""
+
+import os
+from typing import Iterator, Literal, Optional, Sequence, Union
+
+import PIL.Image
+
+_Path = Union[str, os.PathLike[str]]
+
+class PageData:
+    """A page of pixels, exposing them through the buffer protocol"""
+
+    @property
+    def width(self) -> int: ...
+    @property
+    def height(self) -> int: ...
+    @property
+    def mode(self) -> Literal["RGB", "L", "RGBA"]:
+        """Pixel format, named like Pillow's modes ("L" is grayscale)"""
+    @property
+    def rotation(self) -> int:
+        """Clockwise rotation viewers should apply to the page, in degrees"""
+    @property
+    def size_pts(self) -> Optional[tuple[float, float]]:
+        """Size of the original page in points, if the converter sent it"""
+    @property
+    def blank(self) -> bool:
+        """Whether the converter found nothing on the page"""
+    @property
+    def failed(self) -> bool:
+        """Whether the converter failed to convert the page, whose pixels are
+        then those of a placeholder"""
+    @property
+    def pixels(self) -> bytes:
+        """Raw pixels, row by row, with 1 to 4 bytes per pixel depending on
+        `mode`"""
+    def __init__(
+        self,
+        width: int,
+        height: int,
+        pixels: bytes,
+        mode: Literal["RGB", "L", "RGBA"] = "RGB",
+    ) -> None: ...
+    def to_pil(self) -> PIL.Image.Image:
+        """Return the page as a `PIL.Image.Image` (requires Pillow)"""
+    def __buffer__(self, flags: int, /) -> memoryview: ...
+    def __release_buffer__(self, buffer: memoryview, /) -> None: ...
+
+class PageIterator(Iterator[PageData]):
+    """Iterator over the pages of a document being converted"""
+
+    def __iter__(self) -> PageIterator: ...
+    def __next__(self) -> PageData: ...
+    def close(self) -> None:
+        """Stop the conversion, killing the container if it is still running"""
+
+class BatchResult:
+    """Outcome of converting one document of a `convert_batch`"""
+
+    @property
+    def input_path(self) -> str: ...
+    @property
+    def output_path(self) -> str: ...
+    @property
+    def success(self) -> bool: ...
+    @property
+    def error(self) -> Optional[str]:
+        """Why the conversion failed, `None` on success"""
+    @property
+    def duration(self) -> float:
+        """Time taken by the conversion, in seconds"""
+    @property
+    def warnings(self) -> list[str]:
+        """What went differently than asked during a successful conversion"""
+
+def parse_pixel_data(data: bytes) -> list[PageData]: ...
+def convert_doc_to_pixels(input_path: _Path) -> bytes: ...
+def pixels_to_pdf(pages: Sequence[PageData], output_path: _Path) -> None: ...
+def convert_document(
+    input_path: _Path,
+    output_path: _Path,
+    ocr: bool = False,
+    *,
+    ocr_lang: Optional[str] = None,
+    dpi: Optional[float] = None,
+    timeout: Optional[float] = None,
+    runtime: Optional[str] = None,
+) -> list[str]: ...
+def iter_pages(
+    input_path: _Path,
+    *,
+    timeout: Optional[float] = None,
+    runtime: Optional[str] = None,
+) -> PageIterator: ...
+def convert_batch(
+    inputs: Sequence[_Path],
+    output_dir: _Path,
+    jobs: Optional[int] = None,
+    *,
+    ocr: bool = False,
+    ocr_lang: Optional[str] = None,
+    dpi: Optional[float] = None,
+    timeout: Optional[float] = None,
+    runtime: Optional[str] = None,
+    session_max_documents: Optional[int] = None,
+    output_template: Optional[str] = None,
+) -> list[BatchResult]: ...
+def warmup(
+    *,
+    timeout: Optional[float] = None,
+    runtime: Optional[str] = None,
+) -> None: ...
+def apply_ocr_fn(input_pdf: _Path, output_pdf: _Path) -> None: ...
@@ -0,0 +1,98 @@
+/* Generated by cbindgen from src/ffi.rs, do not edit */
+
+#ifndef DANGERZONE_RS_H
+#define DANGERZONE_RS_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Result of a call into the C API
+ */
+typedef enum DzStatus {
+  DZ_STATUS_OK = 0,
+  /**
+   * A pointer argument was null or a path was not valid UTF-8
+   */
+  DZ_STATUS_INVALID_ARGUMENT = 1,
+  /**
+   * The conversion failed, see the error buffer for details
+   */
+  DZ_STATUS_ERROR = 2,
+  /**
+   * The progress callback asked for the conversion to stop
+   */
+  DZ_STATUS_CANCELLED = 3,
+} DzStatus;
+
+/**
+ * Stage reported to a [`DzProgressCallback`]
+ */
+typedef enum DzStage {
+  DZ_STAGE_CONVERTING_TO_PIXELS = 0,
+  DZ_STAGE_PIXELS_RECEIVED = 1,
+  DZ_STAGE_WRITING_PAGE = 2,
+  DZ_STAGE_APPLYING_OCR = 3,
+  DZ_STAGE_DONE = 4,
+} DzStage;
+
+/**
+ * Options for [`dz_convert_document`]
+ */
+typedef struct DzOptions {
+  /**
+   * Add a text layer to the safe PDF
+   */
+  bool ocr;
+} DzOptions;
+
+/**
+ * Called for each conversion stage. `page` is 1-based and only set for
+ * `WritingPage`, `total_pages` is 0 until the page count is known. Return
+ * `false` to cancel the conversion.
+ */
+typedef bool (*DzProgressCallback)(void *user_data,
+                                   enum DzStage stage,
+                                   uint32_t page,
+                                   uint32_t total_pages);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Version of the library, as a static NUL-terminated string
+ */
+const char *dz_version(void);
+
+/**
+ * Convert the document at `input_path` to a safe PDF at `output_path`
+ *
+ * Log messages are printed to stderr. `options` may be null to use the
+ * defaults. `progress` may be null;
+ * `user_data` is passed to it unchanged. On failure, a NUL-terminated error
+ * message is written to `error_buf` (truncated to `error_buf_len` bytes) if
+ * it is not null.
+ *
+ * # Safety
+ *
+ * `input_path` and `output_path` must be valid NUL-terminated strings,
+ * `options` must be null or point to a valid `DzOptions`, and `error_buf`
+ * must be null or point to at least `error_buf_len` writable bytes.
+ */
+enum DzStatus dz_convert_document(const char *input_path,
+                                  const char *output_path,
+                                  const struct DzOptions *options,
+                                  DzProgressCallback progress,
+                                  void *user_data,
+                                  char *error_buf,
+                                  size_t error_buf_len);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* DANGERZONE_RS_H */
@@ -0,0 +1,504 @@
+//! Archives (.zip and .tar) converted entry by entry
+//!
+//! Entries are extracted on the host into a private temporary directory,
+//! under names of their own rather than the paths the archive gives them, so
+//! that no entry can be written elsewhere. Archives with too many entries or
+//! too much data once decompressed are rejected, whatever sizes their
+//! headers claim. Each entry of a supported document type is then converted
+//! in the sandbox to a safe PDF of its own, and a manifest records what
+//! became of every entry.
+
+use crate::{
+    check_options, conversion_temp_dir, convert_batch, replace_control_chars, safe_file_name,
+    BatchResult, CancellationToken, ConversionOptions,
+};
+use anyhow::{Context, Result};
+use log::info;
+use serde_json::json;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// File name of the manifest, in the output directory
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+/// Extensions of the documents the converter supports
+const SUPPORTED_EXTENSIONS: &[&str] = &[
+    "pdf", "docx", "doc", "docm", "xlsx", "xls", "pptx", "ppt", "odt", "ods", "odp", "odg", "rtf",
+    "epub", "hwp", "hwpx", "jpg", "jpeg", "png", "gif", "bmp", "tif", "tiff", "pnm", "pbm", "ppm",
+    "svg",
+];
+
+/// Bounds on what an archive may expand to
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ArchiveLimits {
+    /// Most entries the archive may have, directories included
+    pub max_entries: usize,
+    /// Largest entry, once decompressed; larger entries are skipped
+    pub max_entry_bytes: u64,
+    /// Most data extracted from the archive, once decompressed
+    pub max_total_bytes: u64,
+}
+
+impl Default for ArchiveLimits {
+    fn default() -> Self {
+        ArchiveLimits {
+            max_entries: 1000,
+            max_entry_bytes: 256 << 20,
+            max_total_bytes: 1 << 30,
+        }
+    }
+}
+
+/// What became of one entry of an archive
+#[derive(Debug)]
+pub struct ArchiveEntry {
+    /// Path of the entry in the archive
+    pub name: String,
+    pub outcome: EntryOutcome,
+}
+
+#[derive(Debug)]
+pub enum EntryOutcome {
+    /// Not converted, for this reason
+    Skipped(String),
+    /// Converted, successfully or not
+    Converted(Box<BatchResult>),
+}
+
+/// Whether the file at `path` is an archive, from its extension
+pub fn is_archive(path: &str) -> bool {
+    archive_kind(path).is_some()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+fn archive_kind(path: &str) -> Option<ArchiveKind> {
+    let name = Path::new(path).file_name()?.to_str()?.to_ascii_lowercase();
+    if name.ends_with(".zip") {
+        Some(ArchiveKind::Zip)
+    } else if name.ends_with(".tar") {
+        Some(ArchiveKind::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(ArchiveKind::TarGz)
+    } else {
+        None
+    }
+}
+
+/// Convert each supported entry of the archive at `input_path` to a safe PDF
+/// in `output_dir`, as [`convert_batch`] does, and write a manifest of all
+/// its entries there, [`MANIFEST_NAME`]
+///
+/// Fails without converting anything if the archive exceeds `limits`, apart
+/// from entries larger than [`ArchiveLimits::max_entry_bytes`], which are
+/// skipped.
+pub fn convert_archive(
+    input_path: &str,
+    output_dir: &str,
+    jobs: usize,
+    options: &ConversionOptions,
+    limits: &ArchiveLimits,
+    cancel: &CancellationToken,
+) -> Result<Vec<ArchiveEntry>> {
+    check_options(options)?;
+    let input_path_sanitized = replace_control_chars(input_path, false);
+    let kind = archive_kind(input_path).context(format!(
+        "Not a .zip, .tar, .tar.gz or .tgz archive: '{input_path_sanitized}'"
+    ))?;
+    let file = File::open(input_path)
+        .context(format!("Failed to open archive '{input_path_sanitized}'"))?;
+    let temp_dir = conversion_temp_dir()?;
+    let mut extraction = Extraction {
+        dir: temp_dir.path(),
+        limits,
+        total_bytes: 0,
+        entries: Vec::new(),
+    };
+    match kind {
+        ArchiveKind::Zip => extraction.extract_zip(file),
+        ArchiveKind::Tar => extraction.extract_tar(BufReader::new(file)),
+        ArchiveKind::TarGz => {
+            extraction.extract_tar(flate2::read::GzDecoder::new(BufReader::new(file)))
+        }
+    }
+    .context(format!("Failed to extract '{input_path_sanitized}'"))?;
+
+    let paths: Vec<String> = extraction
+        .entries
+        .iter()
+        .filter_map(|(_, extracted)| extracted.as_ref().ok())
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    info!(
+        "Converting {} of the {} file(s) of {input_path_sanitized}",
+        paths.len(),
+        extraction.entries.len()
+    );
+    let mut results = convert_batch(&paths, output_dir, jobs, options, cancel)?.into_iter();
+    let entries: Vec<ArchiveEntry> = extraction
+        .entries
+        .into_iter()
+        .map(|(name, extracted)| {
+            let outcome = match extracted {
+                Err(reason) => EntryOutcome::Skipped(reason),
+                Ok(_) => {
+                    let mut result = results.next().expect("a result for each converted entry");
+                    result.input_path = name.clone();
+                    EntryOutcome::Converted(Box::new(result))
+                }
+            };
+            ArchiveEntry { name, outcome }
+        })
+        .collect();
+    write_manifest(input_path, output_dir, &entries)?;
+    Ok(entries)
+}
+
+/// Entries of an archive being extracted to `dir`
+struct Extraction<'a> {
+    dir: &'a Path,
+    limits: &'a ArchiveLimits,
+    total_bytes: u64,
+    /// Name of each file of the archive, with the path it was extracted to,
+    /// or the reason it was skipped
+    entries: Vec<(String, Result<PathBuf, String>)>,
+}
+
+impl Extraction<'_> {
+    fn extract_zip<R: Read + Seek>(&mut self, reader: R) -> Result<()> {
+        let mut archive = zip::ZipArchive::new(reader).context("Not a valid zip archive")?;
+        self.check_entry_count(archive.len())?;
+        for index in 0..archive.len() {
+            let name = archive
+                .name_for_index(index)
+                .unwrap_or_default()
+                .to_string();
+            let mut entry = match archive.by_index(index) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    self.skip(name, format!("unreadable: {e}"));
+                    continue;
+                }
+            };
+            if entry.is_dir() {
+                continue;
+            }
+            if entry.is_symlink() {
+                self.skip(name, "symbolic link".to_string());
+                continue;
+            }
+            self.extract(name, &mut entry)?;
+        }
+        Ok(())
+    }
+
+    fn extract_tar<R: Read>(&mut self, reader: R) -> Result<()> {
+        let mut archive = tar::Archive::new(reader);
+        let mut count = 0;
+        for entry in archive.entries().context("Not a valid tar archive")? {
+            let mut entry = entry.context("Not a valid tar archive")?;
+            count += 1;
+            self.check_entry_count(count)?;
+            let name = entry.path().map_or_else(
+                |_| String::from_utf8_lossy(&entry.path_bytes()).into_owned(),
+                |path| path.to_string_lossy().into_owned(),
+            );
+            let entry_type = entry.header().entry_type();
+            if entry_type.is_dir() {
+                continue;
+            }
+            if !entry_type.is_file() {
+                self.skip(name, "not a regular file".to_string());
+                continue;
+            }
+            self.extract(name, &mut entry)?;
+        }
+        Ok(())
+    }
+
+    fn check_entry_count(&self, count: usize) -> Result<()> {
+        if count > self.limits.max_entries {
+            anyhow::bail!(
+                "The archive has more than {} entries",
+                self.limits.max_entries
+            );
+        }
+        Ok(())
+    }
+
+    fn skip(&mut self, name: String, reason: String) {
+        info!(
+            "Skipping '{name_sanitized}': {reason_sanitized}",
+            name_sanitized = replace_control_chars(&name, false),
+            reason_sanitized = replace_control_chars(&reason, false)
+        );
+        self.entries.push((name, Err(reason)));
+    }
+
+    /// Write the file `name` of the archive to a directory of its own, if it
+    /// is a supported document within the limits
+    fn extract(&mut self, name: String, reader: &mut dyn Read) -> Result<()> {
+        let file_name = safe_file_name(Some(&name), "document");
+        let supported = Path::new(&file_name)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| {
+                SUPPORTED_EXTENSIONS
+                    .iter()
+                    .any(|supported| extension.eq_ignore_ascii_case(supported))
+            });
+        if !supported {
+            self.skip(name, "unsupported file type".to_string());
+            return Ok(());
+        }
+
+        let entry_dir = self.dir.join(self.entries.len().to_string());
+        std::fs::create_dir(&entry_dir).context("Failed to create directory for entry")?;
+        let path = entry_dir.join(file_name);
+        let mut file = File::create(&path).context("Failed to create file for entry")?;
+        let limit = self
+            .limits
+            .max_entry_bytes
+            .min(self.limits.max_total_bytes - self.total_bytes);
+        // Sizes in headers can't be trusted, so the data itself is counted
+        let copied = match std::io::copy(&mut reader.take(limit + 1), &mut file) {
+            Ok(copied) => copied,
+            Err(e) => {
+                drop(file);
+                std::fs::remove_file(&path).context("Failed to remove entry")?;
+                self.skip(name, format!("unreadable: {e}"));
+                return Ok(());
+            }
+        };
+        file.flush().context("Failed to write entry")?;
+        drop(file);
+        if copied > limit {
+            std::fs::remove_file(&path).context("Failed to remove entry")?;
+            if limit < self.limits.max_entry_bytes {
+                anyhow::bail!(
+                    "The archive expands to more than {} bytes",
+                    self.limits.max_total_bytes
+                );
+            }
+            self.skip(
+                name,
+                format!("larger than {} bytes", self.limits.max_entry_bytes),
+            );
+            return Ok(());
+        }
+        self.total_bytes += copied;
+        self.entries.push((name, Ok(path)));
+        Ok(())
+    }
+}
+
+/// Write the manifest of the entries of the archive at `input_path` to
+/// `output_dir`
+fn write_manifest(input_path: &str, output_dir: &str, entries: &[ArchiveEntry]) -> Result<()> {
+    let entries: Vec<serde_json::Value> = entries
+        .iter()
+        .map(|entry| match &entry.outcome {
+            EntryOutcome::Skipped(reason) => {
+                json!({"name": entry.name, "status": "skipped", "reason": reason})
+            }
+            EntryOutcome::Converted(result) => {
+                let mut value = json!({
+                    "name": entry.name,
+                    "output": Path::new(&result.output_path).file_name().map(|name| name.to_string_lossy()),
+                    "duration_secs": result.duration.as_secs_f64(),
+                });
+                match &result.result {
+                    Ok(report) => {
+                        value["status"] = json!("converted");
+                        if let Some(reason) = &report.ocr_fallback {
+                            value["ocr_fallback"] = json!(reason);
+                        }
+                        if !report.warnings.is_empty() {
+                            let warnings: Vec<String> =
+                                report.warnings.iter().map(ToString::to_string).collect();
+                            value["warnings"] = json!(warnings);
+                        }
+                    }
+                    Err(e) => {
+                        value["status"] = json!("failed");
+                        value["error"] = json!(format!("{e:#}"));
+                    }
+                }
+                value
+            }
+        })
+        .collect();
+    let archive = Path::new(input_path)
+        .file_name()
+        .map(|name| name.to_string_lossy());
+    let manifest = json!({"archive": archive, "entries": entries});
+    let path = Path::new(output_dir).join(MANIFEST_NAME);
+    let mut file = File::create(&path).context("Failed to create the manifest")?;
+    serde_json::to_writer_pretty(&mut file, &manifest).context("Failed to write the manifest")?;
+    writeln!(file).context("Failed to write the manifest")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn zip(entries: &[(&str, &[u8])]) -> Cursor<Vec<u8>> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in entries {
+            if name.ends_with('/') {
+                writer
+                    .add_directory(*name, zip::write::SimpleFileOptions::default())
+                    .unwrap();
+                continue;
+            }
+            writer
+                .start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(data).unwrap();
+        }
+        let mut cursor = writer.finish().unwrap();
+        cursor.set_position(0);
+        cursor
+    }
+
+    fn extracted<'a>(extraction: &'a Extraction) -> Vec<(&'a str, Result<String, &'a str>)> {
+        extraction
+            .entries
+            .iter()
+            .map(|(name, extracted)| {
+                (
+                    name.as_str(),
+                    extracted
+                        .as_ref()
+                        .map(|path| std::fs::read_to_string(path).unwrap())
+                        .map_err(String::as_str),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_archive_kind() {
+        assert_eq!(archive_kind("a/Docs.ZIP"), Some(ArchiveKind::Zip));
+        assert_eq!(archive_kind("docs.tar"), Some(ArchiveKind::Tar));
+        assert_eq!(archive_kind("docs.tar.gz"), Some(ArchiveKind::TarGz));
+        assert_eq!(archive_kind("docs.tgz"), Some(ArchiveKind::TarGz));
+        assert_eq!(archive_kind("docs.gz"), None);
+        assert!(!is_archive("report.docx"));
+    }
+
+    #[test]
+    fn test_extract_zip() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let limits = ArchiveLimits {
+            max_entry_bytes: 8,
+            ..ArchiveLimits::default()
+        };
+        let mut extraction = Extraction {
+            dir: temp_dir.path(),
+            limits: &limits,
+            total_bytes: 0,
+            entries: Vec::new(),
+        };
+        extraction
+            .extract_zip(zip(&[
+                ("docs/", b""),
+                ("docs/report.pdf", b"%PDF-"),
+                ("../../evil.docx", b"PK"),
+                ("tool.exe", b"MZ"),
+                ("huge.png", b"0123456789"),
+            ]))
+            .unwrap();
+        assert_eq!(
+            extracted(&extraction),
+            [
+                ("docs/report.pdf", Ok("%PDF-".to_string())),
+                ("../../evil.docx", Ok("PK".to_string())),
+                ("tool.exe", Err("unsupported file type")),
+                ("huge.png", Err("larger than 8 bytes")),
+            ]
+        );
+        // Every entry is written inside the directory, under its own name
+        let Ok(path) = &extraction.entries[1].1 else {
+            panic!("evil.docx wasn't extracted");
+        };
+        assert_eq!(path, &temp_dir.path().join("1").join("evil.docx"));
+    }
+
+    #[test]
+    fn test_extract_limits() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let limits = ArchiveLimits {
+            max_entries: 2,
+            max_entry_bytes: 8,
+            max_total_bytes: 12,
+        };
+        let mut extraction = Extraction {
+            dir: temp_dir.path(),
+            limits: &limits,
+            total_bytes: 0,
+            entries: Vec::new(),
+        };
+        let e = extraction
+            .extract_zip(zip(&[("a.pdf", b""), ("b.pdf", b""), ("c.pdf", b"")]))
+            .unwrap_err();
+        assert_eq!(e.to_string(), "The archive has more than 2 entries");
+
+        let mut extraction = Extraction {
+            dir: temp_dir.path(),
+            limits: &limits,
+            total_bytes: 0,
+            entries: Vec::new(),
+        };
+        let e = extraction
+            .extract_zip(zip(&[("a.pdf", b"01234567"), ("b.pdf", b"01234567")]))
+            .unwrap_err();
+        assert_eq!(e.to_string(), "The archive expands to more than 12 bytes");
+    }
+
+    #[test]
+    fn test_extract_tar() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "scans/page.png", &b"image"[..])
+            .unwrap();
+        let mut link = tar::Header::new_gnu();
+        link.set_entry_type(tar::EntryType::Symlink);
+        link.set_size(0);
+        builder
+            .append_link(&mut link, "secret.pdf", "/etc/passwd")
+            .unwrap();
+        let data = builder.into_inner().unwrap();
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let limits = ArchiveLimits::default();
+        let mut extraction = Extraction {
+            dir: temp_dir.path(),
+            limits: &limits,
+            total_bytes: 0,
+            entries: Vec::new(),
+        };
+        extraction.extract_tar(&data[..]).unwrap();
+        assert_eq!(
+            extracted(&extraction),
+            [
+                ("scans/page.png", Ok("image".to_string())),
+                ("secret.pdf", Err("not a regular file")),
+            ]
+        );
+    }
+}
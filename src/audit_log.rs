@@ -0,0 +1,335 @@
+//! Hash-chained log of conversions, for proving documents were sanitized
+//!
+//! Each conversion appends one line of JSON to the log: when it ran, who ran
+//! it, the SHA-256 of the document and of the safe PDF, the converter image,
+//! whether it succeeded and the [`canary`] alerts its sandboxes raised. Each
+//! record also holds the hash of the record
+//! before it (`prev`) and its own hash (`hash`), the SHA-256 of the record
+//! serialized without that field. Editing, removing or reordering records
+//! breaks the chain, which [`verify_log`] checks; only the last records can
+//! be removed unnoticed, unless their hashes are kept elsewhere.
+
+use crate::{canary, image_digest, replace_control_chars, Runtime, UtcTime, IMAGE_NAME};
+use anyhow::{Context, Result};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// `prev` of the first record of a log
+const FIRST_PREV: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Longest record read back to continue the chain
+const MAX_RECORD_BYTES: u64 = 64 * 1024;
+
+/// A conversion, recorded in the log once it is over
+pub struct ConversionRecord {
+    input: String,
+    input_sha256: Option<String>,
+    output: String,
+    runtime: Runtime,
+    started: SystemTime,
+    /// Alerts raised before the conversion started
+    alerts_before: usize,
+}
+
+impl ConversionRecord {
+    /// Start recording the conversion of `input` to `output`, hashing the
+    /// input now, before the conversion reads it
+    pub fn start(input: &str, output: &str, runtime: Runtime) -> Self {
+        ConversionRecord {
+            input: absolute(input),
+            input_sha256: sha256_file(Path::new(input)).ok(),
+            output: absolute(output),
+            runtime,
+            started: SystemTime::now(),
+            alerts_before: canary::alert_count(),
+        }
+    }
+
+    /// Append the record of the conversion to the log at `log_path`, with
+    /// `error` if it failed, and the alerts raised since it started
+    pub fn finish(self, log_path: &Path, error: Option<&anyhow::Error>) -> Result<()> {
+        let result = if error.is_none() {
+            "success"
+        } else {
+            "failure"
+        };
+        self.append_to(log_path, result, error)
+    }
+
+    /// Append the record of a conversion that was skipped, since its output
+    /// already is the safe PDF of the same document, to the log at
+    /// `log_path`
+    pub fn skipped(self, log_path: &Path) -> Result<()> {
+        self.append_to(log_path, "skipped", None)
+    }
+
+    fn append_to(self, log_path: &Path, result: &str, error: Option<&anyhow::Error>) -> Result<()> {
+        let output_sha256 = match error {
+            None => sha256_file(Path::new(&self.output)).ok(),
+            Some(_) => None,
+        };
+        let alerts: Vec<String> = canary::alerts_since(self.alerts_before)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        let record = json!({
+            "time": rfc3339(self.started),
+            "user": user(),
+            "input": self.input,
+            "input_sha256": self.input_sha256,
+            "output": self.output,
+            "output_sha256": output_sha256,
+            "image": IMAGE_NAME,
+            "image_digest": image_digest(self.runtime),
+            "runtime": self.runtime.to_string(),
+            "result": result,
+            "error": error.map(|e| format!("{e:#}")),
+            "alerts": alerts,
+        });
+        let Value::Object(record) = record else {
+            unreachable!("records are objects");
+        };
+        append(log_path, record)
+    }
+}
+
+/// Append `record` to the log at `log_path`, chained to its last record
+///
+/// The log is locked while the record is appended, so that conversions
+/// running at the same time don't break the chain.
+fn append(log_path: &Path, mut record: Map<String, Value>) -> Result<()> {
+    let log_path_sanitized = replace_control_chars(&log_path.to_string_lossy(), false);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(log_path)
+        .with_context(|| format!("Failed to open the audit log '{log_path_sanitized}'"))?;
+    file.lock()
+        .with_context(|| format!("Failed to lock the audit log '{log_path_sanitized}'"))?;
+
+    let (seq, prev) = match last_record(&mut file)
+        .with_context(|| format!("Failed to read the audit log '{log_path_sanitized}'"))?
+    {
+        Some(last) => {
+            let seq = last.get("seq").and_then(Value::as_u64);
+            let hash = last.get("hash").and_then(Value::as_str);
+            let (Some(seq), Some(hash)) = (seq, hash) else {
+                anyhow::bail!("The last record of the audit log '{log_path_sanitized}' is invalid");
+            };
+            (seq + 1, hash.to_string())
+        }
+        None => (1, FIRST_PREV.to_string()),
+    };
+    record.insert("seq".to_string(), seq.into());
+    record.insert("prev".to_string(), prev.into());
+    let hash = record_hash(&record);
+    record.insert("hash".to_string(), hash.into());
+
+    let mut line = serde_json::to_vec(&record)?;
+    line.push(b'\n');
+    file.write_all(&line)
+        .and_then(|()| file.sync_data())
+        .with_context(|| format!("Failed to write to the audit log '{log_path_sanitized}'"))
+}
+
+/// The last record of the log, if any
+fn last_record(file: &mut File) -> Result<Option<Map<String, Value>>> {
+    let len = file.seek(SeekFrom::End(0))?;
+    let start = len.saturating_sub(MAX_RECORD_BYTES);
+    file.seek(SeekFrom::Start(start))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    let end = tail
+        .iter()
+        .rposition(|&byte| byte != b'\n')
+        .map_or(0, |last| last + 1);
+    let tail = &tail[..end];
+    if tail.is_empty() {
+        return Ok(None);
+    }
+    let line = match tail.iter().rposition(|&byte| byte == b'\n') {
+        Some(newline) => &tail[newline + 1..],
+        None if start == 0 => tail,
+        None => anyhow::bail!("The last record is too long"),
+    };
+    serde_json::from_slice(line)
+        .map(Some)
+        .context("The last record isn't a JSON object")
+}
+
+/// SHA-256 of `record` serialized without its `hash`, in hexadecimal
+fn record_hash(record: &Map<String, Value>) -> String {
+    let mut record = record.clone();
+    // Unlike `remove`, keeps the other keys in order, whichever map type
+    // serde_json was built with
+    record.retain(|key, _| key != "hash");
+    let serialized = serde_json::to_vec(&record).expect("JSON values always serialize");
+    format!("{:x}", Sha256::digest(serialized))
+}
+
+/// Check the chain of the log at `log_path`, returning its number of records
+pub fn verify_log(log_path: &Path) -> Result<usize> {
+    let log_path_sanitized = replace_control_chars(&log_path.to_string_lossy(), false);
+    let file = File::open(log_path)
+        .with_context(|| format!("Failed to open the audit log '{log_path_sanitized}'"))?;
+    let mut prev = FIRST_PREV.to_string();
+    let mut count = 0;
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line_num = index + 1;
+        let line = line.with_context(|| format!("Failed to read line {line_num}"))?;
+        if line.is_empty() {
+            continue;
+        }
+        let record: Map<String, Value> = serde_json::from_str(&line)
+            .with_context(|| format!("Line {line_num} isn't a JSON object"))?;
+        count += 1;
+        if record.get("seq").and_then(Value::as_u64) != Some(count as u64) {
+            anyhow::bail!(
+                "Record on line {line_num} isn't record {count}: records were removed or reordered"
+            );
+        }
+        if record.get("prev").and_then(Value::as_str) != Some(prev.as_str()) {
+            anyhow::bail!("Record on line {line_num} doesn't follow the record before it");
+        }
+        let hash = record_hash(&record);
+        if record.get("hash").and_then(Value::as_str) != Some(hash.as_str()) {
+            anyhow::bail!("Record on line {line_num} was modified");
+        }
+        prev = hash;
+    }
+    Ok(count)
+}
+
+/// SHA-256 of the file at `path`, in hexadecimal
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub(crate) fn absolute(path: &str) -> String {
+    std::path::absolute(path).map_or_else(
+        |_| path.to_string(),
+        |path| path.to_string_lossy().into_owned(),
+    )
+}
+
+/// Name of the user running the conversion, from the environment
+fn user() -> Option<String> {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .ok()
+}
+
+/// `time` as an RFC 3339 timestamp in UTC, to the second
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+    let UtcTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    } = time.into();
+    format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn record(input: &str) -> Map<String, Value> {
+        let Value::Object(record) = json!({"input": input, "result": "success"}) else {
+            unreachable!();
+        };
+        record
+    }
+
+    #[test]
+    fn test_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("audit.jsonl");
+        for input in ["a.pdf", "b.docx", "c.png"] {
+            append(&log, record(input)).unwrap();
+        }
+        assert_eq!(verify_log(&log).unwrap(), 3);
+
+        let text = std::fs::read_to_string(&log).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        let first: Map<String, Value> = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(first["seq"], 1);
+        assert_eq!(first["prev"], FIRST_PREV);
+        let second: Map<String, Value> = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(second["prev"], first["hash"]);
+
+        let tampered = text.replace("b.docx", "d.docx");
+        std::fs::write(&log, &tampered).unwrap();
+        let err = verify_log(&log).unwrap_err().to_string();
+        assert_eq!(err, "Record on line 2 was modified");
+
+        let removed = format!("{}\n{}\n", lines[0], lines[2]);
+        std::fs::write(&log, removed).unwrap();
+        assert!(verify_log(&log).is_err());
+    }
+
+    #[test]
+    fn test_record_finish() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("audit.jsonl");
+        let input = dir.path().join("in.txt");
+        std::fs::write(&input, "hello").unwrap();
+        let input = input.to_string_lossy();
+        let output = dir.path().join("out.pdf");
+        let record = ConversionRecord::start(&input, &output.to_string_lossy(), Runtime::Bwrap);
+        record
+            .finish(&log, Some(&anyhow::anyhow!("Container failed")))
+            .unwrap();
+        assert_eq!(verify_log(&log).unwrap(), 1);
+
+        let record: Map<String, Value> =
+            serde_json::from_str(&std::fs::read_to_string(&log).unwrap()).unwrap();
+        assert_eq!(
+            record["input_sha256"],
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert_eq!(record["output_sha256"], Value::Null);
+        assert_eq!(record["result"], "failure");
+        assert_eq!(record["error"], "Container failed");
+        assert_eq!(record["image_digest"], Value::Null);
+        // Other tests may raise alerts meanwhile
+        assert!(record["alerts"].is_array());
+    }
+
+    #[test]
+    fn test_record_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("audit.jsonl");
+        let output = dir.path().join("out.pdf");
+        std::fs::write(&output, "%PDF").unwrap();
+        let output = output.to_string_lossy();
+        ConversionRecord::start(&output, &output, Runtime::Bwrap)
+            .skipped(&log)
+            .unwrap();
+        assert_eq!(verify_log(&log).unwrap(), 1);
+
+        let record: Map<String, Value> =
+            serde_json::from_str(&std::fs::read_to_string(&log).unwrap()).unwrap();
+        assert_eq!(record["result"], "skipped");
+        assert_eq!(record["output_sha256"], record["input_sha256"]);
+        assert_eq!(record["error"], Value::Null);
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(SystemTime::UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_210_096);
+        assert_eq!(rfc3339(time), "2024-02-29T12:34:56Z");
+    }
+}
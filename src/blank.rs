@@ -0,0 +1,145 @@
+//! Detection of blank pages, such as the backsides of duplex scans
+//!
+//! A page is blank when nearly all of its pixels have about the same
+//! luminance as its background, the most common luminance. Scanner noise and
+//! faint show-through from the other side stay within the tolerance, while a
+//! single line of text doesn't.
+
+use crate::{PdfPage, PixelFormat};
+use std::io::Write;
+
+/// Largest luminance difference from the background still counted as
+/// background
+const TOLERANCE: usize = 64;
+
+/// Pixels differing from the background allowed on a blank page, per 10000
+const MAX_INK_PER_10000: u64 = 5;
+
+/// Whether the pixels of `page` have a near-uniform luminance
+pub(crate) fn is_blank<P: PdfPage>(page: &P) -> std::io::Result<bool> {
+    let mut histogram = LuminanceHistogram {
+        format: page.format(),
+        counts: [0; 256],
+        partial: Vec::with_capacity(4),
+    };
+    page.write_pixels(&mut histogram)?;
+    Ok(histogram.is_uniform())
+}
+
+/// Writer counting the pixels of each luminance
+struct LuminanceHistogram {
+    format: PixelFormat,
+    counts: [u64; 256],
+    /// Bytes of a pixel split across writes
+    partial: Vec<u8>,
+}
+
+impl LuminanceHistogram {
+    fn add(&mut self, pixel: &[u8]) {
+        self.counts[luminance(self.format, pixel) as usize] += 1;
+    }
+
+    fn is_uniform(&self) -> bool {
+        let total: u64 = self.counts.iter().sum();
+        let background = (0..256).max_by_key(|&i| self.counts[i]).unwrap_or(0);
+        let close = background.saturating_sub(TOLERANCE)..=(background + TOLERANCE).min(255);
+        let ink = total - self.counts[close].iter().sum::<u64>();
+        ink * 10000 <= total * MAX_INK_PER_10000
+    }
+}
+
+impl Write for LuminanceHistogram {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let size = self.format.bytes_per_pixel();
+        let mut rest = buf;
+        if !self.partial.is_empty() {
+            let take = (size - self.partial.len()).min(rest.len());
+            self.partial.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+            if self.partial.len() < size {
+                return Ok(buf.len());
+            }
+            let pixel = std::mem::take(&mut self.partial);
+            self.add(&pixel);
+        }
+
+        let mut pixels = rest.chunks_exact(size);
+        for pixel in &mut pixels {
+            self.add(pixel);
+        }
+        self.partial.extend_from_slice(pixels.remainder());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Luminance of a pixel, as seen over a white background for RGBA
+pub(crate) fn luminance(format: PixelFormat, pixel: &[u8]) -> u8 {
+    let rgb = |pixel: &[u8]| {
+        (299 * pixel[0] as u32 + 587 * pixel[1] as u32 + 114 * pixel[2] as u32) / 1000
+    };
+    match format {
+        PixelFormat::Gray => pixel[0],
+        PixelFormat::Rgb => rgb(pixel) as u8,
+        PixelFormat::Rgba => {
+            let alpha = pixel[3] as u32;
+            ((rgb(pixel) * alpha + 255 * (255 - alpha)) / 255) as u8
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PageData;
+
+    /// 100x100 white page with `ink` black pixels
+    fn page(ink: usize) -> PageData {
+        let mut pixels = vec![255; 100 * 100 * 3];
+        pixels[..ink * 3].fill(0);
+        PageData::new(100, 100, pixels)
+    }
+
+    #[test]
+    fn test_blank_pages() {
+        assert!(is_blank(&page(0)).unwrap());
+        assert!(is_blank(&page(5)).unwrap());
+        assert!(!is_blank(&page(6)).unwrap());
+        assert!(!is_blank(&page(5000)).unwrap());
+    }
+
+    #[test]
+    fn test_noise_and_show_through() {
+        // Grey paper with noise and faint text from the other side
+        let pixels: Vec<u8> = (0..100 * 100).map(|i| 200 + (i % 7) as u8 * 5).collect();
+        let page = PageData::with_format(100, 100, PixelFormat::Gray, pixels);
+        assert!(is_blank(&page).unwrap());
+    }
+
+    #[test]
+    fn test_transparent_pixels_are_white() {
+        let mut pixels = vec![0; 10 * 10 * 4];
+        pixels[..4].copy_from_slice(&[0, 0, 0, 255]);
+        let page = PageData::with_format(10, 10, PixelFormat::Rgba, pixels);
+        assert!(!is_blank(&page).unwrap());
+        assert_eq!(luminance(PixelFormat::Rgba, &[0, 0, 0, 0]), 255);
+    }
+
+    #[test]
+    fn test_pixels_split_across_writes() {
+        let page = page(100);
+        let mut histogram = LuminanceHistogram {
+            format: PixelFormat::Rgb,
+            counts: [0; 256],
+            partial: Vec::new(),
+        };
+        for chunk in page.pixels.chunks(7) {
+            histogram.write_all(chunk).unwrap();
+        }
+        assert_eq!(histogram.counts[0], 100);
+        assert_eq!(histogram.counts[255], 9900);
+    }
+}
@@ -0,0 +1,392 @@
+//! Local sandbox running the converter under bubblewrap, for hosts without
+//! podman or docker
+//!
+//! The converter (`dangerzone.conversion.doc_to_pixels`) must be installed
+//! system-wide. It runs in a root filesystem built from read-only binds of the
+//! system directories, with fresh namespaces (no network), no capabilities
+//! and a seccomp filter denying the syscalls a document converter never
+//! needs.
+
+use anyhow::{Context, Result};
+use std::io::{PipeReader, PipeWriter, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+/// File or directory of the host made visible in the sandbox
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Bind<'a> {
+    pub(crate) host: &'a Path,
+    pub(crate) sandbox: &'a str,
+    pub(crate) writable: bool,
+}
+
+/// Pipes shared with a bubblewrap command, which must stay open until it is
+/// spawned
+pub(crate) struct Pipes {
+    /// Carries the seccomp filter to bubblewrap
+    _seccomp: PipeReader,
+    /// Receives the info bubblewrap writes once the sandbox started
+    info: Option<PipeReader>,
+    /// Closed with the pipes, so that reading the info ends once bubblewrap
+    /// closed its end too
+    _info_writer: PipeWriter,
+}
+
+impl Pipes {
+    /// Take the pipe receiving bubblewrap's info, such as the host PID of
+    /// the sandbox (`child-pid`), made non-blocking
+    pub(crate) fn take_info(&mut self) -> Result<PipeReader> {
+        let info = self
+            .info
+            .take()
+            .context("The info pipe was already taken")?;
+        let fd = info.as_raw_fd();
+        // SAFETY: fcntl only changes the flags of a pipe this function owns
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1
+        {
+            return Err(std::io::Error::last_os_error()).context("Failed to set up info pipe");
+        }
+        Ok(info)
+    }
+}
+
+/// Build the bubblewrap command running `converter`, a command line of a
+/// locally installed program, with the extra variables `env` and the host
+/// paths `binds`
+pub(crate) fn command(
+    converter: &[&str],
+    env: &[(&str, &str)],
+    binds: &[Bind<'_>],
+) -> Result<(Command, Pipes)> {
+    let (seccomp, mut writer) = std::io::pipe().context("Failed to create seccomp pipe")?;
+    // The filter is far smaller than a pipe buffer, so this doesn't block
+    writer
+        .write_all(&seccomp_filter())
+        .context("Failed to write seccomp filter")?;
+    drop(writer);
+    let (info, info_writer) = std::io::pipe().context("Failed to create info pipe")?;
+
+    let fds = [seccomp.as_raw_fd(), info_writer.as_raw_fd()];
+    let mut command = Command::new("bwrap");
+    command
+        .args(args(fds[0], fds[1], env, binds))
+        .args(converter);
+    // SAFETY: fcntl is async-signal-safe and only touches the inherited fds
+    unsafe {
+        command.pre_exec(move || {
+            // Let bubblewrap inherit the read end of the seccomp pipe and the
+            // write end of the info pipe
+            for fd in fds {
+                if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let pipes = Pipes {
+        _seccomp: seccomp,
+        info: Some(info),
+        _info_writer: info_writer,
+    };
+    Ok((command, pipes))
+}
+
+fn args(seccomp_fd: i32, info_fd: i32, env: &[(&str, &str)], binds: &[Bind<'_>]) -> Vec<String> {
+    let mut args: Vec<String> = [
+        "--ro-bind",
+        "/usr",
+        "/usr",
+        "--ro-bind",
+        "/etc",
+        "/etc",
+        "--ro-bind-try",
+        "/opt",
+        "/opt",
+    ]
+    .map(String::from)
+    .to_vec();
+    // Either real directories or symlinks into /usr, depending on the distro
+    for dir in ["/bin", "/sbin", "/lib", "/lib32", "/lib64"] {
+        args.extend(["--ro-bind-try", dir, dir].map(String::from));
+    }
+    args.extend(
+        [
+            "--dev",
+            "/dev",
+            "--proc",
+            "/proc",
+            "--tmpfs",
+            "/tmp",
+            "--unshare-all",
+            "--die-with-parent",
+            "--new-session",
+            "--cap-drop",
+            "ALL",
+            "--clearenv",
+            "--setenv",
+            "HOME",
+            "/tmp",
+            "--setenv",
+            "PATH",
+            "/usr/local/bin:/usr/bin:/bin",
+            "--setenv",
+            "LANG",
+            "C.UTF-8",
+            "--chdir",
+            "/tmp",
+        ]
+        .map(String::from),
+    );
+    for (key, value) in env {
+        args.extend(["--setenv", key, value].map(String::from));
+    }
+    for bind in binds {
+        args.extend([
+            if bind.writable { "--bind" } else { "--ro-bind" }.to_string(),
+            bind.host.to_string_lossy().into_owned(),
+            bind.sandbox.to_string(),
+        ]);
+    }
+    args.extend(["--seccomp".to_string(), seccomp_fd.to_string()]);
+    args.extend(["--info-fd".to_string(), info_fd.to_string()]);
+    args.push("--".to_string());
+    args
+}
+
+// Classic BPF opcodes and seccomp return values, from linux/filter.h and
+// linux/seccomp.h
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const EPERM: u32 = 1;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Syscalls failing with EPERM: kernel modules, mounts, namespaces, tracing,
+/// keyrings, BPF and io_uring, and changes to the host's clock or identity
+#[cfg(target_arch = "x86_64")]
+const DENIED_SYSCALLS: &[u32] = &[
+    101, // ptrace
+    103, // syslog
+    134, // uselib
+    153, // vhangup
+    155, // pivot_root
+    159, // adjtimex
+    163, // acct
+    164, // settimeofday
+    165, // mount
+    166, // umount2
+    167, // swapon
+    168, // swapoff
+    169, // reboot
+    170, // sethostname
+    171, // setdomainname
+    172, // iopl
+    173, // ioperm
+    175, // init_module
+    176, // delete_module
+    179, // quotactl
+    212, // lookup_dcookie
+    227, // clock_settime
+    246, // kexec_load
+    248, // add_key
+    249, // request_key
+    250, // keyctl
+    272, // unshare
+    298, // perf_event_open
+    303, // name_to_handle_at
+    304, // open_by_handle_at
+    308, // setns
+    310, // process_vm_readv
+    311, // process_vm_writev
+    313, // finit_module
+    320, // kexec_file_load
+    321, // bpf
+    323, // userfaultfd
+    425, // io_uring_setup
+    426, // io_uring_enter
+    427, // io_uring_register
+    428, // open_tree
+    429, // move_mount
+    430, // fsopen
+    431, // fsconfig
+    432, // fsmount
+    433, // fspick
+];
+
+#[cfg(target_arch = "aarch64")]
+const DENIED_SYSCALLS: &[u32] = &[
+    18,  // lookup_dcookie
+    39,  // umount2
+    40,  // mount
+    41,  // pivot_root
+    58,  // vhangup
+    60,  // quotactl
+    89,  // acct
+    97,  // unshare
+    104, // kexec_load
+    105, // init_module
+    106, // delete_module
+    112, // clock_settime
+    116, // syslog
+    117, // ptrace
+    142, // reboot
+    161, // sethostname
+    162, // setdomainname
+    170, // settimeofday
+    171, // adjtimex
+    217, // add_key
+    218, // request_key
+    219, // keyctl
+    224, // swapon
+    225, // swapoff
+    241, // perf_event_open
+    264, // name_to_handle_at
+    265, // open_by_handle_at
+    268, // setns
+    270, // process_vm_readv
+    271, // process_vm_writev
+    273, // finit_module
+    280, // bpf
+    282, // userfaultfd
+    294, // kexec_file_load
+    425, // io_uring_setup
+    426, // io_uring_enter
+    427, // io_uring_register
+    428, // open_tree
+    429, // move_mount
+    430, // fsopen
+    431, // fsconfig
+    432, // fsmount
+    433, // fspick
+];
+
+/// Seccomp program in the `struct sock_filter` layout bubblewrap expects
+fn seccomp_filter() -> Vec<u8> {
+    let mut program = vec![
+        // Kill anything not using the native syscall ABI
+        (BPF_LD_W_ABS, 0, 0, 4), // seccomp_data.arch
+        (BPF_JEQ_K, 1, 0, AUDIT_ARCH),
+        (BPF_RET_K, 0, 0, SECCOMP_RET_KILL_PROCESS),
+        (BPF_LD_W_ABS, 0, 0, 0), // seccomp_data.nr
+    ];
+    #[cfg(target_arch = "x86_64")]
+    {
+        // x32 syscalls share the architecture but set bit 30
+        program.push((BPF_JGE_K, 0, 1, 0x4000_0000));
+        program.push((BPF_RET_K, 0, 0, SECCOMP_RET_KILL_PROCESS));
+    }
+    for &nr in DENIED_SYSCALLS {
+        program.push((BPF_JEQ_K, 0, 1, nr));
+        program.push((BPF_RET_K, 0, 0, SECCOMP_RET_ERRNO | EPERM));
+    }
+    program.push((BPF_RET_K, 0, 0, SECCOMP_RET_ALLOW));
+
+    program
+        .into_iter()
+        .flat_map(|(code, jt, jf, k): (u16, u8, u8, u32)| {
+            let mut instruction = [0; 8];
+            instruction[..2].copy_from_slice(&code.to_ne_bytes());
+            instruction[2] = jt;
+            instruction[3] = jf;
+            instruction[4..].copy_from_slice(&k.to_ne_bytes());
+            instruction
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instruction(filter: &[u8], index: usize) -> (u16, u8, u8, u32) {
+        let bytes = &filter[index * 8..(index + 1) * 8];
+        (
+            u16::from_ne_bytes([bytes[0], bytes[1]]),
+            bytes[2],
+            bytes[3],
+            u32::from_ne_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        )
+    }
+
+    #[test]
+    fn test_seccomp_filter_layout() {
+        let filter = seccomp_filter();
+        assert_eq!(filter.len() % 8, 0);
+        let len = filter.len() / 8;
+
+        assert_eq!(instruction(&filter, 0), (BPF_LD_W_ABS, 0, 0, 4));
+        assert_eq!(instruction(&filter, 1), (BPF_JEQ_K, 1, 0, AUDIT_ARCH));
+        assert_eq!(
+            instruction(&filter, len - 1),
+            (BPF_RET_K, 0, 0, SECCOMP_RET_ALLOW)
+        );
+        // Every denied syscall is followed by its EPERM return
+        let denied: Vec<u32> = (0..len - 1)
+            .filter(|&i| {
+                instruction(&filter, i).0 == BPF_JEQ_K
+                    && instruction(&filter, i + 1) == (BPF_RET_K, 0, 0, SECCOMP_RET_ERRNO | EPERM)
+            })
+            .map(|i| instruction(&filter, i).3)
+            .collect();
+        assert_eq!(denied, DENIED_SYSCALLS);
+    }
+
+    #[test]
+    fn test_bwrap_args() {
+        let args = args(7, 8, &[("DANGERZONE_PAGE_CHECKSUMS", "1")], &[]);
+        let position = |arg: &str| args.iter().position(|a| a == arg);
+
+        assert!(position("--unshare-all").is_some());
+        assert!(
+            position("--bind").is_none(),
+            "nothing may be bound writable"
+        );
+        let setenv = position("DANGERZONE_PAGE_CHECKSUMS").unwrap();
+        assert_eq!(args[setenv - 1], "--setenv");
+        assert_eq!(args[setenv + 1], "1");
+        let seccomp = position("--seccomp").unwrap();
+        assert_eq!(args[seccomp + 1], "7");
+        let info = position("--info-fd").unwrap();
+        assert_eq!(args[info + 1], "8");
+        assert_eq!(args.last().unwrap(), "--");
+    }
+
+    #[test]
+    fn test_bwrap_binds() {
+        let binds = [
+            Bind {
+                host: Path::new("/host/input.pdf"),
+                sandbox: "/input.pdf",
+                writable: false,
+            },
+            Bind {
+                host: Path::new("/host/work"),
+                sandbox: "/work",
+                writable: true,
+            },
+        ];
+        let args = args(7, 8, &[], &binds);
+        let position = |arg: &str| args.iter().position(|a| a == arg).unwrap();
+
+        let input = position("/host/input.pdf");
+        assert_eq!(args[input - 1], "--ro-bind");
+        assert_eq!(args[input + 1], "/input.pdf");
+        let work = position("/host/work");
+        assert_eq!(args[work - 1], "--bind");
+        assert_eq!(args[work + 1], "/work");
+        // Bound over the sandbox's tmpfs, not under it
+        assert!(position("--tmpfs") < input);
+    }
+}
@@ -0,0 +1,337 @@
+//! Tripwires checked after each sandbox exited
+//!
+//! A converter compromised by a document is confined by the sandbox, but
+//! if it got out, it would likely leave traces: a container still running
+//! after its client exited, processes of the sandbox outliving it, mounts
+//! appearing on the host, or more output than the caps let through. None of
+//! these happen in a normal conversion, so each one raises an [`Alert`],
+//! logged as a warning and recorded in the audit log of the conversion.
+//!
+//! The checks are best-effort: mounts and processes are only read on Linux,
+//! and only the processes of bubblewrap's sandboxes can be told apart, once
+//! the sandbox was seen running.
+
+use crate::{replace_control_chars, Runtime};
+use log::warn;
+use std::collections::HashSet;
+use std::io::{PipeReader, Read};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+/// Directories under which runtimes mount the root filesystems and network
+/// namespaces of their containers, on the host with rootful podman and
+/// docker, and desktops mount removable media
+const EXPECTED_MOUNT_PREFIXES: [&str; 8] = [
+    "/var/lib/containers/",
+    "/run/containers/",
+    "/var/lib/docker/",
+    "/run/docker/",
+    "/run/netns/",
+    "/run/user/",
+    "/run/media/",
+    "/media/",
+];
+
+/// Alerts raised by this process, in order
+static ALERTS: Mutex<Vec<Alert>> = Mutex::new(Vec::new());
+
+/// Invariant of the sandbox found broken once it exited
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Alert {
+    /// The container still ran after its runtime's client exited
+    ContainerRunning { name: String },
+    /// Processes of the sandbox outlived it
+    LeftoverProcesses { pids: Vec<u32> },
+    /// Mount points appeared on the host while the sandbox ran
+    HostMounts { mount_points: Vec<String> },
+    /// The conversion produced more than [`ConversionOptions::max_output_bytes`](crate::ConversionOptions::max_output_bytes)
+    OutputOverCap { bytes: u64, limit: u64 },
+}
+
+impl std::fmt::Display for Alert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Alert::ContainerRunning { name } => {
+                write!(f, "Container {name} still runs after its client exited")
+            }
+            Alert::LeftoverProcesses { pids } => {
+                let pids: Vec<String> = pids.iter().map(u32::to_string).collect();
+                write!(
+                    f,
+                    "Processes of the sandbox outlived it: {}",
+                    pids.join(", ")
+                )
+            }
+            Alert::HostMounts { mount_points } => write!(
+                f,
+                "Mounts appeared on the host during the conversion: {}",
+                replace_control_chars(&mount_points.join(", "), false)
+            ),
+            Alert::OutputOverCap { bytes, limit } => write!(
+                f,
+                "The conversion produced {bytes} bytes, more than the limit of {limit}"
+            ),
+        }
+    }
+}
+
+/// Number of alerts raised by this process so far, to pass to
+/// [`alerts_since`]
+pub fn alert_count() -> usize {
+    ALERTS.lock().unwrap().len()
+}
+
+/// Alerts raised by this process after the first `count`
+pub fn alerts_since(count: usize) -> Vec<Alert> {
+    ALERTS
+        .lock()
+        .unwrap()
+        .get(count..)
+        .map(<[Alert]>::to_vec)
+        .unwrap_or_default()
+}
+
+fn raise(alert: Alert) {
+    warn!("Sandbox alert: {alert}");
+    ALERTS.lock().unwrap().push(alert);
+}
+
+/// Alert if the conversion wrote more pixels, or a larger PDF, than
+/// `limit` lets the sandbox produce
+pub(crate) fn check_output(stats: &crate::ConversionStats, limit: u64) {
+    let bytes = stats.pixel_bytes.max(stats.output_bytes);
+    if bytes > limit {
+        raise(Alert::OutputOverCap { bytes, limit });
+    }
+}
+
+/// State of the host when a sandbox started, checked once it exited
+pub(crate) struct Tripwire {
+    mount_points: Option<HashSet<String>>,
+    sandbox: Sandboxed,
+}
+
+enum Sandboxed {
+    /// Container run by the command-line client of `runtime`
+    Container { runtime: Runtime, name: String },
+    /// bubblewrap's sandbox, whose info is written to `info`, and the
+    /// session of its processes once found
+    Bwrap {
+        info: PipeReader,
+        read: Vec<u8>,
+        session: Option<u32>,
+    },
+}
+
+impl Tripwire {
+    /// Tripwire of the container `name`, run by the client of `runtime`
+    pub(crate) fn container(runtime: Runtime, name: String) -> Self {
+        Tripwire {
+            mount_points: mount_points(),
+            sandbox: Sandboxed::Container { runtime, name },
+        }
+    }
+
+    /// Tripwire of a bubblewrap sandbox, writing its info to `info`, a
+    /// non-blocking pipe
+    #[cfg_attr(
+        not(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        )),
+        allow(dead_code)
+    )]
+    pub(crate) fn bwrap(info: PipeReader) -> Self {
+        Tripwire {
+            mount_points: mount_points(),
+            sandbox: Sandboxed::Bwrap {
+                info,
+                read: Vec::new(),
+                session: None,
+            },
+        }
+    }
+
+    /// Look for the session of bubblewrap's sandbox while it runs
+    ///
+    /// bubblewrap starts the sandbox's first process (`child-pid`), which
+    /// starts the converter in a new session; that session is only known
+    /// while the converter runs.
+    pub(crate) fn observe(&mut self) {
+        let Sandboxed::Bwrap {
+            info,
+            read,
+            session,
+        } = &mut self.sandbox
+        else {
+            return;
+        };
+        if session.is_some() {
+            return;
+        }
+        let mut buf = [0; 512];
+        while let Ok(len @ 1..) = info.read(&mut buf) {
+            read.extend_from_slice(&buf[..len]);
+        }
+        let Some(pid) = child_pid(&String::from_utf8_lossy(read)) else {
+            return;
+        };
+        *session = std::fs::read_to_string(format!("/proc/{pid}/task/{pid}/children"))
+            .ok()
+            .and_then(|children| children.split_whitespace().next()?.parse::<u32>().ok())
+            .and_then(|child| {
+                stat_session(&std::fs::read_to_string(format!("/proc/{child}/stat")).ok()?)
+            });
+    }
+
+    /// Check the host once the sandbox exited, raising an alert for each
+    /// invariant broken
+    pub(crate) fn check(self) {
+        match self.sandbox {
+            Sandboxed::Container { runtime, name } => {
+                if container_running(runtime, &name) {
+                    raise(Alert::ContainerRunning { name });
+                }
+            }
+            Sandboxed::Bwrap {
+                session: Some(session),
+                ..
+            } => {
+                let pids = session_members(session);
+                if !pids.is_empty() {
+                    raise(Alert::LeftoverProcesses { pids });
+                }
+            }
+            Sandboxed::Bwrap { session: None, .. } => {}
+        }
+        if let (Some(before), Some(after)) = (self.mount_points, mount_points()) {
+            let mut mount_points: Vec<String> = after
+                .difference(&before)
+                .filter(|mount_point| {
+                    !EXPECTED_MOUNT_PREFIXES
+                        .iter()
+                        .any(|prefix| mount_point.starts_with(prefix))
+                })
+                .cloned()
+                .collect();
+            if !mount_points.is_empty() {
+                mount_points.sort();
+                raise(Alert::HostMounts { mount_points });
+            }
+        }
+    }
+}
+
+/// Whether the runtime still runs the container `name`
+fn container_running(runtime: Runtime, name: &str) -> bool {
+    Command::new(runtime.command())
+        .args(["ps", "-q", "--filter", &format!("name=^{name}$")])
+        .args(["--filter", "status=running"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .is_ok_and(|output| {
+            output.status.success() && !String::from_utf8_lossy(&output.stdout).trim().is_empty()
+        })
+}
+
+/// Mount points of this process's mount namespace, on Linux
+fn mount_points() -> Option<HashSet<String>> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+    Some(parse_mount_points(&mountinfo))
+}
+
+/// Mount points listed in `mountinfo`, the fifth field of each line
+fn parse_mount_points(mountinfo: &str) -> HashSet<String> {
+    mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Host PID of the sandbox's first process, from bubblewrap's info
+fn child_pid(json: &str) -> Option<u32> {
+    let value = json.split("\"child-pid\"").nth(1)?.trim_start();
+    let digits = value.strip_prefix(':')?.trim_start();
+    let end = digits
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(digits.len());
+    digits[..end].parse().ok()
+}
+
+/// Processes of the session `session`, on Linux
+fn session_members(session: u32) -> Vec<u32> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    let mut pids: Vec<u32> = entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .filter(|pid| {
+            std::fs::read_to_string(format!("/proc/{pid}/stat"))
+                .is_ok_and(|stat| stat_session(&stat) == Some(session))
+        })
+        .collect();
+    pids.sort_unstable();
+    pids
+}
+
+/// Session of a process, from its `/proc/<pid>/stat`
+fn stat_session(stat: &str) -> Option<u32> {
+    // The command name may hold spaces and parentheses, but ends the last
+    // one; the state, parent, process group and session follow
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(3)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_child_pid() {
+        let info = "{\n    \"child-pid\": 4242,\n    \"cgroup-namespace\": 4026531835\n}";
+        assert_eq!(child_pid(info), Some(4242));
+        assert_eq!(child_pid("{}"), None);
+    }
+
+    #[test]
+    fn test_stat_session() {
+        let stat = "1234 (python3 (x) y) S 1200 1234 1230 0 -1 4194560";
+        assert_eq!(stat_session(stat), Some(1230));
+        assert_eq!(stat_session("1234 python3"), None);
+    }
+
+    #[test]
+    fn test_parse_mount_points() {
+        let mountinfo = "22 1 0:21 / / rw,relatime - ext4 /dev/sda1 rw\n\
+                         35 22 0:31 / /proc rw,nosuid - proc proc rw\n";
+        let mount_points = parse_mount_points(mountinfo);
+        assert_eq!(
+            mount_points,
+            HashSet::from(["/".to_string(), "/proc".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_alerts() {
+        let count = alert_count();
+        let stats = crate::ConversionStats {
+            pixel_bytes: 2048,
+            output_bytes: 100,
+            ..Default::default()
+        };
+        check_output(&stats, 4096);
+        check_output(&stats, 1024);
+        let alerts = alerts_since(count);
+        assert!(alerts.contains(&Alert::OutputOverCap {
+            bytes: 2048,
+            limit: 1024
+        }));
+        assert!(!alerts.contains(&Alert::OutputOverCap {
+            bytes: 2048,
+            limit: 4096
+        }));
+        assert!(alerts_since(usize::MAX).is_empty());
+    }
+}
@@ -0,0 +1,245 @@
+//! Names of conversion containers, and removal of leftovers
+//!
+//! Every container is named `dangerzone-rs-<uuid>` and labelled with the ID
+//! of the process that started it. Killing the runtime's client (on a
+//! timeout, a cancellation or a crash) doesn't always stop the container
+//! itself, so containers killed by this process are removed by name, and
+//! containers whose owner process is gone are swept on startup.
+
+use crate::process::SandboxProcess;
+use crate::Runtime;
+use anyhow::{Context, Result};
+use log::{debug, info};
+use std::collections::HashMap;
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+
+/// Prefix of the names of conversion containers
+pub const CONTAINER_NAME_PREFIX: &str = "dangerzone-rs-";
+
+/// Label holding the ID of the process that started a container
+const OWNER_LABEL: &str = "dangerzone-rs.pid";
+
+/// Containers started by this process, by the process ID of their client
+static CONTAINERS: Mutex<Option<HashMap<u32, (Runtime, String)>>> = Mutex::new(None);
+
+pub(crate) fn new_container_name() -> String {
+    format!("{CONTAINER_NAME_PREFIX}{}", uuid::Uuid::new_v4())
+}
+
+/// Runtime arguments naming the container and recording its owner
+pub(crate) fn container_args(name: &str) -> Vec<String> {
+    vec![
+        "--name".to_string(),
+        name.to_string(),
+        "--label".to_string(),
+        format!("{OWNER_LABEL}={}", std::process::id()),
+    ]
+}
+
+/// Remember the container run by `client`, until it exits or is killed
+pub(crate) fn track(client: &Child, runtime: Runtime, name: String) {
+    CONTAINERS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(client.id(), (runtime, name));
+}
+
+/// Forget the container run by the client of `sandbox`, which exited on its
+/// own
+pub(crate) fn untrack(sandbox: &SandboxProcess) -> Option<(Runtime, String)> {
+    let id = sandbox.client_id()?;
+    CONTAINERS.lock().unwrap().as_mut()?.remove(&id)
+}
+
+/// Kill the sandbox and remove the container its client ran, if any
+pub(crate) fn kill_container(sandbox: &mut SandboxProcess) {
+    sandbox.kill();
+    if let Some((runtime, name)) = untrack(sandbox) {
+        debug!("Removing container {name}");
+        let _ = remove(runtime, &name);
+    }
+}
+
+/// Remove the conversion containers left behind by processes that are gone,
+/// or every conversion container with `all`, returning their names
+///
+/// Apple's `container` runtime isn't supported. On Windows, whether the
+/// owner is still running can't be checked, so only `all` removes anything.
+pub fn cleanup_containers(runtime: Runtime, all: bool) -> Result<Vec<String>> {
+    match runtime {
+        // bubblewrap's sandboxes die with their parent
+        Runtime::Bwrap => return Ok(Vec::new()),
+        Runtime::AppleContainer => {
+            anyhow::bail!("Cleaning up containers isn't supported with Apple's container runtime")
+        }
+        Runtime::Podman | Runtime::Docker | Runtime::Gvisor => {}
+    }
+
+    let output = Command::new(runtime.command())
+        .args([
+            "ps",
+            "--all",
+            "--filter",
+            &format!("name={CONTAINER_NAME_PREFIX}"),
+            "--format",
+            "{{.Names}}",
+        ])
+        .stderr(Stdio::null())
+        .output()
+        .context(format!(
+            "Failed to run {runtime}. Make sure it is installed."
+        ))?;
+    if !output.status.success() {
+        anyhow::bail!("Failed to list containers (status: {})", output.status);
+    }
+
+    let mut removed = Vec::new();
+    for name in container_names(&String::from_utf8_lossy(&output.stdout)) {
+        if !all && !is_stale(runtime, name)? {
+            continue;
+        }
+        remove(runtime, name)?;
+        removed.push(name.to_string());
+    }
+    Ok(removed)
+}
+
+/// Remove stale containers, logging instead of failing, as done on startup
+pub fn sweep(runtime: Runtime) {
+    if runtime == Runtime::AppleContainer {
+        return;
+    }
+    match cleanup_containers(runtime, false) {
+        Ok(removed) => {
+            for name in removed {
+                info!("Removed leftover container {name}");
+            }
+        }
+        Err(e) => debug!("Failed to clean up leftover containers: {e:#}"),
+    }
+}
+
+/// Names of conversion containers in the output of `ps --format {{.Names}}`
+///
+/// The name filter matches substrings, so names are checked again here.
+fn container_names(ps_output: &str) -> Vec<&str> {
+    ps_output
+        .lines()
+        .map(str::trim)
+        .filter(|name| name.starts_with(CONTAINER_NAME_PREFIX))
+        .collect()
+}
+
+fn is_stale(runtime: Runtime, name: &str) -> Result<bool> {
+    let output = Command::new(runtime.command())
+        .args([
+            "container",
+            "inspect",
+            "--format",
+            &format!("{{{{index .Config.Labels \"{OWNER_LABEL}\"}}}}"),
+            name,
+        ])
+        .stderr(Stdio::null())
+        .output()
+        .context(format!("Failed to run {runtime}"))?;
+    if !output.status.success() {
+        // Removed in the meantime
+        return Ok(false);
+    }
+    Ok(
+        match parse_owner(&String::from_utf8_lossy(&output.stdout)) {
+            Some(pid) if pid == std::process::id() => !is_tracked(name),
+            Some(pid) => !process_alive(pid),
+            None => true,
+        },
+    )
+}
+
+fn parse_owner(label: &str) -> Option<u32> {
+    label.trim().parse().ok()
+}
+
+fn is_tracked(name: &str) -> bool {
+    CONTAINERS
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|containers| containers.values().any(|(_, tracked)| tracked == name))
+}
+
+fn remove(runtime: Runtime, name: &str) -> Result<()> {
+    let args = match runtime {
+        Runtime::AppleContainer => ["delete", "--force", name],
+        _ => ["rm", "--force", name],
+    };
+    let status = Command::new(runtime.command())
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .context(format!("Failed to run {runtime}"))?;
+    if !status.success() {
+        anyhow::bail!("Failed to remove container {name} (status: {status})");
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    // 0 and negative IDs address process groups
+    let Some(pid) = libc::pid_t::try_from(pid).ok().filter(|pid| *pid > 0) else {
+        return false;
+    };
+    // Signal 0 only checks whether the process exists
+    // SAFETY: kill has no memory safety requirements
+    let exists = unsafe { libc::kill(pid, 0) } == 0;
+    exists || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_container_names() {
+        let name = new_container_name();
+        assert!(name.starts_with(CONTAINER_NAME_PREFIX));
+        assert_eq!(name.len(), CONTAINER_NAME_PREFIX.len() + 36);
+        assert_ne!(name, new_container_name());
+
+        let args = container_args(&name);
+        assert_eq!(args[..2], ["--name".to_string(), name]);
+        assert_eq!(args[3], format!("dangerzone-rs.pid={}", std::process::id()));
+    }
+
+    #[test]
+    fn test_ps_output_filtering() {
+        let output = "dangerzone-rs-1234\nmy-dangerzone-rs-db\n  dangerzone-rs-5678  \n\n";
+        assert_eq!(
+            container_names(output),
+            ["dangerzone-rs-1234", "dangerzone-rs-5678"]
+        );
+    }
+
+    #[test]
+    fn test_owner_label() {
+        assert_eq!(parse_owner("1234\n"), Some(1234));
+        assert_eq!(parse_owner("<no value>\n"), None);
+        assert_eq!(parse_owner(""), None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_process_alive() {
+        assert!(process_alive(std::process::id()));
+        assert!(!process_alive(0));
+        assert!(!process_alive(u32::MAX));
+    }
+}
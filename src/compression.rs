@@ -0,0 +1,134 @@
+//! Compression of the pixels embedded in the safe PDF
+//!
+//! Every backend writes a zlib stream, which the PDF decodes with
+//! `/FlateDecode`, so the choice only trades conversion time for file size.
+
+use crate::{CompressionBackend, CompressionConfig};
+use flate2::Compression;
+use std::io::{self, Write};
+
+/// Zlib encoder of the configured backend, writing to memory
+pub(crate) enum Encoder {
+    Flate2(flate2::write::ZlibEncoder<Vec<u8>>),
+    #[cfg(feature = "zopfli")]
+    Zopfli(io::BufWriter<zopfli::ZlibEncoder<Vec<u8>>>),
+}
+
+impl Encoder {
+    pub(crate) fn new(config: &CompressionConfig) -> io::Result<Self> {
+        match config.backend {
+            CompressionBackend::Flate2 => Ok(Encoder::Flate2(flate2::write::ZlibEncoder::new(
+                Vec::new(),
+                Compression::new(config.level),
+            ))),
+            #[cfg(feature = "zopfli")]
+            CompressionBackend::Zopfli => {
+                let options = zopfli::Options {
+                    iteration_count: std::num::NonZeroU64::new(config.level.max(1).into())
+                        .expect("at least one iteration"),
+                    ..zopfli::Options::default()
+                };
+                zopfli::ZlibEncoder::new_buffered(options, zopfli::BlockType::Dynamic, Vec::new())
+                    .map(Encoder::Zopfli)
+            }
+            #[cfg(not(feature = "zopfli"))]
+            CompressionBackend::Zopfli => Err(io::Error::other(
+                "compressing with zopfli requires the `zopfli` feature",
+            )),
+        }
+    }
+
+    /// Complete the zlib stream and return it
+    pub(crate) fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Flate2(encoder) => encoder.finish(),
+            #[cfg(feature = "zopfli")]
+            Encoder::Zopfli(encoder) => encoder.into_inner().map_err(|e| e.into_error())?.finish(),
+        }
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Flate2(encoder) => encoder.write(buf),
+            #[cfg(feature = "zopfli")]
+            Encoder::Zopfli(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Flate2(encoder) => encoder.flush(),
+            #[cfg(feature = "zopfli")]
+            Encoder::Zopfli(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn round_trip(config: &CompressionConfig, data: &[u8]) -> usize {
+        let mut encoder = Encoder::new(config).unwrap();
+        encoder.write_all(data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut inflated = Vec::new();
+        flate2::read::ZlibDecoder::new(&compressed[..])
+            .read_to_end(&mut inflated)
+            .unwrap();
+        assert_eq!(inflated, data);
+        compressed.len()
+    }
+
+    fn sample() -> Vec<u8> {
+        (0..512u32)
+            .map(|i| (i % 251) as u8 ^ (i / 1000) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn test_levels() {
+        let data = sample();
+        let fastest = CompressionConfig {
+            level: 1,
+            ..CompressionConfig::default()
+        };
+        let smallest = CompressionConfig {
+            level: 9,
+            ..CompressionConfig::default()
+        };
+        assert!(round_trip(&smallest, &data) <= round_trip(&fastest, &data));
+    }
+
+    #[test]
+    #[cfg(feature = "container")]
+    fn test_invalid_levels() {
+        for level in [0, 10] {
+            let config = CompressionConfig {
+                level,
+                ..CompressionConfig::default()
+            };
+            let err = config.check().unwrap_err();
+            assert!(err.to_string().contains("Invalid compression level"));
+        }
+        assert!(CompressionConfig::smallest().check().is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "zopfli")]
+    fn test_zopfli() {
+        let data = sample();
+        let flate2 = CompressionConfig {
+            level: 9,
+            ..CompressionConfig::default()
+        };
+        let zopfli = CompressionConfig {
+            level: 1,
+            backend: CompressionBackend::Zopfli,
+        };
+        assert!(round_trip(&zopfli, &data) <= round_trip(&flate2, &data));
+    }
+}
@@ -0,0 +1,159 @@
+//! Session D-Bus service, for file managers and desktop applets
+//!
+//! The service owns `org.freedomofthepress.DangerzoneRs` on the session bus
+//! and exports, at `/org/freedomofthepress/DangerzoneRs`, an interface of
+//! the same name with:
+//!
+//! - `ConvertDocument(s input, s output, b ocr) -> t job`, starting a
+//!   conversion in the background
+//! - `Cancel(t job) -> b`, cancelling a running conversion and returning
+//!   whether the job was still running
+//! - the `Progress(t job, s stage, u page, u total_pages)` signal for each
+//!   conversion stage, with the same stages as the JSON-RPC mode
+//! - the `Finished(t job, s status, s error)` signal, with a status of
+//!   `succeeded`, `failed` or `cancelled`, and an empty error unless the job
+//!   failed
+//!
+//! Paths are those of the caller, which runs as the same user.
+
+use crate::{
+    convert_document_with_options, CancellationToken, Cancelled, ConversionOptions, Progress,
+};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use zbus::object_server::SignalEmitter;
+
+/// Well-known name of the service on the session bus
+pub const BUS_NAME: &str = "org.freedomofthepress.DangerzoneRs";
+/// Path of the object exporting the service's interface
+pub const OBJECT_PATH: &str = "/org/freedomofthepress/DangerzoneRs";
+
+struct Service {
+    jobs: Arc<Mutex<HashMap<u64, CancellationToken>>>,
+    next_job: u64,
+}
+
+#[zbus::interface(name = "org.freedomofthepress.DangerzoneRs")]
+impl Service {
+    /// Start converting `input` into the safe PDF `output`
+    fn convert_document(
+        &mut self,
+        input: String,
+        output: String,
+        ocr: bool,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> zbus::fdo::Result<u64> {
+        let emitter = SignalEmitter::new(connection, OBJECT_PATH)?.into_owned();
+        let job = self.next_job;
+        self.next_job += 1;
+        let cancel = CancellationToken::new();
+        self.jobs.lock().unwrap().insert(job, cancel.clone());
+
+        let jobs = Arc::clone(&self.jobs);
+        std::thread::spawn(move || {
+            let on_progress = |progress: Progress| {
+                let (stage, page, total_pages) = stage(&progress);
+                let _ = zbus::block_on(Service::progress(
+                    &emitter,
+                    job,
+                    stage,
+                    page as u32,
+                    total_pages as u32,
+                ));
+            };
+            let options = ConversionOptions {
+                ocr,
+                ..ConversionOptions::default()
+            };
+            let result =
+                convert_document_with_options(input, output, &options, &on_progress, &cancel);
+            jobs.lock().unwrap().remove(&job);
+
+            let (status, error) = match result {
+                Ok(_) => ("succeeded", String::new()),
+                Err(e) if e.is::<Cancelled>() => ("cancelled", String::new()),
+                Err(e) => ("failed", format!("{e:#}")),
+            };
+            let _ = zbus::block_on(Service::finished(&emitter, job, status, &error));
+        });
+        Ok(job)
+    }
+
+    /// Cancel the running conversion `job`, returning whether it was running
+    fn cancel(&self, job: u64) -> bool {
+        match self.jobs.lock().unwrap().get(&job) {
+            Some(cancel) => {
+                cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[zbus(signal)]
+    async fn progress(
+        emitter: &SignalEmitter<'_>,
+        job: u64,
+        stage: &str,
+        page: u32,
+        total_pages: u32,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn finished(
+        emitter: &SignalEmitter<'_>,
+        job: u64,
+        status: &str,
+        error: &str,
+    ) -> zbus::Result<()>;
+}
+
+/// Name of the stage of `progress`, with its page and page count, or 0
+fn stage(progress: &Progress) -> (&'static str, usize, usize) {
+    match *progress {
+        Progress::ConvertingToPixels => ("converting_to_pixels", 0, 0),
+        Progress::PixelsReceived { total_pages } => ("pixels_received", 0, total_pages),
+        Progress::WritingPage { page, total_pages } => ("writing_page", page, total_pages),
+        Progress::ApplyingOcr => ("applying_ocr", 0, 0),
+        Progress::Done => ("done", 0, 0),
+    }
+}
+
+/// Serve the D-Bus interface on the session bus until the process is
+/// terminated
+pub fn serve_session() -> Result<()> {
+    let service = Service {
+        jobs: Arc::default(),
+        next_job: 1,
+    };
+    let _connection = zbus::blocking::connection::Builder::session()
+        .context("Failed to connect to the session bus")?
+        .name(BUS_NAME)
+        .context("Invalid bus name")?
+        .serve_at(OBJECT_PATH, service)
+        .context("Invalid object path")?
+        .build()
+        .with_context(|| format!("Failed to own {BUS_NAME} on the session bus"))?;
+    log::info!("Serving {BUS_NAME} on the session bus");
+    loop {
+        std::thread::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stage() {
+        assert_eq!(
+            stage(&Progress::WritingPage {
+                page: 2,
+                total_pages: 5
+            }),
+            ("writing_page", 2, 5)
+        );
+        assert_eq!(stage(&Progress::Done), ("done", 0, 0));
+    }
+}
@@ -0,0 +1,216 @@
+//! Checks of the free space a conversion needs, before it starts
+//!
+//! A conversion running out of space fails late, after the container
+//! converted every page, and leaves a partial PDF behind. The space it needs
+//! is estimated from the size of the document: the number of pages isn't
+//! known until the container converted them, so the estimate assumes a page
+//! per [`INPUT_BYTES_PER_PAGE`] of input. Documents vary widely, so the
+//! estimate errs on the low side, only refusing conversions sure to fail.
+
+use crate::{replace_control_chars, ConversionOptions};
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+/// Bytes of input assumed to make a page
+const INPUT_BYTES_PER_PAGE: u64 = 64 << 10;
+
+/// How much smaller a page of the safe PDF is than its pixels
+const PDF_COMPRESSION_RATIO: u64 = 10;
+
+/// Width and height of an A4 page, in inches
+const PAGE_INCHES: (f32, f32) = (8.27, 11.69);
+
+/// Space a conversion is expected to need
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Estimate {
+    /// Bytes written to the temporary directory: the spooled pixels and the
+    /// intermediate PDFs of OCR and linearization
+    pub(crate) temp: u64,
+    /// Bytes of the safe PDF
+    pub(crate) output: u64,
+}
+
+/// Estimate the space the conversion of a document of `input_bytes` needs
+pub(crate) fn estimate(input_bytes: u64, options: &ConversionOptions) -> Estimate {
+    // Saturating, as a huge DPI would overflow
+    let page_pixels = ((PAGE_INCHES.0 * options.dpi).round() as u64)
+        .saturating_mul((PAGE_INCHES.1 * options.dpi).round() as u64)
+        .saturating_mul(3);
+    let max_pages = (options.max_output_bytes / page_pixels.max(1)).max(1);
+    let pages = (input_bytes / INPUT_BYTES_PER_PAGE).clamp(1, max_pages);
+    let pixels = pages.saturating_mul(page_pixels);
+    let output = pixels / PDF_COMPRESSION_RATIO;
+    let mut temp = 0;
+    if let Some(threshold) = options.spool_threshold() {
+        temp = pixels.saturating_sub(threshold);
+    }
+    if options.ocr {
+        // The PDF before OCR, then ocrmypdf's own copy
+        temp = temp.saturating_add(output.saturating_mul(2));
+    }
+    if options.linearize {
+        temp = temp.saturating_add(output);
+    }
+    Estimate { temp, output }
+}
+
+/// Fail if the temporary directory or the directory of `output_path` lacks
+/// the space the conversion of `input_path` is expected to need
+///
+/// Space that can't be read, as on platforms other than Unix, passes.
+pub(crate) fn check(
+    input_path: &str,
+    output_path: &str,
+    options: &ConversionOptions,
+) -> Result<()> {
+    let Ok(metadata) = std::fs::metadata(input_path) else {
+        // The conversion reports missing inputs itself
+        return Ok(());
+    };
+    let estimate = estimate(metadata.len(), options);
+    let output_dir = match Path::new(output_path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let temp_dir = std::env::temp_dir();
+    if same_filesystem(&temp_dir, &output_dir) {
+        return require(&output_dir, estimate.temp + estimate.output);
+    }
+    require(&temp_dir, estimate.temp)?;
+    require(&output_dir, estimate.output)
+}
+
+/// Fail if `dir` has less than `bytes` available
+fn require(dir: &Path, bytes: u64) -> Result<()> {
+    let Some(available) = available_bytes(dir) else {
+        return Ok(());
+    };
+    if available < bytes {
+        anyhow::bail!(
+            "Not enough space in {dir_sanitized} for the conversion: it needs about {needed} MiB, \
+             and {available} MiB are available",
+            dir_sanitized = replace_control_chars(&dir.to_string_lossy(), false),
+            needed = bytes.div_ceil(1 << 20),
+            available = available >> 20
+        );
+    }
+    Ok(())
+}
+
+/// Whether `a` and `b` are on the same filesystem, so their requirements
+/// add up
+fn same_filesystem(a: &Path, b: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match (std::fs::metadata(a), std::fs::metadata(b)) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev(),
+            _ => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (a, b);
+        false
+    }
+}
+
+/// Bytes available to this user in the filesystem of `dir`
+#[cfg(unix)]
+fn available_bytes(dir: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+    // SAFETY: statvfs only writes to `stat`, and `path` is a valid C string
+    let stat = unsafe {
+        let mut stat: libc::statvfs = std::mem::zeroed();
+        if libc::statvfs(path.as_ptr(), &mut stat) != 0 {
+            return None;
+        }
+        stat
+    };
+    // The widths of these fields vary between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Some((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn available_bytes(_dir: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate() {
+        let options = ConversionOptions::default();
+        // An A4 page at 150 DPI is 1241x1753 pixels, once rounded
+        let page = 1241 * 1753 * 3;
+        assert_eq!(
+            estimate(1000, &options),
+            Estimate {
+                temp: 0,
+                output: page / 10
+            }
+        );
+        let pages = estimate(640 << 10, &options);
+        assert_eq!(pages.output, 10 * page / 10);
+
+        let options = ConversionOptions {
+            ocr: true,
+            spool_threshold_bytes: Some(page),
+            ..ConversionOptions::default()
+        };
+        assert_eq!(
+            estimate(640 << 10, &options),
+            Estimate {
+                temp: 9 * page + 2 * (10 * page / 10),
+                output: 10 * page / 10
+            }
+        );
+
+        // The output cap bounds the pages
+        let options = ConversionOptions {
+            max_output_bytes: 2 * page,
+            ..ConversionOptions::default()
+        };
+        assert_eq!(estimate(u64::MAX, &options).output, 2 * page / 10);
+
+        // A huge DPI saturates rather than overflows
+        let options = ConversionOptions {
+            dpi: 1e10,
+            ocr: true,
+            linearize: true,
+            spool_threshold_bytes: Some(0),
+            ..ConversionOptions::default()
+        };
+        assert_eq!(
+            estimate(u64::MAX, &options),
+            Estimate {
+                temp: u64::MAX,
+                output: u64::MAX / 10
+            }
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("report.docx");
+        std::fs::write(&input, "hello").unwrap();
+        let output = dir.path().join("report.pdf");
+        let input = input.to_string_lossy();
+        let output = output.to_string_lossy();
+        check(&input, &output, &ConversionOptions::default()).unwrap();
+
+        #[cfg(unix)]
+        {
+            let available = available_bytes(dir.path()).unwrap();
+            let error = require(dir.path(), available.saturating_mul(2).max(1 << 40))
+                .unwrap_err()
+                .to_string();
+            assert!(error.starts_with("Not enough space in "), "{error}");
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Resampling of pages rendered at a higher resolution than needed
+//!
+//! Pages keep their size in the PDF: only the number of pixels embedded for
+//! them shrinks. Each page is resampled while it is written, so only one page
+//! is held in memory at a time, even for spooled documents.
+
+use crate::{page_size_pts, PageMetadata, PdfPage, PixelFormat};
+use image::imageops::{self, FilterType};
+use image::{ImageBuffer, Luma, Pixel, Rgb, Rgba};
+use std::io::Write;
+
+/// Page resampled to at most a given resolution
+pub(crate) struct Downscaled<P> {
+    page: P,
+    /// New width and height, if the page is resampled
+    size: Option<(u16, u16)>,
+    size_pts: (f32, f32),
+}
+
+impl<P: PdfPage> Downscaled<P> {
+    /// Resample `page`, rendered at `dpi` unless the converter reported its
+    /// size, if it has more than `max_dpi` pixels per inch
+    pub(crate) fn new(page: P, dpi: f32, max_dpi: f32) -> Self {
+        let size_pts = page_size_pts(&page, dpi);
+        let page_dpi = f32::max(
+            page.width() as f32 * 72.0 / size_pts.0,
+            page.height() as f32 * 72.0 / size_pts.1,
+        );
+        let size = (page_dpi > max_dpi).then(|| {
+            let scale = |pixels: u16| ((pixels as f32 * max_dpi / page_dpi).round() as u16).max(1);
+            (scale(page.width()), scale(page.height()))
+        });
+        Downscaled {
+            page,
+            size,
+            size_pts,
+        }
+    }
+}
+
+impl<P: PdfPage> PdfPage for Downscaled<P> {
+    fn width(&self) -> u16 {
+        self.size.map_or(self.page.width(), |size| size.0)
+    }
+
+    fn height(&self) -> u16 {
+        self.size.map_or(self.page.height(), |size| size.1)
+    }
+
+    fn format(&self) -> PixelFormat {
+        self.page.format()
+    }
+
+    fn metadata(&self) -> PageMetadata {
+        let metadata = self.page.metadata();
+        if self.size.is_none() {
+            return metadata;
+        }
+        PageMetadata {
+            size_pts: Some(self.size_pts),
+            ..metadata
+        }
+    }
+
+    fn write_pixels(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        let Some((width, height)) = self.size else {
+            return self.page.write_pixels(out);
+        };
+        let mut pixels = Vec::with_capacity(
+            self.page.width() as usize
+                * self.page.height() as usize
+                * self.format().bytes_per_pixel(),
+        );
+        self.page.write_pixels(&mut pixels)?;
+        let (old_width, old_height) = (self.page.width().into(), self.page.height().into());
+        let (width, height) = (width.into(), height.into());
+        let pixels = match self.format() {
+            PixelFormat::Rgb => resize::<Rgb<u8>>(pixels, old_width, old_height, width, height),
+            PixelFormat::Gray => resize::<Luma<u8>>(pixels, old_width, old_height, width, height),
+            PixelFormat::Rgba => resize::<Rgba<u8>>(pixels, old_width, old_height, width, height),
+        }?;
+        out.write_all(&pixels)
+    }
+}
+
+/// Resample pixels with a Lanczos filter, which keeps text sharp
+fn resize<Px: Pixel<Subpixel = u8> + 'static>(
+    pixels: Vec<u8>,
+    width: u32,
+    height: u32,
+    new_width: u32,
+    new_height: u32,
+) -> std::io::Result<Vec<u8>> {
+    let image = ImageBuffer::<Px, _>::from_raw(width, height, pixels)
+        .ok_or_else(|| std::io::Error::other("page has fewer pixels than its size"))?;
+    Ok(imageops::resize(&image, new_width, new_height, FilterType::Lanczos3).into_raw())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PageData;
+
+    #[test]
+    fn test_downscale_to_max_dpi() {
+        // 4x5 inch page at 300 DPI, without size from the converter
+        let page = PageData::with_format(1200, 1500, PixelFormat::Gray, vec![200; 1200 * 1500]);
+        let downscaled = Downscaled::new(&page, 300.0, 150.0);
+        assert_eq!((downscaled.width(), downscaled.height()), (600, 750));
+        assert_eq!(downscaled.metadata().size_pts, Some((288.0, 360.0)));
+
+        let mut pixels = Vec::new();
+        downscaled.write_pixels(&mut pixels).unwrap();
+        assert_eq!(pixels.len(), 600 * 750);
+        assert!(pixels.iter().all(|&pixel| pixel == 200));
+    }
+
+    #[test]
+    fn test_low_resolution_pages_are_untouched() {
+        let page = PageData::new(2, 1, vec![0, 0, 0, 255, 255, 255]);
+        let downscaled = Downscaled::new(&page, 150.0, 150.0);
+        assert_eq!((downscaled.width(), downscaled.height()), (2, 1));
+        assert_eq!(downscaled.metadata(), page.metadata);
+        let mut pixels = Vec::new();
+        downscaled.write_pixels(&mut pixels).unwrap();
+        assert_eq!(pixels, page.pixels[..]);
+    }
+
+    #[test]
+    fn test_reported_page_size_sets_resolution() {
+        // 600 pixels for a 1-inch square page is 600 DPI, whatever `dpi` says
+        let mut page = PageData::new(600, 600, vec![0; 600 * 600 * 3]);
+        page.metadata.size_pts = Some((72.0, 72.0));
+        let downscaled = Downscaled::new(&page, 150.0, 200.0);
+        assert_eq!((downscaled.width(), downscaled.height()), (200, 200));
+        assert_eq!(downscaled.metadata().size_pts, Some((72.0, 72.0)));
+    }
+}
@@ -0,0 +1,496 @@
+//! Emails (.eml and .msg) converted part by part
+//!
+//! The email is only taken apart on the host: its MIME structure, or the
+//! compound file of an Outlook .msg, is parsed to find the body and the
+//! attachments, whose contents are never interpreted. The body, under the
+//! main headers, becomes a plain text document, and each part is converted
+//! in the sandbox like any other document, either to a safe PDF of its own
+//! or into a single safe PDF for the whole email.
+
+use crate::{
+    check_options, conversion_temp_dir, convert_batch, pipeline, placeholder,
+    replace_control_chars, safe_file_name, stream_pages, BatchResult, CancellationToken,
+    ConversionOptions, ConversionReport, PageData, PageStream, Progress,
+};
+use anyhow::{Context, Result};
+use cfb::CompoundFile;
+use log::{info, warn};
+use mail_parser::{Address, MessageParser, MimeHeaders};
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+/// First bytes of compound files, such as Outlook's .msg
+const COMPOUND_FILE_SIGNATURE: [u8; 8] = [0xD0, 0xCF, 0x11, 0xE0, 0xA1, 0xB1, 0x1A, 0xE1];
+
+/// Storage of each attachment of a .msg, followed by its number
+const MSG_ATTACHMENT_PREFIX: &str = "__attach_version1.0_#";
+
+/// Storage of a message attached to a .msg
+const MSG_EMBEDDED_MESSAGE: &str = "__substg1.0_3701000D";
+
+/// Contents of an attachment of a .msg
+const MSG_ATTACHMENT_DATA: &str = "__substg1.0_37010102";
+
+/// Properties of a .msg read as strings, by their tag
+const MSG_SUBJECT: &str = "0037";
+const MSG_SENDER_NAME: &str = "0C1A";
+const MSG_SENDER_ADDRESS: &str = "5D01";
+const MSG_DISPLAY_TO: &str = "0E04";
+const MSG_DISPLAY_CC: &str = "0E03";
+const MSG_BODY: &str = "1000";
+const MSG_ATTACHMENT_LONG_NAME: &str = "3707";
+const MSG_ATTACHMENT_NAME: &str = "3704";
+const MSG_DISPLAY_NAME: &str = "3001";
+
+/// A document found in an email, converted on its own
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EmailPart {
+    /// File name of the part, made safe to write
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// Whether the file at `path` is an email, from its extension
+pub fn is_email(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            extension.eq_ignore_ascii_case("eml") || extension.eq_ignore_ascii_case("msg")
+        })
+}
+
+/// The body and attachments of the email at `path`, .eml or .msg
+///
+/// The body comes first, as plain text under the main headers, then each
+/// attachment. Attached emails are taken apart the same way.
+pub fn email_parts(path: &str) -> Result<Vec<EmailPart>> {
+    let path_sanitized = replace_control_chars(path, false);
+    let data = std::fs::read(path).context(format!("Failed to read email '{path_sanitized}'"))?;
+    let stem = Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut parts = Vec::new();
+    if data.starts_with(&COMPOUND_FILE_SIGNATURE) {
+        let mut msg = CompoundFile::open(Cursor::new(data))
+            .context(format!("Failed to read Outlook message '{path_sanitized}'"))?;
+        msg_parts(&mut msg, Path::new("/"), &stem, &mut parts)?;
+    } else {
+        eml_parts(&data, &stem, &mut parts)
+            .context(format!("Failed to parse email '{path_sanitized}'"))?;
+    }
+    info!("Found {} part(s) in {path_sanitized}", parts.len());
+    Ok(parts)
+}
+
+/// Add the parts of the MIME message `data` to `parts`, naming its body
+/// after `stem`
+fn eml_parts(data: &[u8], stem: &str, parts: &mut Vec<EmailPart>) -> Result<()> {
+    let message = MessageParser::default()
+        .parse(data)
+        .context("Not a MIME message")?;
+    let addresses = |address: Option<&Address>| {
+        address.map(|address| {
+            address
+                .iter()
+                .map(|addr| match (addr.name(), addr.address()) {
+                    (Some(name), Some(address)) => format!("{name} <{address}>"),
+                    (name, address) => name.or(address).unwrap_or_default().to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+    };
+    let headers = [
+        ("From", addresses(message.from())),
+        ("To", addresses(message.to())),
+        ("Cc", addresses(message.cc())),
+        ("Date", message.date().map(|date| date.to_rfc822())),
+        ("Subject", message.subject().map(str::to_string)),
+    ];
+    // HTML bodies are turned into text, so that nothing of them is rendered
+    let body: Vec<String> = (0..message.text_body_count())
+        .filter_map(|index| message.body_text(index))
+        .map(|text| text.into_owned())
+        .collect();
+    parts.push(body_part(stem, &headers, &body.join("\n\n")));
+
+    for (index, attachment) in message.attachments().enumerate() {
+        let name = attachment.attachment_name();
+        match attachment.message() {
+            Some(_) => {
+                let stem = safe_file_name(name, &format!("message-{}", index + 1));
+                eml_parts(attachment.contents(), file_stem(&stem), parts)?;
+            }
+            None => parts.push(EmailPart {
+                name: safe_file_name(name, &format!("attachment-{}", index + 1)),
+                data: attachment.contents().to_vec(),
+            }),
+        }
+    }
+    Ok(())
+}
+
+/// Add the parts of the .msg message stored at `storage` to `parts`,
+/// naming its body after `stem`
+fn msg_parts<F: Read + std::io::Seek>(
+    msg: &mut CompoundFile<F>,
+    storage: &Path,
+    stem: &str,
+    parts: &mut Vec<EmailPart>,
+) -> Result<()> {
+    let sender = match (
+        msg_string(msg, storage, MSG_SENDER_NAME),
+        msg_string(msg, storage, MSG_SENDER_ADDRESS),
+    ) {
+        (Some(name), Some(address)) if name != address => Some(format!("{name} <{address}>")),
+        (name, address) => name.or(address),
+    };
+    let headers = [
+        ("From", sender),
+        ("To", msg_string(msg, storage, MSG_DISPLAY_TO)),
+        ("Cc", msg_string(msg, storage, MSG_DISPLAY_CC)),
+        ("Subject", msg_string(msg, storage, MSG_SUBJECT)),
+    ];
+    let body = msg_string(msg, storage, MSG_BODY).unwrap_or_default();
+    parts.push(body_part(stem, &headers, &body));
+
+    let mut attachments: Vec<PathBuf> = msg
+        .read_storage(storage)
+        .context("Failed to read the attachments of the Outlook message")?
+        .filter(|entry| entry.is_storage() && entry.name().starts_with(MSG_ATTACHMENT_PREFIX))
+        .map(|entry| entry.path().to_path_buf())
+        .collect();
+    attachments.sort();
+    for (index, attachment) in attachments.iter().enumerate() {
+        let name = msg_string(msg, attachment, MSG_ATTACHMENT_LONG_NAME)
+            .or_else(|| msg_string(msg, attachment, MSG_ATTACHMENT_NAME))
+            .or_else(|| msg_string(msg, attachment, MSG_DISPLAY_NAME));
+        let embedded = attachment.join(MSG_EMBEDDED_MESSAGE);
+        if msg.is_storage(&embedded) {
+            let stem = safe_file_name(name.as_deref(), &format!("message-{}", index + 1));
+            msg_parts(msg, &embedded, file_stem(&stem), parts)?;
+            continue;
+        }
+        let Ok(mut stream) = msg.open_stream(attachment.join(MSG_ATTACHMENT_DATA)) else {
+            warn!(
+                "Skipping attachment {} of the Outlook message, which has no contents",
+                index + 1
+            );
+            continue;
+        };
+        let mut data = Vec::new();
+        stream
+            .read_to_end(&mut data)
+            .context("Failed to read an attachment of the Outlook message")?;
+        parts.push(EmailPart {
+            name: safe_file_name(name.as_deref(), &format!("attachment-{}", index + 1)),
+            data,
+        });
+    }
+    Ok(())
+}
+
+/// The string property `tag` of the .msg storage `storage`, stored either in
+/// UTF-16 or in an 8-bit encoding
+fn msg_string<F: Read + std::io::Seek>(
+    msg: &mut CompoundFile<F>,
+    storage: &Path,
+    tag: &str,
+) -> Option<String> {
+    let mut read = |kind: &str| {
+        let mut stream = msg
+            .open_stream(storage.join(format!("__substg1.0_{tag}{kind}")))
+            .ok()?;
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).ok()?;
+        Some(data)
+    };
+    let text = if let Some(data) = read("001F") {
+        let units: Vec<u16> = data
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    } else {
+        String::from_utf8_lossy(&read("001E")?).into_owned()
+    };
+    let text = text.trim_end_matches('\0').trim().to_string();
+    (!text.is_empty()).then_some(text)
+}
+
+/// The body of an email as a text document, under its headers
+fn body_part(stem: &str, headers: &[(&str, Option<String>)], body: &str) -> EmailPart {
+    let mut text = String::new();
+    for (name, value) in headers {
+        if let Some(value) = value {
+            text.push_str(&format!("{name}: {value}\n"));
+        }
+    }
+    text.push('\n');
+    text.push_str(body);
+    text.push('\n');
+    EmailPart {
+        name: safe_file_name(Some(&format!("{stem}.txt")), "email.txt"),
+        data: text.into_bytes(),
+    }
+}
+
+fn file_stem(name: &str) -> &str {
+    Path::new(name)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(name)
+}
+
+/// Write each part to a directory of its own in `dir`, returning their paths
+fn write_parts(parts: &[EmailPart], dir: &Path) -> Result<Vec<String>> {
+    parts
+        .iter()
+        .enumerate()
+        .map(|(index, part)| {
+            let part_dir = dir.join(index.to_string());
+            std::fs::create_dir(&part_dir).context("Failed to create directory for email part")?;
+            let path = part_dir.join(&part.name);
+            std::fs::write(&path, &part.data).context("Failed to write email part")?;
+            Ok(path.to_string_lossy().into_owned())
+        })
+        .collect()
+}
+
+/// Convert each part of the email at `input_path` to a safe PDF of its own
+/// in `output_dir`, as [`convert_batch`] does
+///
+/// The body is named after the email, e.g. `invoice-safe.pdf` for
+/// `invoice.eml`, and attachments after their own names.
+pub fn convert_email(
+    input_path: &str,
+    output_dir: &str,
+    jobs: usize,
+    options: &ConversionOptions,
+    cancel: &CancellationToken,
+) -> Result<Vec<BatchResult>> {
+    check_options(options)?;
+    let parts = email_parts(input_path)?;
+    let temp_dir = conversion_temp_dir()?;
+    let paths = write_parts(&parts, temp_dir.path())?;
+    let mut results = convert_batch(&paths, output_dir, jobs, options, cancel)?;
+    // Report the parts by their names, rather than by their temporary files
+    for (result, part) in results.iter_mut().zip(&parts) {
+        result.input_path = part.name.clone();
+    }
+    Ok(results)
+}
+
+/// Convert every part of the email at `input_path` into a single safe PDF,
+/// the body first, then each attachment after a page naming it
+///
+/// The parts are converted one after the other, each in its own sandbox;
+/// the conversion fails if any of them does.
+pub fn convert_email_merged(
+    input_path: &str,
+    output_path: String,
+    options: &ConversionOptions,
+    progress: &dyn Fn(Progress),
+    cancel: &CancellationToken,
+) -> Result<ConversionReport> {
+    check_options(options)?;
+    pipeline::timed(input_path, progress, |progress| {
+        let parts = email_parts(input_path)?;
+        let temp_dir = conversion_temp_dir()?;
+        let paths = write_parts(&parts, temp_dir.path())?;
+        progress(Progress::ConvertingToPixels);
+        let pages = MergedPages {
+            parts: parts
+                .iter()
+                .map(|part| part.name.as_str())
+                .zip(paths)
+                .collect(),
+            next: 0,
+            stream: None,
+            options,
+            cancel,
+        };
+        pipeline::write_safe_pdf(
+            pages,
+            &|| None,
+            Some(input_path),
+            output_path,
+            options,
+            progress,
+            cancel,
+        )
+    })
+}
+
+/// Pages of each part in turn, converting the next part once the previous
+/// one is exhausted
+struct MergedPages<'a> {
+    /// Name and path of each part
+    parts: Vec<(&'a str, String)>,
+    next: usize,
+    stream: Option<(&'a str, PageStream)>,
+    options: &'a ConversionOptions,
+    cancel: &'a CancellationToken,
+}
+
+impl Iterator for MergedPages<'_> {
+    type Item = Result<PageData>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((name, stream)) = &mut self.stream {
+                let name = *name;
+                match stream.next() {
+                    Some(Ok(page)) => return Some(Ok(page)),
+                    Some(Err(e)) => {
+                        self.stream = None;
+                        self.next = self.parts.len();
+                        return Some(Err(e.context(format!(
+                            "Failed to convert '{name_sanitized}'",
+                            name_sanitized = replace_control_chars(name, false)
+                        ))));
+                    }
+                    None => self.stream = None,
+                }
+            }
+            let (name, path) = self.parts.get(self.next)?;
+            self.next += 1;
+            info!(
+                "Converting '{name_sanitized}'...",
+                name_sanitized = replace_control_chars(name, false)
+            );
+            match stream_pages(
+                path.clone(),
+                self.options,
+                self.cancel,
+                self.options.buffered_pages(),
+            ) {
+                Ok(stream) => {
+                    self.stream = Some((name, stream));
+                    // Each attachment starts with a page naming it
+                    if self.next > 1 {
+                        let title = format!("Attachment {}", self.next - 1);
+                        return Some(Ok(placeholder::sheet(&title, &[name.to_string()])));
+                    }
+                }
+                Err(e) => {
+                    self.next = self.parts.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const EML: &str = "From: Alice <alice@example.com>\r\n\
+        To: bob@example.com\r\n\
+        Subject: Invoice\r\n\
+        MIME-Version: 1.0\r\n\
+        Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+        \r\n\
+        --b\r\n\
+        Content-Type: text/html\r\n\
+        \r\n\
+        <p>Please <b>pay</b></p>\r\n\
+        --b\r\n\
+        Content-Type: application/pdf\r\n\
+        Content-Disposition: attachment; filename=\"../../etc/in voice?.pdf\"\r\n\
+        Content-Transfer-Encoding: base64\r\n\
+        \r\n\
+        JVBERi0=\r\n\
+        --b\r\n\
+        Content-Type: message/rfc822\r\n\
+        Content-Disposition: attachment; filename=\"fwd.eml\"\r\n\
+        \r\n\
+        Subject: Forwarded\r\n\
+        \r\n\
+        Inner body\r\n\
+        --b--\r\n";
+
+    #[test]
+    fn test_is_email() {
+        assert!(is_email("inbox/Invoice.EML"));
+        assert!(is_email("message.msg"));
+        assert!(!is_email("document.pdf"));
+        assert!(!is_email("eml"));
+    }
+
+    #[test]
+    fn test_eml_parts() {
+        let mut parts = Vec::new();
+        eml_parts(EML.as_bytes(), "invoice", &mut parts).unwrap();
+        let names: Vec<&str> = parts.iter().map(|part| part.name.as_str()).collect();
+        assert_eq!(names, ["invoice.txt", "in voice_.pdf", "fwd.txt"]);
+
+        let body = String::from_utf8(parts[0].data.clone()).unwrap();
+        assert!(body.starts_with(
+            "From: Alice <alice@example.com>\nTo: bob@example.com\nSubject: Invoice\n\n"
+        ));
+        assert!(body.contains("Please pay"));
+        assert!(!body.contains("<b>"));
+        assert_eq!(parts[1].data, b"%PDF-");
+        let forwarded = String::from_utf8(parts[2].data.clone()).unwrap();
+        assert_eq!(forwarded, "Subject: Forwarded\n\nInner body\n");
+    }
+
+    #[test]
+    fn test_msg_parts() {
+        let utf16 =
+            |text: &str| -> Vec<u8> { text.encode_utf16().flat_map(u16::to_le_bytes).collect() };
+        let mut msg = CompoundFile::create(Cursor::new(Vec::new())).unwrap();
+        let write = |msg: &mut CompoundFile<_>, path: &str, data: &[u8]| {
+            msg.create_stream(path).unwrap().write_all(data).unwrap();
+        };
+        write(&mut msg, "/__substg1.0_0037001F", &utf16("Report"));
+        write(&mut msg, "/__substg1.0_0C1A001F", &utf16("Alice"));
+        write(&mut msg, "/__substg1.0_1000001E", b"See attached\0");
+        msg.create_storage("/__attach_version1.0_#00000000")
+            .unwrap();
+        write(
+            &mut msg,
+            "/__attach_version1.0_#00000000/__substg1.0_3707001F",
+            &utf16("notes.docx"),
+        );
+        write(
+            &mut msg,
+            "/__attach_version1.0_#00000000/__substg1.0_37010102",
+            b"PK",
+        );
+        msg.create_storage("/__attach_version1.0_#00000001")
+            .unwrap();
+        msg.create_storage("/__attach_version1.0_#00000001/__substg1.0_3701000D")
+            .unwrap();
+        write(
+            &mut msg,
+            "/__attach_version1.0_#00000001/__substg1.0_3701000D/__substg1.0_1000001F",
+            &utf16("Nested"),
+        );
+
+        let mut parts = Vec::new();
+        msg_parts(&mut msg, Path::new("/"), "report", &mut parts).unwrap();
+        assert_eq!(
+            parts,
+            [
+                EmailPart {
+                    name: "report.txt".to_string(),
+                    data: b"From: Alice\nSubject: Report\n\nSee attached\n".to_vec(),
+                },
+                EmailPart {
+                    name: "notes.docx".to_string(),
+                    data: b"PK".to_vec(),
+                },
+                EmailPart {
+                    name: "message-2.txt".to_string(),
+                    data: b"\nNested\n".to_vec(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,279 @@
+//! Cleanup of poorly scanned pages
+//!
+//! Faded, grey or yellowed scans are hard to read, for people and for OCR
+//! alike. Each step only looks at the page itself: specks are removed first
+//! so that they don't skew the tones the other steps measure, then the paper
+//! is made white, and finally the tones are stretched to the full range.
+
+use crate::blank::luminance;
+use crate::{PageCleanup, PageMetadata, PdfPage, PixelFormat};
+use std::io::Write;
+
+/// Share of the pixels, per 1000, clipped to black and to white by
+/// auto-contrast, so that a few outliers don't prevent stretching
+const CLIP_PER_1000: u64 = 5;
+
+/// Tone range below which auto-contrast leaves a page alone, as stretching it
+/// would only amplify the noise of a blank page
+const MIN_CONTRAST: u8 = 32;
+
+/// Largest luminance difference between pixels of the same stroke or of the
+/// same paper
+const TOLERANCE: u8 = 64;
+
+/// Page whose pixels are cleaned up while they are written
+pub(crate) struct Cleaned<'a, P> {
+    pub(crate) page: P,
+    pub(crate) cleanup: &'a PageCleanup,
+}
+
+impl<P: PdfPage> PdfPage for Cleaned<'_, P> {
+    fn width(&self) -> u16 {
+        self.page.width()
+    }
+
+    fn height(&self) -> u16 {
+        self.page.height()
+    }
+
+    fn format(&self) -> PixelFormat {
+        self.page.format()
+    }
+
+    fn metadata(&self) -> PageMetadata {
+        self.page.metadata()
+    }
+
+    fn write_pixels(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        if !self.cleanup.is_enabled() {
+            return self.page.write_pixels(out);
+        }
+        let format = self.format();
+        let width = self.width() as usize;
+        let mut pixels =
+            Vec::with_capacity(width * self.height() as usize * format.bytes_per_pixel());
+        self.page.write_pixels(&mut pixels)?;
+
+        if self.cleanup.despeckle {
+            pixels = despeckle(&pixels, width, format);
+        }
+        if self.cleanup.normalize_white {
+            normalize_white(&mut pixels, format);
+        }
+        if self.cleanup.auto_contrast {
+            auto_contrast(&mut pixels, format);
+        }
+        out.write_all(&pixels)
+    }
+}
+
+/// Number of color channels, which leaves out alpha
+fn color_channels(format: PixelFormat) -> usize {
+    match format {
+        PixelFormat::Gray => 1,
+        PixelFormat::Rgb | PixelFormat::Rgba => 3,
+    }
+}
+
+/// Apply `table` to the color channels of each pixel
+fn map_colors(pixels: &mut [u8], format: PixelFormat, table: &[[u8; 256]]) {
+    let channels = color_channels(format);
+    for pixel in pixels.chunks_exact_mut(format.bytes_per_pixel()) {
+        for (value, table) in pixel[..channels].iter_mut().zip(table) {
+            *value = table[*value as usize];
+        }
+    }
+}
+
+fn histogram(pixels: &[u8], format: PixelFormat) -> [u64; 256] {
+    let mut counts = [0; 256];
+    for pixel in pixels.chunks_exact(format.bytes_per_pixel()) {
+        counts[luminance(format, pixel) as usize] += 1;
+    }
+    counts
+}
+
+/// Replace pixels that differ from all of their neighbours by the median of
+/// the neighbours, which removes lone dots but keeps strokes and their ends
+fn despeckle(pixels: &[u8], width: usize, format: PixelFormat) -> Vec<u8> {
+    let size = format.bytes_per_pixel();
+    let luma: Vec<u8> = pixels
+        .chunks_exact(size)
+        .map(|pixel| luminance(format, pixel))
+        .collect();
+    let height = luma.len() / width.max(1);
+    let mut cleaned = pixels.to_vec();
+    let mut neighbours = Vec::with_capacity(8);
+
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            neighbours.clear();
+            for ny in y.saturating_sub(1)..=(y + 1).min(height - 1) {
+                for nx in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+                    if (nx, ny) != (x, y) {
+                        neighbours.push(ny * width + nx);
+                    }
+                }
+            }
+            let isolated = !neighbours.is_empty()
+                && neighbours
+                    .iter()
+                    .all(|&n| luma[n].abs_diff(luma[i]) > TOLERANCE);
+            if !isolated {
+                continue;
+            }
+            for channel in 0..color_channels(format) {
+                let mut values: Vec<u8> = neighbours
+                    .iter()
+                    .map(|&n| pixels[n * size + channel])
+                    .collect();
+                values.sort_unstable();
+                cleaned[i * size + channel] = values[values.len() / 2];
+            }
+        }
+    }
+    cleaned
+}
+
+/// Scale each color channel so that the paper, the most common light tone,
+/// becomes white
+fn normalize_white(pixels: &mut [u8], format: PixelFormat) {
+    let counts = histogram(pixels, format);
+    let Some(paper) = (128..256)
+        .filter(|&i| counts[i] > 0)
+        .max_by_key(|&i| counts[i])
+    else {
+        // Dark page without any paper showing
+        return;
+    };
+
+    let channels = color_channels(format);
+    let mut sums = [0u64; 3];
+    let mut count = 0u64;
+    for pixel in pixels.chunks_exact(format.bytes_per_pixel()) {
+        if (luminance(format, pixel) as usize).abs_diff(paper) <= TOLERANCE as usize / 4 {
+            for (sum, &value) in sums.iter_mut().zip(&pixel[..channels]) {
+                *sum += value as u64;
+            }
+            count += 1;
+        }
+    }
+
+    let tables: Vec<[u8; 256]> = sums[..channels]
+        .iter()
+        .map(|&sum| {
+            let white = (sum / count).max(1);
+            std::array::from_fn(|value| (value as u64 * 255 / white).min(255) as u8)
+        })
+        .collect();
+    map_colors(pixels, format, &tables);
+}
+
+/// Stretch the tones of the page so that its darkest and lightest pixels,
+/// short of a few outliers, become black and white
+fn auto_contrast(pixels: &mut [u8], format: PixelFormat) {
+    let counts = histogram(pixels, format);
+    let total: u64 = counts.iter().sum();
+    let clipped = total * CLIP_PER_1000 / 1000;
+
+    let level = |levels: &mut dyn Iterator<Item = usize>| {
+        let mut seen = 0;
+        for i in levels {
+            seen += counts[i];
+            if seen > clipped {
+                return i as u8;
+            }
+        }
+        0
+    };
+    let low = level(&mut (0..256));
+    let high = level(&mut (0..256).rev());
+    if high.saturating_sub(low) < MIN_CONTRAST {
+        return;
+    }
+
+    let range = (high - low) as u32;
+    let table: [u8; 256] = std::array::from_fn(|value| {
+        let value = (value as u32).clamp(low as u32, high as u32);
+        ((value - low as u32) * 255 / range) as u8
+    });
+    map_colors(pixels, format, &[table; 3][..color_channels(format)]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PageData;
+
+    fn cleaned(page: &PageData, cleanup: PageCleanup) -> Vec<u8> {
+        let mut pixels = Vec::new();
+        Cleaned {
+            page,
+            cleanup: &cleanup,
+        }
+        .write_pixels(&mut pixels)
+        .unwrap();
+        pixels
+    }
+
+    #[test]
+    fn test_despeckle_removes_lone_dots() {
+        // A dot, and a horizontal stroke that must survive
+        let mut pixels = vec![255; 7 * 5];
+        pixels[8] = 0;
+        pixels[7 * 3 + 2..7 * 3 + 6].fill(0);
+        let page = PageData::with_format(7, 5, PixelFormat::Gray, pixels.clone());
+        let despeckle = PageCleanup {
+            despeckle: true,
+            ..PageCleanup::default()
+        };
+
+        let mut expected = pixels;
+        expected[8] = 255;
+        assert_eq!(cleaned(&page, despeckle), expected);
+    }
+
+    #[test]
+    fn test_normalize_white() {
+        // Yellowed paper with black text
+        let mut pixels = [[240, 230, 190]; 10].concat();
+        pixels[..3].copy_from_slice(&[20, 20, 20]);
+        let page = PageData::new(10, 1, pixels);
+        let normalize_white = PageCleanup {
+            normalize_white: true,
+            ..PageCleanup::default()
+        };
+
+        let pixels = cleaned(&page, normalize_white);
+        assert_eq!(pixels[3..6], [255, 255, 255]);
+        assert!(pixels[..3].iter().all(|&value| value < 30));
+    }
+
+    #[test]
+    fn test_auto_contrast() {
+        // Faded scan: dark grey text on light grey paper
+        let pixels: Vec<u8> = (0..100).map(|i| if i < 20 { 90 } else { 180 }).collect();
+        let page = PageData::with_format(100, 1, PixelFormat::Gray, pixels);
+        let auto_contrast = PageCleanup {
+            auto_contrast: true,
+            ..PageCleanup::default()
+        };
+
+        let pixels = cleaned(&page, auto_contrast.clone());
+        assert_eq!(pixels[0], 0);
+        assert_eq!(pixels[99], 255);
+
+        // Nearly uniform pages are left alone
+        let page = PageData::with_format(2, 1, PixelFormat::Gray, vec![200, 210]);
+        assert_eq!(cleaned(&page, auto_contrast), [200, 210]);
+    }
+
+    #[test]
+    fn test_alpha_is_untouched() {
+        let pixels = [[50, 50, 50, 255], [200, 200, 200, 254]].concat();
+        let page = PageData::with_format(2, 1, PixelFormat::Rgba, pixels);
+        let pixels = cleaned(&page, PageCleanup::all());
+        assert_eq!((pixels[3], pixels[7]), (255, 254));
+    }
+}
@@ -0,0 +1,100 @@
+//! Errors of conversions, and their cancellation
+
+use crate::replace_control_chars;
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Error returned by a conversion stopped through its [`CancellationToken`]
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Conversion cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Error returned when the container writes more than
+/// [`ConversionOptions::max_output_bytes`](crate::ConversionOptions::max_output_bytes)
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct OutputTooLarge {
+    pub limit: u64,
+}
+
+impl std::fmt::Display for OutputTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Container output exceeded the limit of {} MiB. The document may be too large, or the converter compromised.",
+            self.limit >> 20
+        )
+    }
+}
+
+impl std::error::Error for OutputTooLarge {}
+
+/// Error returned, with
+/// [`ConversionOptions::keep_partial`](crate::ConversionOptions::keep_partial),
+/// when the conversion failed after some pages were received: their safe PDF
+/// was written to `path` instead of the output path
+///
+/// It is the context of the error that stopped the conversion.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct PartialConversion {
+    pub path: String,
+    /// Pages of the partial PDF
+    pub pages: usize,
+    /// Page that failed, from 1
+    pub failed_page: usize,
+}
+
+impl std::fmt::Display for PartialConversion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The conversion failed at page {}; the {} page(s) before it were written to the partial PDF '{}'",
+            self.failed_page,
+            self.pages,
+            replace_control_chars(&self.path, false)
+        )
+    }
+}
+
+impl std::error::Error for PartialConversion {}
+
+/// Handle used to cancel a running conversion from another thread
+///
+/// Clones share the same state, so one clone can be handed to the conversion
+/// while another is kept by the caller.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request cancellation. The conversion stops at the next checkpoint and
+    /// kills the container if it is still running
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        Ok(())
+    }
+}
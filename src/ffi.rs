@@ -0,0 +1,227 @@
+//! Stable C API for desktop applications linking the engine directly
+//!
+//! The matching header is generated by cbindgen into
+//! `include/dangerzone_rs.h` when building with the `ffi` feature.
+
+use crate::{
+    convert_document_with_options, CancellationToken, Cancelled, ConversionOptions, Progress,
+};
+use std::ffi::{c_char, c_void, CStr};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+/// Result of a call into the C API
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DzStatus {
+    Ok = 0,
+    /// A pointer argument was null or a path was not valid UTF-8
+    InvalidArgument = 1,
+    /// The conversion failed, see the error buffer for details
+    Error = 2,
+    /// The progress callback asked for the conversion to stop
+    Cancelled = 3,
+}
+
+/// Stage reported to a [`DzProgressCallback`]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DzStage {
+    ConvertingToPixels = 0,
+    PixelsReceived = 1,
+    WritingPage = 2,
+    ApplyingOcr = 3,
+    Done = 4,
+}
+
+/// Options for [`dz_convert_document`]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DzOptions {
+    /// Add a text layer to the safe PDF
+    pub ocr: bool,
+}
+
+/// Called for each conversion stage. `page` is 1-based and only set for
+/// `WritingPage`, `total_pages` is 0 until the page count is known. Return
+/// `false` to cancel the conversion.
+pub type DzProgressCallback = Option<
+    extern "C" fn(user_data: *mut c_void, stage: DzStage, page: u32, total_pages: u32) -> bool,
+>;
+
+/// Split a [`Progress`] into the arguments of a [`DzProgressCallback`]
+fn callback_args(progress: &Progress) -> (DzStage, u32, u32) {
+    match *progress {
+        Progress::ConvertingToPixels => (DzStage::ConvertingToPixels, 0, 0),
+        Progress::PixelsReceived { total_pages } => {
+            (DzStage::PixelsReceived, 0, total_pages as u32)
+        }
+        Progress::WritingPage { page, total_pages } => {
+            (DzStage::WritingPage, page as u32, total_pages as u32)
+        }
+        Progress::ApplyingOcr => (DzStage::ApplyingOcr, 0, 0),
+        Progress::Done => (DzStage::Done, 0, 0),
+    }
+}
+
+/// Version of the library, as a static NUL-terminated string
+#[no_mangle]
+pub extern "C" fn dz_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr().cast()
+}
+
+/// Convert the document at `input_path` to a safe PDF at `output_path`
+///
+/// Log messages are printed to stderr. `options` may be null to use the
+/// defaults. `progress` may be null;
+/// `user_data` is passed to it unchanged. On failure, a NUL-terminated error
+/// message is written to `error_buf` (truncated to `error_buf_len` bytes) if
+/// it is not null.
+///
+/// # Safety
+///
+/// `input_path` and `output_path` must be valid NUL-terminated strings,
+/// `options` must be null or point to a valid `DzOptions`, and `error_buf`
+/// must be null or point to at least `error_buf_len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn dz_convert_document(
+    input_path: *const c_char,
+    output_path: *const c_char,
+    options: *const DzOptions,
+    progress: DzProgressCallback,
+    user_data: *mut c_void,
+    error_buf: *mut c_char,
+    error_buf_len: usize,
+) -> DzStatus {
+    crate::logging::init_stderr();
+    let (status, message) = match convert(input_path, output_path, options, progress, user_data) {
+        Ok(()) => (DzStatus::Ok, String::new()),
+        Err((status, message)) => (status, message),
+    };
+    write_error(error_buf, error_buf_len, &message);
+    status
+}
+
+unsafe fn convert(
+    input_path: *const c_char,
+    output_path: *const c_char,
+    options: *const DzOptions,
+    progress: DzProgressCallback,
+    user_data: *mut c_void,
+) -> Result<(), (DzStatus, String)> {
+    let input_path = path_arg(input_path, "input_path")?;
+    let output_path = path_arg(output_path, "output_path")?;
+    let options = options.as_ref().copied().unwrap_or_default();
+    let options = ConversionOptions {
+        ocr: options.ocr,
+        ..ConversionOptions::default()
+    };
+
+    let cancel = CancellationToken::new();
+    let on_progress = |p: Progress| {
+        if let Some(callback) = progress {
+            let (stage, page, total_pages) = callback_args(&p);
+            if !callback(user_data, stage, page, total_pages) {
+                cancel.cancel();
+            }
+        }
+    };
+
+    // Unwinding into C is undefined behavior, so panics become errors
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        convert_document_with_options(input_path, output_path, &options, &on_progress, &cancel)
+    }));
+    match result {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) if e.is::<Cancelled>() => Err((DzStatus::Cancelled, e.to_string())),
+        Ok(Err(e)) => Err((DzStatus::Error, format!("{e:#}"))),
+        Err(_) => Err((DzStatus::Error, "Conversion panicked".to_string())),
+    }
+}
+
+unsafe fn path_arg(ptr: *const c_char, name: &str) -> Result<String, (DzStatus, String)> {
+    if ptr.is_null() {
+        return Err((DzStatus::InvalidArgument, format!("{name} is null")));
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map(str::to_owned)
+        .map_err(|_| {
+            (
+                DzStatus::InvalidArgument,
+                format!("{name} is not valid UTF-8"),
+            )
+        })
+}
+
+/// Copy `message` into a C buffer, truncating on a character boundary
+unsafe fn write_error(buf: *mut c_char, len: usize, message: &str) {
+    if buf.is_null() || len == 0 {
+        return;
+    }
+    let mut end = message.len().min(len - 1);
+    while !message.is_char_boundary(end) {
+        end -= 1;
+    }
+    std::ptr::copy_nonoverlapping(message.as_ptr().cast(), buf, end);
+    *buf.add(end) = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    fn error_message(buf: &[c_char]) -> String {
+        unsafe { CStr::from_ptr(buf.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_ffi_null_arguments() {
+        let output = CString::new("out.pdf").unwrap();
+        let mut buf = [0 as c_char; 64];
+
+        let status = unsafe {
+            dz_convert_document(
+                std::ptr::null(),
+                output.as_ptr(),
+                std::ptr::null(),
+                None,
+                std::ptr::null_mut(),
+                buf.as_mut_ptr(),
+                buf.len(),
+            )
+        };
+        assert_eq!(status, DzStatus::InvalidArgument);
+        assert_eq!(error_message(&buf), "input_path is null");
+    }
+
+    #[test]
+    fn test_ffi_error_buffer_truncation() {
+        let mut buf = [0x7f as c_char; 5];
+        unsafe { write_error(buf.as_mut_ptr(), buf.len(), "café au lait") };
+        assert_eq!(error_message(&buf), "caf");
+
+        let mut buf = [0x7f as c_char; 32];
+        unsafe { write_error(buf.as_mut_ptr(), buf.len(), "short") };
+        assert_eq!(error_message(&buf), "short");
+    }
+
+    #[test]
+    fn test_header_is_up_to_date() {
+        let generated = include_str!(concat!(env!("OUT_DIR"), "/dangerzone_rs.h"));
+        let committed = include_str!("../include/dangerzone_rs.h");
+        assert!(
+            generated == committed,
+            "include/dangerzone_rs.h is out of date: copy it from {}",
+            concat!(env!("OUT_DIR"), "/dangerzone_rs.h")
+        );
+    }
+
+    #[test]
+    fn test_ffi_version() {
+        let version = unsafe { CStr::from_ptr(dz_version()) };
+        assert_eq!(version.to_str().unwrap(), env!("CARGO_PKG_VERSION"));
+    }
+}
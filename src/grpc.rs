@@ -0,0 +1,396 @@
+//! gRPC service exposing the conversion pipeline to other processes
+//!
+//! The service and its client stubs are generated from
+//! `proto/dangerzone.proto`. Documents are sent as raw bytes, written to a
+//! private temporary directory and converted with the same pipeline as the
+//! CLI. Conversions are bounded by the service's [`ServerLimits`].
+
+use crate::jobs::{self, JobQueue, JobState, QueueFull};
+use crate::limits::{RateLimiter, Reservation, ServerLimits, Slots};
+use crate::{
+    convert_document_with_options, CancellationToken, ConversionOptions, Progress, IMAGE_NAME,
+};
+use anyhow::Context;
+use std::net::SocketAddr;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tonic::{Request, Response, Status};
+
+/// Messages and stubs generated from `proto/dangerzone.proto`
+pub mod proto {
+    tonic::include_proto!("dangerzone.v1");
+}
+
+use proto::convert_event::Event;
+use proto::{
+    ConvertEvent, ConvertRequest, ConvertResponse, HealthRequest, HealthResponse, Job, JobRequest,
+    ListJobsRequest, ListJobsResponse,
+};
+
+pub use proto::dangerzone_client::DangerzoneClient;
+pub use proto::dangerzone_server::{Dangerzone, DangerzoneServer};
+
+/// Largest document or safe PDF accepted in a single gRPC message
+pub const MAX_MESSAGE_BYTES: usize = 256 * 1024 * 1024;
+
+/// Implementation of the `Dangerzone` gRPC service
+pub struct DangerzoneService {
+    jobs: JobQueue,
+    slots: Arc<Slots>,
+    rate: Option<RateLimiter>,
+    max_document_bytes: usize,
+}
+
+impl DangerzoneService {
+    /// Service queueing the jobs it is submitted in `jobs`, and running
+    /// direct conversions in `slots`, the slots of the queue's workers
+    pub fn new(jobs: JobQueue, slots: Arc<Slots>, limits: &ServerLimits) -> Self {
+        DangerzoneService {
+            jobs,
+            slots,
+            rate: limits.per_client_per_minute.map(RateLimiter::new),
+            max_document_bytes: limits.max_document_bytes,
+        }
+    }
+
+    /// Refuse documents over the size limit, and clients over their rate
+    /// limit
+    ///
+    /// Clients connected over a Unix socket have no address and no rate
+    /// limit.
+    fn admit(&self, request: &Request<ConvertRequest>) -> Result<(), Status> {
+        let size = request.get_ref().document.len();
+        if size > self.max_document_bytes {
+            return Err(Status::invalid_argument(format!(
+                "The document is {size} bytes, more than the {} bytes allowed",
+                self.max_document_bytes
+            )));
+        }
+        if let (Some(rate), Some(addr)) = (&self.rate, request.remote_addr()) {
+            rate.check(addr.ip()).map_err(|wait| {
+                Status::resource_exhausted(format!(
+                    "Too many conversions; retry in {} seconds",
+                    wait.as_secs().max(1)
+                ))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Take a place in the line for a conversion slot
+    fn reserve(&self) -> Result<Reservation, Status> {
+        self.slots
+            .reserve()
+            .ok_or_else(|| Status::resource_exhausted("Too many conversions are waiting"))
+    }
+
+    fn job(&self, id: &str) -> Result<jobs::Job, Status> {
+        self.jobs
+            .get(id)
+            .ok_or_else(|| Status::not_found(format!("No job {id}")))
+    }
+}
+
+impl From<Progress> for proto::Progress {
+    fn from(progress: Progress) -> Self {
+        use proto::progress::Stage;
+
+        let (stage, page, total_pages) = match progress {
+            Progress::ConvertingToPixels => (Stage::ConvertingToPixels, 0, 0),
+            Progress::PixelsReceived { total_pages } => (Stage::PixelsReceived, 0, total_pages),
+            Progress::WritingPage { page, total_pages } => (Stage::WritingPage, page, total_pages),
+            Progress::ApplyingOcr => (Stage::ApplyingOcr, 0, 0),
+            Progress::Done => (Stage::Done, 0, 0),
+        };
+        proto::Progress {
+            stage: stage as i32,
+            page: page as u32,
+            total_pages: total_pages as u32,
+        }
+    }
+}
+
+/// Convert an in-memory document and return the bytes of the safe PDF
+fn convert_bytes(
+    document: Vec<u8>,
+    ocr: bool,
+    progress: &dyn Fn(Progress),
+    cancel: &CancellationToken,
+) -> anyhow::Result<Vec<u8>> {
+    let dir = tempfile::tempdir().context("Failed to create temporary directory")?;
+    let input_path = dir.path().join("input");
+    let output_path = dir.path().join("safe.pdf");
+
+    let options = ConversionOptions {
+        ocr,
+        ..ConversionOptions::default()
+    };
+
+    std::fs::write(&input_path, document).context("Failed to write input document")?;
+    convert_document_with_options(
+        input_path.to_string_lossy().into_owned(),
+        output_path.to_string_lossy().into_owned(),
+        &options,
+        progress,
+        cancel,
+    )?;
+    std::fs::read(&output_path).context("Failed to read safe PDF")
+}
+
+impl From<jobs::Job> for Job {
+    fn from(job: jobs::Job) -> Self {
+        use proto::job::State;
+
+        let state = match job.state {
+            JobState::Queued => State::Queued,
+            JobState::Running => State::Running,
+            JobState::Succeeded => State::Succeeded,
+            JobState::Failed => State::Failed,
+            JobState::Cancelled => State::Cancelled,
+        };
+        Job {
+            id: job.id,
+            state: state as i32,
+            ocr: job.ocr,
+            created: job.created,
+            finished: job.finished.unwrap_or(0),
+            error: job.error.unwrap_or_default(),
+            progress: job.progress.map(Into::into),
+        }
+    }
+}
+
+fn to_status(err: anyhow::Error) -> Status {
+    Status::internal(format!("{err:#}"))
+}
+
+/// Whether podman is usable and the conversion image is present locally
+fn image_available() -> bool {
+    Command::new("podman")
+        .args(["image", "exists", IMAGE_NAME])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+#[tonic::async_trait]
+impl Dangerzone for DangerzoneService {
+    async fn convert(
+        &self,
+        request: Request<ConvertRequest>,
+    ) -> Result<Response<ConvertResponse>, Status> {
+        self.admit(&request)?;
+        let reservation = self.reserve()?;
+        let ConvertRequest { document, ocr } = request.into_inner();
+        let pdf = tokio::task::spawn_blocking(move || {
+            let _slot = reservation.wait();
+            convert_bytes(document, ocr, &|_| {}, &CancellationToken::new())
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(to_status)?;
+        Ok(Response::new(ConvertResponse { pdf }))
+    }
+
+    type ConvertStreamStream = UnboundedReceiverStream<Result<ConvertEvent, Status>>;
+
+    async fn convert_stream(
+        &self,
+        request: Request<ConvertRequest>,
+    ) -> Result<Response<Self::ConvertStreamStream>, Status> {
+        self.admit(&request)?;
+        let reservation = self.reserve()?;
+        let ConvertRequest { document, ocr } = request.into_inner();
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::task::spawn_blocking(move || {
+            let _slot = reservation.wait();
+            let cancel = CancellationToken::new();
+            let on_progress = |progress: Progress| {
+                let event = ConvertEvent {
+                    event: Some(Event::Progress(progress.into())),
+                };
+                // Stop converting once the client hung up
+                if tx.send(Ok(event)).is_err() {
+                    cancel.cancel();
+                }
+            };
+            let result = convert_bytes(document, ocr, &on_progress, &cancel);
+            let _ = tx.send(
+                result
+                    .map(|pdf| ConvertEvent {
+                        event: Some(Event::Result(ConvertResponse { pdf })),
+                    })
+                    .map_err(to_status),
+            );
+        });
+
+        Ok(Response::new(UnboundedReceiverStream::new(rx)))
+    }
+
+    async fn health(
+        &self,
+        _request: Request<HealthRequest>,
+    ) -> Result<Response<HealthResponse>, Status> {
+        use proto::health_response::Status as HealthStatus;
+
+        let status = match tokio::task::spawn_blocking(image_available).await {
+            Ok(true) => HealthStatus::Serving,
+            _ => HealthStatus::NotServing,
+        };
+        Ok(Response::new(HealthResponse {
+            status: status as i32,
+            image: IMAGE_NAME.to_string(),
+        }))
+    }
+
+    async fn submit_job(&self, request: Request<ConvertRequest>) -> Result<Response<Job>, Status> {
+        self.admit(&request)?;
+        let ConvertRequest { document, ocr } = request.into_inner();
+        let jobs = self.jobs.clone();
+        let job = tokio::task::spawn_blocking(move || jobs.submit(&document, ocr))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|e| match e.downcast_ref::<QueueFull>() {
+                Some(full) => Status::resource_exhausted(full.to_string()),
+                None => to_status(e),
+            })?;
+        Ok(Response::new(job.into()))
+    }
+
+    async fn get_job(&self, request: Request<JobRequest>) -> Result<Response<Job>, Status> {
+        let job = self.job(&request.into_inner().id)?;
+        Ok(Response::new(job.into()))
+    }
+
+    async fn list_jobs(
+        &self,
+        _request: Request<ListJobsRequest>,
+    ) -> Result<Response<ListJobsResponse>, Status> {
+        let jobs = self.jobs.list().into_iter().map(Into::into).collect();
+        Ok(Response::new(ListJobsResponse { jobs }))
+    }
+
+    async fn cancel_job(&self, request: Request<JobRequest>) -> Result<Response<Job>, Status> {
+        let id = request.into_inner().id;
+        let job = self
+            .jobs
+            .cancel(&id)
+            .map_err(to_status)?
+            .ok_or_else(|| Status::not_found(format!("No job {id}")))?;
+        Ok(Response::new(job.into()))
+    }
+
+    async fn get_job_result(
+        &self,
+        request: Request<JobRequest>,
+    ) -> Result<Response<ConvertResponse>, Status> {
+        let id = request.into_inner().id;
+        let job = self.job(&id)?;
+        let jobs = self.jobs.clone();
+        let pdf = tokio::task::spawn_blocking(move || jobs.result(&id))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(to_status)?
+            .ok_or_else(|| Status::failed_precondition(format!("Job {} didn't succeed", job.id)))?;
+        Ok(Response::new(ConvertResponse { pdf }))
+    }
+}
+
+/// Serve `service` on `addr` until the process is terminated
+pub fn serve(addr: SocketAddr, service: DangerzoneService) -> anyhow::Result<()> {
+    runtime()?.block_on(async {
+        log::info!("Serving gRPC on {addr}");
+        server(service)
+            .serve(addr)
+            .await
+            .context("gRPC server failed")
+    })
+}
+
+/// Serve `service` as a systemd daemon, on the socket systemd
+/// passed, or on `fallback` if the service wasn't socket activated
+///
+/// systemd is told once the service is ready, and regularly after that if
+/// its watchdog is on.
+#[cfg(target_os = "linux")]
+pub fn serve_systemd(fallback: SocketAddr, service: DangerzoneService) -> anyhow::Result<()> {
+    use crate::systemd::{self, Listener};
+    use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream};
+
+    let listener = match systemd::take_listener()? {
+        Some(listener) => listener,
+        None => Listener::Tcp(
+            std::net::TcpListener::bind(fallback)
+                .with_context(|| format!("Failed to listen on {fallback}"))?,
+        ),
+    };
+    runtime()?.block_on(async {
+        let server = server(service);
+        let served = match listener {
+            Listener::Tcp(listener) => {
+                listener.set_nonblocking(true)?;
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                let addr = listener.local_addr()?;
+                log::info!("Serving gRPC on {addr}");
+                systemd::notify(&format!("READY=1\nSTATUS=Serving gRPC on {addr}"))?;
+                systemd::start_watchdog();
+                server
+                    .serve_with_incoming(TcpListenerStream::new(listener))
+                    .await
+            }
+            Listener::Unix(listener) => {
+                listener.set_nonblocking(true)?;
+                let listener = tokio::net::UnixListener::from_std(listener)?;
+                log::info!("Serving gRPC on a Unix socket");
+                systemd::notify("READY=1\nSTATUS=Serving gRPC on a Unix socket")?;
+                systemd::start_watchdog();
+                server
+                    .serve_with_incoming(UnixListenerStream::new(listener))
+                    .await
+            }
+        };
+        let _ = systemd::notify("STOPPING=1");
+        served.context("gRPC server failed")
+    })
+}
+
+fn runtime() -> anyhow::Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start async runtime")
+}
+
+fn server(service: DangerzoneService) -> tonic::transport::server::Router {
+    tonic::transport::Server::builder().add_service(
+        DangerzoneServer::new(service)
+            .max_decoding_message_size(MAX_MESSAGE_BYTES)
+            .max_encoding_message_size(MAX_MESSAGE_BYTES),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::progress::Stage;
+
+    #[test]
+    fn test_progress_to_proto() {
+        let progress: proto::Progress = Progress::WritingPage {
+            page: 2,
+            total_pages: 5,
+        }
+        .into();
+        assert_eq!(progress.stage(), Stage::WritingPage);
+        assert_eq!(progress.page, 2);
+        assert_eq!(progress.total_pages, 5);
+
+        let progress: proto::Progress = Progress::Done.into();
+        assert_eq!(progress.stage(), Stage::Done);
+        assert_eq!(progress.total_pages, 0);
+    }
+}
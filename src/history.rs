@@ -0,0 +1,351 @@
+//! Local history of conversions, in an SQLite database
+//!
+//! Each conversion recorded adds a row: when it ran, the input and its
+//! SHA-256, the safe PDF written and its SHA-256, the options, as JSON, and
+//! the result.
+//! Looking up the hash of a file tells whether it was already sanitized, and
+//! where its safe PDF went, even if it was renamed or moved since.
+//!
+//! Unlike the [`audit_log`](crate::audit_log), the history isn't meant as
+//! evidence: it can be cleared, and isn't protected against edits.
+
+use crate::audit_log::{absolute, rfc3339, sha256_file};
+use crate::{replace_control_chars, ConversionOptions};
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS conversions (
+        id INTEGER PRIMARY KEY,
+        time TEXT NOT NULL,
+        input TEXT NOT NULL,
+        input_sha256 TEXT,
+        output TEXT NOT NULL,
+        options TEXT NOT NULL,
+        succeeded INTEGER NOT NULL,
+        error TEXT,
+        output_sha256 TEXT
+    );
+    CREATE INDEX IF NOT EXISTS conversions_input_sha256 ON conversions (input_sha256);
+";
+
+const COLUMNS: &str =
+    "id, time, input, input_sha256, output, options, succeeded, error, output_sha256";
+
+/// A conversion of the history
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub id: i64,
+    /// When the conversion started, as an RFC 3339 timestamp in UTC
+    pub time: String,
+    /// Absolute path of the document
+    pub input: String,
+    /// SHA-256 of the document when the conversion started, if it could be
+    /// read
+    pub input_sha256: Option<String>,
+    /// Absolute path of the safe PDF
+    pub output: String,
+    /// [`ConversionOptions`] of the conversion, as JSON
+    pub options: String,
+    pub succeeded: bool,
+    /// Why the conversion failed, if it did
+    pub error: Option<String>,
+    /// SHA-256 of the safe PDF once it was written, if the conversion
+    /// succeeded and the PDF could be read
+    pub output_sha256: Option<String>,
+}
+
+impl Entry {
+    fn from_row(row: &Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Entry {
+            id: row.get(0)?,
+            time: row.get(1)?,
+            input: row.get(2)?,
+            input_sha256: row.get(3)?,
+            output: row.get(4)?,
+            options: row.get(5)?,
+            succeeded: row.get(6)?,
+            error: row.get(7)?,
+            output_sha256: row.get(8)?,
+        })
+    }
+}
+
+/// History database
+pub struct History {
+    connection: Connection,
+}
+
+impl History {
+    /// Open the history at `path`, creating it and its directory if needed
+    pub fn open(path: &Path) -> Result<Self> {
+        let path_sanitized = replace_control_chars(&path.to_string_lossy(), false);
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).with_context(|| {
+                format!("Failed to create the directory of the history '{path_sanitized}'")
+            })?;
+        }
+        let connection = Connection::open(path)
+            .with_context(|| format!("Failed to open the history '{path_sanitized}'"))?;
+        // Conversions running at the same time wait for each other's writes
+        connection.busy_timeout(std::time::Duration::from_secs(5))?;
+        connection
+            .execute_batch(SCHEMA)
+            .with_context(|| format!("Failed to set up the history '{path_sanitized}'"))?;
+        // Histories created before the hashes of safe PDFs were recorded
+        let has_output_sha256: bool = connection.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('conversions') WHERE name = 'output_sha256'",
+            [],
+            |row| row.get(0),
+        )?;
+        if !has_output_sha256 {
+            connection
+                .execute_batch("ALTER TABLE conversions ADD COLUMN output_sha256 TEXT")
+                .with_context(|| format!("Failed to update the history '{path_sanitized}'"))?;
+        }
+        Ok(History { connection })
+    }
+
+    /// The last `limit` conversions, newest first
+    pub fn list(&self, limit: usize) -> Result<Vec<Entry>> {
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT {COLUMNS} FROM conversions ORDER BY id DESC LIMIT ?1"
+        ))?;
+        let entries = statement
+            .query_map([i64::try_from(limit).unwrap_or(i64::MAX)], Entry::from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(entries)
+    }
+
+    /// The conversion `id`, if the history has it
+    pub fn get(&self, id: i64) -> Result<Option<Entry>> {
+        let entry = self
+            .connection
+            .query_row(
+                &format!("SELECT {COLUMNS} FROM conversions WHERE id = ?1"),
+                [id],
+                Entry::from_row,
+            )
+            .optional()?;
+        Ok(entry)
+    }
+
+    /// The conversions of documents whose SHA-256 was `sha256`, newest first
+    pub fn find_by_sha256(&self, sha256: &str) -> Result<Vec<Entry>> {
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT {COLUMNS} FROM conversions WHERE input_sha256 = ?1 ORDER BY id DESC"
+        ))?;
+        let entries = statement
+            .query_map([sha256], Entry::from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(entries)
+    }
+
+    /// The conversions of the document at `path`, as it is now, newest first
+    pub fn find_file(&self, path: &Path) -> Result<Vec<Entry>> {
+        let sha256 = sha256_file(path).with_context(|| {
+            format!(
+                "Failed to read '{path_sanitized}'",
+                path_sanitized = replace_control_chars(&path.to_string_lossy(), false)
+            )
+        })?;
+        self.find_by_sha256(&sha256)
+    }
+
+    /// The last successful conversion of the document at `path`, as it is
+    /// now, with the same `options`, whose safe PDF is still there, unchanged
+    ///
+    /// Documents that can't be hashed, such as directories, have none.
+    pub fn previous_conversion(
+        &self,
+        path: &Path,
+        options: &ConversionOptions,
+    ) -> Result<Option<Entry>> {
+        let Ok(sha256) = sha256_file(path) else {
+            return Ok(None);
+        };
+        let options = options_json(options);
+        Ok(self.find_by_sha256(&sha256)?.into_iter().find(|entry| {
+            entry.succeeded
+                && entry.options == options
+                && entry.output_sha256.as_ref().is_some_and(|expected| {
+                    sha256_file(Path::new(&entry.output)).is_ok_and(|sha256| sha256 == *expected)
+                })
+        }))
+    }
+
+    /// Remove every conversion of the history, returning how many there were
+    pub fn clear(&self) -> Result<usize> {
+        Ok(self.connection.execute("DELETE FROM conversions", [])?)
+    }
+}
+
+/// A conversion, added to the history once it is over
+pub struct PendingEntry {
+    time: SystemTime,
+    input: String,
+    input_sha256: Option<String>,
+    output: String,
+    options: String,
+}
+
+impl PendingEntry {
+    /// Start recording the conversion of `input` to `output`, hashing the
+    /// input now, before the conversion reads it
+    pub fn start(input: &str, output: &str, options: &ConversionOptions) -> Self {
+        PendingEntry {
+            time: SystemTime::now(),
+            input: absolute(input),
+            input_sha256: sha256_file(Path::new(input)).ok(),
+            output: absolute(output),
+            options: options_json(options),
+        }
+    }
+
+    /// Add the conversion to `history`, with `error` if it failed, returning
+    /// its ID
+    pub fn finish(self, history: &History, error: Option<&anyhow::Error>) -> Result<i64> {
+        let output_sha256 = match error {
+            None => sha256_file(Path::new(&self.output)).ok(),
+            Some(_) => None,
+        };
+        history
+            .connection
+            .execute(
+                "INSERT INTO conversions
+                 (time, input, input_sha256, output, options, succeeded, error, output_sha256)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    rfc3339(self.time),
+                    self.input,
+                    self.input_sha256,
+                    self.output,
+                    self.options,
+                    error.is_none(),
+                    error.map(|e| format!("{e:#}")),
+                    output_sha256,
+                ],
+            )
+            .context("Failed to add the conversion to the history")?;
+        Ok(history.connection.last_insert_rowid())
+    }
+}
+
+/// `options` as recorded in the history
+fn options_json(options: &ConversionOptions) -> String {
+    serde_json::to_string(options).expect("options always serialize")
+}
+
+/// Default path of the history: in the XDG data directory
+pub fn default_path() -> Option<PathBuf> {
+    let data = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".local/share"),
+    };
+    Some(data.join("dangerzone-rs/history.sqlite3"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::open(&dir.path().join("db/history.sqlite3")).unwrap();
+        let input = dir.path().join("report.docx");
+        std::fs::write(&input, "hello").unwrap();
+        let input = input.to_string_lossy();
+        let options = ConversionOptions {
+            ocr: true,
+            ..ConversionOptions::default()
+        };
+
+        let first = PendingEntry::start(&input, "/safe/report.pdf", &options)
+            .finish(&history, Some(&anyhow::anyhow!("Container failed")))
+            .unwrap();
+        let second = PendingEntry::start(&input, "/safe/report-2.pdf", &options)
+            .finish(&history, None)
+            .unwrap();
+        let entry = history.get(second).unwrap().unwrap();
+        assert_eq!(entry.output, "/safe/report-2.pdf");
+        assert!(entry.succeeded);
+        assert!(entry.options.contains("\"ocr\":true"));
+        assert_eq!(
+            entry.input_sha256.as_deref(),
+            Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+        );
+        let failed = history.get(first).unwrap().unwrap();
+        assert_eq!(failed.error.as_deref(), Some("Container failed"));
+        assert!(history.get(second + 1).unwrap().is_none());
+
+        let ids = |entries: Vec<Entry>| entries.iter().map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(ids(history.list(10).unwrap()), [second, first]);
+        assert_eq!(ids(history.list(1).unwrap()), [second]);
+        assert_eq!(
+            ids(history.find_file(Path::new(input.as_ref())).unwrap()),
+            [second, first]
+        );
+        assert!(history.find_by_sha256("0").unwrap().is_empty());
+
+        // The safe PDFs of the conversions are gone
+        let previous = |options: &ConversionOptions| {
+            history
+                .previous_conversion(Path::new(input.as_ref()), options)
+                .unwrap()
+                .map(|entry| entry.id)
+        };
+        assert_eq!(previous(&options), None);
+        let output = dir.path().join("report.pdf");
+        std::fs::write(&output, "%PDF").unwrap();
+        let third = PendingEntry::start(&input, &output.to_string_lossy(), &options)
+            .finish(&history, None)
+            .unwrap();
+        assert_eq!(previous(&options), Some(third));
+        assert_eq!(
+            history.get(third).unwrap().unwrap().output_sha256,
+            Some(sha256_file(&output).unwrap())
+        );
+        assert_eq!(
+            history.previous_conversion(dir.path(), &options).unwrap(),
+            None
+        );
+
+        // Conversions with other options, or whose safe PDF changed since,
+        // are not reused
+        assert_eq!(previous(&ConversionOptions::default()), None);
+        std::fs::write(&output, "%PDF-changed").unwrap();
+        assert_eq!(previous(&options), None);
+
+        assert_eq!(history.clear().unwrap(), 3);
+        assert!(history.list(10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_old_history_is_updated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.sqlite3");
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE conversions (
+                    id INTEGER PRIMARY KEY,
+                    time TEXT NOT NULL,
+                    input TEXT NOT NULL,
+                    input_sha256 TEXT,
+                    output TEXT NOT NULL,
+                    options TEXT NOT NULL,
+                    succeeded INTEGER NOT NULL,
+                    error TEXT
+                );
+                INSERT INTO conversions (time, input, output, options, succeeded)
+                VALUES ('2024-01-01T00:00:00Z', '/a.pdf', '/a-safe.pdf', '{}', 1);",
+            )
+            .unwrap();
+        let history = History::open(&path).unwrap();
+        assert_eq!(history.get(1).unwrap().unwrap().output_sha256, None);
+        History::open(&path).unwrap();
+    }
+}
@@ -0,0 +1,700 @@
+//! OCR results as hOCR and ALTO, the XML formats of archival workflows
+//!
+//! Results are read from the hOCR that tesseract writes for each page, and
+//! written back as one hOCR or ALTO document for the whole safe PDF. Only
+//! the layout tesseract produces is kept: pages made of blocks, paragraphs,
+//! lines and words, with their bounding boxes in pixels.
+
+use anyhow::Result;
+use std::fmt::Write;
+
+/// Bounding box in pixels: left, top, right and bottom
+pub type BBox = [u32; 4];
+
+/// Words found on a page, in the coordinates of its pixels
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OcrPage {
+    pub bbox: BBox,
+    pub blocks: Vec<OcrBlock>,
+}
+
+/// Area of a page with text, such as a column
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OcrBlock {
+    pub bbox: BBox,
+    pub paragraphs: Vec<OcrParagraph>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OcrParagraph {
+    pub bbox: BBox,
+    /// Tesseract language of the paragraph, e.g. `eng`
+    pub lang: Option<String>,
+    pub lines: Vec<OcrLine>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OcrLine {
+    pub bbox: BBox,
+    /// Where the characters of the line sit, if the engine told
+    pub baseline: Option<Baseline>,
+    pub words: Vec<OcrWord>,
+}
+
+/// Baseline of a line, as tesseract reports it: `y = slope * x + offset`, in
+/// pixels from the bottom-left corner of the line, with `y` growing downwards
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Baseline {
+    pub slope: f32,
+    pub offset: f32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OcrWord {
+    pub bbox: BBox,
+    /// Confidence of the recognition, from 0 to 100
+    pub confidence: Option<f32>,
+    pub text: String,
+}
+
+/// Elements of the hOCR layout, by their `class`
+#[derive(Clone, Copy, PartialEq)]
+enum Element {
+    Page,
+    Block,
+    Paragraph,
+    Line,
+    Word,
+}
+
+impl Element {
+    fn from_class(class: &str) -> Option<Self> {
+        class.split_whitespace().find_map(|class| match class {
+            "ocr_page" => Some(Element::Page),
+            "ocr_carea" => Some(Element::Block),
+            "ocr_par" => Some(Element::Paragraph),
+            "ocr_line" | "ocr_header" | "ocr_caption" | "ocr_textfloat" => Some(Element::Line),
+            "ocrx_word" => Some(Element::Word),
+            _ => None,
+        })
+    }
+}
+
+/// HTML elements that have no end tag
+const VOID_ELEMENTS: [&str; 6] = ["br", "hr", "img", "input", "link", "meta"];
+
+/// Parse the pages of an hOCR document
+///
+/// Elements missing around a word, such as a line, are made up from the
+/// word's own bounding box. Markup the layout doesn't use is ignored.
+pub fn parse_hocr(document: &str) -> Result<Vec<OcrPage>> {
+    let mut pages: Vec<OcrPage> = Vec::new();
+    // The layout element opened by each open HTML element, if any
+    let mut open: Vec<Option<Element>> = Vec::new();
+    let mut rest = document;
+
+    while let Some(start) = rest.find('<') {
+        let text = &rest[..start];
+        if open.contains(&Some(Element::Word)) {
+            if let Some(word) = last_word(&mut pages) {
+                word.text.push_str(&unescape(text));
+            }
+        }
+        rest = &rest[start..];
+
+        // Comments, doctypes and processing instructions
+        if rest.starts_with("<!") || rest.starts_with("<?") {
+            let closing = if rest.starts_with("<!--") {
+                "-->"
+            } else if rest.starts_with("<?") {
+                "?>"
+            } else {
+                ">"
+            };
+            let end = rest[2..]
+                .find(closing)
+                .ok_or_else(|| anyhow::anyhow!("Unterminated markup in hOCR"))?;
+            rest = &rest[2 + end + closing.len()..];
+            continue;
+        }
+
+        let end = tag_end(rest).ok_or_else(|| anyhow::anyhow!("Unterminated tag in hOCR"))?;
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        if let Some(name) = tag.strip_prefix('/') {
+            let name = name.trim();
+            if !VOID_ELEMENTS.contains(&name.to_ascii_lowercase().as_str())
+                && open.pop() == Some(Some(Element::Word))
+            {
+                if let Some(word) = last_word(&mut pages) {
+                    word.text = word.text.trim().to_string();
+                }
+            }
+            continue;
+        }
+
+        let self_closing = tag.ends_with('/');
+        let tag = tag.trim_end_matches('/');
+        let name_end = tag
+            .find(|c: char| c.is_ascii_whitespace())
+            .unwrap_or(tag.len());
+        let name = tag[..name_end].to_ascii_lowercase();
+        let attributes = parse_attributes(&tag[name_end..]);
+        let attribute = |key: &str| {
+            attributes
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(key))
+                .map(|(_, value)| value.as_str())
+        };
+
+        let element = attribute("class").and_then(Element::from_class);
+        if let Some(element) = element {
+            let properties = attribute("title").unwrap_or_default();
+            let bbox = property(properties, "bbox")
+                .and_then(|values| parse_bbox(&values))
+                .unwrap_or_default();
+            open_element(&mut pages, element, bbox, properties, attribute("lang"));
+        }
+        if !self_closing && !VOID_ELEMENTS.contains(&name.as_str()) {
+            open.push(element);
+        }
+    }
+    Ok(pages)
+}
+
+/// Position of the `>` closing the tag at the start of `tag`, skipping those
+/// in quoted attribute values
+fn tag_end(tag: &str) -> Option<usize> {
+    let mut quote = None;
+    for (index, c) in tag.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '>') => return Some(index),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parse `name="value"` and `name='value'` attributes, unescaping values
+fn parse_attributes(mut attributes: &str) -> Vec<(String, String)> {
+    let mut parsed = Vec::new();
+    loop {
+        attributes = attributes.trim_start();
+        let Some(equals) = attributes.find('=') else {
+            break;
+        };
+        let name = attributes[..equals].trim();
+        let value = attributes[equals + 1..].trim_start();
+        let Some(quote) = value.chars().next().filter(|c| matches!(c, '"' | '\'')) else {
+            break;
+        };
+        let Some(end) = value[1..].find(quote) else {
+            break;
+        };
+        // Attributes without a value before this one are part of `name`
+        let name = name.rsplit(char::is_whitespace).next().unwrap_or(name);
+        parsed.push((name.to_string(), unescape(&value[1..end + 1])));
+        attributes = &value[end + 2..];
+    }
+    parsed
+}
+
+/// Values of the property `key` in an hOCR `title`, such as `bbox 0 0 10 10`
+fn property<'a>(properties: &'a str, key: &str) -> Option<Vec<&'a str>> {
+    properties.split(';').find_map(|property| {
+        let mut values = property.split_whitespace();
+        (values.next() == Some(key)).then(|| values.collect())
+    })
+}
+
+fn parse_bbox(values: &[&str]) -> Option<BBox> {
+    let [left, top, right, bottom] = values else {
+        return None;
+    };
+    Some([
+        left.parse().ok()?,
+        top.parse().ok()?,
+        right.parse().ok()?,
+        bottom.parse().ok()?,
+    ])
+}
+
+fn parse_baseline(values: &[&str]) -> Option<Baseline> {
+    let [slope, offset] = values else {
+        return None;
+    };
+    let baseline = Baseline {
+        slope: slope.parse().ok()?,
+        offset: offset.parse().ok()?,
+    };
+    (baseline.slope.is_finite() && baseline.offset.is_finite()).then_some(baseline)
+}
+
+/// Add a layout element to the last page, making up the elements missing
+/// between it and the page
+fn open_element(
+    pages: &mut Vec<OcrPage>,
+    element: Element,
+    bbox: BBox,
+    properties: &str,
+    lang: Option<&str>,
+) {
+    if element == Element::Page {
+        pages.push(OcrPage {
+            bbox,
+            blocks: Vec::new(),
+        });
+        return;
+    }
+    let Some(page) = pages.last_mut() else {
+        // Outside of any page, as in a fragment
+        pages.push(OcrPage::default());
+        return open_element(pages, element, bbox, properties, lang);
+    };
+
+    if element == Element::Block || page.blocks.is_empty() {
+        page.blocks.push(OcrBlock {
+            bbox,
+            paragraphs: Vec::new(),
+        });
+        if element == Element::Block {
+            return;
+        }
+    }
+    let block = page.blocks.last_mut().expect("a block was added");
+
+    if element == Element::Paragraph || block.paragraphs.is_empty() {
+        block.paragraphs.push(OcrParagraph {
+            bbox,
+            lang: lang.map(str::to_string),
+            lines: Vec::new(),
+        });
+        if element == Element::Paragraph {
+            return;
+        }
+    }
+    let paragraph = block.paragraphs.last_mut().expect("a paragraph was added");
+
+    if element == Element::Line || paragraph.lines.is_empty() {
+        paragraph.lines.push(OcrLine {
+            bbox,
+            baseline: (element == Element::Line)
+                .then(|| {
+                    property(properties, "baseline").and_then(|values| parse_baseline(&values))
+                })
+                .flatten(),
+            words: Vec::new(),
+        });
+        if element == Element::Line {
+            return;
+        }
+    }
+    let line = paragraph.lines.last_mut().expect("a line was added");
+
+    line.words.push(OcrWord {
+        bbox,
+        confidence: property(properties, "x_wconf").and_then(|values| values.first()?.parse().ok()),
+        text: String::new(),
+    });
+}
+
+fn last_word(pages: &mut [OcrPage]) -> Option<&mut OcrWord> {
+    pages
+        .last_mut()?
+        .blocks
+        .last_mut()?
+        .paragraphs
+        .last_mut()?
+        .lines
+        .last_mut()?
+        .words
+        .last_mut()
+}
+
+/// Replace the character references of HTML text; unknown ones are kept
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let reference = rest
+            .find(';')
+            .filter(|&end| end <= 10)
+            .and_then(|end| Some((end, character(&rest[1..end])?)));
+        match reference {
+            Some((end, c)) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+fn character(reference: &str) -> Option<char> {
+    match reference {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{a0}'),
+        _ => {
+            let code = reference.strip_prefix('#')?;
+            let code = match code.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => code.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+/// Escape text for XML content and double-quoted attribute values
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            // Not allowed in XML 1.0
+            c if c.is_control() && !matches!(c, '\t' | '\n' | '\r') => escaped.push('\u{fffd}'),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn format_bbox([left, top, right, bottom]: BBox) -> String {
+    format!("bbox {left} {top} {right} {bottom}")
+}
+
+/// Write `pages` as one hOCR document
+pub fn write_hocr(pages: &[OcrPage]) -> String {
+    let mut hocr = String::new();
+    hocr.push_str(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Transitional//EN\"\n",
+        "    \"http://www.w3.org/TR/xhtml1/DTD/xhtml1-transitional.dtd\">\n",
+        "<html xmlns=\"http://www.w3.org/1999/xhtml\">\n",
+        " <head>\n",
+        "  <title></title>\n",
+        "  <meta http-equiv=\"Content-Type\" content=\"text/html;charset=utf-8\"/>\n",
+    ));
+    let _ = writeln!(
+        hocr,
+        "  <meta name=\"ocr-system\" content=\"dangerzone-rs {}\"/>",
+        env!("CARGO_PKG_VERSION")
+    );
+    hocr.push_str(concat!(
+        "  <meta name=\"ocr-capabilities\" content=\"ocr_page ocr_carea ocr_par ocr_line ocrx_word ocrp_lang ocrp_wconf\"/>\n",
+        " </head>\n",
+        " <body>\n",
+    ));
+
+    for (page_index, page) in pages.iter().enumerate() {
+        let p = page_index + 1;
+        let _ = writeln!(
+            hocr,
+            "  <div class=\"ocr_page\" id=\"page_{p}\" title=\"{}; ppageno {page_index}\">",
+            format_bbox(page.bbox)
+        );
+        let (mut b, mut par, mut l, mut w) = (0, 0, 0, 0);
+        for block in &page.blocks {
+            b += 1;
+            let _ = writeln!(
+                hocr,
+                "   <div class=\"ocr_carea\" id=\"block_{p}_{b}\" title=\"{}\">",
+                format_bbox(block.bbox)
+            );
+            for paragraph in &block.paragraphs {
+                par += 1;
+                let lang = paragraph
+                    .lang
+                    .as_deref()
+                    .map(|lang| format!(" lang=\"{}\"", escape(lang)))
+                    .unwrap_or_default();
+                let _ = writeln!(
+                    hocr,
+                    "    <p class=\"ocr_par\" id=\"par_{p}_{par}\"{lang} title=\"{}\">",
+                    format_bbox(paragraph.bbox)
+                );
+                for line in &paragraph.lines {
+                    l += 1;
+                    let baseline = line
+                        .baseline
+                        .map(|Baseline { slope, offset }| format!("; baseline {slope} {offset}"))
+                        .unwrap_or_default();
+                    let _ = writeln!(
+                        hocr,
+                        "     <span class=\"ocr_line\" id=\"line_{p}_{l}\" title=\"{}{baseline}\">",
+                        format_bbox(line.bbox)
+                    );
+                    for word in &line.words {
+                        w += 1;
+                        let confidence = word
+                            .confidence
+                            .map(|confidence| format!("; x_wconf {}", confidence.round()))
+                            .unwrap_or_default();
+                        let _ = writeln!(
+                            hocr,
+                            "      <span class=\"ocrx_word\" id=\"word_{p}_{w}\" title=\"{}{confidence}\">{}</span>",
+                            format_bbox(word.bbox),
+                            escape(&word.text)
+                        );
+                    }
+                    hocr.push_str("     </span>\n");
+                }
+                hocr.push_str("    </p>\n");
+            }
+            hocr.push_str("   </div>\n");
+        }
+        hocr.push_str("  </div>\n");
+    }
+    hocr.push_str(" </body>\n</html>\n");
+    hocr
+}
+
+/// `HPOS`, `VPOS`, `WIDTH` and `HEIGHT` attributes of an ALTO element
+fn alto_position([left, top, right, bottom]: BBox) -> String {
+    format!(
+        "HPOS=\"{left}\" VPOS=\"{top}\" WIDTH=\"{}\" HEIGHT=\"{}\"",
+        right.saturating_sub(left),
+        bottom.saturating_sub(top)
+    )
+}
+
+/// Write `pages` as one ALTO 4 document, with a text block per paragraph
+pub fn write_alto(pages: &[OcrPage]) -> String {
+    let mut alto = String::new();
+    alto.push_str(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<alto xmlns=\"http://www.loc.gov/standards/alto/ns-v4#\"",
+        " xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\"",
+        " xsi:schemaLocation=\"http://www.loc.gov/standards/alto/ns-v4#",
+        " http://www.loc.gov/alto/v4/alto-4-2.xsd\">\n",
+        " <Description>\n",
+        "  <MeasurementUnit>pixel</MeasurementUnit>\n",
+        "  <OCRProcessing ID=\"OCR_0\">\n",
+        "   <ocrProcessingStep>\n",
+        "    <processingSoftware>\n",
+        "     <softwareName>dangerzone-rs</softwareName>\n",
+    ));
+    let _ = writeln!(
+        alto,
+        "     <softwareVersion>{}</softwareVersion>",
+        env!("CARGO_PKG_VERSION")
+    );
+    alto.push_str(concat!(
+        "    </processingSoftware>\n",
+        "   </ocrProcessingStep>\n",
+        "  </OCRProcessing>\n",
+        " </Description>\n",
+        " <Layout>\n",
+    ));
+
+    for (page_index, page) in pages.iter().enumerate() {
+        let p = page_index + 1;
+        let [_, _, width, height] = page.bbox;
+        let _ = writeln!(
+            alto,
+            "  <Page ID=\"page_{p}\" PHYSICAL_IMG_NR=\"{p}\" WIDTH=\"{width}\" HEIGHT=\"{height}\">"
+        );
+        let _ = writeln!(alto, "   <PrintSpace {}>", alto_position(page.bbox));
+        let (mut b, mut l, mut s) = (0, 0, 0);
+        for paragraph in page.blocks.iter().flat_map(|block| &block.paragraphs) {
+            b += 1;
+            let lang = paragraph
+                .lang
+                .as_deref()
+                .map(|lang| format!(" LANG=\"{}\"", escape(lang)))
+                .unwrap_or_default();
+            let _ = writeln!(
+                alto,
+                "    <TextBlock ID=\"block_{p}_{b}\" {}{lang}>",
+                alto_position(paragraph.bbox)
+            );
+            for line in &paragraph.lines {
+                l += 1;
+                let _ = writeln!(
+                    alto,
+                    "     <TextLine ID=\"line_{p}_{l}\" {}>",
+                    alto_position(line.bbox)
+                );
+                for (index, word) in line.words.iter().enumerate() {
+                    if index > 0 {
+                        // The gap since the end of the previous word
+                        let previous = line.words[index - 1].bbox;
+                        let _ = writeln!(
+                            alto,
+                            "      <SP HPOS=\"{}\" VPOS=\"{}\" WIDTH=\"{}\"/>",
+                            previous[2],
+                            previous[1],
+                            word.bbox[0].saturating_sub(previous[2])
+                        );
+                    }
+                    s += 1;
+                    let confidence = word
+                        .confidence
+                        .map(|confidence| {
+                            format!(" WC=\"{:.2}\"", confidence.clamp(0.0, 100.0) / 100.0)
+                        })
+                        .unwrap_or_default();
+                    let _ = writeln!(
+                        alto,
+                        "      <String ID=\"string_{p}_{s}\" {}{confidence} CONTENT=\"{}\"/>",
+                        alto_position(word.bbox),
+                        escape(&word.text)
+                    );
+                }
+                alto.push_str("     </TextLine>\n");
+            }
+            alto.push_str("    </TextBlock>\n");
+        }
+        alto.push_str("   </PrintSpace>\n  </Page>\n");
+    }
+    alto.push_str(" </Layout>\n</alto>\n");
+    alto
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Trimmed from tesseract 5's hOCR output
+    const TESSERACT_HOCR: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.0 Transitional//EN"
+    "http://www.w3.org/TR/xhtml1/DTD/xhtml1-transitional.dtd">
+<html xmlns="http://www.w3.org/1999/xhtml" xml:lang="en" lang="en">
+ <head>
+  <title></title>
+  <meta http-equiv="Content-Type" content="text/html;charset=utf-8"/>
+  <meta name='ocr-system' content='tesseract 5.3.0' />
+ </head>
+ <body>
+  <div class='ocr_page' id='page_1' title='image "000001_ocr.png"; bbox 0 0 850 1100; ppageno 0; scan_res 100 100'>
+   <div class='ocr_carea' id='block_1_1' title="bbox 100 90 420 130">
+    <p class='ocr_par' id='par_1_1' lang='eng' title="bbox 100 90 420 130">
+     <span class='ocr_line' id='line_1_1' title="bbox 100 90 420 130; baseline 0 -8; x_size 40">
+      <span class='ocrx_word' id='word_1_1' title='bbox 100 90 230 130; x_wconf 96'>Fish</span>
+      <span class='ocrx_word' id='word_1_2' title='bbox 250 92 420 130; x_wconf 91'><strong>&amp;</strong>&#160;Chips&quot;</span>
+     </span>
+    </p>
+   </div>
+   <!-- a comment with <span class='ocrx_word'>markup</span> -->
+   <div class='ocr_carea' id='block_1_2' title="bbox 100 200 300 240">
+    <p class='ocr_par' id='par_1_2' lang='deu' title="bbox 100 200 300 240">
+     <span class='ocr_header' id='line_1_2' title="bbox 100 200 300 240">
+      <span class='ocrx_word' id='word_1_3' title='bbox 100 200 300 240; x_wconf 55'>Grüße</span>
+     </span>
+    </p>
+   </div>
+  </div>
+ </body>
+</html>
+"#;
+
+    fn word(bbox: BBox, confidence: f32, text: &str) -> OcrWord {
+        OcrWord {
+            bbox,
+            confidence: Some(confidence),
+            text: text.to_string(),
+        }
+    }
+
+    fn sample() -> Vec<OcrPage> {
+        vec![OcrPage {
+            bbox: [0, 0, 850, 1100],
+            blocks: vec![
+                OcrBlock {
+                    bbox: [100, 90, 420, 130],
+                    paragraphs: vec![OcrParagraph {
+                        bbox: [100, 90, 420, 130],
+                        lang: Some("eng".to_string()),
+                        lines: vec![OcrLine {
+                            bbox: [100, 90, 420, 130],
+                            baseline: Some(Baseline {
+                                slope: 0.0,
+                                offset: -8.0,
+                            }),
+                            words: vec![
+                                word([100, 90, 230, 130], 96.0, "Fish"),
+                                word([250, 92, 420, 130], 91.0, "&\u{a0}Chips\""),
+                            ],
+                        }],
+                    }],
+                },
+                OcrBlock {
+                    bbox: [100, 200, 300, 240],
+                    paragraphs: vec![OcrParagraph {
+                        bbox: [100, 200, 300, 240],
+                        lang: Some("deu".to_string()),
+                        lines: vec![OcrLine {
+                            bbox: [100, 200, 300, 240],
+                            baseline: None,
+                            words: vec![word([100, 200, 300, 240], 55.0, "Grüße")],
+                        }],
+                    }],
+                },
+            ],
+        }]
+    }
+
+    #[test]
+    fn test_parse_hocr() {
+        assert_eq!(parse_hocr(TESSERACT_HOCR).unwrap(), sample());
+
+        // Words without their enclosing elements
+        let pages =
+            parse_hocr("<span class='ocrx_word' title='bbox 1 2 3 4'>a&lt;b</span>").unwrap();
+        assert_eq!(
+            pages[0].blocks[0].paragraphs[0].lines[0].words,
+            [OcrWord {
+                bbox: [1, 2, 3, 4],
+                confidence: None,
+                text: "a<b".to_string(),
+            }]
+        );
+
+        assert!(parse_hocr("<div class='ocr_page' title='bbox 0 0 1 1'").is_err());
+    }
+
+    #[test]
+    fn test_hocr_round_trip() {
+        let hocr = write_hocr(&sample());
+        assert!(hocr.contains("title=\"bbox 100 90 420 130; baseline 0 -8\">"));
+        assert!(hocr.contains(
+            "<span class=\"ocrx_word\" id=\"word_1_2\" title=\"bbox 250 92 420 130; x_wconf 91\">&amp;\u{a0}Chips&quot;</span>"
+        ));
+        assert_eq!(parse_hocr(&hocr).unwrap(), sample());
+    }
+
+    #[test]
+    fn test_write_alto() {
+        let alto = write_alto(&sample());
+        assert!(alto
+            .contains("<Page ID=\"page_1\" PHYSICAL_IMG_NR=\"1\" WIDTH=\"850\" HEIGHT=\"1100\">"));
+        assert!(alto.contains(
+            "<TextBlock ID=\"block_1_1\" HPOS=\"100\" VPOS=\"90\" WIDTH=\"320\" HEIGHT=\"40\" LANG=\"eng\">"
+        ));
+        assert!(alto.contains(
+            "<String ID=\"string_1_1\" HPOS=\"100\" VPOS=\"90\" WIDTH=\"130\" HEIGHT=\"40\" WC=\"0.96\" CONTENT=\"Fish\"/>"
+        ));
+        assert!(alto.contains("<SP HPOS=\"230\" VPOS=\"90\" WIDTH=\"20\"/>"));
+        assert!(alto.contains("CONTENT=\"&amp;\u{a0}Chips&quot;\"/>"));
+        assert!(alto.contains("<TextBlock ID=\"block_1_2\" HPOS=\"100\" VPOS=\"200\" WIDTH=\"200\" HEIGHT=\"40\" LANG=\"deu\">"));
+        assert_eq!(alto.matches("<TextLine ").count(), 2);
+        assert_eq!(alto.matches("</TextLine>").count(), 2);
+    }
+}
@@ -0,0 +1,240 @@
+//! Commands run at stages of a conversion, listed in the JSON file of
+//! `--hooks`
+//!
+//! ```json
+//! {
+//!   "pre-convert": ["clamscan", "--no-summary"],
+//!   "post-pixels": ["/usr/local/bin/classify-page"],
+//!   "post-pdf": ["/usr/local/bin/upload", "--bucket", "sanitized"]
+//! }
+//! ```
+//!
+//! Each hook is a program and its first arguments, run without a shell,
+//! with these arguments appended:
+//!
+//! - `pre-convert`: the path of `--input`, before anything is converted. A
+//!   hook that fails, like a virus scanner finding something, stops the
+//!   conversion. It can't be used with batches (`--tui` or `--resume`).
+//! - `post-pixels`: for each page, once it is sanitized and before it is
+//!   written, its number from 1, its width, its height and its pixel format
+//!   (`rgb`, `gray` or `rgba`), with its pixels on stdin, row by row. A hook
+//!   that fails fails the conversion.
+//! - `post-pdf`: the path of the safe PDF, then that of `--input`, once the
+//!   safe PDF is written. A hook that fails fails the conversion, but the
+//!   safe PDF is kept. It can't be used unless a single safe PDF is
+//!   written.
+//!
+//! The other hooks get no stdin. Their output is shown on stderr, with
+//! control characters replaced.
+
+use anyhow::{Context, Result};
+use dangerzone_rs::replace_control_chars;
+use dangerzone_rs::stage::Stage;
+use dangerzone_rs::{ConversionOptions, PageData, PixelFormat};
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Hooks of a `--hooks` file
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Hooks {
+    pre_convert: Option<Vec<String>>,
+    post_pixels: Option<Vec<String>>,
+    post_pdf: Option<Vec<String>>,
+}
+
+impl Hooks {
+    pub fn load(path: &Path) -> Result<Self> {
+        let path_sanitized = replace_control_chars(&path.to_string_lossy(), false);
+        let config = std::fs::read(path)
+            .with_context(|| format!("Failed to read the hooks '{path_sanitized}'"))?;
+        let hooks: Hooks = serde_json::from_slice(&config)
+            .with_context(|| format!("Invalid hooks '{path_sanitized}'"))?;
+        for (name, command) in [
+            ("pre-convert", &hooks.pre_convert),
+            ("post-pixels", &hooks.post_pixels),
+            ("post-pdf", &hooks.post_pdf),
+        ] {
+            if command.as_ref().is_some_and(|command| command.is_empty()) {
+                anyhow::bail!("Invalid hooks '{path_sanitized}': the {name} hook has no program");
+            }
+        }
+        Ok(hooks)
+    }
+
+    pub fn has_pre_convert(&self) -> bool {
+        self.pre_convert.is_some()
+    }
+
+    pub fn has_post_pdf(&self) -> bool {
+        self.post_pdf.is_some()
+    }
+
+    /// Run the pre-convert hook on `input`
+    pub fn pre_convert(&self, input: &str) -> Result<()> {
+        let Some(command) = &self.pre_convert else {
+            return Ok(());
+        };
+        run("pre-convert", command, &[input], None).context("The document was not converted")
+    }
+
+    /// Run the post-pixels hook on each page, as a stage of `options`
+    pub fn add_stages(&self, options: &mut ConversionOptions) {
+        if let Some(command) = &self.post_pixels {
+            options.stages.push(PostPixels {
+                command: command.clone(),
+            });
+        }
+    }
+
+    /// Run the post-pdf hook on the safe PDF `output` of `input`
+    pub fn post_pdf(&self, output: &str, input: &str) -> Result<()> {
+        let Some(command) = &self.post_pdf else {
+            return Ok(());
+        };
+        run("post-pdf", command, &[output, input], None).with_context(|| {
+            format!(
+                "The safe PDF was kept at '{output_sanitized}'",
+                output_sanitized = replace_control_chars(output, false)
+            )
+        })
+    }
+}
+
+/// Stage running the post-pixels hook on each page
+struct PostPixels {
+    command: Vec<String>,
+}
+
+impl Stage for PostPixels {
+    fn name(&self) -> &str {
+        "post-pixels hook"
+    }
+
+    fn process(&self, page: PageData, page_num: usize) -> Result<Option<PageData>> {
+        let format = match page.format {
+            PixelFormat::Rgb => "rgb",
+            PixelFormat::Gray => "gray",
+            PixelFormat::Rgba => "rgba",
+        };
+        let args = [
+            page_num.to_string(),
+            page.width.to_string(),
+            page.height.to_string(),
+            format.to_string(),
+        ];
+        run("post-pixels", &self.command, &args, Some(&page.pixels))?;
+        Ok(Some(page))
+    }
+}
+
+/// Run the hook `name`, `command` followed by `args`, with `stdin` as its
+/// input, showing its output
+fn run(
+    name: &str,
+    command: &[String],
+    args: &[impl AsRef<str>],
+    stdin: Option<&[u8]>,
+) -> Result<()> {
+    let program_sanitized = replace_control_chars(&command[0], false);
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .args(args.iter().map(AsRef::as_ref))
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run the {name} hook '{program_sanitized}'"))?;
+    let output = std::thread::scope(|scope| {
+        if let (Some(data), Some(mut pipe)) = (stdin, child.stdin.take()) {
+            // The hook may exit without reading all of it
+            scope.spawn(move || pipe.write_all(data));
+        }
+        child.wait_with_output()
+    })
+    .with_context(|| format!("Failed to run the {name} hook '{program_sanitized}'"))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for line in stdout.lines().chain(stderr.lines()) {
+        eprintln!(
+            "{name} hook: {line_sanitized}",
+            line_sanitized = replace_control_chars(line, false)
+        );
+    }
+    if !output.status.success() {
+        anyhow::bail!(
+            "The {name} hook '{program_sanitized}' failed ({})",
+            output.status
+        );
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn load(config: &str) -> Result<Hooks> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hooks.json");
+        std::fs::write(&path, config).unwrap();
+        Hooks::load(&path)
+    }
+
+    #[test]
+    fn test_load() {
+        let hooks = load(r#"{"pre-convert": ["true"], "post-pdf": ["echo", "uploaded"]}"#).unwrap();
+        assert!(hooks.has_pre_convert());
+        assert!(hooks.has_post_pdf());
+        assert!(hooks.post_pixels.is_none());
+
+        let error = load(r#"{"post-pdf": []}"#).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("the post-pdf hook has no program"));
+        assert!(load(r#"{"post_pdf": ["true"]}"#).is_err());
+    }
+
+    #[test]
+    fn test_pre_convert() {
+        let hooks = load(r#"{"pre-convert": ["test", "-f"]}"#).unwrap();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        hooks.pre_convert(&file.path().to_string_lossy()).unwrap();
+        let error = hooks.pre_convert("/nonexistent").unwrap_err();
+        assert_eq!(error.to_string(), "The document was not converted");
+        assert!(format!("{error:#}").contains("The pre-convert hook 'test' failed"));
+    }
+
+    #[test]
+    fn test_post_pixels() {
+        let dir = tempfile::tempdir().unwrap();
+        let seen = dir.path().join("seen");
+        let script = format!(
+            "echo \"$@\" >> '{}'; [ \"$(wc -c)\" -eq 6 ]",
+            seen.display()
+        );
+        let hooks = Hooks {
+            post_pixels: Some(vec!["sh".into(), "-c".into(), script, "hook".into()]),
+            ..Hooks::default()
+        };
+        let mut options = ConversionOptions::default();
+        hooks.add_stages(&mut options);
+        let stage = PostPixels {
+            command: hooks.post_pixels.clone().unwrap(),
+        };
+        let page = PageData::new(2, 1, vec![7; 6]);
+        let processed = stage.process(page, 3).unwrap().unwrap();
+        assert_eq!(&processed.pixels[..], &[7; 6]);
+        assert_eq!(std::fs::read_to_string(&seen).unwrap(), "3 2 1 rgb\n");
+        assert!(stage
+            .process(PageData::with_format(1, 1, PixelFormat::Gray, vec![0]), 1)
+            .is_err());
+        assert_eq!(format!("{:?}", options.stages), "[\"post-pixels hook\"]");
+    }
+}
@@ -0,0 +1,184 @@
+//! ICC profiles of the pixels of RGB pages
+//!
+//! The sRGB profile is a version 2 matrix/TRC display profile built here,
+//! rather than shipped as a file: the sRGB primaries adapted to the D50
+//! white of the profile connection space, and the sRGB tone curve sampled
+//! at 1024 points.
+
+use anyhow::Result;
+
+/// Largest profile read from a file
+pub(crate) const MAX_PROFILE_BYTES: u64 = 4 << 20;
+
+/// Size of the profile header
+const HEADER_BYTES: usize = 128;
+
+/// D50 white point of the profile connection space
+const D50: [f64; 3] = [0.9642, 1.0, 0.8249];
+
+/// Red, green and blue sRGB primaries, adapted to D50 with the Bradford
+/// transform
+const PRIMARIES: [[f64; 3]; 3] = [
+    [0.4360747, 0.2225045, 0.0139322],
+    [0.3850649, 0.7168786, 0.0971045],
+    [0.1430804, 0.0606169, 0.7141733],
+];
+
+/// Points of the sampled tone curve
+const CURVE_POINTS: usize = 1024;
+
+/// The sRGB profile
+pub(crate) fn srgb() -> Vec<u8> {
+    let xyz = |[x, y, z]: [f64; 3]| {
+        let mut tag = b"XYZ \0\0\0\0".to_vec();
+        for value in [x, y, z] {
+            tag.extend(s15_fixed16(value));
+        }
+        tag
+    };
+    let mut curve = b"curv\0\0\0\0".to_vec();
+    curve.extend((CURVE_POINTS as u32).to_be_bytes());
+    for i in 0..CURVE_POINTS {
+        let value = srgb_to_linear(i as f64 / (CURVE_POINTS - 1) as f64);
+        curve.extend(((value * 65535.0).round() as u16).to_be_bytes());
+    }
+
+    // The three tone curves share their data
+    let tags: [(&[u8; 4], Vec<u8>); 9] = [
+        (b"desc", description("sRGB")),
+        (b"cprt", text("No copyright, use freely")),
+        (b"wtpt", xyz(D50)),
+        (b"rXYZ", xyz(PRIMARIES[0])),
+        (b"gXYZ", xyz(PRIMARIES[1])),
+        (b"bXYZ", xyz(PRIMARIES[2])),
+        (b"rTRC", curve),
+        (b"gTRC", Vec::new()),
+        (b"bTRC", Vec::new()),
+    ];
+
+    let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+    let mut data = Vec::new();
+    let data_start = HEADER_BYTES + 4 + 12 * tags.len();
+    let mut last = (0, 0);
+    for (signature, tag) in &tags {
+        if !tag.is_empty() {
+            last = (data_start + data.len(), tag.len());
+            data.extend(tag);
+            // Each tag starts on a four-byte boundary
+            data.resize(data.len().next_multiple_of(4), 0);
+        }
+        table.extend(*signature);
+        table.extend((last.0 as u32).to_be_bytes());
+        table.extend((last.1 as u32).to_be_bytes());
+    }
+
+    let size = data_start + data.len();
+    let mut profile = Vec::with_capacity(size);
+    profile.extend((size as u32).to_be_bytes());
+    profile.extend([0; 4]); // Preferred CMM
+    profile.extend([2, 0x10, 0, 0]); // Version 2.1
+    profile.extend(b"mntrRGB XYZ ");
+    profile.extend([0; 12]); // Creation date
+    profile.extend(b"acsp");
+    profile.extend([0; 24]); // Platform, flags, device and attributes
+    profile.extend([0; 4]); // Perceptual rendering intent
+    for value in D50 {
+        profile.extend(s15_fixed16(value));
+    }
+    profile.resize(HEADER_BYTES, 0);
+    profile.extend(table);
+    profile.extend(data);
+    profile
+}
+
+/// Check that `profile` looks like an ICC profile of RGB pixels
+pub(crate) fn check_rgb(profile: &[u8]) -> Result<()> {
+    if profile.len() < HEADER_BYTES + 4 || &profile[36..40] != b"acsp" {
+        anyhow::bail!("Not an ICC profile");
+    }
+    let size = u32::from_be_bytes(profile[..4].try_into().unwrap());
+    if size as usize != profile.len() {
+        anyhow::bail!(
+            "ICC profile of {} bytes declares a size of {size}",
+            profile.len()
+        );
+    }
+    if &profile[16..20] != b"RGB " {
+        anyhow::bail!("The ICC profile isn't one of RGB pixels");
+    }
+    Ok(())
+}
+
+/// Linear intensity of an sRGB value, both from 0 to 1
+fn srgb_to_linear(value: f64) -> f64 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn s15_fixed16(value: f64) -> [u8; 4] {
+    ((value * 65536.0).round() as i32).to_be_bytes()
+}
+
+/// `textType` tag
+fn text(text: &str) -> Vec<u8> {
+    let mut tag = b"text\0\0\0\0".to_vec();
+    tag.extend(text.as_bytes());
+    tag.push(0);
+    tag
+}
+
+/// `textDescriptionType` tag, with an ASCII description only
+fn description(text: &str) -> Vec<u8> {
+    let mut tag = b"desc\0\0\0\0".to_vec();
+    tag.extend((text.len() as u32 + 1).to_be_bytes());
+    tag.extend(text.as_bytes());
+    tag.push(0);
+    // No Unicode nor ScriptCode description
+    tag.extend([0; 8]);
+    tag.extend([0; 3]);
+    tag.extend([0; 67]);
+    tag
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag<'a>(profile: &'a [u8], signature: &[u8; 4]) -> &'a [u8] {
+        let count = u32::from_be_bytes(profile[128..132].try_into().unwrap()) as usize;
+        let entry = (0..count)
+            .map(|i| &profile[132 + 12 * i..144 + 12 * i])
+            .find(|entry| &entry[..4] == signature)
+            .unwrap();
+        let offset = u32::from_be_bytes(entry[4..8].try_into().unwrap()) as usize;
+        let size = u32::from_be_bytes(entry[8..12].try_into().unwrap()) as usize;
+        &profile[offset..offset + size]
+    }
+
+    #[test]
+    fn test_srgb() {
+        let profile = srgb();
+        check_rgb(&profile).unwrap();
+        assert_eq!(profile.len() % 4, 0);
+
+        assert_eq!(&tag(&profile, b"wtpt")[8..12], &[0, 0, 0xf6, 0xd6]);
+        let curve = tag(&profile, b"gTRC");
+        assert_eq!(curve, tag(&profile, b"rTRC"));
+        assert_eq!(curve.len(), 12 + 2 * CURVE_POINTS);
+        assert_eq!(&curve[12..14], &[0, 0]);
+        assert_eq!(&curve[curve.len() - 2..], &[0xff, 0xff]);
+        assert!(tag(&profile, b"desc").starts_with(b"desc\0\0\0\0\0\0\0\x05sRGB\0"));
+    }
+
+    #[test]
+    fn test_check_rgb() {
+        let mut profile = srgb();
+        profile[16..20].copy_from_slice(b"GRAY");
+        assert!(check_rgb(&profile).is_err());
+        assert!(check_rgb(&srgb()[..200]).is_err());
+        assert!(check_rgb(b"%PDF-1.4").is_err());
+    }
+}
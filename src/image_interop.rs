@@ -0,0 +1,187 @@
+//! Conversions between pages and the buffers of the `image` crate
+//!
+//! Each pixel format maps to the buffer with the same layout, so converting a
+//! page to an image copies its pixels once and never converts them. Use
+//! [`DynamicImage`] for pages of any format.
+
+use crate::{PageData, PixelFormat};
+use anyhow::{Context, Result};
+use image::{DynamicImage, GrayImage, ImageBuffer, Pixel, RgbImage, RgbaImage};
+
+fn format_name(format: PixelFormat) -> &'static str {
+    match format {
+        PixelFormat::Rgb => "RGB",
+        PixelFormat::Gray => "grayscale",
+        PixelFormat::Rgba => "RGBA",
+    }
+}
+
+fn to_buffer<Px: Pixel<Subpixel = u8>>(
+    page: &PageData,
+    format: PixelFormat,
+) -> Result<ImageBuffer<Px, Vec<u8>>> {
+    if page.format != format {
+        anyhow::bail!(
+            "Page is {}, not {}",
+            format_name(page.format),
+            format_name(format)
+        );
+    }
+    let expected = page.width as usize * page.height as usize * format.bytes_per_pixel();
+    if page.pixels.len() != expected {
+        anyhow::bail!(
+            "Page has {} bytes of pixels, expected {expected}",
+            page.pixels.len()
+        );
+    }
+    ImageBuffer::from_raw(page.width.into(), page.height.into(), page.pixels.to_vec())
+        .context("Page doesn't fit in an image buffer")
+}
+
+fn from_buffer<Px: Pixel<Subpixel = u8>>(
+    image: ImageBuffer<Px, Vec<u8>>,
+    format: PixelFormat,
+) -> Result<PageData> {
+    let (Ok(width), Ok(height)) = (u16::try_from(image.width()), u16::try_from(image.height()))
+    else {
+        anyhow::bail!(
+            "Image of {}x{} pixels is too large for a page",
+            image.width(),
+            image.height()
+        );
+    };
+    Ok(PageData::with_format(
+        width,
+        height,
+        format,
+        image.into_raw(),
+    ))
+}
+
+impl TryFrom<&PageData> for RgbImage {
+    type Error = anyhow::Error;
+
+    fn try_from(page: &PageData) -> Result<Self> {
+        to_buffer(page, PixelFormat::Rgb)
+    }
+}
+
+impl TryFrom<&PageData> for GrayImage {
+    type Error = anyhow::Error;
+
+    fn try_from(page: &PageData) -> Result<Self> {
+        to_buffer(page, PixelFormat::Gray)
+    }
+}
+
+impl TryFrom<&PageData> for RgbaImage {
+    type Error = anyhow::Error;
+
+    fn try_from(page: &PageData) -> Result<Self> {
+        to_buffer(page, PixelFormat::Rgba)
+    }
+}
+
+impl TryFrom<&PageData> for DynamicImage {
+    type Error = anyhow::Error;
+
+    fn try_from(page: &PageData) -> Result<Self> {
+        Ok(match page.format {
+            PixelFormat::Rgb => DynamicImage::ImageRgb8(page.try_into()?),
+            PixelFormat::Gray => DynamicImage::ImageLuma8(page.try_into()?),
+            PixelFormat::Rgba => DynamicImage::ImageRgba8(page.try_into()?),
+        })
+    }
+}
+
+impl TryFrom<RgbImage> for PageData {
+    type Error = anyhow::Error;
+
+    fn try_from(image: RgbImage) -> Result<Self> {
+        from_buffer(image, PixelFormat::Rgb)
+    }
+}
+
+impl TryFrom<GrayImage> for PageData {
+    type Error = anyhow::Error;
+
+    fn try_from(image: GrayImage) -> Result<Self> {
+        from_buffer(image, PixelFormat::Gray)
+    }
+}
+
+impl TryFrom<RgbaImage> for PageData {
+    type Error = anyhow::Error;
+
+    fn try_from(image: RgbaImage) -> Result<Self> {
+        from_buffer(image, PixelFormat::Rgba)
+    }
+}
+
+/// Images with other color types, such as 16-bit ones, are converted to the
+/// closest pixel format
+impl TryFrom<DynamicImage> for PageData {
+    type Error = anyhow::Error;
+
+    fn try_from(image: DynamicImage) -> Result<Self> {
+        match image {
+            DynamicImage::ImageRgb8(image) => image.try_into(),
+            DynamicImage::ImageLuma8(image) => image.try_into(),
+            DynamicImage::ImageRgba8(image) => image.try_into(),
+            image if !image.color().has_color() && !image.color().has_alpha() => {
+                image.into_luma8().try_into()
+            }
+            image if image.color().has_alpha() => image.into_rgba8().try_into(),
+            image => image.into_rgb8().try_into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let pixels: Vec<u8> = (0..2 * 3 * 3).collect();
+        let page = PageData::new(2, 3, pixels.clone());
+        let image = RgbImage::try_from(&page).unwrap();
+        assert_eq!(image.dimensions(), (2, 3));
+        assert_eq!(image.get_pixel(1, 0).0, [3, 4, 5]);
+
+        let page = PageData::try_from(image).unwrap();
+        assert_eq!(
+            (page.width, page.height, page.format),
+            (2, 3, PixelFormat::Rgb)
+        );
+        assert_eq!(page.pixels, pixels);
+    }
+
+    #[test]
+    fn test_dynamic_image_keeps_format() {
+        let page = PageData::with_format(2, 1, PixelFormat::Gray, vec![10, 20]);
+        let image = DynamicImage::try_from(&page).unwrap();
+        assert!(matches!(image, DynamicImage::ImageLuma8(_)));
+        let page = PageData::try_from(image).unwrap();
+        assert_eq!(page.format, PixelFormat::Gray);
+
+        let image = DynamicImage::ImageLumaA8(ImageBuffer::from_raw(1, 1, vec![10, 20]).unwrap());
+        let page = PageData::try_from(image).unwrap();
+        assert_eq!(page.format, PixelFormat::Rgba);
+        assert_eq!(page.pixels, [10, 10, 10, 20][..]);
+    }
+
+    #[test]
+    fn test_mismatched_pages() {
+        let page = PageData::with_format(2, 1, PixelFormat::Gray, vec![10, 20]);
+        let err = RgbImage::try_from(&page).unwrap_err();
+        assert_eq!(err.to_string(), "Page is grayscale, not RGB");
+
+        let page = PageData::new(2, 1, vec![0; 5]);
+        let err = RgbImage::try_from(&page).unwrap_err();
+        assert_eq!(err.to_string(), "Page has 5 bytes of pixels, expected 6");
+
+        let image = GrayImage::new(70000, 1);
+        assert!(PageData::try_from(image).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+//! Versions of the converter image, in upstream's tag scheme
+//!
+//! Upstream publishes the converter as `ghcr.io/freedomofpress/dangerzone/v<N>`,
+//! where `N` is the version of the protocol between the converter and its
+//! caller, raised whenever one can no longer read the other. Each release of
+//! the image is tagged `<YYYYMMDD>-<Dangerzone version>`, such as
+//! `20250129-0.8.1`, and the newest one `latest`, which is the one run.
+//!
+//! The image of the protocol this crate speaks is selected by its name (see
+//! [`IMAGE_NAME`](crate::IMAGE_NAME)). Within it, the version is read from the
+//! dated tags of the local image, so an image pulled by its tag alone, or
+//! only as `latest`, isn't checked.
+
+use crate::{replace_control_chars, ConversionOptions, Runtime};
+use anyhow::Result;
+use log::{debug, warn};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+/// Version of the protocol between the converter and this crate
+pub const CONVERTER_PROTOCOL: u32 = 1;
+
+/// Registry and path under which upstream publishes the converter image of
+/// each protocol
+pub const IMAGE_REPOSITORY: &str = "ghcr.io/freedomofpress/dangerzone";
+
+/// Releases of Dangerzone, as `(major, minor)`, whose converter image this
+/// crate supports
+const SUPPORTED_RELEASES: std::ops::RangeInclusive<(u32, u32)> = (0, 8)..=(0, 9);
+
+/// Runtimes whose image was checked by this process
+static CHECKED: Mutex<Vec<Runtime>> = Mutex::new(Vec::new());
+
+/// Check the version of the converter image the runtime of `options` has,
+/// once per process and runtime, warning if it is outside
+/// [`SUPPORTED_RELEASES`], or failing with
+/// [`ConversionOptions::strict_image_version`]
+pub(crate) fn check(options: &ConversionOptions) -> Result<()> {
+    let runtime = options.runtime;
+    if matches!(runtime, Runtime::Bwrap | Runtime::AppleContainer)
+        || CHECKED.lock().unwrap().contains(&runtime)
+    {
+        return Ok(());
+    }
+    let Some(version) = local_version(options) else {
+        debug!(
+            "The release of the converter image {} is unknown",
+            crate::IMAGE_NAME
+        );
+        return Ok(());
+    };
+    if let Err(e) = check_version(version, runtime) {
+        if options.strict_image_version {
+            return Err(e);
+        }
+        warn!("{e}");
+    }
+    CHECKED.lock().unwrap().push(runtime);
+    Ok(())
+}
+
+/// Fail if the image of Dangerzone `version` isn't supported
+fn check_version(version: (u32, u32, u32), runtime: Runtime) -> Result<()> {
+    let (major, minor, patch) = version;
+    let (oldest, newest) = (SUPPORTED_RELEASES.start(), SUPPORTED_RELEASES.end());
+    if (major, minor) < *oldest {
+        anyhow::bail!(
+            "The converter image {image} is from Dangerzone {major}.{minor}.{patch}, older than \
+             {}.{} which this version of dangerzone-rs needs. Pull a newer one with: {} pull {image}",
+            oldest.0,
+            oldest.1,
+            runtime.command(),
+            image = crate::IMAGE_NAME,
+        );
+    }
+    if (major, minor) > *newest {
+        anyhow::bail!(
+            "The converter image {} is from Dangerzone {major}.{minor}.{patch}, newer than {}.{} \
+             which this version of dangerzone-rs supports. Update dangerzone-rs.",
+            crate::IMAGE_NAME,
+            newest.0,
+            newest.1,
+        );
+    }
+    Ok(())
+}
+
+/// Release of Dangerzone of the converter image the runtime has, read from
+/// its newest dated tag, if it has any
+fn local_version(options: &ConversionOptions) -> Option<(u32, u32, u32)> {
+    if options.runtime_api {
+        let (_, tags) = crate::sandbox::inspect_through_api(options.runtime).ok()??;
+        return tags.iter().filter_map(|tag| parse_tag(tag)).max();
+    }
+    let output = Command::new(options.runtime.command())
+        .args([
+            "image",
+            "inspect",
+            "--format",
+            "{{range .RepoTags}}{{.}} {{end}}",
+            crate::IMAGE_NAME,
+        ])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let tags = String::from_utf8_lossy(&output.stdout);
+    debug!(
+        "Tags of the converter image: {tags_sanitized}",
+        tags_sanitized = replace_control_chars(tags.trim(), false)
+    );
+    tags.split_whitespace().filter_map(parse_tag).max()
+}
+
+/// Release of Dangerzone in a tag of the converter image, such as
+/// `ghcr.io/freedomofpress/dangerzone/v1:20250129-0.8.1`, or `None` for
+/// another image or an undated tag such as `latest`
+fn parse_tag(tag: &str) -> Option<(u32, u32, u32)> {
+    let tag = tag.strip_prefix(crate::IMAGE_NAME)?.strip_prefix(':')?;
+    let (date, rest) = tag.split_once('-')?;
+    if date.len() != 8 || !date.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    // Development builds add the commit after the release
+    let release = rest.split('-').next()?;
+    let mut parts = release.split('.').map(|part| part.parse().ok());
+    let version = (parts.next()??, parts.next()??, parts.next()??);
+    parts.next().is_none().then_some(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_name_matches_protocol() {
+        assert_eq!(
+            crate::IMAGE_NAME,
+            format!("{IMAGE_REPOSITORY}/v{CONVERTER_PROTOCOL}")
+        );
+    }
+
+    #[test]
+    fn test_parse_tag() {
+        let image = crate::IMAGE_NAME;
+        assert_eq!(
+            parse_tag(&format!("{image}:20250129-0.8.1")),
+            Some((0, 8, 1))
+        );
+        assert_eq!(
+            parse_tag(&format!("{image}:20250417-0.9.0-25-g5d3f1c2")),
+            Some((0, 9, 0))
+        );
+        assert_eq!(parse_tag(&format!("{image}:latest")), None);
+        assert_eq!(parse_tag(&format!("{image}:2025-0.8.1")), None);
+        assert_eq!(parse_tag(&format!("{image}:20250129-0.8")), None);
+        assert_eq!(
+            parse_tag(&format!("{IMAGE_REPOSITORY}/v2:20250129-0.8.1")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_check_version() {
+        assert!(check_version((0, 8, 1), Runtime::Podman).is_ok());
+        assert!(check_version((0, 9, 3), Runtime::Podman).is_ok());
+        let older = check_version((0, 7, 0), Runtime::Docker).unwrap_err();
+        assert!(older.to_string().contains("docker pull"));
+        let newer = check_version((1, 0, 0), Runtime::Podman).unwrap_err();
+        assert!(newer.to_string().contains("newer than 0.9"));
+    }
+}
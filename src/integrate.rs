@@ -0,0 +1,357 @@
+//! "Sanitize with Dangerzone" actions of the Nautilus and Dolphin file
+//! managers
+//!
+//! `integrate` installs a Nautilus script and a Dolphin service menu, both
+//! running `dangerzone-rs --uri` on the selected files. Each document is
+//! converted next to itself, e.g. into `report-safe.pdf` for `report.docx`,
+//! and its progress is shown in a desktop notification, since file managers
+//! don't show the output of their actions.
+
+use crate::convert;
+use anyhow::{Context, Result};
+use dangerzone_rs::replace_control_chars;
+use dangerzone_rs::{CancellationToken, ConversionOptions, Progress};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Name of the action in the context menus
+const ACTION_NAME: &str = "Sanitize with Dangerzone";
+
+/// Files of the actions, relative to the XDG data directory
+fn action_files() -> [PathBuf; 2] {
+    [
+        Path::new("nautilus/scripts").join(ACTION_NAME),
+        PathBuf::from("kio/servicemenus/dangerzone-rs.desktop"),
+    ]
+}
+
+/// The Nautilus script, running `exe` on the selected URIs
+fn nautilus_script(exe: &Path) -> String {
+    format!(
+        "#!/bin/sh\n\
+         # Installed by dangerzone-rs integrate\n\
+         # Nautilus passes the selected URIs one per line\n\
+         set -f\n\
+         IFS='\n'\n\
+         exec {exe} --uri $NAUTILUS_SCRIPT_SELECTED_URIS\n",
+        exe = shell_quote(&exe.to_string_lossy()),
+    )
+}
+
+/// The Dolphin service menu, running `exe` on the selected URIs
+fn dolphin_service_menu(exe: &Path) -> String {
+    format!(
+        "[Desktop Entry]\n\
+         Type=Service\n\
+         MimeType=all/allfiles;\n\
+         Actions=sanitize\n\
+         X-KDE-ServiceTypes=KonqPopupMenu/Plugin\n\
+         \n\
+         [Desktop Action sanitize]\n\
+         Name={ACTION_NAME}\n\
+         Icon=security-high\n\
+         Exec={exe} --uri %U\n",
+        exe = desktop_exec_arg(&exe.to_string_lossy()),
+    )
+}
+
+/// `arg` quoted for a POSIX shell
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// `arg` quoted for the `Exec` key of a desktop entry, where `%` starts
+/// field codes and backslashes are escaped once more by the string value
+fn desktop_exec_arg(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%");
+    let plain = escaped
+        .bytes()
+        .all(|byte| byte.is_ascii_alphanumeric() || b"/._+-%".contains(&byte));
+    if plain {
+        return escaped;
+    }
+    let mut quoted = String::from("\"");
+    for c in escaped.chars() {
+        match c {
+            '"' | '`' | '$' => quoted.push_str(&format!("\\\\{c}")),
+            '\\' => quoted.push_str("\\\\\\\\"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The XDG data directory of the user, where the actions are installed
+fn data_dir() -> Result<PathBuf> {
+    if let Some(dir) = std::env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir));
+    }
+    let home = std::env::var_os("HOME").context("Neither XDG_DATA_HOME nor HOME is set")?;
+    Ok(PathBuf::from(home).join(".local/share"))
+}
+
+/// Install the file-manager actions running `exe`, returning their paths
+pub(crate) fn install(exe: &Path) -> Result<Vec<PathBuf>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let data_dir = data_dir()?;
+    let contents = [nautilus_script(exe), dolphin_service_menu(exe)];
+    let mut installed = Vec::new();
+    for (file, contents) in action_files().iter().zip(contents) {
+        let path = data_dir.join(file);
+        let dir = path.parent().expect("action files are in a directory");
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create '{}'", dir.display()))?;
+        std::fs::write(&path, contents)
+            .with_context(|| format!("Failed to write '{}'", path.display()))?;
+        // Both file managers only run executable actions
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("Failed to make '{}' executable", path.display()))?;
+        installed.push(path);
+    }
+    Ok(installed)
+}
+
+/// Remove the file-manager actions, returning the paths of those that were
+/// installed
+pub(crate) fn uninstall() -> Result<Vec<PathBuf>> {
+    let data_dir = data_dir()?;
+    let mut removed = Vec::new();
+    for file in action_files() {
+        let path = data_dir.join(file);
+        match std::fs::remove_file(&path) {
+            Ok(()) => removed.push(path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to remove '{}'", path.display()))
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Local path of a `file://` URI, or `uri` itself if it is a plain path
+fn uri_path(uri: &str) -> Result<PathBuf> {
+    let Some(rest) = uri.strip_prefix("file://") else {
+        if uri.contains("://") {
+            anyhow::bail!("Only local files can be sanitized");
+        }
+        return Ok(PathBuf::from(uri));
+    };
+    let path = match rest.find('/') {
+        Some(0) => rest,
+        Some(start) if &rest[..start] == "localhost" => &rest[start..],
+        _ => anyhow::bail!("Only local files can be sanitized"),
+    };
+    Ok(PathBuf::from(percent_decode(path)?))
+}
+
+/// `text` with its `%XX` escapes decoded
+fn percent_decode(text: &str) -> Result<std::ffi::OsString> {
+    use std::os::unix::ffi::OsStringExt;
+
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .context("Invalid escape in URI")?;
+            decoded.push(hex);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(std::ffi::OsString::from_vec(decoded))
+}
+
+/// Path of the safe PDF of `input`, next to it and not overwriting any file
+fn safe_output(input: &Path) -> PathBuf {
+    let stem = input
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "document".to_string());
+    let dir = input.parent().unwrap_or(Path::new(""));
+    let mut output = dir.join(format!("{stem}-safe.pdf"));
+    let mut n = 1;
+    while output.exists() {
+        n += 1;
+        output = dir.join(format!("{stem}-safe-{n}.pdf"));
+    }
+    output
+}
+
+/// Desktop notification updated in place with `notify-send`, falling back
+/// to stderr without it
+struct Notification {
+    id: Option<String>,
+}
+
+impl Notification {
+    fn show(&mut self, summary: &str, body: &str, progress: Option<usize>) {
+        let mut command = Command::new("notify-send");
+        command.args([
+            "--print-id",
+            "--app-name=Dangerzone",
+            "--icon=security-high",
+        ]);
+        if let Some(id) = &self.id {
+            command.arg(format!("--replace-id={id}"));
+        }
+        if let Some(percent) = progress {
+            command.arg(format!("--hint=int:value:{percent}"));
+        }
+        // Notification servers may interpret the body as markup
+        let body = body
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;");
+        let output = command
+            .args(["--", summary, &body])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output();
+        match output {
+            Ok(output) if output.status.success() => {
+                let id = String::from_utf8_lossy(&output.stdout).trim().to_string();
+                if !id.is_empty() {
+                    self.id = Some(id);
+                }
+            }
+            _ => eprintln!("{summary}: {body}"),
+        }
+    }
+}
+
+/// Convert the documents of `uris` next to themselves, one after the
+/// other, notifying the desktop of their progress
+pub(crate) fn convert_uris(uris: &[String], options: &ConversionOptions) -> Result<()> {
+    let mut failed = 0;
+    for uri in uris {
+        let mut notification = Notification { id: None };
+        let name = replace_control_chars(uri.rsplit('/').next().unwrap_or(uri), false);
+        let input = match uri_path(uri) {
+            Ok(input) => input,
+            Err(e) => {
+                notification.show(&format!("Can't sanitize {name}"), &format!("{e:#}"), None);
+                failed += 1;
+                continue;
+            }
+        };
+        let name = replace_control_chars(
+            &input
+                .file_name()
+                .map_or_else(|| input.to_string_lossy(), |name| name.to_string_lossy()),
+            false,
+        );
+        let output = safe_output(&input);
+        let summary = format!("Sanitizing {name}");
+
+        let notification = std::sync::Mutex::new(notification);
+        let on_progress = |progress: Progress| {
+            let (body, percent) = match progress {
+                Progress::ConvertingToPixels => {
+                    ("Converting the document to pixels".to_string(), 0)
+                }
+                Progress::PixelsReceived { total_pages } => {
+                    (format!("Received {total_pages} page(s)"), 50)
+                }
+                Progress::WritingPage { page, total_pages } => (
+                    format!("Writing page {page} of {total_pages}"),
+                    50 + 45 * page / total_pages.max(1),
+                ),
+                Progress::ApplyingOcr => ("Recognizing text".to_string(), 95),
+                Progress::Done => return,
+                _ => return,
+            };
+            notification
+                .lock()
+                .unwrap()
+                .show(&summary, &body, Some(percent));
+        };
+        let result = convert(
+            input.to_string_lossy().into_owned(),
+            output.to_string_lossy().into_owned(),
+            options,
+            &on_progress,
+            &CancellationToken::new(),
+        );
+        let mut notification = notification.into_inner().unwrap();
+        match result {
+            Ok(_) => {
+                let output_name = output
+                    .file_name()
+                    .map(|name| name.to_string_lossy())
+                    .unwrap_or_default();
+                notification.show(
+                    &format!("Sanitized {name}"),
+                    &format!("Saved as {}", replace_control_chars(&output_name, false)),
+                    None,
+                );
+            }
+            Err(e) => {
+                failed += 1;
+                notification.show(
+                    &format!("Failed to sanitize {name}"),
+                    &format!("{e:#}"),
+                    None,
+                );
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!(
+            "{failed} of {} document(s) couldn't be sanitized",
+            uris.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uri_path() {
+        assert_eq!(
+            uri_path("file:///home/me/My%20Report%2B.docx").unwrap(),
+            Path::new("/home/me/My Report+.docx")
+        );
+        assert_eq!(
+            uri_path("file://localhost/tmp/a.pdf").unwrap(),
+            Path::new("/tmp/a.pdf")
+        );
+        assert_eq!(uri_path("/tmp/a b.pdf").unwrap(), Path::new("/tmp/a b.pdf"));
+        assert!(uri_path("smb://server/share/a.pdf").is_err());
+        assert!(uri_path("file://server/a.pdf").is_err());
+        assert!(uri_path("file:///tmp/a%2.pdf").is_err());
+    }
+
+    #[test]
+    fn test_safe_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("report.docx");
+        assert_eq!(safe_output(&input), dir.path().join("report-safe.pdf"));
+        std::fs::write(dir.path().join("report-safe.pdf"), b"").unwrap();
+        assert_eq!(safe_output(&input), dir.path().join("report-safe-2.pdf"));
+    }
+
+    #[test]
+    fn test_action_quoting() {
+        let exe = Path::new("/opt/it's \"here\"/dangerzone-rs");
+        assert!(nautilus_script(exe).contains(
+            "exec '/opt/it'\\''s \"here\"/dangerzone-rs' --uri $NAUTILUS_SCRIPT_SELECTED_URIS\n"
+        ));
+        assert!(dolphin_service_menu(exe)
+            .contains("Exec=\"/opt/it's \\\\\"here\\\\\"/dangerzone-rs\" --uri %U\n"));
+        assert!(dolphin_service_menu(Path::new("/usr/bin/dangerzone-rs"))
+            .contains("Exec=/usr/bin/dangerzone-rs --uri %U\n"));
+    }
+}
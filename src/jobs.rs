@@ -0,0 +1,483 @@
+//! Persistent queue of conversion jobs, for the gRPC service
+//!
+//! Each job is a directory of the queue, named after the job's ID, holding
+//! the untrusted `document`, the job's state in `job.json` and, once
+//! converted, `safe.pdf`. Jobs that were queued or running when the service
+//! stopped are queued again when it starts. Finished jobs are removed once
+//! they are older than the retention period.
+
+use crate::limits::Slots;
+use crate::{
+    convert_document_with_options, replace_control_chars, CancellationToken, ConversionOptions,
+    Progress,
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+/// Default retention period of finished jobs
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Interval between two removals of expired jobs
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const JOB_FILE: &str = "job.json";
+const DOCUMENT_FILE: &str = "document";
+const RESULT_FILE: &str = "safe.pdf";
+/// Prefix of the directories of jobs being submitted
+const STAGING_PREFIX: &str = ".submit-";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+/// Error of [`JobQueue::submit`] when the queue already holds as many
+/// queued jobs as it may
+#[derive(Debug)]
+pub struct QueueFull;
+
+impl std::fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("The job queue is full")
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+/// A conversion submitted to the queue
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub state: JobState,
+    /// Add a text layer to the safe PDF
+    pub ocr: bool,
+    /// When the job was submitted, in seconds since the Unix epoch
+    pub created: u64,
+    /// Rank of the job in the order of submission
+    seq: u64,
+    /// When the job finished, in seconds since the Unix epoch
+    pub finished: Option<u64>,
+    /// Why the conversion failed
+    pub error: Option<String>,
+    /// Latest stage of the running conversion
+    #[serde(skip)]
+    pub progress: Option<Progress>,
+}
+
+/// Queue of conversion jobs kept in a directory
+///
+/// Clones share the same queue.
+#[derive(Clone)]
+pub struct JobQueue {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    dir: PathBuf,
+    retention: Duration,
+    max_queued: usize,
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    jobs: HashMap<String, Job>,
+    queue: VecDeque<String>,
+    running: HashMap<String, CancellationToken>,
+    next_seq: u64,
+}
+
+impl JobQueue {
+    /// Open the queue kept in `dir`, creating it if needed, and queue again
+    /// the jobs that didn't finish
+    ///
+    /// Submissions are refused while `max_queued` jobs wait in the queue.
+    pub fn open(dir: &Path, retention: Duration, max_queued: usize) -> Result<Self> {
+        let dir_sanitized = replace_control_chars(&dir.to_string_lossy(), false);
+        create_private_dir(dir)
+            .with_context(|| format!("Failed to create the job directory '{dir_sanitized}'"))?;
+
+        let mut state = State::default();
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read the job directory '{dir_sanitized}'"))?;
+        for entry in entries {
+            let path = entry?.path();
+            // Left behind by a submission interrupted by the service stopping
+            if path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with(STAGING_PREFIX))
+            {
+                let _ = std::fs::remove_dir_all(&path);
+                continue;
+            }
+            if !path.join(JOB_FILE).is_file() {
+                continue;
+            }
+            match load_job(&path) {
+                Ok(mut job) => {
+                    // Interrupted by the service stopping
+                    if job.state == JobState::Running {
+                        job.state = JobState::Queued;
+                    }
+                    state.jobs.insert(job.id.clone(), job);
+                }
+                Err(e) => log::warn!("Skipping job in '{}': {e:#}", path.display()),
+            }
+        }
+        let mut queued: Vec<&Job> = state
+            .jobs
+            .values()
+            .filter(|job| job.state == JobState::Queued)
+            .collect();
+        queued.sort_by_key(|job| job.seq);
+        state.queue = queued.into_iter().map(|job| job.id.clone()).collect();
+        state.next_seq = state
+            .jobs
+            .values()
+            .map(|job| job.seq + 1)
+            .max()
+            .unwrap_or(0);
+
+        let queue = JobQueue {
+            shared: Arc::new(Shared {
+                dir: dir.to_path_buf(),
+                retention,
+                max_queued,
+                state: Mutex::new(state),
+                wake: Condvar::new(),
+            }),
+        };
+        queue.remove_expired();
+        Ok(queue)
+    }
+
+    /// Queue the conversion of `document`, failing with [`QueueFull`] if
+    /// the queue is full
+    pub fn submit(&self, document: &[u8], ocr: bool) -> Result<Job> {
+        let seq = {
+            let mut state = self.lock();
+            if state.queue.len() >= self.shared.max_queued {
+                return Err(QueueFull.into());
+            }
+            state.next_seq += 1;
+            state.next_seq - 1
+        };
+        let job = Job {
+            id: uuid::Uuid::new_v4().to_string(),
+            state: JobState::Queued,
+            ocr,
+            created: unix_time(SystemTime::now()),
+            seq,
+            finished: None,
+            error: None,
+            progress: None,
+        };
+        // Written next to the queue and renamed into it, so that a job is
+        // never found without its document
+        let staging = tempfile::Builder::new()
+            .prefix(STAGING_PREFIX)
+            .tempdir_in(&self.shared.dir)
+            .context("Failed to create the job directory")?;
+        std::fs::write(staging.path().join(DOCUMENT_FILE), document)
+            .context("Failed to write the document of the job")?;
+        save_job(staging.path(), &job)?;
+        std::fs::rename(staging.keep(), self.job_dir(&job.id))
+            .context("Failed to add the job to the queue")?;
+
+        let mut state = self.lock();
+        // Filled by another submission while this one was written
+        if state.queue.len() >= self.shared.max_queued {
+            drop(state);
+            let _ = std::fs::remove_dir_all(self.job_dir(&job.id));
+            return Err(QueueFull.into());
+        }
+        state.jobs.insert(job.id.clone(), job.clone());
+        state.queue.push_back(job.id.clone());
+        self.shared.wake.notify_one();
+        Ok(job)
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.lock().jobs.get(id).cloned()
+    }
+
+    /// All jobs, oldest first
+    pub fn list(&self) -> Vec<Job> {
+        let mut jobs: Vec<Job> = self.lock().jobs.values().cloned().collect();
+        jobs.sort_by_key(|job| job.seq);
+        jobs
+    }
+
+    /// Cancel the job `id` unless it finished, returning it if it exists
+    ///
+    /// A queued job is cancelled at once; a running one once its conversion
+    /// stops.
+    pub fn cancel(&self, id: &str) -> Result<Option<Job>> {
+        let mut state = self.lock();
+        if let Some(cancel) = state.running.get(id) {
+            cancel.cancel();
+        }
+        let Some(job) = state.jobs.get_mut(id) else {
+            return Ok(None);
+        };
+        if job.state == JobState::Queued {
+            job.state = JobState::Cancelled;
+            job.finished = Some(unix_time(SystemTime::now()));
+            let job = job.clone();
+            state.queue.retain(|queued| queued != id);
+            let _ = std::fs::remove_file(self.job_dir(id).join(DOCUMENT_FILE));
+            save_job(&self.job_dir(id), &job)?;
+            return Ok(Some(job));
+        }
+        Ok(Some(job.clone()))
+    }
+
+    /// Safe PDF of the job `id`, if it succeeded
+    pub fn result(&self, id: &str) -> Result<Option<Vec<u8>>> {
+        match self.get(id) {
+            Some(job) if job.state == JobState::Succeeded => {
+                let pdf = std::fs::read(self.job_dir(id).join(RESULT_FILE))
+                    .context("Failed to read the safe PDF of the job")?;
+                Ok(Some(pdf))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Convert queued jobs on `workers` threads, each waiting for one of
+    /// `slots` first, and remove expired jobs, until the process exits
+    pub fn start(&self, workers: usize, options: ConversionOptions, slots: Arc<Slots>) {
+        let options = Arc::new(options);
+        for _ in 0..workers.max(1) {
+            let queue = self.clone();
+            let options = Arc::clone(&options);
+            let slots = Arc::clone(&slots);
+            std::thread::spawn(move || loop {
+                let (job, cancel) = queue.next();
+                let _slot = slots.acquire();
+                queue.run(job, &options, &cancel);
+            });
+        }
+        let queue = self.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(SWEEP_INTERVAL);
+            queue.remove_expired();
+        });
+    }
+
+    /// Wait for the next queued job and mark it running
+    fn next(&self) -> (Job, CancellationToken) {
+        let mut state = self.lock();
+        loop {
+            if let Some(id) = state.queue.pop_front() {
+                let cancel = CancellationToken::new();
+                state.running.insert(id.clone(), cancel.clone());
+                let job = state.jobs.get_mut(&id).expect("queued jobs exist");
+                job.state = JobState::Running;
+                let job = job.clone();
+                drop(state);
+                if let Err(e) = save_job(&self.job_dir(&job.id), &job) {
+                    log::warn!("{e:#}");
+                }
+                return (job, cancel);
+            }
+            state = self
+                .shared
+                .wake
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    fn run(&self, mut job: Job, options: &ConversionOptions, cancel: &CancellationToken) {
+        let dir = self.job_dir(&job.id);
+        let output = dir.join(RESULT_FILE);
+        let options = ConversionOptions {
+            ocr: job.ocr,
+            ..options.clone()
+        };
+        let progress = |progress: Progress| {
+            if let Some(job) = self.lock().jobs.get_mut(&job.id) {
+                job.progress = Some(progress);
+            }
+        };
+        log::info!("Converting job {}", job.id);
+        let result = convert_document_with_options(
+            dir.join(DOCUMENT_FILE).to_string_lossy().into_owned(),
+            output.to_string_lossy().into_owned(),
+            &options,
+            &progress,
+            cancel,
+        );
+        match result {
+            Ok(_) => job.state = JobState::Succeeded,
+            Err(_) if cancel.is_cancelled() => job.state = JobState::Cancelled,
+            Err(e) => {
+                log::warn!("Job {} failed: {e:#}", job.id);
+                job.state = JobState::Failed;
+                job.error = Some(format!("{e:#}"));
+            }
+        }
+        job.finished = Some(unix_time(SystemTime::now()));
+        // The document is only kept for converting it again after a restart
+        let _ = std::fs::remove_file(dir.join(DOCUMENT_FILE));
+        if let Err(e) = save_job(&dir, &job) {
+            log::warn!("{e:#}");
+        }
+
+        let mut state = self.lock();
+        state.running.remove(&job.id);
+        state.jobs.insert(job.id.clone(), job);
+    }
+
+    /// Remove the jobs that finished longer than the retention period ago
+    fn remove_expired(&self) {
+        let now = unix_time(SystemTime::now());
+        let retention = self.shared.retention.as_secs();
+        let mut state = self.lock();
+        let expired: Vec<String> = state
+            .jobs
+            .values()
+            .filter(|job| {
+                job.finished
+                    .is_some_and(|t| now.saturating_sub(t) > retention)
+            })
+            .map(|job| job.id.clone())
+            .collect();
+        for id in expired {
+            if let Err(e) = std::fs::remove_dir_all(self.job_dir(&id)) {
+                log::warn!("Failed to remove expired job {id}: {e}");
+                continue;
+            }
+            state.jobs.remove(&id);
+        }
+    }
+
+    fn job_dir(&self, id: &str) -> PathBuf {
+        self.shared.dir.join(id)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.shared.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Default job directory: `$STATE_DIRECTORY` under systemd, the XDG state
+/// directory otherwise
+pub fn default_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("STATE_DIRECTORY") {
+        return Some(PathBuf::from(dir).join("jobs"));
+    }
+    let state = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(state.join("dangerzone-rs/jobs"))
+}
+
+fn load_job(dir: &Path) -> Result<Job> {
+    let json = std::fs::read(dir.join(JOB_FILE)).context("Failed to read the job")?;
+    let job: Job = serde_json::from_slice(&json).context("Invalid job")?;
+    if dir.file_name() != Some(job.id.as_ref()) {
+        anyhow::bail!("The job's ID doesn't match its directory");
+    }
+    Ok(job)
+}
+
+/// Write `job` into its directory `dir`, replacing its previous state
+/// atomically
+fn save_job(dir: &Path, job: &Job) -> Result<()> {
+    let json = serde_json::to_vec_pretty(job)?;
+    let temp = dir.join(format!("{JOB_FILE}.tmp"));
+    std::fs::write(&temp, json)
+        .and_then(|()| std::fs::rename(&temp, dir.join(JOB_FILE)))
+        .with_context(|| format!("Failed to save job {}", job.id))
+}
+
+/// Create `dir`, readable by the current user only, since it holds
+/// documents
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = JobQueue::open(dir.path(), DEFAULT_RETENTION, usize::MAX).unwrap();
+        let first = queue.submit(b"first", false).unwrap();
+        let second = queue.submit(b"second", true).unwrap();
+        let third = queue.submit(b"third", false).unwrap();
+        assert_eq!(
+            queue.cancel(&second.id).unwrap().unwrap().state,
+            JobState::Cancelled
+        );
+        assert!(queue.cancel("missing").unwrap().is_none());
+        drop(queue);
+
+        let queue = JobQueue::open(dir.path(), DEFAULT_RETENTION, usize::MAX).unwrap();
+        let ids: Vec<String> = queue.list().into_iter().map(|job| job.id).collect();
+        assert_eq!(ids.len(), 3);
+        assert_eq!(queue.get(&second.id).unwrap().state, JobState::Cancelled);
+        assert!(queue.get(&second.id).unwrap().ocr);
+        let queued: Vec<String> = queue.lock().queue.iter().cloned().collect();
+        assert_eq!(queued, [first.id.clone(), third.id]);
+        assert!(queue.result(&first.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_interrupted_and_expired_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = JobQueue::open(dir.path(), DEFAULT_RETENTION, usize::MAX).unwrap();
+        let running = queue.submit(b"running", false).unwrap();
+        let (job, _cancel) = queue.next();
+        assert_eq!(job.id, running.id);
+        assert_eq!(queue.get(&running.id).unwrap().state, JobState::Running);
+
+        let expired = queue.submit(b"expired", false).unwrap();
+        let mut job = queue.cancel(&expired.id).unwrap().unwrap();
+        job.finished = Some(1);
+        save_job(&queue.job_dir(&job.id), &job).unwrap();
+        drop(queue);
+
+        let queue = JobQueue::open(dir.path(), DEFAULT_RETENTION, usize::MAX).unwrap();
+        assert_eq!(queue.get(&running.id).unwrap().state, JobState::Queued);
+        assert!(queue.get(&expired.id).is_none());
+        assert!(!dir.path().join(&expired.id).exists());
+    }
+
+    #[test]
+    fn test_queue_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = JobQueue::open(dir.path(), DEFAULT_RETENTION, 1).unwrap();
+        let first = queue.submit(b"first", false).unwrap();
+        let err = queue.submit(b"second", false).unwrap_err();
+        assert!(err.is::<QueueFull>());
+        assert_eq!(queue.list().len(), 1);
+
+        queue.cancel(&first.id).unwrap();
+        queue.submit(b"third", false).unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+//! Choice of the OCR languages of a document, for
+//! [`OCR_LANG_AUTO`](crate::OCR_LANG_AUTO)
+//!
+//! Tesseract's script detection tells the writing system of a sample page,
+//! which narrows the candidates down to the installed language packs of that
+//! script. When several remain, such as the many languages written in Latin
+//! script, the sample is recognized with all of them, and the language whose
+//! most common words appear most often in its text is chosen.
+
+use crate::ocr::tesseract_languages;
+use crate::orient::write_pnm;
+use crate::{conversion_temp_dir, replace_control_chars, PdfPage};
+use anyhow::{Context, Result};
+use log::{debug, info};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Language packs of the scripts tesseract detects, by order of preference
+const SCRIPT_LANGUAGES: &[(&str, &[&str])] = &[
+    (
+        "Latin",
+        &["eng", "fra", "deu", "spa", "ita", "por", "nld", "pol"],
+    ),
+    ("Cyrillic", &["rus", "ukr", "bul", "srp"]),
+    ("Greek", &["ell"]),
+    ("Arabic", &["ara", "fas"]),
+    ("Hebrew", &["heb"]),
+    ("Devanagari", &["hin"]),
+    ("Thai", &["tha"]),
+    ("Han", &["chi_sim", "chi_tra"]),
+    ("Japanese", &["jpn"]),
+    ("Katakana", &["jpn"]),
+    ("Hiragana", &["jpn"]),
+    ("Hangul", &["kor"]),
+];
+
+/// Most common words of languages sharing a script
+const COMMON_WORDS: &[(&str, &[&str])] = &[
+    (
+        "eng",
+        &[
+            "the", "and", "of", "to", "is", "in", "that", "for", "with", "this",
+        ],
+    ),
+    (
+        "fra",
+        &[
+            "le", "la", "les", "et", "des", "est", "une", "pour", "dans", "que",
+        ],
+    ),
+    (
+        "deu",
+        &[
+            "der", "die", "und", "das", "ist", "nicht", "mit", "den", "ein", "für",
+        ],
+    ),
+    (
+        "spa",
+        &[
+            "el", "los", "las", "y", "que", "del", "por", "una", "para", "con",
+        ],
+    ),
+    (
+        "ita",
+        &[
+            "il", "che", "di", "della", "per", "non", "una", "sono", "gli", "con",
+        ],
+    ),
+    (
+        "por",
+        &[
+            "o", "os", "que", "do", "da", "não", "uma", "para", "com", "em",
+        ],
+    ),
+    (
+        "nld",
+        &[
+            "de", "het", "een", "en", "van", "is", "niet", "dat", "voor", "met",
+        ],
+    ),
+    (
+        "pol",
+        &["i", "w", "nie", "na", "się", "jest", "że", "do", "to", "z"],
+    ),
+    (
+        "rus",
+        &["и", "в", "не", "на", "что", "с", "по", "это", "как", "из"],
+    ),
+    (
+        "ukr",
+        &["і", "в", "не", "на", "що", "з", "до", "це", "як", "та"],
+    ),
+];
+
+/// The tesseract language to recognize a document with, chosen from
+/// `sample`, its first page
+pub(crate) fn detect<P: PdfPage>(sample: &P) -> Result<String> {
+    let installed = tesseract_languages()?;
+    let temp_dir = conversion_temp_dir()?;
+    let image_path = temp_dir.path().join("sample.pnm");
+    write_pnm(sample, &image_path)?;
+
+    let osd = run_tesseract(&image_path, &["--psm", "0"])
+        .context("Tesseract couldn't detect the script of the first page")?;
+    let script = parse_script(&osd).context("Tesseract found no script on the first page")?;
+    let candidates = candidates(script, &installed);
+    debug!(
+        "Detected {script_sanitized} script, candidate languages {candidates:?}",
+        script_sanitized = replace_control_chars(script, false)
+    );
+    let lang = match candidates[..] {
+        [] => anyhow::bail!(
+            "No language pack is installed for the {script_sanitized} script",
+            script_sanitized = replace_control_chars(script, false)
+        ),
+        [lang] => lang,
+        [first, ..] => {
+            let text = run_tesseract(&image_path, &["-l", &candidates.join("+")])
+                .context("Tesseract couldn't recognize the first page")?;
+            best_language(&text, &candidates).unwrap_or(first)
+        }
+    };
+    info!("Detected OCR language: {lang}");
+    Ok(lang.to_string())
+}
+
+/// Standard output of tesseract reading the image at `image_path`
+fn run_tesseract(image_path: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("tesseract")
+        .arg(image_path)
+        .arg("stdout")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .context("Failed to run tesseract; to detect OCR languages, install it")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{stderr_sanitized}",
+            stderr_sanitized =
+                replace_control_chars(String::from_utf8_lossy(&output.stderr).trim(), true)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Script from tesseract's orientation and script detection output
+fn parse_script(osd: &str) -> Option<&str> {
+    osd.lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim() == "Script")
+        .map(|(_, value)| value.trim())
+        .filter(|script| !script.is_empty())
+}
+
+/// Installed languages written in `script`, by order of preference
+fn candidates(script: &str, installed: &[String]) -> Vec<&'static str> {
+    SCRIPT_LANGUAGES
+        .iter()
+        .find(|(name, _)| *name == script)
+        .map(|(_, languages)| {
+            languages
+                .iter()
+                .copied()
+                .filter(|lang| installed.iter().any(|installed| installed == lang))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The candidate whose common words appear most often in `text`, if any
+/// appear at all; ties go to the first one
+fn best_language<'a>(text: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    let score = |lang: &str| {
+        COMMON_WORDS
+            .iter()
+            .find(|(name, _)| *name == lang)
+            .map_or(0, |(_, common)| {
+                words
+                    .iter()
+                    .filter(|word| common.contains(&word.as_str()))
+                    .count()
+            })
+    };
+    let mut best = None;
+    for &lang in candidates {
+        let score = score(lang);
+        if score > 0 && best.is_none_or(|(_, best_score)| score > best_score) {
+            best = Some((lang, score));
+        }
+    }
+    best.map(|(lang, _)| lang)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_script() {
+        let osd = "Page number: 0\n\
+                   Orientation in degrees: 0\n\
+                   Rotate: 0\n\
+                   Orientation confidence: 6.20\n\
+                   Script: Cyrillic\n\
+                   Script confidence: 3.33\n";
+        assert_eq!(parse_script(osd), Some("Cyrillic"));
+        assert_eq!(parse_script("Script: \n"), None);
+    }
+
+    #[test]
+    fn test_candidates() {
+        let installed = ["osd", "deu", "eng", "rus"].map(String::from);
+        assert_eq!(candidates("Latin", &installed), ["eng", "deu"]);
+        assert_eq!(candidates("Cyrillic", &installed), ["rus"]);
+        assert!(candidates("Hangul", &installed).is_empty());
+        assert!(candidates("Braille", &installed).is_empty());
+    }
+
+    #[test]
+    fn test_best_language() {
+        let candidates = ["eng", "fra", "deu"];
+        assert_eq!(
+            best_language(
+                "Die Bank ist nicht mit der Post verbunden, und das Amt schließt.",
+                &candidates
+            ),
+            Some("deu")
+        );
+        assert_eq!(
+            best_language("La facture est due à la fin du mois.", &candidates),
+            Some("fra")
+        );
+        assert_eq!(best_language("Invoice 2024-03", &candidates), None);
+    }
+}
@@ -0,0 +1,225 @@
+//! Placement of page images on the sheets of the safe PDF
+//!
+//! By default each sheet is exactly the size of its page. On standard paper,
+//! the sheet is turned to the orientation of the page, and the page is
+//! scaled to fit within the margins and centered.
+//!
+//! With several pages per sheet, the sheet is laid out as if it held a grid
+//! of copies of its first page, each page being then fit to its cell.
+
+use crate::{PageLayout, Paper};
+
+/// 210 × 297 mm, in points
+const A4_PTS: (f32, f32) = (595.28, 841.89);
+/// 8.5 × 11 inches, in points
+const LETTER_PTS: (f32, f32) = (612.0, 792.0);
+
+const PTS_PER_MM: f32 = 72.0 / 25.4;
+
+/// Largest margin, in millimeters, leaving room for the page on either paper
+#[cfg(feature = "container")]
+pub(crate) const MAX_MARGIN_MM: f32 = 100.0;
+
+/// Where the image of a page goes on its sheet, in points
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Placement {
+    pub(crate) sheet: (f32, f32),
+    /// Lower-left corner of the image
+    pub(crate) origin: (f32, f32),
+    pub(crate) size: (f32, f32),
+}
+
+/// Place a page of `size_pts` points as `layout` says
+pub(crate) fn place(size_pts: (f32, f32), layout: &PageLayout) -> Placement {
+    let (width, height) = size_pts;
+    let paper = match layout.paper {
+        Paper::Original => {
+            return Placement {
+                sheet: size_pts,
+                origin: (0.0, 0.0),
+                size: size_pts,
+            }
+        }
+        Paper::A4 => A4_PTS,
+        Paper::Letter => LETTER_PTS,
+        Paper::Auto => closest_paper(size_pts),
+    };
+    let sheet = if width > height {
+        (paper.1, paper.0)
+    } else {
+        paper
+    };
+    let margin = layout.margin_mm * PTS_PER_MM;
+    let (origin, scale) = fit(
+        size_pts,
+        (margin, margin),
+        (sheet.0 - 2.0 * margin, sheet.1 - 2.0 * margin),
+    );
+    Placement {
+        sheet,
+        origin,
+        size: (width * scale, height * scale),
+    }
+}
+
+/// Lower-left corner and scale of a page of `size` points, scaled to fit the
+/// area at `origin` of `available` points and centered in it
+fn fit(size: (f32, f32), origin: (f32, f32), available: (f32, f32)) -> ((f32, f32), f32) {
+    let scale = (available.0 / size.0.max(f32::MIN_POSITIVE))
+        .min(available.1 / size.1.max(f32::MIN_POSITIVE));
+    (
+        (
+            origin.0 + (available.0 - size.0 * scale) / 2.0,
+            origin.1 + (available.1 - size.1 * scale) / 2.0,
+        ),
+        scale,
+    )
+}
+
+/// Sheet holding several pages in a grid, filled row by row from the top
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Sheet {
+    pub(crate) size: (f32, f32),
+    /// Lower-left corner of each cell
+    cells: Vec<(f32, f32)>,
+    cell_size: (f32, f32),
+}
+
+impl Sheet {
+    /// The sheet of `layout.nup` pages whose first page is shown at
+    /// `size_pts` points, once turned
+    pub(crate) fn new(size_pts: (f32, f32), layout: &PageLayout) -> Sheet {
+        let (width, height) = size_pts;
+        // Pages go side by side, or on top of each other if they are
+        // landscape, so that two pages make a sheet of the usual shape
+        let (columns, rows) = match layout.nup {
+            2 if width > height => (1, 2),
+            2 => (2, 1),
+            4 => (2, 2),
+            _ => (1, 1),
+        };
+        let grid = place((width * columns as f32, height * rows as f32), layout);
+        let cell_size = (grid.size.0 / columns as f32, grid.size.1 / rows as f32);
+        let cells = (0..rows)
+            .flat_map(|row| {
+                (0..columns).map(move |column| {
+                    (
+                        grid.origin.0 + column as f32 * cell_size.0,
+                        grid.origin.1 + (rows - 1 - row) as f32 * cell_size.1,
+                    )
+                })
+            })
+            .collect();
+        Sheet {
+            size: grid.sheet,
+            cells,
+            cell_size,
+        }
+    }
+
+    /// Number of pages the sheet holds
+    pub(crate) fn capacity(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Lower-left corner and scale of a page shown at `size_pts` points in
+    /// cell `cell`
+    pub(crate) fn fit(&self, cell: usize, size_pts: (f32, f32)) -> ((f32, f32), f32) {
+        fit(size_pts, self.cells[cell], self.cell_size)
+    }
+}
+
+/// A4 or Letter, whichever is closest in shape to a page of `size_pts`
+fn closest_paper(size_pts: (f32, f32)) -> (f32, f32) {
+    let ratio = |(width, height): (f32, f32)| width.max(height) / width.min(height).max(1e-3);
+    let page = ratio(size_pts);
+    if (page - ratio(LETTER_PTS)).abs() < (page - ratio(A4_PTS)).abs() {
+        LETTER_PTS
+    } else {
+        A4_PTS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(paper: Paper) -> PageLayout {
+        PageLayout {
+            paper,
+            ..PageLayout::default()
+        }
+    }
+
+    #[test]
+    fn test_original_size() {
+        let placement = place((300.0, 200.0), &PageLayout::default());
+        assert_eq!(placement.sheet, (300.0, 200.0));
+        assert_eq!(placement.origin, (0.0, 0.0));
+        assert_eq!(placement.size, (300.0, 200.0));
+    }
+
+    #[test]
+    fn test_fit_to_paper() {
+        // Letter page on A4, limited by the width within 10 mm margins
+        let placement = place(LETTER_PTS, &layout(Paper::A4));
+        assert_eq!(placement.sheet, A4_PTS);
+        let margin = 10.0 * PTS_PER_MM;
+        assert!((placement.origin.0 - margin).abs() < 0.01);
+        assert!((placement.size.0 - (A4_PTS.0 - 2.0 * margin)).abs() < 0.01);
+        let bottom = (A4_PTS.1 - placement.size.1) / 2.0;
+        assert!((placement.origin.1 - bottom).abs() < 0.01);
+
+        // Small landscape page, scaled up on landscape Letter
+        let placement = place((200.0, 100.0), &layout(Paper::Letter));
+        assert_eq!(placement.sheet, (792.0, 612.0));
+        assert!(placement.size.0 > 200.0);
+        assert!((placement.size.0 / placement.size.1 - 2.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_sheet() {
+        // Two portrait pages side by side, on their own size
+        let sheet = Sheet::new(
+            (100.0, 200.0),
+            &PageLayout {
+                nup: 2,
+                ..PageLayout::default()
+            },
+        );
+        assert_eq!(sheet.size, (200.0, 200.0));
+        assert_eq!(sheet.capacity(), 2);
+        assert_eq!(sheet.fit(0, (100.0, 200.0)), ((0.0, 0.0), 1.0));
+        assert_eq!(sheet.fit(1, (100.0, 200.0)), ((100.0, 0.0), 1.0));
+        // A landscape page is centered in its cell
+        assert_eq!(sheet.fit(1, (200.0, 100.0)), ((100.0, 75.0), 0.5));
+
+        // Four Letter pages on portrait Letter, from the top left
+        let sheet = Sheet::new(
+            LETTER_PTS,
+            &PageLayout {
+                paper: Paper::Letter,
+                margin_mm: 0.0,
+                nup: 4,
+                ..PageLayout::default()
+            },
+        );
+        assert_eq!(sheet.size, LETTER_PTS);
+        assert_eq!(sheet.capacity(), 4);
+        assert_eq!(sheet.fit(0, LETTER_PTS), ((0.0, 396.0), 0.5));
+        assert_eq!(sheet.fit(3, LETTER_PTS), ((306.0, 0.0), 0.5));
+    }
+
+    #[test]
+    fn test_auto_paper() {
+        assert_eq!(
+            place((612.0, 790.0), &layout(Paper::Auto)).sheet,
+            LETTER_PTS
+        );
+        assert_eq!(place((590.0, 840.0), &layout(Paper::Auto)).sheet, A4_PTS);
+        assert_eq!(
+            place((840.0, 590.0), &layout(Paper::Auto)).sheet,
+            (A4_PTS.1, A4_PTS.0)
+        );
+    }
+}
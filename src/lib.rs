@@ -1,731 +1,1605 @@
 use anyhow::{Context, Result};
-use flate2::write::ZlibEncoder;
-use flate2::Compression;
+use log::info;
 use std::fs::File;
-use std::io::{BufRead, BufReader, IsTerminal, Read, Write};
-use std::process::{Command, Stdio};
-use util::replace_control_chars;
+use std::io::BufWriter;
+#[cfg(feature = "container")]
+use std::io::{BufReader, Write};
+use std::path::Path;
+#[cfg(feature = "container")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "container")]
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+#[cfg(feature = "container")]
+use std::sync::{Arc, OnceLock};
+#[cfg(feature = "container")]
+use std::thread::JoinHandle;
+use std::time::Duration;
+#[cfg(feature = "container")]
+use std::time::{Instant, SystemTime};
+
+pub use errors::{CancellationToken, Cancelled, OutputTooLarge, PartialConversion};
+#[cfg(feature = "container")]
+pub use ocr::{apply_ocr_fn, extract_text, PageText};
+pub use options::{
+    ColorProfile, CompressionBackend, CompressionConfig, ContainerHardening, ConversionOptions,
+    OcrMyPdfOptions, OcrSidecar, PageCleanup, PageLayout, PageNumbers, Paper, Runtime,
+    StampPosition, DEFAULT_MAX_OUTPUT_BYTES, DPI, OCR_LANG_AUTO,
+};
+pub use pdf::{audit_pdf, validate_pdf, write_pdf};
+pub use protocol::{
+    parse_pixel_data, PageData, PageMetadata, PageReader, PixelFormat, INT_BYTES,
+    PAGE_CHECKSUMS_ENV, PAGE_CHECKSUMS_MAGIC, PAGE_COMPRESSION_ENV, PAGE_COMPRESSION_MAGIC,
+    PAGE_FAILURES_ENV, PAGE_METADATA_ENV, PIXEL_FORMATS_ENV, STREAM_FLAGS_MAGIC,
+};
+#[cfg(feature = "container")]
+pub use sandbox::{image_digest, warmup, CONTAINER_ENV_ALLOWLIST};
+pub use util::replace_control_chars;
+
+#[cfg(feature = "container")]
+use pdf::{
+    page_size_pts, CountingWriter, EncodedPage, PdfWriter, Provenance, UtcTime, WriterSettings,
+};
+use pdf::{pdf_text_string, write_pdf_with_progress, PdfPage};
+#[cfg(feature = "container")]
+use protocol::{parse_pixel_data_with_limit, verify_page, CappedReader};
+#[cfg(feature = "container")]
+use sandbox::{
+    check_container, check_container_env, check_container_status,
+    convert_doc_to_pixels_cancellable, find_executable, join_stderr_thread, run_converter,
+    spawn_container, spawn_sandbox, wait_for_container, CONTAINER_POLL_INTERVAL,
+};
+
+/// Errors of conversions, and their cancellation
+pub mod errors;
+
+/// Options of conversions
+pub mod options;
+
+/// Writing and checking of safe PDFs
+pub mod pdf;
+
+/// Pages of the pixel stream the converter writes, and its parsing
+pub mod protocol;
+
+/// The sandbox running the converter
+#[cfg(feature = "container")]
+pub mod sandbox;
 
 mod util;
 
+#[cfg(all(
+    feature = "container",
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod bwrap;
+
+/// Converter image of the protocol this crate speaks (see [`image_version`])
 pub const IMAGE_NAME: &str = "ghcr.io/freedomofpress/dangerzone/v1";
-pub const INT_BYTES: usize = 2;
-pub const DPI: f32 = 150.0;
-const MAX_SANITIZED_CHUNK_BYTES: u64 = 64 * 1024;
-
-fn get_security_args() -> Vec<String> {
-    vec![
-        "--log-driver".to_string(),
-        "none".to_string(),
-        "--security-opt".to_string(),
-        "no-new-privileges".to_string(),
-        "--cap-drop".to_string(),
-        "all".to_string(),
-        "--cap-add".to_string(),
-        "SYS_CHROOT".to_string(),
-        "--security-opt".to_string(),
-        "label=type:container_engine_t".to_string(),
-        "--network=none".to_string(),
-        "-u".to_string(),
-        "dangerzone".to_string(),
-    ]
-}
-
-fn read_u16_be(data: &[u8]) -> Result<u16> {
-    if data.len() < INT_BYTES {
-        anyhow::bail!("Not enough bytes to read u16");
-    }
-    Ok(u16::from_be_bytes([data[0], data[1]]))
-}
-
-/// Page data structure representing a single page's pixel information
-#[derive(Clone)]
-pub struct PageData {
-    pub width: u16,
-    pub height: u16,
-    pub pixels: Vec<u8>,
-}
-
-impl PageData {
-    pub fn new(width: u16, height: u16, pixels: Vec<u8>) -> Self {
-        PageData {
-            width,
-            height,
-            pixels,
-        }
-    }
+/// Log target of the sanitized output of the conversion container
+pub const UNTRUSTED_LOG_TARGET: &str = "dangerzone_rs::untrusted";
+/// Longest name of the files written for untrusted names, in bytes
+#[cfg(any(feature = "email", feature = "archive"))]
+const MAX_FILE_NAME_BYTES: usize = 100;
+
+/// What happened during a successful conversion, besides writing the safe
+/// PDF
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+#[non_exhaustive]
+pub struct ConversionReport {
+    /// Why the safe PDF has no text layer although OCR was requested: every
+    /// OCR engine failed, and [`ConversionOptions::ocr_required`] is unset
+    pub ocr_fallback: Option<String>,
+    /// The languages chosen for [`OCR_LANG_AUTO`]
+    pub ocr_lang: Option<String>,
+    /// Peak resident set size of this process so far, in bytes, on Unix
+    /// hosts: that of the conversion, unless an earlier conversion in the
+    /// same process used more
+    pub peak_rss_bytes: Option<u64>,
+    /// Durations and sizes of the conversion
+    pub stats: ConversionStats,
+    /// What went differently than asked, in the order it happened
+    pub warnings: Vec<Warning>,
+    /// Text blacked out because it matched
+    /// [`ConversionOptions::redact_patterns`], in the order of the pages
+    pub redacted: Vec<redact::PatternMatch>,
 }
 
-/// Parse binary pixel data stream from the container
-/// Returns a list of (width, height, pixel_data) tuples for each page
-pub fn parse_pixel_data(data: Vec<u8>) -> Result<Vec<PageData>> {
-    let mut pos = 0;
+/// Durations and sizes of a conversion, to compare options
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+#[non_exhaustive]
+pub struct ConversionStats {
+    /// Time until the first page was written: the whole conversion to
+    /// pixels when pages are spooled, or that of the first page when they
+    /// are written as they arrive
+    pub converting: Duration,
+    /// Time writing the pages, while the sandbox converts the following ones
+    pub writing: Duration,
+    /// Time adding the text layer to the written PDF, if not done while
+    /// writing it
+    pub ocr: Duration,
+    /// Time of the whole conversion, including checks of the safe PDF
+    pub total: Duration,
+    /// Pages of the safe PDF, before they are laid out on sheets
+    pub pages: usize,
+    /// Size of the document converted, or of the pixels read from a file
+    pub input_bytes: Option<u64>,
+    /// Size of the pages' pixels, uncompressed
+    pub pixel_bytes: u64,
+    /// Size of the safe PDF
+    pub output_bytes: u64,
+}
 
-    // Read page count
-    if data.len() < INT_BYTES {
-        anyhow::bail!("Insufficient data for page count");
+impl ConversionStats {
+    /// How many times smaller than the pixels of its pages the safe PDF is
+    pub fn compression_ratio(&self) -> Option<f64> {
+        (self.output_bytes > 0).then(|| self.pixel_bytes as f64 / self.output_bytes as f64)
     }
-    let page_count = read_u16_be(&data[pos..pos + INT_BYTES])?;
-    pos += INT_BYTES;
 
-    eprintln!("Document has {page_count} page(s)");
+    /// Pages converted per second, over the whole conversion
+    pub fn pages_per_second(&self) -> Option<f64> {
+        let secs = self.total.as_secs_f64();
+        (secs > 0.0).then(|| self.pages as f64 / secs)
+    }
+}
 
-    let mut pages = Vec::new();
+/// Something that went differently than asked during a conversion that
+/// still succeeded, reported in [`ConversionReport::warnings`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", rename_all = "snake_case"))]
+#[non_exhaustive]
+pub enum Warning {
+    /// Every OCR engine failed, so the safe PDF has no text layer (see
+    /// [`ConversionReport::ocr_fallback`])
+    OcrFallback { reason: String },
+    /// The language of the document couldn't be detected for
+    /// [`OCR_LANG_AUTO`], so OCR used `lang`
+    OcrLangUndetected { reason: String, lang: String },
+    /// The OCR sidecar wasn't written, as OCR found no text to put in it
+    OcrSidecarMissing,
+    /// Blank pages were left out of the safe PDF, as
+    /// [`ConversionOptions::drop_blank_pages`] asks
+    BlankPagesDropped { count: usize },
+    /// The safe PDF has no outline although [`ConversionOptions::outline`]
+    /// asks for one, as no heading was found
+    OutlineEmpty,
+    /// The converter failed to convert these pages, numbered from 1, so the
+    /// safe PDF has placeholders in their place
+    PagesFailed { pages: Vec<usize> },
+    /// These pages, numbered from 1, were left out of the safe PDF by
+    /// [`ConversionOptions::stages`]
+    PagesLeftOut { pages: Vec<usize> },
+}
 
-    for page_num in 0..page_count {
-        // Read width
-        if pos + INT_BYTES > data.len() {
-            anyhow::bail!("Insufficient data for page {} width", page_num + 1);
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Warning::OcrFallback { reason } => {
+                write!(f, "OCR failed, so there is no text layer: {reason}")
+            }
+            Warning::OcrLangUndetected { reason, lang } => {
+                write!(
+                    f,
+                    "Failed to detect the OCR language, using {lang}: {reason}"
+                )
+            }
+            Warning::OcrSidecarMissing => {
+                write!(f, "No OCR sidecar was written, as there are no OCR results")
+            }
+            Warning::BlankPagesDropped { count } => write!(f, "Dropped {count} blank page(s)"),
+            Warning::OutlineEmpty => write!(f, "No headings were found for the outline"),
+            Warning::PagesFailed { pages } => {
+                let pages: Vec<String> = pages.iter().map(usize::to_string).collect();
+                write!(
+                    f,
+                    "Page(s) {} failed to convert and were replaced by placeholders",
+                    pages.join(", ")
+                )
+            }
+            Warning::PagesLeftOut { pages } => {
+                let pages: Vec<String> = pages.iter().map(usize::to_string).collect();
+                write!(f, "Page(s) {} were left out by stages", pages.join(", "))
+            }
         }
-        let width = read_u16_be(&data[pos..pos + INT_BYTES])?;
-        pos += INT_BYTES;
+    }
+}
 
-        // Read height
-        if pos + INT_BYTES > data.len() {
-            anyhow::bail!("Insufficient data for page {} height", page_num + 1);
-        }
-        let height = read_u16_be(&data[pos..pos + INT_BYTES])?;
-        pos += INT_BYTES;
+/// Stage of a conversion, reported to callers of [`convert_document_with_options`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Progress {
+    /// The document is being converted to pixels inside the container
+    ConvertingToPixels,
+    /// The number of pages of the document is known; pages may still be
+    /// arriving from the container
+    PixelsReceived { total_pages: usize },
+    /// Page `page` (1-based) is being written to the safe PDF
+    WritingPage { page: usize, total_pages: usize },
+    /// A text layer is being added to the safe PDF
+    ApplyingOcr,
+    /// The safe PDF was written to its final location
+    Done,
+}
 
-        eprintln!("Page {}: {}x{} pixels", page_num + 1, width, height);
+/// Convert a document to raw RGB pixel data using the Dangerzone container
+#[cfg(feature = "container")]
+pub fn convert_doc_to_pixels(input_path: String) -> Result<Vec<u8>> {
+    convert_doc_to_pixels_cancellable(
+        input_path,
+        &ConversionOptions::default(),
+        &CancellationToken::new(),
+    )
+}
 
-        // Read pixel data (RGB, 3 bytes per pixel)
-        let num_bytes = (width as usize) * (height as usize) * 3;
-        if pos + num_bytes > data.len() {
-            anyhow::bail!(
-                "Insufficient data for page {} pixels (expected {} bytes)",
-                page_num + 1,
-                num_bytes
-            );
-        }
+/// Run only the sandboxed half of the conversion of a document, copying the
+/// pixel stream of the converter to `output` as it arrives; returns the
+/// number of bytes written
+///
+/// The stream is the one [`PageReader`] reads, and [`pixels_to_safe_pdf`]
+/// builds the safe PDF from it, possibly on another host. It comes straight
+/// from the sandbox and is only checked there.
+#[cfg(feature = "container")]
+pub fn convert_doc_to_pixel_stream<W: Write + Send + 'static>(
+    input_path: String,
+    mut output: W,
+    options: &ConversionOptions,
+    cancel: &CancellationToken,
+) -> Result<u64> {
+    run_converter(input_path, options, cancel, move |stdout| {
+        let written = std::io::copy(stdout, &mut output)?;
+        output.flush()?;
+        Ok(written)
+    })
+}
 
-        let pixels = data[pos..pos + num_bytes].to_vec();
-        pos += num_bytes;
+/// Convert a document to pixels, yielding each page as soon as the container
+/// has produced it
+///
+/// Only one page is buffered at a time: the container is paused until the
+/// caller asks for the next page.
+#[cfg(feature = "container")]
+pub fn stream_doc_to_pages(
+    input_path: String,
+    options: &ConversionOptions,
+    cancel: &CancellationToken,
+) -> Result<PageStream> {
+    stream_pages(input_path, options, cancel, 0)
+}
 
-        pages.push(PageData {
-            width,
-            height,
-            pixels,
-        });
-    }
+/// Convert a document to pixels, letting the container run ahead of the
+/// caller by up to `buffered_pages` pages
+#[cfg(feature = "container")]
+fn stream_pages(
+    input_path: String,
+    options: &ConversionOptions,
+    cancel: &CancellationToken,
+    buffered_pages: usize,
+) -> Result<PageStream> {
+    info!("Converting document to pixels...");
+
+    let (mut child, stderr_thread, repro) = spawn_container(&input_path, options)?;
+    let started = Instant::now();
+
+    let stdout = child
+        .stdout
+        .take()
+        .context("Failed to take ownership of stdout")?;
+    let limit = options.max_output_bytes;
+    let (sender, receiver) = mpsc::sync_channel(buffered_pages);
+    let page_count = Arc::new(OnceLock::new());
+    let reader_page_count = page_count.clone();
+    let reader_thread = std::thread::spawn(move || {
+        let mut stdout = CappedReader::new(stdout, limit);
+        let mut pages = PageReader::with_limit(&mut stdout, limit);
+        let error = loop {
+            match pages.next() {
+                Some(Ok(page)) => {
+                    // Already read with the first page
+                    if let Ok(count) = pages.page_count() {
+                        let _ = reader_page_count.set(count);
+                    }
+                    if sender.send(Ok(page)).is_err() {
+                        return;
+                    }
+                }
+                Some(Err(e)) => break e,
+                None => return,
+            }
+        };
+        if stdout.exceeded() {
+            let _ = sender.send(Err(OutputTooLarge { limit }.into()));
+            return;
+        }
+        if sender.send(Err(error)).is_ok() {
+            // Keep draining so the container can still exit on its own and
+            // report its status
+            let _ = std::io::copy(&mut stdout, &mut std::io::sink());
+        }
+    });
 
-    Ok(pages)
+    Ok(PageStream {
+        child,
+        pages: receiver,
+        page_count,
+        reader_thread: Some(reader_thread),
+        stderr_thread: Some(stderr_thread),
+        started,
+        timeout: options.timeout,
+        cancel: cancel.clone(),
+        repro,
+        finished: false,
+    })
 }
 
-/// Read from a source (mostly locked stderr/stdout) and write sanitized
-/// text to given output. Output is marked as untrusted
-fn forward_sanitized_text<R: BufRead, W: Write + IsTerminal>(
-    mut reader: R,
-    mut out: W,
-) -> Result<()> {
-    const ANSI_GRAY: &str = "\x1b[90m";
-    const ANSI_RESET: &str = "\x1b[0m";
-    const UNTRUSTED_PREFIX: &str = "UNTRUSTED> ";
-
-    let mut line_buf = Vec::new();
-    loop {
-        line_buf.clear();
-        let n = reader
-            .by_ref()
-            .take(MAX_SANITIZED_CHUNK_BYTES)
-            .read_until(b'\n', &mut line_buf)
-            .context("Failed to read output for sanitizing")?;
-        if n == 0 {
-            break;
+/// Pages of a document being converted, created by [`stream_doc_to_pages`]
+///
+/// Dropping the stream before it is exhausted kills the container.
+#[cfg(feature = "container")]
+pub struct PageStream {
+    child: process::SandboxProcess,
+    pages: Receiver<Result<PageData>>,
+    page_count: Arc<OnceLock<u16>>,
+    reader_thread: Option<JoinHandle<()>>,
+    stderr_thread: Option<JoinHandle<Result<()>>>,
+    started: Instant,
+    timeout: Option<Duration>,
+    cancel: CancellationToken,
+    repro: repro::ReproScript,
+    finished: bool,
+}
+
+#[cfg(feature = "container")]
+impl PageStream {
+    /// Number of pages of the document, known once the first page arrived
+    pub fn page_count(&self) -> Option<usize> {
+        self.page_count.get().map(|&count| count.into())
+    }
+
+    fn next_page(&mut self) -> Result<Option<PageData>> {
+        let mut parse_error = None;
+        loop {
+            check_container(&mut self.child, self.started, self.timeout, &self.cancel)?;
+            match self.pages.recv_timeout(CONTAINER_POLL_INTERVAL) {
+                Ok(Ok(page)) => return Ok(Some(page)),
+                Ok(Err(e)) if e.is::<OutputTooLarge>() => {
+                    cleanup::kill_container(&mut self.child);
+                    return Err(e);
+                }
+                // Hold the error back: if the container failed, its exit
+                // status is the more useful message
+                Ok(Err(e)) => parse_error = Some(e),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
         }
 
-        let s = String::from_utf8_lossy(&line_buf);
-        let mut sanitized: String = replace_control_chars(&s, true);
-        if !sanitized.ends_with('\n') {
-            sanitized.push('\n');
+        let status = wait_for_container(&mut self.child, self.started, self.timeout, &self.cancel)?;
+        if let Some(reader_thread) = self.reader_thread.take() {
+            let _ = reader_thread.join();
+        }
+        if let Some(stderr_thread) = self.stderr_thread.take() {
+            join_stderr_thread(stderr_thread);
+        }
+        check_container_status(status, &self.repro)?;
+        if let Some(e) = parse_error {
+            return Err(e);
         }
-        let sanitized_untrusted_prefix = if out.is_terminal() {
-            format!("{ANSI_GRAY}{UNTRUSTED_PREFIX}{sanitized}{ANSI_RESET}")
-        } else {
-            format!("{UNTRUSTED_PREFIX}{sanitized}")
-        };
 
-        out.write_all(sanitized_untrusted_prefix.as_bytes())
-            .context("Failed to write sanitized output")?;
-        out.flush().context("Failed to flush sanitized output")?;
+        info!("Document converted to pixels successfully");
+        Ok(None)
     }
-
-    Ok(())
 }
 
-/// Convert a document to raw RGB pixel data using the Dangerzone container
-pub fn convert_doc_to_pixels(input_path: String) -> Result<Vec<u8>> {
-    eprintln!("Converting document to pixels...");
-
-    let mut args = vec!["run".to_string()];
-    args.extend(get_security_args());
-    args.extend(vec![
-        "--rm".to_string(),
-        "-i".to_string(),
-        IMAGE_NAME.to_string(),
-        "/usr/bin/python3".to_string(),
-        "-m".to_string(),
-        "dangerzone.conversion.doc_to_pixels".to_string(),
-    ]);
-
-    let mut child = Command::new("podman")
-        .args(&args)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .context(format!(
-            "Failed to spawn container. Make sure podman is installed and the image '{IMAGE_NAME}' is pulled."
-        ))?;
-
-    // Take ownership of child stderr pipe and output sanitized text to parent stderr
-    let stderr = child
-        .stderr
-        .take()
-        .context("Failed to take ownership of stderr")?;
-    let stderr_thread = std::thread::spawn(move || -> Result<()> {
-        forward_sanitized_text(BufReader::new(stderr), std::io::stderr().lock())
-    });
+#[cfg(feature = "container")]
+impl Iterator for PageStream {
+    type Item = Result<PageData>;
 
-    // Read the input document
-    let mut input_file = File::open(&input_path).context(format!(
-        "Failed to open input file '{input_path_sanitized}'",
-        input_path_sanitized = replace_control_chars(&input_path, false)
-    ))?;
-    let mut input_data = Vec::new();
-    input_file
-        .read_to_end(&mut input_data)
-        .context("Failed to read input file")?;
-
-    // Write the document to the container's stdin
-    if let Some(mut stdin) = child.stdin.take() {
-        stdin
-            .write_all(&input_data)
-            .context("Failed to write to container stdin")?;
-    }
-
-    // Read the output from the container
-    let output = child
-        .wait_with_output()
-        .context("Failed to wait for container")?;
-
-    // Read stderr from the container
-    match stderr_thread.join() {
-        Err(_) => {
-            eprintln!("Warning: stderr_thread panicked while forwarding container stderr");
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
         }
-        Ok(Err(e)) => {
-            eprintln!(
-                "Warning: Failed to forward container stderr: {err_sanitized}",
-                err_sanitized = replace_control_chars(&e.to_string(), true)
-            );
+        let result = self.next_page();
+        if !matches!(result, Ok(Some(_))) {
+            self.finished = true;
         }
-        Ok(Ok(_)) => {}
+        result.transpose()
     }
+}
 
-    if !output.status.success() {
-        anyhow::bail!(
-            "Container failed with status: {}. The document format may be unsupported or corrupted.",
-            output.status
-        );
+#[cfg(feature = "container")]
+impl Drop for PageStream {
+    fn drop(&mut self) {
+        if !self.finished {
+            cleanup::kill_container(&mut self.child);
+        }
     }
-
-    eprintln!("Document converted to pixels successfully");
-    Ok(output.stdout)
 }
 
 /// Convert pixel data to a PDF file
 pub fn pixels_to_pdf(pages: Vec<PageData>, output_path: String) -> Result<()> {
-    eprintln!("Converting pixels to safe PDF...");
+    pixels_to_pdf_with_progress(&pages, output_path, DPI, &|_| {})
+}
+
+fn pixels_to_pdf_with_progress<P: PdfPage>(
+    pages: &[P],
+    output_path: String,
+    dpi: f32,
+    progress: &dyn Fn(Progress),
+) -> Result<()> {
+    info!("Converting pixels to safe PDF...");
 
     if pages.is_empty() {
         anyhow::bail!("No pages to convert");
     }
 
-    let mut file = File::create(&output_path).context(format!(
+    let file = File::create(&output_path).context(format!(
         "Failed to create output file '{output_path_sanitized}'",
         output_path_sanitized = replace_control_chars(&output_path, false)
     ))?;
-    write_pdf(&mut file, &pages).context("Failed to write PDF")?;
+    write_pdf_with_progress(&mut BufWriter::new(file), pages, dpi, progress)
+        .context("Failed to write PDF")?;
+    if let Err(e) = audit_pdf(&output_path) {
+        let _ = std::fs::remove_file(&output_path);
+        return Err(e);
+    }
 
-    eprintln!(
+    info!(
         "Safe PDF created successfully at: {output_path_sanitized}",
         output_path_sanitized = replace_control_chars(&output_path, false)
     );
     Ok(())
 }
 
-/// Convert a document to a safe PDF in one call
-pub fn convert_document(input_path: String, output_path: String, apply_ocr: bool) -> Result<()> {
-    let pixels_data = convert_doc_to_pixels(input_path)?;
-    let pages = parse_pixel_data(pixels_data)?;
-
-    let temp_output = if apply_ocr {
-        format!("{output_path}.temp.pdf")
-    } else {
-        output_path.clone()
-    };
-
-    pixels_to_pdf(pages.clone(), temp_output.clone()).context("Failed to convert pixels to PDF")?;
+/// Write `pages` to `output_dir` as raw pixels, one file per page, with an
+/// index of them all, [`pixel_dump::INDEX_NAME`]
+pub fn write_pixel_dump(pages: &[PageData], output_dir: &str) -> Result<()> {
+    pixel_dump::write_pages(
+        pages.iter().map(Ok),
+        &|| Some(pages.len()),
+        Path::new(output_dir),
+        &|_| {},
+        &CancellationToken::new(),
+    )?;
+    Ok(())
+}
 
-    if apply_ocr {
-        apply_ocr_fn(temp_output.clone(), output_path.clone())?;
-        std::fs::remove_file(&temp_output).context("Failed to remove temporary file")?;
+/// Convert a document to raw pixels in `output_dir`, as
+/// [`write_pixel_dump`] does, writing each page as soon as the container has
+/// produced it; returns the number of pages
+///
+/// Pages are written as the converter produced them, without any of the
+/// processing of the safe PDF.
+#[cfg(feature = "container")]
+pub fn convert_document_to_pixel_dump(
+    input_path: String,
+    output_dir: &str,
+    options: &ConversionOptions,
+    progress: &dyn Fn(Progress),
+    cancel: &CancellationToken,
+) -> Result<usize> {
+    if !options.redactions.is_empty() || !options.redact_patterns.is_empty() {
+        anyhow::bail!("Redactions only apply to safe PDFs, not to raw pixels");
     }
-
-    Ok(())
+    progress(Progress::ConvertingToPixels);
+    let pages = stream_pages(input_path, options, cancel, options.buffered_pages())?;
+    let page_count = pages.page_count.clone();
+    pixel_dump::write_pages(
+        pages,
+        &|| page_count.get().map(|&count| count.into()),
+        Path::new(output_dir),
+        progress,
+        cancel,
+    )
 }
 
-/// Write a minimal PDF file with embedded RGB pixel data
-fn write_pdf<W: Write>(writer: &mut W, pages: &[PageData]) -> Result<()> {
-    let mut pdf_data = Vec::new();
-    let mut object_offsets = Vec::new();
-
-    // PDF Header
-    pdf_data.extend_from_slice(b"%PDF-1.4\n");
-    pdf_data.extend_from_slice(b"%\xE2\xE3\xCF\xD3\n");
-
-    // Object 1: Catalog
-    object_offsets.push(pdf_data.len());
-    pdf_data.extend_from_slice(b"1 0 obj\n");
-    pdf_data.extend_from_slice(b"<<\n");
-    pdf_data.extend_from_slice(b"/Type /Catalog\n");
-    pdf_data.extend_from_slice(b"/Pages 2 0 R\n");
-    pdf_data.extend_from_slice(b">>\n");
-    pdf_data.extend_from_slice(b"endobj\n");
-
-    // Object 2: Pages (parent)
-    object_offsets.push(pdf_data.len());
-    pdf_data.extend_from_slice(b"2 0 obj\n");
-    pdf_data.extend_from_slice(b"<<\n");
-    pdf_data.extend_from_slice(b"/Type /Pages\n");
-
-    // Build kids array
-    let mut kids = String::from("/Kids [");
-    for i in 0..pages.len() {
-        kids.push_str(&format!("{} 0 R ", 3 + i * 2));
-    }
-    kids.push_str("]\n");
-    pdf_data.extend_from_slice(kids.as_bytes());
-
-    pdf_data.extend_from_slice(format!("/Count {}\n", pages.len()).as_bytes());
-    pdf_data.extend_from_slice(b">>\n");
-    pdf_data.extend_from_slice(b"endobj\n");
-
-    // For each page, create a Page object and an Image XObject
-    for (page_idx, page) in pages.iter().enumerate() {
-        eprintln!("Adding page {} to PDF...", page_idx + 1);
-
-        // Convert pixels to points (1 point = 1/72 inch)
-        let width_pts = (page.width as f32) / DPI * 72.0;
-        let height_pts = (page.height as f32) / DPI * 72.0;
-
-        // Page object
-        let page_obj_num = 3 + page_idx * 2;
-        let image_obj_num = page_obj_num + 1;
-
-        object_offsets.push(pdf_data.len());
-        pdf_data.extend_from_slice(format!("{page_obj_num} 0 obj\n").as_bytes());
-        pdf_data.extend_from_slice(b"<<\n");
-        pdf_data.extend_from_slice(b"/Type /Page\n");
-        pdf_data.extend_from_slice(b"/Parent 2 0 R\n");
-        pdf_data.extend_from_slice(
-            format!("/MediaBox [0 0 {width_pts:.2} {height_pts:.2}]\n").as_bytes(),
-        );
-        pdf_data.extend_from_slice(b"/Resources <<\n");
-        pdf_data.extend_from_slice(
-            format!("  /XObject << /Im{page_idx} {image_obj_num} 0 R >>\n").as_bytes(),
-        );
-        pdf_data.extend_from_slice(b">>\n");
+/// Build the safe PDF from pixels converted elsewhere: a file holding the
+/// pixel stream of the container, or a directory of raw pages written by
+/// [`convert_document_to_pixel_dump`] or other tools
+///
+/// The pixels get the same checks and processing as those of a conversion,
+/// so a document can be converted on one host and its safe PDF built on
+/// another. Reading directories requires the `serde` feature.
+#[cfg(feature = "container")]
+pub fn pixels_to_safe_pdf(
+    input_path: &str,
+    output_path: String,
+    options: &ConversionOptions,
+    progress: &dyn Fn(Progress),
+    cancel: &CancellationToken,
+) -> Result<ConversionReport> {
+    check_options(options)?;
+    pipeline::timed(input_path, progress, |progress| {
+        let input_path_sanitized = replace_control_chars(input_path, false);
+        if Path::new(input_path).is_dir() {
+            #[cfg(feature = "serde")]
+            {
+                let (page_count, pages) =
+                    pixel_dump::read_pages(Path::new(input_path), options.max_output_bytes)
+                        .with_context(|| {
+                            format!("Failed to read pixels from {input_path_sanitized}")
+                        })?;
+                return pipeline::write_safe_pdf(
+                    pages,
+                    &|| Some(page_count),
+                    None,
+                    output_path,
+                    options,
+                    progress,
+                    cancel,
+                );
+            }
+            #[cfg(not(feature = "serde"))]
+            anyhow::bail!("Reading pixels from a directory requires the `serde` feature");
+        }
 
-        // Reference to content stream object
-        pdf_data.extend_from_slice(
-            format!("/Contents {} 0 R\n", 3 + pages.len() * 2 + page_idx).as_bytes(),
-        );
-        pdf_data.extend_from_slice(b">>\n");
-        pdf_data.extend_from_slice(b"endobj\n");
-
-        // Image XObject
-        object_offsets.push(pdf_data.len());
-        pdf_data.extend_from_slice(format!("{image_obj_num} 0 obj\n").as_bytes());
-        pdf_data.extend_from_slice(b"<<\n");
-        pdf_data.extend_from_slice(b"/Type /XObject\n");
-        pdf_data.extend_from_slice(b"/Subtype /Image\n");
-        pdf_data.extend_from_slice(format!("/Width {}\n", page.width).as_bytes());
-        pdf_data.extend_from_slice(format!("/Height {}\n", page.height).as_bytes());
-        pdf_data.extend_from_slice(b"/ColorSpace /DeviceRGB\n");
-        pdf_data.extend_from_slice(b"/BitsPerComponent 8\n");
-
-        // Compress pixel data using Flate compression
-        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
-        encoder
-            .write_all(&page.pixels)
-            .context("Failed to compress pixel data")?;
-        let compressed_pixels = encoder.finish().context("Failed to finish compression")?;
-
-        pdf_data.extend_from_slice(b"/Filter /FlateDecode\n");
-        pdf_data.extend_from_slice(format!("/Length {}\n", compressed_pixels.len()).as_bytes());
-        pdf_data.extend_from_slice(b">>\n");
-        pdf_data.extend_from_slice(b"stream\n");
-        pdf_data.extend_from_slice(&compressed_pixels);
-        pdf_data.extend_from_slice(b"\nendstream\n");
-        pdf_data.extend_from_slice(b"endobj\n");
-    }
-
-    // Content stream objects for each page
-    for (page_idx, page) in pages.iter().enumerate() {
-        let width_pts = (page.width as f32) / DPI * 72.0;
-        let height_pts = (page.height as f32) / DPI * 72.0;
-        let content =
-            format!("q\n{width_pts:.2} 0 0 {height_pts:.2} 0 0 cm\n/Im{page_idx} Do\nQ\n");
-
-        let content_obj_num = 3 + pages.len() * 2 + page_idx;
-        object_offsets.push(pdf_data.len());
-        pdf_data.extend_from_slice(format!("{content_obj_num} 0 obj\n").as_bytes());
-        pdf_data.extend_from_slice(b"<<\n");
-        pdf_data.extend_from_slice(format!("/Length {}\n", content.len()).as_bytes());
-        pdf_data.extend_from_slice(b">>\n");
-        pdf_data.extend_from_slice(b"stream\n");
-        pdf_data.extend_from_slice(content.as_bytes());
-        pdf_data.extend_from_slice(b"\nendstream\n");
-        pdf_data.extend_from_slice(b"endobj\n");
-    }
-
-    // Cross-reference table
-    let xref_offset = pdf_data.len();
-    let num_objects = object_offsets.len();
-    pdf_data.extend_from_slice(b"xref\n");
-    pdf_data.extend_from_slice(format!("0 {}\n", num_objects + 1).as_bytes());
-    pdf_data.extend_from_slice(b"0000000000 65535 f \n");
-    for offset in &object_offsets {
-        pdf_data.extend_from_slice(format!("{offset:010} 00000 n \n").as_bytes());
-    }
-
-    // Trailer
-    pdf_data.extend_from_slice(b"trailer\n");
-    pdf_data.extend_from_slice(b"<<\n");
-    pdf_data.extend_from_slice(format!("/Size {}\n", num_objects + 1).as_bytes());
-    pdf_data.extend_from_slice(b"/Root 1 0 R\n");
-    pdf_data.extend_from_slice(b">>\n");
-    pdf_data.extend_from_slice(b"startxref\n");
-    pdf_data.extend_from_slice(format!("{xref_offset}\n").as_bytes());
-    pdf_data.extend_from_slice(b"%%EOF\n");
-
-    writer
-        .write_all(&pdf_data)
-        .context("Failed to write PDF data")?;
+        let file = File::open(input_path)
+            .with_context(|| format!("Failed to open {input_path_sanitized}"))?;
+        let mut pages = PageReader::with_limit(BufReader::new(file), options.max_output_bytes);
+        let page_count = pages.page_count()?;
+        pipeline::write_safe_pdf(
+            pages,
+            &|| Some(page_count.into()),
+            None,
+            output_path,
+            options,
+            progress,
+            cancel,
+        )
+    })
+}
+
+/// Convert a document to a safe PDF in one call
+#[cfg(feature = "container")]
+pub fn convert_document(input_path: String, output_path: String, apply_ocr: bool) -> Result<()> {
+    let options = ConversionOptions {
+        ocr: apply_ocr,
+        ..ConversionOptions::default()
+    };
+    convert_document_with_options(
+        input_path,
+        output_path,
+        &options,
+        &|_| {},
+        &CancellationToken::new(),
+    )?;
     Ok(())
 }
 
-/// Apply OCR to add text layer to PDF (platform-aware)
-pub fn apply_ocr_fn(input_pdf: String, output_pdf: String) -> Result<()> {
-    eprintln!("Applying OCR to PDF...");
+/// Convert a document to a safe PDF in one call, reporting each stage to
+/// `progress` and stopping early once `cancel` is triggered
+#[cfg(feature = "container")]
+pub fn convert_document_with_options(
+    input_path: String,
+    output_path: String,
+    options: &ConversionOptions,
+    progress: &dyn Fn(Progress),
+    cancel: &CancellationToken,
+) -> Result<ConversionReport> {
+    check_options(options)?;
+    checked_conversion(&input_path.clone(), &output_path.clone(), options, || {
+        convert_checked_document(input_path, output_path, options, progress, cancel)
+    })
+}
 
-    // On macOS, try using PDFKit's saveTextFromOCROption first
-    #[cfg(target_os = "macos")]
-    {
-        match apply_ocr_macos(&input_pdf, &output_pdf) {
-            Ok(()) => return Ok(()),
-            Err(e) => {
-                eprintln!(
-                    "Warning: macOS PDFKit OCR failed: {stderr_sanitized}",
-                    stderr_sanitized = replace_control_chars(&e.to_string(), true)
-                );
-                eprintln!("Falling back to ocrmypdf...");
-            }
+/// Convert a document whose options and disk space were checked
+#[cfg(feature = "container")]
+fn convert_checked_document(
+    input_path: String,
+    output_path: String,
+    options: &ConversionOptions,
+    progress: &dyn Fn(Progress),
+    cancel: &CancellationToken,
+) -> Result<ConversionReport> {
+    pipeline::timed(&input_path.clone(), progress, |progress| {
+        progress(Progress::ConvertingToPixels);
+        let original = input_path.clone();
+        if let Some(threshold) = options.spool_threshold() {
+            let limit = options.max_output_bytes;
+            let pages = run_converter(input_path, options, cancel, move |stdout| {
+                spool::read_pages(stdout, threshold, limit)
+            })?;
+            return pages_to_pdf(
+                &pages,
+                Some(&original),
+                output_path,
+                options,
+                progress,
+                cancel,
+            );
         }
-    }
+        // Pages are written while the container converts the following ones
+        let pages = stream_pages(input_path, options, cancel, options.buffered_pages())?;
+        let page_count = pages.page_count.clone();
+        pipeline::write_safe_pdf(
+            pages,
+            &|| page_count.get().map(|&count| count.into()),
+            Some(&original),
+            output_path,
+            options,
+            progress,
+            cancel,
+        )
+    })
+}
 
-    // Fall back to ocrmypdf (for non-macOS or if PDFKit fails)
-    let output = Command::new("ocrmypdf")
-        .args([&input_pdf, &output_pdf])
-        .output();
+/// Run `convert`, writing the safe PDF of `input_path` to `output_path`,
+/// once there is space for it, and check its output for the traces of a
+/// compromised converter
+#[cfg(feature = "container")]
+fn checked_conversion(
+    input_path: &str,
+    output_path: &str,
+    options: &ConversionOptions,
+    convert: impl FnOnce() -> Result<ConversionReport>,
+) -> Result<ConversionReport> {
+    disk_space::check(input_path, output_path, options)?;
+    let report = convert()?;
+    canary::check_output(&report.stats, options.max_output_bytes);
+    Ok(report)
+}
 
-    match output {
-        Ok(result) if result.status.success() => {
-            eprintln!("OCR applied successfully");
-            Ok(())
+/// Fail on options that can't be used, before starting the conversion
+#[cfg(feature = "container")]
+fn check_options(options: &ConversionOptions) -> Result<()> {
+    if !(options.dpi.is_finite() && options.dpi > 0.0) {
+        anyhow::bail!("Invalid DPI {}: must be a positive number", options.dpi);
+    }
+    orient::check_rotation(options.rotation)?;
+    if let Some(max_dpi) = options.max_dpi {
+        if !cfg!(feature = "downscale") {
+            anyhow::bail!("Downscaling pages requires the `downscale` feature");
         }
-        Ok(result) => {
-            let stderr = String::from_utf8_lossy(&result.stderr);
-            eprintln!(
-                "Warning: OCR failed: {stderr_sanitized}",
-                stderr_sanitized = replace_control_chars(&stderr, true)
-            );
-            eprintln!("Falling back to PDF without OCR");
-            std::fs::copy(&input_pdf, &output_pdf).context("Failed to copy PDF")?;
-            Ok(())
+        if !(max_dpi.is_finite() && max_dpi > 0.0) {
+            anyhow::bail!("Invalid maximum DPI {max_dpi}: must be a positive number");
         }
-        Err(e) => {
-            eprintln!("Warning: ocrmypdf not found or failed: {e}");
-            eprintln!("Falling back to PDF without OCR");
-            eprintln!("To enable OCR, install ocrmypdf: pip install ocrmypdf");
-            std::fs::copy(&input_pdf, &output_pdf).context("Failed to copy PDF")?;
-            Ok(())
+    }
+    if let Some(platform) = &options.image_platform {
+        let valid = platform.contains('/')
+            && platform.split('/').all(|part| {
+                !part.is_empty()
+                    && part
+                        .bytes()
+                        .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'.'))
+            });
+        if !valid {
+            anyhow::bail!(
+                "Invalid image platform '{platform_sanitized}': expected os/architecture, \
+                 such as linux/amd64",
+                platform_sanitized = replace_control_chars(platform, false)
+            );
         }
     }
+    check_container_env(&options.container_env)?;
+    if options.runtime_api && !cfg!(all(feature = "runtime-api", unix)) {
+        anyhow::bail!(
+            "Running containers through the runtime's API requires the `runtime-api` feature, on Unix"
+        );
+    }
+    options.parse_output_template()?;
+    options.redactions.check()?;
+    redact::compile_patterns(&options.redact_patterns)?;
+    options.compression.check()?;
+    options.layout.check()?;
+    if options.outline && !options.ocr {
+        anyhow::bail!("An outline needs OCR, to find the headings of the pages");
+    }
+    if options.linearize && options.object_streams {
+        anyhow::bail!("Linearized PDFs can't have object streams");
+    }
+    options.color_profile.load()?;
+    options.ocrmypdf.check()
+}
+
+/// Turn the pixel data of a converted document into the safe PDF
+#[cfg(feature = "container")]
+fn pixels_data_to_pdf(
+    pixels_data: Vec<u8>,
+    original: Option<&str>,
+    output_path: String,
+    options: &ConversionOptions,
+    progress: &dyn Fn(Progress),
+    cancel: &CancellationToken,
+) -> Result<ConversionReport> {
+    let pages = parse_pixel_data_with_limit(pixels_data, options.max_output_bytes)?;
+    pages_to_pdf(&pages, original, output_path, options, progress, cancel)
 }
 
-#[cfg(target_os = "macos")]
-fn apply_ocr_macos(input_pdf: &str, output_pdf: &str) -> Result<()> {
-    eprintln!("Using macOS PDFKit for OCR...");
+/// Write the parsed pages of a converted document, the one at `original` if
+/// any, to the safe PDF
+#[cfg(feature = "container")]
+fn pages_to_pdf<P: PdfPage + Sync>(
+    pages: &[P],
+    original: Option<&str>,
+    output_path: String,
+    options: &ConversionOptions,
+    progress: &dyn Fn(Progress),
+    cancel: &CancellationToken,
+) -> Result<ConversionReport> {
+    pipeline::write_safe_pdf(
+        pages.iter().map(Ok),
+        &|| Some(pages.len()),
+        original,
+        output_path,
+        options,
+        progress,
+        cancel,
+    )
+}
 
-    let script_path = if let Ok(exe_path) = std::env::current_exe() {
-        let mut path = exe_path.parent().unwrap().to_path_buf();
-        path.push("macos_ocr.swift");
-        if path.exists() {
-            path
-        } else {
-            std::path::PathBuf::from("src/macos_ocr.swift")
-        }
-    } else {
-        std::path::PathBuf::from("src/macos_ocr.swift")
-    };
+/// Outcome of converting one document of a [`convert_batch`] or
+/// [`convert_iter`]
+///
+/// With the `serde` feature, the result is serialized as the message of its
+/// error, if any, and the duration in seconds.
+#[derive(Debug)]
+pub struct BatchResult {
+    pub input_path: String,
+    pub output_path: String,
+    pub result: Result<ConversionReport>,
+    pub duration: Duration,
+}
 
-    if !script_path.exists() {
-        anyhow::bail!("macOS OCR script not found at {:?}", script_path);
+/// Convert several documents into `output_dir`, running up to `jobs`
+/// conversions at the same time
+///
+/// Each safe PDF is named after its input, e.g. `report-safe.pdf` for
+/// `report.docx`, or by [`ConversionOptions::output_template`]. Results are
+/// returned in the order of `inputs`; once `cancel` is triggered, the
+/// documents not started yet fail as cancelled. With
+/// [`ConversionOptions::session_max_documents`], each worker converts its
+/// documents in a [`session::ContainerSession`]. See [`convert_iter`] to get
+/// each result as soon as it is ready.
+#[cfg(feature = "container")]
+pub fn convert_batch(
+    inputs: &[String],
+    output_dir: &str,
+    jobs: usize,
+    options: &ConversionOptions,
+    cancel: &CancellationToken,
+) -> Result<Vec<BatchResult>> {
+    let (sender, receiver) = mpsc::channel();
+    spawn_batch(
+        inputs.to_vec(),
+        output_dir,
+        jobs,
+        options,
+        cancel,
+        move |index, result| sender.send((index, result)).is_ok(),
+    )?;
+    let mut results: Vec<_> = receiver.into_iter().collect();
+    results.sort_by_key(|(index, _)| *index);
+    Ok(results.into_iter().map(|(_, result)| result).collect())
+}
+
+/// Convert several documents into `output_dir` like [`convert_batch`],
+/// yielding each result as soon as its conversion is over, so frontends can
+/// show the first results of a large batch right away
+///
+/// Results come in the order conversions finish. Dropping the iterator
+/// stops starting new conversions; those already running still finish.
+#[cfg(feature = "container")]
+pub fn convert_iter(
+    inputs: Vec<String>,
+    output_dir: &str,
+    jobs: usize,
+    options: &ConversionOptions,
+    cancel: &CancellationToken,
+) -> Result<BatchResults> {
+    let (sender, results) = mpsc::channel();
+    spawn_batch(
+        inputs,
+        output_dir,
+        jobs,
+        options,
+        cancel,
+        move |_, result| sender.send(result).is_ok(),
+    )?;
+    Ok(BatchResults { results })
+}
+
+/// Results of a [`convert_iter`], in the order conversions finish
+#[cfg(feature = "container")]
+pub struct BatchResults {
+    results: Receiver<BatchResult>,
+}
+
+#[cfg(feature = "container")]
+impl Iterator for BatchResults {
+    type Item = BatchResult;
+
+    fn next(&mut self) -> Option<BatchResult> {
+        self.results.recv().ok()
     }
+}
 
-    let input_absolute = std::fs::canonicalize(input_pdf).with_context(|| {
-        format!(
-            "Failed to get absolute path for input: {input_pdf_sanitized}",
-            input_pdf_sanitized = replace_control_chars(input_pdf, false)
-        )
-    })?;
-    let output_absolute = std::path::Path::new(output_pdf)
-        .canonicalize()
-        .unwrap_or_else(|_| {
-            let output_path = std::path::Path::new(output_pdf);
-            if output_path.is_absolute() {
-                output_path.to_path_buf()
-            } else {
-                std::env::current_dir().unwrap().join(output_path)
+/// Convert several documents into `output_dir` like [`convert_batch`],
+/// as a stream of results in the order conversions finish, for async
+/// frontends
+///
+/// The conversions run on threads of their own, not on the async runtime.
+/// Dropping the stream stops starting new conversions.
+#[cfg(feature = "async")]
+pub fn convert_stream(
+    inputs: Vec<String>,
+    output_dir: &str,
+    jobs: usize,
+    options: &ConversionOptions,
+    cancel: &CancellationToken,
+) -> Result<impl tokio_stream::Stream<Item = BatchResult>> {
+    let (sender, results) = tokio::sync::mpsc::unbounded_channel();
+    spawn_batch(
+        inputs,
+        output_dir,
+        jobs,
+        options,
+        cancel,
+        move |_, result| sender.send(result).is_ok(),
+    )?;
+    Ok(tokio_stream::wrappers::UnboundedReceiverStream::new(
+        results,
+    ))
+}
+
+/// Start converting `inputs` into `output_dir` on up to `jobs` threads,
+/// handing each result to `send` with the index of its input; workers stop
+/// once `send` returns false
+#[cfg(feature = "container")]
+fn spawn_batch(
+    inputs: Vec<String>,
+    output_dir: &str,
+    jobs: usize,
+    options: &ConversionOptions,
+    cancel: &CancellationToken,
+    send: impl Fn(usize, BatchResult) -> bool + Send + Clone + 'static,
+) -> Result<()> {
+    std::fs::create_dir_all(output_dir).context(format!(
+        "Failed to create output directory '{output_dir_sanitized}'",
+        output_dir_sanitized = replace_control_chars(output_dir, false)
+    ))?;
+    let template = options.parse_output_template()?;
+    let output_paths = batch_output_paths(&inputs, output_dir, &template);
+    spawn_conversions(inputs, output_paths, jobs, options, cancel, send);
+    Ok(())
+}
+
+/// Start converting each of `inputs` into the safe PDF at the same index of
+/// `output_paths`, like [`spawn_batch`]
+#[cfg(feature = "container")]
+fn spawn_conversions(
+    inputs: Vec<String>,
+    output_paths: Vec<String>,
+    jobs: usize,
+    options: &ConversionOptions,
+    cancel: &CancellationToken,
+    send: impl Fn(usize, BatchResult) -> bool + Send + Clone + 'static,
+) {
+    let batch = Arc::new((inputs, output_paths, AtomicUsize::new(0)));
+
+    for _ in 0..jobs.clamp(1, batch.0.len().max(1)) {
+        let batch = batch.clone();
+        let options = options.clone();
+        let cancel = cancel.clone();
+        let send = send.clone();
+        std::thread::spawn(move || {
+            let (inputs, output_paths, next) = &*batch;
+            let mut session = options
+                .session_max_documents
+                .map(|max| session::ContainerSession::new(options.clone(), max));
+            loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let (Some(input_path), Some(output_path)) =
+                    (inputs.get(index), output_paths.get(index))
+                else {
+                    break;
+                };
+                let started = Instant::now();
+                let result = if cancel.is_cancelled() {
+                    Err(Cancelled.into())
+                } else if let Some(session) = session.as_mut() {
+                    checked_conversion(input_path, output_path, &options, || {
+                        let pixels_data = session.convert_doc_to_pixels(input_path, &cancel)?;
+                        pixels_data_to_pdf(
+                            pixels_data,
+                            Some(input_path),
+                            output_path.clone(),
+                            &options,
+                            &|_| {},
+                            &cancel,
+                        )
+                    })
+                } else {
+                    convert_document_with_options(
+                        input_path.clone(),
+                        output_path.clone(),
+                        &options,
+                        &|_| {},
+                        &cancel,
+                    )
+                };
+                let result = BatchResult {
+                    input_path: input_path.clone(),
+                    output_path: output_path.clone(),
+                    result,
+                    duration: started.elapsed(),
+                };
+                if !send(index, result) {
+                    break;
+                }
             }
         });
+    }
+}
 
-    let output = Command::new("swift")
-        .arg(&script_path)
-        .arg(&input_absolute)
-        .arg(&output_absolute)
-        .output()
-        .context("Failed to execute Swift OCR script")?;
+/// Paths of the safe PDFs of a [`convert_batch`], named by `template` and
+/// made unique when names collide
+#[cfg(feature = "container")]
+pub fn batch_output_paths(
+    inputs: &[String],
+    output_dir: &str,
+    template: &naming::OutputTemplate,
+) -> Vec<String> {
+    naming::output_paths(inputs, output_dir, template, SystemTime::now())
+}
 
-    if output.status.success() {
-        eprintln!("OCR applied successfully using macOS PDFKit");
-        Ok(())
+/// A file name for an untrusted `name`, such as that of an attachment,
+/// keeping only the last component of its path and plain characters, or
+/// `fallback`
+#[cfg(any(feature = "email", feature = "archive"))]
+fn safe_file_name(name: Option<&str>, fallback: &str) -> String {
+    let name = name
+        .and_then(|name| name.rsplit(['/', '\\']).next())
+        .unwrap_or_default();
+    let mut safe = String::new();
+    for c in name.chars() {
+        let c = if c.is_alphanumeric() || matches!(c, '.' | '-' | '_' | ' ') {
+            c
+        } else {
+            '_'
+        };
+        if safe.len() + c.len_utf8() > MAX_FILE_NAME_BYTES {
+            break;
+        }
+        safe.push(c);
+    }
+    let safe = safe.trim().trim_start_matches('.');
+    if safe.is_empty() {
+        fallback.to_string()
     } else {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!(
-            "Swift OCR script failed: {stderr_sanitized}",
-            stderr_sanitized = replace_control_chars(&stderr, true)
-        )
+        safe.to_string()
     }
 }
 
-/// Python bindings module
-/// Re-exports from the python module to make them available to PyO3
-#[cfg(feature = "python")]
-pub mod python;
+/// Private directory for the intermediate files of one conversion
+#[cfg(feature = "container")]
+fn conversion_temp_dir() -> Result<tempfile::TempDir> {
+    tempfile::Builder::new()
+        .prefix("dangerzone-rs-")
+        .tempdir()
+        .context("Failed to create temporary directory")
+}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Logger printing to stderr, for hosts without their own logging
+pub mod logging;
 
-    #[test]
-    fn test_page_size_calculation() {
-        let width_pixels = 1500u16;
-        let height_pixels = 2000u16;
-        let dpi = 150.0f32;
+/// Virtual machines hosting the container runtime on macOS and Windows
+#[cfg(feature = "container")]
+pub mod vm;
 
-        let width_mm = (width_pixels as f32) / dpi * 25.4;
-        let height_mm = (height_pixels as f32) / dpi * 25.4;
+/// Names of conversion containers, and removal of leftovers
+#[cfg(feature = "container")]
+pub mod cleanup;
 
-        assert_eq!(width_mm, 254.0);
-        assert_eq!(height_mm, 338.66666);
-    }
+/// Scripts reproducing the sandbox of failed conversions
+#[cfg(feature = "container")]
+mod repro;
 
-    #[test]
-    fn test_pixel_data_parsing() {
-        let mut data = Vec::new();
+/// Processes running the sandbox
+#[cfg(feature = "container")]
+mod process;
 
-        let page_count: u16 = 1;
-        data.extend_from_slice(&page_count.to_be_bytes());
+/// Tripwires checked after each sandbox exited
+#[cfg(feature = "container")]
+pub mod canary;
 
-        let width: u16 = 100;
-        let height: u16 = 50;
-        data.extend_from_slice(&width.to_be_bytes());
-        data.extend_from_slice(&height.to_be_bytes());
+/// Client of the REST API of podman and docker
+#[cfg(all(feature = "runtime-api", unix))]
+pub mod runtime_api;
 
-        let num_pixels = (width as usize) * (height as usize) * 3;
-        data.extend(vec![128u8; num_pixels]);
+/// Versions of the converter image
+#[cfg(feature = "container")]
+pub mod image_version;
 
-        let result = parse_pixel_data(data);
-        assert!(result.is_ok());
+/// Archives (.zip and .tar) converted entry by entry
+#[cfg(feature = "archive")]
+pub mod archive;
 
-        let pages = result.unwrap();
-        assert_eq!(pages.len(), 1);
-        assert_eq!(pages[0].width, width);
-        assert_eq!(pages[0].height, height);
-        assert_eq!(pages[0].pixels.len(), num_pixels);
-    }
+/// Hash-chained log of conversions, for proving documents were sanitized
+#[cfg(feature = "audit-log")]
+pub mod audit_log;
 
-    #[test]
-    fn test_pdf_generation() {
-        use std::io::Cursor;
+/// Local history of conversions, in an SQLite database
+#[cfg(feature = "history")]
+pub mod history;
 
-        let width = 10u16;
-        let height = 10u16;
-        let mut pixels = Vec::new();
+/// Emails (.eml and .msg) converted part by part
+#[cfg(feature = "email")]
+pub mod email;
 
-        for _ in 0..(width * height) {
-            pixels.push(255);
-            pixels.push(0);
-            pixels.push(0);
-        }
+/// Long-lived conversion sandboxes for batch conversions
+#[cfg(feature = "container")]
+pub mod session;
 
-        let page = PageData {
-            width,
-            height,
-            pixels,
-        };
-        let pages = vec![page];
+/// Detection of blank pages
+#[cfg(feature = "container")]
+mod blank;
 
-        let mut buffer = Cursor::new(Vec::new());
-        let result = write_pdf(buffer.get_mut(), &pages);
-        assert!(result.is_ok(), "PDF generation should succeed");
+/// Pages generated on the host, such as placeholders for failed pages
+mod placeholder;
 
-        let pdf_data = buffer.into_inner();
-        assert!(!pdf_data.is_empty(), "PDF should have data");
+/// Encrypted temporary storage for the pixels of very large documents
+#[cfg(feature = "container")]
+mod spool;
 
-        let header = String::from_utf8_lossy(&pdf_data[0..9]);
-        assert!(
-            header.starts_with("%PDF-1.4"),
-            "PDF should have correct header"
-        );
+/// Free space checks before conversions
+#[cfg(feature = "container")]
+mod disk_space;
 
-        let trailer = String::from_utf8_lossy(&pdf_data);
-        assert!(trailer.contains("%%EOF"), "PDF should have EOF marker");
-        assert!(
-            trailer.contains("/Type /Catalog"),
-            "PDF should have catalog"
-        );
-        assert!(trailer.contains("/Type /Pages"), "PDF should have pages");
-        assert!(
-            trailer.contains("/Type /Page"),
-            "PDF should have page object"
-        );
-        assert!(
-            trailer.contains("/Type /XObject"),
-            "PDF should have image object"
-        );
+/// Templates of the names of safe PDFs
+#[cfg(feature = "container")]
+pub mod naming;
 
-        assert!(
-            trailer.contains("/Filter /FlateDecode"),
-            "PDF should use Flate compression for images"
-        );
-    }
+/// Thumbnails of unsafe documents, rendered in the sandbox
+#[cfg(feature = "container")]
+pub mod preview;
 
-    #[test]
-    fn test_pdf_compression_reduces_size() {
-        use std::io::Cursor;
+/// Batches resumed from a state file after an interruption
+#[cfg(all(feature = "container", feature = "serde"))]
+pub mod resume;
 
-        let width = 100u16;
-        let height = 100u16;
-        let mut pixels = Vec::new();
+/// Cleanup of poorly scanned pages
+#[cfg(feature = "container")]
+mod enhance;
 
-        for _ in 0..(width * height) {
-            pixels.push(255);
-            pixels.push(0);
-            pixels.push(0);
-        }
+/// Regions of pages blacked out in the safe PDF
+pub mod redact;
 
-        let page = PageData {
-            width,
-            height,
-            pixels: pixels.clone(),
-        };
-        let pages = vec![page];
+/// Compression of the pixels embedded in the safe PDF
+mod compression;
+
+/// Consistency checks of written PDFs
+mod validate;
+
+/// Linearization of the safe PDF, for viewers reading it over HTTP
+#[cfg(feature = "container")]
+mod linearize;
+
+/// Placement of page images on standard paper
+mod layout;
+
+/// Page numbers stamped on the pages of the safe PDF
+mod stamp;
+
+/// ICC profiles embedded in the safe PDF
+#[cfg(feature = "container")]
+mod icc;
+
+/// Pages written as raw pixels with an index, instead of a safe PDF
+pub mod pixel_dump;
+
+/// OCR engines adding a text layer to safe PDFs
+#[cfg(feature = "container")]
+pub mod ocr;
+
+/// OCR with Apple's Vision framework on macOS
+#[cfg(feature = "container")]
+mod vision;
+
+/// OCR results as hOCR and ALTO documents
+pub mod hocr;
+
+/// Invisible text layer of pages, from the words found by OCR
+mod text_layer;
+
+/// Outline of safe PDFs, from the headings found by OCR
+mod outline;
+
+/// Choice of the OCR languages of a document
+#[cfg(feature = "container")]
+mod lang_detect;
+
+/// Custom steps run on each page between pixel parsing and PDF writing
+pub mod stage;
 
-        let mut buffer = Cursor::new(Vec::new());
-        let result = write_pdf(buffer.get_mut(), &pages);
-        assert!(result.is_ok(), "PDF generation should succeed");
+/// Writing of the safe PDF while pages are still arriving
+#[cfg(feature = "container")]
+mod pipeline;
 
-        let pdf_data = buffer.into_inner();
+/// Rotation of pages, set by the user or detected from their text
+#[cfg(feature = "container")]
+mod orient;
 
-        let uncompressed_pixel_size = pixels.len();
-        assert_eq!(uncompressed_pixel_size, 30000);
+/// Serialization of pixels and conversion results
+#[cfg(feature = "serde")]
+mod serde_impls;
 
-        let estimated_uncompressed_pdf_size = uncompressed_pixel_size + 1000;
+/// Conversions between pages and the `image` crate's buffers
+#[cfg(feature = "image")]
+mod image_interop;
 
-        eprintln!("PDF size with compression: {} bytes", pdf_data.len());
-        eprintln!("Estimated uncompressed size: {estimated_uncompressed_pdf_size} bytes");
-        eprintln!(
-            "Compression ratio: {:.2}%",
-            (pdf_data.len() as f32 / estimated_uncompressed_pdf_size as f32) * 100.0
+/// Resampling of pages to a maximum resolution
+#[cfg(feature = "downscale")]
+mod downscale;
+
+/// gRPC service wrapping the library, with server and client stubs
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
+/// Persistent queue of conversion jobs, for the gRPC service
+#[cfg(feature = "grpc")]
+pub mod jobs;
+
+/// Concurrency, queue, rate and size limits of the gRPC service
+#[cfg(feature = "grpc")]
+pub mod limits;
+
+/// Socket activation and readiness notification of systemd
+#[cfg(all(feature = "grpc", target_os = "linux"))]
+pub mod systemd;
+
+/// JSON-RPC over stdio for GUI frontends
+#[cfg(feature = "rpc")]
+pub mod rpc;
+
+/// Qubes RPC service converting a document read from stdin
+#[cfg(feature = "rpc")]
+pub mod qrexec;
+
+/// Session D-Bus service for desktop integration
+#[cfg(feature = "dbus")]
+pub mod dbus;
+
+/// C API for desktop applications
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
+/// WebAssembly bindings for the pixel parser and PDF writer
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+/// Rendering of PDFs back to pixels, for tests of the PDF writer
+#[cfg(feature = "render")]
+pub mod render;
+
+/// Python bindings module
+/// Re-exports from the python module to make them available to PyO3
+#[cfg(feature = "python")]
+pub mod python;
+
+#[cfg(test)]
+mod tests {
+    #[cfg_attr(not(feature = "container"), allow(unused_imports))]
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "container")]
+    fn test_batch_output_paths() {
+        let inputs = [
+            "a/report.docx".to_string(),
+            "b/report.pdf".to_string(),
+            "notes.odt".to_string(),
+            "c/report.docx".to_string(),
+        ];
+        assert_eq!(
+            batch_output_paths(&inputs, "out", &naming::OutputTemplate::default()),
+            [
+                "out/report-safe.pdf",
+                "out/report-safe-2.pdf",
+                "out/notes-safe.pdf",
+                "out/report-safe-3.pdf",
+            ]
         );
+    }
 
-        assert!(
-            pdf_data.len() < estimated_uncompressed_pdf_size / 2,
-            "PDF with compression should be significantly smaller than uncompressed"
+    #[cfg(any(feature = "email", feature = "archive"))]
+    #[test]
+    fn test_safe_file_name() {
+        assert_eq!(safe_file_name(Some("report.docx"), "x"), "report.docx");
+        assert_eq!(
+            safe_file_name(Some("C:\\Users\\a\\plan.xlsx"), "x"),
+            "plan.xlsx"
         );
+        assert_eq!(safe_file_name(Some("a\u{202e}fdp.exe"), "x"), "a_fdp.exe");
+        assert_eq!(safe_file_name(Some("../.."), "x"), "x");
+        assert_eq!(safe_file_name(Some(".hidden"), "x"), "hidden");
+        assert_eq!(safe_file_name(None, "attachment-1"), "attachment-1");
+        assert_eq!(safe_file_name(Some(&"é".repeat(80)), "x").len(), 100);
     }
 
     #[test]
-    #[cfg(target_os = "macos")]
-    fn test_macos_ocr_function_compiles() {
-        use std::io::Write;
-        use tempfile::NamedTempFile;
+    #[cfg(feature = "container")]
+    fn test_ocr_leaves_no_intermediate_files() {
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_path = output_dir.path().join("safe.pdf");
+        // One 1x1 page; without ocrmypdf, OCR falls back to copying the PDF
+        let pixels_data = vec![0, 1, 0, 1, 0, 1, 255, 255, 255];
+        let options = ConversionOptions {
+            ocr: true,
+            ..ConversionOptions::default()
+        };
 
-        let mut temp_input = NamedTempFile::new().unwrap();
-        temp_input.write_all(b"%PDF-1.4\n%%EOF\n").unwrap();
-        let input_path = temp_input.path().to_str().unwrap();
+        pixels_data_to_pdf(
+            pixels_data,
+            None,
+            output_path.to_string_lossy().into_owned(),
+            &options,
+            &|_| {},
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        let entries: Vec<_> = std::fs::read_dir(output_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, ["safe.pdf"]);
+    }
 
-        let temp_output = NamedTempFile::new().unwrap();
-        let output_path = temp_output.path().to_str().unwrap();
+    #[test]
+    #[cfg(feature = "container")]
+    fn test_ocr_fallback_is_reported() {
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_path = output_dir.path().join("safe.pdf");
+        let pixels_data = vec![0, 1, 0, 1, 0, 1, 255, 255, 255];
+        // Options of ocrmypdf make it add the text layer, and it has no
+        // models for this language even if it is installed
+        let options = ConversionOptions {
+            ocr: true,
+            ocr_lang: "nonexistent".to_string(),
+            ocrmypdf: OcrMyPdfOptions {
+                jobs: Some(1),
+                ..OcrMyPdfOptions::default()
+            },
+            ..ConversionOptions::default()
+        };
+        let convert = |options: &ConversionOptions| {
+            pixels_data_to_pdf(
+                pixels_data.clone(),
+                None,
+                output_path.to_string_lossy().into_owned(),
+                options,
+                &|_| {},
+                &CancellationToken::new(),
+            )
+        };
 
-        let result = apply_ocr_macos(input_path, output_path);
-        assert!(result.is_err());
+        let report = convert(&options).unwrap();
+        assert!(report.ocr_fallback.is_some());
+        assert!(matches!(
+            report.warnings.as_slice(),
+            [Warning::OcrFallback { .. }]
+        ));
+        assert!(output_path.exists());
+
+        std::fs::remove_file(&output_path).unwrap();
+        let required = ConversionOptions {
+            ocr_required: true,
+            ..options
+        };
+        assert!(convert(&required).is_err());
+        assert!(!output_path.exists());
     }
 
     #[test]
-    fn test_forward_sanitized_text() {
-        let input = concat!(
-            "plain ✓ café 😀\n",
-            "\x1b[31mANSI escaped\n",
-            "red text.\x1b[0m\n",
-            "\ttab\rline\n",
-            "a\u{200E}b\u{E000}c\u{0378}d\u{2028}e\u{2029}f\n",
-            "x\n",
-            "\u{2028}\u{2029}y\n",
-            "ok line\n",
-            "\x1b[31mred\x1b[0m\n",
-            "end",
-        );
-        let expected_output = concat!(
-            "UNTRUSTED> plain ✓ café 😀\n",
-            "UNTRUSTED> \u{FFFD}[31mANSI escaped\n",
-            "UNTRUSTED> red text.\u{FFFD}[0m\n",
-            "UNTRUSTED> \u{FFFD}tab\u{FFFD}line\n",
-            "UNTRUSTED> a\u{FFFD}b\u{FFFD}c\u{FFFD}d\u{FFFD}e\u{FFFD}f\n",
-            "UNTRUSTED> x\n",
-            "UNTRUSTED> \u{FFFD}\u{FFFD}y\n",
-            "UNTRUSTED> ok line\n",
-            "UNTRUSTED> \u{FFFD}[31mred\u{FFFD}[0m\n",
-            "UNTRUSTED> end\n",
-        );
+    #[cfg(feature = "container")]
+    fn test_convert_batch_reports_each_document() {
+        let output_dir = tempfile::tempdir().unwrap();
+        let inputs = [
+            "/nonexistent/a.pdf".to_string(),
+            "/nonexistent/b.pdf".to_string(),
+            "/nonexistent/c.pdf".to_string(),
+        ];
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+
+        let results = convert_batch(
+            &inputs,
+            output_dir.path().to_str().unwrap(),
+            2,
+            &ConversionOptions::default(),
+            &cancel,
+        )
+        .unwrap();
+        assert_eq!(results.len(), 3);
+        for (result, input) in results.iter().zip(&inputs) {
+            assert_eq!(&result.input_path, input);
+            assert!(result.result.as_ref().unwrap_err().is::<Cancelled>());
+        }
+    }
 
-        let reader = BufReader::new(std::io::Cursor::new(input.as_bytes()));
-        let out = tempfile::NamedTempFile::new().unwrap();
-        let out_path = out.path().to_path_buf();
-        let out_file = out.reopen().unwrap();
+    #[test]
+    #[cfg(feature = "container")]
+    fn test_session_conversions_are_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("doc.pdf").to_string_lossy().into_owned();
+        let output_path = dir.path().join("safe.pdf").to_string_lossy().into_owned();
+        std::fs::write(&input_path, "%PDF-").unwrap();
+        // The pixels fit in the limit, but not the PDF around them
+        let options = ConversionOptions {
+            max_output_bytes: 100,
+            ..ConversionOptions::default()
+        };
+        let alerts_before = canary::alert_count();
+
+        // As the pixels a session sent for the document
+        checked_conversion(&input_path, &output_path, &options, || {
+            pixels_data_to_pdf(
+                vec![0, 1, 0, 1, 0, 1, 1, 2, 3],
+                Some(&input_path),
+                output_path.clone(),
+                &options,
+                &|_| {},
+                &CancellationToken::new(),
+            )
+        })
+        .unwrap();
+        assert!(canary::alerts_since(alerts_before)
+            .iter()
+            .any(|alert| matches!(alert, canary::Alert::OutputOverCap { limit: 100, .. })));
+    }
 
-        forward_sanitized_text(reader, out_file).unwrap();
+    #[test]
+    #[cfg(feature = "container")]
+    fn test_convert_iter_yields_each_document() {
+        let output_dir = tempfile::tempdir().unwrap();
+        let inputs: Vec<String> = (0..5).map(|i| format!("/nonexistent/{i}.pdf")).collect();
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+
+        let results = convert_iter(
+            inputs.clone(),
+            output_dir.path().to_str().unwrap(),
+            3,
+            &ConversionOptions::default(),
+            &cancel,
+        )
+        .unwrap();
+        let mut converted: Vec<String> = results
+            .map(|result| {
+                assert!(result.result.unwrap_err().is::<Cancelled>());
+                result.input_path
+            })
+            .collect();
+        converted.sort();
+        assert_eq!(converted, inputs);
+    }
 
-        let output_bytes = std::fs::read(out_path).unwrap();
-        let output = String::from_utf8(output_bytes).unwrap();
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_convert_stream() {
+        use tokio_stream::StreamExt;
+
+        let output_dir = tempfile::tempdir().unwrap();
+        let inputs = vec!["/nonexistent/a.pdf".to_string()];
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let results: Vec<BatchResult> = runtime.block_on(
+            convert_stream(
+                inputs,
+                output_dir.path().to_str().unwrap(),
+                1,
+                &ConversionOptions::default(),
+                &cancel,
+            )
+            .unwrap()
+            .collect(),
+        );
+        assert_eq!(results.len(), 1);
+        assert!(results[0].result.as_ref().unwrap_err().is::<Cancelled>());
+    }
+
+    #[test]
+    #[cfg(feature = "container")]
+    fn test_ocrmypdf_options() {
+        assert!(OcrMyPdfOptions::default().args().is_empty());
+        let options = OcrMyPdfOptions {
+            deskew: true,
+            rotate_pages: true,
+            jobs: Some(2),
+            optimize: Some(0),
+            ..OcrMyPdfOptions::default()
+        };
+        assert!(options.check().is_ok());
         assert_eq!(
-            output, expected_output,
-            "forward_sanitized_text failed for input: {input:?}",
+            options.args(),
+            [
+                "--deskew",
+                "--rotate-pages",
+                "--jobs",
+                "2",
+                "--optimize",
+                "0"
+            ]
         );
+
+        for invalid in [
+            OcrMyPdfOptions {
+                jobs: Some(0),
+                ..OcrMyPdfOptions::default()
+            },
+            OcrMyPdfOptions {
+                optimize: Some(4),
+                ..OcrMyPdfOptions::default()
+            },
+        ] {
+            assert!(invalid.check().is_err());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "container")]
+    fn test_pdf_icc_profile() {
+        let mut pdf = PdfWriter::new(
+            Vec::new(),
+            WriterSettings {
+                icc_profile: Some(icc::srgb()),
+                ..WriterSettings::default()
+            },
+        )
+        .unwrap();
+        for format in [PixelFormat::Rgb, PixelFormat::Gray, PixelFormat::Rgba] {
+            let page = PageData::with_format(1, 1, format, vec![0; format.bytes_per_pixel()]);
+            pdf.add_page(&EncodedPage::new(&page, DPI, &CompressionConfig::default()).unwrap())
+                .unwrap();
+        }
+        let pdf_data = pdf.finish().unwrap();
+        crate::validate::validate(&mut std::io::Cursor::new(&pdf_data)).unwrap();
+        crate::validate::audit(&mut std::io::Cursor::new(&pdf_data)).unwrap();
+        let pdf = String::from_utf8_lossy(&pdf_data);
+
+        // One profile, for both RGB images but neither the gray image nor
+        // the alpha channel
+        assert_eq!(pdf.matches("/N 3\n/Alternate /DeviceRGB\n").count(), 1);
+        assert_eq!(pdf.matches("/ColorSpace [/ICCBased 3 0 R]").count(), 2);
+        assert_eq!(pdf.matches("/ColorSpace /DeviceGray").count(), 2);
+        assert!(!pdf.contains("/ColorSpace /DeviceRGB"));
+    }
+
+    #[test]
+    #[cfg(feature = "container")]
+    fn test_pdf_nup() {
+        let layout = PageLayout {
+            nup: 2,
+            ..PageLayout::default()
+        };
+        let mut pdf = PdfWriter::new(
+            Vec::new(),
+            WriterSettings {
+                layout,
+                ..WriterSettings::default()
+            },
+        )
+        .unwrap();
+        for rotation in [0, 90, 0] {
+            let mut page = PageData::new(1, 2, vec![0; 6]);
+            page.metadata.size_pts = Some((100.0, 200.0));
+            page.metadata.rotation = rotation;
+            pdf.add_page(&EncodedPage::new(&page, DPI, &CompressionConfig::default()).unwrap())
+                .unwrap();
+        }
+        assert_eq!(pdf.page_count(), 3);
+        let pdf_data = pdf.finish().unwrap();
+        crate::validate::validate(&mut std::io::Cursor::new(&pdf_data)).unwrap();
+        let pdf = String::from_utf8_lossy(&pdf_data);
+
+        // Two sheets, the first with both pages side by side, the second
+        // turned clockwise and centered in its cell
+        assert!(pdf.contains("/Count 2\n"));
+        assert!(pdf.contains("/MediaBox [0 0 200.00 200.00]"));
+        assert!(!pdf.contains("/Rotate"));
+        assert!(pdf.contains("/XObject << /Im0 3 0 R /Im1 4 0 R >>"));
+        assert!(pdf.contains("1.0000 0.0000 0.0000 1.0000 0.00 0.00 cm"));
+        assert!(pdf.contains("0.0000 -0.5000 0.5000 0.0000 100.00 125.00 cm"));
+    }
+
+    #[test]
+    #[cfg(feature = "container")]
+    fn test_drop_blank_pages() {
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_path = output_dir.path().join("safe.pdf");
+        // Flagged by the converter, and detected from the pixels
+        let mut flagged = PageData::new(2, 1, vec![0, 0, 0, 255, 255, 255]);
+        flagged.metadata.blank = true;
+        let uniform = PageData::new(2, 1, vec![250; 6]);
+        let text = PageData::new(2, 1, vec![0, 0, 0, 255, 255, 255]);
+        let pages = [flagged, uniform, text];
+
+        let options = ConversionOptions {
+            drop_blank_pages: true,
+            ..ConversionOptions::default()
+        };
+        let report = pages_to_pdf(
+            &pages,
+            None,
+            output_path.to_string_lossy().into_owned(),
+            &options,
+            &|_| {},
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        let pdf = std::fs::read(&output_path).unwrap();
+        assert!(String::from_utf8_lossy(&pdf).contains("/Count 1\n"));
+        assert_eq!(report.warnings, [Warning::BlankPagesDropped { count: 2 }]);
+    }
+
+    #[test]
+    #[cfg(feature = "container")]
+    fn test_rotate_pages() {
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_path = output_dir.path().join("safe.pdf");
+        let mut rotated = PageData::new(1, 1, vec![0, 0, 0]);
+        rotated.metadata.rotation = 90;
+        let pages = [PageData::new(1, 1, vec![0, 0, 0]), rotated];
+
+        let options = ConversionOptions {
+            rotation: 270,
+            ..ConversionOptions::default()
+        };
+        pages_to_pdf(
+            &pages,
+            None,
+            output_path.to_string_lossy().into_owned(),
+            &options,
+            &|_| {},
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        let pdf = std::fs::read(&output_path).unwrap();
+        let pdf = String::from_utf8_lossy(&pdf);
+        assert!(pdf.contains("/Rotate 270\n"));
+        // 90 + 270 turns the second page back upright
+        assert_eq!(pdf.matches("/Rotate").count(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "container")]
+    fn test_pages_are_written_in_order() {
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_path = output_dir.path().join("safe.pdf");
+        // More pages than are prepared at once, told apart by their width
+        let pages: Vec<PageData> = (1..=40)
+            .map(|width| PageData::new(width, 1, vec![0; width as usize * 3]))
+            .collect();
+
+        let written = std::sync::Mutex::new(Vec::new());
+        pages_to_pdf(
+            &pages,
+            None,
+            output_path.to_string_lossy().into_owned(),
+            &ConversionOptions::default(),
+            &|progress| {
+                if let Progress::WritingPage { page, total_pages } = progress {
+                    assert_eq!(total_pages, 40);
+                    written.lock().unwrap().push(page);
+                }
+            },
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        assert_eq!(*written.lock().unwrap(), (1..=40).collect::<Vec<_>>());
+
+        let pdf = std::fs::read(&output_path).unwrap();
+        let widths: Vec<u16> = String::from_utf8_lossy(&pdf)
+            .split("/Width ")
+            .skip(1)
+            .map(|rest| rest.split_whitespace().next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(widths, (1..=40).collect::<Vec<_>>());
     }
 }
@@ -0,0 +1,273 @@
+//! Limits protecting a shared gRPC service from being starved by one client
+//!
+//! Conversions, direct or queued, run in a bounded number of slots, each
+//! running at most one container. Direct conversions waiting for a slot and
+//! jobs waiting in the queue are bounded too, and each client IP address
+//! may only start so many conversions per minute.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Limits of the gRPC service
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ServerLimits {
+    /// Conversions running at the same time, direct or queued
+    pub max_conversions: usize,
+    /// Direct conversions waiting for a free slot, and jobs waiting in the
+    /// queue
+    pub max_queued: usize,
+    /// Conversions each client IP address may start per minute, if limited
+    pub per_client_per_minute: Option<u32>,
+    /// Largest document accepted
+    pub max_document_bytes: usize,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        ServerLimits {
+            max_conversions: 2,
+            max_queued: 100,
+            per_client_per_minute: None,
+            max_document_bytes: crate::grpc::MAX_MESSAGE_BYTES,
+        }
+    }
+}
+
+/// Slots in which conversions run, shared by direct conversions and the
+/// workers of the job queue
+pub struct Slots {
+    max_running: usize,
+    max_waiting: usize,
+    state: Mutex<SlotState>,
+    freed: Condvar,
+}
+
+#[derive(Default)]
+struct SlotState {
+    running: usize,
+    waiting: usize,
+}
+
+/// Place in the line for a slot
+pub struct Reservation {
+    slots: Arc<Slots>,
+}
+
+/// Slot held by a running conversion, freed when dropped
+pub struct Slot {
+    slots: Arc<Slots>,
+}
+
+impl Slots {
+    pub fn new(max_running: usize, max_waiting: usize) -> Arc<Self> {
+        Arc::new(Slots {
+            max_running: max_running.max(1),
+            max_waiting,
+            state: Mutex::default(),
+            freed: Condvar::new(),
+        })
+    }
+
+    /// Take a place in the line for a slot, unless `max_waiting`
+    /// conversions already wait for one
+    pub fn reserve(self: &Arc<Self>) -> Option<Reservation> {
+        let mut state = self.lock();
+        if state.running >= self.max_running && state.waiting >= self.max_waiting {
+            return None;
+        }
+        state.waiting += 1;
+        Some(Reservation {
+            slots: Arc::clone(self),
+        })
+    }
+
+    /// Wait for a slot, however many conversions wait for one already
+    pub fn acquire(self: &Arc<Self>) -> Slot {
+        self.lock().waiting += 1;
+        Reservation {
+            slots: Arc::clone(self),
+        }
+        .wait()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SlotState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Reservation {
+    /// Wait for a slot to be free
+    pub fn wait(self) -> Slot {
+        let slots = Arc::clone(&self.slots);
+        let mut state = slots.lock();
+        while state.running >= slots.max_running {
+            state = slots.freed.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        state.running += 1;
+        drop(state);
+        // Counted as running now, rather than waiting
+        drop(self);
+        Slot { slots }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.slots.lock().waiting -= 1;
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.slots.lock().running -= 1;
+        self.slots.freed.notify_one();
+    }
+}
+
+/// Most clients whose buckets are remembered: full ones are forgotten first,
+/// then those used the longest ago
+const MAX_TRACKED_CLIENTS: usize = 4096;
+
+/// Token buckets limiting how many conversions each client starts per
+/// minute
+///
+/// Clients are told apart by their IPv4 address, or the /64 prefix of their
+/// IPv6 address, which is usually all a single host gets.
+pub struct RateLimiter {
+    per_minute: u32,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Allow `per_minute` conversions per minute to each client, in bursts
+    /// of at most as many
+    pub fn new(per_minute: u32) -> Self {
+        RateLimiter {
+            per_minute: per_minute.max(1),
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Take a token for a conversion started by `client`, or return how
+    /// long it has to wait for the next one
+    pub fn check(&self, client: IpAddr) -> Result<(), Duration> {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let capacity = f64::from(self.per_minute);
+        let rate = capacity / 60.0;
+        let client = client_key(client);
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(&client) {
+            buckets.retain(|_, bucket| level(bucket, rate, capacity, now) < capacity);
+            if buckets.len() >= MAX_TRACKED_CLIENTS {
+                let oldest = buckets
+                    .iter()
+                    .min_by_key(|(_, bucket)| bucket.updated)
+                    .map(|(&client, _)| client);
+                if let Some(oldest) = oldest {
+                    buckets.remove(&oldest);
+                }
+            }
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = level(bucket, rate, capacity, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+/// Address the bucket of `client` is kept under: its IPv4 address, or the
+/// /64 prefix of its IPv6 address
+fn client_key(client: IpAddr) -> IpAddr {
+    match client {
+        IpAddr::V4(_) => client,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !u128::from(u64::MAX))),
+        },
+    }
+}
+
+/// Tokens in `bucket` at `now`, refilled at `rate` per second
+fn level(bucket: &Bucket, rate: f64, capacity: f64, now: Instant) -> f64 {
+    let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+    (bucket.tokens + elapsed * rate).min(capacity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots() {
+        let slots = Slots::new(1, 1);
+        let running = slots.reserve().unwrap().wait();
+        let waiting = slots.reserve().unwrap();
+        assert!(slots.reserve().is_none());
+
+        let handle = std::thread::spawn(move || drop(waiting.wait()));
+        drop(running);
+        handle.join().unwrap();
+        let state = slots.lock();
+        assert_eq!((state.running, state.waiting), (0, 0));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(2);
+        let client: IpAddr = [192, 0, 2, 1].into();
+        let other: IpAddr = [192, 0, 2, 2].into();
+        let start = Instant::now();
+        assert!(limiter.check_at(client, start).is_ok());
+        assert!(limiter.check_at(client, start).is_ok());
+        let wait = limiter.check_at(client, start).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(30));
+        assert!(limiter.check_at(other, start).is_ok());
+        assert!(limiter.check_at(client, start + wait).is_ok());
+        assert!(limiter.check_at(client, start + wait).is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter_clients() {
+        let limiter = RateLimiter::new(1);
+        let start = Instant::now();
+        // Addresses of the same /64 share a bucket
+        let client: IpAddr = "2001:db8::1".parse().unwrap();
+        let neighbour: IpAddr = "2001:db8::ffff:1234".parse().unwrap();
+        assert!(limiter.check_at(client, start).is_ok());
+        assert!(limiter.check_at(neighbour, start).is_err());
+        assert!(limiter
+            .check_at("2001:db8:0:1::1".parse().unwrap(), start)
+            .is_ok());
+        let mapped: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        assert!(limiter.check_at(mapped, start).is_ok());
+        assert!(limiter.check_at([192, 0, 2, 1].into(), start).is_err());
+
+        // Clients that spent their tokens can't grow the buckets without
+        // bound: the oldest ones are forgotten
+        for i in 0..2 * MAX_TRACKED_CLIENTS as u32 {
+            let now = start + Duration::from_millis(u64::from(i));
+            assert!(limiter.check_at(IpAddr::V4(i.into()), now).is_ok());
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), MAX_TRACKED_CLIENTS);
+        assert!(buckets.contains_key(&IpAddr::V4((2 * MAX_TRACKED_CLIENTS as u32 - 1).into())));
+    }
+}
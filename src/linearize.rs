@@ -0,0 +1,703 @@
+//! Linearization of safe PDFs, so that viewers reading them over HTTP show
+//! the first page before the rest of the file arrives
+//!
+//! The PDF is written as usual first, then rewritten in the order Annex F of
+//! the PDF specification gives: the linearization parameters and the
+//! cross-reference table of the first page, the catalog, the hint tables,
+//! everything the first page needs, each of the other pages with the objects
+//! only it uses, the objects several pages share, and the main
+//! cross-reference table. The objects of the first page are numbered last, so
+//! that each table is a single subsection. Stream data is copied from the
+//! first PDF rather than held in memory.
+
+use crate::validate::{self, Document, Object, Parser, XrefEntry};
+use crate::CountingWriter;
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Largest offset, as hint tables give offsets in 32 bits
+const MAX_OFFSET: u64 = u32::MAX as u64;
+
+/// End of objects with a stream, after their data
+const STREAM_END: &[u8] = b"\nendstream\nendobj\n";
+
+/// Object of the PDF, renumbered
+struct Rewritten {
+    /// The object up to its stream data, or up to `endobj` included
+    head: Vec<u8>,
+    /// Start and length of its stream data in the first PDF
+    stream: Option<(u64, u64)>,
+}
+
+impl Rewritten {
+    fn len(&self) -> u64 {
+        self.head.len() as u64
+            + self
+                .stream
+                .map_or(0, |(_, length)| length + STREAM_END.len() as u64)
+    }
+}
+
+/// Values of the linearization dictionary
+#[derive(Default)]
+struct Parameters {
+    length: u64,
+    /// Offset and length of the hint stream
+    hints: (u64, u64),
+    first_page: u32,
+    end_of_first_page: u64,
+    pages: usize,
+    /// Offset of the end of line before the first entry of the main table
+    main_xref_entries: u64,
+}
+
+impl Parameters {
+    /// The dictionary, padded to the length it has with the largest values,
+    /// as it is laid out before they are known
+    fn object(&self, number: u32) -> String {
+        let dict = |parameters: &Parameters| {
+            format!(
+                "<< /Linearized 1 /L {} /H [{} {}] /O {} /E {} /N {} /T {} >>",
+                parameters.length,
+                parameters.hints.0,
+                parameters.hints.1,
+                parameters.first_page,
+                parameters.end_of_first_page,
+                parameters.pages,
+                parameters.main_xref_entries
+            )
+        };
+        let width = dict(&Parameters {
+            length: MAX_OFFSET,
+            hints: (MAX_OFFSET, MAX_OFFSET),
+            first_page: u32::MAX,
+            end_of_first_page: MAX_OFFSET,
+            pages: u32::MAX as usize,
+            main_xref_entries: MAX_OFFSET,
+        })
+        .len();
+        format!("{number} 0 obj\n{:width$}\nendobj\n", dict(self))
+    }
+}
+
+/// Trailer of the first-page table, padded like the linearization
+/// dictionary; the startxref of the file gives the offset of this table
+fn first_trailer(size: u32, catalog: u32, info: Option<u32>, main_xref: u64) -> String {
+    let info = info.map_or(String::new(), |info| format!(" /Info {info} 0 R"));
+    let dict =
+        |main_xref| format!("<< /Size {size} /Root {catalog} 0 R{info} /Prev {main_xref} >>");
+    let width = dict(MAX_OFFSET).len();
+    format!("trailer\n{:width$}\nstartxref\n0\n%%EOF\n", dict(main_xref))
+}
+
+/// Rewrite the PDF at `input`, written by this crate, as a linearized PDF at
+/// `output`
+pub(crate) fn linearize_file(input: &Path, output: &Path) -> Result<()> {
+    let mut input =
+        BufReader::new(File::open(input).context("Failed to open the PDF to linearize")?);
+    let output = File::create(output).context("Failed to create the linearized PDF")?;
+    linearize(&mut input, BufWriter::new(output)).context("Failed to linearize the PDF")?;
+    Ok(())
+}
+
+/// Rewrite the PDF read from `input`, which has a single cross-reference
+/// table, as a linearized PDF written to `output`
+pub(crate) fn linearize<R: Read + Seek, W: Write>(input: &mut R, output: W) -> Result<W> {
+    let (document, trailer) = validate::read_document(input)?;
+    let pages = document.pages(&trailer)?;
+    let mut offsets = BTreeMap::new();
+    for (&number, entry) in &document.entries {
+        match *entry {
+            XrefEntry::InFile {
+                offset,
+                generation: 0,
+            } => offsets.insert(number, offset),
+            _ => {
+                anyhow::bail!("Only PDFs without object streams nor generations can be linearized")
+            }
+        };
+    }
+    let Some(&Object::Ref(catalog, _)) = trailer.get("Root") else {
+        anyhow::bail!("Trailer has no /Root");
+    };
+
+    // Each page object comes first in its part of the file
+    let needs: Vec<BTreeSet<u32>> = pages.iter().map(|&page| needed(&document, page)).collect();
+    let mut first_page = vec![pages[0]];
+    first_page.extend(needs[0].iter().filter(|&&number| number != pages[0]));
+    let mut users = BTreeMap::<u32, usize>::new();
+    for need in &needs[1..] {
+        for &number in need.difference(&needs[0]) {
+            *users.entry(number).or_default() += 1;
+        }
+    }
+    let shared: Vec<u32> = users
+        .iter()
+        .filter(|(_, &count)| count > 1)
+        .map(|(&number, _)| number)
+        .collect();
+    let other_pages: Vec<Vec<u32>> = pages[1..]
+        .iter()
+        .zip(&needs[1..])
+        .map(|(&page, need)| {
+            let mut objects = vec![page];
+            objects.extend(need.iter().filter(|&&number| {
+                number != page && !needs[0].contains(&number) && users[&number] == 1
+            }));
+            objects
+        })
+        .collect();
+    let mut placed: HashSet<u32> = first_page.iter().chain(&shared).copied().collect();
+    placed.extend(other_pages.iter().flatten());
+    placed.insert(catalog);
+    // The page tree, which no page needs
+    let others = offsets.keys().filter(|number| !placed.contains(number));
+    let main: Vec<u32> = other_pages
+        .iter()
+        .flatten()
+        .chain(&shared)
+        .chain(others)
+        .copied()
+        .collect();
+
+    // The main table lists the objects after the first page, from 1
+    let linearization_number = main.len() as u32 + 1;
+    let catalog_number = linearization_number + 1;
+    let hints_number = linearization_number + 2;
+    let size = hints_number + 1 + first_page.len() as u32;
+    let mut numbers: BTreeMap<u32, u32> = main
+        .iter()
+        .zip(1..)
+        .map(|(&number, new)| (number, new))
+        .collect();
+    numbers.insert(catalog, catalog_number);
+    for (&number, new) in first_page.iter().zip(hints_number + 1..) {
+        numbers.insert(number, new);
+    }
+    let info = match trailer.get("Info") {
+        Some(&Object::Ref(info, _)) => Some(numbers[&info]),
+        Some(_) => anyhow::bail!("Trailer /Info isn't a reference"),
+        None => None,
+    };
+    let mut rewritten = BTreeMap::new();
+    for (&number, &offset) in &offsets {
+        rewritten.insert(number, rewrite(input, &document, number, offset, &numbers)?);
+    }
+    let length = |objects: &[u32]| -> u64 { objects.iter().map(|n| rewritten[n].len()).sum() };
+
+    // Hint tables give offsets as if the hint stream weren't there
+    let first_offset = *offsets.values().min().context("PDF has no objects")?;
+    let header = validate::read_at(input, 0, first_offset)?;
+    let linearization_len = Parameters::default().object(linearization_number).len() as u64;
+    let first_xref_offset = header.len() as u64 + linearization_len;
+    let first_xref_head = format!(
+        "xref\n{linearization_number} {}\n",
+        size - linearization_number
+    );
+    let catalog_offset = first_xref_offset
+        + first_xref_head.len() as u64
+        + 20 * (size - linearization_number) as u64
+        + first_trailer(size, catalog_number, info, 0).len() as u64;
+    let hints_offset = catalog_offset + rewritten[&catalog].len();
+    let first_page_len = length(&first_page);
+    let other_pages_len: u64 = other_pages.iter().map(|objects| length(objects)).sum();
+
+    let groups: Vec<u32> = first_page.iter().chain(&shared).copied().collect();
+    let group_index: BTreeMap<u32, usize> = groups
+        .iter()
+        .enumerate()
+        .map(|(index, &number)| (number, index))
+        .collect();
+    let mut page_hints = vec![PageHint {
+        objects: first_page.len(),
+        length: first_page_len,
+        shared: Vec::new(),
+    }];
+    for (objects, need) in other_pages.iter().zip(&needs[1..]) {
+        page_hints.push(PageHint {
+            objects: objects.len(),
+            length: length(objects),
+            shared: need
+                .iter()
+                .filter_map(|number| group_index.get(number).copied())
+                .collect(),
+        });
+    }
+    let (data, shared_table) = hint_tables(&HintTables {
+        pages: &page_hints,
+        first_page_offset: hints_offset,
+        groups: &groups
+            .iter()
+            .map(|n| rewritten[n].len())
+            .collect::<Vec<_>>(),
+        first_page_groups: first_page.len(),
+        first_shared: shared
+            .first()
+            .map(|n| (numbers[n], hints_offset + first_page_len + other_pages_len)),
+    });
+    let mut hints = format!(
+        "{hints_number} 0 obj\n<< /S {shared_table} /Length {} >>\nstream\n",
+        data.len()
+    )
+    .into_bytes();
+    hints.extend(data);
+    hints.extend(STREAM_END);
+    let hints_len = hints.len() as u64;
+
+    let first_page_offset = hints_offset + hints_len;
+    let main_offset = first_page_offset + first_page_len;
+    let main_xref_offset = main_offset + length(&main);
+    let main_xref_head = format!("xref\n0 {linearization_number}\n");
+    let main_trailer =
+        format!("trailer\n<< /Size {size} >>\nstartxref\n{first_xref_offset}\n%%EOF\n");
+    let parameters = Parameters {
+        length: main_xref_offset
+            + main_xref_head.len() as u64
+            + 20 * linearization_number as u64
+            + main_trailer.len() as u64,
+        hints: (hints_offset, hints_len),
+        first_page: numbers[&pages[0]],
+        end_of_first_page: main_offset,
+        pages: pages.len(),
+        main_xref_entries: main_xref_offset + main_xref_head.len() as u64 - 1,
+    };
+    if parameters.length > MAX_OFFSET {
+        anyhow::bail!("PDFs of more than 4 GiB can't be linearized");
+    }
+
+    let mut out = CountingWriter {
+        writer: output,
+        written: 0,
+    };
+    out.write_all(&header)?;
+    out.write_all(parameters.object(linearization_number).as_bytes())?;
+    out.write_all(first_xref_head.as_bytes())?;
+    let mut offset = first_page_offset;
+    for entry in [header.len() as u64, catalog_offset, hints_offset]
+        .into_iter()
+        .chain(first_page.iter().map(|number| {
+            let start = offset;
+            offset += rewritten[number].len();
+            start
+        }))
+    {
+        out.write_all(format!("{entry:010} 00000 n \n").as_bytes())?;
+    }
+    out.write_all(first_trailer(size, catalog_number, info, main_xref_offset).as_bytes())?;
+    write_object(input, &mut out, &rewritten[&catalog])?;
+    out.write_all(&hints)?;
+    for number in first_page.iter().chain(&main) {
+        write_object(input, &mut out, &rewritten[number])?;
+    }
+
+    out.write_all(main_xref_head.as_bytes())?;
+    out.write_all(b"0000000000 65535 f \n")?;
+    let mut offset = main_offset;
+    for number in &main {
+        out.write_all(format!("{offset:010} 00000 n \n").as_bytes())?;
+        offset += rewritten[number].len();
+    }
+    out.write_all(main_trailer.as_bytes())?;
+    if out.written as u64 != parameters.length {
+        anyhow::bail!(
+            "Linearized PDF is {} bytes long instead of {}",
+            out.written,
+            parameters.length
+        );
+    }
+    out.flush()?;
+    Ok(out.writer)
+}
+
+/// Objects the page `page` needs, itself included: those it refers to,
+/// directly or not, without going up the page tree
+fn needed(document: &Document, page: u32) -> BTreeSet<u32> {
+    let mut needed = BTreeSet::new();
+    let mut stack = vec![page];
+    while let Some(number) = stack.pop() {
+        if needed.insert(number) {
+            if let Some(object) = document.objects.get(&number) {
+                references(object, &mut stack);
+            }
+        }
+    }
+    needed
+}
+
+/// Add the objects `object` refers to, except its parent, to `found`
+fn references(object: &Object, found: &mut Vec<u32>) {
+    match object {
+        Object::Ref(number, _) => found.push(*number),
+        Object::Array(items) => {
+            for item in items {
+                references(item, found);
+            }
+        }
+        Object::Dict(dict) => {
+            for (key, value) in dict {
+                if key != "Parent" {
+                    references(value, found);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Read the object `number`, at `offset` of `input`, with the numbers of
+/// itself and of the objects it refers to replaced by `numbers`
+fn rewrite<R: Read + Seek>(
+    input: &mut R,
+    document: &Document,
+    number: u32,
+    offset: u64,
+    numbers: &BTreeMap<u32, u32>,
+) -> Result<Rewritten> {
+    let stream = document.streams.get(&number).copied();
+    let data = validate::read_at(
+        input,
+        offset,
+        validate::MAX_OBJECT_BYTES.min(document.len - offset),
+    )?;
+    // `number 0 obj`, as read_document checked
+    let mut parser = Parser::new(&data);
+    parser.integer::<u32>();
+    parser.integer::<u16>();
+    parser.token();
+    let body = parser.pos;
+    parser.object(0)?;
+    let end = match stream {
+        Some((start, _)) => (start - offset) as usize,
+        None => {
+            parser.token();
+            parser.pos
+        }
+    };
+
+    let mut head = format!("{} 0 obj", numbers[&number]).into_bytes();
+    let mut copied = body;
+    for range in &parser.references {
+        head.extend_from_slice(&data[copied..range.start]);
+        let target: u32 = std::str::from_utf8(&data[range.clone()])?.parse()?;
+        let target = numbers
+            .get(&target)
+            .with_context(|| format!("Object {number} refers to missing object {target}"))?;
+        head.extend_from_slice(target.to_string().as_bytes());
+        copied = range.end;
+    }
+    head.extend_from_slice(&data[copied..end]);
+    if stream.is_none() {
+        head.push(b'\n');
+    }
+    Ok(Rewritten { head, stream })
+}
+
+/// Write `object`, copying its stream data from `input`
+fn write_object<R: Read + Seek, W: Write>(
+    input: &mut R,
+    out: &mut W,
+    object: &Rewritten,
+) -> Result<()> {
+    out.write_all(&object.head)?;
+    if let Some((start, length)) = object.stream {
+        input.seek(SeekFrom::Start(start))?;
+        let copied = std::io::copy(&mut input.take(length), out)?;
+        if copied != length {
+            anyhow::bail!("PDF to linearize is truncated");
+        }
+        out.write_all(STREAM_END)?;
+    }
+    Ok(())
+}
+
+/// Entry of the page offset hint table
+struct PageHint {
+    objects: usize,
+    /// Length of the objects of the page, from its page object
+    length: u64,
+    /// Indices in the shared object hint table of the objects of other
+    /// pages the page needs
+    shared: Vec<usize>,
+}
+
+/// Content of the primary hint stream
+struct HintTables<'a> {
+    pages: &'a [PageHint],
+    first_page_offset: u64,
+    /// Length of each object of the first page, then of each shared object,
+    /// each in a group of its own
+    groups: &'a [u64],
+    first_page_groups: usize,
+    /// Number and offset of the first shared object
+    first_shared: Option<(u32, u64)>,
+}
+
+/// Bits needed to write `value`
+fn bits(value: u64) -> u64 {
+    (u64::BITS - value.leading_zeros()) as u64
+}
+
+/// Page offset and shared object hint tables, and the offset of the latter
+///
+/// Like other writers, the offsets and lengths of content streams are given
+/// as those of whole pages, and shared objects have no position in pages.
+fn hint_tables(tables: &HintTables<'_>) -> (Vec<u8>, usize) {
+    let mut out = BitWriter::default();
+    let pages = tables.pages;
+    let least_objects = pages.iter().map(|page| page.objects).min().unwrap_or(0) as u64;
+    let most_objects = pages.iter().map(|page| page.objects).max().unwrap_or(0) as u64;
+    let least_length = pages.iter().map(|page| page.length).min().unwrap_or(0);
+    let most_length = pages.iter().map(|page| page.length).max().unwrap_or(0);
+    let most_shared = pages
+        .iter()
+        .map(|page| page.shared.len())
+        .max()
+        .unwrap_or(0) as u64;
+    let greatest_shared = pages
+        .iter()
+        .flat_map(|page| page.shared.iter().copied())
+        .max()
+        .unwrap_or(0) as u64;
+    let objects_bits = bits(most_objects - least_objects);
+    let length_bits = bits(most_length - least_length);
+    let shared_bits = bits(greatest_shared);
+    for (value, width) in [
+        (least_objects, 32),
+        (tables.first_page_offset, 32),
+        (objects_bits, 16),
+        (least_length, 32),
+        (length_bits, 16),
+        (0, 32),
+        (0, 16),
+        (least_length, 32),
+        (length_bits, 16),
+        (bits(most_shared), 16),
+        (shared_bits, 16),
+        (0, 16),
+        (1, 16),
+    ] {
+        out.write(value, width);
+    }
+    // Each item of the entries is given for all pages before the next one
+    for page in pages {
+        out.write(page.objects as u64 - least_objects, objects_bits);
+    }
+    out.align();
+    for page in pages {
+        out.write(page.length - least_length, length_bits);
+    }
+    out.align();
+    for page in pages {
+        out.write(page.shared.len() as u64, bits(most_shared));
+    }
+    out.align();
+    for page in pages {
+        for &index in &page.shared {
+            out.write(index as u64, shared_bits);
+        }
+    }
+    out.align();
+    // Content stream lengths, as the numerators and content stream offsets
+    // take no bits
+    for page in pages {
+        out.write(page.length - least_length, length_bits);
+    }
+    out.align();
+
+    let shared_table = out.bytes.len();
+    let least_group = tables.groups.iter().copied().min().unwrap_or(0);
+    let most_group = tables.groups.iter().copied().max().unwrap_or(0);
+    let group_bits = bits(most_group - least_group);
+    let (first_shared, first_shared_offset) = tables.first_shared.unwrap_or((0, 0));
+    for (value, width) in [
+        (first_shared as u64, 32),
+        (first_shared_offset, 32),
+        (tables.first_page_groups as u64, 32),
+        (tables.groups.len() as u64, 32),
+        (0, 16),
+        (least_group, 32),
+        (group_bits, 16),
+    ] {
+        out.write(value, width);
+    }
+    for &group in tables.groups {
+        out.write(group - least_group, group_bits);
+    }
+    out.align();
+    // No MD5 signatures
+    for _ in tables.groups {
+        out.write(0, 1);
+    }
+    out.align();
+    (out.bytes, shared_table)
+}
+
+/// Writer of bits, from the most significant
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits used in the last byte, from 0 to 7
+    used: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, width: u64) {
+        for bit in (0..width).rev() {
+            if self.used == 0 {
+                self.bytes.push(0);
+            }
+            let last = self.bytes.last_mut().unwrap();
+            *last |= (((value >> bit) & 1) as u8) << (7 - self.used);
+            self.used = (self.used + 1) % 8;
+        }
+    }
+
+    /// Start the next item at a byte boundary
+    fn align(&mut self) {
+        self.used = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        CompressionConfig, EncodedPage, PageData, PageLayout, PageNumbers, PdfWriter, Provenance,
+        WriterSettings, DPI,
+    };
+    use std::io::Cursor;
+
+    /// A PDF of `pages` pages with page numbers, whose font is shared
+    fn sample_pdf(pages: usize) -> Vec<u8> {
+        let mut pdf = PdfWriter::new(
+            Vec::new(),
+            WriterSettings {
+                layout: PageLayout {
+                    page_numbers: Some(PageNumbers::default()),
+                    ..PageLayout::default()
+                },
+                ..WriterSettings::default()
+            },
+        )
+        .unwrap();
+        for index in 0..pages {
+            let page = PageData::new(2, 2, vec![index as u8; 12]);
+            pdf.add_page(&EncodedPage::new(&page, DPI, &CompressionConfig::default()).unwrap())
+                .unwrap();
+        }
+        pdf.finish().unwrap()
+    }
+
+    fn linearized(pdf: Vec<u8>) -> Vec<u8> {
+        linearize(&mut Cursor::new(pdf), Vec::new()).unwrap()
+    }
+
+    fn find(pdf: &[u8], needle: &str) -> Option<usize> {
+        pdf.windows(needle.len())
+            .position(|window| window == needle.as_bytes())
+    }
+
+    #[test]
+    fn test_linearize() {
+        let pdf = linearized(sample_pdf(3));
+        validate::validate(&mut Cursor::new(&pdf)).unwrap();
+        validate::audit(&mut Cursor::new(&pdf)).unwrap();
+
+        // The first page and the font all pages share come before the end of
+        // the first page, numbered after the other objects
+        let parameters = format!("10 0 obj\n<< /Linearized 1 /L {} /H [", pdf.len());
+        assert!(find(&pdf[..100], &parameters).is_some());
+        let end = find(&pdf, " /E ").unwrap() + 4;
+        let end: usize = std::str::from_utf8(&pdf[end..end + 4])
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(find(&pdf[..end], "/Im0 14 0 R").is_some());
+        assert!(find(&pdf[..end], "/Subtype /Type1").is_some());
+        assert!(find(&pdf[end..], "/Im1 2 0 R").is_some());
+        assert!(find(&pdf[end..], "/FStamp 15 0 R").is_some());
+        assert!(pdf.ends_with(b"trailer\n<< /Size 18 >>\nstartxref\n148\n%%EOF\n"));
+
+        // Viewers ignore the parameters of files changed since
+        let mut patched = pdf.clone();
+        patched[find(&pdf, " /N 3 ").unwrap() + 4] = b'4';
+        let error = validate::validate(&mut Cursor::new(patched)).unwrap_err();
+        assert!(format!("{error:#}").contains("not the /N it declares"));
+    }
+
+    #[test]
+    fn test_linearize_one_page() {
+        let pdf = linearized(sample_pdf(1));
+        validate::validate(&mut Cursor::new(&pdf)).unwrap();
+        // Only the page tree is in the main table
+        assert!(find(&pdf, "\nxref\n0 2\n").is_some());
+    }
+
+    #[test]
+    fn test_linearize_info() {
+        let mut pdf = PdfWriter::new(
+            Vec::new(),
+            WriterSettings {
+                provenance: Some(Provenance::new(Some("/tmp/report.docx"))),
+                ..WriterSettings::default()
+            },
+        )
+        .unwrap();
+        for _ in 0..2 {
+            let page = PageData::new(2, 2, vec![0; 12]);
+            pdf.add_page(&EncodedPage::new(&page, DPI, &CompressionConfig::default()).unwrap())
+                .unwrap();
+        }
+        let pdf = linearized(pdf.finish().unwrap());
+        validate::validate(&mut Cursor::new(&pdf)).unwrap();
+        validate::audit(&mut Cursor::new(&pdf)).unwrap();
+        // The first-page trailer points to the information dictionary, with
+        // the page tree in the main part of the file
+        let start = find(&pdf, "/Info ").unwrap();
+        let info: u32 = String::from_utf8_lossy(&pdf[start + 6..])
+            .split(' ')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let object = find(&pdf, &format!("\n{info} 0 obj\n")).unwrap();
+        assert!(find(&pdf[object..], "/Keywords (original: report.docx; ").is_some());
+    }
+
+    #[test]
+    fn test_hint_tables() {
+        let pages = [
+            PageHint {
+                objects: 3,
+                length: 300,
+                shared: Vec::new(),
+            },
+            PageHint {
+                objects: 2,
+                length: 200,
+                shared: vec![2],
+            },
+        ];
+        let (data, shared_table) = hint_tables(&HintTables {
+            pages: &pages,
+            first_page_offset: 1000,
+            groups: &[100, 150, 50],
+            first_page_groups: 3,
+            first_shared: None,
+        });
+        // 36 bytes of header, then each item of the entries: 1 bit for
+        // the number of objects, 7 for lengths, 1 for the number of shared
+        // objects and 2 for their indices
+        assert_eq!(shared_table, 36 + 1 + 2 + 1 + 1 + 2);
+        assert_eq!(&data[..8], &[0, 0, 0, 2, 0, 0, 0x03, 0xe8]);
+        assert_eq!(data[36], 0b1000_0000);
+        assert_eq!(&data[37..39], &[0b1100_1000, 0]);
+        assert_eq!(data[39], 0b0100_0000);
+        assert_eq!(data[40], 0b1000_0000);
+        // The group lengths, from 50, take 7 bits each
+        assert_eq!(data.len(), shared_table + 24 + 3 + 1);
+    }
+}
@@ -0,0 +1,51 @@
+//! Logger printing the library's log records to stderr
+//!
+//! The library reports what it is doing through the `log` crate. Hosts with
+//! their own logging (such as the Python bindings) route the records there;
+//! the CLI and the C API install this logger instead.
+
+use crate::UNTRUSTED_LOG_TARGET;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::io::{IsTerminal, Write};
+
+const ANSI_GRAY: &str = "\x1b[90m";
+const ANSI_RESET: &str = "\x1b[0m";
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Debug && metadata.target().starts_with("dangerzone_rs")
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut stderr = std::io::stderr().lock();
+        // Container output is grayed out on terminals, to set it apart from
+        // our own messages
+        let _ = if record.target() == UNTRUSTED_LOG_TARGET && stderr.is_terminal() {
+            writeln!(stderr, "{ANSI_GRAY}{}{ANSI_RESET}", record.args())
+        } else {
+            match record.level() {
+                Level::Error => writeln!(stderr, "Error: {}", record.args()),
+                Level::Warn => writeln!(stderr, "Warning: {}", record.args()),
+                _ => writeln!(stderr, "{}", record.args()),
+            }
+        };
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// Print log records to stderr, unless a logger is already installed
+pub fn init_stderr() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Debug);
+    }
+}
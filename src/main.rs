@@ -1,48 +1,1685 @@
-use anyhow::Result;
-use clap::Parser;
-use dangerzone_rs::convert_document;
-use util::replace_control_chars;
+use anyhow::{Context, Result};
+use clap::builder::RangedU64ValueParser;
+use clap::{Parser, Subcommand};
+#[cfg(feature = "archive")]
+use dangerzone_rs::archive::{self, ArchiveLimits, EntryOutcome};
+#[cfg(feature = "audit-log")]
+use dangerzone_rs::audit_log::{self, ConversionRecord};
+use dangerzone_rs::cleanup::cleanup_containers;
+#[cfg(feature = "email")]
+use dangerzone_rs::email;
+#[cfg(feature = "history")]
+use dangerzone_rs::history::{self, History, PendingEntry};
+#[cfg(feature = "grpc")]
+use dangerzone_rs::jobs::{self, JobQueue};
+#[cfg(feature = "grpc")]
+use dangerzone_rs::limits::{ServerLimits, Slots};
+use dangerzone_rs::naming::OutputTemplate;
+use dangerzone_rs::ocr::ocr_languages;
+use dangerzone_rs::preview::{self, PreviewOptions};
+use dangerzone_rs::redact::Redactions;
+use dangerzone_rs::resume;
+use dangerzone_rs::vm::Vm;
+use dangerzone_rs::{
+    batch_output_paths, convert_doc_to_pixel_stream, convert_document_to_pixel_dump,
+    convert_document_with_options, extract_text, pixels_to_safe_pdf, replace_control_chars, warmup,
+    CancellationToken, ColorProfile, CompressionConfig, ContainerHardening, ConversionOptions,
+    ConversionReport, OcrMyPdfOptions, OcrSidecar, PageCleanup, PageLayout, PageNumbers, Paper,
+    Progress, Runtime, StampPosition, Warning, DEFAULT_MAX_OUTPUT_BYTES, DPI,
+};
+use std::fs::File;
+use std::io::{BufWriter, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-mod util;
+use hooks::Hooks;
+
+mod hooks;
+#[cfg(unix)]
+mod integrate;
+#[cfg(feature = "tui")]
+mod tui;
 
 /// A simple Dangerzone CLI implementation in Rust
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Input document path
-    #[arg(short, long)]
-    input: String,
+    #[cfg_attr(unix, arg(short, long, required_unless_present_any = ["rpc", "uri"]))]
+    #[cfg_attr(not(unix), arg(short, long, required_unless_present = "rpc"))]
+    input: Option<String>,
 
-    /// Output PDF path
-    #[arg(short, long)]
-    output: String,
+    /// Output PDF path, or directory of pixels with --output-format pixels
+    #[cfg_attr(unix, arg(short, long, required_unless_present_any = ["rpc", "uri"]))]
+    #[cfg_attr(not(unix), arg(short, long, required_unless_present = "rpc"))]
+    output: Option<String>,
+
+    /// Write a safe PDF, or the raw pixels of each page with an index.json,
+    /// as the sandbox produced them
+    #[arg(
+        long,
+        value_enum,
+        default_value = "pdf",
+        conflicts_with_all = ["ocr", "verify"]
+    )]
+    output_format: OutputFormat,
 
     /// Enable OCR to add text layer to PDF
     #[arg(long, default_value = "false")]
     ocr: bool,
+
+    /// Fail if OCR can't add the text layer, instead of writing the safe PDF
+    /// without one
+    #[arg(long, requires = "ocr")]
+    ocr_required: bool,
+
+    /// Also write the text found by OCR to this file, with pages separated by
+    /// form feeds
+    #[arg(long, value_name = "PATH", requires = "ocr")]
+    text_sidecar: Option<String>,
+
+    /// Also write the OCR results next to the safe PDF, as hOCR (.hocr) or
+    /// ALTO XML (.alto.xml)
+    #[arg(long, value_name = "FORMAT", requires = "ocr")]
+    ocr_sidecar: Option<OcrSidecar>,
+
+    /// Tesseract language(s) used for OCR, e.g. "eng" or "eng+deu", or "auto"
+    /// to detect the language of the document with tesseract
+    #[arg(long, default_value = "eng")]
+    ocr_lang: String,
+
+    /// Straighten crooked pages with ocrmypdf
+    #[arg(long, requires = "ocr")]
+    ocr_deskew: bool,
+
+    /// Clean up pages with unpaper before ocrmypdf recognizes them, without
+    /// changing the pages of the safe PDF
+    #[arg(long, requires = "ocr")]
+    ocr_clean: bool,
+
+    /// Turn pages whose text is sideways or upside down upright with ocrmypdf
+    #[arg(long, requires = "ocr")]
+    ocr_rotate_pages: bool,
+
+    /// Number of pages ocrmypdf recognizes at once (default: one per CPU)
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "ocr",
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    ocr_jobs: Option<usize>,
+
+    /// Optimization of the safe PDF by ocrmypdf, from 0 (none) to 3
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        requires = "ocr",
+        value_parser = clap::value_parser!(u8).range(0..=3)
+    )]
+    ocr_optimize: Option<u8>,
+
+    /// Run ocrmypdf under bubblewrap, with access to the PDF only (Linux)
+    #[arg(long, requires = "ocr")]
+    ocr_sandbox: bool,
+
+    /// Kill ocrmypdf if it runs longer than this many seconds, and treat OCR
+    /// as failed
+    #[arg(long, value_name = "SECONDS", requires = "ocr")]
+    ocr_timeout: Option<u64>,
+
+    /// Resolution of the page images, used to size the PDF pages
+    #[arg(long, default_value_t = DPI)]
+    dpi: f32,
+
+    /// Abort the conversion if the container runs longer than this many seconds
+    #[arg(long)]
+    timeout: Option<u64>,
+
+    /// Runtime running the conversion sandbox: podman, docker, gvisor (podman
+    /// with runsc), container (Apple's container CLI on macOS), or bwrap to
+    /// run a locally installed converter under bubblewrap
+    #[arg(long, default_value_t = Runtime::Podman)]
+    runtime: Runtime,
+
+    /// Platform of the converter image, such as linux/amd64, to run it under
+    /// emulation [default: that of the host]
+    #[arg(long, value_name = "OS/ARCH")]
+    image_platform: Option<String>,
+
+    /// Start the container runtime's virtual machine (macOS and Windows)
+    /// without asking if it is stopped
+    #[arg(long)]
+    auto_start_vm: bool,
+
+    /// Refuse to run a converter image older or newer than supported,
+    /// instead of warning
+    #[arg(long)]
+    strict_image_version: bool,
+
+    /// Run the container through the runtime's API socket instead of its
+    /// command-line client (requires the runtime-api feature)
+    #[arg(long)]
+    runtime_api: bool,
+
+    /// Run the container with a read-only root filesystem, a size-limited
+    /// scratch tmpfs and, with podman, its own user namespace
+    #[arg(long)]
+    hardened: bool,
+
+    /// Set a variable in the sandbox to tune the converter, such as
+    /// SAL_USE_VCLPLUGIN=svp for LibreOffice or TZ=Europe/Paris; only a few
+    /// variables are allowed. Repeat for several.
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_env)]
+    container_env: Vec<(String, String)>,
+
+    /// Print the command starting the sandbox before running it
+    #[arg(long)]
+    print_command: bool,
+
+    /// If the sandbox fails, write a shell script starting it the same way to
+    /// this path, taking the document as its argument
+    #[arg(long, value_name = "PATH")]
+    repro_script: Option<PathBuf>,
+
+    /// Abort the conversion if the container writes more than this many MiB
+    /// of pixel data
+    #[arg(long, default_value_t = DEFAULT_MAX_OUTPUT_BYTES >> 20)]
+    max_output_size: u64,
+
+    /// Keep at most this many MiB of pixels in memory, and spool the pages
+    /// after them to an encrypted temporary file
+    #[arg(long, value_name = "MIB")]
+    spool_after: Option<u64>,
+
+    /// Embed the image of each page in strips of at most this many MiB of
+    /// pixels
+    #[arg(long, value_name = "MIB", value_parser = clap::value_parser!(u64).range(1..))]
+    image_strip_size: Option<u64>,
+
+    /// Use as little memory as possible, at the cost of speed: spool pages
+    /// to disk, compress one page at a time and embed images in strips
+    #[arg(long)]
+    low_memory: bool,
+
+    /// Leave blank pages, such as the backsides of duplex scans, out of the
+    /// safe PDF
+    #[arg(long)]
+    drop_blank_pages: bool,
+
+    /// Turn every page clockwise by this many degrees (0, 90, 180 or 270)
+    #[arg(long, value_name = "DEGREES", default_value_t = 0, value_parser = parse_rotation)]
+    rotate: u16,
+
+    /// Turn pages whose text is sideways or upside down upright, using
+    /// tesseract's orientation detection
+    #[arg(long)]
+    auto_orient: bool,
+
+    /// Stretch the tones of each page to the full range from black to white
+    #[arg(long)]
+    auto_contrast: bool,
+
+    /// Turn the paper of grey or yellowed scans white
+    #[arg(long)]
+    normalize_white: bool,
+
+    /// Remove isolated dots left by dust and scanner noise
+    #[arg(long)]
+    despeckle: bool,
+
+    /// Shorthand for --auto-contrast --normalize-white --despeckle
+    #[arg(long)]
+    clean_scan: bool,
+
+    /// Black out the regions of pages listed in this JSON file in the pixels
+    /// of the safe PDF: [{"page": 1, "rects": [{"x": 72, "y": 100,
+    /// "width": 200, "height": 14}]}], in points from the top-left corner
+    #[arg(long, value_name = "FILE", value_parser = parse_redactions)]
+    redactions: Option<Redactions>,
+
+    /// Black out the text matching this regular expression wherever OCR
+    /// finds it, such as '\d{3}-\d{2}-\d{4}'; may be repeated. Needs an OCR
+    /// engine reading pixels, even without --ocr.
+    #[arg(long, value_name = "REGEX")]
+    redact_pattern: Vec<String>,
+
+    /// Resample pages with more pixels per inch than this, for smaller PDFs
+    #[cfg(feature = "downscale")]
+    #[arg(long, value_name = "DPI", alias = "downscale-to")]
+    max_dpi: Option<f32>,
+
+    /// Compression level of the pixels in the safe PDF, from 1 (fastest) to 9
+    /// (smallest)
+    #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(u32).range(1..=9))]
+    compress_level: Option<u32>,
+
+    /// Make the safe PDF as small as possible, with zopfli if the CLI was
+    /// built with it; much slower
+    #[arg(long, conflicts_with = "compress_level")]
+    smallest: bool,
+
+    /// Center each page on A4 or Letter paper, or whichever of them is
+    /// closest in shape with "auto", instead of sizing it to its image
+    #[arg(long, value_name = "PAPER", default_value_t = Paper::Original)]
+    paper: Paper,
+
+    /// Margin on each side of the paper, in millimeters
+    #[arg(long, value_name = "MM", default_value_t = PageLayout::default().margin_mm)]
+    paper_margin: f32,
+
+    /// Put 2 or 4 pages on each page of the safe PDF, for review printouts
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = parse_nup)]
+    nup: usize,
+
+    /// Stamp "Page N of M" on each page of the safe PDF
+    #[arg(long)]
+    page_numbers: bool,
+
+    /// Where page numbers are stamped: top-left, top, top-right,
+    /// bottom-left, bottom or bottom-right
+    #[arg(
+        long,
+        value_name = "POSITION",
+        default_value_t = StampPosition::default(),
+        requires = "page_numbers"
+    )]
+    page_number_position: StampPosition,
+
+    /// Font size of page numbers, in points
+    #[arg(
+        long,
+        value_name = "PT",
+        default_value_t = PageNumbers::default().font_size,
+        requires = "page_numbers"
+    )]
+    page_number_size: f32,
+
+    /// Color space of the pages' RGB pixels: "device", for viewers to
+    /// interpret, "srgb", or the path of an RGB ICC profile, embedded in the
+    /// safe PDF
+    #[arg(long, value_name = "PROFILE", default_value_t = ColorProfile::Device)]
+    color_profile: ColorProfile,
+
+    /// Write a PDF 1.5 with compressed object streams and a cross-reference
+    /// stream, smaller for documents of many pages, instead of a PDF 1.4
+    #[arg(long)]
+    object_streams: bool,
+
+    /// Linearize the safe PDF ("fast web view"), so that viewers reading it
+    /// over HTTP show the first page before the rest arrives
+    #[arg(long, conflicts_with = "object_streams")]
+    linearize: bool,
+
+    /// Add bookmarks to the headings OCR finds: short lines notably larger
+    /// than the text around them
+    #[arg(long, requires = "ocr")]
+    outline: bool,
+
+    /// If the conversion fails after some pages were converted, write them
+    /// to a partial PDF named <output>.partial.pdf instead of discarding them
+    #[arg(long)]
+    keep_partial: bool,
+
+    /// Record the original's file name, the time of the conversion and the
+    /// version of dangerzone-rs in the keywords of the safe PDF
+    #[arg(long)]
+    record_provenance: bool,
+
+    /// Start the safe PDF with a page stating the original's file name and
+    /// SHA-256, the time of the conversion, the versions of dangerzone-rs and
+    /// of the converter image, and whether OCR was requested
+    #[arg(long)]
+    cover_sheet: bool,
+
+    /// Read the safe PDF back and check its structure before finishing
+    #[arg(long)]
+    verify: bool,
+
+    /// Open the safe PDF in the default viewer once it is written and
+    /// verified (implies --verify)
+    #[arg(long)]
+    open: bool,
+
+    /// With a .zip or .tar input, fail if it has more entries than this
+    #[cfg(feature = "archive")]
+    #[arg(long, value_name = "N", default_value_t = ArchiveLimits::default().max_entries)]
+    archive_max_entries: usize,
+
+    /// With a .zip or .tar input, fail if it expands to more MiB than this
+    #[cfg(feature = "archive")]
+    #[arg(
+        long,
+        value_name = "MiB",
+        default_value_t = ArchiveLimits::default().max_total_bytes >> 20
+    )]
+    archive_max_size: u64,
+
+    /// With an .eml or .msg input, convert its body and each attachment to a
+    /// safe PDF of its own in the --output directory, rather than all of them
+    /// into one safe PDF
+    #[cfg(feature = "email")]
+    #[arg(long, conflicts_with = "text_sidecar")]
+    email_split: bool,
+
+    /// Run the commands of this JSON file at stages of the conversion, e.g.
+    /// {"pre-convert": ["clamscan", "--no-summary"], "post-pdf":
+    /// ["upload.sh"]}; see the README for their arguments
+    #[arg(long, value_name = "FILE", value_parser = parse_hooks)]
+    hooks: Option<Hooks>,
+
+    /// Append a hash-chained record of the conversion to this JSONL file:
+    /// when, who, the hashes of the input and output, the converter image
+    /// and the result
+    #[cfg(feature = "audit-log")]
+    #[arg(long, value_name = "PATH")]
+    audit_log: Option<String>,
+
+    /// Add the conversion to the local history: the hash of the input, the
+    /// output path, the options and the result
+    #[cfg(feature = "history")]
+    #[arg(long)]
+    history: bool,
+
+    /// History database, for --history and the history subcommand
+    /// [default: ~/.local/share/dangerzone-rs/history.sqlite3]
+    #[cfg(feature = "history")]
+    #[arg(long, value_name = "PATH")]
+    history_db: Option<PathBuf>,
+
+    /// Don't convert a document the history already has a safe PDF of, with
+    /// the same options, if that PDF is unchanged, and print its path
+    /// instead; implies --history
+    #[cfg(feature = "history")]
+    #[arg(long)]
+    skip_duplicates: bool,
+
+    /// Convert the documents of the --input directory into the --output
+    /// directory, showing their progress in a terminal interface where
+    /// conversions can be cancelled and retried
+    #[cfg(feature = "tui")]
+    #[arg(
+        long,
+        group = "batch",
+        conflicts_with_all = ["output_format", "text_sidecar", "open"]
+    )]
+    tui: bool,
+
+    /// Convert the documents of the --input directory into the --output
+    /// directory, keeping the state of the batch in this file; running the
+    /// same command again after an interruption skips the documents already
+    /// converted and retries those that failed
+    #[arg(
+        long,
+        value_name = "STATE",
+        group = "batch",
+        conflicts_with_all = ["output_format", "text_sidecar", "open"]
+    )]
+    resume: Option<PathBuf>,
+
+    /// Documents converted at the same time with --tui or --resume
+    #[arg(long, value_name = "N", default_value_t = 2, requires = "batch")]
+    jobs: usize,
+
+    /// Name the safe PDFs written into --output by this template, such as
+    /// "{stem}-{date}-safe.pdf", with the variables {stem}, {date}, {hash}
+    /// and {counter}; a single document is then also written into the
+    /// --output directory
+    #[arg(long, value_name = "TEMPLATE", value_parser = parse_output_template)]
+    output_template: Option<String>,
+
+    /// Speak JSON-RPC on stdin/stdout, for GUI frontends
+    #[arg(long, conflicts_with_all = ["input", "output", "ocr"])]
+    rpc: bool,
+
+    /// Convert the files of these file:// URIs or paths next to themselves,
+    /// showing their progress in desktop notifications, as the actions
+    /// installed by `integrate` do
+    #[cfg(unix)]
+    #[arg(
+        long,
+        value_name = "URI",
+        num_args = 1..,
+        conflicts_with_all = ["input", "output", "rpc", "output_format", "text_sidecar", "open"]
+    )]
+    uri: Vec<String>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Pdf,
+    Pixels,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Serve the conversion pipeline over gRPC
+    #[cfg(feature = "grpc")]
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:50051")]
+        listen: std::net::SocketAddr,
+        /// Run as a systemd service: listen on the socket systemd passes, if
+        /// any, and report readiness and liveness to systemd
+        #[cfg(target_os = "linux")]
+        #[arg(long)]
+        systemd: bool,
+        /// Write dangerzone.service and dangerzone.socket units into DIR,
+        /// instead of serving
+        #[cfg(target_os = "linux")]
+        #[arg(long, value_name = "DIR", conflicts_with = "systemd")]
+        write_systemd_units: Option<std::path::PathBuf>,
+        /// Directory keeping the queued jobs across restarts [default:
+        /// $STATE_DIRECTORY/jobs or ~/.local/state/dangerzone-rs/jobs]
+        #[arg(long, value_name = "DIR")]
+        jobs_dir: Option<std::path::PathBuf>,
+        /// Hours finished jobs and their safe PDFs are kept
+        #[arg(long, value_name = "HOURS", default_value_t = 168)]
+        job_retention: u64,
+        /// Queued jobs converted at the same time
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = 1,
+            value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        workers: usize,
+        /// Conversions running at the same time, direct or queued
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = ServerLimits::default().max_conversions,
+            value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        max_conversions: usize,
+        /// Jobs waiting in the queue, and direct conversions waiting for a
+        /// free slot, beyond which new ones are refused
+        #[arg(long, value_name = "N", default_value_t = ServerLimits::default().max_queued)]
+        max_queued: usize,
+        /// Conversions and jobs each client IP address may start per minute
+        /// [default: unlimited]
+        #[arg(
+            long,
+            value_name = "N",
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        rate_limit: Option<u32>,
+        /// Refuse documents larger than this many MiB
+        #[arg(
+            long,
+            value_name = "MiB",
+            default_value_t = ServerLimits::default().max_document_bytes >> 20,
+            value_parser = RangedU64ValueParser::<usize>::new()
+                .range(1..=(dangerzone_rs::grpc::MAX_MESSAGE_BYTES >> 20) as u64)
+        )]
+        max_document_size: usize,
+    },
+    /// Serve the conversion pipeline on the session D-Bus, as
+    /// org.freedomofthepress.DangerzoneRs
+    #[cfg(feature = "dbus")]
+    Dbus,
+    /// Install "Sanitize with Dangerzone" in the context menus of the
+    /// Nautilus and Dolphin file managers
+    #[cfg(unix)]
+    Integrate {
+        /// Remove the actions instead
+        #[arg(long)]
+        uninstall: bool,
+    },
+    /// Pull the image and start a container once, so that the first
+    /// conversion starts quickly
+    Warmup,
+    /// Act as the server side of a Qubes RPC call: read the document from
+    /// stdin, write the safe PDF to stdout, and progress and errors as JSON
+    /// lines to stderr
+    Qrexec,
+    /// Remove conversion containers left behind by crashed processes
+    Cleanup {
+        /// Also remove the containers of conversions that are still running
+        #[arg(long)]
+        all: bool,
+    },
+    /// List the languages OCR can recognize, for --ocr-lang
+    OcrLangs,
+    /// Run only the sandboxed conversion, writing the pixel stream of the
+    /// converter for pixels-to-pdf to build the safe PDF from, possibly on
+    /// another host; conversion flags go before the subcommand
+    DocToPixels {
+        /// Document to convert
+        input: String,
+        /// File to write the pixel stream to, or - for standard output
+        #[arg(default_value = "-")]
+        output: String,
+    },
+    /// Render thumbnails of the first pages of an unsafe document in the
+    /// sandbox, as PNG images, for frontends to show what is about to be
+    /// converted; conversion flags go before the subcommand
+    Preview {
+        /// Document to preview
+        input: String,
+        /// Directory to write the thumbnails into, as page-<n>.png
+        output_dir: String,
+        /// Pages previewed, from the first
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = 10,
+            value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        pages: usize,
+        /// Longest side of the thumbnails, in pixels
+        #[arg(
+            long,
+            value_name = "PIXELS",
+            default_value_t = 256,
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        size: u32,
+    },
+    /// Check that no record of an --audit-log file was modified, removed or
+    /// reordered
+    #[cfg(feature = "audit-log")]
+    VerifyAuditLog {
+        /// The audit log
+        path: String,
+    },
+    /// List, look up or clear the conversions recorded with --history
+    #[cfg(feature = "history")]
+    History {
+        #[command(subcommand)]
+        action: HistoryAction,
+    },
+    /// Build the safe PDF from pixels converted elsewhere, such as those
+    /// written by --output-format pixels on another host; conversion flags
+    /// go before the subcommand
+    PixelsToPdf {
+        /// File holding the pixel stream of the converter, or directory of
+        /// raw pages with an index.json
+        input: String,
+        /// Path of the safe PDF
+        output: String,
+    },
+}
+
+#[cfg(feature = "history")]
+#[derive(Subcommand, Debug)]
+enum HistoryAction {
+    /// List the last conversions, newest first
+    List {
+        /// Conversions listed
+        #[arg(long, value_name = "N", default_value_t = 20)]
+        limit: usize,
+    },
+    /// Show a conversion by its ID, or the conversions of a file, found by
+    /// its hash even if it was moved or renamed
+    Show {
+        /// ID of the conversion, or path of the document
+        entry: String,
+    },
+    /// Remove every conversion from the history
+    Clear,
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
+    let mut args = Args::parse();
+    dangerzone_rs::logging::init_stderr();
+
+    match args.command.take() {
+        #[cfg(feature = "grpc")]
+        Some(Command::Serve {
+            listen,
+            #[cfg(target_os = "linux")]
+            systemd,
+            #[cfg(target_os = "linux")]
+            write_systemd_units,
+            jobs_dir,
+            job_retention,
+            workers,
+            max_conversions,
+            max_queued,
+            rate_limit,
+            max_document_size,
+        }) => {
+            #[cfg(target_os = "linux")]
+            if let Some(dir) = write_systemd_units {
+                let exe = std::env::current_exe().context("Failed to locate this executable")?;
+                dangerzone_rs::systemd::write_units(&dir, &exe, listen)?;
+                println!(
+                    "Wrote dangerzone.service and dangerzone.socket to {}",
+                    dir.display()
+                );
+                return Ok(());
+            }
+            let jobs_dir = jobs_dir
+                .or_else(jobs::default_dir)
+                .context("No directory for the jobs; pass --jobs-dir")?;
+            let retention = Duration::from_secs(job_retention.saturating_mul(60 * 60));
+            let limits = ServerLimits {
+                max_conversions,
+                max_queued,
+                per_client_per_minute: rate_limit,
+                max_document_bytes: max_document_size << 20,
+            };
+            let slots = Slots::new(limits.max_conversions, limits.max_queued);
+            let jobs = JobQueue::open(&jobs_dir, retention, limits.max_queued)?;
+            jobs.start(workers, ConversionOptions::default(), slots.clone());
+            let service = dangerzone_rs::grpc::DangerzoneService::new(jobs, slots, &limits);
+            #[cfg(target_os = "linux")]
+            if systemd {
+                return dangerzone_rs::grpc::serve_systemd(listen, service);
+            }
+            return dangerzone_rs::grpc::serve(listen, service);
+        }
+        #[cfg(feature = "dbus")]
+        Some(Command::Dbus) => return dangerzone_rs::dbus::serve_session(),
+        #[cfg(unix)]
+        Some(Command::Integrate { uninstall }) => {
+            if uninstall {
+                for path in integrate::uninstall()? {
+                    eprintln!("Removed {}", path.display());
+                }
+                return Ok(());
+            }
+            let exe = std::env::current_exe().context("Failed to locate this executable")?;
+            for path in integrate::install(&exe)? {
+                eprintln!("Installed {}", path.display());
+            }
+            return Ok(());
+        }
+        Some(Command::Warmup) => {
+            let options = ConversionOptions {
+                timeout: args.timeout.map(Duration::from_secs),
+                runtime: args.runtime,
+                image_platform: args.image_platform.clone(),
+                auto_start_vm: args.auto_start_vm || offer_to_start_vm(args.runtime)?,
+                strict_image_version: args.strict_image_version,
+                runtime_api: args.runtime_api,
+                container_hardening: container_hardening(args.hardened),
+                container_env: args.container_env.clone(),
+                print_command: args.print_command,
+                repro_script: args.repro_script.clone(),
+                ..ConversionOptions::default()
+            };
+            return warmup(&options, &CancellationToken::new());
+        }
+        Some(Command::Cleanup { all }) => {
+            let removed = cleanup_containers(args.runtime, all)?;
+            for name in &removed {
+                println!("{name}");
+            }
+            eprintln!("Removed {} container(s)", removed.len());
+            return Ok(());
+        }
+        Some(Command::OcrLangs) => return list_ocr_languages(),
+        Some(Command::Qrexec) => {
+            // Only the side channel may write to stderr; log records would
+            // also carry the untrusted output of the container to the caller
+            log::set_max_level(log::LevelFilter::Off);
+            if !dangerzone_rs::qrexec::serve_stdio(conversion_options(&args, false))? {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::DocToPixels { input, output }) => {
+            let auto_start_vm = args.auto_start_vm || offer_to_start_vm(args.runtime)?;
+            return doc_to_pixels(input, &output, &conversion_options(&args, auto_start_vm));
+        }
+        Some(Command::PixelsToPdf { input, output }) => {
+            return pixels_to_pdf(&input, output, &conversion_options(&args, false));
+        }
+        Some(Command::Preview {
+            input,
+            output_dir,
+            pages,
+            size,
+        }) => {
+            let auto_start_vm = args.auto_start_vm || offer_to_start_vm(args.runtime)?;
+            let preview_options = PreviewOptions {
+                max_pages: pages,
+                max_size: size,
+            };
+            return write_preview(
+                input,
+                &output_dir,
+                &conversion_options(&args, auto_start_vm),
+                &preview_options,
+            );
+        }
+        #[cfg(feature = "audit-log")]
+        Some(Command::VerifyAuditLog { path }) => {
+            let records = audit_log::verify_log(Path::new(&path))?;
+            eprintln!("The audit log holds {records} intact record(s)");
+            return Ok(());
+        }
+        #[cfg(feature = "history")]
+        Some(Command::History { action }) => return run_history(&args, action),
+        None => {}
+    }
+    dangerzone_rs::cleanup::sweep(args.runtime);
+    if args.rpc {
+        return dangerzone_rs::rpc::serve_stdio();
+    }
+    #[cfg(unix)]
+    if !args.uri.is_empty() {
+        return integrate::convert_uris(&args.uri, &conversion_options(&args, args.auto_start_vm));
+    }
+    let input = args
+        .input
+        .take()
+        .expect("--input is required without a subcommand, --rpc or --uri");
+    let output = args
+        .output
+        .take()
+        .expect("--output is required without a subcommand, --rpc or --uri");
+    let output = templated_output(&args, &input, output)?;
 
     eprintln!("Dangerzone Rust CLI");
-    eprintln!("Using container runtime: podman");
+    eprintln!("Using container runtime: {}", args.runtime);
     eprintln!(
         "Input: {input_sanitized}",
-        input_sanitized = replace_control_chars(&args.input, false)
+        input_sanitized = replace_control_chars(&input, false)
     );
     eprintln!(
         "Output: {output_sanitized}",
-        output_sanitized = replace_control_chars(&args.output, false)
+        output_sanitized = replace_control_chars(&output, false)
     );
     if args.ocr {
-        eprintln!("OCR: enabled");
+        eprintln!(
+            "OCR: enabled ({ocr_lang_sanitized})",
+            ocr_lang_sanitized = replace_control_chars(&args.ocr_lang, false)
+        );
     }
     eprintln!();
 
-    convert_document(args.input, args.output, args.ocr)?;
+    let auto_start_vm = args.auto_start_vm || offer_to_start_vm(args.runtime)?;
+    let mut options = conversion_options(&args, auto_start_vm);
+    if let Some(hooks) = &args.hooks {
+        hooks.add_stages(&mut options);
+    }
+    #[cfg(feature = "history")]
+    if args.skip_duplicates {
+        let history = History::open(&history_path(&args)?)?;
+        if let Some(entry) = history.previous_conversion(Path::new(&input), &options)? {
+            if reuse_conversion(&args, &input, &entry)? {
+                return Ok(());
+            }
+        }
+    }
+    #[cfg(feature = "audit-log")]
+    let record = args
+        .audit_log
+        .as_ref()
+        .map(|log| (log, ConversionRecord::start(&input, &output, args.runtime)));
+    #[cfg(feature = "history")]
+    let entry = (args.history || args.skip_duplicates)
+        .then(|| PendingEntry::start(&input, &output, &options));
+    let result = run(&args, input, output, &options);
+    #[cfg(feature = "audit-log")]
+    let audited = match record {
+        Some((log, record)) => record.finish(Path::new(log), result.as_ref().err()),
+        None => Ok(()),
+    };
+    #[cfg(feature = "history")]
+    let added = match entry {
+        Some(entry) => history_path(&args)
+            .and_then(|path| History::open(&path))
+            .and_then(|history| entry.finish(&history, result.as_ref().err()))
+            .map(drop),
+        None => Ok(()),
+    };
+    #[cfg(feature = "audit-log")]
+    let result = with_record(result, audited);
+    #[cfg(feature = "history")]
+    let result = with_record(result, added);
+    result
+}
 
+/// The `result` of a conversion, or the failure to record it in `recorded`;
+/// if both failed, the conversion's error is returned and the other one only
+/// shown
+#[cfg_attr(not(any(feature = "audit-log", feature = "history")), allow(dead_code))]
+fn with_record(result: Result<()>, recorded: Result<()>) -> Result<()> {
+    match (result, recorded) {
+        (Err(e), Err(record_error)) => {
+            eprintln!(
+                "Warning: {record_error_sanitized}",
+                record_error_sanitized = replace_control_chars(&format!("{record_error:#}"), false)
+            );
+            Err(e)
+        }
+        (Ok(()), recorded) => recorded,
+        (result, Ok(())) => result,
+    }
+}
+
+/// Use the safe PDF of `entry`, a previous conversion of `input` with the
+/// same options, instead of converting it again, as long as it is a valid
+/// PDF; the hooks run and the audit log records it as for a conversion
+#[cfg(feature = "history")]
+fn reuse_conversion(args: &Args, input: &str, entry: &history::Entry) -> Result<bool> {
+    if let Err(e) = dangerzone_rs::validate_pdf(&entry.output) {
+        eprintln!(
+            "Warning: converting it again, since the safe PDF of conversion {id} is invalid: \
+             {e_sanitized}",
+            id = entry.id,
+            e_sanitized = replace_control_chars(&format!("{e:#}"), false)
+        );
+        return Ok(false);
+    }
+    pre_convert(args, input)?;
+    eprintln!(
+        "Already sanitized on {time} (conversion {id}), skipping it",
+        time = entry.time,
+        id = entry.id
+    );
+    #[cfg(feature = "audit-log")]
+    if let Some(log) = &args.audit_log {
+        ConversionRecord::start(input, &entry.output, args.runtime).skipped(Path::new(log))?;
+    }
+    println!(
+        "{output_sanitized}",
+        output_sanitized = replace_control_chars(&entry.output, false)
+    );
+    post_pdf(args, &entry.output, input)?;
+    if args.open {
+        open_in_viewer(&entry.output);
+    }
+    Ok(true)
+}
+
+/// Path of the history database of `args`
+#[cfg(feature = "history")]
+fn history_path(args: &Args) -> Result<PathBuf> {
+    args.history_db
+        .clone()
+        .or_else(history::default_path)
+        .context("No path for the history; pass --history-db")
+}
+
+/// Run a history subcommand
+#[cfg(feature = "history")]
+fn run_history(args: &Args, action: HistoryAction) -> Result<()> {
+    let history = History::open(&history_path(args)?)?;
+    match action {
+        HistoryAction::List { limit } => {
+            for entry in history.list(limit)? {
+                print_history_entry(&entry, false);
+            }
+        }
+        HistoryAction::Show { entry } => {
+            let entries = match entry.parse() {
+                Ok(id) if !Path::new(&entry).exists() => history.get(id)?.into_iter().collect(),
+                _ => history.find_file(Path::new(&entry))?,
+            };
+            if entries.is_empty() {
+                anyhow::bail!(
+                    "No conversion of '{entry_sanitized}' in the history",
+                    entry_sanitized = replace_control_chars(&entry, false)
+                );
+            }
+            for entry in entries {
+                print_history_entry(&entry, true);
+            }
+        }
+        HistoryAction::Clear => {
+            let removed = history.clear()?;
+            eprintln!("Removed {removed} conversion(s) from the history");
+        }
+    }
+    Ok(())
+}
+
+/// Print a conversion of the history on one line, or with its hash, options
+/// and error with `details`
+#[cfg(feature = "history")]
+fn print_history_entry(entry: &history::Entry, details: bool) {
+    let result = if entry.succeeded { "ok" } else { "failed" };
+    println!(
+        "{id}\t{time}\t{result}\t{input_sanitized} -> {output_sanitized}",
+        id = entry.id,
+        time = entry.time,
+        input_sanitized = replace_control_chars(&entry.input, false),
+        output_sanitized = replace_control_chars(&entry.output, false),
+    );
+    if !details {
+        return;
+    }
+    if let Some(sha256) = &entry.input_sha256 {
+        println!("\tSHA-256: {sha256}");
+    }
+    if let Some(sha256) = &entry.output_sha256 {
+        println!("\tSafe PDF SHA-256: {sha256}");
+    }
+    println!(
+        "\tOptions: {options_sanitized}",
+        options_sanitized = replace_control_chars(&entry.options, false)
+    );
+    if let Some(error) = &entry.error {
+        println!(
+            "\tError: {error_sanitized}",
+            error_sanitized = replace_control_chars(error, false)
+        );
+    }
+}
+
+/// Path of the safe PDF of a single document named by --output-template in
+/// the --output directory, or `output` as is for batches and without a
+/// template
+fn templated_output(args: &Args, input: &str, output: String) -> Result<String> {
+    let Some(template) = &args.output_template else {
+        return Ok(output);
+    };
+    #[cfg(feature = "tui")]
+    if args.tui {
+        return Ok(output);
+    }
+    if args.resume.is_some() {
+        return Ok(output);
+    }
+    #[cfg(feature = "archive")]
+    if archive::is_archive(input) {
+        return Ok(output);
+    }
+    #[cfg(feature = "email")]
+    if args.email_split {
+        return Ok(output);
+    }
+    std::fs::create_dir_all(&output).with_context(|| {
+        format!(
+            "Failed to create output directory '{output_sanitized}'",
+            output_sanitized = replace_control_chars(&output, false)
+        )
+    })?;
+    let template = OutputTemplate::parse(template)?;
+    let mut paths = batch_output_paths(&[input.to_string()], &output, &template);
+    Ok(paths.remove(0))
+}
+
+/// Convert `input` to `output` as the flags of `args` say
+fn run(args: &Args, input: String, output: String, options: &ConversionOptions) -> Result<()> {
+    pre_convert(args, &input)?;
+    #[cfg(feature = "tui")]
+    if args.tui {
+        return tui::run(&input, &output, args.jobs, options);
+    }
+    if let Some(state) = &args.resume {
+        return convert_resumable(&input, &output, args.jobs, options, state);
+    }
+    if args.output_format == OutputFormat::Pixels {
+        let pages = convert_document_to_pixel_dump(
+            input,
+            &output,
+            options,
+            &|_| {},
+            &CancellationToken::new(),
+        )?;
+        eprintln!();
+        eprintln!(
+            "Wrote the pixels of {pages} page(s) to {output_sanitized}",
+            output_sanitized = replace_control_chars(&output, false)
+        );
+        return Ok(());
+    }
+    #[cfg(feature = "archive")]
+    if archive::is_archive(&input) {
+        let limits = ArchiveLimits {
+            max_entries: args.archive_max_entries,
+            max_total_bytes: args.archive_max_size.saturating_mul(1 << 20),
+            ..ArchiveLimits::default()
+        };
+        return convert_archive(&input, &output, options, &limits);
+    }
+    #[cfg(feature = "email")]
+    if args.email_split {
+        return split_email(&input, &output, options);
+    }
+    let report = convert(
+        input.clone(),
+        output.clone(),
+        options,
+        &|_| {},
+        &CancellationToken::new(),
+    )?;
+    if let Some(sidecar) = &args.text_sidecar {
+        write_text_sidecar(&output, sidecar)?;
+    }
+    print_report(report);
+    post_pdf(args, &output, &input)?;
+    if args.open {
+        open_in_viewer(&output);
+    }
+    Ok(())
+}
+
+/// Whether `run` converts a single input document, which the pre-convert
+/// hook runs on, rather than a batch
+fn converts_one_input(args: &Args) -> bool {
+    #[cfg(feature = "tui")]
+    if args.tui {
+        return false;
+    }
+    args.resume.is_none()
+}
+
+/// Whether `run` writes a single safe PDF, which the post-pdf hook runs on
+#[cfg_attr(not(feature = "archive"), allow(unused_variables))]
+fn writes_one_pdf(args: &Args, input: &str) -> bool {
+    if !converts_one_input(args) {
+        return false;
+    }
+    #[cfg(feature = "archive")]
+    if archive::is_archive(input) {
+        return false;
+    }
+    #[cfg(feature = "email")]
+    if args.email_split {
+        return false;
+    }
+    args.output_format == OutputFormat::Pdf
+}
+
+/// Check that the hooks of `args`, if any, can run on what `run` converts,
+/// and run the pre-convert hook on `input`
+fn pre_convert(args: &Args, input: &str) -> Result<()> {
+    let Some(hooks) = &args.hooks else {
+        return Ok(());
+    };
+    if hooks.has_pre_convert() && !converts_one_input(args) {
+        anyhow::bail!(
+            "The pre-convert hook needs a single input document: it can't be used with \
+             --tui or --resume"
+        );
+    }
+    if hooks.has_post_pdf() && !writes_one_pdf(args, input) {
+        anyhow::bail!(
+            "The post-pdf hook needs a single safe PDF: it can't be used with --tui, \
+             --resume, --output-format pixels, --email-split or an archive"
+        );
+    }
+    hooks.pre_convert(input)
+}
+
+/// Run the post-pdf hook of `args`, if any, on the safe PDF `output`
+fn post_pdf(args: &Args, output: &str, input: &str) -> Result<()> {
+    match &args.hooks {
+        Some(hooks) => hooks.post_pdf(output, input),
+        None => Ok(()),
+    }
+}
+
+/// Open the PDF at `path` in the platform's default viewer, warning if it
+/// can't be
+fn open_in_viewer(path: &str) {
+    #[cfg(target_os = "macos")]
+    let mut command = std::process::Command::new("open");
+    // Unlike `cmd /C start`, explorer doesn't interpret the characters of the
+    // path
+    #[cfg(windows)]
+    let mut command = std::process::Command::new("explorer");
+    #[cfg(not(any(target_os = "macos", windows)))]
+    let mut command = std::process::Command::new("xdg-open");
+    let result = command
+        .arg(path)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn();
+    if let Err(e) = result {
+        eprintln!(
+            "Warning: failed to open the safe PDF with {program}: {e}",
+            program = command.get_program().to_string_lossy()
+        );
+    }
+}
+
+/// Write the pixel stream of the conversion of `input` to the file
+/// `output`, or to standard output for `-`
+fn doc_to_pixels(input: String, output: &str, options: &ConversionOptions) -> Result<()> {
+    let cancel = CancellationToken::new();
+    if output == "-" {
+        if std::io::stdout().is_terminal() {
+            anyhow::bail!("Refusing to write the pixel stream to a terminal; redirect it");
+        }
+        convert_doc_to_pixel_stream(input, std::io::stdout(), options, &cancel)?;
+        return Ok(());
+    }
+    let output_sanitized = replace_control_chars(output, false);
+    let file =
+        File::create(output).with_context(|| format!("Failed to create {output_sanitized}"))?;
+    let written = match convert_doc_to_pixel_stream(input, BufWriter::new(file), options, &cancel) {
+        Ok(written) => written,
+        Err(e) => {
+            // A partial stream would only fail later, on the other host
+            let _ = std::fs::remove_file(output);
+            return Err(e);
+        }
+    };
+    eprintln!("Wrote {written} bytes of pixels to {output_sanitized}");
+    Ok(())
+}
+
+/// Write thumbnails of the first pages of `input` into `output_dir`,
+/// printing their paths
+fn write_preview(
+    input: String,
+    output_dir: &str,
+    options: &ConversionOptions,
+    preview_options: &PreviewOptions,
+) -> Result<()> {
+    let output_dir_sanitized = replace_control_chars(output_dir, false);
+    std::fs::create_dir_all(output_dir)
+        .with_context(|| format!("Failed to create output directory '{output_dir_sanitized}'"))?;
+    let preview = preview::preview(input, options, preview_options, &CancellationToken::new())?;
+    for thumbnail in &preview.thumbnails {
+        let path = Path::new(output_dir).join(format!("page-{}.png", thumbnail.page));
+        let path_sanitized = replace_control_chars(&path.to_string_lossy(), false);
+        std::fs::write(&path, &thumbnail.png)
+            .with_context(|| format!("Failed to write {path_sanitized}"))?;
+        println!("{path_sanitized}");
+    }
+    match preview.page_count {
+        Some(count) => eprintln!(
+            "Wrote thumbnails of {} of {count} page(s)",
+            preview.thumbnails.len()
+        ),
+        None => eprintln!("Wrote {} thumbnail(s)", preview.thumbnails.len()),
+    }
+    Ok(())
+}
+
+/// Build the safe PDF `output` from the pixels at `input`
+fn pixels_to_pdf(input: &str, output: String, options: &ConversionOptions) -> Result<()> {
+    eprintln!(
+        "Input pixels: {input_sanitized}",
+        input_sanitized = replace_control_chars(input, false)
+    );
+    eprintln!(
+        "Output: {output_sanitized}",
+        output_sanitized = replace_control_chars(&output, false)
+    );
+    let report = pixels_to_safe_pdf(input, output, options, &|_| {}, &CancellationToken::new())?;
+    print_report(report);
+    Ok(())
+}
+
+/// Tell how the conversion of `report` went
+fn print_report(report: ConversionReport) {
+    eprintln!();
+    let stats = &report.stats;
+    match stats.pages_per_second() {
+        Some(rate) => eprintln!(
+            "Converted {} pages in {:.1}s ({rate:.2} pages/s)",
+            stats.pages,
+            stats.total.as_secs_f64()
+        ),
+        None => eprintln!("Converted {} pages", stats.pages),
+    }
+    eprintln!(
+        "Stages: {:.1}s until the first page, {:.1}s writing, {:.1}s OCR",
+        stats.converting.as_secs_f64(),
+        stats.writing.as_secs_f64(),
+        stats.ocr.as_secs_f64()
+    );
+    let input = stats
+        .input_bytes
+        .map_or_else(|| "unknown".to_string(), |bytes| format!("{bytes} bytes"));
+    match stats.compression_ratio() {
+        Some(ratio) => eprintln!(
+            "Sizes: {input} in, {} bytes out ({ratio:.1}x smaller than the pixels)",
+            stats.output_bytes
+        ),
+        None => eprintln!("Sizes: {input} in, {} bytes out", stats.output_bytes),
+    }
+    if let Some(bytes) = report.peak_rss_bytes {
+        eprintln!("Peak memory use: {} MiB", bytes >> 20);
+    }
+    // The OCR fallback gets the last line
+    for warning in &report.warnings {
+        if !matches!(warning, Warning::OcrFallback { .. }) {
+            eprintln!(
+                "Warning: {warning_sanitized}",
+                warning_sanitized = replace_control_chars(&warning.to_string(), true)
+            );
+        }
+    }
+    for found in &report.redacted {
+        eprintln!(
+            "Redacted on page {}: '{text_sanitized}', matching '{pattern_sanitized}'",
+            found.page,
+            text_sanitized = replace_control_chars(&found.text, false),
+            pattern_sanitized = replace_control_chars(&found.pattern, false)
+        );
+    }
+    if let Some(lang) = report.ocr_lang {
+        eprintln!(
+            "OCR language (auto): {lang_sanitized}",
+            lang_sanitized = replace_control_chars(&lang, false)
+        );
+    }
+    if let Some(reason) = report.ocr_fallback {
+        eprintln!(
+            "Conversion completed without a text layer, as OCR failed: {reason_sanitized}",
+            reason_sanitized = replace_control_chars(&reason, true)
+        );
+        return;
+    }
+    eprintln!("Conversion completed successfully!");
+}
+
+/// Options of the conversion set by the flags of `args`
+fn conversion_options(args: &Args, auto_start_vm: bool) -> ConversionOptions {
+    ConversionOptions {
+        ocr: args.ocr,
+        ocr_lang: args.ocr_lang.clone(),
+        ocr_sidecar: args.ocr_sidecar,
+        ocrmypdf: OcrMyPdfOptions {
+            deskew: args.ocr_deskew,
+            clean: args.ocr_clean,
+            rotate_pages: args.ocr_rotate_pages,
+            jobs: args.ocr_jobs,
+            optimize: args.ocr_optimize,
+            sandbox: args.ocr_sandbox,
+        },
+        ocr_timeout: args.ocr_timeout.map(Duration::from_secs),
+        ocr_required: args.ocr_required,
+        dpi: args.dpi,
+        timeout: args.timeout.map(Duration::from_secs),
+        runtime: args.runtime,
+        image_platform: args.image_platform.clone(),
+        auto_start_vm,
+        strict_image_version: args.strict_image_version,
+        runtime_api: args.runtime_api,
+        container_hardening: container_hardening(args.hardened),
+        container_env: args.container_env.clone(),
+        print_command: args.print_command,
+        repro_script: args.repro_script.clone(),
+        session_max_documents: None,
+        output_template: args.output_template.clone(),
+        max_output_bytes: args.max_output_size.saturating_mul(1 << 20),
+        page_checksums: true,
+        page_compression: true,
+        spool_threshold_bytes: args.spool_after.map(|mib| mib.saturating_mul(1 << 20)),
+        image_strip_bytes: args.image_strip_size.map(|mib| mib.saturating_mul(1 << 20)),
+        low_memory: args.low_memory,
+        drop_blank_pages: args.drop_blank_pages,
+        rotation: args.rotate,
+        auto_orient: args.auto_orient,
+        #[cfg(feature = "downscale")]
+        max_dpi: args.max_dpi,
+        #[cfg(not(feature = "downscale"))]
+        max_dpi: None,
+        page_cleanup: PageCleanup {
+            auto_contrast: args.auto_contrast || args.clean_scan,
+            normalize_white: args.normalize_white || args.clean_scan,
+            despeckle: args.despeckle || args.clean_scan,
+        },
+        redactions: args.redactions.clone().unwrap_or_default(),
+        redact_patterns: args.redact_pattern.clone(),
+        compression: if args.smallest {
+            CompressionConfig::smallest()
+        } else {
+            CompressionConfig {
+                level: args
+                    .compress_level
+                    .unwrap_or(CompressionConfig::default().level),
+                ..CompressionConfig::default()
+            }
+        },
+        color_profile: args.color_profile.clone(),
+        layout: PageLayout {
+            paper: args.paper,
+            margin_mm: args.paper_margin,
+            nup: args.nup,
+            page_numbers: args.page_numbers.then_some(PageNumbers {
+                position: args.page_number_position,
+                font_size: args.page_number_size,
+            }),
+        },
+        object_streams: args.object_streams,
+        linearize: args.linearize,
+        outline: args.outline,
+        keep_partial: args.keep_partial,
+        record_provenance: args.record_provenance,
+        cover_sheet: args.cover_sheet,
+        verify: args.verify || args.open,
+        stages: Default::default(),
+    }
+}
+
+/// Convert `input` to the safe PDF `output`, with all the parts of emails
+/// in it
+fn convert(
+    input: String,
+    output: String,
+    options: &ConversionOptions,
+    progress: &dyn Fn(Progress),
+    cancel: &CancellationToken,
+) -> Result<ConversionReport> {
+    #[cfg(feature = "email")]
+    if email::is_email(&input) {
+        return email::convert_email_merged(&input, output, options, progress, cancel);
+    }
+    convert_document_with_options(input, output, options, progress, cancel)
+}
+
+/// Convert each supported entry of the archive `input` to a safe PDF in
+/// `output_dir`
+#[cfg(feature = "archive")]
+fn convert_archive(
+    input: &str,
+    output_dir: &str,
+    options: &ConversionOptions,
+    limits: &ArchiveLimits,
+) -> Result<()> {
+    let entries = archive::convert_archive(
+        input,
+        output_dir,
+        1,
+        options,
+        limits,
+        &CancellationToken::new(),
+    )?;
+    eprintln!();
+    let (mut converted, mut failed, mut skipped) = (0, 0, 0);
+    for entry in &entries {
+        let name_sanitized = replace_control_chars(&entry.name, false);
+        match &entry.outcome {
+            EntryOutcome::Skipped(reason) => {
+                skipped += 1;
+                eprintln!(
+                    "{name_sanitized}: skipped, {reason_sanitized}",
+                    reason_sanitized = replace_control_chars(reason, false)
+                );
+            }
+            EntryOutcome::Converted(result) => match &result.result {
+                Ok(_) => {
+                    converted += 1;
+                    eprintln!(
+                        "{name_sanitized}: {output_sanitized}",
+                        output_sanitized = replace_control_chars(&result.output_path, false)
+                    );
+                }
+                Err(e) => {
+                    failed += 1;
+                    eprintln!(
+                        "{name_sanitized}: failed: {e_sanitized}",
+                        e_sanitized = replace_control_chars(&format!("{e:#}"), true)
+                    );
+                }
+            },
+        }
+    }
+    eprintln!(
+        "Converted {converted}, failed {failed} and skipped {skipped} of {} file(s); see {}",
+        entries.len(),
+        archive::MANIFEST_NAME
+    );
+    if failed > 0 {
+        anyhow::bail!("{failed} file(s) of the archive failed to convert");
+    }
+    Ok(())
+}
+
+/// Convert the documents of the directory `input` into `output_dir`, as a
+/// batch resumed from the state file `state`
+fn convert_resumable(
+    input: &str,
+    output_dir: &str,
+    jobs: usize,
+    options: &ConversionOptions,
+    state: &Path,
+) -> Result<()> {
+    let inputs = batch_inputs(input)?;
+    if inputs.is_empty() {
+        anyhow::bail!("No documents to convert");
+    }
+    let summary = resume::convert_resumable(
+        &inputs,
+        output_dir,
+        jobs,
+        options,
+        state,
+        &CancellationToken::new(),
+        &mut |result| {
+            let name_sanitized = replace_control_chars(&result.input_path, false);
+            match &result.result {
+                Ok(_) => eprintln!(
+                    "{name_sanitized}: {output_sanitized}",
+                    output_sanitized = replace_control_chars(&result.output_path, false)
+                ),
+                Err(e) => eprintln!(
+                    "{name_sanitized}: failed: {e_sanitized}",
+                    e_sanitized = replace_control_chars(&format!("{e:#}"), true)
+                ),
+            }
+        },
+    )?;
+    eprintln!(
+        "Converted {converted}, failed {failed} and skipped {skipped} already converted \
+         document(s)",
+        converted = summary.converted,
+        failed = summary.failed,
+        skipped = summary.skipped
+    );
+    if summary.failed > 0 {
+        anyhow::bail!(
+            "{failed} document(s) failed to convert; run the same command again to retry them",
+            failed = summary.failed
+        );
+    }
+    Ok(())
+}
+
+/// The files of the directory `input`, not hidden, sorted by name, or
+/// `input` itself if it is a file
+pub(crate) fn batch_inputs(input: &str) -> Result<Vec<String>> {
+    if !Path::new(input).is_dir() {
+        return Ok(vec![input.to_string()]);
+    }
+    let input_sanitized = replace_control_chars(input, false);
+    let mut inputs = Vec::new();
+    for entry in std::fs::read_dir(input)
+        .with_context(|| format!("Failed to read the directory '{input_sanitized}'"))?
+    {
+        let entry =
+            entry.with_context(|| format!("Failed to read the directory '{input_sanitized}'"))?;
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if !hidden && entry.file_type().is_ok_and(|kind| kind.is_file()) {
+            inputs.push(entry.path().to_string_lossy().into_owned());
+        }
+    }
+    inputs.sort();
+    Ok(inputs)
+}
+
+/// Convert each part of the email `input` to a safe PDF in `output_dir`
+#[cfg(feature = "email")]
+fn split_email(input: &str, output_dir: &str, options: &ConversionOptions) -> Result<()> {
+    if !email::is_email(input) {
+        anyhow::bail!("--email-split needs an .eml or .msg input");
+    }
+    let results = email::convert_email(input, output_dir, 1, options, &CancellationToken::new())?;
     eprintln!();
+    let mut failed = 0;
+    for result in &results {
+        let name_sanitized = replace_control_chars(&result.input_path, false);
+        match &result.result {
+            Ok(_) => eprintln!(
+                "{name_sanitized}: {output_sanitized}",
+                output_sanitized = replace_control_chars(&result.output_path, false)
+            ),
+            Err(e) => {
+                failed += 1;
+                eprintln!(
+                    "{name_sanitized}: failed: {e_sanitized}",
+                    e_sanitized = replace_control_chars(&format!("{e:#}"), true)
+                );
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} part(s) failed to convert", results.len());
+    }
     eprintln!("Conversion completed successfully!");
     Ok(())
 }
+
+/// Print the languages of `ocr_languages`, one per line, with their engine
+fn list_ocr_languages() -> Result<()> {
+    let languages = ocr_languages();
+    if languages.is_empty() {
+        anyhow::bail!("No OCR language found; install tesseract and its language packs");
+    }
+    for language in &languages {
+        let code = language.code.as_deref().unwrap_or("-");
+        let code_sanitized = replace_control_chars(code, false);
+        let name_sanitized = replace_control_chars(&language.name, false);
+        if language.code.as_deref() == Some(language.name.as_str()) {
+            println!("{code_sanitized:<10} {}", language.engine);
+        } else {
+            println!(
+                "{code_sanitized:<10} {} ({name_sanitized})",
+                language.engine
+            );
+        }
+    }
+    eprintln!(
+        "Pass a code to --ocr-lang, several joined with '+' such as 'eng+deu', or 'auto' to \
+         detect the language of the document"
+    );
+    Ok(())
+}
+
+/// Write the text layer of the safe PDF to `sidecar`
+fn write_text_sidecar(pdf: &str, sidecar: &str) -> Result<()> {
+    let pages = extract_text(pdf)?;
+    if pages.iter().all(|page| page.text.trim().is_empty()) {
+        eprintln!("Warning: no text was found in the safe PDF; OCR may have failed");
+    }
+    let text: Vec<String> = pages
+        .iter()
+        .map(|page| format!("{}\n", page.text))
+        .collect();
+    std::fs::write(sidecar, text.join("\x0c")).with_context(|| {
+        format!(
+            "Failed to write text to '{sidecar_sanitized}'",
+            sidecar_sanitized = replace_control_chars(sidecar, false)
+        )
+    })
+}
+
+fn container_hardening(hardened: bool) -> ContainerHardening {
+    if hardened {
+        ContainerHardening::strict()
+    } else {
+        ContainerHardening::default()
+    }
+}
+
+fn parse_env(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err("must be KEY=VALUE".to_string()),
+    }
+}
+
+fn parse_output_template(value: &str) -> Result<String, String> {
+    OutputTemplate::parse(value)
+        .map(|_| value.to_string())
+        .map_err(|e| e.to_string())
+}
+
+fn parse_redactions(value: &str) -> Result<Redactions, String> {
+    Redactions::load(Path::new(value)).map_err(|e| format!("{e:#}"))
+}
+
+fn parse_hooks(value: &str) -> Result<Hooks, String> {
+    Hooks::load(Path::new(value)).map_err(|e| format!("{e:#}"))
+}
+
+fn parse_rotation(value: &str) -> Result<u16, String> {
+    match value.parse() {
+        Ok(degrees @ (0 | 90 | 180 | 270)) => Ok(degrees),
+        _ => Err("must be 0, 90, 180 or 270".to_string()),
+    }
+}
+
+fn parse_nup(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(pages @ (1 | 2 | 4)) => Ok(pages),
+        _ => Err("must be 1, 2 or 4".to_string()),
+    }
+}
+
+/// Ask whether to start the runtime's virtual machine if it is stopped and
+/// we are running interactively
+fn offer_to_start_vm(runtime: Runtime) -> Result<bool> {
+    let Some(vm) = Vm::required_by(runtime) else {
+        return Ok(false);
+    };
+    if !std::io::stdin().is_terminal() || vm.is_running()? {
+        return Ok(false);
+    }
+
+    eprint!("The {vm} is not running. Start it now? [y/N] ");
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["b.docx", "a.pdf", ".hidden.pdf"] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+        std::fs::create_dir(dir.path().join("nested")).unwrap();
+        let inputs = batch_inputs(&dir.path().to_string_lossy()).unwrap();
+        let names: Vec<_> = inputs
+            .iter()
+            .map(|input| Path::new(input).file_name().unwrap().to_string_lossy())
+            .collect();
+        assert_eq!(names, ["a.pdf", "b.docx"]);
+        assert_eq!(batch_inputs("report.pdf").unwrap(), ["report.pdf"]);
+    }
+
+    #[test]
+    fn test_with_record() {
+        let error = |message: &str| Err(anyhow::anyhow!(message.to_string()));
+        assert!(with_record(Ok(()), Ok(())).is_ok());
+        let recorded = with_record(Ok(()), error("Failed to write to the audit log"));
+        assert_eq!(
+            recorded.unwrap_err().to_string(),
+            "Failed to write to the audit log"
+        );
+        let both = with_record(error("Container failed"), error("Failed to write"));
+        assert_eq!(both.unwrap_err().to_string(), "Container failed");
+    }
+
+    #[test]
+    fn test_hooks_need_one_document() {
+        let args = |flags: &[&str]| {
+            Args::parse_from(
+                ["dangerzone-rs", "--input", "doc.pdf", "--output", "out"]
+                    .iter()
+                    .chain(flags),
+            )
+        };
+        assert!(converts_one_input(&args(&[])));
+        assert!(writes_one_pdf(&args(&[]), "doc.pdf"));
+        let resumed = args(&["--resume", "state.json"]);
+        assert!(!converts_one_input(&resumed));
+        assert!(!writes_one_pdf(&resumed, "doc.pdf"));
+        let pixels = args(&["--output-format", "pixels"]);
+        assert!(converts_one_input(&pixels));
+        assert!(!writes_one_pdf(&pixels, "doc.pdf"));
+    }
+}
@@ -0,0 +1,245 @@
+//! Names of safe PDFs, from templates such as `{stem}-{date}-safe.pdf`
+//!
+//! A template is a file name where these variables are replaced:
+//!
+//! - `{stem}`: the name of the document without its extension
+//! - `{date}`: the date the batch started, as `YYYY-MM-DD` in UTC
+//! - `{hash}`: the first 12 hexadecimal digits of the SHA-256 of the document
+//! - `{counter}`: the position of the document in the batch, from 1
+//!
+//! `{{` and `}}` stand for braces. Names that come out the same in a batch
+//! get `-2`, `-3`… before their extension.
+
+use crate::{replace_control_chars, UtcTime};
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Template of the names of safe PDFs, unless one is given
+pub const DEFAULT_TEMPLATE: &str = "{stem}-safe.pdf";
+
+/// Hexadecimal digits of the hash in `{hash}`
+const HASH_DIGITS: usize = 12;
+
+/// Parsed template of the names of safe PDFs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutputTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Stem,
+    Date,
+    Hash,
+    Counter,
+}
+
+impl OutputTemplate {
+    /// Parse `template`, failing on unknown variables, unbalanced braces and
+    /// characters that can't be in a file name
+    pub fn parse(template: &str) -> Result<Self> {
+        let template_sanitized = replace_control_chars(template, false);
+        if template
+            .chars()
+            .any(|c| matches!(c, '/' | '\\') || c.is_control())
+        {
+            anyhow::bail!(
+                "Invalid output template '{template_sanitized}': it must be a file name, \
+                 without directories"
+            );
+        }
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let Some((name, rest)) = chars.as_str().split_once('}') else {
+                        anyhow::bail!(
+                            "Invalid output template '{template_sanitized}': unclosed {{"
+                        );
+                    };
+                    let part = match name {
+                        "stem" => Part::Stem,
+                        "date" => Part::Date,
+                        "hash" => Part::Hash,
+                        "counter" => Part::Counter,
+                        _ => anyhow::bail!(
+                            "Invalid output template '{template_sanitized}': unknown variable \
+                             {{{name}}}; use {{stem}}, {{date}}, {{hash}} or {{counter}}"
+                        ),
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(part);
+                    chars = rest.chars();
+                }
+                '}' => anyhow::bail!("Invalid output template '{template_sanitized}': unopened }}"),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        if template.trim_matches('.').is_empty() {
+            anyhow::bail!("Invalid output template '{template_sanitized}': it names no file");
+        }
+        Ok(OutputTemplate { parts })
+    }
+
+    /// Name of the safe PDF of `input`, the `counter`th document of a batch
+    /// started on `date`
+    fn name(&self, input: &Path, counter: usize, date: &str) -> String {
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => name.push_str(literal),
+                Part::Stem => name.push_str(
+                    &input
+                        .file_stem()
+                        .map_or_else(|| "document".into(), |stem| stem.to_string_lossy()),
+                ),
+                Part::Date => name.push_str(date),
+                Part::Hash => name.push_str(&hash_prefix(input)),
+                Part::Counter => name.push_str(&counter.to_string()),
+            }
+        }
+        match name.trim_matches('.') {
+            "" => "document.pdf".to_string(),
+            _ => name,
+        }
+    }
+}
+
+impl Default for OutputTemplate {
+    fn default() -> Self {
+        OutputTemplate::parse(DEFAULT_TEMPLATE).expect("the default template is valid")
+    }
+}
+
+impl std::str::FromStr for OutputTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(template: &str) -> Result<Self> {
+        OutputTemplate::parse(template)
+    }
+}
+
+/// Start of the SHA-256 of `path`, or `unreadable` if it can't be read
+fn hash_prefix(path: &Path) -> String {
+    let Ok(mut file) = std::fs::File::open(path) else {
+        return "unreadable".to_string();
+    };
+    let mut hasher = Sha256::new();
+    if std::io::copy(&mut file, &mut hasher).is_err() {
+        return "unreadable".to_string();
+    }
+    let mut hash = format!("{:x}", hasher.finalize());
+    hash.truncate(HASH_DIGITS);
+    hash
+}
+
+/// Paths in `output_dir` of the safe PDFs of `inputs`, named by `template`
+/// and made unique, for a batch started at `now`
+pub(crate) fn output_paths(
+    inputs: &[String],
+    output_dir: &str,
+    template: &OutputTemplate,
+    now: SystemTime,
+) -> Vec<String> {
+    let UtcTime {
+        year, month, day, ..
+    } = now.into();
+    let date = format!("{year:04}-{month:02}-{day:02}");
+    let mut seen = HashSet::new();
+    inputs
+        .iter()
+        .enumerate()
+        .map(|(index, input)| {
+            let name = template.name(Path::new(input), index + 1, &date);
+            let (base, extension) = match name.rfind('.') {
+                Some(dot) if dot > 0 => name.split_at(dot),
+                _ => (name.as_str(), ""),
+            };
+            let mut unique = name.clone();
+            let mut n = 1;
+            while !seen.insert(unique.clone()) {
+                n += 1;
+                unique = format!("{base}-{n}{extension}");
+            }
+            Path::new(output_dir)
+                .join(unique)
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            OutputTemplate::parse("{stem}-{{x}}.pdf").unwrap().parts,
+            [Part::Stem, Part::Literal("-{x}.pdf".to_string())]
+        );
+        for invalid in [
+            "{name}.pdf",
+            "{stem.pdf",
+            "stem}.pdf",
+            "../{stem}.pdf",
+            "..",
+        ] {
+            assert!(OutputTemplate::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_output_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let report = dir.path().join("report.docx");
+        std::fs::write(&report, "hello").unwrap();
+        let inputs = [
+            report.to_string_lossy().into_owned(),
+            "b/report.pdf".to_string(),
+            "notes".to_string(),
+        ];
+        // 2024-03-01T12:00:00Z
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_294_400);
+
+        let template = "{counter}-{stem}-{date}-{hash}-safe.pdf".parse().unwrap();
+        assert_eq!(
+            output_paths(&inputs, "out", &template, now),
+            [
+                "out/1-report-2024-03-01-2cf24dba5fb0-safe.pdf",
+                "out/2-report-2024-03-01-unreadable-safe.pdf",
+                "out/3-notes-2024-03-01-unreadable-safe.pdf",
+            ]
+        );
+        let template = "{date}.pdf".parse().unwrap();
+        assert_eq!(
+            output_paths(&inputs, "out", &template, now),
+            [
+                "out/2024-03-01.pdf",
+                "out/2024-03-01-2.pdf",
+                "out/2024-03-01-3.pdf"
+            ]
+        );
+    }
+}
@@ -0,0 +1,809 @@
+//! OCR engines adding a text layer to safe PDFs
+//!
+//! Engines reading the pixels of pages, tesseract with the `tesseract`
+//! feature or Vision on macOS with the `ocr` feature, recognize them while
+//! the safe PDF is written, and their words are written with each page.
+//! Otherwise, the text layer is added to the written PDF by ocrmypdf. If it
+//! fails, the safe PDF is kept without a text layer.
+
+use crate::hocr::{self, OcrPage};
+use crate::orient::OverWhite;
+#[cfg(all(target_os = "macos", feature = "ocr"))]
+pub use crate::vision::Vision;
+use crate::{
+    conversion_temp_dir, replace_control_chars, ConversionOptions, OcrMyPdfOptions, OcrSidecar,
+    PageData, PdfPage, PixelFormat,
+};
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use std::io::Read;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Suffix of the hOCR files ocrmypdf keeps for each page, after the page
+/// number
+const OCRMYPDF_HOCR_SUFFIX: &str = "_ocr_hocr.hocr";
+
+/// How often ocrmypdf is checked for having exited or timed out
+const OCR_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// OCR of one safe PDF
+#[derive(Clone, Copy, Debug)]
+pub struct OcrJob<'a> {
+    /// PDF of page images, without text
+    pub input_pdf: &'a Path,
+    /// Where to write the PDF with a text layer
+    pub output_pdf: &'a Path,
+    /// Tesseract language(s), e.g. `eng` or `eng+deu`
+    pub lang: &'a str,
+    /// Also return the results in this format
+    pub sidecar: Option<OcrSidecar>,
+    /// Options of ocrmypdf, ignored by other engines
+    pub ocrmypdf: &'a OcrMyPdfOptions,
+    /// Kill the engine if it runs longer, for engines running in their own
+    /// process
+    pub timeout: Option<Duration>,
+    /// Directory for the engine's temporary files, removed after the
+    /// conversion
+    pub temp_dir: &'a Path,
+    /// Write a linearized PDF, if the engine can
+    pub linearize: bool,
+}
+
+/// Results of an OCR engine, besides the PDF it writes
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OcrOutput {
+    /// The document requested by [`OcrJob::sidecar`], if the engine could
+    /// write it
+    pub sidecar: Option<String>,
+}
+
+/// Program finding the text of pages, either in a PDF of page images, to
+/// which it adds a text layer, or in their pixels
+pub trait OcrEngine: Send + Sync {
+    /// Name of the engine, for logs
+    fn name(&self) -> &'static str;
+
+    /// Whether the engine can return [`OcrSidecar`] documents
+    fn supports_sidecars(&self) -> bool {
+        false
+    }
+
+    /// Whether the engine reads the pixels of pages, with
+    /// [`recognize_page`](OcrEngine::recognize_page), rather than PDFs,
+    /// with [`apply`](OcrEngine::apply)
+    fn reads_pixels(&self) -> bool {
+        false
+    }
+
+    /// Write `job.output_pdf`, failing if the text layer couldn't be added
+    fn apply(&self, job: &OcrJob<'_>) -> Result<OcrOutput> {
+        let _ = job;
+        anyhow::bail!("{} doesn't read PDFs", self.name())
+    }
+
+    /// Find the words on a grayscale or RGB page with `ppi` pixels per inch
+    fn recognize_page(&self, page: &PageData, ppi: f32) -> Result<OcrPage> {
+        let _ = (page, ppi);
+        anyhow::bail!("{} doesn't read pixels", self.name())
+    }
+}
+
+/// [ocrmypdf](https://ocrmypdf.readthedocs.io), running tesseract on each page
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OcrMyPdf;
+
+impl OcrEngine for OcrMyPdf {
+    fn name(&self) -> &'static str {
+        "ocrmypdf"
+    }
+
+    fn supports_sidecars(&self) -> bool {
+        true
+    }
+
+    fn apply(&self, job: &OcrJob<'_>) -> Result<OcrOutput> {
+        let mut args = vec!["-l".to_string(), job.lang.to_string()];
+        args.extend(job.ocrmypdf.args());
+        if job.linearize {
+            // Linearize whatever the size of the PDF
+            args.extend(["--fast-web-view", "0"].map(String::from));
+        }
+        if job.sidecar.is_some() {
+            // Keep the hOCR tesseract writes for each page in the work folder
+            args.extend(["--pdf-renderer", "hocr", "--keep-temporary-files"].map(String::from));
+        }
+        if job.ocrmypdf.sandbox {
+            run_sandboxed(job, args)?;
+        } else {
+            let mut command = Command::new("ocrmypdf");
+            command
+                .args(args)
+                .arg(job.input_pdf)
+                .arg(job.output_pdf)
+                .env("TMPDIR", job.temp_dir);
+            run_ocrmypdf(
+                command,
+                job.timeout,
+                "Failed to run ocrmypdf; to enable OCR, install it: pip install ocrmypdf",
+            )?;
+        }
+
+        // The text layer is there even if the sidecar can't be read
+        let sidecar = job
+            .sidecar
+            .and_then(|format| match read_ocrmypdf_hocr(job.temp_dir) {
+                Ok(pages) => Some(format.write(&pages)),
+                Err(e) => {
+                    warn!(
+                        "Failed to read the results of ocrmypdf: {e_sanitized}",
+                        e_sanitized = replace_control_chars(&format!("{e:#}"), true)
+                    );
+                    None
+                }
+            });
+        Ok(OcrOutput { sidecar })
+    }
+}
+
+/// Run ocrmypdf with `args`, then its input and output PDFs, under
+/// bubblewrap
+///
+/// Only the input PDF, read-only, and the job's temporary directory, where
+/// the output is written before being copied, are visible to it.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn run_sandboxed(job: &OcrJob<'_>, mut args: Vec<String>) -> Result<()> {
+    use crate::bwrap::{self, Bind};
+
+    const INPUT: &str = "/input.pdf";
+    const WORK_DIR: &str = "/work";
+    let output = "ocr.pdf";
+    args.extend([INPUT.to_string(), format!("{WORK_DIR}/{output}")]);
+
+    let mut command_line = vec!["ocrmypdf"];
+    command_line.extend(args.iter().map(String::as_str));
+    let (command, _pipes) = bwrap::command(
+        &command_line,
+        &[("TMPDIR", WORK_DIR)],
+        &[
+            Bind {
+                host: job.input_pdf,
+                sandbox: INPUT,
+                writable: false,
+            },
+            Bind {
+                host: job.temp_dir,
+                sandbox: WORK_DIR,
+                writable: true,
+            },
+        ],
+    )?;
+    run_ocrmypdf(
+        command,
+        job.timeout,
+        "Failed to run ocrmypdf under bubblewrap; make sure bwrap and ocrmypdf are installed",
+    )?;
+    std::fs::copy(job.temp_dir.join(output), job.output_pdf)
+        .context("Failed to copy the PDF written by ocrmypdf")?;
+    Ok(())
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+fn run_sandboxed(_job: &OcrJob<'_>, _args: Vec<String>) -> Result<()> {
+    anyhow::bail!("Sandboxing ocrmypdf is only available on Linux (x86_64 and aarch64)")
+}
+
+/// Run `command`, a command line of ocrmypdf, killing it and the processes
+/// it started if it runs longer than `timeout`
+///
+/// `spawn_error` explains a failure to start it.
+fn run_ocrmypdf(
+    mut command: Command,
+    timeout: Option<Duration>,
+    spawn_error: &'static str,
+) -> Result<()> {
+    // Its own process group, so that its tesseract processes are killed too
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(spawn_error)?;
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let stderr_thread = std::thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = stderr.read_to_end(&mut output);
+        output
+    });
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().context("Failed to wait for ocrmypdf")? {
+            break status;
+        }
+        if let Some(timeout) = timeout.filter(|timeout| started.elapsed() > *timeout) {
+            kill_process_group(&mut child);
+            anyhow::bail!("ocrmypdf timed out after {} seconds", timeout.as_secs_f64());
+        }
+        std::thread::sleep(OCR_POLL_INTERVAL);
+    };
+    let stderr = stderr_thread.join().unwrap_or_default();
+    if !status.success() {
+        anyhow::bail!(
+            "{stderr_sanitized}",
+            stderr_sanitized = replace_control_chars(String::from_utf8_lossy(&stderr).trim(), true)
+        );
+    }
+    Ok(())
+}
+
+fn kill_process_group(child: &mut Child) {
+    // SAFETY: kill only sends a signal; the group is the child's own, as it
+    // hasn't been reaped yet
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Read the hOCR of each page kept by ocrmypdf in its work folder, in
+/// `temp_dir`
+fn read_ocrmypdf_hocr(temp_dir: &Path) -> Result<Vec<OcrPage>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(temp_dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            for entry in std::fs::read_dir(&path)? {
+                let path = entry?.path();
+                if path
+                    .file_name()
+                    .is_some_and(|name| name.to_string_lossy().ends_with(OCRMYPDF_HOCR_SUFFIX))
+                {
+                    files.push(path);
+                }
+            }
+        }
+    }
+    if files.is_empty() {
+        anyhow::bail!("ocrmypdf kept no hOCR files");
+    }
+    // Page numbers are zero-padded, so names sort by page
+    files.sort();
+
+    let mut pages = Vec::with_capacity(files.len());
+    for file in &files {
+        let hocr = std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read {}", file.display()))?;
+        pages.extend(hocr::parse_hocr(&hocr)?);
+    }
+    Ok(pages)
+}
+
+/// Instances of an engine's API, each used by one page at a time, created
+/// when all of them are busy until there are `max`
+///
+/// Pages wait for an instance once there are `max` of them. An instance
+/// whose page fails, or panics, is dropped, and replaced when needed.
+#[cfg_attr(not(feature = "tesseract"), allow(dead_code))]
+struct Pool<T> {
+    state: Mutex<PoolState<T>>,
+    /// Notified when an instance is put back or dropped
+    released: Condvar,
+    max: usize,
+}
+
+struct PoolState<T> {
+    idle: Vec<T>,
+    /// Instances idle, in use, or being created
+    count: usize,
+}
+
+/// Place of an instance taken from a [`Pool`], given up when dropped unless
+/// the instance is put back
+struct Lease<'a, T>(&'a Pool<T>);
+
+#[cfg_attr(not(feature = "tesseract"), allow(dead_code))]
+impl<T> Pool<T> {
+    /// A pool holding `first`, the instance created to check that the API
+    /// works
+    fn new(first: T, max: usize) -> Self {
+        Pool {
+            state: Mutex::new(PoolState {
+                idle: vec![first],
+                count: 1,
+            }),
+            released: Condvar::new(),
+            max: max.max(1),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolState<T>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Run `f` with an idle instance, or one made by `create`, and put back
+    /// the instance it returns
+    fn run<R>(
+        &self,
+        create: impl FnOnce() -> Result<T>,
+        f: impl FnOnce(T) -> Result<(T, R)>,
+    ) -> Result<R> {
+        let mut state = self.lock();
+        let idle = loop {
+            if let Some(instance) = state.idle.pop() {
+                break Some(instance);
+            }
+            if state.count < self.max {
+                state.count += 1;
+                break None;
+            }
+            state = self
+                .released
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        };
+        drop(state);
+
+        let lease = Lease(self);
+        let instance = match idle {
+            Some(instance) => instance,
+            None => create()?,
+        };
+        let (instance, result) = f(instance)?;
+        lease.put_back(instance);
+        Ok(result)
+    }
+}
+
+impl<T> Lease<'_, T> {
+    fn put_back(self, instance: T) {
+        let pool = self.0;
+        std::mem::forget(self);
+        pool.lock().idle.push(instance);
+        pool.released.notify_one();
+    }
+}
+
+impl<T> Drop for Lease<'_, T> {
+    fn drop(&mut self) {
+        self.0.lock().count -= 1;
+        self.0.released.notify_one();
+    }
+}
+
+/// [Tesseract](https://tesseract-ocr.github.io) through its C API, reading
+/// the pixels of pages
+///
+/// Pages are recognized concurrently, each by its own instance of the API,
+/// with up to one instance per thread of the rayon pool. Each instance loads
+/// its own copy of the models.
+#[cfg(feature = "tesseract")]
+pub struct Tesseract {
+    lang: String,
+    pool: Pool<tesseract::Tesseract>,
+}
+
+#[cfg(feature = "tesseract")]
+impl Tesseract {
+    /// Load the models of `lang`, e.g. `eng` or `eng+deu`, failing if they
+    /// aren't installed
+    pub fn new(lang: &str) -> Result<Self> {
+        Ok(Tesseract {
+            lang: lang.to_string(),
+            pool: Pool::new(Self::load(lang)?, rayon::current_num_threads()),
+        })
+    }
+
+    fn load(lang: &str) -> Result<tesseract::Tesseract> {
+        tesseract::Tesseract::new(None, Some(lang)).with_context(|| {
+            format!(
+                "Failed to load tesseract's models for '{lang_sanitized}'",
+                lang_sanitized = replace_control_chars(lang, false)
+            )
+        })
+    }
+}
+
+#[cfg(feature = "tesseract")]
+impl OcrEngine for Tesseract {
+    fn name(&self) -> &'static str {
+        "tesseract"
+    }
+
+    fn supports_sidecars(&self) -> bool {
+        true
+    }
+
+    fn reads_pixels(&self) -> bool {
+        true
+    }
+
+    fn recognize_page(&self, page: &PageData, ppi: f32) -> Result<OcrPage> {
+        let bytes_per_pixel = page.format.bytes_per_pixel();
+        // Each step consumes the API, which is lost if it fails
+        let hocr = self.pool.run(
+            || Self::load(&self.lang),
+            |tesseract| {
+                let mut tesseract = tesseract
+                    .set_frame(
+                        &page.pixels,
+                        page.width as i32,
+                        page.height as i32,
+                        bytes_per_pixel as i32,
+                        (page.width as usize * bytes_per_pixel) as i32,
+                    )
+                    .context("Failed to pass the page to tesseract")?
+                    .set_source_resolution(ppi.round() as i32)
+                    .recognize()
+                    .context("Tesseract failed to recognize the page")?;
+                let hocr = tesseract
+                    .get_hocr_text(0)
+                    .context("Failed to get the words found by tesseract")?;
+                Ok((tesseract, hocr))
+            },
+        )?;
+
+        let page_bbox = [0, 0, page.width as u32, page.height as u32];
+        Ok(hocr::parse_hocr(&hocr)?
+            .into_iter()
+            .next()
+            .unwrap_or(OcrPage {
+                bbox: page_bbox,
+                blocks: Vec::new(),
+            }))
+    }
+}
+
+/// The engine reading pixels to use for `lang`, if one is available
+pub(crate) fn pixel_engine(lang: &str) -> Option<Box<dyn OcrEngine>> {
+    #[cfg(feature = "tesseract")]
+    match Tesseract::new(lang) {
+        Ok(engine) => return Some(Box::new(engine)),
+        Err(e) => warn!(
+            "{e_sanitized}; adding the text layer once the PDF is written instead",
+            e_sanitized = replace_control_chars(&format!("{e:#}"), true)
+        ),
+    }
+    #[cfg(all(target_os = "macos", feature = "ocr"))]
+    return Some(Box::new(Vision::new(lang)));
+    #[cfg(not(any(feature = "tesseract", all(target_os = "macos", feature = "ocr"))))]
+    let _ = lang;
+    #[cfg(not(all(target_os = "macos", feature = "ocr")))]
+    None
+}
+
+/// Pixels of `page` for an engine reading them: grayscale or RGB, with
+/// transparent pixels put over white
+pub(crate) fn page_image<P: PdfPage>(page: &P) -> Result<PageData> {
+    let (format, bytes_per_pixel) = match page.format() {
+        PixelFormat::Gray => (PixelFormat::Gray, 1),
+        PixelFormat::Rgb | PixelFormat::Rgba => (PixelFormat::Rgb, 3),
+    };
+    let mut pixels =
+        Vec::with_capacity(page.width() as usize * page.height() as usize * bytes_per_pixel);
+    if page.format() == PixelFormat::Rgba {
+        page.write_pixels(&mut OverWhite::new(&mut pixels))?;
+    } else {
+        page.write_pixels(&mut pixels)?;
+    }
+    Ok(PageData::with_format(
+        page.width(),
+        page.height(),
+        format,
+        pixels,
+    ))
+}
+
+/// The engines reading PDFs available on this platform, by order of
+/// preference
+fn engines() -> Vec<Box<dyn OcrEngine>> {
+    vec![Box::new(OcrMyPdf)]
+}
+
+/// Add a text layer to `job.input_pdf` with the first engine that succeeds,
+/// failing with the error of each engine if none does
+///
+/// Engines that can't return the requested sidecar are skipped.
+pub(crate) fn apply_ocr(job: &OcrJob<'_>) -> Result<OcrOutput> {
+    info!("Applying OCR to PDF...");
+    let mut failures = Vec::new();
+    for engine in engines() {
+        if job.sidecar.is_some() && !engine.supports_sidecars() {
+            info!("Skipping {}, which can't write OCR sidecars", engine.name());
+            continue;
+        }
+        match engine.apply(job) {
+            Ok(output) => {
+                info!("OCR applied successfully using {}", engine.name());
+                return Ok(output);
+            }
+            Err(e) => {
+                let e_sanitized = replace_control_chars(&format!("{e:#}"), true);
+                warn!("OCR with {} failed: {e_sanitized}", engine.name());
+                failures.push(format!("{}: {e_sanitized}", engine.name()));
+            }
+        }
+    }
+    if failures.is_empty() {
+        anyhow::bail!("No OCR engine is available");
+    }
+    anyhow::bail!("No OCR engine succeeded ({})", failures.join("; "))
+}
+
+/// A language an OCR engine can recognize
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OcrLanguage {
+    /// Name of the engine, as in logs
+    pub engine: &'static str,
+    /// Code selecting the language in
+    /// [`ConversionOptions::ocr_lang`](crate::ConversionOptions::ocr_lang),
+    /// or None if the language has none
+    pub code: Option<String>,
+    /// The engine's own name for the language, e.g. `en-US` for Vision
+    pub name: String,
+}
+
+/// The languages OCR can recognize here: tesseract's installed language
+/// packs, also used by ocrmypdf, and the languages of Vision on macOS
+///
+/// Engines that aren't installed have no languages.
+pub fn ocr_languages() -> Vec<OcrLanguage> {
+    let mut languages = Vec::new();
+    match tesseract_languages() {
+        Ok(installed) => languages.extend(
+            installed
+                .into_iter()
+                // Orientation and script detection only
+                .filter(|lang| lang != "osd")
+                .map(|lang| OcrLanguage {
+                    engine: "tesseract",
+                    code: Some(lang.clone()),
+                    name: lang,
+                }),
+        ),
+        Err(e) => debug!("No tesseract languages: {e:#}"),
+    }
+    #[cfg(all(target_os = "macos", feature = "ocr"))]
+    match crate::vision::supported_languages() {
+        Ok(supported) => languages.extend(supported.into_iter().map(|locale| OcrLanguage {
+            engine: "macOS Vision",
+            code: crate::vision::tesseract_code(&locale).map(str::to_string),
+            name: locale,
+        })),
+        Err(e) => warn!("{e:#}"),
+    }
+    languages
+}
+
+/// Language packs tesseract has installed
+pub(crate) fn tesseract_languages() -> Result<Vec<String>> {
+    let output = Command::new("tesseract")
+        .arg("--list-langs")
+        .stdin(Stdio::null())
+        .output()
+        .context("Failed to run tesseract")?;
+    if !output.status.success() {
+        anyhow::bail!("Failed to list tesseract's languages");
+    }
+    Ok(parse_language_list(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// Languages of `tesseract --list-langs`, after its header line
+fn parse_language_list(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter(|line| !line.starts_with("List of available languages"))
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Copy `job.input_pdf` as is after OCR failed with `error`
+pub(crate) fn fall_back(job: &OcrJob<'_>, error: &anyhow::Error) -> Result<()> {
+    warn!("Falling back to PDF without OCR: {error:#}");
+    if job.linearize {
+        return crate::linearize::linearize_file(job.input_pdf, job.output_pdf);
+    }
+    std::fs::copy(job.input_pdf, job.output_pdf).context("Failed to copy PDF")?;
+    Ok(())
+}
+
+/// Apply OCR to add text layer to PDF (platform-aware)
+///
+/// If no OCR engine succeeds, the PDF is copied without a text layer.
+pub fn apply_ocr_fn(input_pdf: String, output_pdf: String) -> Result<()> {
+    let temp_dir = conversion_temp_dir()?;
+    let job = OcrJob {
+        input_pdf: Path::new(&input_pdf),
+        output_pdf: Path::new(&output_pdf),
+        lang: &ConversionOptions::default().ocr_lang,
+        sidecar: None,
+        ocrmypdf: &OcrMyPdfOptions::default(),
+        timeout: None,
+        temp_dir: temp_dir.path(),
+        linearize: false,
+    };
+    if let Err(e) = apply_ocr(&job) {
+        fall_back(&job, &e)?;
+    }
+    Ok(())
+}
+
+/// Text of a page of a PDF
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PageText {
+    /// Page number, starting at 1
+    pub page: usize,
+    pub text: String,
+}
+
+/// Extract the text of each page of `pdf_path`, such as the text layer added
+/// to a safe PDF by OCR, with poppler's `pdftotext`
+///
+/// Safe PDFs converted without OCR have pages without text. Control
+/// characters other than newlines are replaced.
+pub fn extract_text(pdf_path: &str) -> Result<Vec<PageText>> {
+    let output = Command::new("pdftotext")
+        .args(["-enc", "UTF-8", pdf_path, "-"])
+        .stdin(Stdio::null())
+        .output()
+        .context("Failed to run pdftotext; is poppler-utils installed?")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "pdftotext failed: {stderr_sanitized}",
+            stderr_sanitized =
+                replace_control_chars(String::from_utf8_lossy(&output.stderr).trim(), true)
+        );
+    }
+    Ok(split_pages(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Split the output of pdftotext, where each page ends with a form feed
+fn split_pages(text: &str) -> Vec<PageText> {
+    let mut pages: Vec<&str> = text.split('\x0c').collect();
+    // Nothing follows the form feed of the last page
+    pages.pop();
+    pages
+        .into_iter()
+        .enumerate()
+        .map(|(index, text)| PageText {
+            page: index + 1,
+            text: replace_control_chars(text.trim_end(), true),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_language_list() {
+        let list = "List of available languages in \"/usr/share/tessdata/\" (3):\n\
+                    deu\neng\nosd\n";
+        assert_eq!(parse_language_list(list), ["deu", "eng", "osd"]);
+        assert!(parse_language_list("").is_empty());
+    }
+
+    #[test]
+    fn test_read_ocrmypdf_hocr() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        assert!(read_ocrmypdf_hocr(temp_dir.path()).is_err());
+
+        let work_dir = temp_dir.path().join("ocrmypdf.io.abc123");
+        std::fs::create_dir(&work_dir).unwrap();
+        // Written out of order, and among the other intermediate files
+        for (page, word) in [(2, "second"), (1, "first"), (10, "tenth")] {
+            std::fs::write(
+                work_dir.join(format!("{page:06}{OCRMYPDF_HOCR_SUFFIX}")),
+                format!(
+                    "<div class='ocr_page' title='bbox 0 0 10 10'>\
+                     <span class='ocrx_word' title='bbox 1 1 5 5'>{word}</span></div>"
+                ),
+            )
+            .unwrap();
+        }
+        std::fs::write(work_dir.join("000001_ocr.png"), b"").unwrap();
+
+        let pages = read_ocrmypdf_hocr(temp_dir.path()).unwrap();
+        let words: Vec<&str> = pages
+            .iter()
+            .map(|page| page.blocks[0].paragraphs[0].lines[0].words[0].text.as_str())
+            .collect();
+        assert_eq!(words, ["first", "second", "tenth"]);
+    }
+
+    #[test]
+    fn test_pool() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let pool = Pool::new(0, 2);
+        let created = AtomicUsize::new(1);
+        let busy = AtomicUsize::new(0);
+        let max_busy = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            for page in 0..16 {
+                let (pool, created, busy, max_busy) = (&pool, &created, &busy, &max_busy);
+                scope.spawn(move || {
+                    let result = pool.run(
+                        || Ok(created.fetch_add(1, Ordering::SeqCst)),
+                        |instance| {
+                            let now_busy = busy.fetch_add(1, Ordering::SeqCst) + 1;
+                            max_busy.fetch_max(now_busy, Ordering::SeqCst);
+                            std::thread::sleep(std::time::Duration::from_millis(2));
+                            busy.fetch_sub(1, Ordering::SeqCst);
+                            // Every third page loses its instance
+                            if page % 3 == 0 {
+                                anyhow::bail!("page {page} failed");
+                            }
+                            Ok((instance, page))
+                        },
+                    );
+                    assert_eq!(result.is_ok(), page % 3 != 0);
+                });
+            }
+        });
+        assert!(max_busy.load(Ordering::SeqCst) <= 2);
+        let state = pool.lock();
+        assert!(state.count <= 2);
+        assert_eq!(state.idle.len(), state.count);
+        // Lost instances were replaced by new ones
+        assert!(created.load(Ordering::SeqCst) > 2);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_ocrmypdf_timeout() {
+        let mut command = Command::new("sh");
+        // The shell and its sleep are killed together
+        command.args(["-c", "sleep 10; true"]);
+        let started = Instant::now();
+        let error = run_ocrmypdf(command, Some(Duration::from_millis(200)), "").unwrap_err();
+        assert!(error.to_string().contains("timed out"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo 'bad page' >&2; exit 1"]);
+        let error = run_ocrmypdf(command, None, "").unwrap_err();
+        assert_eq!(error.to_string(), "bad page");
+    }
+
+    #[test]
+    fn test_page_image() {
+        let page =
+            PageData::with_format(2, 1, PixelFormat::Rgba, vec![0, 0, 0, 0, 10, 20, 30, 255]);
+        let image = page_image(&page).unwrap();
+        assert_eq!(image.format, PixelFormat::Rgb);
+        assert_eq!(image.pixels[..], [255, 255, 255, 10, 20, 30]);
+
+        let gray = PageData::with_format(1, 1, PixelFormat::Gray, vec![7]);
+        assert_eq!(page_image(&gray).unwrap().pixels[..], [7]);
+    }
+
+    #[test]
+    fn test_split_pages() {
+        let pages = split_pages("Hello\nworld\n\x0c\x0cNext\x1b[31m page\n\x0c");
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[0].text, "Hello\nworld");
+        assert_eq!(pages[1].text, "");
+        assert_eq!(pages[2].text, "Next\u{FFFD}[31m page");
+        assert_eq!(pages[2].page, 3);
+        assert!(split_pages("").is_empty());
+    }
+}
@@ -0,0 +1,822 @@
+//! Options of conversions
+
+use crate::redact;
+use crate::stage::Stages;
+#[cfg(feature = "container")]
+use crate::{hocr, icc, layout, naming, replace_control_chars};
+#[cfg(feature = "container")]
+use anyhow::Context;
+use anyhow::Result;
+#[cfg(feature = "container")]
+use std::fs::File;
+#[cfg(feature = "container")]
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const DPI: f32 = 150.0;
+/// [`ConversionOptions::ocr_lang`] detecting the languages of the document
+pub const OCR_LANG_AUTO: &str = "auto";
+/// Default for [`ConversionOptions::max_output_bytes`]
+pub const DEFAULT_MAX_OUTPUT_BYTES: u64 = 4 << 30;
+/// Pages the container may convert ahead of the PDF writer
+#[cfg(feature = "container")]
+pub(crate) const PIPELINE_BUFFERED_PAGES: usize = 4;
+/// Pixels kept in memory before spooling, with [`ConversionOptions::low_memory`]
+#[cfg(feature = "container")]
+pub(crate) const LOW_MEMORY_SPOOL_BYTES: u64 = 64 << 20;
+/// Largest image strip, with [`ConversionOptions::low_memory`]
+#[cfg(feature = "container")]
+pub(crate) const LOW_MEMORY_STRIP_BYTES: u64 = 16 << 20;
+
+/// Container runtime used to run the conversion sandbox
+///
+/// Serialized with the names [`FromStr`](std::str::FromStr) accepts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Runtime {
+    #[default]
+    Podman,
+    Docker,
+    /// No container: a locally installed converter sandboxed by bubblewrap
+    Bwrap,
+    /// Podman with gVisor's runsc as its OCI runtime, putting a user-space
+    /// kernel between the converter and the host
+    Gvisor,
+    /// Apple's `container` CLI (macOS 15 and later), running each container
+    /// in its own virtual machine
+    #[cfg_attr(feature = "serde", serde(rename = "container"))]
+    AppleContainer,
+}
+
+impl Runtime {
+    /// Name of the runtime's command-line client
+    pub fn command(&self) -> &'static str {
+        match self {
+            Runtime::Podman | Runtime::Gvisor => "podman",
+            Runtime::Docker => "docker",
+            Runtime::Bwrap => "bwrap",
+            Runtime::AppleContainer => "container",
+        }
+    }
+}
+
+impl std::fmt::Display for Runtime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Runtime::Gvisor => f.write_str("gvisor"),
+            runtime => f.write_str(runtime.command()),
+        }
+    }
+}
+
+impl std::str::FromStr for Runtime {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "podman" => Ok(Runtime::Podman),
+            "docker" => Ok(Runtime::Docker),
+            "bwrap" => Ok(Runtime::Bwrap),
+            "gvisor" => Ok(Runtime::Gvisor),
+            "container" => Ok(Runtime::AppleContainer),
+            _ => {
+                anyhow::bail!(
+                    "Unknown container runtime '{s}' (expected podman, docker, bwrap, gvisor or container)"
+                )
+            }
+        }
+    }
+}
+
+/// Confinement of the conversion container beyond the default security
+/// arguments
+///
+/// Applies to podman, docker and gVisor. Apple's `container` runtime and
+/// bubblewrap always use their own read-only root filesystem.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ContainerHardening {
+    /// Mount the image's root filesystem read-only
+    pub read_only: bool,
+    /// Size in MiB of the tmpfs mounted on `/tmp` as scratch space, which
+    /// also becomes the converter's home directory
+    pub tmpfs_size_mib: Option<u32>,
+    /// Run the container in its own user namespace (`--userns=auto`, podman
+    /// only)
+    pub userns_auto: bool,
+}
+
+impl ContainerHardening {
+    /// Every restriction enabled, with a 512 MiB scratch tmpfs
+    pub fn strict() -> Self {
+        ContainerHardening {
+            read_only: true,
+            tmpfs_size_mib: Some(512),
+            userns_auto: true,
+        }
+    }
+}
+
+/// Cleanup of poor scans, applied to the pixels of each page before they are
+/// embedded in the safe PDF, and so before OCR
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct PageCleanup {
+    /// Stretch the darkest and lightest tones of the page to black and white
+    pub auto_contrast: bool,
+    /// Turn the paper of grey or yellowed scans white
+    pub normalize_white: bool,
+    /// Remove isolated dots left by dust and scanner noise
+    pub despeckle: bool,
+}
+
+impl PageCleanup {
+    /// Every cleanup step enabled
+    pub fn all() -> Self {
+        PageCleanup {
+            auto_contrast: true,
+            normalize_white: true,
+            despeckle: true,
+        }
+    }
+
+    /// Whether any cleanup step is enabled
+    pub fn is_enabled(&self) -> bool {
+        self.auto_contrast || self.normalize_white || self.despeckle
+    }
+}
+
+/// Compressor of the pixels embedded in the safe PDF
+///
+/// flate2 uses the zlib-ng backend when the crate is built with the `zlib-ng`
+/// feature, which compresses faster to the same format.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum CompressionBackend {
+    /// Deflate with flate2, fast at every level
+    #[default]
+    Flate2,
+    /// Zopfli, which makes the smallest output but is orders of magnitude
+    /// slower. Needs the `zopfli` feature.
+    Zopfli,
+}
+
+/// How the pixels embedded in the safe PDF are compressed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct CompressionConfig {
+    /// From 1, fastest, to 9, smallest. For zopfli, the number of
+    /// optimization passes over each page.
+    pub level: u32,
+    pub backend: CompressionBackend,
+}
+
+impl CompressionConfig {
+    /// The smallest output, at the cost of a much slower conversion
+    pub fn smallest() -> Self {
+        CompressionConfig {
+            level: 9,
+            backend: if cfg!(feature = "zopfli") {
+                CompressionBackend::Zopfli
+            } else {
+                CompressionBackend::Flate2
+            },
+        }
+    }
+
+    #[cfg(feature = "container")]
+    pub(crate) fn check(&self) -> Result<()> {
+        if !(1..=9).contains(&self.level) {
+            anyhow::bail!(
+                "Invalid compression level {}: must be between 1 and 9",
+                self.level
+            );
+        }
+        if self.backend == CompressionBackend::Zopfli && !cfg!(feature = "zopfli") {
+            anyhow::bail!("Compressing with zopfli requires the `zopfli` feature");
+        }
+        Ok(())
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            level: 6,
+            backend: CompressionBackend::default(),
+        }
+    }
+}
+
+/// Color space the RGB pixels of pages are assumed to be in
+///
+/// Gray pages and alpha channels are always embedded as device gray.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum ColorProfile {
+    /// Device RGB, left for viewers and printers to interpret
+    #[default]
+    Device,
+    /// sRGB, whose ICC profile is embedded in the safe PDF
+    Srgb,
+    /// The RGB ICC profile in this file, embedded in the safe PDF
+    File(PathBuf),
+}
+
+impl ColorProfile {
+    /// The ICC profile to embed, if any
+    #[cfg(feature = "container")]
+    pub(crate) fn load(&self) -> Result<Option<Vec<u8>>> {
+        match self {
+            ColorProfile::Device => Ok(None),
+            ColorProfile::Srgb => Ok(Some(icc::srgb())),
+            ColorProfile::File(path) => {
+                let path_sanitized = replace_control_chars(&path.to_string_lossy(), false);
+                let mut profile = Vec::new();
+                File::open(path)
+                    .and_then(|file| {
+                        file.take(icc::MAX_PROFILE_BYTES + 1)
+                            .read_to_end(&mut profile)
+                    })
+                    .with_context(|| format!("Failed to read ICC profile '{path_sanitized}'"))?;
+                if profile.len() as u64 > icc::MAX_PROFILE_BYTES {
+                    anyhow::bail!(
+                        "ICC profile '{path_sanitized}' is larger than {} MiB",
+                        icc::MAX_PROFILE_BYTES >> 20
+                    );
+                }
+                icc::check_rgb(&profile)
+                    .with_context(|| format!("Invalid ICC profile '{path_sanitized}'"))?;
+                Ok(Some(profile))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ColorProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ColorProfile::Device => f.write_str("device"),
+            ColorProfile::Srgb => f.write_str("srgb"),
+            ColorProfile::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl std::str::FromStr for ColorProfile {
+    type Err = std::convert::Infallible;
+
+    /// `device`, `srgb`, or else the path of a profile
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s {
+            "device" => ColorProfile::Device,
+            "srgb" => ColorProfile::Srgb,
+            path => ColorProfile::File(PathBuf::from(path)),
+        })
+    }
+}
+
+/// Paper the pages of the safe PDF are laid out on
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Paper {
+    /// Each page is exactly the size of its image
+    #[default]
+    Original,
+    /// ISO A4, 210 × 297 mm
+    A4,
+    /// US Letter, 8.5 × 11 inches
+    Letter,
+    /// A4 or Letter, whichever is closest in shape to each page
+    Auto,
+}
+
+impl std::fmt::Display for Paper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Paper::Original => "original",
+            Paper::A4 => "a4",
+            Paper::Letter => "letter",
+            Paper::Auto => "auto",
+        })
+    }
+}
+
+impl std::str::FromStr for Paper {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "original" => Ok(Paper::Original),
+            "a4" => Ok(Paper::A4),
+            "letter" => Ok(Paper::Letter),
+            "auto" => Ok(Paper::Auto),
+            _ => anyhow::bail!("Unknown paper '{s}' (expected original, a4, letter or auto)"),
+        }
+    }
+}
+
+/// How the page images are laid out on the pages of the safe PDF
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct PageLayout {
+    /// Paper of each page. On standard paper, the page is turned to the
+    /// orientation of its image, which is scaled to fit within the margins
+    /// and centered.
+    pub paper: Paper,
+    /// Margin on each side of standard paper, in millimeters
+    pub margin_mm: f32,
+    /// Pages per sheet: 1, or 2 or 4 for printouts of long documents. The
+    /// pages are laid out in a grid, from the top left.
+    pub nup: usize,
+    /// Stamp "Page N of M" on each page, counting sheets when there are
+    /// several pages per sheet
+    pub page_numbers: Option<PageNumbers>,
+}
+
+impl PageLayout {
+    #[cfg(feature = "container")]
+    pub(crate) fn check(&self) -> Result<()> {
+        if !(self.margin_mm.is_finite() && (0.0..=layout::MAX_MARGIN_MM).contains(&self.margin_mm))
+        {
+            anyhow::bail!(
+                "Invalid margin {} mm: must be between 0 and {} mm",
+                self.margin_mm,
+                layout::MAX_MARGIN_MM
+            );
+        }
+        if !matches!(self.nup, 1 | 2 | 4) {
+            anyhow::bail!("Invalid pages per sheet {}: must be 1, 2 or 4", self.nup);
+        }
+        if let Some(numbers) = &self.page_numbers {
+            if !(numbers.font_size.is_finite() && (4.0..=72.0).contains(&numbers.font_size)) {
+                anyhow::bail!(
+                    "Invalid page number font size {}: must be between 4 and 72 points",
+                    numbers.font_size
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for PageLayout {
+    fn default() -> Self {
+        PageLayout {
+            paper: Paper::default(),
+            margin_mm: 10.0,
+            nup: 1,
+            page_numbers: None,
+        }
+    }
+}
+
+/// Corner or edge of the page where page numbers are stamped
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum StampPosition {
+    TopLeft,
+    Top,
+    TopRight,
+    BottomLeft,
+    #[default]
+    Bottom,
+    BottomRight,
+}
+
+impl std::fmt::Display for StampPosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            StampPosition::TopLeft => "top-left",
+            StampPosition::Top => "top",
+            StampPosition::TopRight => "top-right",
+            StampPosition::BottomLeft => "bottom-left",
+            StampPosition::Bottom => "bottom",
+            StampPosition::BottomRight => "bottom-right",
+        })
+    }
+}
+
+impl std::str::FromStr for StampPosition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "top-left" => Ok(StampPosition::TopLeft),
+            "top" => Ok(StampPosition::Top),
+            "top-right" => Ok(StampPosition::TopRight),
+            "bottom-left" => Ok(StampPosition::BottomLeft),
+            "bottom" => Ok(StampPosition::Bottom),
+            "bottom-right" => Ok(StampPosition::BottomRight),
+            _ => anyhow::bail!(
+                "Unknown position '{s}' (expected top-left, top, top-right, bottom-left, bottom or bottom-right)"
+            ),
+        }
+    }
+}
+
+/// "Page N of M" stamped on each page of the safe PDF
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct PageNumbers {
+    pub position: StampPosition,
+    /// In points
+    pub font_size: f32,
+}
+
+impl Default for PageNumbers {
+    fn default() -> Self {
+        PageNumbers {
+            position: StampPosition::default(),
+            font_size: 9.0,
+        }
+    }
+}
+
+/// Machine-readable OCR results, written alongside the safe PDF
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum OcrSidecar {
+    /// [hOCR](http://kba.github.io/hocr-spec/1.2/), the HTML tesseract writes
+    Hocr,
+    /// [ALTO](https://www.loc.gov/standards/alto/) 4 XML, common in
+    /// digitization and archival workflows
+    Alto,
+}
+
+impl OcrSidecar {
+    /// Path of the sidecar of the safe PDF at `pdf_path`, like `safe.hocr` or
+    /// `safe.alto.xml` for `safe.pdf`
+    pub fn path_for(self, pdf_path: &Path) -> PathBuf {
+        pdf_path.with_extension(match self {
+            OcrSidecar::Hocr => "hocr",
+            OcrSidecar::Alto => "alto.xml",
+        })
+    }
+
+    #[cfg(feature = "container")]
+    pub(crate) fn write(self, pages: &[hocr::OcrPage]) -> String {
+        match self {
+            OcrSidecar::Hocr => hocr::write_hocr(pages),
+            OcrSidecar::Alto => hocr::write_alto(pages),
+        }
+    }
+}
+
+impl std::fmt::Display for OcrSidecar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            OcrSidecar::Hocr => "hocr",
+            OcrSidecar::Alto => "alto",
+        })
+    }
+}
+
+impl std::str::FromStr for OcrSidecar {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "hocr" => Ok(OcrSidecar::Hocr),
+            "alto" => Ok(OcrSidecar::Alto),
+            _ => anyhow::bail!("Unknown OCR sidecar format '{s}' (expected hocr or alto)"),
+        }
+    }
+}
+
+/// Options of ocrmypdf when it adds the text layer; engines reading pixels
+/// don't have them, so setting any of them makes OCR use ocrmypdf
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct OcrMyPdfOptions {
+    /// Straighten crooked pages (`--deskew`)
+    pub deskew: bool,
+    /// Clean up pages with unpaper before recognizing them, without changing
+    /// the pages of the PDF (`--clean`)
+    pub clean: bool,
+    /// Turn pages whose text is sideways or upside down upright
+    /// (`--rotate-pages`)
+    pub rotate_pages: bool,
+    /// Number of pages recognized at once, instead of one per CPU (`--jobs`)
+    pub jobs: Option<usize>,
+    /// Optimization of the PDF, from 0 (none) to 3 (`--optimize`)
+    pub optimize: Option<u8>,
+    /// Run ocrmypdf under bubblewrap (Linux only), like
+    /// [`Runtime::Bwrap`] runs the converter, with access to the PDF and a
+    /// work directory only. ocrmypdf must be installed system-wide.
+    pub sandbox: bool,
+}
+
+impl OcrMyPdfOptions {
+    #[cfg(feature = "container")]
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.jobs == Some(0) {
+            anyhow::bail!("Invalid number of OCR jobs 0: must be at least 1");
+        }
+        if let Some(level) = self.optimize.filter(|level| *level > 3) {
+            anyhow::bail!("Invalid OCR optimization level {level}: must be between 0 and 3");
+        }
+        Ok(())
+    }
+
+    /// Arguments of ocrmypdf for these options
+    #[cfg(feature = "container")]
+    pub(crate) fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for (enabled, flag) in [
+            (self.deskew, "--deskew"),
+            (self.clean, "--clean"),
+            (self.rotate_pages, "--rotate-pages"),
+        ] {
+            if enabled {
+                args.push(flag.to_string());
+            }
+        }
+        if let Some(jobs) = self.jobs {
+            args.extend(["--jobs".to_string(), jobs.to_string()]);
+        }
+        if let Some(level) = self.optimize {
+            args.extend(["--optimize".to_string(), level.to_string()]);
+        }
+        args
+    }
+}
+
+/// Options controlling a conversion
+///
+/// With the `serde` feature, missing fields are deserialized with their
+/// default value.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ConversionOptions {
+    /// Add a text layer to the safe PDF
+    pub ocr: bool,
+    /// Tesseract language(s) used for OCR, e.g. `eng` or `eng+deu`, or
+    /// [`OCR_LANG_AUTO`] to choose among the installed ones from the first
+    /// page (see [`ConversionReport::ocr_lang`](crate::ConversionReport::ocr_lang))
+    pub ocr_lang: String,
+    /// Also write the OCR results in this format, next to the safe PDF (see
+    /// [`OcrSidecar::path_for`])
+    pub ocr_sidecar: Option<OcrSidecar>,
+    /// Options of ocrmypdf, when it adds the text layer
+    pub ocrmypdf: OcrMyPdfOptions,
+    /// Maximum time ocrmypdf may take to add the text layer, after which it
+    /// is killed and OCR fails. Engines reading pixels run in this process
+    /// and aren't bounded.
+    pub ocr_timeout: Option<Duration>,
+    /// Fail the conversion if no OCR engine could add the text layer,
+    /// instead of keeping the safe PDF without one (see
+    /// [`ConversionReport::ocr_fallback`](crate::ConversionReport::ocr_fallback))
+    pub ocr_required: bool,
+    /// Resolution of the page images, used to size the pages of the safe PDF
+    pub dpi: f32,
+    /// Maximum time the container may take to convert the document
+    pub timeout: Option<Duration>,
+    /// Container runtime running the conversion sandbox
+    pub runtime: Runtime,
+    /// Platform of the converter image to pull and run, such as
+    /// `linux/amd64`, instead of the one matching the host's architecture.
+    /// Images for another architecture run under emulation, if the runtime
+    /// has any. Ignored by bubblewrap and Apple's `container` runtime.
+    pub image_platform: Option<String>,
+    /// Start the runtime's virtual machine (macOS and Windows) if it is
+    /// stopped, instead of failing
+    pub auto_start_vm: bool,
+    /// Refuse to run a converter image from a release of Dangerzone older or
+    /// newer than this crate supports, instead of warning (see
+    /// [`image_version`](crate::image_version))
+    pub strict_image_version: bool,
+    /// Run the container through the REST API of podman or docker, on its
+    /// socket, instead of their command-line client (requires the
+    /// `runtime-api` feature). Ignored by bubblewrap and Apple's `container`
+    /// runtime.
+    pub runtime_api: bool,
+    /// Extra restrictions on the conversion container
+    pub container_hardening: ContainerHardening,
+    /// Variables to set in the sandbox, to tune the converter; only those
+    /// of [`CONTAINER_ENV_ALLOWLIST`](crate::CONTAINER_ENV_ALLOWLIST) are accepted
+    pub container_env: Vec<(String, String)>,
+    /// Log the command starting the sandbox, quoted for a shell, before
+    /// running it
+    pub print_command: bool,
+    /// If the sandbox fails, write a shell script starting it the same way
+    /// here, taking the document as its argument, to debug the runtime
+    pub repro_script: Option<PathBuf>,
+    /// Let each worker of a [`convert_batch`](crate::convert_batch) reuse one
+    /// sandbox for up to this many documents, instead of starting one per
+    /// document
+    pub session_max_documents: Option<usize>,
+    /// Template of the names of the safe PDFs of a
+    /// [`convert_batch`](crate::convert_batch), such as `{stem}-{date}-safe.pdf`,
+    /// instead of [`naming::DEFAULT_TEMPLATE`]; see [`naming`] for its
+    /// variables
+    pub output_template: Option<String>,
+    /// Maximum size of the pixel stream the container may write, and of the
+    /// pixels it inflates to; the conversion is aborted once it writes more
+    pub max_output_bytes: u64,
+    /// Ask the converter for per-page checksums, verified while reading the
+    /// pixel stream. Images that don't support them are still read.
+    pub page_checksums: bool,
+    /// Ask the converter to compress the pixels of each page, inflated while
+    /// reading the pixel stream. Images that don't support it send them raw.
+    pub page_compression: bool,
+    /// Keep the pixels of pages in memory only up to this many bytes, and
+    /// spool the following pages to an encrypted temporary file. The pixel
+    /// streams of [`ContainerSession`](crate::session::ContainerSession)s are always
+    /// kept in memory.
+    pub spool_threshold_bytes: Option<u64>,
+    /// Split the image of each page into horizontal strips of at most this
+    /// many bytes of pixels, each compressed and embedded as an image of its
+    /// own, so that neither the writer nor viewers need a buffer for the
+    /// whole image of a huge page
+    pub image_strip_bytes: Option<u64>,
+    /// Trade speed for memory, for long scans on small machines: pages are
+    /// parsed as they arrive and spooled to disk beyond a few MiB unless
+    /// [`spool_threshold_bytes`](Self::spool_threshold_bytes) is set, the
+    /// container runs one page ahead of the writer, pages are compressed one
+    /// at a time, and their images split in strips unless
+    /// [`image_strip_bytes`](Self::image_strip_bytes) is set
+    pub low_memory: bool,
+    /// Leave out blank pages: those the converter reports as blank, and those
+    /// whose pixels have a near-uniform luminance
+    pub drop_blank_pages: bool,
+    /// Turn every page clockwise by this many degrees (0, 90, 180 or 270),
+    /// on top of the rotation reported by the converter
+    pub rotation: u16,
+    /// Turn pages whose text tesseract detects as sideways or upside down
+    pub auto_orient: bool,
+    /// Resample pages with more pixels per inch than this before embedding
+    /// them, for smaller PDFs. Needs the `downscale` feature.
+    pub max_dpi: Option<f32>,
+    /// Cleanup applied to the pixels of each page
+    pub page_cleanup: PageCleanup,
+    /// Regions of pages blacked out in their pixels, before anything else
+    /// reads them
+    pub redactions: redact::Redactions,
+    /// Regular expressions, such as `\d{3}-\d{2}-\d{4}`, whose matches in
+    /// the text of each page are blacked out in its pixels. Pages are read
+    /// by an OCR engine reading pixels in [`ocr_lang`](Self::ocr_lang), even
+    /// without [`ocr`](Self::ocr), and the conversion fails if there is none.
+    /// Matches are listed in [`ConversionReport::redacted`](crate::ConversionReport::redacted).
+    pub redact_patterns: Vec<String>,
+    /// Compression of the pixels embedded in the safe PDF
+    pub compression: CompressionConfig,
+    /// Color space of the RGB pixels of pages, embedded in the safe PDF as
+    /// an ICC profile unless it is the device's
+    pub color_profile: ColorProfile,
+    /// Layout of the page images on the pages of the safe PDF
+    pub layout: PageLayout,
+    /// Write a PDF 1.5 whose objects other than streams are compressed in
+    /// object streams, with a cross-reference stream, rather than a PDF 1.4
+    /// that older readers also understand
+    pub object_streams: bool,
+    /// Rewrite the safe PDF once written so that viewers reading it over
+    /// HTTP show the first page before the rest arrives ("fast web view").
+    /// Linearized PDFs are written without object streams.
+    pub linearize: bool,
+    /// Add an outline (bookmarks) to the safe PDF, listing the headings
+    /// found in its text layer: short lines notably larger than the text
+    /// around them. Needs [`ocr`](Self::ocr) with an engine reading pixels;
+    /// the report warns when no heading was found.
+    pub outline: bool,
+    /// When the conversion fails after some pages were received from the
+    /// sandbox, write them to a partial safe PDF next to the output, named
+    /// `<name>.partial.pdf`, rather than discarding them. The conversion
+    /// still fails, with a [`PartialConversion`](crate::PartialConversion) telling where. Pages
+    /// spooled to disk are only written once all of them were received.
+    pub keep_partial: bool,
+    /// Record the file name of the original document, the time of the
+    /// conversion and the version of this crate in the document information
+    /// dictionary of the safe PDF, as its keywords. Off by default, as the
+    /// file name may reveal more than the safe PDF itself.
+    pub record_provenance: bool,
+    /// Start the safe PDF with a cover sheet stating where it comes from:
+    /// the file name and SHA-256 of the original document, when it was
+    /// converted, the versions of this crate and of the converter image, and
+    /// whether OCR was requested. Off by default, like
+    /// [`ConversionOptions::record_provenance`].
+    pub cover_sheet: bool,
+    /// Read the safe PDF back once written, and fail the conversion if its
+    /// structure is inconsistent (see [`validate_pdf`](crate::validate_pdf)). With OCR, the PDF
+    /// checked is the one the text layer is added to.
+    pub verify: bool,
+    /// Custom steps run on each page before it is written (see
+    /// [`stage`](crate::stage))
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub stages: Stages,
+}
+
+impl Default for ConversionOptions {
+    fn default() -> Self {
+        ConversionOptions {
+            ocr: false,
+            ocr_lang: "eng".to_string(),
+            ocr_sidecar: None,
+            ocrmypdf: OcrMyPdfOptions::default(),
+            ocr_timeout: None,
+            ocr_required: false,
+            dpi: DPI,
+            timeout: None,
+            runtime: Runtime::default(),
+            image_platform: None,
+            auto_start_vm: false,
+            strict_image_version: false,
+            runtime_api: false,
+            container_hardening: ContainerHardening::default(),
+            container_env: Vec::new(),
+            print_command: false,
+            repro_script: None,
+            session_max_documents: None,
+            output_template: None,
+            max_output_bytes: DEFAULT_MAX_OUTPUT_BYTES,
+            page_checksums: true,
+            page_compression: true,
+            spool_threshold_bytes: None,
+            image_strip_bytes: None,
+            low_memory: false,
+            drop_blank_pages: false,
+            rotation: 0,
+            auto_orient: false,
+            max_dpi: None,
+            page_cleanup: PageCleanup::default(),
+            redactions: redact::Redactions::default(),
+            redact_patterns: Vec::new(),
+            compression: CompressionConfig::default(),
+            color_profile: ColorProfile::default(),
+            layout: PageLayout::default(),
+            object_streams: false,
+            linearize: false,
+            outline: false,
+            keep_partial: false,
+            record_provenance: false,
+            cover_sheet: false,
+            verify: false,
+            stages: Stages::default(),
+        }
+    }
+}
+
+#[cfg(feature = "container")]
+impl ConversionOptions {
+    /// Parsed [`output_template`](Self::output_template), or the default one
+    #[cfg(feature = "container")]
+    pub fn parse_output_template(&self) -> Result<naming::OutputTemplate> {
+        self.output_template
+            .as_deref()
+            .map_or_else(|| Ok(Default::default()), naming::OutputTemplate::parse)
+    }
+
+    /// Bytes of pixels kept in memory before spooling the following pages
+    pub(crate) fn spool_threshold(&self) -> Option<u64> {
+        self.spool_threshold_bytes
+            .or(self.low_memory.then_some(LOW_MEMORY_SPOOL_BYTES))
+    }
+
+    /// Largest strip of the image of a page
+    pub(crate) fn image_strip(&self) -> Option<u64> {
+        self.image_strip_bytes
+            .or(self.low_memory.then_some(LOW_MEMORY_STRIP_BYTES))
+    }
+
+    /// Pages the container may convert ahead of the PDF writer
+    pub(crate) fn buffered_pages(&self) -> usize {
+        if self.low_memory {
+            1
+        } else {
+            PIPELINE_BUFFERED_PAGES
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ocr_sidecar_paths() {
+        assert_eq!("alto".parse::<OcrSidecar>().unwrap(), OcrSidecar::Alto);
+        assert_eq!(OcrSidecar::Hocr.to_string(), "hocr");
+        assert!("html".parse::<OcrSidecar>().is_err());
+
+        let pdf = Path::new("out/safe.pdf");
+        assert_eq!(OcrSidecar::Hocr.path_for(pdf), Path::new("out/safe.hocr"));
+        assert_eq!(
+            OcrSidecar::Alto.path_for(pdf),
+            Path::new("out/safe.alto.xml")
+        );
+    }
+}
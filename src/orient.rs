@@ -0,0 +1,261 @@
+//! Orientation of pages, set by the user or detected by tesseract
+//!
+//! Pages are turned with the PDF's `/Rotate` entry rather than by moving
+//! pixels around, so rotating doesn't cost anything and is lossless.
+
+use crate::{replace_control_chars, PageMetadata, PdfPage, PixelFormat};
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Confidence below which tesseract's orientation is ignored, as in ocrmypdf
+const MIN_CONFIDENCE: f32 = 14.0;
+
+/// Page turned clockwise by `rotation` degrees on top of its own rotation
+pub(crate) struct Oriented<P> {
+    pub(crate) page: P,
+    pub(crate) rotation: u16,
+}
+
+impl<P: PdfPage> PdfPage for Oriented<P> {
+    fn width(&self) -> u16 {
+        self.page.width()
+    }
+
+    fn height(&self) -> u16 {
+        self.page.height()
+    }
+
+    fn format(&self) -> PixelFormat {
+        self.page.format()
+    }
+
+    fn metadata(&self) -> PageMetadata {
+        let metadata = self.page.metadata();
+        PageMetadata {
+            rotation: (metadata.rotation + self.rotation) % 360,
+            ..metadata
+        }
+    }
+
+    fn write_pixels(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        self.page.write_pixels(out)
+    }
+}
+
+/// Check that `rotation` is a multiple of 90 degrees below 360
+pub(crate) fn check_rotation(rotation: u16) -> Result<()> {
+    if !rotation.is_multiple_of(90) || rotation >= 360 {
+        anyhow::bail!("Invalid rotation {rotation}: must be 0, 90, 180 or 270 degrees");
+    }
+    Ok(())
+}
+
+/// Orientation detection shared by the pages of a document, which may run
+/// on several threads at once
+pub(crate) struct OrientationDetector {
+    temp_dir: tempfile::TempDir,
+    /// Set once tesseract couldn't be run, so that other pages don't try
+    unavailable: AtomicBool,
+}
+
+impl OrientationDetector {
+    pub(crate) fn new() -> Result<Self> {
+        Ok(OrientationDetector {
+            temp_dir: crate::conversion_temp_dir()?,
+            unavailable: AtomicBool::new(false),
+        })
+    }
+
+    /// Clockwise rotation making the text of `page` upright, according to
+    /// tesseract's orientation detection
+    ///
+    /// Returns 0 if tesseract can't tell, isn't confident enough, or can't be
+    /// run at all.
+    pub(crate) fn detect<P: PdfPage>(&self, page: &P, page_num: usize) -> Result<u16> {
+        if self.unavailable.load(Ordering::Relaxed) {
+            return Ok(0);
+        }
+        let image_path = self
+            .temp_dir
+            .path()
+            .join(format!("orientation-{page_num}.pnm"));
+        write_pnm(page, &image_path)?;
+
+        let output = Command::new("tesseract")
+            .arg(&image_path)
+            .args(["stdout", "--psm", "0"])
+            .stdin(Stdio::null())
+            .output();
+        let _ = std::fs::remove_file(&image_path);
+        let output = match output {
+            Ok(output) => output,
+            Err(e) => {
+                if !self.unavailable.swap(true, Ordering::Relaxed) {
+                    warn!("tesseract not found or failed: {e}");
+                    info!("To detect the orientation of pages, install tesseract");
+                }
+                return Ok(0);
+            }
+        };
+        if !output.status.success() {
+            // Typically too little text to tell
+            debug!(
+                "Orientation of page {page_num} not detected: {stderr_sanitized}",
+                stderr_sanitized =
+                    replace_control_chars(&String::from_utf8_lossy(&output.stderr), true)
+            );
+            return Ok(0);
+        }
+
+        match parse_osd(&String::from_utf8_lossy(&output.stdout)) {
+            Some((rotation, confidence)) if confidence >= MIN_CONFIDENCE => {
+                if rotation != 0 {
+                    info!("Rotating page {page_num} by {rotation} degrees");
+                }
+                Ok(rotation)
+            }
+            Some((rotation, confidence)) => {
+                debug!(
+                    "Ignoring rotation {rotation} of page {page_num} (confidence {confidence:.2})"
+                );
+                Ok(0)
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+/// Rotation and confidence from tesseract's orientation and script
+/// detection output
+fn parse_osd(output: &str) -> Option<(u16, f32)> {
+    let mut rotation = None;
+    let mut confidence = None;
+    for line in output.lines() {
+        if let Some((key, value)) = line.split_once(':') {
+            match key.trim() {
+                "Rotate" => rotation = value.trim().parse().ok(),
+                "Orientation confidence" => confidence = value.trim().parse().ok(),
+                _ => {}
+            }
+        }
+    }
+    let rotation = rotation.filter(|rotation| check_rotation(*rotation).is_ok())?;
+    Some((rotation, confidence?))
+}
+
+/// Write the pixels of `page` as a binary PPM or PGM image, which tesseract
+/// reads without any image library. Transparent pixels are put over white.
+pub(crate) fn write_pnm<P: PdfPage>(page: &P, path: &Path) -> Result<()> {
+    let file = File::create(path).context("Failed to create page image for tesseract")?;
+    let mut out = BufWriter::new(file);
+    let magic = match page.format() {
+        PixelFormat::Gray => "P5",
+        PixelFormat::Rgb | PixelFormat::Rgba => "P6",
+    };
+    write!(out, "{magic}\n{} {}\n255\n", page.width(), page.height())?;
+    if page.format() == PixelFormat::Rgba {
+        page.write_pixels(&mut OverWhite::new(&mut out))?;
+    } else {
+        page.write_pixels(&mut out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Writer turning RGBA pixels into RGB ones over a white background
+pub(crate) struct OverWhite<W> {
+    out: W,
+    /// Bytes of a pixel split across writes
+    partial: Vec<u8>,
+}
+
+impl<W: Write> OverWhite<W> {
+    pub(crate) fn new(out: W) -> Self {
+        OverWhite {
+            out,
+            partial: Vec::with_capacity(4),
+        }
+    }
+}
+
+impl<W: Write> Write for OverWhite<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let blend = |pixel: &[u8]| {
+            let alpha = pixel[3] as u32;
+            [pixel[0], pixel[1], pixel[2]]
+                .map(|c| ((c as u32 * alpha + 255 * (255 - alpha)) / 255) as u8)
+        };
+        let mut rest = buf;
+        if !self.partial.is_empty() {
+            let take = (4 - self.partial.len()).min(rest.len());
+            self.partial.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+            if self.partial.len() < 4 {
+                return Ok(buf.len());
+            }
+            let pixel = std::mem::take(&mut self.partial);
+            self.out.write_all(&blend(&pixel))?;
+        }
+
+        let mut pixels = rest.chunks_exact(4);
+        let rgb: Vec<u8> = (&mut pixels).flat_map(blend).collect();
+        self.out.write_all(&rgb)?;
+        self.partial.extend_from_slice(pixels.remainder());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PageData;
+
+    #[test]
+    fn test_parse_osd() {
+        let output = "Page number: 0\n\
+                      Orientation in degrees: 270\n\
+                      Rotate: 90\n\
+                      Orientation confidence: 21.33\n\
+                      Script: Latin\n\
+                      Script confidence: 8.10\n";
+        assert_eq!(parse_osd(output), Some((90, 21.33)));
+        assert_eq!(parse_osd("Rotate: 45\nOrientation confidence: 20\n"), None);
+        assert_eq!(parse_osd(""), None);
+    }
+
+    #[test]
+    fn test_oriented_page() {
+        let mut page = PageData::new(1, 1, vec![0, 0, 0]);
+        page.metadata.rotation = 270;
+        let oriented = Oriented {
+            page: &page,
+            rotation: 180,
+        };
+        assert_eq!(oriented.metadata().rotation, 90);
+        assert!(check_rotation(270).is_ok());
+        assert!(check_rotation(360).is_err());
+        assert!(check_rotation(45).is_err());
+    }
+
+    #[test]
+    fn test_pnm_over_white() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("page.pnm");
+        let page =
+            PageData::with_format(2, 1, PixelFormat::Rgba, vec![0, 0, 0, 0, 10, 20, 30, 255]);
+        write_pnm(&page, &path).unwrap();
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            b"P6\n2 1\n255\n\xff\xff\xff\x0a\x14\x1e"
+        );
+    }
+}
@@ -0,0 +1,286 @@
+//! Outline (bookmarks) of safe PDFs, from the headings OCR finds
+//!
+//! The safe PDF has no structure left, only images and their text layer, so
+//! headings are told apart by their size: a short line, alone in its
+//! paragraph, at least [`HEADING_RATIO`] times as high as the body text of
+//! its page. Those at least [`TITLE_RATIO`] times as high are the top level
+//! of the outline, and the others are nested under the last of them.
+
+use crate::hocr::OcrPage;
+use crate::pdf_text_string;
+
+/// Height of a heading, relative to the body text of its page
+const HEADING_RATIO: f32 = 1.3;
+
+/// Height of a top-level heading, relative to the body text of its page
+const TITLE_RATIO: f32 = 1.8;
+
+/// Most words in a heading
+const MAX_HEADING_WORDS: usize = 12;
+
+/// Fewest lines of body text on a page for its headings to be found
+const MIN_BODY_LINES: usize = 3;
+
+/// Heading found on a page
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Heading {
+    pub(crate) title: String,
+    /// 1 for top-level headings, 2 for the others
+    pub(crate) level: u8,
+    /// Top of the heading, in pixels from the top of the page
+    pub(crate) top: u32,
+}
+
+/// Headings of a page, from the words OCR found on it, in reading order
+pub(crate) fn headings(text: &OcrPage) -> Vec<Heading> {
+    let paragraphs = || {
+        text.blocks
+            .iter()
+            .flat_map(|block| &block.paragraphs)
+            .filter(|paragraph| !paragraph.lines.is_empty())
+    };
+    let height = |bbox: [u32; 4]| bbox[3].saturating_sub(bbox[1]);
+
+    // The body text is that of most words
+    let mut heights: Vec<(u32, usize)> = paragraphs()
+        .flat_map(|paragraph| &paragraph.lines)
+        .map(|line| (height(line.bbox), line.words.len()))
+        .filter(|&(height, words)| height > 0 && words > 0)
+        .collect();
+    if heights.len() < MIN_BODY_LINES {
+        return Vec::new();
+    }
+    heights.sort_unstable();
+    let total_words: usize = heights.iter().map(|&(_, words)| words).sum();
+    let mut counted = 0;
+    let body = heights
+        .iter()
+        .find(|&&(_, words)| {
+            counted += words;
+            2 * counted >= total_words
+        })
+        .map_or(0, |&(height, _)| height) as f32;
+
+    paragraphs()
+        .filter(|paragraph| paragraph.lines.len() == 1)
+        .filter_map(|paragraph| {
+            let line = &paragraph.lines[0];
+            let words: Vec<&str> = line
+                .words
+                .iter()
+                .map(|word| word.text.trim())
+                .filter(|word| !word.is_empty())
+                .collect();
+            let ratio = height(line.bbox) as f32 / body;
+            let is_heading = ratio >= HEADING_RATIO
+                && !words.is_empty()
+                && words.len() <= MAX_HEADING_WORDS
+                && words
+                    .iter()
+                    .any(|word| word.chars().any(char::is_alphabetic));
+            is_heading.then(|| Heading {
+                title: words.join(" "),
+                level: if ratio >= TITLE_RATIO { 1 } else { 2 },
+                top: line.bbox[1],
+            })
+        })
+        .collect()
+}
+
+/// Entry of the outline, pointing at a page of the PDF
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Bookmark {
+    pub(crate) title: String,
+    pub(crate) level: u8,
+    /// Index of the page in the page tree
+    pub(crate) page: usize,
+    /// Top of the heading, in points from the bottom of the page, or `None`
+    /// to show the whole page
+    pub(crate) top: Option<f32>,
+}
+
+/// Dictionaries of the outline of `bookmarks`: the outline's own, numbered
+/// `root_obj_num`, then that of each bookmark, numbered from
+/// `first_obj_num`, in order
+pub(crate) fn objects(
+    bookmarks: &[Bookmark],
+    root_obj_num: usize,
+    first_obj_num: usize,
+    page_obj_nums: &[usize],
+) -> Vec<String> {
+    // Bookmarks below the lowest level nest under the last one above them
+    let mut parents = Vec::with_capacity(bookmarks.len());
+    let mut last_top_level = None;
+    for (index, bookmark) in bookmarks.iter().enumerate() {
+        if bookmark.level <= 1 {
+            parents.push(None);
+            last_top_level = Some(index);
+        } else {
+            parents.push(last_top_level);
+        }
+    }
+    let children = |parent: Option<usize>| -> Vec<usize> {
+        (0..bookmarks.len())
+            .filter(|&index| parents[index] == parent)
+            .collect()
+    };
+    let obj_num = |index: usize| first_obj_num + index;
+    // `count` is the number of visible descendants
+    let first_last = |children: &[usize], count: usize| match (children.first(), children.last()) {
+        (Some(&first), Some(&last)) => format!(
+            "/First {} 0 R\n/Last {} 0 R\n/Count {count}\n",
+            obj_num(first),
+            obj_num(last),
+        ),
+        _ => String::new(),
+    };
+
+    // Every bookmark is visible, the top-level ones being open
+    let top_level = children(None);
+    let mut objects = Vec::with_capacity(bookmarks.len() + 1);
+    objects.push(match top_level.is_empty() {
+        true => "<<\n/Type /Outlines\n/Count 0\n>>\n".to_string(),
+        false => format!(
+            "<<\n/Type /Outlines\n{}>>\n",
+            first_last(&top_level, bookmarks.len())
+        ),
+    });
+    for (index, bookmark) in bookmarks.iter().enumerate() {
+        let siblings = children(parents[index]);
+        let position = siblings
+            .iter()
+            .position(|&sibling| sibling == index)
+            .expect("a bookmark is among its parent's children");
+        let mut object = format!("<<\n/Title {}\n", pdf_text_string(&bookmark.title));
+        let parent = parents[index].map_or(root_obj_num, obj_num);
+        object.push_str(&format!("/Parent {parent} 0 R\n"));
+        if let Some(&prev) = position.checked_sub(1).and_then(|p| siblings.get(p)) {
+            object.push_str(&format!("/Prev {} 0 R\n", obj_num(prev)));
+        }
+        if let Some(&next) = siblings.get(position + 1) {
+            object.push_str(&format!("/Next {} 0 R\n", obj_num(next)));
+        }
+        let kids = children(Some(index));
+        object.push_str(&first_last(&kids, kids.len()));
+        let page = page_obj_nums[bookmark.page];
+        match bookmark.top {
+            Some(top) => object.push_str(&format!("/Dest [{page} 0 R /XYZ null {top:.2} null]\n")),
+            None => object.push_str(&format!("/Dest [{page} 0 R /Fit]\n")),
+        }
+        object.push_str(">>\n");
+        objects.push(object);
+    }
+    objects
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hocr::{OcrBlock, OcrLine, OcrParagraph, OcrWord};
+
+    fn paragraph(top: u32, height: u32, words: &[&str]) -> OcrParagraph {
+        let bbox = [10, top, 500, top + height];
+        OcrParagraph {
+            bbox,
+            lang: None,
+            lines: vec![OcrLine {
+                bbox,
+                baseline: None,
+                words: words
+                    .iter()
+                    .map(|text| OcrWord {
+                        bbox,
+                        confidence: None,
+                        text: text.to_string(),
+                    })
+                    .collect(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_headings() {
+        let body = ["the", "body", "text", "of", "the", "page"];
+        let text = OcrPage {
+            bbox: [0, 0, 600, 800],
+            blocks: vec![OcrBlock {
+                bbox: [0, 0, 600, 800],
+                paragraphs: vec![
+                    paragraph(10, 40, &["Annual", "report"]),
+                    paragraph(60, 10, &body),
+                    paragraph(80, 14, &["1.", "Results"]),
+                    paragraph(100, 10, &body),
+                    paragraph(120, 10, &body),
+                    // Large, but no words
+                    paragraph(140, 30, &["42"]),
+                ],
+            }],
+        };
+        assert_eq!(
+            headings(&text),
+            [
+                Heading {
+                    title: "Annual report".to_string(),
+                    level: 1,
+                    top: 10
+                },
+                Heading {
+                    title: "1. Results".to_string(),
+                    level: 2,
+                    top: 80
+                },
+            ]
+        );
+
+        // Too little text to tell the body apart
+        let text = OcrPage {
+            blocks: vec![OcrBlock {
+                paragraphs: vec![paragraph(10, 40, &["Title"]), paragraph(60, 10, &body)],
+                ..OcrBlock::default()
+            }],
+            ..OcrPage::default()
+        };
+        assert!(headings(&text).is_empty());
+    }
+
+    #[test]
+    fn test_objects() {
+        let bookmark = |title: &str, level, page, top| Bookmark {
+            title: title.to_string(),
+            level,
+            page,
+            top,
+        };
+        let bookmarks = [
+            bookmark("Intro", 2, 0, Some(700.0)),
+            bookmark("Part 1", 1, 0, Some(500.0)),
+            bookmark("Section", 2, 1, None),
+            bookmark("Part 2", 1, 1, Some(300.5)),
+        ];
+        let objects = objects(&bookmarks, 3, 10, &[4, 8]);
+        assert_eq!(
+            objects[0],
+            "<<\n/Type /Outlines\n/First 10 0 R\n/Last 13 0 R\n/Count 4\n>>\n"
+        );
+        assert_eq!(
+            objects[1],
+            "<<\n/Title (Intro)\n/Parent 3 0 R\n/Next 11 0 R\n\
+             /Dest [4 0 R /XYZ null 700.00 null]\n>>\n"
+        );
+        assert_eq!(
+            objects[2],
+            "<<\n/Title (Part 1)\n/Parent 3 0 R\n/Prev 10 0 R\n/Next 13 0 R\n\
+             /First 12 0 R\n/Last 12 0 R\n/Count 1\n/Dest [4 0 R /XYZ null 500.00 null]\n>>\n"
+        );
+        assert_eq!(
+            objects[3],
+            "<<\n/Title (Section)\n/Parent 11 0 R\n/Dest [8 0 R /Fit]\n>>\n"
+        );
+        assert_eq!(objects.len(), 5);
+
+        assert_eq!(
+            super::objects(&[], 3, 4, &[]),
+            ["<<\n/Type /Outlines\n/Count 0\n>>\n"]
+        );
+    }
+}
@@ -0,0 +1,1680 @@
+//! Writing and checking of safe PDFs
+//!
+//! [`write_pdf`] writes the pages it is given one after the other, each as
+//! images compressed on their own, and nothing else that a reader could run
+//! or fetch.
+
+use crate::{
+    compression, hocr, layout, outline, replace_control_chars, stamp, text_layer, validate,
+    CompressionConfig, PageData, PageLayout, PageMetadata, PixelFormat, Progress, DPI,
+};
+use anyhow::{Context, Result};
+use log::debug;
+use std::fs::File;
+use std::io::{BufReader, Write};
+#[cfg(feature = "container")]
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Check the structure of the PDF at `path`: that its cross-reference table
+/// points at each object, that each stream ends where its length says, and
+/// that its page tree is consistent and every page has a valid MediaBox
+///
+/// Only PDFs with a single cross-reference table or stream, such as those
+/// written by this crate, can be checked; incrementally updated PDFs are
+/// rejected.
+pub fn validate_pdf(path: &str) -> Result<()> {
+    let path_sanitized = replace_control_chars(path, false);
+    let file = File::open(path).context(format!("Failed to open '{path_sanitized}'"))?;
+    validate::validate(&mut BufReader::new(file)).context(format!("Invalid PDF '{path_sanitized}'"))
+}
+
+/// Check that the PDF at `path` only holds what the safe PDFs of this crate
+/// hold: pages of images, and fonts for their text layer
+///
+/// Scripts, actions, attached files, forms, annotations, and object types
+/// the writer never uses fail the check. Every conversion audits the PDF it
+/// writes, as a defense against bugs of the writer; this function makes the
+/// same check available for PDFs written earlier. Like [`validate_pdf`], it
+/// needs a single cross-reference table or stream.
+pub fn audit_pdf(path: &str) -> Result<()> {
+    let path_sanitized = replace_control_chars(path, false);
+    let file = File::open(path).context(format!("Failed to open '{path_sanitized}'"))?;
+    validate::audit(&mut BufReader::new(file)).context(format!(
+        "PDF '{path_sanitized}' failed the sanitization audit"
+    ))
+}
+
+/// Write a minimal PDF file with embedded RGB pixel data
+pub fn write_pdf<W: Write>(writer: &mut W, pages: &[PageData]) -> Result<()> {
+    write_pdf_with_progress(writer, pages, DPI, &|_| {})
+}
+
+pub(crate) fn write_pdf_with_progress<W: Write, P: PdfPage>(
+    writer: &mut W,
+    pages: &[P],
+    dpi: f32,
+    progress: &dyn Fn(Progress),
+) -> Result<()> {
+    // Written as it is generated, so that only one compressed page is held
+    // in memory at a time
+    let mut pdf = PdfWriter::new(writer, WriterSettings::default())?;
+    for (page_idx, page) in pages.iter().enumerate() {
+        debug!("Adding page {} to PDF...", page_idx + 1);
+        progress(Progress::WritingPage {
+            page: page_idx + 1,
+            total_pages: pages.len(),
+        });
+        pdf.add_page(&EncodedPage::new(page, dpi, &CompressionConfig::default())?)?;
+    }
+    pdf.finish()?;
+    Ok(())
+}
+
+/// Page whose pixels are compressed, ready to be written to a PDF
+pub(crate) struct EncodedPage {
+    pub(crate) width: u16,
+    pub(crate) height: u16,
+    pub(crate) format: PixelFormat,
+    rotation: u16,
+    pub(crate) size_pts: (f32, f32),
+    /// Image of the page, from top to bottom, in a single strip unless it
+    /// was split
+    strips: Vec<ImageStrip>,
+    /// Words found by OCR, written as an invisible text layer
+    pub(crate) text: Option<hocr::OcrPage>,
+}
+
+/// Rows of the image of an [`EncodedPage`], compressed, each written as an
+/// image of its own
+pub(crate) struct ImageStrip {
+    rows: u16,
+    pixels: Vec<u8>,
+    /// Alpha channel of RGBA pages
+    alpha: Option<Vec<u8>>,
+}
+
+impl EncodedPage {
+    pub(crate) fn new<P: PdfPage>(
+        page: &P,
+        dpi: f32,
+        compression: &CompressionConfig,
+    ) -> Result<Self> {
+        Self::in_strips(page, dpi, compression, None)
+    }
+
+    /// Compress a page whose image is split into strips of at most
+    /// `strip_bytes` of pixels, if given
+    pub(crate) fn in_strips<P: PdfPage>(
+        page: &P,
+        dpi: f32,
+        compression: &CompressionConfig,
+        strip_bytes: Option<u64>,
+    ) -> Result<Self> {
+        let row_bytes = page.width() as u64 * page.format().bytes_per_pixel() as u64;
+        let strip_rows = match strip_bytes {
+            Some(bytes) => (bytes / row_bytes.max(1)).clamp(1, u16::MAX.into()) as u16,
+            None => u16::MAX,
+        };
+        Ok(EncodedPage {
+            width: page.width(),
+            height: page.height(),
+            format: page.format(),
+            rotation: page.metadata().rotation,
+            size_pts: page_size_pts(page, dpi),
+            strips: compress_pixels(page, compression, strip_rows)?,
+            text: None,
+        })
+    }
+}
+
+/// Settings of a [`PdfWriter`], from the conversion options
+#[derive(Default)]
+pub(crate) struct WriterSettings {
+    pub(crate) layout: PageLayout,
+    /// ICC profile of the RGB images, if they aren't device RGB
+    pub(crate) icc_profile: Option<Vec<u8>>,
+    /// Write a PDF 1.5, with compressed object streams and a cross-reference
+    /// stream
+    pub(crate) object_streams: bool,
+    /// Recorded in the document information dictionary
+    pub(crate) provenance: Option<Provenance>,
+    /// Add an outline of the headings found in the text layers of the pages
+    pub(crate) outline: bool,
+}
+
+/// Where a safe PDF comes from, for [`ConversionOptions::record_provenance`]
+pub(crate) struct Provenance {
+    /// File name of the original document, if the pixels come from one
+    original_name: Option<String>,
+    converted: SystemTime,
+}
+
+impl Provenance {
+    /// Provenance of a conversion of the document at `original` happening now
+    #[cfg(feature = "container")]
+    pub(crate) fn new(original: Option<&str>) -> Self {
+        Provenance {
+            original_name: original
+                .and_then(|path| Path::new(path).file_name())
+                .map(|name| replace_control_chars(&name.to_string_lossy(), false)),
+            converted: SystemTime::now(),
+        }
+    }
+
+    /// Document information dictionary
+    fn info(&self) -> String {
+        let UtcTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        } = self.converted.into();
+        let producer = concat!("dangerzone-rs ", env!("CARGO_PKG_VERSION"));
+        let mut keywords = Vec::new();
+        if let Some(name) = &self.original_name {
+            keywords.push(format!("original: {name}"));
+        }
+        keywords.push(format!(
+            "converted: {year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z"
+        ));
+        keywords.push(producer.to_string());
+        format!(
+            "<<\n/Keywords {}\n/Producer {}\n/CreationDate (D:{year:04}{month:02}{day:02}{hour:02}{minute:02}{second:02}Z)\n>>\n",
+            pdf_text_string(&keywords.join("; ")),
+            pdf_text_string(producer),
+        )
+    }
+}
+
+/// Date and time of day in UTC, to the second
+pub(crate) struct UtcTime {
+    pub(crate) year: i64,
+    pub(crate) month: i64,
+    pub(crate) day: i64,
+    pub(crate) hour: u64,
+    pub(crate) minute: u64,
+    pub(crate) second: u64,
+}
+
+impl From<SystemTime> for UtcTime {
+    fn from(time: SystemTime) -> Self {
+        let secs = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let (days, secs) = (secs / 86400, secs % 86400);
+        // Civil date of a day count, after Howard Hinnant's `civil_from_days`
+        let z = days as i64 + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        UtcTime {
+            year: yoe + era * 400 + i64::from(month <= 2),
+            month,
+            day,
+            hour: secs / 3600,
+            minute: secs / 60 % 60,
+            second: secs % 60,
+        }
+    }
+}
+
+/// `text` as a PDF string: literal if it is printable ASCII, or else in
+/// UTF-16BE with a byte order mark, in hexadecimal
+pub(crate) fn pdf_text_string(text: &str) -> String {
+    if text.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        let mut string = String::from("(");
+        for c in text.chars() {
+            if matches!(c, '(' | ')' | '\\') {
+                string.push('\\');
+            }
+            string.push(c);
+        }
+        string.push(')');
+        return string;
+    }
+    let mut string = String::from("<FEFF");
+    for unit in text.encode_utf16() {
+        string.push_str(&format!("{unit:04X}"));
+    }
+    string.push('>');
+    string
+}
+
+/// Where an object of a [`PdfWriter`] is in the file
+#[derive(Clone, Copy)]
+enum ObjectLocation {
+    /// Offset of the object, or 0 until an object reserved in advance is
+    /// written
+    Offset(usize),
+    /// Index of the object in the object stream numbered `stream`, or the
+    /// object stream still being filled
+    Compressed { stream: usize, index: usize },
+}
+
+/// PDF written one page at a time
+///
+/// Objects are numbered in the order they are written, and the page tree,
+/// which lists every page, comes last, so the number of pages doesn't need
+/// to be known in advance. With object streams, objects other than streams
+/// are gathered and compressed in groups of [`PdfWriter::OBJECTS_PER_STREAM`].
+pub(crate) struct PdfWriter<W: Write> {
+    out: CountingWriter<W>,
+    /// Location of each object, by object number minus one
+    objects: Vec<ObjectLocation>,
+    /// Object number and content of the objects of the object stream still
+    /// being filled
+    pending_objects: Vec<(usize, String)>,
+    object_streams: bool,
+    page_obj_nums: Vec<usize>,
+    /// Font of the text layers, written with the first page that has one
+    text_font_obj_num: Option<usize>,
+    layout: PageLayout,
+    /// Sheet still being filled, with several pages per sheet
+    sheet: Option<PendingSheet>,
+    /// Number of pages added, which is that of the pages written unless
+    /// there are several per sheet
+    pages_added: usize,
+    /// Font of page numbers, written with the first page
+    stamp_font_obj_num: Option<usize>,
+    /// Object number, size and rotation of the stamp of each page, whose
+    /// content is written by [`PdfWriter::finish`] once the number of pages
+    /// is known
+    stamps: Vec<(usize, (f32, f32), u16)>,
+    /// ICC profile of the RGB images, written with the first of them
+    icc_profile: Option<Vec<u8>>,
+    icc_profile_obj_num: Option<usize>,
+    provenance: Option<Provenance>,
+    /// Document information dictionary, written by [`PdfWriter::finish`]
+    info_obj_num: Option<usize>,
+    /// Bookmarks of the headings found so far, if the PDF has an outline
+    outline: Option<Vec<outline::Bookmark>>,
+}
+
+/// Sheet of several pages whose last cells are still empty
+struct PendingSheet {
+    sheet: layout::Sheet,
+    content: String,
+    /// Resource names and object numbers of the images of each page
+    images: Vec<Vec<(String, usize)>>,
+    font_obj_num: Option<usize>,
+}
+
+impl<W: Write> PdfWriter<W> {
+    /// Object number of the page tree, written by [`PdfWriter::finish`]
+    const PAGES_OBJ_NUM: usize = 2;
+
+    /// Object number of the outline, if any, written by [`PdfWriter::finish`]
+    const OUTLINES_OBJ_NUM: usize = 3;
+
+    /// Objects compressed together in an object stream
+    const OBJECTS_PER_STREAM: usize = 100;
+
+    pub(crate) fn new(writer: W, settings: WriterSettings) -> Result<Self> {
+        let mut pdf = PdfWriter {
+            out: CountingWriter { writer, written: 0 },
+            objects: Vec::new(),
+            pending_objects: Vec::new(),
+            object_streams: settings.object_streams,
+            page_obj_nums: Vec::new(),
+            text_font_obj_num: None,
+            layout: settings.layout,
+            sheet: None,
+            pages_added: 0,
+            stamp_font_obj_num: None,
+            stamps: Vec::new(),
+            icc_profile: settings.icc_profile,
+            icc_profile_obj_num: None,
+            provenance: settings.provenance,
+            info_obj_num: None,
+            outline: settings.outline.then(Vec::new),
+        };
+
+        // PDF Header
+        if pdf.object_streams {
+            pdf.out.write_all(b"%PDF-1.5\n")?;
+        } else {
+            pdf.out.write_all(b"%PDF-1.4\n")?;
+        }
+        pdf.out.write_all(b"%\xE2\xE3\xCF\xD3\n")?;
+
+        // Object 1: Catalog
+        let outline = match pdf.outline {
+            Some(_) => format!(
+                "/Outlines {} 0 R\n/PageMode /UseOutlines\n",
+                Self::OUTLINES_OBJ_NUM
+            ),
+            None => String::new(),
+        };
+        pdf.write_object(format!(
+            "<<\n/Type /Catalog\n/Pages {} 0 R\n{outline}>>\n",
+            Self::PAGES_OBJ_NUM
+        ))?;
+
+        // Object 2: Pages, and object 3: the outline, written last
+        pdf.reserve_object();
+        if pdf.outline.is_some() {
+            pdf.reserve_object();
+        }
+        Ok(pdf)
+    }
+
+    /// Start the next object, returning its number
+    fn start_object(&mut self) -> Result<usize> {
+        self.objects.push(ObjectLocation::Offset(self.out.written));
+        let obj_num = self.objects.len();
+        self.out
+            .write_all(format!("{obj_num} 0 obj\n").as_bytes())?;
+        Ok(obj_num)
+    }
+
+    /// Number the next object, to be written later by
+    /// [`PdfWriter::write_reserved_object`] or as a stream
+    fn reserve_object(&mut self) -> usize {
+        self.objects.push(ObjectLocation::Offset(0));
+        self.objects.len()
+    }
+
+    /// Start the object `obj_num`, reserved earlier
+    fn start_reserved_object(&mut self, obj_num: usize) -> Result<()> {
+        self.objects[obj_num - 1] = ObjectLocation::Offset(self.out.written);
+        self.out
+            .write_all(format!("{obj_num} 0 obj\n").as_bytes())?;
+        Ok(())
+    }
+
+    /// Write an object other than a stream, returning its number
+    fn write_object(&mut self, object: String) -> Result<usize> {
+        let obj_num = self.reserve_object();
+        self.write_reserved_object(obj_num, object)?;
+        Ok(obj_num)
+    }
+
+    /// Write the object `obj_num`, reserved earlier, which isn't a stream
+    fn write_reserved_object(&mut self, obj_num: usize, object: String) -> Result<()> {
+        if !self.object_streams {
+            self.start_reserved_object(obj_num)?;
+            self.out.write_all(object.as_bytes())?;
+            self.out.write_all(b"endobj\n")?;
+            return Ok(());
+        }
+        self.objects[obj_num - 1] = ObjectLocation::Compressed {
+            stream: 0,
+            index: self.pending_objects.len(),
+        };
+        self.pending_objects.push((obj_num, object));
+        if self.pending_objects.len() == Self::OBJECTS_PER_STREAM {
+            self.write_object_stream()?;
+        }
+        Ok(())
+    }
+
+    /// Write the pending objects as an object stream
+    fn write_object_stream(&mut self) -> Result<()> {
+        if self.pending_objects.is_empty() {
+            return Ok(());
+        }
+        let pending = std::mem::take(&mut self.pending_objects);
+        let mut header = String::new();
+        let mut objects = String::new();
+        for (obj_num, object) in &pending {
+            header.push_str(&format!("{obj_num} {} ", objects.len()));
+            objects.push_str(object);
+        }
+        header.push('\n');
+        let mut encoder = compression::Encoder::new(&CompressionConfig::default())?;
+        encoder.write_all(header.as_bytes())?;
+        encoder.write_all(objects.as_bytes())?;
+        let data = encoder.finish()?;
+
+        let stream_obj_num = self.start_object()?;
+        self.out.write_all(b"<<\n")?;
+        self.out.write_all(b"/Type /ObjStm\n")?;
+        self.out
+            .write_all(format!("/N {}\n", pending.len()).as_bytes())?;
+        self.out
+            .write_all(format!("/First {}\n", header.len()).as_bytes())?;
+        self.out.write_all(b"/Filter /FlateDecode\n")?;
+        self.out
+            .write_all(format!("/Length {}\n", data.len()).as_bytes())?;
+        self.out.write_all(b">>\n")?;
+        self.out.write_all(b"stream\n")?;
+        self.out.write_all(&data)?;
+        self.out.write_all(b"\nendstream\n")?;
+        self.out.write_all(b"endobj\n")?;
+
+        for (index, (obj_num, _)) in pending.iter().enumerate() {
+            self.objects[obj_num - 1] = ObjectLocation::Compressed {
+                stream: stream_obj_num,
+                index,
+            };
+        }
+        Ok(())
+    }
+
+    /// Write an image XObject of `width` by `height` pixels, returning its
+    /// object number
+    fn write_image(
+        &mut self,
+        (width, height): (u16, u16),
+        color_space: &str,
+        data: &[u8],
+        mask_obj_num: Option<usize>,
+    ) -> Result<usize> {
+        let obj_num = self.start_object()?;
+        self.out.write_all(b"<<\n")?;
+        self.out.write_all(b"/Type /XObject\n")?;
+        self.out.write_all(b"/Subtype /Image\n")?;
+        self.out.write_all(format!("/Width {width}\n").as_bytes())?;
+        self.out
+            .write_all(format!("/Height {height}\n").as_bytes())?;
+        self.out
+            .write_all(format!("/ColorSpace {color_space}\n").as_bytes())?;
+        self.out.write_all(b"/BitsPerComponent 8\n")?;
+        if let Some(mask_obj_num) = mask_obj_num {
+            self.out
+                .write_all(format!("/SMask {mask_obj_num} 0 R\n").as_bytes())?;
+        }
+        self.out.write_all(b"/Filter /FlateDecode\n")?;
+        self.out
+            .write_all(format!("/Length {}\n", data.len()).as_bytes())?;
+        self.out.write_all(b">>\n")?;
+        self.out.write_all(b"stream\n")?;
+        self.out.write_all(data)?;
+        self.out.write_all(b"\nendstream\n")?;
+        self.out.write_all(b"endobj\n")?;
+        Ok(obj_num)
+    }
+
+    /// Write the image of a page, one image per strip, returning their
+    /// object numbers
+    fn write_strips(&mut self, page: &EncodedPage) -> Result<Vec<usize>> {
+        let mut obj_nums = Vec::with_capacity(page.strips.len());
+        for strip in &page.strips {
+            let size = (page.width, strip.rows);
+            // The alpha channel of RGBA pages is a separate image, referenced
+            // as the soft mask of the page's image
+            let mask_obj_num = match &strip.alpha {
+                Some(alpha) => Some(self.write_image(size, "/DeviceGray", alpha, None)?),
+                None => None,
+            };
+            let color_space = match page.format {
+                PixelFormat::Gray => "/DeviceGray".to_string(),
+                PixelFormat::Rgb | PixelFormat::Rgba => match self.icc_profile()? {
+                    Some(obj_num) => format!("[/ICCBased {obj_num} 0 R]"),
+                    None => "/DeviceRGB".to_string(),
+                },
+            };
+            obj_nums.push(self.write_image(size, &color_space, &strip.pixels, mask_obj_num)?);
+        }
+        Ok(obj_nums)
+    }
+
+    /// Write the image, content stream and page objects of a page, or with
+    /// several pages per sheet, add the page to the current sheet
+    pub(crate) fn add_page(&mut self, page: &EncodedPage) -> Result<()> {
+        let page_idx = self.page_obj_nums.len();
+        self.pages_added += 1;
+
+        let image_obj_nums = self.write_strips(page)?;
+        if self.layout.nup > 1 {
+            // Bookmarks show the whole sheet
+            self.add_bookmarks(page, |_| None);
+            return self.add_to_sheet(page, &image_obj_nums);
+        }
+
+        let placement = layout::place(page.size_pts, &self.layout);
+        let scale_y = placement.size.1 / page.height.max(1) as f32;
+        self.add_bookmarks(page, |top| {
+            (page.rotation == 0)
+                .then_some(placement.origin.1 + placement.size.1 - top as f32 * scale_y)
+        });
+        let (width_pts, height_pts) = placement.size;
+        let origin = match placement.origin {
+            (0.0, 0.0) => "0 0".to_string(),
+            (x, y) => format!("{x:.2} {y:.2}"),
+        };
+
+        // Content stream
+        let mut content = format!(
+            "q\n{width_pts:.2} 0 0 {height_pts:.2} {origin} cm\n{}Q\n",
+            draw_image(page_idx, page)
+        );
+        let font_obj_num = match &page.text {
+            Some(text) => {
+                // Words are positioned from the lower-left corner of the image
+                let text = text_layer::content(text, (page.width, page.height), placement.size);
+                if origin == "0 0" {
+                    content.push_str(&text);
+                } else {
+                    content.push_str(&format!("q\n1 0 0 1 {origin} cm\n{text}Q\n"));
+                }
+                Some(self.text_font()?)
+            }
+            None => None,
+        };
+        self.write_page(
+            &content,
+            placement.sheet,
+            page.rotation,
+            &image_resources(page_idx, &image_obj_nums),
+            font_obj_num,
+        )
+    }
+
+    /// Draw a page, whose image strips are objects `image_obj_nums`, in the
+    /// next cell of the current sheet, and write the sheet once it is full
+    fn add_to_sheet(&mut self, page: &EncodedPage, image_obj_nums: &[usize]) -> Result<()> {
+        let font_obj_num = match page.text {
+            Some(_) => Some(self.text_font()?),
+            None => None,
+        };
+        let (width, height) = page.size_pts;
+        let shown = match page.rotation {
+            90 | 270 => (height, width),
+            _ => (width, height),
+        };
+        let sheet = self.sheet.get_or_insert_with(|| PendingSheet {
+            sheet: layout::Sheet::new(shown, &self.layout),
+            content: String::new(),
+            images: Vec::new(),
+            font_obj_num: None,
+        });
+        let cell = sheet.images.len();
+        let ((x, y), scale) = sheet.sheet.fit(cell, shown);
+
+        // The page can't have a /Rotate of its own: turn it clockwise as
+        // /Rotate would, then scale it and move it to its cell
+        let (a, b, c, d, e, f) = match page.rotation {
+            90 => (0.0, -1.0, 1.0, 0.0, 0.0, width),
+            180 => (-1.0, 0.0, 0.0, -1.0, width, height),
+            270 => (0.0, 1.0, -1.0, 0.0, height, 0.0),
+            _ => (1.0, 0.0, 0.0, 1.0, 0.0, 0.0),
+        };
+        sheet.content.push_str(&format!(
+            "q\n{:.4} {:.4} {:.4} {:.4} {:.2} {:.2} cm\n\
+             q\n{width:.2} 0 0 {height:.2} 0 0 cm\n{}Q\n",
+            a * scale,
+            b * scale,
+            c * scale,
+            d * scale,
+            e * scale + x,
+            f * scale + y,
+            draw_image(cell, page),
+        ));
+        if let Some(text) = &page.text {
+            sheet.content.push_str(&text_layer::content(
+                text,
+                (page.width, page.height),
+                page.size_pts,
+            ));
+        }
+        sheet.content.push_str("Q\n");
+        sheet.images.push(image_resources(cell, image_obj_nums));
+        sheet.font_obj_num = sheet.font_obj_num.or(font_obj_num);
+
+        if sheet.images.len() == sheet.sheet.capacity() {
+            self.write_sheet()?;
+        }
+        Ok(())
+    }
+
+    /// Write the current sheet, if it has any page
+    fn write_sheet(&mut self) -> Result<()> {
+        match self.sheet.take() {
+            Some(sheet) => self.write_page(
+                &sheet.content,
+                sheet.sheet.size,
+                0,
+                &sheet.images.concat(),
+                sheet.font_obj_num,
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the content stream and page object of a page of `size` points,
+    /// drawing the images named in `images`
+    fn write_page(
+        &mut self,
+        content: &str,
+        size: (f32, f32),
+        rotation: u16,
+        images: &[(String, usize)],
+        font_obj_num: Option<usize>,
+    ) -> Result<()> {
+        let (width_pts, height_pts) = size;
+        let stamp = match self.layout.page_numbers {
+            Some(_) => {
+                let font_obj_num = match self.stamp_font_obj_num {
+                    Some(obj_num) => obj_num,
+                    None => {
+                        let obj_num = self.write_object(stamp::FONT.to_string())?;
+                        self.stamp_font_obj_num = Some(obj_num);
+                        obj_num
+                    }
+                };
+                // Reserved for the stamp, written once the number of pages
+                // is known
+                let stamp_obj_num = self.reserve_object();
+                self.stamps.push((stamp_obj_num, size, rotation));
+                Some((stamp_obj_num, font_obj_num))
+            }
+            None => None,
+        };
+        let content_obj_num = self.start_object()?;
+        self.out.write_all(b"<<\n")?;
+        self.out
+            .write_all(format!("/Length {}\n", content.len()).as_bytes())?;
+        self.out.write_all(b">>\n")?;
+        self.out.write_all(b"stream\n")?;
+        self.out.write_all(content.as_bytes())?;
+        self.out.write_all(b"\nendstream\n")?;
+        self.out.write_all(b"endobj\n")?;
+
+        // Page object
+        let mut page = String::from("<<\n/Type /Page\n");
+        page.push_str(&format!("/Parent {} 0 R\n", Self::PAGES_OBJ_NUM));
+        page.push_str(&format!("/MediaBox [0 0 {width_pts:.2} {height_pts:.2}]\n"));
+        if rotation != 0 {
+            page.push_str(&format!("/Rotate {rotation}\n"));
+        }
+        page.push_str("/Resources <<\n");
+        page.push_str("  /XObject <<");
+        for (name, obj_num) in images {
+            page.push_str(&format!(" /{name} {obj_num} 0 R"));
+        }
+        page.push_str(" >>\n");
+        let mut fonts = Vec::new();
+        if let Some(font_obj_num) = font_obj_num {
+            fonts.push(format!("{} {font_obj_num} 0 R", text_layer::FONT_RESOURCE));
+        }
+        if let Some((_, font_obj_num)) = stamp {
+            fonts.push(format!("{} {font_obj_num} 0 R", stamp::FONT_RESOURCE));
+        }
+        if !fonts.is_empty() {
+            page.push_str(&format!("  /Font << {} >>\n", fonts.join(" ")));
+        }
+        page.push_str(">>\n");
+        match stamp {
+            Some((stamp_obj_num, _)) => page.push_str(&format!(
+                "/Contents [{content_obj_num} 0 R {stamp_obj_num} 0 R]\n"
+            )),
+            None => page.push_str(&format!("/Contents {content_obj_num} 0 R\n")),
+        }
+        page.push_str(">>\n");
+        let page_obj_num = self.write_object(page)?;
+
+        self.page_obj_nums.push(page_obj_num);
+        Ok(())
+    }
+
+    /// Write the ICC profile of RGB images, if any, the first time it is
+    /// needed, returning its object number
+    fn icc_profile(&mut self) -> Result<Option<usize>> {
+        if self.icc_profile_obj_num.is_some() {
+            return Ok(self.icc_profile_obj_num);
+        }
+        let Some(profile) = self.icc_profile.take() else {
+            return Ok(None);
+        };
+        let mut encoder = compression::Encoder::new(&CompressionConfig::default())?;
+        encoder.write_all(&profile)?;
+        let data = encoder.finish()?;
+
+        let obj_num = self.start_object()?;
+        self.out.write_all(b"<<\n")?;
+        self.out.write_all(b"/N 3\n")?;
+        self.out.write_all(b"/Alternate /DeviceRGB\n")?;
+        self.out.write_all(b"/Filter /FlateDecode\n")?;
+        self.out
+            .write_all(format!("/Length {}\n", data.len()).as_bytes())?;
+        self.out.write_all(b">>\n")?;
+        self.out.write_all(b"stream\n")?;
+        self.out.write_all(&data)?;
+        self.out.write_all(b"\nendstream\n")?;
+        self.out.write_all(b"endobj\n")?;
+
+        self.icc_profile_obj_num = Some(obj_num);
+        Ok(Some(obj_num))
+    }
+
+    /// Write the font of text layers the first time it is needed, returning
+    /// its object number
+    fn text_font(&mut self) -> Result<usize> {
+        if let Some(obj_num) = self.text_font_obj_num {
+            return Ok(obj_num);
+        }
+        let to_unicode_obj_num = self.start_object()?;
+        self.out
+            .write_all(format!("<<\n/Length {}\n>>\n", text_layer::TO_UNICODE.len()).as_bytes())?;
+        self.out.write_all(b"stream\n")?;
+        self.out.write_all(text_layer::TO_UNICODE.as_bytes())?;
+        self.out.write_all(b"\nendstream\n")?;
+        self.out.write_all(b"endobj\n")?;
+
+        let descriptor_obj_num = self.write_object(text_layer::FONT_DESCRIPTOR.to_string())?;
+        let cid_font_obj_num = self.write_object(text_layer::cid_font(descriptor_obj_num))?;
+        let font_obj_num =
+            self.write_object(text_layer::type0_font(cid_font_obj_num, to_unicode_obj_num))?;
+
+        self.text_font_obj_num = Some(font_obj_num);
+        Ok(font_obj_num)
+    }
+
+    /// Add bookmarks to the headings of the text layer of `page`, if the PDF
+    /// has an outline, with `top` giving where a heading, at a row of
+    /// pixels, is on the page to write next
+    fn add_bookmarks(&mut self, page: &EncodedPage, top: impl Fn(u32) -> Option<f32>) {
+        let (Some(bookmarks), Some(text)) = (&mut self.outline, &page.text) else {
+            return;
+        };
+        let page_index = self.page_obj_nums.len();
+        bookmarks.extend(
+            outline::headings(text)
+                .into_iter()
+                .map(|heading| outline::Bookmark {
+                    title: heading.title,
+                    level: heading.level,
+                    page: page_index,
+                    top: top(heading.top),
+                }),
+        );
+    }
+
+    /// Number of pages added so far
+    #[cfg(feature = "container")]
+    pub(crate) fn page_count(&self) -> usize {
+        self.pages_added
+    }
+
+    /// Number of bookmarks of the outline so far
+    #[cfg(any(feature = "container", test))]
+    pub(crate) fn bookmark_count(&self) -> usize {
+        self.outline.as_ref().map_or(0, Vec::len)
+    }
+
+    /// Write the page tree, the cross-reference table and the trailer
+    pub(crate) fn finish(mut self) -> Result<W> {
+        self.write_sheet()?;
+        if let Some(numbers) = self.layout.page_numbers {
+            let total = self.page_obj_nums.len();
+            for (index, (obj_num, size, rotation)) in
+                std::mem::take(&mut self.stamps).into_iter().enumerate()
+            {
+                let content = stamp::content(index + 1, total, size, rotation, &numbers);
+                self.start_reserved_object(obj_num)?;
+                self.out
+                    .write_all(format!("<<\n/Length {}\n>>\n", content.len()).as_bytes())?;
+                self.out.write_all(b"stream\n")?;
+                self.out.write_all(content.as_bytes())?;
+                self.out.write_all(b"\nendstream\n")?;
+                self.out.write_all(b"endobj\n")?;
+            }
+        }
+
+        // Object 2: Pages (parent)
+        let mut kids = String::from("/Kids [");
+        for obj_num in &self.page_obj_nums {
+            kids.push_str(&format!("{obj_num} 0 R "));
+        }
+        kids.push_str("]\n");
+        let pages = format!(
+            "<<\n/Type /Pages\n{kids}/Count {}\n>>\n",
+            self.page_obj_nums.len()
+        );
+        self.write_reserved_object(Self::PAGES_OBJ_NUM, pages)?;
+        if let Some(bookmarks) = self.outline.take() {
+            let first_obj_num = self.objects.len() + 1;
+            for _ in &bookmarks {
+                self.reserve_object();
+            }
+            let objects = outline::objects(
+                &bookmarks,
+                Self::OUTLINES_OBJ_NUM,
+                first_obj_num,
+                &self.page_obj_nums,
+            );
+            for (object, obj_num) in objects
+                .into_iter()
+                .zip(std::iter::once(Self::OUTLINES_OBJ_NUM).chain(first_obj_num..))
+            {
+                self.write_reserved_object(obj_num, object)?;
+            }
+        }
+        if let Some(provenance) = self.provenance.take() {
+            self.info_obj_num = Some(self.write_object(provenance.info())?);
+        }
+
+        if self.object_streams {
+            self.write_object_stream()?;
+            self.write_xref_stream()?;
+        } else {
+            self.write_xref_table()?;
+        }
+
+        self.out.flush()?;
+        Ok(self.out.writer)
+    }
+
+    /// Write the cross-reference table and the trailer
+    fn write_xref_table(&mut self) -> Result<()> {
+        let xref_offset = self.out.written;
+        let num_objects = self.objects.len();
+        self.out.write_all(b"xref\n")?;
+        self.out
+            .write_all(format!("0 {}\n", num_objects + 1).as_bytes())?;
+        self.out.write_all(b"0000000000 65535 f \n")?;
+        for location in &self.objects {
+            let ObjectLocation::Offset(offset) = location else {
+                anyhow::bail!("Compressed object without object streams");
+            };
+            self.out
+                .write_all(format!("{offset:010} 00000 n \n").as_bytes())?;
+        }
+
+        // Trailer
+        self.out.write_all(b"trailer\n")?;
+        self.out.write_all(b"<<\n")?;
+        self.out
+            .write_all(format!("/Size {}\n", num_objects + 1).as_bytes())?;
+        self.out.write_all(b"/Root 1 0 R\n")?;
+        if let Some(obj_num) = self.info_obj_num {
+            self.out
+                .write_all(format!("/Info {obj_num} 0 R\n").as_bytes())?;
+        }
+        self.out.write_all(b">>\n")?;
+        self.out.write_all(b"startxref\n")?;
+        self.out.write_all(format!("{xref_offset}\n").as_bytes())?;
+        self.out.write_all(b"%%EOF\n")?;
+        Ok(())
+    }
+
+    /// Write the cross-reference stream, which is also the trailer
+    fn write_xref_stream(&mut self) -> Result<()> {
+        let xref_offset = self.out.written;
+        self.objects.push(ObjectLocation::Offset(xref_offset));
+        let size = self.objects.len() + 1;
+
+        // Each entry is a type, then an offset or the number of an object
+        // stream, then a generation or an index in that stream
+        let largest = self
+            .objects
+            .iter()
+            .fold(0, |largest, location| match *location {
+                ObjectLocation::Offset(offset) => largest.max(offset),
+                ObjectLocation::Compressed { stream, .. } => largest.max(stream),
+            });
+        let width = (usize::BITS - largest.leading_zeros()).div_ceil(8).max(1) as usize;
+        let mut entries = Vec::with_capacity(size * (width + 3));
+        entries.push(0);
+        entries.extend(&[0; 8][..width]);
+        entries.extend(u16::MAX.to_be_bytes());
+        for location in &self.objects {
+            let (kind, field, second) = match *location {
+                ObjectLocation::Offset(offset) => (1, offset, 0),
+                ObjectLocation::Compressed { stream, index } => (2, stream, index as u16),
+            };
+            entries.push(kind);
+            entries.extend(&field.to_be_bytes()[size_of::<usize>() - width..]);
+            entries.extend(second.to_be_bytes());
+        }
+        let mut encoder = compression::Encoder::new(&CompressionConfig::default())?;
+        encoder.write_all(&entries)?;
+        let data = encoder.finish()?;
+
+        self.out
+            .write_all(format!("{} 0 obj\n", size - 1).as_bytes())?;
+        self.out.write_all(b"<<\n")?;
+        self.out.write_all(b"/Type /XRef\n")?;
+        self.out.write_all(format!("/Size {size}\n").as_bytes())?;
+        self.out
+            .write_all(format!("/W [1 {width} 2]\n").as_bytes())?;
+        self.out.write_all(b"/Root 1 0 R\n")?;
+        if let Some(obj_num) = self.info_obj_num {
+            self.out
+                .write_all(format!("/Info {obj_num} 0 R\n").as_bytes())?;
+        }
+        self.out.write_all(b"/Filter /FlateDecode\n")?;
+        self.out
+            .write_all(format!("/Length {}\n", data.len()).as_bytes())?;
+        self.out.write_all(b">>\n")?;
+        self.out.write_all(b"stream\n")?;
+        self.out.write_all(&data)?;
+        self.out.write_all(b"\nendstream\n")?;
+        self.out.write_all(b"endobj\n")?;
+        self.out.write_all(b"startxref\n")?;
+        self.out.write_all(format!("{xref_offset}\n").as_bytes())?;
+        self.out.write_all(b"%%EOF\n")?;
+        Ok(())
+    }
+}
+
+/// Operators drawing the image `/Im<index>` of `page` in the unit square, or
+/// its strips `/Im<index>_<strip>` from top to bottom
+fn draw_image(index: usize, page: &EncodedPage) -> String {
+    if page.strips.len() == 1 {
+        return format!("/Im{index} Do\n");
+    }
+    let height = f64::from(page.height);
+    let mut content = String::new();
+    let mut rows_below = height;
+    for (strip_index, strip) in page.strips.iter().enumerate() {
+        rows_below -= f64::from(strip.rows);
+        content.push_str(&format!(
+            "q\n1 0 0 {:.6} 0 {:.6} cm\n/Im{index}_{strip_index} Do\nQ\n",
+            f64::from(strip.rows) / height,
+            rows_below / height,
+        ));
+    }
+    content
+}
+
+/// Resource names of the images drawn by [`draw_image`], with their object
+/// numbers
+fn image_resources(index: usize, obj_nums: &[usize]) -> Vec<(String, usize)> {
+    if let [obj_num] = obj_nums {
+        return vec![(format!("Im{index}"), *obj_num)];
+    }
+    obj_nums
+        .iter()
+        .enumerate()
+        .map(|(strip_index, &obj_num)| (format!("Im{index}_{strip_index}"), obj_num))
+        .collect()
+}
+
+/// Size of the safe page in points: that of the original page if the
+/// converter sent it, or else that of the pixels at `dpi`
+pub(crate) fn page_size_pts<P: PdfPage>(page: &P, dpi: f32) -> (f32, f32) {
+    if let Some(size) = page.metadata().size_pts {
+        return size;
+    }
+    // Convert pixels to points (1 point = 1/72 inch)
+    (
+        (page.width() as f32) / dpi * 72.0,
+        (page.height() as f32) / dpi * 72.0,
+    )
+}
+
+/// Compress the pixels of `page` in strips of `strip_rows` rows, splitting
+/// the alpha channel of RGBA pages into a separate stream
+pub(crate) fn compress_pixels<P: PdfPage>(
+    page: &P,
+    compression: &CompressionConfig,
+    strip_rows: u16,
+) -> Result<Vec<ImageStrip>> {
+    let mut strips = StripWriter {
+        compression,
+        format: page.format(),
+        row_bytes: page.width() as usize * page.format().bytes_per_pixel(),
+        strip_rows,
+        rows_left: page.height(),
+        encoder: StripEncoder::new(compression, page.format())?,
+        written: 0,
+        strips: Vec::new(),
+    };
+    page.write_pixels(&mut strips)
+        .context("Failed to compress pixel data")?;
+    strips.finish().context("Failed to finish compression")
+}
+
+/// Writer compressing pixels into a new [`ImageStrip`] every `strip_rows`
+/// rows
+struct StripWriter<'a> {
+    compression: &'a CompressionConfig,
+    format: PixelFormat,
+    row_bytes: usize,
+    strip_rows: u16,
+    /// Rows of the page from the start of the current strip
+    rows_left: u16,
+    encoder: StripEncoder,
+    /// Bytes written to the current strip
+    written: usize,
+    strips: Vec<ImageStrip>,
+}
+
+impl StripWriter<'_> {
+    /// Rows of the current strip
+    fn rows(&self) -> u16 {
+        self.strip_rows.min(self.rows_left)
+    }
+
+    fn finish(mut self) -> std::io::Result<Vec<ImageStrip>> {
+        let rows = self.rows();
+        let (pixels, alpha) = self.encoder.finish()?;
+        self.strips.push(ImageStrip {
+            rows,
+            pixels,
+            alpha,
+        });
+        Ok(self.strips)
+    }
+}
+
+impl Write for StripWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let strip_bytes = self.rows() as usize * self.row_bytes;
+            if self.written == strip_bytes && self.rows_left > self.rows() {
+                let rows = self.rows();
+                let encoder = std::mem::replace(
+                    &mut self.encoder,
+                    StripEncoder::new(self.compression, self.format)?,
+                );
+                let (pixels, alpha) = encoder.finish()?;
+                self.strips.push(ImageStrip {
+                    rows,
+                    pixels,
+                    alpha,
+                });
+                self.rows_left -= rows;
+                self.written = 0;
+                continue;
+            }
+            // Anything after the last row goes to the last strip
+            let take = if self.rows_left > self.rows() {
+                (strip_bytes - self.written).min(rest.len())
+            } else {
+                rest.len()
+            };
+            self.encoder.write_all(&rest[..take])?;
+            self.written += take;
+            rest = &rest[take..];
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Compressor of the pixels of one strip
+enum StripEncoder {
+    Pixels(compression::Encoder),
+    Rgba(AlphaSplitter),
+}
+
+impl StripEncoder {
+    fn new(compression: &CompressionConfig, format: PixelFormat) -> std::io::Result<Self> {
+        let pixels = compression::Encoder::new(compression)?;
+        if format != PixelFormat::Rgba {
+            return Ok(StripEncoder::Pixels(pixels));
+        }
+        Ok(StripEncoder::Rgba(AlphaSplitter {
+            pixels,
+            alpha: compression::Encoder::new(compression)?,
+            partial: Vec::with_capacity(4),
+        }))
+    }
+
+    /// Compressed pixels, and alpha channel of RGBA pixels
+    fn finish(self) -> std::io::Result<(Vec<u8>, Option<Vec<u8>>)> {
+        match self {
+            StripEncoder::Pixels(encoder) => Ok((encoder.finish()?, None)),
+            StripEncoder::Rgba(splitter) => {
+                Ok((splitter.pixels.finish()?, Some(splitter.alpha.finish()?)))
+            }
+        }
+    }
+}
+
+impl Write for StripEncoder {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            StripEncoder::Pixels(encoder) => encoder.write(buf),
+            StripEncoder::Rgba(splitter) => splitter.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writer splitting RGBA pixels into compressed RGB and alpha streams
+pub(crate) struct AlphaSplitter {
+    pixels: compression::Encoder,
+    alpha: compression::Encoder,
+    /// Bytes of a pixel split across writes
+    partial: Vec<u8>,
+}
+
+impl Write for AlphaSplitter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut rest = buf;
+        if !self.partial.is_empty() {
+            let take = (4 - self.partial.len()).min(rest.len());
+            self.partial.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+            if self.partial.len() < 4 {
+                return Ok(buf.len());
+            }
+            self.pixels.write_all(&self.partial[..3])?;
+            self.alpha.write_all(&self.partial[3..])?;
+            self.partial.clear();
+        }
+
+        let whole = rest.len() / 4 * 4;
+        let mut pixels = Vec::with_capacity(whole / 4 * 3);
+        let mut alpha = Vec::with_capacity(whole / 4);
+        for pixel in rest[..whole].chunks_exact(4) {
+            pixels.extend_from_slice(&pixel[..3]);
+            alpha.push(pixel[3]);
+        }
+        self.pixels.write_all(&pixels)?;
+        self.alpha.write_all(&alpha)?;
+        self.partial.extend_from_slice(&rest[whole..]);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Page whose pixels can be written to a PDF
+pub(crate) trait PdfPage {
+    fn width(&self) -> u16;
+    fn height(&self) -> u16;
+    fn format(&self) -> PixelFormat;
+    fn metadata(&self) -> PageMetadata;
+    /// Write the page's pixels, row by row
+    fn write_pixels(&self, out: &mut dyn Write) -> std::io::Result<()>;
+}
+
+impl PdfPage for PageData {
+    fn width(&self) -> u16 {
+        self.width
+    }
+
+    fn height(&self) -> u16 {
+        self.height
+    }
+
+    fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    fn metadata(&self) -> PageMetadata {
+        self.metadata
+    }
+
+    fn write_pixels(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        out.write_all(&self.pixels)
+    }
+}
+
+impl<P: PdfPage> PdfPage for &P {
+    fn width(&self) -> u16 {
+        (*self).width()
+    }
+
+    fn height(&self) -> u16 {
+        (*self).height()
+    }
+
+    fn format(&self) -> PixelFormat {
+        (*self).format()
+    }
+
+    fn metadata(&self) -> PageMetadata {
+        (*self).metadata()
+    }
+
+    fn write_pixels(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        (*self).write_pixels(out)
+    }
+}
+
+/// Writer keeping track of the offset of the next byte, for the PDF's
+/// cross-reference table
+pub(crate) struct CountingWriter<W> {
+    pub(crate) writer: W,
+    pub(crate) written: usize,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.written += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PageNumbers, Paper};
+    use std::io::Read;
+    use std::time::Duration;
+
+    #[test]
+    fn test_page_size_calculation() {
+        let width_pixels = 1500u16;
+        let height_pixels = 2000u16;
+        let dpi = 150.0f32;
+
+        let width_mm = (width_pixels as f32) / dpi * 25.4;
+        let height_mm = (height_pixels as f32) / dpi * 25.4;
+
+        assert_eq!(width_mm, 254.0);
+        assert_eq!(height_mm, 338.66666);
+    }
+
+    #[test]
+    fn test_pdf_generation() {
+        use std::io::Cursor;
+
+        let width = 10u16;
+        let height = 10u16;
+        let mut pixels = Vec::new();
+
+        for _ in 0..(width * height) {
+            pixels.push(255);
+            pixels.push(0);
+            pixels.push(0);
+        }
+
+        let page = PageData::new(width, height, pixels);
+        let pages = vec![page];
+
+        let mut buffer = Cursor::new(Vec::new());
+        let result = write_pdf(buffer.get_mut(), &pages);
+        assert!(result.is_ok(), "PDF generation should succeed");
+
+        let pdf_data = buffer.into_inner();
+        assert!(!pdf_data.is_empty(), "PDF should have data");
+
+        let header = String::from_utf8_lossy(&pdf_data[0..9]);
+        assert!(
+            header.starts_with("%PDF-1.4"),
+            "PDF should have correct header"
+        );
+
+        let trailer = String::from_utf8_lossy(&pdf_data);
+        assert!(trailer.contains("%%EOF"), "PDF should have EOF marker");
+        assert!(
+            trailer.contains("/Type /Catalog"),
+            "PDF should have catalog"
+        );
+        assert!(trailer.contains("/Type /Pages"), "PDF should have pages");
+        assert!(
+            trailer.contains("/Type /Page"),
+            "PDF should have page object"
+        );
+        assert!(
+            trailer.contains("/Type /XObject"),
+            "PDF should have image object"
+        );
+
+        assert!(
+            trailer.contains("/Filter /FlateDecode"),
+            "PDF should use Flate compression for images"
+        );
+    }
+
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        let mut inflated = Vec::new();
+        flate2::read::ZlibDecoder::new(data)
+            .read_to_end(&mut inflated)
+            .unwrap();
+        inflated
+    }
+
+    #[test]
+    fn test_pdf_pixel_formats() {
+        let pages = [
+            PageData::with_format(2, 2, PixelFormat::Gray, vec![0, 64, 128, 255]),
+            PageData::with_format(1, 2, PixelFormat::Rgba, vec![1, 2, 3, 4, 5, 6, 7, 8]),
+            PageData::new(1, 1, vec![9, 9, 9]),
+        ];
+        let mut pdf_data = Vec::new();
+        write_pdf(&mut pdf_data, &pages).unwrap();
+        let pdf = String::from_utf8_lossy(&pdf_data);
+
+        assert!(pdf.contains("/ColorSpace /DeviceGray\n/BitsPerComponent 8\n/Filter"));
+        // Objects are numbered as they are written: the soft mask of the
+        // RGBA page comes right before its image
+        assert!(pdf.contains("6 0 obj\n<<\n/Type /XObject\n/Subtype /Image\n/Width 1\n"));
+        assert!(pdf.contains("/SMask 6 0 R"));
+        assert!(pdf.contains("/Kids [5 0 R 9 0 R 12 0 R ]"));
+        assert!(pdf.contains("0 13\n"));
+
+        // Every object is where the cross-reference table says
+        let xref = pdf.rfind("xref\n").unwrap();
+        for (i, entry) in pdf[xref..].lines().skip(3).take(12).enumerate() {
+            let offset: usize = entry[..10].parse().unwrap();
+            assert!(pdf[offset..].starts_with(&format!("{} 0 obj\n", i + 1)));
+        }
+    }
+
+    #[test]
+    fn test_pdf_page_metadata() {
+        let mut page = PageData::new(1, 1, vec![0, 0, 0]);
+        page.metadata = PageMetadata {
+            rotation: 270,
+            size_pts: Some((612.0, 792.0)),
+            blank: false,
+            failed: false,
+        };
+        let mut pdf_data = Vec::new();
+        write_pdf(&mut pdf_data, &[page]).unwrap();
+        let pdf = String::from_utf8_lossy(&pdf_data);
+
+        assert!(pdf.contains("/MediaBox [0 0 612.00 792.00]"));
+        assert!(pdf.contains("/Rotate 270\n"));
+        assert!(pdf.contains("612.00 0 0 792.00 0 0 cm"));
+    }
+
+    #[test]
+    fn test_pdf_on_paper() {
+        let mut page = PageData::new(1, 1, vec![0, 0, 0]);
+        page.metadata.size_pts = Some((792.0, 612.0));
+        let layout = PageLayout {
+            paper: Paper::A4,
+            margin_mm: 0.0,
+            ..PageLayout::default()
+        };
+        let mut pdf = PdfWriter::new(
+            Vec::new(),
+            WriterSettings {
+                layout,
+                ..WriterSettings::default()
+            },
+        )
+        .unwrap();
+        pdf.add_page(&EncodedPage::new(&page, DPI, &CompressionConfig::default()).unwrap())
+            .unwrap();
+        let pdf_data = pdf.finish().unwrap();
+        let pdf = String::from_utf8_lossy(&pdf_data);
+
+        // Landscape A4, with the page scaled to its height and centered
+        assert!(pdf.contains("/MediaBox [0 0 841.89 595.28]"));
+        assert!(pdf.contains("770.36 0 0 595.28 35.76 0.00 cm"));
+    }
+
+    #[test]
+    fn test_pdf_object_streams() {
+        let mut pdf = PdfWriter::new(
+            Vec::new(),
+            WriterSettings {
+                object_streams: true,
+                ..WriterSettings::default()
+            },
+        )
+        .unwrap();
+        // Enough pages for more than one object stream
+        for _ in 0..120 {
+            let page = PageData::new(1, 1, vec![0; 3]);
+            pdf.add_page(&EncodedPage::new(&page, DPI, &CompressionConfig::default()).unwrap())
+                .unwrap();
+        }
+        let pdf_data = pdf.finish().unwrap();
+        crate::validate::validate(&mut std::io::Cursor::new(&pdf_data)).unwrap();
+        crate::validate::audit(&mut std::io::Cursor::new(&pdf_data)).unwrap();
+        let pdf = String::from_utf8_lossy(&pdf_data);
+
+        assert!(pdf.starts_with("%PDF-1.5\n"));
+        assert_eq!(pdf.matches("/Type /ObjStm").count(), 2);
+        assert_eq!(pdf.matches("/Type /XRef").count(), 1);
+        assert!(!pdf.contains("\nxref\n"));
+        assert!(!pdf.contains("trailer"));
+        // Page dictionaries are compressed, image streams aren't
+        assert!(!pdf.contains("/Type /Page"));
+        assert_eq!(pdf.matches("/Subtype /Image").count(), 120);
+    }
+
+    #[test]
+    fn test_pdf_outline() {
+        let line = |top: u32, height: u32, text: &str| {
+            format!(
+                "<p class='ocr_par'><span class='ocr_line' title='bbox 0 {top} 100 {}'>\
+                 <span class='ocrx_word' title='bbox 0 {top} 100 {}'>{text}</span></span></p>",
+                top + height,
+                top + height
+            )
+        };
+        let hocr = format!(
+            "<div class='ocr_page' title='bbox 0 0 100 100'>{}{}{}{}</div>",
+            line(10, 20, "Summary"),
+            line(40, 5, "body"),
+            line(50, 5, "body"),
+            line(60, 5, "body")
+        );
+        let text = hocr::parse_hocr(&hocr).unwrap().remove(0);
+
+        let mut pdf = PdfWriter::new(
+            Vec::new(),
+            WriterSettings {
+                outline: true,
+                ..WriterSettings::default()
+            },
+        )
+        .unwrap();
+        let page = PageData::new(100, 100, vec![255; 30000]);
+        let mut encoded = EncodedPage::new(&page, 72.0, &CompressionConfig::default()).unwrap();
+        encoded.text = Some(text);
+        pdf.add_page(&encoded).unwrap();
+        pdf.add_page(&EncodedPage::new(&page, 72.0, &CompressionConfig::default()).unwrap())
+            .unwrap();
+        assert_eq!(pdf.bookmark_count(), 1);
+        let pdf_data = pdf.finish().unwrap();
+        crate::validate::validate(&mut std::io::Cursor::new(&pdf_data)).unwrap();
+        crate::validate::audit(&mut std::io::Cursor::new(&pdf_data)).unwrap();
+        let pdf = String::from_utf8_lossy(&pdf_data);
+
+        assert!(pdf.contains("/Outlines 3 0 R\n/PageMode /UseOutlines\n"));
+        assert!(pdf.contains("3 0 obj\n<<\n/Type /Outlines\n"));
+        // A point per pixel: the heading is 10 points below the top
+        assert!(pdf.contains("/Title (Summary)"));
+        assert!(pdf.contains(" 0 R /XYZ null 90.00 null]"));
+    }
+
+    #[test]
+    fn test_pdf_provenance() {
+        let provenance = |object_streams| {
+            let mut pdf = PdfWriter::new(
+                Vec::new(),
+                WriterSettings {
+                    object_streams,
+                    provenance: Some(Provenance {
+                        original_name: Some("Q3 (draft).docx".to_string()),
+                        converted: SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_210_096),
+                    }),
+                    ..WriterSettings::default()
+                },
+            )
+            .unwrap();
+            let page = PageData::new(1, 1, vec![0; 3]);
+            pdf.add_page(&EncodedPage::new(&page, DPI, &CompressionConfig::default()).unwrap())
+                .unwrap();
+            pdf.finish().unwrap()
+        };
+
+        let pdf_data = provenance(false);
+        crate::validate::validate(&mut std::io::Cursor::new(&pdf_data)).unwrap();
+        crate::validate::audit(&mut std::io::Cursor::new(&pdf_data)).unwrap();
+        let pdf = String::from_utf8_lossy(&pdf_data);
+        assert!(pdf.contains(concat!(
+            "/Keywords (original: Q3 \\(draft\\).docx; converted: 2024-02-29T12:34:56Z; ",
+            "dangerzone-rs ",
+            env!("CARGO_PKG_VERSION"),
+            ")\n"
+        )));
+        assert!(pdf.contains("/CreationDate (D:20240229123456Z)\n"));
+        assert!(pdf.contains("trailer\n<<\n/Size 7\n/Root 1 0 R\n/Info 6 0 R\n>>\n"));
+
+        // The information dictionary is compressed like the other objects
+        let pdf_data = provenance(true);
+        crate::validate::audit(&mut std::io::Cursor::new(&pdf_data)).unwrap();
+        assert!(String::from_utf8_lossy(&pdf_data).contains("/Info 6 0 R\n"));
+
+        // Without the option, the PDF has no metadata
+        let mut pdf = Vec::new();
+        write_pdf(&mut pdf, &[PageData::new(1, 1, vec![0; 3])]).unwrap();
+        assert!(!String::from_utf8_lossy(&pdf).contains("/Info"));
+    }
+
+    #[test]
+    fn test_pdf_text_string() {
+        assert_eq!(pdf_text_string("a (b) \\"), "(a \\(b\\) \\\\)");
+        assert_eq!(pdf_text_string("é"), "<FEFF00E9>");
+        assert_eq!(pdf_text_string("a\tb"), "<FEFF006100090062>");
+    }
+
+    #[test]
+    fn test_pdf_page_numbers() {
+        let layout = PageLayout {
+            page_numbers: Some(PageNumbers::default()),
+            ..PageLayout::default()
+        };
+        let mut pdf = PdfWriter::new(
+            Vec::new(),
+            WriterSettings {
+                layout,
+                ..WriterSettings::default()
+            },
+        )
+        .unwrap();
+        for _ in 0..2 {
+            let page = PageData::new(1, 1, vec![0, 0, 0]);
+            pdf.add_page(&EncodedPage::new(&page, DPI, &CompressionConfig::default()).unwrap())
+                .unwrap();
+        }
+        let pdf_data = pdf.finish().unwrap();
+        crate::validate::validate(&mut std::io::Cursor::new(&pdf_data)).unwrap();
+        crate::validate::audit(&mut std::io::Cursor::new(&pdf_data)).unwrap();
+        let pdf = String::from_utf8_lossy(&pdf_data);
+
+        // One font for both pages, and a stamp after the content of each
+        assert_eq!(pdf.matches("/BaseFont /Helvetica").count(), 1);
+        assert!(pdf.contains("/Font << /FStamp 4 0 R >>"));
+        assert!(pdf.contains("/Contents [6 0 R 5 0 R]"));
+        assert!(pdf.contains("(Page 1 of 2) Tj"));
+        assert!(pdf.contains("(Page 2 of 2) Tj"));
+    }
+
+    #[test]
+    fn test_compress_pixels_in_strips() {
+        let rgba: Vec<u8> = (0..120).collect();
+        let page = PageData::with_format(3, 10, PixelFormat::Rgba, rgba);
+        let strips = compress_pixels(&page, &CompressionConfig::default(), 4).unwrap();
+        let rows: Vec<u16> = strips.iter().map(|strip| strip.rows).collect();
+        assert_eq!(rows, [4, 4, 2]);
+        let pixels: Vec<u8> = strips.iter().flat_map(|s| inflate(&s.pixels)).collect();
+        let alpha: Vec<u8> = strips
+            .iter()
+            .flat_map(|s| inflate(s.alpha.as_ref().unwrap()))
+            .collect();
+        assert_eq!(pixels.len(), 90);
+        assert_eq!(alpha, (3..120).step_by(4).collect::<Vec<u8>>());
+
+        // Strips of more rows than the page has hold the whole page
+        let strips = compress_pixels(&page, &CompressionConfig::default(), 20).unwrap();
+        assert_eq!(strips.len(), 1);
+        assert_eq!(strips[0].rows, 10);
+    }
+
+    #[test]
+    fn test_pdf_image_strips() {
+        let page = PageData::new(3, 10, (0..90).collect::<Vec<u8>>());
+        let mut pdf = PdfWriter::new(Vec::new(), WriterSettings::default()).unwrap();
+        // Four rows of three RGB pixels per strip
+        pdf.add_page(
+            &EncodedPage::in_strips(&page, DPI, &CompressionConfig::default(), Some(36)).unwrap(),
+        )
+        .unwrap();
+        let pdf_data = pdf.finish().unwrap();
+        crate::validate::validate(&mut std::io::Cursor::new(&pdf_data)).unwrap();
+        crate::validate::audit(&mut std::io::Cursor::new(&pdf_data)).unwrap();
+        let pdf = String::from_utf8_lossy(&pdf_data);
+
+        let heights: Vec<&str> = pdf
+            .split("/Height ")
+            .skip(1)
+            .map(|rest| rest.split_whitespace().next().unwrap())
+            .collect();
+        assert_eq!(heights, ["4", "4", "2"]);
+        assert!(pdf.contains("/XObject << /Im0_0 3 0 R /Im0_1 4 0 R /Im0_2 5 0 R >>"));
+        // From the top of the page down
+        assert!(pdf.contains("1 0 0 0.400000 0 0.600000 cm\n/Im0_0 Do\n"));
+        assert!(pdf.contains("1 0 0 0.400000 0 0.200000 cm\n/Im0_1 Do\n"));
+        assert!(pdf.contains("1 0 0 0.200000 0 0.000000 cm\n/Im0_2 Do\n"));
+    }
+
+    #[test]
+    fn test_alpha_splitter() {
+        let rgba: Vec<u8> = (0..40).collect();
+        let mut splitter = AlphaSplitter {
+            pixels: compression::Encoder::new(&CompressionConfig::default()).unwrap(),
+            alpha: compression::Encoder::new(&CompressionConfig::default()).unwrap(),
+            partial: Vec::new(),
+        };
+        // Pixels split across writes
+        for chunk in rgba.chunks(7) {
+            splitter.write_all(chunk).unwrap();
+        }
+        let pixels = inflate(&splitter.pixels.finish().unwrap());
+        let alpha = inflate(&splitter.alpha.finish().unwrap());
+
+        let expected: Vec<u8> = rgba
+            .chunks(4)
+            .flat_map(|pixel| pixel[..3].to_vec())
+            .collect();
+        assert_eq!(pixels, expected);
+        assert_eq!(alpha, (3..40).step_by(4).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn test_pdf_compression_reduces_size() {
+        use std::io::Cursor;
+
+        let width = 100u16;
+        let height = 100u16;
+        let mut pixels = Vec::new();
+
+        for _ in 0..(width * height) {
+            pixels.push(255);
+            pixels.push(0);
+            pixels.push(0);
+        }
+
+        let page = PageData::new(width, height, pixels.clone());
+        let pages = vec![page];
+
+        let mut buffer = Cursor::new(Vec::new());
+        let result = write_pdf(buffer.get_mut(), &pages);
+        assert!(result.is_ok(), "PDF generation should succeed");
+
+        let pdf_data = buffer.into_inner();
+
+        let uncompressed_pixel_size = pixels.len();
+        assert_eq!(uncompressed_pixel_size, 30000);
+
+        let estimated_uncompressed_pdf_size = uncompressed_pixel_size + 1000;
+
+        eprintln!("PDF size with compression: {} bytes", pdf_data.len());
+        eprintln!("Estimated uncompressed size: {estimated_uncompressed_pdf_size} bytes");
+        eprintln!(
+            "Compression ratio: {:.2}%",
+            (pdf_data.len() as f32 / estimated_uncompressed_pdf_size as f32) * 100.0
+        );
+
+        assert!(
+            pdf_data.len() < estimated_uncompressed_pdf_size / 2,
+            "PDF with compression should be significantly smaller than uncompressed"
+        );
+    }
+}
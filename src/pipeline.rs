@@ -0,0 +1,1199 @@
+//! Writing of the safe PDF while pages are still arriving
+//!
+//! Three stages overlap: the reader thread of a [`PageStream`](crate::PageStream)
+//! parses pages while the container converts the following ones, a rayon
+//! pool prepares and compresses several pages at once, and the calling
+//! thread writes the prepared pages to the PDF in order. At most a few pages
+//! per core are in flight, so memory use doesn't grow with the document.
+
+use crate::hocr::OcrPage;
+use crate::linearize;
+use crate::ocr::{self, OcrEngine, OcrJob, OcrOutput};
+use crate::orient::{self, OrientationDetector};
+use crate::redact::{self, PatternMatch, PatternRedactor};
+use crate::{
+    audit_pdf, blank, conversion_temp_dir, enhance, image_digest, lang_detect, placeholder,
+    replace_control_chars, validate_pdf, CancellationToken, Cancelled, ConversionOptions,
+    ConversionReport, ConversionStats, EncodedPage, OcrMyPdfOptions, OcrSidecar, PageData,
+    PartialConversion, PdfPage, PdfWriter, Progress, Provenance, Runtime, UtcTime, Warning,
+    WriterSettings, IMAGE_NAME, OCR_LANG_AUTO,
+};
+use anyhow::{Context, Result};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Pages prepared ahead of the one being written, per thread of the pool
+const PAGES_IN_FLIGHT_PER_THREAD: usize = 2;
+
+/// OCR language used when detecting it fails
+const DEFAULT_OCR_LANG: &str = "eng";
+
+/// Write `pages` to the safe PDF at `output_path`, applying OCR if requested
+///
+/// `page_count` returns the number of pages of the document, once known, and
+/// `original` is the path of the document the pages come from, if any.
+pub(crate) fn write_safe_pdf<P: PdfPage + Send>(
+    pages: impl Iterator<Item = Result<P>>,
+    page_count: &dyn Fn() -> Option<usize>,
+    original: Option<&str>,
+    output_path: String,
+    options: &ConversionOptions,
+    progress: &dyn Fn(Progress),
+    cancel: &CancellationToken,
+) -> Result<ConversionReport> {
+    info!("Converting pixels to safe PDF...");
+    let output_path_sanitized = replace_control_chars(&output_path, false);
+
+    let mut pages = pages.peekable();
+    let mut report = ConversionReport::default();
+    let mut ocr_lang = options.ocr_lang.clone();
+    let redacting = !options.redact_patterns.is_empty();
+    if (options.ocr || redacting) && ocr_lang == OCR_LANG_AUTO {
+        ocr_lang = match pages.peek() {
+            Some(Ok(first)) => lang_detect::detect(first).unwrap_or_else(|e| {
+                let warning = Warning::OcrLangUndetected {
+                    reason: format!("{e:#}"),
+                    lang: DEFAULT_OCR_LANG.to_string(),
+                };
+                warn!(
+                    "{warning_sanitized}",
+                    warning_sanitized = replace_control_chars(&warning.to_string(), true)
+                );
+                report.warnings.push(warning);
+                DEFAULT_OCR_LANG.to_string()
+            }),
+            // The error is returned once the page is written
+            _ => DEFAULT_OCR_LANG.to_string(),
+        };
+        report.ocr_lang = Some(ocr_lang.clone());
+    }
+    let cover = options
+        .cover_sheet
+        .then(|| cover_sheet(original, &ocr_lang, options))
+        .transpose()?;
+
+    // Engines reading pixels add the text layer while the PDF is written, in
+    // a single pass, unless options of ocrmypdf ask for it
+    let pixel_engine = (options.ocr && options.ocrmypdf == OcrMyPdfOptions::default())
+        .then(|| ocr::pixel_engine(&ocr_lang))
+        .flatten();
+    // Patterns are found by the engine adding the text layer, if it reads
+    // pixels, or by one of their own
+    let redaction_engine = match pixel_engine {
+        None if redacting => Some(ocr::pixel_engine(&ocr_lang).context(
+            "Redacting patterns requires an OCR engine reading pixels, such as tesseract",
+        )?),
+        _ => None,
+    };
+    let redactor = pixel_engine
+        .as_deref()
+        .or(redaction_engine.as_deref())
+        .filter(|_| redacting)
+        .map(|engine| PatternRedactor::new(engine, &options.redact_patterns))
+        .transpose()?;
+    if !options.ocr || pixel_engine.is_some() {
+        // Linearized PDFs are rewritten from a first one in a temporary
+        // directory, removed when dropped
+        let temp_dir = options.linearize.then(conversion_temp_dir).transpose()?;
+        let written = match &temp_dir {
+            Some(temp_dir) => temp_dir.path().join("unlinearized.pdf"),
+            None => PathBuf::from(&output_path),
+        };
+        let file = File::create(&written).context(format!(
+            "Failed to create output file '{output_path_sanitized}'"
+        ))?;
+        let result = pdf_writer(BufWriter::new(file), options, original)
+            .and_then(|pdf| with_cover(pdf, cover.as_ref(), options, pixel_engine.as_deref()))
+            .and_then(|pdf| {
+                write_pages(
+                    pages,
+                    page_count,
+                    pdf,
+                    options,
+                    pixel_engine.as_deref(),
+                    redactor.as_ref(),
+                    progress,
+                    cancel,
+                )
+            })
+            .and_then(|written_pages| {
+                if temp_dir.is_some() {
+                    linearize::linearize_file(&written, Path::new(&output_path))?;
+                }
+                check_written(&output_path, options)?;
+                Ok(written_pages)
+            });
+        let mut written_pages = match result {
+            Ok(written_pages) => written_pages,
+            Err(e) => {
+                // Don't leave a partial PDF behind
+                let _ = std::fs::remove_file(&output_path);
+                return Err(e);
+            }
+        };
+        let failure = written_pages.failure.take();
+        let output_path = match &failure {
+            Some(_) => move_to_partial(output_path)?,
+            None => {
+                info!("Safe PDF created successfully at: {output_path_sanitized}");
+                output_path
+            }
+        };
+        if let (Some(format), Some(_)) = (options.ocr_sidecar, &pixel_engine) {
+            write_sidecar(
+                format,
+                Path::new(&output_path),
+                Some(format.write(&written_pages.text)),
+                &mut report,
+            )?;
+        }
+        if let Some(failure) = failure {
+            return Err(failure.into_error(output_path, written_pages.pages));
+        }
+        written_pages.record(&mut report, Path::new(&output_path));
+        report.peak_rss_bytes = peak_rss_bytes();
+        progress(Progress::Done);
+        return Ok(report);
+    }
+
+    // Removed with everything in it when dropped, even on error or panic
+    let temp_dir = conversion_temp_dir()?;
+    let temp_output = temp_dir.path().join("pixels.pdf");
+    let file = File::create(&temp_output).context("Failed to create temporary PDF")?;
+    let pdf = pdf_writer(BufWriter::new(file), options, original)?;
+    let mut written_pages = write_pages(
+        pages,
+        page_count,
+        with_cover(pdf, cover.as_ref(), options, None)?,
+        options,
+        None,
+        redactor.as_ref(),
+        progress,
+        cancel,
+    )?;
+    check_written(&temp_output.to_string_lossy(), options)?;
+
+    cancel.check()?;
+    progress(Progress::ApplyingOcr);
+    let failure = written_pages.failure.take();
+    let output_path = match &failure {
+        Some(_) => partial_path(&output_path),
+        None => output_path,
+    };
+    let output_pdf = Path::new(&output_path);
+    let job = OcrJob {
+        input_pdf: &temp_output,
+        output_pdf,
+        lang: &ocr_lang,
+        sidecar: options.ocr_sidecar,
+        ocrmypdf: &options.ocrmypdf,
+        timeout: options.ocr_timeout,
+        temp_dir: temp_dir.path(),
+        linearize: options.linearize,
+    };
+    let ocr = match ocr::apply_ocr(&job) {
+        Ok(ocr) => ocr,
+        Err(e) if options.ocr_required => {
+            return Err(e.context("OCR is required, but the text layer couldn't be added"))
+        }
+        Err(e) => {
+            ocr::fall_back(&job, &e)?;
+            report.ocr_fallback = Some(format!("{e:#}"));
+            report.warnings.push(Warning::OcrFallback {
+                reason: format!("{e:#}"),
+            });
+            OcrOutput::default()
+        }
+    };
+    if let Some(format) = options.ocr_sidecar {
+        write_sidecar(format, output_pdf, ocr.sidecar, &mut report)?;
+    }
+
+    if let Some(failure) = failure {
+        return Err(failure.into_error(output_path, written_pages.pages));
+    }
+    written_pages.record(&mut report, output_pdf);
+    report.peak_rss_bytes = peak_rss_bytes();
+    progress(Progress::Done);
+    Ok(report)
+}
+
+/// What [`write_pages`] wrote
+struct WrittenPages<W> {
+    /// Only read by tests: conversions write to a file
+    #[cfg_attr(not(test), allow(dead_code))]
+    writer: W,
+    /// Words found on each page by an engine reading pixels
+    text: Vec<OcrPage>,
+    pages: usize,
+    /// Blank pages left out
+    dropped: usize,
+    /// Pages left out by [`ConversionOptions::stages`], from 1
+    left_out: Vec<usize>,
+    /// Bytes of the uncompressed pixels of the pages
+    pixel_bytes: u64,
+    /// Text blacked out because it matched a pattern
+    redacted: Vec<PatternMatch>,
+    /// The outline asked for has no bookmarks
+    empty_outline: bool,
+    /// Pages the converter failed to convert, replaced by placeholders
+    failed_pages: Vec<usize>,
+    /// Why the pages stopped coming, if the pages before were written
+    /// anyway for [`ConversionOptions::keep_partial`]
+    failure: Option<PartialFailure>,
+}
+
+/// Failure to receive a page, after the pages before it
+struct PartialFailure {
+    /// Page that failed, from 1
+    page: usize,
+    error: anyhow::Error,
+}
+
+impl PartialFailure {
+    /// The error of the conversion whose `pages` received before the
+    /// failure were written to the partial PDF at `path`
+    fn into_error(self, path: String, pages: usize) -> anyhow::Error {
+        warn!(
+            "Wrote the {pages} page(s) before page {page} to the partial PDF '{path_sanitized}'",
+            page = self.page,
+            path_sanitized = replace_control_chars(&path, false)
+        );
+        self.error.context(PartialConversion {
+            path,
+            pages,
+            failed_page: self.page,
+        })
+    }
+}
+
+/// Path of the partial PDF of the safe PDF at `output_path`: `safe.pdf`
+/// becomes `safe.partial.pdf`
+fn partial_path(output_path: &str) -> String {
+    let path = Path::new(output_path);
+    let partial = match path.extension() {
+        Some(extension) => path.with_extension(format!("partial.{}", extension.to_string_lossy())),
+        None => path.with_extension("partial"),
+    };
+    partial.to_string_lossy().into_owned()
+}
+
+/// Move the safe PDF written at `output_path` to its partial path, so that
+/// it can't be mistaken for a complete one
+fn move_to_partial(output_path: String) -> Result<String> {
+    let partial = partial_path(&output_path);
+    if let Err(e) = std::fs::rename(&output_path, &partial) {
+        let _ = std::fs::remove_file(&output_path);
+        return Err(e).context(format!(
+            "Failed to move the partial PDF to '{partial_sanitized}'",
+            partial_sanitized = replace_control_chars(&partial, false)
+        ));
+    }
+    Ok(partial)
+}
+
+impl<W> WrittenPages<W> {
+    /// Record the pages, the pages that failed, the blank pages dropped,
+    /// the text redacted, a missing outline and the size of the safe PDF at
+    /// `output` in `report`
+    fn record(&self, report: &mut ConversionReport, output: &Path) {
+        report.redacted = self.redacted.clone();
+        if !self.failed_pages.is_empty() {
+            report.warnings.push(Warning::PagesFailed {
+                pages: self.failed_pages.clone(),
+            });
+        }
+        if self.dropped > 0 {
+            report.warnings.push(Warning::BlankPagesDropped {
+                count: self.dropped,
+            });
+        }
+        if !self.left_out.is_empty() {
+            report.warnings.push(Warning::PagesLeftOut {
+                pages: self.left_out.clone(),
+            });
+        }
+        if self.empty_outline {
+            report.warnings.push(Warning::OutlineEmpty);
+        }
+        let stats = &mut report.stats;
+        stats.pages = self.pages;
+        stats.pixel_bytes = self.pixel_bytes;
+        stats.output_bytes = std::fs::metadata(output).map_or(0, |metadata| metadata.len());
+    }
+}
+
+/// Durations of the stages of a conversion, told apart by the progress it
+/// reports
+struct StageTimer {
+    started: Instant,
+    first_page: Cell<Option<Instant>>,
+    ocr: Cell<Option<Instant>>,
+    done: Cell<Option<Instant>>,
+}
+
+impl StageTimer {
+    fn new() -> Self {
+        StageTimer {
+            started: Instant::now(),
+            first_page: Cell::new(None),
+            ocr: Cell::new(None),
+            done: Cell::new(None),
+        }
+    }
+
+    fn record(&self, progress: &Progress) {
+        let stage = match progress {
+            Progress::WritingPage { .. } if self.first_page.get().is_none() => &self.first_page,
+            Progress::ApplyingOcr => &self.ocr,
+            Progress::Done => &self.done,
+            _ => return,
+        };
+        stage.set(Some(Instant::now()));
+    }
+
+    /// Record the durations of the stages, up to now, in `stats`
+    fn finish(&self, stats: &mut ConversionStats) {
+        let now = Instant::now();
+        let first_page = self.first_page.get().unwrap_or(now);
+        let done = self.done.get().unwrap_or(now);
+        let written = self.ocr.get().unwrap_or(done);
+        stats.converting = first_page.saturating_duration_since(self.started);
+        stats.writing = written.saturating_duration_since(first_page);
+        stats.ocr = done.saturating_duration_since(written);
+        stats.total = now.saturating_duration_since(self.started);
+    }
+}
+
+/// Run `convert`, timing its stages through the progress it reports, and
+/// add the durations and the size of the document at `input_path` to the
+/// statistics of its report
+pub(crate) fn timed(
+    input_path: &str,
+    progress: &dyn Fn(Progress),
+    convert: impl FnOnce(&dyn Fn(Progress)) -> Result<ConversionReport>,
+) -> Result<ConversionReport> {
+    let timer = StageTimer::new();
+    let mut report = convert(&|stage| {
+        timer.record(&stage);
+        progress(stage);
+    })?;
+    report.stats.input_bytes = std::fs::metadata(input_path)
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len());
+    timer.finish(&mut report.stats);
+    Ok(report)
+}
+
+/// Peak resident set size of this process, in bytes
+#[cfg(unix)]
+fn peak_rss_bytes() -> Option<u64> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage only writes to the struct it is given
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    // SAFETY: getrusage succeeded, so it filled the struct
+    let max_rss = u64::try_from(unsafe { usage.assume_init() }.ru_maxrss).ok()?;
+    // Kibibytes everywhere but on macOS
+    if cfg!(target_os = "macos") {
+        Some(max_rss)
+    } else {
+        Some(max_rss * 1024)
+    }
+}
+
+#[cfg(not(unix))]
+fn peak_rss_bytes() -> Option<u64> {
+    None
+}
+
+/// Write the OCR results of the safe PDF at `output_pdf` next to it, or warn
+/// in `report` that there are none
+fn write_sidecar(
+    format: OcrSidecar,
+    output_pdf: &Path,
+    sidecar: Option<String>,
+    report: &mut ConversionReport,
+) -> Result<()> {
+    let Some(sidecar) = sidecar else {
+        warn!("No {format} sidecar was written, as there are no OCR results");
+        report.warnings.push(Warning::OcrSidecarMissing);
+        return Ok(());
+    };
+    let path = format.path_for(output_pdf);
+    let path_sanitized = replace_control_chars(&path.to_string_lossy(), false);
+    std::fs::write(&path, sidecar)
+        .with_context(|| format!("Failed to write the OCR sidecar '{path_sanitized}'"))?;
+    info!("OCR results written to: {path_sanitized}");
+    Ok(())
+}
+
+/// Read back the PDF written at `path`: audit its objects, and check its
+/// structure if the options ask for it
+///
+/// With ocrmypdf, the PDF audited is the one the text layer is added to, as
+/// ocrmypdf writes objects this crate doesn't, and hides them in object
+/// streams the audit can't read.
+fn check_written(path: &str, options: &ConversionOptions) -> Result<()> {
+    if options.verify {
+        info!("Verifying the structure of the safe PDF...");
+        validate_pdf(path)?;
+    }
+    audit_pdf(path)
+}
+
+/// PDF writer with the settings of `options`, for the document at `original`
+fn pdf_writer<W: Write>(
+    writer: W,
+    options: &ConversionOptions,
+    original: Option<&str>,
+) -> Result<PdfWriter<W>> {
+    PdfWriter::new(
+        writer,
+        WriterSettings {
+            layout: options.layout.clone(),
+            icc_profile: options.color_profile.load()?,
+            object_streams: options.object_streams,
+            provenance: options.record_provenance.then(|| Provenance::new(original)),
+            outline: options.outline,
+        },
+    )
+}
+
+/// Cover sheet of a conversion of the document at `original`, for
+/// [`ConversionOptions::cover_sheet`]
+fn cover_sheet(
+    original: Option<&str>,
+    ocr_lang: &str,
+    options: &ConversionOptions,
+) -> Result<PageData> {
+    let mut lines = vec![
+        "This document was converted to pixels in a sandbox, and its pixels \
+         turned back into the safe PDF that follows."
+            .to_string(),
+        String::new(),
+    ];
+    match original {
+        Some(path) => {
+            let name = Path::new(path)
+                .file_name()
+                .map_or_else(|| path.into(), |name| name.to_string_lossy());
+            let path_sanitized = replace_control_chars(path, false);
+            let mut file =
+                File::open(path).with_context(|| format!("Failed to open '{path_sanitized}'"))?;
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut file, &mut hasher)
+                .with_context(|| format!("Failed to read '{path_sanitized}'"))?;
+            let hash = format!("{:x}", hasher.finalize());
+            lines.push(format!(
+                "Original: {name_sanitized}",
+                name_sanitized = replace_control_chars(&name, false)
+            ));
+            // Split in halves, so that it wraps in the middle
+            lines.push(format!("SHA-256: {} {}", &hash[..32], &hash[32..]));
+        }
+        None => lines.push("Original: unknown, the pages were read as pixels".to_string()),
+    }
+    let UtcTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    } = SystemTime::now().into();
+    lines.push(format!(
+        "Converted: {year:04}-{month:02}-{day:02} {hour:02}:{minute:02}:{second:02} UTC"
+    ));
+    lines.push(concat!("Converted by: dangerzone-rs ", env!("CARGO_PKG_VERSION")).to_string());
+    lines.push(match options.runtime {
+        Runtime::Bwrap => "Converter: installed locally, run by bubblewrap".to_string(),
+        runtime => format!(
+            "Converter image: {IMAGE_NAME} ({digest})",
+            digest = image_digest(runtime).as_deref().unwrap_or("unknown digest")
+        ),
+    });
+    lines.push(match options.ocr {
+        true => format!("OCR: requested, in {ocr_lang}"),
+        false => "OCR: not requested".to_string(),
+    });
+    Ok(placeholder::sheet("Safe PDF", &lines))
+}
+
+/// `pdf` starting with `cover`, if any, with the words `ocr` finds on it
+fn with_cover<W: Write>(
+    mut pdf: PdfWriter<W>,
+    cover: Option<&PageData>,
+    options: &ConversionOptions,
+    ocr: Option<&dyn OcrEngine>,
+) -> Result<PdfWriter<W>> {
+    if let Some(cover) = cover {
+        pdf.add_page(&encode_page(cover, 0, options, ocr)?)?;
+    }
+    Ok(pdf)
+}
+
+/// Prepare `pages` on the rayon pool and write them to `pdf` in order
+///
+/// With `ocr`, the words found on each page are written with it, and also
+/// returned if the options ask for a sidecar. With `redactor`, the words
+/// matching its patterns are blacked out first. With
+/// [`ConversionOptions::keep_partial`], a failure to receive a page after
+/// the first ends the PDF there, and is returned with the pages written.
+#[allow(clippy::too_many_arguments)]
+fn write_pages<P: PdfPage + Send, W: Write>(
+    pages: impl Iterator<Item = Result<P>>,
+    page_count: &dyn Fn() -> Option<usize>,
+    mut pdf: PdfWriter<W>,
+    options: &ConversionOptions,
+    ocr: Option<&dyn OcrEngine>,
+    redactor: Option<&PatternRedactor>,
+    progress: &dyn Fn(Progress),
+    cancel: &CancellationToken,
+) -> Result<WrittenPages<W>> {
+    let detector = options
+        .auto_orient
+        .then(OrientationDetector::new)
+        .transpose()?;
+    let detector = detector.as_ref();
+    let max_in_flight = if options.low_memory {
+        1
+    } else {
+        rayon::current_num_threads() * PAGES_IN_FLIGHT_PER_THREAD
+    };
+    // Pages written before those of the document, such as a cover sheet
+    let covers = pdf.page_count();
+    let mut received = 0;
+    let mut reported_count = false;
+    let mut text = Vec::new();
+    let mut pixel_bytes = 0;
+    let mut redacted = Vec::new();
+    let mut failure = None;
+    let mut failed_pages = Vec::new();
+    let mut left_out = Vec::new();
+
+    rayon::in_place_scope(|scope| -> Result<()> {
+        let (sender, receiver) = mpsc::channel();
+        let mut pages = pages.fuse();
+        let mut prepared = BTreeMap::new();
+        let mut next = 0;
+        loop {
+            cancel.check()?;
+            // Keep the pool busy while the next page is prepared
+            while failure.is_none() && received - next < max_in_flight {
+                let Some(page) = pages.next() else {
+                    break;
+                };
+                let page = match page {
+                    Ok(page) => page,
+                    Err(e) if options.keep_partial && received > 0 && !e.is::<Cancelled>() => {
+                        failure = Some(PartialFailure {
+                            page: received + 1,
+                            error: e,
+                        });
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                };
+                if !reported_count {
+                    if let Some(total_pages) = page_count() {
+                        progress(Progress::PixelsReceived { total_pages });
+                        reported_count = true;
+                    }
+                }
+                let index = received;
+                received += 1;
+                if page.metadata().failed {
+                    failed_pages.push(index + 1);
+                }
+                let sender = sender.clone();
+                scope.spawn(move |_| {
+                    let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        prepare_page(page, index + 1, options, detector, ocr, redactor)
+                    }))
+                    .unwrap_or_else(|_| {
+                        Err(anyhow::anyhow!("Failed to prepare page {}", index + 1))
+                    });
+                    let _ = sender.send((index, result));
+                });
+            }
+            if next == received {
+                break;
+            }
+
+            while !prepared.contains_key(&next) {
+                let (index, result) = wait_for(&receiver);
+                prepared.insert(index, result);
+            }
+            let (page, matches) = prepared.remove(&next).unwrap()?;
+            next += 1;
+            redacted.extend(matches);
+            match page {
+                Prepared::Page(page) => {
+                    progress(Progress::WritingPage {
+                        page: pdf.page_count() - covers + 1,
+                        total_pages: page_count().unwrap_or(received),
+                    });
+                    pdf.add_page(&page)?;
+                    pixel_bytes += u64::from(page.width)
+                        * u64::from(page.height)
+                        * page.format.bytes_per_pixel() as u64;
+                    if options.ocr_sidecar.is_some() {
+                        text.extend(page.text);
+                    }
+                }
+                Prepared::Blank => {}
+                Prepared::LeftOut => left_out.push(next),
+            }
+        }
+        Ok(())
+    })?;
+
+    if !reported_count {
+        progress(Progress::PixelsReceived {
+            total_pages: received,
+        });
+    }
+    if received == 0 {
+        anyhow::bail!("No pages to convert");
+    }
+    let dropped = received + covers - pdf.page_count() - left_out.len();
+    if dropped > 0 {
+        info!("Dropped {dropped} blank page(s)");
+    }
+    if !left_out.is_empty() {
+        info!("Stages left out {} page(s)", left_out.len());
+    }
+    if pdf.page_count() == covers {
+        if let Some(failure) = failure {
+            return Err(failure.error);
+        }
+        if left_out.is_empty() {
+            anyhow::bail!("All pages are blank");
+        }
+        anyhow::bail!("No pages left: the stages left out every page that isn't blank");
+    }
+    let pages = pdf.page_count();
+    let empty_outline = options.outline && pdf.bookmark_count() == 0;
+    let writer = pdf.finish().context("Failed to write PDF")?;
+    Ok(WrittenPages {
+        dropped,
+        left_out,
+        writer,
+        text,
+        pages,
+        pixel_bytes,
+        redacted,
+        empty_outline,
+        failed_pages,
+        failure,
+    })
+}
+
+/// A page ready to be written, or why it is left out
+enum Prepared {
+    Page(EncodedPage),
+    /// Blank, with [`ConversionOptions::drop_blank_pages`]
+    Blank,
+    /// By one of [`ConversionOptions::stages`]
+    LeftOut,
+}
+
+/// Filter, transform and compress a page, with the text blacked out on it
+fn prepare_page<P: PdfPage>(
+    page: P,
+    page_num: usize,
+    options: &ConversionOptions,
+    detector: Option<&OrientationDetector>,
+    ocr: Option<&dyn OcrEngine>,
+    redactor: Option<&PatternRedactor>,
+) -> Result<(Prepared, Vec<PatternMatch>)> {
+    if options.drop_blank_pages
+        && !page.metadata().failed
+        && (page.metadata().blank
+            || blank::is_blank(&page).context("Failed to read page pixels")?)
+    {
+        return Ok((Prepared::Blank, Vec::new()));
+    }
+
+    let page = redact::Redacted {
+        page: &page,
+        rects: options.redactions.rects(page_num),
+        dpi: options.dpi,
+    };
+    // Matches are found before the page is cleaned up or turned, in the
+    // coordinates of the redaction spec. The text layer, read from the
+    // redacted pixels, no longer has them.
+    let (rects, matches) = match redactor {
+        Some(redactor) => redactor.find(&page, page_num, options.dpi)?,
+        None => (Vec::new(), Vec::new()),
+    };
+    let page = redact::Redacted {
+        page: &page,
+        rects,
+        dpi: options.dpi,
+    };
+    let page = enhance::Cleaned {
+        page: &page,
+        cleanup: &options.page_cleanup,
+    };
+    let mut rotation = options.rotation;
+    if let Some(detector) = detector {
+        rotation += detector.detect(&page, page_num)?;
+    }
+    let page = orient::Oriented {
+        page: &page,
+        rotation,
+    };
+    if !options.stages.is_empty() {
+        let Some(page) = options.stages.run(&page, page_num)? else {
+            return Ok((Prepared::LeftOut, matches));
+        };
+        let page = downscale_page(&page, page_num, options, ocr)?;
+        return Ok((Prepared::Page(page), matches));
+    }
+    let page = downscale_page(&page, page_num, options, ocr)?;
+    Ok((Prepared::Page(page), matches))
+}
+
+/// Compress a page, downscaled to [`ConversionOptions::max_dpi`] if set
+fn downscale_page<P: PdfPage>(
+    page: &P,
+    page_num: usize,
+    options: &ConversionOptions,
+    ocr: Option<&dyn OcrEngine>,
+) -> Result<EncodedPage> {
+    #[cfg(feature = "downscale")]
+    if let Some(max_dpi) = options.max_dpi {
+        let page = crate::downscale::Downscaled::new(page, options.dpi, max_dpi);
+        return encode_page(&page, page_num, options, ocr);
+    }
+    encode_page(page, page_num, options, ocr)
+}
+
+/// Compress a page, with the words `ocr` finds on it
+fn encode_page<P: PdfPage>(
+    page: &P,
+    page_num: usize,
+    options: &ConversionOptions,
+    ocr: Option<&dyn OcrEngine>,
+) -> Result<EncodedPage> {
+    let mut encoded = EncodedPage::in_strips(
+        page,
+        options.dpi,
+        &options.compression,
+        options.image_strip(),
+    )?;
+    if let Some(engine) = ocr {
+        let ppi = page.width() as f32 * 72.0 / encoded.size_pts.0;
+        let image = ocr::page_image(page).context("Failed to read page pixels")?;
+        encoded.text = Some(
+            engine
+                .recognize_page(&image, ppi)
+                .with_context(|| format!("OCR of page {page_num} failed"))?,
+        );
+    }
+    Ok(encoded)
+}
+
+/// Wait for a prepared page, running other tasks of the pool in the meantime
+/// if called from one of its threads, which would otherwise be lost to it
+fn wait_for<T>(receiver: &Receiver<T>) -> T {
+    loop {
+        match receiver.try_recv() {
+            Ok(value) => return value,
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) => unreachable!("the sender outlives the receiver"),
+        }
+        match rayon::yield_now() {
+            Some(rayon::Yield::Executed) => {}
+            Some(rayon::Yield::Idle) => {
+                if let Ok(value) = receiver.recv_timeout(Duration::from_millis(1)) {
+                    return value;
+                }
+            }
+            None => return receiver.recv().expect("the sender outlives the receiver"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hocr::{OcrBlock, OcrLine, OcrParagraph, OcrWord};
+
+    /// Engine finding one word on each page: its width
+    struct PageWidths;
+
+    impl OcrEngine for PageWidths {
+        fn name(&self) -> &'static str {
+            "page widths"
+        }
+
+        fn reads_pixels(&self) -> bool {
+            true
+        }
+
+        fn recognize_page(&self, page: &PageData, _ppi: f32) -> Result<OcrPage> {
+            let bbox = [0, 0, page.width as u32, page.height as u32];
+            Ok(OcrPage {
+                bbox,
+                blocks: vec![OcrBlock {
+                    bbox,
+                    paragraphs: vec![OcrParagraph {
+                        bbox,
+                        lang: None,
+                        lines: vec![OcrLine {
+                            bbox,
+                            baseline: None,
+                            words: vec![OcrWord {
+                                bbox,
+                                confidence: None,
+                                text: page.width.to_string(),
+                            }],
+                        }],
+                    }],
+                }],
+            })
+        }
+    }
+
+    #[test]
+    fn test_pixel_engine_text_layer() {
+        let pages = (1..=20).map(|width| Ok(PageData::new(width, 2, vec![0; width as usize * 6])));
+        let options = ConversionOptions {
+            ocr: true,
+            ocr_sidecar: Some(OcrSidecar::Hocr),
+            ..ConversionOptions::default()
+        };
+        let WrittenPages {
+            writer: pdf, text, ..
+        } = write_pages(
+            pages,
+            &|| None,
+            pdf_writer(Vec::new(), &options, None).unwrap(),
+            &options,
+            Some(&PageWidths),
+            None,
+            &|_| {},
+            &CancellationToken::new(),
+        )
+        .unwrap();
+
+        // Words of each page, in order
+        let words: Vec<&str> = text
+            .iter()
+            .map(|page| page.blocks[0].paragraphs[0].lines[0].words[0].text.as_str())
+            .collect();
+        let widths: Vec<String> = (1..=20).map(|width: u16| width.to_string()).collect();
+        assert_eq!(words, widths);
+
+        // One font, used by the text layer of every page
+        crate::validate::validate(&mut std::io::Cursor::new(&pdf)).unwrap();
+        crate::validate::audit(&mut std::io::Cursor::new(&pdf)).unwrap();
+        let pdf = String::from_utf8_lossy(&pdf);
+        assert_eq!(pdf.matches("/Subtype /Type0").count(), 1);
+        assert_eq!(pdf.matches("/Font << /FOcr").count(), 20);
+    }
+
+    #[test]
+    fn test_redact_patterns() {
+        let pages =
+            (1..=12).map(|width| Ok(PageData::new(width, 2, vec![255; width as usize * 6])));
+        let options = ConversionOptions {
+            redact_patterns: vec!["^1[01]$".to_string()],
+            ..ConversionOptions::default()
+        };
+        let redactor = PatternRedactor::new(&PageWidths, &options.redact_patterns).unwrap();
+        let WrittenPages {
+            writer, redacted, ..
+        } = write_pages(
+            pages,
+            &|| None,
+            pdf_writer(Vec::new(), &options, None).unwrap(),
+            &options,
+            None,
+            Some(&redactor),
+            &|_| {},
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        let found: Vec<(usize, &str)> = redacted
+            .iter()
+            .map(|found| (found.page, found.text.as_str()))
+            .collect();
+        assert_eq!(found, [(10, "10"), (11, "11")]);
+        assert!(redacted.iter().all(|found| found.pattern == "^1[01]$"));
+        crate::validate::validate(&mut std::io::Cursor::new(&writer)).unwrap();
+    }
+
+    #[test]
+    fn test_failed_pages() {
+        let page = || Ok(PageData::new(1, 2, vec![0; 6]));
+        let pages = [page(), Ok(crate::placeholder::failed_page(2)), page()];
+        // Placeholders are never dropped as blank, unlike the other pages
+        let options = ConversionOptions {
+            drop_blank_pages: true,
+            ..ConversionOptions::default()
+        };
+        let written = write_pages(
+            pages.into_iter(),
+            &|| None,
+            pdf_writer(Vec::new(), &options, None).unwrap(),
+            &options,
+            None,
+            None,
+            &|_| {},
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        assert_eq!(written.pages, 1);
+        assert_eq!(written.failed_pages, [2]);
+
+        let mut report = ConversionReport::default();
+        written.record(&mut report, Path::new("/nonexistent"));
+        assert_eq!(
+            report.warnings[0].to_string(),
+            "Page(s) 2 failed to convert and were replaced by placeholders"
+        );
+    }
+
+    /// Stage leaving out the pages narrower than 2 pixels, and widening the
+    /// others
+    struct WidenOrDrop;
+
+    impl crate::stage::Stage for WidenOrDrop {
+        fn process(&self, page: PageData, _page_num: usize) -> Result<Option<PageData>> {
+            if page.width < 2 {
+                return Ok(None);
+            }
+            let pixels = page.pixels.repeat(2);
+            Ok(Some(PageData::new(page.width * 2, page.height, pixels)))
+        }
+    }
+
+    #[test]
+    fn test_stages() {
+        let pages = [
+            Ok(PageData::new(2, 1, vec![0; 6])),
+            Ok(PageData::new(1, 1, vec![0; 3])),
+        ];
+        let mut options = ConversionOptions::default();
+        options.stages.push(WidenOrDrop);
+        let written = write_pages(
+            pages.into_iter(),
+            &|| None,
+            pdf_writer(Vec::new(), &options, None).unwrap(),
+            &options,
+            None,
+            None,
+            &|_| {},
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        assert_eq!(written.pages, 1);
+        assert_eq!(written.dropped, 0);
+        assert_eq!(written.left_out, [2]);
+        assert_eq!(written.pixel_bytes, 4 * 3);
+
+        let mut report = ConversionReport::default();
+        written.record(&mut report, Path::new("/nonexistent"));
+        assert_eq!(report.warnings, [Warning::PagesLeftOut { pages: vec![2] }]);
+    }
+
+    #[test]
+    fn test_keep_partial() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("safe.pdf").to_string_lossy().into_owned();
+        let pages = || {
+            (1..=3)
+                .map(|width| Ok(PageData::new(width, 2, vec![0; width as usize * 6])))
+                .chain([Err(anyhow::anyhow!("The converter crashed"))])
+        };
+        let convert = |options: &ConversionOptions| {
+            write_safe_pdf(
+                pages(),
+                &|| Some(5),
+                None,
+                output.clone(),
+                options,
+                &|_| {},
+                &CancellationToken::new(),
+            )
+        };
+
+        let e = convert(&ConversionOptions::default()).unwrap_err();
+        assert_eq!(e.to_string(), "The converter crashed");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        let options = ConversionOptions {
+            keep_partial: true,
+            ..ConversionOptions::default()
+        };
+        let e = convert(&options).unwrap_err();
+        let partial = e.downcast_ref::<PartialConversion>().unwrap();
+        assert_eq!(
+            partial.path,
+            dir.path().join("safe.partial.pdf").to_str().unwrap()
+        );
+        assert_eq!((partial.pages, partial.failed_page), (3, 4));
+        assert!(format!("{e:#}").ends_with(": The converter crashed"));
+        assert!(!Path::new(&output).exists());
+        validate_pdf(&partial.path).unwrap();
+
+        // Nothing is kept when the first page fails
+        let _ = std::fs::remove_file(&partial.path);
+        let e = write_safe_pdf(
+            pages().skip(3),
+            &|| None,
+            None,
+            output.clone(),
+            &options,
+            &|_| {},
+            &CancellationToken::new(),
+        )
+        .unwrap_err();
+        assert!(e.downcast_ref::<PartialConversion>().is_none());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        assert_eq!(partial_path("out/report"), "out/report.partial");
+    }
+
+    #[test]
+    fn test_cover_sheet() {
+        let dir = tempfile::tempdir().unwrap();
+        let original = dir.path().join("report.docx");
+        std::fs::write(&original, "unsafe").unwrap();
+        let original = original.to_string_lossy().into_owned();
+        let output = dir.path().join("safe.pdf").to_string_lossy().into_owned();
+        let options = ConversionOptions {
+            cover_sheet: true,
+            runtime: Runtime::Bwrap,
+            ..ConversionOptions::default()
+        };
+        let convert = |original: Option<&str>, options: &ConversionOptions| {
+            let pages = (0..2).map(|_| Ok(PageData::new(1, 2, vec![0; 6])));
+            write_safe_pdf(
+                pages,
+                &|| Some(2),
+                original,
+                output.clone(),
+                options,
+                &|_| {},
+                &CancellationToken::new(),
+            )
+        };
+
+        let report = convert(Some(&original), &options).unwrap();
+        assert_eq!(report.stats.pages, 3);
+        validate_pdf(&output).unwrap();
+        // Pixels read from a file have no original to describe
+        assert_eq!(convert(None, &options).unwrap().stats.pages, 3);
+
+        // The cover sheet alone isn't a safe PDF
+        let blank = ConversionOptions {
+            drop_blank_pages: true,
+            ..options.clone()
+        };
+        let e = convert(Some(&original), &blank).unwrap_err();
+        assert_eq!(e.to_string(), "All pages are blank");
+
+        let missing = dir.path().join("missing.docx");
+        assert!(convert(Some(&missing.to_string_lossy()), &options).is_err());
+    }
+
+    #[test]
+    fn test_low_memory() {
+        let options = ConversionOptions {
+            low_memory: true,
+            ..ConversionOptions::default()
+        };
+        assert_eq!(options.buffered_pages(), 1);
+        assert_eq!(
+            options.spool_threshold(),
+            Some(crate::options::LOW_MEMORY_SPOOL_BYTES)
+        );
+        let options = ConversionOptions {
+            image_strip_bytes: Some(6),
+            ..options
+        };
+        assert_eq!(options.image_strip(), Some(6));
+
+        // Pages are still written in order, one at a time
+        let pages = (1..=5).map(|width| Ok(PageData::new(width, 2, vec![0; width as usize * 6])));
+        let pdf = write_pages(
+            pages,
+            &|| None,
+            pdf_writer(Vec::new(), &options, None).unwrap(),
+            &options,
+            None,
+            None,
+            &|_| {},
+            &CancellationToken::new(),
+        )
+        .unwrap()
+        .writer;
+        crate::validate::validate(&mut std::io::Cursor::new(&pdf)).unwrap();
+        let pdf = String::from_utf8_lossy(&pdf);
+        let widths: Vec<&str> = pdf
+            .split("/Width ")
+            .skip(1)
+            .map(|rest| rest.split_whitespace().next().unwrap())
+            .collect();
+        // Both rows of the first page fit in a strip of 6 bytes, those of
+        // the wider pages each need one
+        assert_eq!(widths, ["1", "2", "2", "3", "3", "4", "4", "5", "5"]);
+
+        #[cfg(unix)]
+        assert!(peak_rss_bytes().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_timed_stages() {
+        let input = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(input.path(), [0; 100]).unwrap();
+        let stages = std::cell::RefCell::new(Vec::new());
+        let report = timed(
+            input.path().to_str().unwrap(),
+            &|stage| stages.borrow_mut().push(stage),
+            |progress| {
+                progress(Progress::ConvertingToPixels);
+                std::thread::sleep(Duration::from_millis(20));
+                progress(Progress::WritingPage {
+                    page: 1,
+                    total_pages: 1,
+                });
+                std::thread::sleep(Duration::from_millis(20));
+                progress(Progress::Done);
+                let mut report = ConversionReport::default();
+                report.stats.pages = 1;
+                report.stats.pixel_bytes = 3000;
+                report.stats.output_bytes = 1000;
+                Ok(report)
+            },
+        )
+        .unwrap();
+        // The stages still reach the caller
+        assert_eq!(stages.borrow().len(), 3);
+
+        let stats = &report.stats;
+        assert_eq!(stats.input_bytes, Some(100));
+        assert!(stats.converting >= Duration::from_millis(20));
+        assert!(stats.writing >= Duration::from_millis(20));
+        assert_eq!(stats.ocr, Duration::ZERO);
+        assert!(stats.total >= stats.converting + stats.writing);
+        assert_eq!(stats.compression_ratio(), Some(3.0));
+        assert!(stats.pages_per_second().unwrap() > 0.0);
+        assert_eq!(ConversionStats::default().compression_ratio(), None);
+    }
+}
@@ -0,0 +1,341 @@
+//! Pages written as they came out of the sandbox, for other tools
+//!
+//! Each page is a file of raw pixels, row by row without padding, named
+//! after its number and its format: `page-0001.rgb`, `page-0002.gray` or
+//! `page-0003.rgba`. `index.json` lists the pages in order, with their size
+//! in pixels, their format and what the converter said about them:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "pages": [
+//!     {"file": "page-0001.rgb", "width": 1275, "height": 1650, "format": "rgb",
+//!      "bytes_per_pixel": 3, "rotation": 0, "size_pts": null, "blank": false,
+//!      "failed": false}
+//!   ]
+//! }
+//! ```
+//!
+//! Directories in this layout, written by this crate or by other tools, can
+//! be turned back into pages with `read_pages`, to build the safe PDF on
+//! another host.
+
+#[cfg(all(feature = "serde", feature = "container"))]
+use crate::{protocol::count_pixels, PageData, PageMetadata};
+use crate::{CancellationToken, PdfPage, PixelFormat, Progress};
+use anyhow::{Context, Result};
+use std::fs::File;
+#[cfg(all(feature = "serde", feature = "container"))]
+use std::io::Read;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// File name of the index of the pages, in the output directory
+pub const INDEX_NAME: &str = "index.json";
+
+/// Version of the layout of the index, raised when it changes incompatibly
+const INDEX_VERSION: u32 = 1;
+
+fn extension(format: PixelFormat) -> &'static str {
+    match format {
+        PixelFormat::Rgb => "rgb",
+        PixelFormat::Gray => "gray",
+        PixelFormat::Rgba => "rgba",
+    }
+}
+
+/// Write each page of `pages` to `dir`, then the index of all of them,
+/// returning the number of pages
+///
+/// `page_count` returns the number of pages of the document, once known.
+pub(crate) fn write_pages<P: PdfPage>(
+    pages: impl Iterator<Item = Result<P>>,
+    page_count: &dyn Fn() -> Option<usize>,
+    dir: &Path,
+    progress: &dyn Fn(Progress),
+    cancel: &CancellationToken,
+) -> Result<usize> {
+    std::fs::create_dir_all(dir).context("Failed to create the pixel directory")?;
+    let mut entries = Vec::new();
+    for (index, page) in pages.enumerate() {
+        cancel.check()?;
+        let page = page?;
+        let file_name = format!("page-{:04}.{}", index + 1, extension(page.format()));
+        progress(Progress::WritingPage {
+            page: index + 1,
+            total_pages: page_count().unwrap_or(index + 1),
+        });
+        let file =
+            File::create(dir.join(&file_name)).context(format!("Failed to create {file_name}"))?;
+        let mut writer = BufWriter::new(file);
+        page.write_pixels(&mut writer)
+            .and_then(|()| writer.flush())
+            .context(format!("Failed to write {file_name}"))?;
+        entries.push(index_entry(&file_name, &page));
+    }
+    if entries.is_empty() {
+        anyhow::bail!("No pages to write");
+    }
+
+    let index = format!(
+        "{{\n  \"version\": {INDEX_VERSION},\n  \"pages\": [\n    {}\n  ]\n}}\n",
+        entries.join(",\n    ")
+    );
+    std::fs::write(dir.join(INDEX_NAME), index).context("Failed to write the pixel index")?;
+    progress(Progress::Done);
+    Ok(entries.len())
+}
+
+/// The entry of `page` in the index, as a JSON object on one line
+fn index_entry<P: PdfPage>(file_name: &str, page: &P) -> String {
+    let metadata = page.metadata();
+    // Page sizes are finite, as they were checked when the stream was parsed
+    let size_pts = metadata.size_pts.map_or_else(
+        || "null".to_string(),
+        |(width, height)| format!("[{width}, {height}]"),
+    );
+    format!(
+        "{{\"file\": \"{file_name}\", \"width\": {}, \"height\": {}, \"format\": \"{}\", \
+         \"bytes_per_pixel\": {}, \"rotation\": {}, \"size_pts\": {size_pts}, \"blank\": {}, \
+         \"failed\": {}}}",
+        page.width(),
+        page.height(),
+        extension(page.format()),
+        page.format().bytes_per_pixel(),
+        metadata.rotation,
+        metadata.blank,
+        metadata.failed,
+    )
+}
+
+/// Index of a directory of pages, as [`write_pages`] writes it
+#[cfg(all(feature = "serde", feature = "container"))]
+#[derive(serde::Deserialize)]
+struct Index {
+    version: u32,
+    pages: Vec<IndexEntry>,
+}
+
+/// Entry of a page in the index; `bytes_per_pixel` follows from the format
+/// and is ignored
+#[cfg(all(feature = "serde", feature = "container"))]
+#[derive(serde::Deserialize)]
+struct IndexEntry {
+    file: String,
+    width: u16,
+    height: u16,
+    format: PixelFormat,
+    #[serde(flatten)]
+    metadata: PageMetadata,
+}
+
+/// Read the index of the pages in `dir`, returning the number of pages and
+/// an iterator reading each of them in turn, failing with
+/// [`OutputTooLarge`](crate::OutputTooLarge) once they hold more than
+/// `limit` bytes of pixels in total
+///
+/// The index is checked as a whole before any page is read: its page files
+/// must be plain names inside `dir`, and its page sizes and metadata must
+/// be ones a safe PDF can have.
+#[cfg(all(feature = "serde", feature = "container"))]
+pub(crate) fn read_pages(
+    dir: &Path,
+    limit: u64,
+) -> Result<(usize, impl Iterator<Item = Result<PageData>>)> {
+    let index = std::fs::read(dir.join(INDEX_NAME)).context("Failed to read the pixel index")?;
+    let index: Index = serde_json::from_slice(&index).context("Invalid pixel index")?;
+    if index.version != INDEX_VERSION {
+        anyhow::bail!("Unsupported pixel index version {}", index.version);
+    }
+    if index.pages.is_empty() {
+        anyhow::bail!("The pixel index lists no pages");
+    }
+    if index.pages.len() > u16::MAX.into() {
+        anyhow::bail!("The pixel index lists too many pages");
+    }
+    for (page_num, entry) in (0..).zip(&index.pages) {
+        check_entry(entry, page_num)?;
+    }
+
+    let page_count = index.pages.len();
+    let dir = dir.to_path_buf();
+    let mut total_bytes = 0;
+    let pages = (0..)
+        .zip(index.pages)
+        .map(move |(page_num, entry)| read_page(&dir, entry, page_num, &mut total_bytes, limit));
+    Ok((page_count, pages))
+}
+
+#[cfg(all(feature = "serde", feature = "container"))]
+fn check_entry(entry: &IndexEntry, page_num: u16) -> Result<()> {
+    let path = Path::new(&entry.file);
+    if path.file_name() != Some(path.as_os_str()) {
+        anyhow::bail!("Invalid file name for page {}", page_num + 1);
+    }
+    if entry.width == 0 || entry.height == 0 {
+        anyhow::bail!("Invalid dimensions for page {}", page_num + 1);
+    }
+    entry.metadata.check(page_num)
+}
+
+#[cfg(all(feature = "serde", feature = "container"))]
+fn read_page(
+    dir: &Path,
+    entry: IndexEntry,
+    page_num: u16,
+    total_bytes: &mut u64,
+    limit: u64,
+) -> Result<PageData> {
+    let num_bytes =
+        usize::from(entry.width) * usize::from(entry.height) * entry.format.bytes_per_pixel();
+    count_pixels(total_bytes, num_bytes, limit)?;
+    let context = || format!("Failed to read page {} pixels", page_num + 1);
+    let file = File::open(dir.join(&entry.file)).with_context(context)?;
+    // Reading one byte more than the page needs tells a longer file apart,
+    // and the buffer grows as data arrives rather than trusting the index
+    let mut pixels = Vec::new();
+    file.take(num_bytes as u64 + 1)
+        .read_to_end(&mut pixels)
+        .with_context(context)?;
+    if pixels.len() != num_bytes {
+        anyhow::bail!(
+            "Page {} should hold {num_bytes} bytes of pixels, not {}",
+            page_num + 1,
+            pixels.len()
+        );
+    }
+    let mut page = PageData::with_format(entry.width, entry.height, entry.format, pixels);
+    page.metadata = entry.metadata;
+    Ok(page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PageData, PageMetadata};
+
+    #[test]
+    fn test_write_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gray = PageData::with_format(2, 1, PixelFormat::Gray, vec![0, 255]);
+        gray.metadata = PageMetadata {
+            rotation: 90,
+            size_pts: Some((612.0, 792.5)),
+            blank: false,
+            failed: false,
+        };
+        let pages = vec![PageData::new(1, 2, vec![1, 2, 3, 4, 5, 6]), gray];
+        let count = write_pages(
+            pages.into_iter().map(Ok),
+            &|| Some(2),
+            dir.path(),
+            &|_| {},
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        assert_eq!(count, 2);
+
+        assert_eq!(
+            std::fs::read(dir.path().join("page-0001.rgb")).unwrap(),
+            [1, 2, 3, 4, 5, 6]
+        );
+        assert_eq!(
+            std::fs::read(dir.path().join("page-0002.gray")).unwrap(),
+            [0, 255]
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join(INDEX_NAME)).unwrap(),
+            "{\n  \"version\": 1,\n  \"pages\": [\n    \
+             {\"file\": \"page-0001.rgb\", \"width\": 1, \"height\": 2, \"format\": \"rgb\", \
+             \"bytes_per_pixel\": 3, \"rotation\": 0, \"size_pts\": null, \"blank\": false, \"failed\": false},\n    \
+             {\"file\": \"page-0002.gray\", \"width\": 2, \"height\": 1, \"format\": \"gray\", \
+             \"bytes_per_pixel\": 1, \"rotation\": 90, \"size_pts\": [612, 792.5], \"blank\": false, \
+             \"failed\": false}\n  \
+             ]\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_write_no_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let pages: Vec<Result<PageData>> = Vec::new();
+        assert!(write_pages(
+            pages.into_iter(),
+            &|| None,
+            dir.path(),
+            &|_| {},
+            &CancellationToken::new()
+        )
+        .is_err());
+        assert!(!dir.path().join(INDEX_NAME).exists());
+    }
+
+    #[cfg(all(feature = "serde", feature = "container"))]
+    #[test]
+    fn test_read_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gray = PageData::with_format(2, 1, PixelFormat::Gray, vec![0, 255]);
+        gray.metadata = PageMetadata {
+            rotation: 270,
+            size_pts: Some((612.0, 792.0)),
+            blank: true,
+            failed: false,
+        };
+        let pages = vec![PageData::new(1, 2, vec![1, 2, 3, 4, 5, 6]), gray];
+        write_pages(
+            pages.iter().map(Ok),
+            &|| Some(2),
+            dir.path(),
+            &|_| {},
+            &CancellationToken::new(),
+        )
+        .unwrap();
+
+        let (count, read) = read_pages(dir.path(), u64::MAX).unwrap();
+        assert_eq!(count, 2);
+        let read: Vec<PageData> = read.collect::<Result<_>>().unwrap();
+        for (read, page) in read.iter().zip(&pages) {
+            assert_eq!(
+                (read.width, read.height, read.format, read.metadata),
+                (page.width, page.height, page.format, page.metadata)
+            );
+            assert_eq!(read.pixels, page.pixels);
+        }
+
+        let (_, mut read) = read_pages(dir.path(), 7).unwrap();
+        assert!(read.next().unwrap().is_ok());
+        let err = read.next().unwrap().unwrap_err();
+        assert!(err.downcast_ref::<crate::OutputTooLarge>().is_some());
+    }
+
+    #[cfg(all(feature = "serde", feature = "container"))]
+    #[test]
+    fn test_read_invalid_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let read_index = |index: &str| {
+            std::fs::write(dir.path().join(INDEX_NAME), index).unwrap();
+            read_pages(dir.path(), u64::MAX).map(|(_, pages)| pages.collect::<Result<Vec<_>>>())
+        };
+        let page = |file: &str, extra: &str| {
+            format!(
+                "{{\"version\": 1, \"pages\": [{{\"file\": \"{file}\", \"width\": 2, \
+                 \"height\": 1, \"format\": \"gray\"{extra}}}]}}"
+            )
+        };
+        std::fs::write(dir.path().join("page.gray"), [0, 255]).unwrap();
+        assert!(read_index(&page("page.gray", "")).unwrap().is_ok());
+
+        assert!(read_index("{\"version\": 2, \"pages\": []}").is_err());
+        assert!(read_index("{\"version\": 1, \"pages\": []}").is_err());
+        assert!(read_index(&page("../page.gray", "")).is_err());
+        assert!(read_index(&page("/etc/passwd", "")).is_err());
+        assert!(read_index(&page("..", "")).is_err());
+        assert!(read_index(&page("page.gray", ", \"rotation\": 45")).is_err());
+        assert!(read_index(&page("page.gray", ", \"size_pts\": [0, 792]")).is_err());
+
+        std::fs::write(dir.path().join("short.gray"), [0]).unwrap();
+        assert!(read_index(&page("short.gray", "")).unwrap().is_err());
+        std::fs::write(dir.path().join("long.gray"), [0, 1, 2]).unwrap();
+        assert!(read_index(&page("long.gray", "")).unwrap().is_err());
+    }
+}
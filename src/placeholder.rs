@@ -0,0 +1,376 @@
+//! Pages generated on the host: placeholders for pages the converter failed
+//! to convert, separators and cover sheets
+//!
+//! Their text is drawn with an embedded 5×7 bitmap font of the printable
+//! ASCII characters, so no font file or text engine is needed; other
+//! characters are drawn as `?`. Pages are the size of a US Letter page, in
+//! grayscale pixels at [`PPI`] pixels per inch, and sized from their
+//! metadata.
+
+use crate::{PageData, PageMetadata, PixelFormat};
+
+/// Size of generated pages, in points
+const SIZE_PTS: (f32, f32) = (612.0, 792.0);
+
+/// Pixels per inch of generated pages
+const PPI: usize = 100;
+
+/// Margin around the text, in pixels
+const MARGIN: usize = PPI;
+
+/// Scale of the font of titles and of the other lines, in pixels per dot of
+/// a glyph
+const TITLE_SCALE: usize = 4;
+const TEXT_SCALE: usize = 2;
+
+#[cfg_attr(not(feature = "container"), allow(dead_code))]
+const WHITE: u8 = 0xff;
+const LIGHT_GRAY: u8 = 0xe6;
+const INK: u8 = 0x00;
+
+/// Columns of a glyph, and the column between two glyphs
+const GLYPH_WIDTH: usize = 5;
+const ADVANCE: usize = GLYPH_WIDTH + 1;
+/// Rows of a glyph, and the rows of a line of text
+const GLYPH_HEIGHT: usize = 7;
+const LINE_HEIGHT: usize = 10;
+
+/// Glyphs of the printable ASCII characters, from ' ' to '~': 5 columns,
+/// from the left, whose bits are the dots from the top
+const FONT: [[u8; GLYPH_WIDTH]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x5f, 0x00, 0x00], // '!'
+    [0x00, 0x07, 0x00, 0x07, 0x00], // '"'
+    [0x14, 0x7f, 0x14, 0x7f, 0x14], // '#'
+    [0x24, 0x2a, 0x7f, 0x2a, 0x12], // '$'
+    [0x23, 0x13, 0x08, 0x64, 0x62], // '%'
+    [0x36, 0x49, 0x56, 0x20, 0x50], // '&'
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '\''
+    [0x00, 0x1c, 0x22, 0x41, 0x00], // '('
+    [0x00, 0x41, 0x22, 0x1c, 0x00], // ')'
+    [0x08, 0x2a, 0x1c, 0x2a, 0x08], // '*'
+    [0x08, 0x08, 0x3e, 0x08, 0x08], // '+'
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ','
+    [0x08, 0x08, 0x08, 0x08, 0x08], // '-'
+    [0x00, 0x60, 0x60, 0x00, 0x00], // '.'
+    [0x20, 0x10, 0x08, 0x04, 0x02], // '/'
+    [0x3e, 0x51, 0x49, 0x45, 0x3e], // '0'
+    [0x00, 0x42, 0x7f, 0x40, 0x00], // '1'
+    [0x42, 0x61, 0x51, 0x49, 0x46], // '2'
+    [0x21, 0x41, 0x45, 0x4b, 0x31], // '3'
+    [0x18, 0x14, 0x12, 0x7f, 0x10], // '4'
+    [0x27, 0x45, 0x45, 0x45, 0x39], // '5'
+    [0x3c, 0x4a, 0x49, 0x49, 0x30], // '6'
+    [0x01, 0x71, 0x09, 0x05, 0x03], // '7'
+    [0x36, 0x49, 0x49, 0x49, 0x36], // '8'
+    [0x06, 0x49, 0x49, 0x29, 0x1e], // '9'
+    [0x00, 0x36, 0x36, 0x00, 0x00], // ':'
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ';'
+    [0x08, 0x14, 0x22, 0x41, 0x00], // '<'
+    [0x14, 0x14, 0x14, 0x14, 0x14], // '='
+    [0x00, 0x41, 0x22, 0x14, 0x08], // '>'
+    [0x02, 0x01, 0x51, 0x09, 0x06], // '?'
+    [0x32, 0x49, 0x79, 0x41, 0x3e], // '@'
+    [0x7e, 0x11, 0x11, 0x11, 0x7e], // 'A'
+    [0x7f, 0x49, 0x49, 0x49, 0x36], // 'B'
+    [0x3e, 0x41, 0x41, 0x41, 0x22], // 'C'
+    [0x7f, 0x41, 0x41, 0x22, 0x1c], // 'D'
+    [0x7f, 0x49, 0x49, 0x49, 0x41], // 'E'
+    [0x7f, 0x09, 0x09, 0x09, 0x01], // 'F'
+    [0x3e, 0x41, 0x49, 0x49, 0x7a], // 'G'
+    [0x7f, 0x08, 0x08, 0x08, 0x7f], // 'H'
+    [0x00, 0x41, 0x7f, 0x41, 0x00], // 'I'
+    [0x20, 0x40, 0x41, 0x3f, 0x01], // 'J'
+    [0x7f, 0x08, 0x14, 0x22, 0x41], // 'K'
+    [0x7f, 0x40, 0x40, 0x40, 0x40], // 'L'
+    [0x7f, 0x02, 0x0c, 0x02, 0x7f], // 'M'
+    [0x7f, 0x04, 0x08, 0x10, 0x7f], // 'N'
+    [0x3e, 0x41, 0x41, 0x41, 0x3e], // 'O'
+    [0x7f, 0x09, 0x09, 0x09, 0x06], // 'P'
+    [0x3e, 0x41, 0x51, 0x21, 0x5e], // 'Q'
+    [0x7f, 0x09, 0x19, 0x29, 0x46], // 'R'
+    [0x46, 0x49, 0x49, 0x49, 0x31], // 'S'
+    [0x01, 0x01, 0x7f, 0x01, 0x01], // 'T'
+    [0x3f, 0x40, 0x40, 0x40, 0x3f], // 'U'
+    [0x1f, 0x20, 0x40, 0x20, 0x1f], // 'V'
+    [0x3f, 0x40, 0x38, 0x40, 0x3f], // 'W'
+    [0x63, 0x14, 0x08, 0x14, 0x63], // 'X'
+    [0x07, 0x08, 0x70, 0x08, 0x07], // 'Y'
+    [0x61, 0x51, 0x49, 0x45, 0x43], // 'Z'
+    [0x00, 0x7f, 0x41, 0x41, 0x00], // '['
+    [0x02, 0x04, 0x08, 0x10, 0x20], // '\\'
+    [0x00, 0x41, 0x41, 0x7f, 0x00], // ']'
+    [0x04, 0x02, 0x01, 0x02, 0x04], // '^'
+    [0x40, 0x40, 0x40, 0x40, 0x40], // '_'
+    [0x00, 0x01, 0x02, 0x04, 0x00], // '`'
+    [0x20, 0x54, 0x54, 0x54, 0x78], // 'a'
+    [0x7f, 0x48, 0x44, 0x44, 0x38], // 'b'
+    [0x38, 0x44, 0x44, 0x44, 0x20], // 'c'
+    [0x38, 0x44, 0x44, 0x48, 0x7f], // 'd'
+    [0x38, 0x54, 0x54, 0x54, 0x18], // 'e'
+    [0x08, 0x7e, 0x09, 0x01, 0x02], // 'f'
+    [0x0c, 0x52, 0x52, 0x52, 0x3e], // 'g'
+    [0x7f, 0x08, 0x04, 0x04, 0x78], // 'h'
+    [0x00, 0x44, 0x7d, 0x40, 0x00], // 'i'
+    [0x20, 0x40, 0x44, 0x3d, 0x00], // 'j'
+    [0x7f, 0x10, 0x28, 0x44, 0x00], // 'k'
+    [0x00, 0x41, 0x7f, 0x40, 0x00], // 'l'
+    [0x7c, 0x04, 0x18, 0x04, 0x78], // 'm'
+    [0x7c, 0x08, 0x04, 0x04, 0x78], // 'n'
+    [0x38, 0x44, 0x44, 0x44, 0x38], // 'o'
+    [0x7c, 0x14, 0x14, 0x14, 0x08], // 'p'
+    [0x08, 0x14, 0x14, 0x18, 0x7c], // 'q'
+    [0x7c, 0x08, 0x04, 0x04, 0x08], // 'r'
+    [0x48, 0x54, 0x54, 0x54, 0x20], // 's'
+    [0x04, 0x3f, 0x44, 0x40, 0x20], // 't'
+    [0x3c, 0x40, 0x40, 0x20, 0x7c], // 'u'
+    [0x1c, 0x20, 0x40, 0x20, 0x1c], // 'v'
+    [0x3c, 0x40, 0x30, 0x40, 0x3c], // 'w'
+    [0x44, 0x28, 0x10, 0x28, 0x44], // 'x'
+    [0x0c, 0x50, 0x50, 0x50, 0x3c], // 'y'
+    [0x44, 0x64, 0x54, 0x4c, 0x44], // 'z'
+    [0x00, 0x08, 0x36, 0x41, 0x00], // '{'
+    [0x00, 0x00, 0x7f, 0x00, 0x00], // '|'
+    [0x00, 0x41, 0x36, 0x08, 0x00], // '}'
+    [0x10, 0x08, 0x08, 0x10, 0x08], // '~'
+];
+
+/// Glyph of `c`, or of `?` if the font doesn't have it
+fn glyph(c: char) -> &'static [u8; GLYPH_WIDTH] {
+    let index = match c {
+        ' '..='~' => c as usize - ' ' as usize,
+        _ => '?' as usize - ' ' as usize,
+    };
+    &FONT[index]
+}
+
+/// Grayscale pixels text is drawn on
+struct Canvas {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+/// Size of generated pages, in pixels
+const WIDTH: usize = (SIZE_PTS.0 as usize * PPI).div_ceil(72);
+const HEIGHT: usize = (SIZE_PTS.1 as usize * PPI).div_ceil(72);
+
+/// Bytes of pixels of a generated page
+pub(crate) const PAGE_BYTES: usize = WIDTH * HEIGHT;
+
+impl Canvas {
+    /// Canvas of a whole page, filled with `background`
+    fn page(background: u8) -> Self {
+        let (width, height) = (WIDTH, HEIGHT);
+        Canvas {
+            width,
+            height,
+            pixels: vec![background; width * height],
+        }
+    }
+
+    /// Fill the rectangle at `x`, `y` of `width` by `height` pixels,
+    /// clipped to the canvas
+    fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, value: u8) {
+        let columns = x.min(self.width)..(x + width).min(self.width);
+        for row in y.min(self.height)..(y + height).min(self.height) {
+            self.pixels[row * self.width..][columns.clone()].fill(value);
+        }
+    }
+
+    /// Draw `text` on one line from `x`, `y`, the top left of its first
+    /// glyph, with dots of `scale` pixels
+    fn draw_text(&mut self, x: usize, y: usize, text: &str, scale: usize) {
+        for (index, c) in text.chars().enumerate() {
+            let left = x + index * ADVANCE * scale;
+            for (column, bits) in glyph(c).iter().enumerate() {
+                for row in 0..GLYPH_HEIGHT {
+                    if bits & (1 << row) != 0 {
+                        let (dot_x, dot_y) = (left + column * scale, y + row * scale);
+                        self.fill(dot_x, dot_y, scale, scale, INK);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Draw `lines`, wrapped to the margins, from the row `y`, returning the
+    /// row below them; lines below the bottom margin are left out
+    fn draw_lines(&mut self, y: usize, lines: &[String], scale: usize) -> usize {
+        let columns = (self.width - 2 * MARGIN) / (ADVANCE * scale);
+        let mut y = y;
+        for line in lines.iter().flat_map(|line| wrap(line, columns)) {
+            if y + LINE_HEIGHT * scale > self.height - MARGIN {
+                break;
+            }
+            self.draw_text(MARGIN, y, &line, scale);
+            y += LINE_HEIGHT * scale;
+        }
+        y
+    }
+
+    fn into_page(self, metadata: PageMetadata) -> PageData {
+        let mut page = PageData::with_format(
+            self.width as u16,
+            self.height as u16,
+            PixelFormat::Gray,
+            self.pixels,
+        );
+        page.metadata = PageMetadata {
+            size_pts: Some(SIZE_PTS),
+            ..metadata
+        };
+        page
+    }
+}
+
+/// `text` split into lines of at most `columns` characters, at spaces if
+/// it can be
+fn wrap(text: &str, columns: usize) -> Vec<String> {
+    let columns = columns.max(1);
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split(' ') {
+        let mut word: Vec<char> = word.chars().collect();
+        let line_len = line.chars().count();
+        if line_len > 0 && line_len + 1 + word.len() > columns {
+            lines.push(std::mem::take(&mut line));
+        } else if line_len > 0 {
+            line.push(' ');
+        }
+        // Words longer than a line are split
+        while line.chars().count() + word.len() > columns {
+            let split = columns - line.chars().count();
+            line.extend(word.drain(..split));
+            lines.push(std::mem::take(&mut line));
+        }
+        line.extend(word);
+    }
+    lines.push(line);
+    lines
+}
+
+/// Page showing `title`, then `lines` below it, wrapped to the width of the
+/// page, on `background`
+fn text_page(title: &str, lines: &[String], background: u8) -> Canvas {
+    let mut canvas = Canvas::page(background);
+    let y = canvas.draw_lines(MARGIN, &[title.to_string()], TITLE_SCALE);
+    canvas.draw_lines(y + LINE_HEIGHT * TEXT_SCALE, lines, TEXT_SCALE);
+    canvas
+}
+
+/// Placeholder for the page `page_num`, from 1, that the converter failed
+/// to convert, framed so it stands out from the pages around it
+pub(crate) fn failed_page(page_num: usize) -> PageData {
+    let mut canvas = text_page(
+        &format!("Page {page_num} could not be converted"),
+        &[
+            "The converter failed to convert this page of the original document, so \
+           its content is missing from this safe PDF."
+                .to_string(),
+        ],
+        LIGHT_GRAY,
+    );
+    let (width, height) = (canvas.width, canvas.height);
+    let border = MARGIN / 4;
+    let stroke = TEXT_SCALE * 2;
+    canvas.fill(border, border, width - 2 * border, stroke, INK);
+    canvas.fill(
+        border,
+        height - border - stroke,
+        width - 2 * border,
+        stroke,
+        INK,
+    );
+    canvas.fill(border, border, stroke, height - 2 * border, INK);
+    canvas.fill(
+        width - border - stroke,
+        border,
+        stroke,
+        height - 2 * border,
+        INK,
+    );
+    canvas.into_page(PageMetadata {
+        failed: true,
+        ..PageMetadata::default()
+    })
+}
+
+/// Page with `title` and `lines` on white, such as a cover sheet or a page
+/// separating the documents merged in a safe PDF
+#[cfg_attr(not(feature = "container"), allow(dead_code))]
+pub(crate) fn sheet(title: &str, lines: &[String]) -> PageData {
+    text_page(title, lines, WHITE).into_page(PageMetadata::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The text of `page` as rows of `#` and `.`, one per dot of its font
+    fn dots(page: &PageData, x: usize, y: usize, columns: usize, scale: usize) -> Vec<String> {
+        (0..GLYPH_HEIGHT)
+            .map(|row| {
+                (0..columns)
+                    .map(|column| {
+                        let offset = (y + row * scale) * page.width as usize + x + column * scale;
+                        if page.pixels[offset] == INK {
+                            '#'
+                        } else {
+                            '.'
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sheet() {
+        let page = sheet("Hi", &["é".to_string()]);
+        assert_eq!((page.width, page.height), (850, 1100));
+        assert_eq!(page.metadata.size_pts, Some(SIZE_PTS));
+        assert!(!page.metadata.failed);
+        assert_eq!(
+            dots(&page, MARGIN, MARGIN, 2 * ADVANCE, TITLE_SCALE),
+            [
+                "#...#...#...",
+                "#...#.......",
+                "#...#..##...",
+                "#####...#...",
+                "#...#...#...",
+                "#...#...#...",
+                "#...#..###..",
+            ]
+        );
+        // Characters the font doesn't have are question marks
+        let y = MARGIN + LINE_HEIGHT * TITLE_SCALE + LINE_HEIGHT * TEXT_SCALE;
+        let question_mark = sheet("Hi", &["?".to_string()]);
+        assert_eq!(
+            dots(&page, MARGIN, y, GLYPH_WIDTH, TEXT_SCALE),
+            dots(&question_mark, MARGIN, y, GLYPH_WIDTH, TEXT_SCALE)
+        );
+        assert!(dots(&page, MARGIN, y, GLYPH_WIDTH, TEXT_SCALE)[0].contains('#'));
+    }
+
+    #[test]
+    fn test_failed_page() {
+        let page = failed_page(7);
+        assert!(page.metadata.failed);
+        assert_eq!(page.pixels.len(), PAGE_BYTES);
+        assert_eq!(page.pixels[0], LIGHT_GRAY);
+        // The title's first glyph, "P"
+        assert_eq!(
+            dots(&page, MARGIN, MARGIN, GLYPH_WIDTH, TITLE_SCALE)[..2],
+            ["####.", "#...#"]
+        );
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(wrap("one two three", 7), ["one two", "three"]);
+        assert_eq!(wrap("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+        assert_eq!(wrap("ab cdefgh", 4), ["ab", "cdef", "gh"]);
+        assert_eq!(wrap("", 4), [""]);
+    }
+}
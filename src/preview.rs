@@ -0,0 +1,217 @@
+//! Thumbnails of the pages of unsafe documents, to show before converting
+//!
+//! The document is converted to pixels in the sandbox, as for a conversion,
+//! and only the first pages are read before the sandbox is stopped. The
+//! host never parses the document itself: it only scales down the pixels
+//! the sandbox wrote and encodes them as PNG, so showing a preview is as
+//! safe as converting.
+
+use crate::{stream_doc_to_pages, CancellationToken, ConversionOptions, PageData, PixelFormat};
+use anyhow::{Context, Result};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::io::Write;
+
+/// Options of a [`preview`]
+#[derive(Clone, Debug)]
+pub struct PreviewOptions {
+    /// Pages previewed, from the first; the sandbox is stopped once they
+    /// were converted
+    pub max_pages: usize,
+    /// Longest side of a thumbnail, in pixels; smaller pages aren't scaled
+    /// up
+    pub max_size: u32,
+}
+
+impl Default for PreviewOptions {
+    fn default() -> Self {
+        PreviewOptions {
+            max_pages: 10,
+            max_size: 256,
+        }
+    }
+}
+
+/// Thumbnail of a page of the unsafe document
+#[derive(Clone, Debug)]
+pub struct Thumbnail {
+    /// Page number, starting at 1
+    pub page: usize,
+    pub width: u32,
+    pub height: u32,
+    /// The thumbnail, as a PNG image
+    pub png: Vec<u8>,
+}
+
+/// Thumbnails of the first pages of a document
+#[derive(Clone, Debug)]
+pub struct Preview {
+    /// Pages of the whole document, if the sandbox told
+    pub page_count: Option<usize>,
+    pub thumbnails: Vec<Thumbnail>,
+}
+
+/// Render thumbnails of the first pages of the unsafe document at
+/// `input_path`, in the sandbox
+pub fn preview(
+    input_path: String,
+    options: &ConversionOptions,
+    preview: &PreviewOptions,
+    cancel: &CancellationToken,
+) -> Result<Preview> {
+    if preview.max_size == 0 {
+        anyhow::bail!("Thumbnails must be at least 1 pixel wide");
+    }
+    let mut pages = stream_doc_to_pages(input_path, options, cancel)?;
+    let mut thumbnails = Vec::new();
+    while thumbnails.len() < preview.max_pages {
+        let Some(page) = pages.next().transpose()? else {
+            break;
+        };
+        let (width, height, pixels) = scale_down(&page, preview.max_size);
+        thumbnails.push(Thumbnail {
+            page: thumbnails.len() + 1,
+            width,
+            height,
+            png: encode_png(width, height, page.format, &pixels)?,
+        });
+    }
+    // Dropping the stream stops the sandbox if pages are left
+    Ok(Preview {
+        page_count: pages.page_count(),
+        thumbnails,
+    })
+}
+
+/// Pixels of `page` averaged over boxes, so that its longest side is at
+/// most `max_size`
+fn scale_down(page: &PageData, max_size: u32) -> (u32, u32, Vec<u8>) {
+    let (width, height) = (u32::from(page.width), u32::from(page.height));
+    let longest = width.max(height);
+    if longest <= max_size {
+        return (width, height, page.pixels.to_vec());
+    }
+    let scaled =
+        |side: u32| ((u64::from(side) * u64::from(max_size)).div_ceil(u64::from(longest))) as u32;
+    let (new_width, new_height) = (scaled(width), scaled(height));
+    let channels = page.format.bytes_per_pixel();
+    let mut pixels = Vec::with_capacity(new_width as usize * new_height as usize * channels);
+    for y in 0..new_height {
+        let rows = box_range(y, new_height, height);
+        for x in 0..new_width {
+            let columns = box_range(x, new_width, width);
+            let mut sums = [0u64; 4];
+            for row in rows.clone() {
+                let start = (row * width as usize + columns.start) * channels;
+                let end = (row * width as usize + columns.end) * channels;
+                for pixel in page.pixels[start..end].chunks_exact(channels) {
+                    for (sum, &value) in sums.iter_mut().zip(pixel) {
+                        *sum += u64::from(value);
+                    }
+                }
+            }
+            let count = (rows.len() * columns.len()) as u64;
+            pixels.extend(sums[..channels].iter().map(|sum| (sum / count) as u8));
+        }
+    }
+    (new_width, new_height, pixels)
+}
+
+/// Source pixels averaged into the `index`th of `new_len` pixels, out of
+/// `len`
+fn box_range(index: u32, new_len: u32, len: u32) -> std::ops::Range<usize> {
+    let start = u64::from(index) * u64::from(len) / u64::from(new_len);
+    let end = (u64::from(index + 1) * u64::from(len)).div_ceil(u64::from(new_len));
+    start as usize..end.max(start + 1) as usize
+}
+
+/// Encode pixels as an 8-bit PNG image
+fn encode_png(width: u32, height: u32, format: PixelFormat, pixels: &[u8]) -> Result<Vec<u8>> {
+    let color_type = match format {
+        PixelFormat::Gray => 0,
+        PixelFormat::Rgb => 2,
+        PixelFormat::Rgba => 6,
+    };
+    let row_bytes = width as usize * format.bytes_per_pixel();
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    for row in pixels.chunks_exact(row_bytes.max(1)).take(height as usize) {
+        // No filter
+        encoder.write_all(&[0])?;
+        encoder.write_all(row)?;
+    }
+    let data = encoder.finish().context("Failed to compress a thumbnail")?;
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    let mut header = Vec::with_capacity(13);
+    header.extend(width.to_be_bytes());
+    header.extend(height.to_be_bytes());
+    // Bit depth, color type, compression, filter and interlace methods
+    header.extend([8, color_type, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &data);
+    write_chunk(&mut png, b"IEND", &[]);
+    Ok(png)
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    png.extend(kind);
+    png.extend(data);
+    let mut crc = flate2::Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    png.extend(crc.sum().to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn page(width: u16, height: u16, format: PixelFormat, pixels: Vec<u8>) -> PageData {
+        PageData {
+            width,
+            height,
+            format,
+            pixels: pixels.into(),
+            metadata: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_scale_down() {
+        // Columns of 0 and 200 average to 100
+        let pixels = (0..4 * 2)
+            .map(|i| if i % 2 == 0 { 0 } else { 200 })
+            .collect();
+        let gray = page(4, 2, PixelFormat::Gray, pixels);
+        assert_eq!(scale_down(&gray, 2), (2, 1, vec![100, 100]));
+        assert_eq!(scale_down(&gray, 4), (4, 2, gray.pixels.to_vec()));
+
+        let rgb = page(3, 1, PixelFormat::Rgb, vec![30, 0, 0, 0, 60, 0, 0, 0, 90]);
+        assert_eq!(scale_down(&rgb, 1), (1, 1, vec![10, 20, 30]));
+    }
+
+    #[test]
+    fn test_encode_png() {
+        let pixels = vec![1, 2, 3, 4, 5, 6];
+        let png = encode_png(2, 1, PixelFormat::Rgb, &pixels).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..24], [0, 0, 0, 2, 0, 0, 0, 1]);
+        assert_eq!(png[25], 2);
+        // The CRC covers the type and data of the chunk
+        let mut crc = flate2::Crc::new();
+        crc.update(&png[12..29]);
+        assert_eq!(&png[29..33], crc.sum().to_be_bytes());
+        assert!(png.ends_with(b"IEND\xae\x42\x60\x82"));
+
+        let idat_len = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let mut rows = Vec::new();
+        flate2::read::ZlibDecoder::new(&png[41..41 + idat_len])
+            .read_to_end(&mut rows)
+            .unwrap();
+        assert_eq!(rows, [0, 1, 2, 3, 4, 5, 6]);
+    }
+}
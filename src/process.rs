@@ -0,0 +1,105 @@
+//! Processes running the sandbox, whichever way they were started
+//!
+//! A sandbox is usually the runtime's client or bubblewrap, run as a child
+//! process. With [`ConversionOptions::runtime_api`](crate::ConversionOptions::runtime_api),
+//! it is a container the runtime runs on behalf of this process, reached
+//! through its API instead. Both are driven the same way: their stdio is
+//! taken as in [`Child`], then they are polled until they exit, or killed.
+
+use std::io::{Read, Write};
+use std::process::{Child, ExitStatus};
+
+/// Sandbox started by [`spawn_sandbox`](crate::spawn_sandbox), with its
+/// stdio to take
+pub(crate) struct SandboxProcess {
+    pub(crate) stdin: Option<Box<dyn Write + Send>>,
+    pub(crate) stdout: Option<Box<dyn Read + Send>>,
+    pub(crate) stderr: Option<Box<dyn Read + Send>>,
+    /// Checked once the sandbox exited on its own
+    pub(crate) tripwire: Option<crate::canary::Tripwire>,
+    inner: Inner,
+}
+
+enum Inner {
+    Client(Child),
+    #[cfg(all(feature = "runtime-api", unix))]
+    Api(crate::runtime_api::Container),
+}
+
+impl From<Child> for SandboxProcess {
+    fn from(mut child: Child) -> Self {
+        SandboxProcess {
+            stdin: child
+                .stdin
+                .take()
+                .map(|stdin| Box::new(stdin) as Box<dyn Write + Send>),
+            stdout: child
+                .stdout
+                .take()
+                .map(|stdout| Box::new(stdout) as Box<dyn Read + Send>),
+            stderr: child
+                .stderr
+                .take()
+                .map(|stderr| Box::new(stderr) as Box<dyn Read + Send>),
+            tripwire: None,
+            inner: Inner::Client(child),
+        }
+    }
+}
+
+#[cfg(all(feature = "runtime-api", unix))]
+impl From<crate::runtime_api::Container> for SandboxProcess {
+    fn from(mut container: crate::runtime_api::Container) -> Self {
+        SandboxProcess {
+            stdin: container
+                .take_stdin()
+                .map(|stdin| Box::new(stdin) as Box<dyn Write + Send>),
+            stdout: container
+                .take_stdout()
+                .map(|stdout| Box::new(stdout) as Box<dyn Read + Send>),
+            stderr: container
+                .take_stderr()
+                .map(|stderr| Box::new(stderr) as Box<dyn Read + Send>),
+            tripwire: None,
+            inner: Inner::Api(container),
+        }
+    }
+}
+
+impl SandboxProcess {
+    pub(crate) fn with_tripwire(mut self, tripwire: crate::canary::Tripwire) -> Self {
+        self.tripwire = Some(tripwire);
+        self
+    }
+
+    /// Process ID of the runtime's client, if the sandbox runs in one
+    pub(crate) fn client_id(&self) -> Option<u32> {
+        match &self.inner {
+            Inner::Client(child) => Some(child.id()),
+            #[cfg(all(feature = "runtime-api", unix))]
+            Inner::Api(_) => None,
+        }
+    }
+
+    /// Exit status of the sandbox, if it exited
+    pub(crate) fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        match &mut self.inner {
+            Inner::Client(child) => child.try_wait(),
+            #[cfg(all(feature = "runtime-api", unix))]
+            Inner::Api(container) => container.try_wait(),
+        }
+    }
+
+    /// Kill the sandbox; containers run by a client are left to
+    /// [`cleanup`](crate::cleanup) to remove
+    pub(crate) fn kill(&mut self) {
+        match &mut self.inner {
+            Inner::Client(child) => {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            #[cfg(all(feature = "runtime-api", unix))]
+            Inner::Api(container) => container.kill(),
+        }
+    }
+}
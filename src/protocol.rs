@@ -0,0 +1,1074 @@
+//! Pages of the pixel stream the converter writes, and its parsing
+//!
+//! The stream starts with the page count, each page follows with its width,
+//! height and pixels, and the flags of the stream add checksums, compression,
+//! pixel formats, metadata and failures to each page (see the README).
+
+use crate::{placeholder, replace_control_chars, OutputTooLarge, UNTRUSTED_LOG_TARGET};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use log::debug;
+use std::io::Read;
+
+pub const INT_BYTES: usize = 2;
+
+fn read_u16_be<R: Read>(reader: &mut R) -> std::io::Result<u16> {
+    let mut buf = [0; INT_BYTES];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+/// Page data structure representing a single page's pixel information
+///
+/// Cloning a page is cheap: the pixels are shared, reference-counted bytes.
+///
+/// With the `serde` feature, the pixels are serialized as base64 in
+/// human-readable formats such as JSON, and as bytes in binary ones. Pages
+/// without pixels, such as those of [`PageData::without_pixels`], leave them
+/// out.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PageData {
+    pub width: u16,
+    pub height: u16,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub format: PixelFormat,
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            default,
+            skip_serializing_if = "Bytes::is_empty",
+            with = "crate::serde_impls::pixels"
+        )
+    )]
+    pub pixels: Bytes,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub metadata: PageMetadata,
+}
+
+/// Pixels are shown by their size only
+impl std::fmt::Debug for PageData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PageData")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("format", &self.format)
+            .field("pixels", &format_args!("{} bytes", self.pixels.len()))
+            .field("metadata", &self.metadata)
+            .finish()
+    }
+}
+
+impl PageData {
+    /// Create a page of RGB pixels
+    pub fn new(width: u16, height: u16, pixels: impl Into<Bytes>) -> Self {
+        Self::with_format(width, height, PixelFormat::Rgb, pixels)
+    }
+
+    pub fn with_format(
+        width: u16,
+        height: u16,
+        format: PixelFormat,
+        pixels: impl Into<Bytes>,
+    ) -> Self {
+        PageData {
+            width,
+            height,
+            format,
+            pixels: pixels.into(),
+            metadata: PageMetadata::default(),
+        }
+    }
+
+    /// Copy of the page without its pixels, e.g. to pass on its size and
+    /// metadata only
+    pub fn without_pixels(&self) -> Self {
+        PageData {
+            pixels: Bytes::new(),
+            ..self.clone()
+        }
+    }
+}
+
+/// What the converter knows about a page beyond its pixels
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct PageMetadata {
+    /// Clockwise rotation viewers should apply to the page, in degrees: 0,
+    /// 90, 180 or 270
+    pub rotation: u16,
+    /// Size of the original page in points, used as the size of the safe
+    /// page instead of the one derived from the DPI
+    pub size_pts: Option<(f32, f32)>,
+    /// The converter found nothing on the page
+    pub blank: bool,
+    /// The converter failed to convert the page, so its pixels are those of
+    /// a placeholder
+    pub failed: bool,
+}
+
+/// Layout of the pixels of a page, row by row
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum PixelFormat {
+    /// Red, green and blue, 3 bytes per pixel
+    #[default]
+    Rgb,
+    /// 1 byte per pixel
+    Gray,
+    /// Red, green, blue and alpha, 4 bytes per pixel
+    Rgba,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb => 3,
+            PixelFormat::Gray => 1,
+            PixelFormat::Rgba => 4,
+        }
+    }
+
+    /// Format of a page with a format byte in the pixel stream
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(PixelFormat::Rgb),
+            1 => Some(PixelFormat::Gray),
+            2 => Some(PixelFormat::Rgba),
+            _ => None,
+        }
+    }
+}
+
+/// Parse binary pixel data stream from the container
+///
+/// The pixels of each page are slices of `data`, which is not copied,
+/// unless the stream is compressed.
+pub fn parse_pixel_data(data: Vec<u8>) -> Result<Vec<PageData>> {
+    parse_pixel_data_with_limit(data, u64::MAX)
+}
+
+/// Parse a pixel stream whose pages may hold at most `limit` bytes of pixels
+/// in total
+pub(crate) fn parse_pixel_data_with_limit(data: Vec<u8>, limit: u64) -> Result<Vec<PageData>> {
+    let data = Bytes::from(data);
+    let mut rest: &[u8] = &data;
+    let (page_count, format) = read_stream_header(&mut rest)?;
+    let mut pages = Vec::with_capacity(page_count.into());
+    let mut total_bytes = 0;
+    let mut failed_pages = 0;
+    for page_num in 0..page_count {
+        if format.page_failures && read_page_status(&mut rest, page_num)? {
+            pages.push(failed_page(
+                page_num,
+                &mut failed_pages,
+                &mut total_bytes,
+                limit,
+            )?);
+            continue;
+        }
+        let header = read_page_header(&mut rest, page_num, format)?;
+        let num_bytes = header.num_bytes();
+        count_pixels(&mut total_bytes, num_bytes, limit)?;
+        let pixels = if format.compressed {
+            read_pixels(&mut rest, format, num_bytes, page_num)?.into()
+        } else {
+            let offset = data.len() - rest.len();
+            let pixels = data.slice(offset..offset + num_bytes.min(rest.len()));
+            rest = &rest[pixels.len()..];
+            pixels
+        };
+        verify_page(&pixels, num_bytes, header.checksum, page_num)?;
+        pages.push(header.into_page(pixels));
+    }
+    Ok(pages)
+}
+
+/// Environment variable asking the converter for per-page checksums
+pub const PAGE_CHECKSUMS_ENV: &str = "DANGERZONE_PAGE_CHECKSUMS";
+/// Marker of a pixel stream with per-page checksums, after a page count of 0
+pub const PAGE_CHECKSUMS_MAGIC: &[u8; 4] = b"DZCK";
+/// Environment variable asking the converter to compress the pixels of each
+/// page, set to the name of the compression (`zlib`)
+pub const PAGE_COMPRESSION_ENV: &str = "DANGERZONE_PAGE_COMPRESSION";
+/// Marker of a pixel stream with per-page checksums and zlib-compressed
+/// pixels, after a page count of 0
+pub const PAGE_COMPRESSION_MAGIC: &[u8; 4] = b"DZZL";
+/// Environment variable telling the converter that pages may use any
+/// [`PixelFormat`]
+pub const PIXEL_FORMATS_ENV: &str = "DANGERZONE_PIXEL_FORMATS";
+/// Marker of a pixel stream whose features are listed in the following byte,
+/// after a page count of 0
+pub const STREAM_FLAGS_MAGIC: &[u8; 4] = b"DZFL";
+/// Stream flag: pages carry the CRC-32 of their pixels
+const FLAG_CHECKSUMS: u8 = 1;
+/// Stream flag: the pixels of pages are zlib-compressed
+const FLAG_COMPRESSED: u8 = 2;
+/// Stream flag: pages declare their [`PixelFormat`]
+pub(crate) const FLAG_PIXEL_FORMATS: u8 = 4;
+/// Environment variable telling the converter that pages may carry
+/// [`PageMetadata`]
+pub const PAGE_METADATA_ENV: &str = "DANGERZONE_PAGE_METADATA";
+/// Stream flag: pages carry [`PageMetadata`]
+pub(crate) const FLAG_PAGE_METADATA: u8 = 8;
+/// Environment variable telling the converter that it may mark pages it
+/// failed to convert, rather than stopping
+pub const PAGE_FAILURES_ENV: &str = "DANGERZONE_PAGE_FAILURES";
+/// Stream flag: pages start with their status
+pub(crate) const FLAG_PAGE_FAILURES: u8 = 16;
+/// Most pages the converter may mark as failed: each one costs the host a
+/// placeholder page, but the converter only a few bytes
+pub(crate) const MAX_FAILED_PAGES: u16 = 100;
+/// Largest page size allowed by PDF viewers, in points (200 inches)
+const MAX_PAGE_SIZE_PTS: f32 = 14400.0;
+
+/// Incremental parser for the pixel stream written by the container
+///
+/// Pages are read one at a time, so they can be processed while the rest of
+/// the stream is still being produced. Iteration stops after the first error.
+///
+/// Images that honour [`PAGE_CHECKSUMS_ENV`] start the stream with a page
+/// count of 0 followed by [`PAGE_CHECKSUMS_MAGIC`] and the real page count,
+/// and write the CRC-32 of each page's pixels (4 bytes, big-endian) between
+/// its size and its pixels. Older images ignore the variable, and both
+/// layouts are read.
+///
+/// Images that honour [`PAGE_COMPRESSION_ENV`] use [`PAGE_COMPRESSION_MAGIC`]
+/// instead, and replace the pixels of each page with their compressed size
+/// (4 bytes, big-endian) and the zlib stream, inflated while reading. The
+/// checksums are those of the inflated pixels.
+///
+/// Images that honour [`PIXEL_FORMATS_ENV`] use [`STREAM_FLAGS_MAGIC`]
+/// followed by a byte of flags (1: checksums, 2: zlib compression, 4: pixel
+/// formats, 8: page metadata). With pixel formats, the height of each page is
+/// followed by its format (1 byte: 0 for RGB, 1 for grayscale, 2 for RGBA).
+/// With page metadata, the page's rotation (2 bytes, in degrees), the size
+/// of the original page in hundredths of a point (4 bytes each for width and
+/// height, 0 if unknown) and flags (1 byte, 1 if the page is blank) come
+/// next, before the checksum. Images only send metadata if
+/// [`PAGE_METADATA_ENV`] is set.
+///
+/// Images that honour [`PAGE_FAILURES_ENV`] may set the flag 16, and start
+/// each page with a status byte: 0 if it was converted, and its header and
+/// pixels follow, or 1 if it failed, followed by the length of the reason
+/// (2 bytes) and the reason, in UTF-8. A failed page is read as a
+/// placeholder whose [`PageMetadata::failed`] is set; the reason, coming
+/// from the sandbox, is only logged, sanitized, as its other output.
+/// Placeholders count towards the limit of the reader, and the stream fails
+/// once more than 100 pages failed.
+pub struct PageReader<R> {
+    reader: R,
+    page_count: Option<u16>,
+    format: StreamFormat,
+    next_page: u16,
+    limit: u64,
+    total_bytes: u64,
+    failed_pages: u16,
+    failed: bool,
+}
+
+impl<R: Read> PageReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self::with_limit(reader, u64::MAX)
+    }
+
+    /// Create a reader failing with [`OutputTooLarge`] once the pages hold
+    /// more than `limit` bytes of pixels in total
+    pub fn with_limit(reader: R, limit: u64) -> Self {
+        PageReader {
+            reader,
+            page_count: None,
+            format: StreamFormat::default(),
+            next_page: 0,
+            limit,
+            total_bytes: 0,
+            failed_pages: 0,
+            failed: false,
+        }
+    }
+
+    /// Number of pages in the stream, read from its header on first use
+    pub fn page_count(&mut self) -> Result<u16> {
+        if let Some(page_count) = self.page_count {
+            return Ok(page_count);
+        }
+        let (page_count, format) = read_stream_header(&mut self.reader)?;
+        self.page_count = Some(page_count);
+        self.format = format;
+        Ok(page_count)
+    }
+
+    fn read_page(&mut self, page_num: u16) -> Result<PageData> {
+        if self.format.page_failures && read_page_status(&mut self.reader, page_num)? {
+            return failed_page(
+                page_num,
+                &mut self.failed_pages,
+                &mut self.total_bytes,
+                self.limit,
+            );
+        }
+        let header = read_page_header(&mut self.reader, page_num, self.format)?;
+
+        let num_bytes = header.num_bytes();
+        count_pixels(&mut self.total_bytes, num_bytes, self.limit)?;
+        let pixels = read_pixels(&mut self.reader, self.format, num_bytes, page_num)?;
+        verify_page(&pixels, num_bytes, header.checksum, page_num)?;
+
+        Ok(header.into_page(pixels))
+    }
+}
+
+/// Layout of a pixel stream, announced by its header
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct StreamFormat {
+    /// Pages carry the CRC-32 of their pixels
+    checksums: bool,
+    /// The pixels of pages are zlib-compressed
+    compressed: bool,
+    /// Pages declare their pixel format instead of being RGB
+    pixel_formats: bool,
+    /// Pages carry metadata
+    page_metadata: bool,
+    /// Pages start with their status, and may have failed
+    pub(crate) page_failures: bool,
+}
+
+impl StreamFormat {
+    fn from_flags(flags: u8) -> Option<Self> {
+        let known = FLAG_CHECKSUMS
+            | FLAG_COMPRESSED
+            | FLAG_PIXEL_FORMATS
+            | FLAG_PAGE_METADATA
+            | FLAG_PAGE_FAILURES;
+        if flags & !known != 0 {
+            return None;
+        }
+        Some(StreamFormat {
+            checksums: flags & FLAG_CHECKSUMS != 0,
+            compressed: flags & FLAG_COMPRESSED != 0,
+            pixel_formats: flags & FLAG_PIXEL_FORMATS != 0,
+            page_metadata: flags & FLAG_PAGE_METADATA != 0,
+            page_failures: flags & FLAG_PAGE_FAILURES != 0,
+        })
+    }
+}
+
+/// Read the header of the pixel stream: the page count, and the layout of
+/// the pages
+pub(crate) fn read_stream_header<R: Read>(reader: &mut R) -> Result<(u16, StreamFormat)> {
+    let mut page_count = read_u16_be(reader).context("Insufficient data for page count")?;
+    let mut format = StreamFormat::default();
+    if page_count == 0 {
+        let mut magic = Vec::new();
+        reader
+            .take(PAGE_CHECKSUMS_MAGIC.len() as u64)
+            .read_to_end(&mut magic)
+            .context("Failed to read pixel stream header")?;
+        if !magic.is_empty() {
+            format = match magic.as_slice() {
+                magic if magic == PAGE_CHECKSUMS_MAGIC => StreamFormat {
+                    checksums: true,
+                    ..StreamFormat::default()
+                },
+                magic if magic == PAGE_COMPRESSION_MAGIC => StreamFormat {
+                    checksums: true,
+                    compressed: true,
+                    ..StreamFormat::default()
+                },
+                magic if magic == STREAM_FLAGS_MAGIC => {
+                    let mut flags = [0];
+                    reader
+                        .read_exact(&mut flags)
+                        .context("Insufficient data for pixel stream flags")?;
+                    StreamFormat::from_flags(flags[0])
+                        .with_context(|| format!("Unknown pixel stream flags {:#04x}", flags[0]))?
+                }
+                _ => anyhow::bail!("Unknown pixel stream format"),
+            };
+            page_count = read_u16_be(reader).context("Insufficient data for page count")?;
+            debug!("Pixel stream format: {format:?}");
+        }
+    }
+    debug!("Document has {page_count} page(s)");
+    Ok((page_count, format))
+}
+
+/// Read the status of a page: whether it failed to convert, after logging
+/// its sanitized reason; if not, its header follows
+pub(crate) fn read_page_status<R: Read>(reader: &mut R, page_num: u16) -> Result<bool> {
+    let mut status = [0];
+    reader
+        .read_exact(&mut status)
+        .with_context(|| format!("Insufficient data for page {} status", page_num + 1))?;
+    match status[0] {
+        0 => return Ok(false),
+        1 => {}
+        status => anyhow::bail!("Unknown status {status} for page {}", page_num + 1),
+    }
+    let len = read_u16_be(reader)
+        .with_context(|| format!("Insufficient data for page {} failure reason", page_num + 1))?;
+    let mut reason = Vec::with_capacity(len.into());
+    reader
+        .take(len.into())
+        .read_to_end(&mut reason)
+        .with_context(|| format!("Failed to read page {} failure reason", page_num + 1))?;
+    if reason.len() != usize::from(len) {
+        anyhow::bail!("Insufficient data for page {} failure reason", page_num + 1);
+    }
+    log::warn!(
+        target: UNTRUSTED_LOG_TARGET,
+        "Page {} failed to convert: {reason_sanitized}",
+        page_num + 1,
+        reason_sanitized = replace_control_chars(&String::from_utf8_lossy(&reason), false)
+    );
+    Ok(true)
+}
+
+/// Placeholder of the failed page `page_num`, whose pixels count towards
+/// `limit` like those of the pages the converter sent
+pub(crate) fn failed_page(
+    page_num: u16,
+    failed_pages: &mut u16,
+    total_bytes: &mut u64,
+    limit: u64,
+) -> Result<PageData> {
+    *failed_pages += 1;
+    if *failed_pages > MAX_FAILED_PAGES {
+        anyhow::bail!("More than {MAX_FAILED_PAGES} pages failed to convert");
+    }
+    count_pixels(total_bytes, placeholder::PAGE_BYTES, limit)?;
+    Ok(placeholder::failed_page(usize::from(page_num) + 1))
+}
+
+/// Size, pixel format, metadata and checksum of a page, read before its
+/// pixels
+pub(crate) struct PageHeader {
+    pub(crate) width: u16,
+    pub(crate) height: u16,
+    pub(crate) format: PixelFormat,
+    pub(crate) metadata: PageMetadata,
+    pub(crate) checksum: Option<u32>,
+}
+
+impl PageHeader {
+    pub(crate) fn num_bytes(&self) -> usize {
+        (self.width as usize) * (self.height as usize) * self.format.bytes_per_pixel()
+    }
+
+    pub(crate) fn into_page(self, pixels: impl Into<Bytes>) -> PageData {
+        let mut page = PageData::with_format(self.width, self.height, self.format, pixels);
+        page.metadata = self.metadata;
+        page
+    }
+}
+
+/// Read the size of a page and, if the stream has them, its pixel format,
+/// metadata and checksum
+pub(crate) fn read_page_header<R: Read>(
+    reader: &mut R,
+    page_num: u16,
+    stream_format: StreamFormat,
+) -> Result<PageHeader> {
+    let width = read_u16_be(reader)
+        .with_context(|| format!("Insufficient data for page {} width", page_num + 1))?;
+    let height = read_u16_be(reader)
+        .with_context(|| format!("Insufficient data for page {} height", page_num + 1))?;
+    let format = if stream_format.pixel_formats {
+        let mut code = [0];
+        reader
+            .read_exact(&mut code)
+            .with_context(|| format!("Insufficient data for page {} format", page_num + 1))?;
+        PixelFormat::from_code(code[0]).with_context(|| {
+            format!("Unknown pixel format {} for page {}", code[0], page_num + 1)
+        })?
+    } else {
+        PixelFormat::Rgb
+    };
+    let metadata = if stream_format.page_metadata {
+        read_page_metadata(reader, page_num)?
+    } else {
+        PageMetadata::default()
+    };
+    let checksum = if stream_format.checksums {
+        let mut checksum = [0; 4];
+        reader
+            .read_exact(&mut checksum)
+            .with_context(|| format!("Insufficient data for page {} checksum", page_num + 1))?;
+        Some(u32::from_be_bytes(checksum))
+    } else {
+        None
+    };
+
+    debug!(
+        "Page {}: {}x{} pixels ({:?})",
+        page_num + 1,
+        width,
+        height,
+        format
+    );
+    Ok(PageHeader {
+        width,
+        height,
+        format,
+        metadata,
+        checksum,
+    })
+}
+
+fn read_page_metadata<R: Read>(reader: &mut R, page_num: u16) -> Result<PageMetadata> {
+    let mut metadata = [0; 11];
+    reader
+        .read_exact(&mut metadata)
+        .with_context(|| format!("Insufficient data for page {} metadata", page_num + 1))?;
+    let rotation = u16::from_be_bytes([metadata[0], metadata[1]]);
+    let width = u32::from_be_bytes(metadata[2..6].try_into().unwrap());
+    let height = u32::from_be_bytes(metadata[6..10].try_into().unwrap());
+    let size_pts = match (width, height) {
+        (0, 0) => None,
+        (width, height) => Some((width as f32 / 100.0, height as f32 / 100.0)),
+    };
+    let flags = metadata[10];
+    if flags & !1 != 0 {
+        anyhow::bail!("Unknown flags {flags:#04x} for page {}", page_num + 1);
+    }
+    let metadata = PageMetadata {
+        rotation,
+        size_pts,
+        blank: flags & 1 != 0,
+        failed: false,
+    };
+    metadata.check(page_num)?;
+    Ok(metadata)
+}
+
+impl PageMetadata {
+    /// Fail on a rotation or a page size the safe PDF can't have
+    pub(crate) fn check(&self, page_num: u16) -> Result<()> {
+        if !self.rotation.is_multiple_of(90) || self.rotation >= 360 {
+            anyhow::bail!(
+                "Invalid rotation {} for page {}",
+                self.rotation,
+                page_num + 1
+            );
+        }
+        if let Some(size) = self.size_pts {
+            if !(size.0 >= 1.0 && size.1 >= 1.0)
+                || size.0 > MAX_PAGE_SIZE_PTS
+                || size.1 > MAX_PAGE_SIZE_PTS
+            {
+                anyhow::bail!("Invalid size for page {}", page_num + 1);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read up to `num_bytes` pixels of a page, inflating them if the stream is
+/// compressed. The buffer grows as data arrives rather than trusting the
+/// claimed size up front
+pub(crate) fn read_pixels<R: Read>(
+    reader: &mut R,
+    format: StreamFormat,
+    num_bytes: usize,
+    page_num: u16,
+) -> Result<Vec<u8>> {
+    read_pixels_with(reader, format, num_bytes, page_num, |pixels| {
+        let mut buffer = Vec::new();
+        pixels.read_to_end(&mut buffer)?;
+        Ok(buffer)
+    })
+}
+
+/// Hand a reader of up to `num_bytes` pixels of a page to `read`, and skip
+/// the rest of the page's compressed data
+pub(crate) fn read_pixels_with<R: Read, T>(
+    reader: &mut R,
+    format: StreamFormat,
+    num_bytes: usize,
+    page_num: u16,
+    read: impl FnOnce(&mut dyn Read) -> Result<T>,
+) -> Result<T> {
+    let context = || format!("Failed to read page {} pixels", page_num + 1);
+    if !format.compressed {
+        return read(&mut reader.take(num_bytes as u64)).with_context(context);
+    }
+
+    let mut compressed_len = [0; 4];
+    reader.read_exact(&mut compressed_len).with_context(|| {
+        format!(
+            "Insufficient data for page {} compressed size",
+            page_num + 1
+        )
+    })?;
+    let mut compressed = reader.take(u32::from_be_bytes(compressed_len).into());
+    let mut decoder = flate2::read::ZlibDecoder::new(&mut compressed);
+    let output = read(&mut (&mut decoder).take(num_bytes as u64)).with_context(context)?;
+    if decoder.read(&mut [0]).with_context(context)? != 0 {
+        anyhow::bail!(
+            "Page {} inflates to more pixels than its size",
+            page_num + 1
+        );
+    }
+    drop(decoder);
+    std::io::copy(&mut compressed, &mut std::io::sink()).with_context(context)?;
+    Ok(output)
+}
+
+/// Count the pixels of a page towards `limit`, which compressed streams
+/// would otherwise only bound before inflation
+pub(crate) fn count_pixels(total_bytes: &mut u64, num_bytes: usize, limit: u64) -> Result<()> {
+    *total_bytes = total_bytes.saturating_add(num_bytes as u64);
+    if *total_bytes > limit {
+        return Err(OutputTooLarge { limit }.into());
+    }
+    Ok(())
+}
+
+/// Check that all `num_bytes` pixels of a page arrived intact
+pub(crate) fn verify_page(
+    pixels: &[u8],
+    num_bytes: usize,
+    checksum: Option<u32>,
+    page_num: u16,
+) -> Result<()> {
+    check_page_length(pixels.len(), num_bytes, page_num)?;
+    if let Some(expected) = checksum {
+        let mut crc = flate2::Crc::new();
+        crc.update(pixels);
+        check_page_checksum(crc.sum(), expected, page_num)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn check_page_length(len: usize, num_bytes: usize, page_num: u16) -> Result<()> {
+    if len != num_bytes {
+        anyhow::bail!(
+            "Insufficient data for page {} pixels (expected {} bytes)",
+            page_num + 1,
+            num_bytes
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn check_page_checksum(crc: u32, expected: u32, page_num: u16) -> Result<()> {
+    if crc != expected {
+        anyhow::bail!(
+            "Checksum mismatch for page {}: the pixel data was corrupted in transfer",
+            page_num + 1
+        );
+    }
+    Ok(())
+}
+
+impl<R: Read> Iterator for PageReader<R> {
+    type Item = Result<PageData>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = self.page_count().and_then(|page_count| {
+            if self.next_page >= page_count {
+                return Ok(None);
+            }
+            let page = self.read_page(self.next_page)?;
+            self.next_page += 1;
+            Ok(Some(page))
+        });
+        match result {
+            Ok(page) => page.map(Ok),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Reader failing once more than `limit` bytes were read from the container
+#[cfg(feature = "container")]
+pub(crate) struct CappedReader<R> {
+    reader: R,
+    limit: u64,
+    read: u64,
+}
+
+#[cfg(feature = "container")]
+impl<R: Read> CappedReader<R> {
+    pub(crate) fn new(reader: R, limit: u64) -> Self {
+        CappedReader {
+            reader,
+            limit,
+            read: 0,
+        }
+    }
+
+    pub(crate) fn exceeded(&self) -> bool {
+        self.read > self.limit
+    }
+}
+
+#[cfg(feature = "container")]
+impl<R: Read> Read for CappedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.exceeded() {
+            return Err(std::io::Error::other(OutputTooLarge { limit: self.limit }));
+        }
+        // Allow one byte past the limit, to tell a stream of exactly `limit`
+        // bytes from a longer one
+        let allowed = (self.limit - self.read + 1).min(buf.len() as u64) as usize;
+        let n = self.reader.read(&mut buf[..allowed])?;
+        self.read += n as u64;
+        if self.exceeded() {
+            return Err(std::io::Error::other(OutputTooLarge { limit: self.limit }));
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_pixel_data_parsing() {
+        let mut data = Vec::new();
+
+        let page_count: u16 = 1;
+        data.extend_from_slice(&page_count.to_be_bytes());
+
+        let width: u16 = 100;
+        let height: u16 = 50;
+        data.extend_from_slice(&width.to_be_bytes());
+        data.extend_from_slice(&height.to_be_bytes());
+
+        let num_pixels = (width as usize) * (height as usize) * 3;
+        data.extend(vec![128u8; num_pixels]);
+
+        let result = parse_pixel_data(data);
+        assert!(result.is_ok());
+
+        let pages = result.unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].width, width);
+        assert_eq!(pages[0].height, height);
+        assert_eq!(pages[0].pixels.len(), num_pixels);
+    }
+
+    #[test]
+    fn test_page_reader_stops_after_truncated_page() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&3u16.to_be_bytes());
+        for _ in 0..2 {
+            data.extend_from_slice(&2u16.to_be_bytes());
+            data.extend_from_slice(&1u16.to_be_bytes());
+            data.extend_from_slice(&[7u8; 6]);
+        }
+        // Third page is cut off in the middle of its pixels
+        data.extend_from_slice(&2u16.to_be_bytes());
+        data.extend_from_slice(&2u16.to_be_bytes());
+        data.extend_from_slice(&[7u8; 5]);
+
+        let mut reader = PageReader::new(data.as_slice());
+        assert_eq!(reader.page_count().unwrap(), 3);
+        assert_eq!(reader.next().unwrap().unwrap().pixels, vec![7u8; 6]);
+        assert_eq!(reader.next().unwrap().unwrap().width, 2);
+        let Some(Err(err)) = reader.next() else {
+            panic!("truncated page should fail to parse");
+        };
+        assert!(err.to_string().contains("page 3 pixels"), "{err}");
+        assert!(reader.next().is_none());
+
+        assert!(parse_pixel_data(data).is_err());
+        assert!(parse_pixel_data(vec![0]).is_err());
+        assert!(parse_pixel_data(vec![0, 0]).unwrap().is_empty());
+    }
+
+    fn checksummed_stream(pixels: &[u8]) -> Vec<u8> {
+        let mut crc = flate2::Crc::new();
+        crc.update(pixels);
+        let mut stream = vec![0, 0];
+        stream.extend(PAGE_CHECKSUMS_MAGIC);
+        stream.extend([0, 1, 0, 1, 0, 1]);
+        stream.extend(crc.sum().to_be_bytes());
+        stream.extend(pixels);
+        stream
+    }
+
+    #[test]
+    fn test_page_checksums() {
+        let pages = parse_pixel_data(checksummed_stream(&[1, 2, 3])).unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].pixels[..], [1, 2, 3]);
+
+        let mut corrupted = checksummed_stream(&[1, 2, 3]);
+        *corrupted.last_mut().unwrap() = 4;
+        let Err(err) = parse_pixel_data(corrupted) else {
+            panic!("corrupted page was accepted");
+        };
+        assert!(err.to_string().contains("Checksum mismatch for page 1"));
+    }
+
+    #[test]
+    fn test_parse_pixel_data_does_not_copy() {
+        let data = vec![0, 2, 0, 1, 0, 1, 1, 2, 3, 0, 1, 0, 1, 4, 5, 6];
+        let buffer = data.as_ptr_range();
+
+        let pages = parse_pixel_data(data).unwrap();
+        assert_eq!(pages.len(), 2);
+        for page in &pages {
+            assert!(buffer.contains(&page.pixels.as_ptr()));
+        }
+        assert_eq!(pages[1].pixels[..], [4, 5, 6]);
+    }
+
+    #[test]
+    fn test_pixel_stream_without_checksums() {
+        // Older images: a bare page count, possibly 0
+        assert!(parse_pixel_data(vec![0, 0]).unwrap().is_empty());
+        let pages = parse_pixel_data(vec![0, 1, 0, 1, 0, 1, 7, 8, 9]).unwrap();
+        assert_eq!(pages[0].pixels[..], [7, 8, 9]);
+
+        assert!(parse_pixel_data(vec![0, 0, b'X', b'Y', b'Z', b'W']).is_err());
+    }
+
+    fn compressed_stream(width: u16, height: u16, pixels: &[u8]) -> Vec<u8> {
+        let mut crc = flate2::Crc::new();
+        crc.update(pixels);
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(pixels).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut stream = vec![0, 0];
+        stream.extend(PAGE_COMPRESSION_MAGIC);
+        stream.extend([0, 2]);
+        for _ in 0..2 {
+            stream.extend(width.to_be_bytes());
+            stream.extend(height.to_be_bytes());
+            stream.extend(crc.sum().to_be_bytes());
+            stream.extend((compressed.len() as u32).to_be_bytes());
+            stream.extend(&compressed);
+        }
+        stream
+    }
+
+    #[test]
+    fn test_compressed_pages() {
+        let pixels = [9u8; 4 * 4 * 3];
+        let stream = compressed_stream(4, 4, &pixels);
+
+        let pages = parse_pixel_data(stream.clone()).unwrap();
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[1].pixels[..], pixels);
+
+        let pages = PageReader::new(stream.as_slice())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[1].pixels[..], pixels);
+    }
+
+    #[test]
+    fn test_compressed_page_larger_than_its_size() {
+        // The zlib stream holds more pixels than a 2x2 page
+        let mut stream = compressed_stream(4, 4, &[9u8; 4 * 4 * 3]);
+        stream[8..12].copy_from_slice(&[0, 2, 0, 2]);
+        let Err(err) = parse_pixel_data(stream) else {
+            panic!("oversized page was accepted");
+        };
+        assert!(err.to_string().contains("inflates to more pixels"));
+    }
+
+    #[test]
+    fn test_compressed_pages_count_towards_the_limit() {
+        // A few hundred bytes inflating to 3 MiB of pixels
+        let stream = compressed_stream(1024, 1024, &vec![0u8; 1024 * 1024 * 3]);
+        let Err(err) = parse_pixel_data_with_limit(stream.clone(), 4 << 20) else {
+            panic!("stream over the limit was accepted");
+        };
+        assert!(err.is::<OutputTooLarge>());
+
+        let mut pages = PageReader::with_limit(stream.as_slice(), 4 << 20);
+        assert!(pages.next().unwrap().is_ok());
+        let Some(Err(err)) = pages.next() else {
+            panic!("page over the limit was accepted");
+        };
+        assert!(err.is::<OutputTooLarge>());
+    }
+
+    #[test]
+    fn test_pixel_formats() {
+        let mut stream = vec![0, 0];
+        stream.extend(STREAM_FLAGS_MAGIC);
+        stream.extend([FLAG_PIXEL_FORMATS, 0, 3]);
+        stream.extend([0, 2, 0, 1, 1, 10, 20]);
+        stream.extend([0, 1, 0, 1, 2, 1, 2, 3, 4]);
+        stream.extend([0, 1, 0, 1, 0, 5, 6, 7]);
+
+        let pages = parse_pixel_data(stream.clone()).unwrap();
+        let formats: Vec<_> = pages.iter().map(|page| page.format).collect();
+        assert_eq!(
+            formats,
+            [PixelFormat::Gray, PixelFormat::Rgba, PixelFormat::Rgb]
+        );
+        assert_eq!(pages[0].pixels[..], [10, 20]);
+        assert_eq!(pages[1].pixels[..], [1, 2, 3, 4]);
+        assert_eq!(pages[2].pixels[..], [5, 6, 7]);
+
+        let pages = PageReader::new(stream.as_slice())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(pages[1].format, PixelFormat::Rgba);
+
+        // Unknown formats and flags
+        stream[13] = 7;
+        let Err(err) = parse_pixel_data(stream.clone()) else {
+            panic!("unknown pixel format was accepted");
+        };
+        assert!(err
+            .to_string()
+            .contains("Unknown pixel format 7 for page 1"));
+        stream[6] = 0x80;
+        let Err(err) = parse_pixel_data(stream) else {
+            panic!("unknown flags were accepted");
+        };
+        assert!(err.to_string().contains("Unknown pixel stream flags"));
+    }
+
+    #[test]
+    fn test_page_metadata() {
+        let mut stream = vec![0, 0];
+        stream.extend(STREAM_FLAGS_MAGIC);
+        stream.extend([FLAG_PAGE_METADATA, 0, 2]);
+        // Rotated A4 page
+        stream.extend([0, 1, 0, 1]);
+        stream.extend(90u16.to_be_bytes());
+        stream.extend(59528u32.to_be_bytes());
+        stream.extend(84189u32.to_be_bytes());
+        stream.extend([0, 1, 2, 3]);
+        // Blank page of unknown size
+        stream.extend([0, 1, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 4, 5, 6]);
+
+        let pages = parse_pixel_data(stream.clone()).unwrap();
+        assert_eq!(
+            pages[0].metadata,
+            PageMetadata {
+                rotation: 90,
+                size_pts: Some((595.28, 841.89)),
+                blank: false,
+                failed: false,
+            }
+        );
+        assert_eq!(pages[0].pixels[..], [1, 2, 3]);
+        assert_eq!(
+            pages[1].metadata,
+            PageMetadata {
+                blank: true,
+                ..PageMetadata::default()
+            }
+        );
+
+        // Rotations other than quarter turns
+        stream[14] = 45;
+        let Err(err) = parse_pixel_data(stream) else {
+            panic!("invalid rotation was accepted");
+        };
+        assert!(err.to_string().contains("Invalid rotation"));
+    }
+
+    #[test]
+    fn test_page_failures() {
+        let mut stream = vec![0, 0];
+        stream.extend(STREAM_FLAGS_MAGIC);
+        stream.extend([FLAG_PAGE_FAILURES, 0, 3]);
+        stream.extend([0, 0, 1, 0, 1, 1, 2, 3]);
+        stream.extend([1, 0, 5]);
+        stream.extend(b"crash");
+        stream.extend([0, 0, 1, 0, 1, 4, 5, 6]);
+
+        let pages = parse_pixel_data(stream.clone()).unwrap();
+        let read: Vec<PageData> = PageReader::new(stream.as_slice())
+            .collect::<Result<_>>()
+            .unwrap();
+        for pages in [&pages, &read] {
+            assert_eq!(pages.len(), 3);
+            assert_eq!(pages[0].pixels[..], [1, 2, 3]);
+            assert!(!pages[0].metadata.failed);
+            assert!(pages[1].metadata.failed);
+            assert_eq!(pages[1].metadata.size_pts, Some((612.0, 792.0)));
+            assert_eq!(pages[2].pixels[..], [4, 5, 6]);
+        }
+
+        // Truncated reasons, and unknown statuses
+        let Err(err) = parse_pixel_data(stream[..22].to_vec()) else {
+            panic!("truncated reason was accepted");
+        };
+        assert!(err.to_string().contains("page 2 failure reason"));
+        stream[17] = 2;
+        let Err(err) = parse_pixel_data(stream) else {
+            panic!("unknown status was accepted");
+        };
+        assert!(err.to_string().contains("Unknown status 2 for page 2"));
+    }
+
+    #[test]
+    fn test_failed_pages_are_counted() {
+        let failures = |count: u16| {
+            let mut stream = vec![0, 0];
+            stream.extend(STREAM_FLAGS_MAGIC);
+            stream.push(FLAG_PAGE_FAILURES);
+            stream.extend(count.to_be_bytes());
+            for _ in 0..count {
+                stream.extend([1, 0, 0]);
+            }
+            stream
+        };
+
+        // Placeholders count towards the limit
+        let limit = 2 * placeholder::PAGE_BYTES as u64;
+        assert_eq!(
+            parse_pixel_data_with_limit(failures(2), limit)
+                .unwrap()
+                .len(),
+            2
+        );
+        let err = parse_pixel_data_with_limit(failures(3), limit).unwrap_err();
+        assert!(err.is::<OutputTooLarge>());
+        let stream = failures(3);
+        let err = PageReader::with_limit(stream.as_slice(), limit)
+            .find_map(Result::err)
+            .unwrap();
+        assert!(err.is::<OutputTooLarge>());
+
+        // And there can only be so many of them
+        let stream = failures(MAX_FAILED_PAGES + 1);
+        let err = parse_pixel_data(stream.clone()).unwrap_err();
+        assert!(err.to_string().contains("pages failed to convert"));
+        assert!(PageReader::new(stream.as_slice()).any(|page| page.is_err()));
+    }
+
+    #[test]
+    #[cfg(feature = "container")]
+    fn test_capped_reader() {
+        let mut data = Vec::new();
+        let mut reader = CappedReader::new([1u8; 8].as_slice(), 8);
+        assert_eq!(reader.read_to_end(&mut data).unwrap(), 8);
+        assert!(!reader.exceeded());
+
+        let mut reader = CappedReader::new([1u8; 9].as_slice(), 8);
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(err.get_ref().unwrap().is::<OutputTooLarge>());
+        assert!(reader.exceeded());
+
+        // A page bigger than the limit stops the page reader
+        let stream = [0, 1, 0, 2, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut reader = CappedReader::new(stream.as_slice(), 10);
+        assert!(PageReader::new(&mut reader).next().unwrap().is_err());
+        assert!(reader.exceeded());
+    }
+}
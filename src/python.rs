@@ -1,17 +1,32 @@
 #![allow(clippy::useless_conversion)]
 
 use crate::{
-    apply_ocr_fn as core_apply_ocr_fn, convert_doc_to_pixels as core_convert_doc_to_pixels,
-    convert_document as core_convert_document, parse_pixel_data as core_parse_pixel_data,
-    pixels_to_pdf as core_pixels_to_pdf, PageData as CorePageData,
+    apply_ocr_fn as core_apply_ocr_fn, convert_batch as core_convert_batch,
+    convert_doc_to_pixels as core_convert_doc_to_pixels,
+    convert_document_with_options as core_convert_document_with_options,
+    parse_pixel_data as core_parse_pixel_data, pixels_to_pdf as core_pixels_to_pdf,
+    stream_doc_to_pages, warmup as core_warmup, BatchResult as CoreBatchResult, CancellationToken,
+    ConversionOptions, PageData as CorePageData, PageMetadata, PageStream, PixelFormat,
 };
 /// Python bindings for the dangerzone-rs library using PyO3
 ///
 /// This module provides PyO3 wrappers around the core Rust functionality,
 /// converting anyhow::Result to PyResult for Python compatibility.
+use bytes::Bytes;
+use pyo3::exceptions::{PyBufferError, PyValueError};
+use pyo3::ffi;
 use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use std::ffi::{c_char, c_int, c_void};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
 
 /// Python-compatible wrapper for PageData
+///
+/// Pages support the buffer protocol, exposing their pixels as a read-only
+/// `height x width x channels` array of bytes: `memoryview(page)` and
+/// `numpy.asarray(page)` don't copy the pixel data.
 #[pyclass]
 #[derive(Clone)]
 pub struct PageData {
@@ -19,18 +34,175 @@ pub struct PageData {
     pub width: u16,
     #[pyo3(get)]
     pub height: u16,
-    #[pyo3(get)]
-    pub pixels: Vec<u8>,
+    pub format: PixelFormat,
+    pub pixels: Bytes,
+    pub metadata: PageMetadata,
+}
+
+/// Pillow's name of a pixel format
+fn mode_name(format: PixelFormat) -> &'static str {
+    match format {
+        PixelFormat::Rgb => "RGB",
+        PixelFormat::Gray => "L",
+        PixelFormat::Rgba => "RGBA",
+    }
 }
 
 #[pymethods]
 impl PageData {
     #[new]
-    pub fn new(width: u16, height: u16, pixels: Vec<u8>) -> Self {
-        PageData {
+    #[pyo3(signature = (width, height, pixels, mode = "RGB"))]
+    pub fn new(width: u16, height: u16, pixels: Vec<u8>, mode: &str) -> PyResult<Self> {
+        let format = match mode {
+            "RGB" => PixelFormat::Rgb,
+            "L" => PixelFormat::Gray,
+            "RGBA" => PixelFormat::Rgba,
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "Unsupported mode {mode:?}: expected \"RGB\", \"L\" or \"RGBA\""
+                )))
+            }
+        };
+        Ok(PageData {
             width,
             height,
-            pixels,
+            format,
+            pixels: pixels.into(),
+            metadata: PageMetadata::default(),
+        })
+    }
+
+    /// Pixel format, named like Pillow's modes: "RGB", "L" (grayscale) or
+    /// "RGBA"
+    #[getter]
+    fn mode(&self) -> &'static str {
+        mode_name(self.format)
+    }
+
+    /// Clockwise rotation viewers should apply to the page, in degrees
+    #[getter]
+    fn rotation(&self) -> u16 {
+        self.metadata.rotation
+    }
+
+    /// Size of the original page in points, if the converter sent it
+    #[getter]
+    fn size_pts(&self) -> Option<(f32, f32)> {
+        self.metadata.size_pts
+    }
+
+    /// Whether the converter found nothing on the page
+    #[getter]
+    fn blank(&self) -> bool {
+        self.metadata.blank
+    }
+
+    /// Whether the converter failed to convert the page, whose pixels are
+    /// then those of a placeholder
+    #[getter]
+    fn failed(&self) -> bool {
+        self.metadata.failed
+    }
+
+    /// Raw pixels, row by row, with 1 to 4 bytes per pixel depending on
+    /// `mode`
+    #[getter]
+    fn pixels<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.pixels)
+    }
+
+    /// Return the page as a `PIL.Image.Image` (requires Pillow)
+    fn to_pil<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        py.import("PIL.Image")?.call_method1(
+            "frombytes",
+            (self.mode(), (self.width, self.height), self.pixels(py)),
+        )
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "PageData(width={}, height={}, mode={:?})",
+            self.width,
+            self.height,
+            self.mode()
+        )
+    }
+
+    unsafe fn __getbuffer__(
+        slf: Bound<'_, Self>,
+        view: *mut ffi::Py_buffer,
+        flags: c_int,
+    ) -> PyResult<()> {
+        if view.is_null() {
+            return Err(PyBufferError::new_err("View is null"));
+        }
+        if flags & ffi::PyBUF_WRITABLE == ffi::PyBUF_WRITABLE {
+            return Err(PyBufferError::new_err("PageData pixels are read-only"));
+        }
+
+        let (buf, len, width, height, channels) = {
+            let page = slf.borrow();
+            (
+                page.pixels.as_ptr(),
+                page.pixels.len(),
+                page.width as ffi::Py_ssize_t,
+                page.height as ffi::Py_ssize_t,
+                page.format.bytes_per_pixel() as ffi::Py_ssize_t,
+            )
+        };
+        let shaped = flags & ffi::PyBUF_ND == ffi::PyBUF_ND;
+        if shaped && len != (width * height * channels) as usize {
+            return Err(PyBufferError::new_err(
+                "PageData pixels don't match its width and height",
+            ));
+        }
+
+        // The pixels are never mutated while the page is alive, and the view
+        // holds a reference to the page
+        unsafe {
+            (*view).obj = slf.into_any().into_ptr();
+            (*view).buf = buf as *mut c_void;
+            (*view).len = len as ffi::Py_ssize_t;
+            (*view).readonly = 1;
+            (*view).itemsize = 1;
+            (*view).format = if flags & ffi::PyBUF_FORMAT == ffi::PyBUF_FORMAT {
+                c"B".as_ptr() as *mut c_char
+            } else {
+                std::ptr::null_mut()
+            };
+            (*view).suboffsets = std::ptr::null_mut();
+            if shaped {
+                // Shape followed by strides, freed in __releasebuffer__
+                let dims: *mut [ffi::Py_ssize_t; 6] = Box::into_raw(Box::new([
+                    height,
+                    width,
+                    channels,
+                    width * channels,
+                    channels,
+                    1,
+                ]));
+                (*view).ndim = 3;
+                (*view).shape = (*dims).as_mut_ptr();
+                (*view).strides = if flags & ffi::PyBUF_STRIDES == ffi::PyBUF_STRIDES {
+                    (*dims).as_mut_ptr().add(3)
+                } else {
+                    std::ptr::null_mut()
+                };
+                (*view).internal = dims.cast();
+            } else {
+                (*view).ndim = 1;
+                (*view).shape = std::ptr::null_mut();
+                (*view).strides = std::ptr::null_mut();
+                (*view).internal = std::ptr::null_mut();
+            }
+        }
+        Ok(())
+    }
+
+    unsafe fn __releasebuffer__(&self, view: *mut ffi::Py_buffer) {
+        let dims = unsafe { (*view).internal } as *mut [ffi::Py_ssize_t; 6];
+        if !dims.is_null() {
+            drop(unsafe { Box::from_raw(dims) });
         }
     }
 }
@@ -40,7 +212,9 @@ impl From<CorePageData> for PageData {
         PageData {
             width: core.width,
             height: core.height,
+            format: core.format,
             pixels: core.pixels,
+            metadata: core.metadata,
         }
     }
 }
@@ -50,56 +224,333 @@ impl From<PageData> for CorePageData {
         CorePageData {
             width: py.width,
             height: py.height,
+            format: py.format,
             pixels: py.pixels,
+            metadata: py.metadata,
         }
     }
 }
 
+// The wrappers below release the GIL while the Rust code runs, so other
+// Python threads (e.g. a GUI event loop) keep running during conversions.
+// Paths can be given as `str` or any `os.PathLike`.
+
+fn path_string(path: PathBuf) -> PyResult<String> {
+    path.into_os_string()
+        .into_string()
+        .map_err(|path| PyValueError::new_err(format!("Path is not valid UTF-8: {path:?}")))
+}
+
+/// Build the `ConversionOptions` of the conversion wrappers' keyword
+/// arguments. Options left to `None` keep their default; `timeout` is in
+/// seconds.
+fn conversion_options(
+    ocr: bool,
+    ocr_lang: Option<String>,
+    dpi: Option<f32>,
+    timeout: Option<f64>,
+    runtime: Option<String>,
+) -> PyResult<ConversionOptions> {
+    let mut options = ConversionOptions {
+        ocr,
+        ..ConversionOptions::default()
+    };
+    if let Some(ocr_lang) = ocr_lang {
+        options.ocr_lang = ocr_lang;
+    }
+    if let Some(dpi) = dpi {
+        options.dpi = dpi;
+    }
+    if let Some(timeout) = timeout {
+        options.timeout = Some(
+            Duration::try_from_secs_f64(timeout)
+                .map_err(|e| PyValueError::new_err(format!("Invalid timeout: {e}")))?,
+        );
+    }
+    if let Some(runtime) = runtime {
+        options.runtime = runtime
+            .parse()
+            .map_err(|e: anyhow::Error| PyValueError::new_err(e.to_string()))?;
+    }
+    Ok(options)
+}
+
 /// Wrapper for parse_pixel_data that converts Result to PyResult
 #[pyfunction]
-fn parse_pixel_data(data: Vec<u8>) -> PyResult<Vec<PageData>> {
-    core_parse_pixel_data(data)
+fn parse_pixel_data(py: Python<'_>, data: Vec<u8>) -> PyResult<Vec<PageData>> {
+    py.detach(|| core_parse_pixel_data(data))
         .map(|pages| pages.into_iter().map(PageData::from).collect())
         .map_err(|e| PyErr::new::<pyo3::exceptions::PyValueError, _>(e.to_string()))
 }
 
 /// Wrapper for convert_doc_to_pixels that converts Result to PyResult
 #[pyfunction]
-fn convert_doc_to_pixels(input_path: String) -> PyResult<Vec<u8>> {
-    core_convert_doc_to_pixels(input_path)
+fn convert_doc_to_pixels(py: Python<'_>, input_path: PathBuf) -> PyResult<Vec<u8>> {
+    let input_path = path_string(input_path)?;
+    py.detach(|| core_convert_doc_to_pixels(input_path))
         .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))
 }
 
 /// Wrapper for pixels_to_pdf that converts Result to PyResult
 #[pyfunction]
-fn pixels_to_pdf(pages: Vec<PageData>, output_path: String) -> PyResult<()> {
+fn pixels_to_pdf(py: Python<'_>, pages: Vec<PageData>, output_path: PathBuf) -> PyResult<()> {
+    let output_path = path_string(output_path)?;
     let core_pages: Vec<CorePageData> = pages.into_iter().map(CorePageData::from).collect();
-    core_pixels_to_pdf(core_pages, output_path)
+    py.detach(|| core_pixels_to_pdf(core_pages, output_path))
+        .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))
+}
+
+/// Wrapper for convert_document_with_options that converts Result to
+/// PyResult, taking the options as keyword arguments and returning the
+/// warnings of the conversion
+#[pyfunction]
+#[pyo3(signature = (
+    input_path,
+    output_path,
+    ocr = false,
+    *,
+    ocr_lang = None,
+    dpi = None,
+    timeout = None,
+    runtime = None,
+))]
+#[allow(clippy::too_many_arguments)]
+fn convert_document(
+    py: Python<'_>,
+    input_path: PathBuf,
+    output_path: PathBuf,
+    ocr: bool,
+    ocr_lang: Option<String>,
+    dpi: Option<f32>,
+    timeout: Option<f64>,
+    runtime: Option<String>,
+) -> PyResult<Vec<String>> {
+    let input_path = path_string(input_path)?;
+    let output_path = path_string(output_path)?;
+
+    let options = conversion_options(ocr, ocr_lang, dpi, timeout, runtime)?;
+
+    py.detach(|| {
+        core_convert_document_with_options(
+            input_path,
+            output_path,
+            &options,
+            &|_| {},
+            &CancellationToken::new(),
+        )
+    })
+    .map(|report| report.warnings.iter().map(ToString::to_string).collect())
+    .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))
+}
+
+/// Iterator over the pages of a document being converted, returned by
+/// `iter_pages`
+///
+/// The container only produces the next page once the previous one was
+/// taken. `close()` stops the conversion early.
+#[pyclass]
+pub struct PageIterator {
+    stream: Mutex<Option<PageStream>>,
+}
+
+#[pymethods]
+impl PageIterator {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&self, py: Python<'_>) -> PyResult<Option<PageData>> {
+        py.detach(|| {
+            let mut stream = self.stream.lock().unwrap();
+            let next = stream.as_mut().and_then(Iterator::next);
+            if !matches!(next, Some(Ok(_))) {
+                *stream = None;
+            }
+            next.transpose()
+        })
+        .map(|page| page.map(PageData::from))
         .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))
+    }
+
+    /// Stop the conversion, killing the container if it is still running
+    fn close(&self, py: Python<'_>) {
+        py.detach(|| self.stream.lock().unwrap().take());
+    }
+}
+
+/// Convert a document to pixels, returning an iterator that yields each
+/// `PageData` as soon as the container produced it
+#[pyfunction]
+#[pyo3(signature = (input_path, *, timeout = None, runtime = None))]
+fn iter_pages(
+    py: Python<'_>,
+    input_path: PathBuf,
+    timeout: Option<f64>,
+    runtime: Option<String>,
+) -> PyResult<PageIterator> {
+    let input_path = path_string(input_path)?;
+    let options = conversion_options(false, None, None, timeout, runtime)?;
+
+    let stream = py
+        .detach(|| stream_doc_to_pages(input_path, &options, &CancellationToken::new()))
+        .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))?;
+    Ok(PageIterator {
+        stream: Mutex::new(Some(stream)),
+    })
 }
 
-/// Wrapper for convert_document that converts Result to PyResult
+/// Pull the image and start a container once, so that the first conversion
+/// starts quickly. Releases the GIL, so it can run in a background thread
+/// while the application starts.
 #[pyfunction]
-fn convert_document(input_path: String, output_path: String, apply_ocr: bool) -> PyResult<()> {
-    core_convert_document(input_path, output_path, apply_ocr)
+#[pyo3(signature = (*, timeout = None, runtime = None))]
+fn warmup(py: Python<'_>, timeout: Option<f64>, runtime: Option<String>) -> PyResult<()> {
+    let options = conversion_options(false, None, None, timeout, runtime)?;
+    py.detach(|| core_warmup(&options, &CancellationToken::new()))
         .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))
 }
 
+/// Outcome of converting one document of a `convert_batch`
+#[pyclass(get_all, frozen)]
+pub struct BatchResult {
+    pub input_path: String,
+    pub output_path: String,
+    pub success: bool,
+    /// Why the conversion failed, `None` on success
+    pub error: Option<String>,
+    /// Time taken by the conversion, in seconds
+    pub duration: f64,
+    /// What went differently than asked during a successful conversion
+    pub warnings: Vec<String>,
+}
+
+#[pymethods]
+impl BatchResult {
+    fn __repr__(&self) -> String {
+        format!(
+            "BatchResult(input_path={:?}, success={}, duration={:.1})",
+            self.input_path, self.success, self.duration
+        )
+    }
+}
+
+impl From<CoreBatchResult> for BatchResult {
+    fn from(core: CoreBatchResult) -> Self {
+        BatchResult {
+            input_path: core.input_path,
+            output_path: core.output_path,
+            success: core.result.is_ok(),
+            warnings: core.result.as_ref().map_or_else(
+                |_| Vec::new(),
+                |report| report.warnings.iter().map(ToString::to_string).collect(),
+            ),
+            error: core.result.err().map(|e| format!("{e:#}")),
+            duration: core.duration.as_secs_f64(),
+        }
+    }
+}
+
+/// Convert several documents into `output_dir` with up to `jobs`
+/// conversions at a time (by default, one per CPU), returning a
+/// `BatchResult` per input in the same order. A failed document doesn't
+/// stop the others. With `session_max_documents`, each worker reuses its
+/// sandbox for up to that many documents instead of starting one per
+/// document. `output_template`, such as `"{stem}-{date}-safe.pdf"`, names
+/// the safe PDFs instead of `<stem>-safe.pdf`.
+#[pyfunction]
+#[pyo3(signature = (
+    inputs,
+    output_dir,
+    jobs = None,
+    *,
+    ocr = false,
+    ocr_lang = None,
+    dpi = None,
+    timeout = None,
+    runtime = None,
+    session_max_documents = None,
+    output_template = None,
+))]
+#[allow(clippy::too_many_arguments)]
+fn convert_batch(
+    py: Python<'_>,
+    inputs: Vec<PathBuf>,
+    output_dir: PathBuf,
+    jobs: Option<usize>,
+    ocr: bool,
+    ocr_lang: Option<String>,
+    dpi: Option<f32>,
+    timeout: Option<f64>,
+    runtime: Option<String>,
+    session_max_documents: Option<usize>,
+    output_template: Option<String>,
+) -> PyResult<Vec<BatchResult>> {
+    let inputs = inputs
+        .into_iter()
+        .map(path_string)
+        .collect::<PyResult<Vec<_>>>()?;
+    let output_dir = path_string(output_dir)?;
+    let mut options = conversion_options(ocr, ocr_lang, dpi, timeout, runtime)?;
+    if session_max_documents == Some(0) {
+        return Err(PyValueError::new_err(
+            "session_max_documents must be at least 1",
+        ));
+    }
+    options.session_max_documents = session_max_documents;
+    options.output_template = output_template;
+    options
+        .parse_output_template()
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    let jobs = match jobs {
+        Some(0) => return Err(PyValueError::new_err("jobs must be at least 1")),
+        Some(jobs) => jobs,
+        None => std::thread::available_parallelism().map_or(1, usize::from),
+    };
+
+    py.detach(|| {
+        core_convert_batch(
+            &inputs,
+            &output_dir,
+            jobs,
+            &options,
+            &CancellationToken::new(),
+        )
+    })
+    .map(|results| results.into_iter().map(BatchResult::from).collect())
+    .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))
+}
+
 /// Wrapper for apply_ocr_fn that converts Result to PyResult
 #[pyfunction]
-fn apply_ocr_fn(input_pdf: String, output_pdf: String) -> PyResult<()> {
-    core_apply_ocr_fn(input_pdf, output_pdf)
+fn apply_ocr_fn(py: Python<'_>, input_pdf: PathBuf, output_pdf: PathBuf) -> PyResult<()> {
+    let input_pdf = path_string(input_pdf)?;
+    let output_pdf = path_string(output_pdf)?;
+    py.detach(|| core_apply_ocr_fn(input_pdf, output_pdf))
         .map_err(|e| PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(e.to_string()))
 }
 
 /// PyO3 module definition
+///
+/// Log records go to the `dangerzone_rs` logger of Python's `logging` module
+/// (sanitized container output to `dangerzone_rs.untrusted`) instead of
+/// stderr. Logger levels are looked up on every record, so changing them
+/// takes effect immediately.
 #[pymodule]
 pub fn dangerzone_rs(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    // Fails if another extension already installed a logger in this process,
+    // which then receives our records instead
+    let _ = pyo3_log::Logger::new(m.py(), pyo3_log::Caching::Loggers)?.install();
+
     m.add_class::<PageData>()?;
+    m.add_class::<PageIterator>()?;
+    m.add_class::<BatchResult>()?;
     m.add_function(wrap_pyfunction!(parse_pixel_data, m)?)?;
     m.add_function(wrap_pyfunction!(convert_doc_to_pixels, m)?)?;
     m.add_function(wrap_pyfunction!(pixels_to_pdf, m)?)?;
     m.add_function(wrap_pyfunction!(convert_document, m)?)?;
+    m.add_function(wrap_pyfunction!(iter_pages, m)?)?;
+    m.add_function(wrap_pyfunction!(convert_batch, m)?)?;
+    m.add_function(wrap_pyfunction!(warmup, m)?)?;
     m.add_function(wrap_pyfunction!(apply_ocr_fn, m)?)?;
     Ok(())
 }
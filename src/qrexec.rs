@@ -0,0 +1,182 @@
+//! Server side of a Qubes RPC call, for SecureDrop Workstation
+//!
+//! The calling VM writes the untrusted document to stdin and closes it. The
+//! safe PDF is written to stdout once the conversion succeeded, and nothing
+//! otherwise. Stderr is the side channel: one JSON object per line, either
+//! `{"progress": {"stage": "...", ...}}` for each conversion stage, with the
+//! stages of the JSON-RPC mode, or a final `{"error": "..."}`.
+//!
+//! The argument of the service, as in `dz.ConvertSafe+ocr`, selects
+//! options: `ocr` adds a text layer to the safe PDF.
+
+use crate::{convert_document_with_options, CancellationToken, ConversionOptions, Progress};
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::io::{Read, Write};
+
+/// Largest document read from the calling VM
+pub const MAX_DOCUMENT_BYTES: u64 = 256 << 20;
+
+/// `options` with those selected by the service argument `argument`
+pub fn apply_argument(
+    mut options: ConversionOptions,
+    argument: Option<&str>,
+) -> Result<ConversionOptions> {
+    match argument.unwrap_or_default() {
+        "" => {}
+        "ocr" => options.ocr = true,
+        other => anyhow::bail!(
+            "Unknown service argument '{}'",
+            crate::replace_control_chars(other, false)
+        ),
+    }
+    Ok(options)
+}
+
+/// Serve the call on stdin, stdout and stderr, with the options selected by
+/// `QREXEC_SERVICE_ARGUMENT`
+///
+/// Returns whether the conversion succeeded; its error, if any, was reported
+/// on stderr already.
+pub fn serve_stdio(options: ConversionOptions) -> Result<bool> {
+    let argument = std::env::var("QREXEC_SERVICE_ARGUMENT").ok();
+    let mut side = std::io::stderr();
+    let options = match apply_argument(options, argument.as_deref()) {
+        Ok(options) => options,
+        Err(e) => {
+            send(&mut side, &json!({ "error": format!("{e:#}") }))?;
+            return Ok(false);
+        }
+    };
+    serve(
+        std::io::stdin().lock(),
+        std::io::stdout().lock(),
+        side,
+        &options,
+    )
+}
+
+/// Convert the document read from `input` and write the safe PDF to
+/// `output`, reporting progress and errors on `side`
+pub fn serve<R: Read, W: Write, E: Write>(
+    input: R,
+    mut output: W,
+    side: E,
+    options: &ConversionOptions,
+) -> Result<bool> {
+    let mut side = RefCell::new(side);
+    let result = convert(input, options, &|progress| {
+        let message = json!({ "progress": progress_value(&progress) });
+        let _ = send(&mut *side.borrow_mut(), &message);
+    });
+    match result {
+        Ok(pdf) => {
+            output
+                .write_all(&pdf)
+                .and_then(|()| output.flush())
+                .context("Failed to write the safe PDF")?;
+            Ok(true)
+        }
+        Err(e) => {
+            send(side.get_mut(), &json!({ "error": format!("{e:#}") }))?;
+            Ok(false)
+        }
+    }
+}
+
+fn convert(
+    input: impl Read,
+    options: &ConversionOptions,
+    progress: &dyn Fn(Progress),
+) -> Result<Vec<u8>> {
+    let dir = tempfile::tempdir().context("Failed to create temporary directory")?;
+    let input_path = dir.path().join("document");
+    let output_path = dir.path().join("safe.pdf");
+
+    let mut document = Vec::new();
+    input
+        .take(MAX_DOCUMENT_BYTES + 1)
+        .read_to_end(&mut document)
+        .context("Failed to read the document")?;
+    if document.len() as u64 > MAX_DOCUMENT_BYTES {
+        anyhow::bail!(
+            "The document is larger than {} MiB",
+            MAX_DOCUMENT_BYTES >> 20
+        );
+    }
+    std::fs::write(&input_path, document).context("Failed to write input document")?;
+
+    convert_document_with_options(
+        input_path.to_string_lossy().into_owned(),
+        output_path.to_string_lossy().into_owned(),
+        options,
+        progress,
+        &CancellationToken::new(),
+    )?;
+    std::fs::read(&output_path).context("Failed to read safe PDF")
+}
+
+fn progress_value(progress: &Progress) -> Value {
+    match progress {
+        Progress::ConvertingToPixels => json!({"stage": "converting_to_pixels"}),
+        Progress::PixelsReceived { total_pages } => {
+            json!({"stage": "pixels_received", "total_pages": total_pages})
+        }
+        Progress::WritingPage { page, total_pages } => json!({
+            "stage": "writing_page",
+            "page": page,
+            "total_pages": total_pages,
+        }),
+        Progress::ApplyingOcr => json!({"stage": "applying_ocr"}),
+        Progress::Done => json!({"stage": "done"}),
+    }
+}
+
+fn send(side: &mut impl Write, message: &Value) -> Result<()> {
+    serde_json::to_writer(&mut *side, message).context("Failed to encode side message")?;
+    side.write_all(b"\n")
+        .and_then(|()| side.flush())
+        .context("Failed to write side message")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_argument() {
+        let options = apply_argument(ConversionOptions::default(), Some("ocr")).unwrap();
+        assert!(options.ocr);
+        let options = apply_argument(ConversionOptions::default(), None).unwrap();
+        assert!(!options.ocr);
+        assert!(apply_argument(ConversionOptions::default(), Some("frobnicate")).is_err());
+    }
+
+    struct BrokenPipe;
+
+    impl Read for BrokenPipe {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    #[test]
+    fn test_failure_reported_on_side_channel() {
+        let mut output = Vec::new();
+        let mut side = Vec::new();
+        let options = ConversionOptions::default();
+        assert!(!serve(BrokenPipe, &mut output, &mut side, &options).unwrap());
+        assert!(output.is_empty());
+        let messages: Vec<Value> = String::from_utf8(side)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Failed to read the document"));
+    }
+}
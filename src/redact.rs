@@ -0,0 +1,478 @@
+//! Regions of pages blacked out in the pixels of the safe PDF
+//!
+//! Redactions drawn over a PDF as shapes can be moved away, and the text
+//! below them copied. Here the pixels of each region are replaced with black
+//! before the page is compressed, so the safe PDF, its text layer and its
+//! OCR sidecar never hold what was there.
+//!
+//! A redaction spec lists pages and their rectangles, in points (1/72 inch)
+//! from the top-left corner of the page as it came out of the sandbox,
+//! before any rotation:
+//!
+//! ```json
+//! [
+//!   {"page": 1, "rects": [{"x": 72, "y": 100, "width": 200, "height": 14}]},
+//!   {"page": 3, "rects": [{"x": 0, "y": 0, "width": 612, "height": 60}]}
+//! ]
+//! ```
+//!
+//! Text can also be redacted wherever it appears: each page is read by an
+//! OCR engine, and the words of lines matching one of the patterns of
+//! [`ConversionOptions::redact_patterns`](crate::ConversionOptions::redact_patterns)
+//! are blacked out. The report of the conversion lists what was removed.
+
+#[cfg(feature = "container")]
+use crate::hocr::{BBox, OcrPage};
+#[cfg(feature = "container")]
+use crate::ocr::{self, OcrEngine};
+#[cfg(feature = "container")]
+use crate::{page_size_pts, PageMetadata, PdfPage, PixelFormat};
+#[cfg(feature = "container")]
+use anyhow::Context;
+#[cfg(any(feature = "serde", feature = "container"))]
+use anyhow::Result;
+#[cfg(feature = "container")]
+use regex::Regex;
+#[cfg(feature = "container")]
+use std::io::Write;
+
+/// Rectangle of a page, in points from its top-left corner
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+/// Rectangles blacked out on a page
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PageRedactions {
+    /// Page number, starting at 1, counting the pages of the document
+    /// before blank pages are dropped
+    pub page: usize,
+    pub rects: Vec<Rect>,
+}
+
+/// Regions of the pages of a document blacked out in the safe PDF
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct Redactions {
+    pub pages: Vec<PageRedactions>,
+}
+
+impl Redactions {
+    /// Read a redaction spec, as JSON, from `path`
+    #[cfg(feature = "serde")]
+    pub fn load(path: &std::path::Path) -> Result<Self> {
+        use anyhow::Context;
+        let path_sanitized = crate::replace_control_chars(&path.to_string_lossy(), false);
+        let spec = std::fs::read(path)
+            .with_context(|| format!("Failed to read the redactions '{path_sanitized}'"))?;
+        let redactions: Redactions = serde_json::from_slice(&spec)
+            .with_context(|| format!("Invalid redactions '{path_sanitized}'"))?;
+        #[cfg(feature = "container")]
+        redactions.check()?;
+        Ok(redactions)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.iter().all(|page| page.rects.is_empty())
+    }
+
+    /// Fail on pages numbered 0 and on rectangles that aren't finite, or
+    /// are empty
+    #[cfg(feature = "container")]
+    pub(crate) fn check(&self) -> Result<()> {
+        for page in &self.pages {
+            if page.page == 0 {
+                anyhow::bail!("Invalid redaction: pages are numbered from 1");
+            }
+            for rect in &page.rects {
+                let valid = [rect.x, rect.y, rect.width, rect.height]
+                    .iter()
+                    .all(|value| value.is_finite())
+                    && rect.width > 0.0
+                    && rect.height > 0.0;
+                if !valid {
+                    anyhow::bail!(
+                        "Invalid redaction on page {}: {rect:?} must have finite coordinates \
+                         and a positive size",
+                        page.page
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Rectangles of page `page`, numbered from 1
+    #[cfg(feature = "container")]
+    pub(crate) fn rects(&self, page: usize) -> Vec<Rect> {
+        self.pages
+            .iter()
+            .filter(|redactions| redactions.page == page)
+            .flat_map(|redactions| redactions.rects.iter().copied())
+            .collect()
+    }
+}
+
+/// Text blacked out because it matched a pattern
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PatternMatch {
+    /// Page number, starting at 1, as in a redaction spec
+    pub page: usize,
+    /// The pattern that matched
+    pub pattern: String,
+    /// The text matched, as the OCR engine read it
+    pub text: String,
+}
+
+/// Margin around the words matched, in points, so that the edges of their
+/// glyphs aren't left over
+#[cfg(feature = "container")]
+const MATCH_MARGIN_PTS: f32 = 1.0;
+
+/// Compile `patterns`, failing on the first invalid one
+#[cfg(feature = "container")]
+pub(crate) fn compile_patterns(patterns: &[String]) -> Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern).with_context(|| {
+                format!(
+                    "Invalid redaction pattern '{pattern_sanitized}'",
+                    pattern_sanitized = crate::replace_control_chars(pattern, false)
+                )
+            })
+        })
+        .collect()
+}
+
+/// Finds the words of pages matching patterns, with an OCR engine reading
+/// pixels
+#[cfg(feature = "container")]
+pub(crate) struct PatternRedactor<'a> {
+    engine: &'a dyn OcrEngine,
+    patterns: Vec<Regex>,
+}
+
+#[cfg(feature = "container")]
+impl<'a> PatternRedactor<'a> {
+    pub(crate) fn new(engine: &'a dyn OcrEngine, patterns: &[String]) -> Result<Self> {
+        Ok(PatternRedactor {
+            engine,
+            patterns: compile_patterns(patterns)?,
+        })
+    }
+
+    /// Rectangles of the words of page `page_num` matching a pattern, and
+    /// what they matched
+    pub(crate) fn find<P: PdfPage>(
+        &self,
+        page: &P,
+        page_num: usize,
+        dpi: f32,
+    ) -> Result<(Vec<Rect>, Vec<PatternMatch>)> {
+        let (width_pts, height_pts) = page_size_pts(page, dpi);
+        let scale = (
+            width_pts / page.width() as f32,
+            height_pts / page.height() as f32,
+        );
+        let image = ocr::page_image(page).context("Failed to read page pixels")?;
+        let text = self
+            .engine
+            .recognize_page(&image, 72.0 / scale.0)
+            .with_context(|| format!("OCR of page {page_num} failed, so it can't be redacted"))?;
+        let (boxes, matches) = self.matches(&text, page_num);
+        let rects = boxes
+            .into_iter()
+            .map(|[left, top, right, bottom]| Rect {
+                x: left as f32 * scale.0 - MATCH_MARGIN_PTS,
+                y: top as f32 * scale.1 - MATCH_MARGIN_PTS,
+                width: right.saturating_sub(left) as f32 * scale.0 + 2.0 * MATCH_MARGIN_PTS,
+                height: bottom.saturating_sub(top) as f32 * scale.1 + 2.0 * MATCH_MARGIN_PTS,
+            })
+            .collect();
+        Ok((rects, matches))
+    }
+
+    /// Boxes of the words of `text` matching a pattern, and what they
+    /// matched
+    ///
+    /// Patterns are matched against each line, its words separated by
+    /// spaces, so they can span words but not lines. Every word a match
+    /// overlaps is blacked out whole.
+    fn matches(&self, text: &OcrPage, page_num: usize) -> (Vec<BBox>, Vec<PatternMatch>) {
+        let mut boxes = Vec::new();
+        let mut matches = Vec::new();
+        let lines = text
+            .blocks
+            .iter()
+            .flat_map(|block| &block.paragraphs)
+            .flat_map(|paragraph| &paragraph.lines);
+        for line in lines {
+            // The line and the range of each word in it
+            let mut line_text = String::new();
+            let mut spans = Vec::with_capacity(line.words.len());
+            for word in &line.words {
+                if !line_text.is_empty() {
+                    line_text.push(' ');
+                }
+                spans.push(line_text.len()..line_text.len() + word.text.len());
+                line_text.push_str(&word.text);
+            }
+            for pattern in &self.patterns {
+                for found in pattern.find_iter(&line_text) {
+                    if found.is_empty() {
+                        continue;
+                    }
+                    boxes.extend(
+                        line.words
+                            .iter()
+                            .zip(&spans)
+                            .filter(|(_, span)| {
+                                span.start < found.end() && found.start() < span.end
+                            })
+                            .map(|(word, _)| word.bbox),
+                    );
+                    matches.push(PatternMatch {
+                        page: page_num,
+                        pattern: pattern.as_str().to_string(),
+                        text: found.as_str().to_string(),
+                    });
+                }
+            }
+        }
+        (boxes, matches)
+    }
+}
+
+/// Page whose pixels under `rects` are blacked out while they are written
+#[cfg(feature = "container")]
+pub(crate) struct Redacted<P> {
+    pub(crate) page: P,
+    pub(crate) rects: Vec<Rect>,
+    /// Pixels per inch the page was converted at
+    pub(crate) dpi: f32,
+}
+
+#[cfg(feature = "container")]
+impl<P: PdfPage> PdfPage for Redacted<P> {
+    fn width(&self) -> u16 {
+        self.page.width()
+    }
+
+    fn height(&self) -> u16 {
+        self.page.height()
+    }
+
+    fn format(&self) -> PixelFormat {
+        self.page.format()
+    }
+
+    fn metadata(&self) -> PageMetadata {
+        self.page.metadata()
+    }
+
+    fn write_pixels(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        if self.rects.is_empty() {
+            return self.page.write_pixels(out);
+        }
+        let format = self.format();
+        let (width, height) = (self.width() as usize, self.height() as usize);
+        let mut pixels = Vec::with_capacity(width * height * format.bytes_per_pixel());
+        self.page.write_pixels(&mut pixels)?;
+
+        // Pixels per point, which pages of a known size may not have at
+        // the DPI
+        let (width_pts, height_pts) = page_size_pts(&self.page, self.dpi);
+        let scale = (width as f32 / width_pts, height as f32 / height_pts);
+        let black: &[u8] = match format {
+            PixelFormat::Gray => &[0],
+            PixelFormat::Rgb => &[0, 0, 0],
+            PixelFormat::Rgba => &[0, 0, 0, 255],
+        };
+        for rect in &self.rects {
+            // Round outwards, so that no partly covered pixel is kept
+            let columns = pixel_range(rect.x, rect.width, scale.0, width);
+            let rows = pixel_range(rect.y, rect.height, scale.1, height);
+            for row in rows {
+                let start = (row * width + columns.start) * black.len();
+                let end = (row * width + columns.end) * black.len();
+                for pixel in pixels[start..end].chunks_exact_mut(black.len()) {
+                    pixel.copy_from_slice(black);
+                }
+            }
+        }
+        out.write_all(&pixels)
+    }
+}
+
+/// Pixels covering `start..start + len` points, clamped to `max` pixels
+#[cfg(feature = "container")]
+fn pixel_range(start: f32, len: f32, scale: f32, max: usize) -> std::ops::Range<usize> {
+    let first = (start * scale).floor().clamp(0.0, max as f32) as usize;
+    let end = ((start + len) * scale).ceil().clamp(0.0, max as f32) as usize;
+    first..end.max(first)
+}
+
+#[cfg(all(test, feature = "container"))]
+mod tests {
+    use super::*;
+    use crate::PageData;
+
+    #[test]
+    fn test_redacted_pixels() {
+        // 4x2 white page at 72 DPI, so that a point is a pixel
+        let page = PageData::with_format(4, 2, PixelFormat::Gray, vec![255; 8]);
+        let rect = |x, y, width, height| Rect {
+            x,
+            y,
+            width,
+            height,
+        };
+        let redacted = |rects| {
+            let mut pixels = Vec::new();
+            Redacted {
+                page: &page,
+                rects,
+                dpi: 72.0,
+            }
+            .write_pixels(&mut pixels)
+            .unwrap();
+            pixels
+        };
+        assert_eq!(
+            redacted(vec![rect(1.0, 0.0, 2.0, 1.0)]),
+            [255, 0, 0, 255, 255, 255, 255, 255]
+        );
+        // Partly covered pixels are blacked out, and rectangles are clipped
+        assert_eq!(
+            redacted(vec![rect(2.5, 0.5, 10.0, 0.2)]),
+            [255, 255, 0, 0, 255, 255, 255, 255]
+        );
+        assert_eq!(
+            redacted(vec![rect(-5.0, 1.0, 6.0, 5.0)]),
+            [255, 255, 255, 255, 0, 255, 255, 255]
+        );
+        assert_eq!(redacted(vec![rect(10.0, 10.0, 1.0, 1.0)]), [255; 8]);
+
+        let rgba = PageData::with_format(1, 1, PixelFormat::Rgba, vec![9, 9, 9, 0]);
+        let mut pixels = Vec::new();
+        Redacted {
+            page: &rgba,
+            rects: vec![rect(0.0, 0.0, 1.0, 1.0)],
+            dpi: 72.0,
+        }
+        .write_pixels(&mut pixels)
+        .unwrap();
+        assert_eq!(pixels, [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_check() {
+        let redactions = |page, width| Redactions {
+            pages: vec![PageRedactions {
+                page,
+                rects: vec![Rect {
+                    x: 0.0,
+                    y: 0.0,
+                    width,
+                    height: 10.0,
+                }],
+            }],
+        };
+        assert!(redactions(1, 10.0).check().is_ok());
+        assert!(redactions(0, 10.0).check().is_err());
+        assert!(redactions(1, 0.0).check().is_err());
+        assert!(redactions(1, f32::NAN).check().is_err());
+        assert_eq!(redactions(2, 10.0).rects(2).len(), 1);
+        assert!(redactions(2, 10.0).rects(1).is_empty());
+    }
+
+    #[test]
+    fn test_pattern_matches() {
+        use crate::hocr::{OcrBlock, OcrLine, OcrParagraph, OcrWord};
+        use crate::ocr::OcrEngine;
+
+        struct NoEngine;
+        impl OcrEngine for NoEngine {
+            fn name(&self) -> &'static str {
+                "none"
+            }
+        }
+
+        let word = |left, text: &str| OcrWord {
+            bbox: [left, 0, left + 10, 10],
+            confidence: None,
+            text: text.to_string(),
+        };
+        let line = |words| OcrLine {
+            bbox: [0, 0, 100, 10],
+            baseline: None,
+            words,
+        };
+        let text = OcrPage {
+            bbox: [0, 0, 100, 20],
+            blocks: vec![OcrBlock {
+                bbox: [0, 0, 100, 20],
+                paragraphs: vec![OcrParagraph {
+                    bbox: [0, 0, 100, 20],
+                    lang: None,
+                    lines: vec![
+                        line(vec![
+                            word(0, "SSN:"),
+                            word(20, "123-45-6789,"),
+                            word(40, "ok"),
+                        ]),
+                        line(vec![word(0, "Jane"), word(20, "Doe"), word(40, "signed")]),
+                    ],
+                }],
+            }],
+        };
+        let redactor = PatternRedactor::new(
+            &NoEngine,
+            &[r"\d{3}-\d{2}-\d{4}".to_string(), "Jane Doe".to_string()],
+        )
+        .unwrap();
+        let (boxes, matches) = redactor.matches(&text, 3);
+        // The words a match overlaps are blacked out whole
+        assert_eq!(boxes, [[20, 0, 30, 10], [0, 0, 10, 10], [20, 0, 30, 10]]);
+        let found: Vec<(usize, &str)> = matches
+            .iter()
+            .map(|found| (found.page, found.text.as_str()))
+            .collect();
+        assert_eq!(found, [(3, "123-45-6789"), (3, "Jane Doe")]);
+
+        assert!(compile_patterns(&["(".to_string()]).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("redactions.json");
+        std::fs::write(
+            &path,
+            r#"[{"page": 2, "rects": [{"x": 1, "y": 2, "width": 3, "height": 4}]}]"#,
+        )
+        .unwrap();
+        let redactions = Redactions::load(&path).unwrap();
+        assert_eq!(
+            redactions.rects(2),
+            [Rect {
+                x: 1.0,
+                y: 2.0,
+                width: 3.0,
+                height: 4.0
+            }]
+        );
+        std::fs::write(&path, r#"[{"page": 0, "rects": []}]"#).unwrap();
+        assert!(Redactions::load(&path).is_err());
+    }
+}
@@ -0,0 +1,115 @@
+//! Rendering of PDFs back to pixels with poppler's `pdftoppm`
+//!
+//! Meant for testing the PDF writer: a safe PDF rendered at the resolution
+//! its pages were written at gives their pixels back, up to the rounding of
+//! the renderer.
+
+use crate::{replace_control_chars, PageData};
+use anyhow::{Context, Result};
+use std::process::{Command, Stdio};
+
+/// Render each page of the PDF at `path` to RGB pixels at `dpi`
+///
+/// Pages are composited over white, like on screen. Requires `pdftoppm`,
+/// from poppler-utils, in `PATH`.
+pub fn render_pdf_to_pixels(path: &str, dpi: f32) -> Result<Vec<PageData>> {
+    if !(dpi.is_finite() && dpi > 0.0) {
+        anyhow::bail!("Invalid DPI {dpi}: must be a positive number");
+    }
+    let temp_dir = tempfile::Builder::new()
+        .prefix("dangerzone-rs-render-")
+        .tempdir()
+        .context("Failed to create temporary directory")?;
+    let output = Command::new("pdftoppm")
+        .arg("-r")
+        .arg(dpi.to_string())
+        .arg(path)
+        .arg(temp_dir.path().join("page"))
+        .stdin(Stdio::null())
+        .output()
+        .context("Failed to run pdftoppm; is poppler-utils installed?")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "pdftoppm failed: {stderr_sanitized}",
+            stderr_sanitized =
+                replace_control_chars(String::from_utf8_lossy(&output.stderr).trim(), true)
+        );
+    }
+
+    // Page numbers are zero-padded to the same width, so names sort by page
+    let mut images = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    images.sort();
+    images
+        .iter()
+        .enumerate()
+        .map(|(index, image)| {
+            read_ppm(&std::fs::read(image)?)
+                .with_context(|| format!("Failed to read rendered page {}", index + 1))
+        })
+        .collect()
+}
+
+/// Parse a binary PPM (P6) image with 8 bits per channel
+fn read_ppm(data: &[u8]) -> Result<PageData> {
+    let mut pos = 0;
+    let mut fields = [0usize; 4];
+    for (i, field) in fields.iter_mut().enumerate() {
+        // Whitespace and comments between fields
+        loop {
+            match data.get(pos) {
+                Some(byte) if byte.is_ascii_whitespace() => pos += 1,
+                Some(b'#') => {
+                    while data.get(pos).is_some_and(|&byte| byte != b'\n') {
+                        pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+        let start = pos;
+        while data
+            .get(pos)
+            .is_some_and(|byte| !byte.is_ascii_whitespace())
+        {
+            pos += 1;
+        }
+        let token = std::str::from_utf8(&data[start..pos])?;
+        *field = match i {
+            0 if token == "P6" => 0,
+            0 => anyhow::bail!("Not a binary PPM image"),
+            _ => token.parse().context("Invalid PPM header")?,
+        };
+    }
+    // A single whitespace byte separates the header from the pixels
+    pos += 1;
+
+    let [_, width, height, max_value] = fields;
+    if max_value != 255 {
+        anyhow::bail!("PPM image has {max_value} levels per channel, not 255");
+    }
+    let (Ok(width), Ok(height)) = (u16::try_from(width), u16::try_from(height)) else {
+        anyhow::bail!("PPM image of {width}x{height} pixels is too large for a page");
+    };
+    let pixels = data
+        .get(pos..pos + width as usize * height as usize * 3)
+        .context("PPM image is truncated")?;
+    Ok(PageData::new(width, height, pixels.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_ppm() {
+        let page = read_ppm(b"P6\n# pdftoppm\n2 1\n255\n\x01\x02\x03\x04\x05\x06").unwrap();
+        assert_eq!((page.width, page.height), (2, 1));
+        assert_eq!(page.pixels[..], [1, 2, 3, 4, 5, 6]);
+
+        assert!(read_ppm(b"P5\n1 1\n255\n\x00").is_err());
+        assert!(read_ppm(b"P6\n2 1\n255\n\x01\x02").is_err());
+        assert!(read_ppm(b"P6\n1 1\n65535\n\x00\x00\x00\x00\x00\x00").is_err());
+    }
+}
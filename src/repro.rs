@@ -0,0 +1,187 @@
+//! Shell scripts reproducing the sandbox of a failed conversion
+//!
+//! The script runs the same command as the conversion did, with the
+//! variables of the host that select and configure the runtime, but not the
+//! document: users pass their own as its argument, so they can share the
+//! script when reporting a runtime issue without sharing the document.
+
+use crate::{replace_control_chars, ConversionOptions};
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Variables of the host selecting and configuring the container runtime
+const RUNTIME_ENV: [&str; 9] = [
+    "PATH",
+    "XDG_RUNTIME_DIR",
+    "CONTAINER_HOST",
+    "CONTAINER_CONNECTION",
+    "CONTAINERS_CONF",
+    "CONTAINERS_STORAGE_CONF",
+    "DOCKER_HOST",
+    "DOCKER_CONTEXT",
+    "DOCKER_CONFIG",
+];
+
+/// Script starting a sandbox the way a conversion did, written to
+/// [`ConversionOptions::repro_script`] if the sandbox fails
+pub(crate) struct ReproScript {
+    path: Option<PathBuf>,
+    script: String,
+}
+
+impl ReproScript {
+    /// Script for the sandbox started by `command`, logging the command
+    /// first if [`ConversionOptions::print_command`] asks for it
+    pub(crate) fn new(command: &Command, options: &ConversionOptions) -> Self {
+        let mut args: Vec<String> = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        let mut notes = Vec::new();
+        // Bubblewrap reads its seccomp filter from a pipe of this process
+        if let Some(index) = args.iter().position(|arg| arg == "--seccomp") {
+            args.drain(index..(index + 2).min(args.len()));
+            notes.push("# Runs without the seccomp filter bubblewrap read from a pipe\n");
+        }
+        // and writes its info to another one
+        if let Some(index) = args.iter().position(|arg| arg == "--info-fd") {
+            args.drain(index..(index + 2).min(args.len()));
+        }
+        let line = std::iter::once(command.get_program().to_string_lossy().into_owned())
+            .chain(args)
+            .map(|arg| quote(&arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if options.print_command {
+            info!(
+                "Sandbox command: {line_sanitized}",
+                line_sanitized = replace_control_chars(&line, false)
+            );
+        }
+
+        let mut script = format!(
+            "#!/bin/sh\n\
+             # Starts the sandbox of a failed conversion as dangerzone-rs {} did.\n\
+             # The document isn't included: pass it as the first argument, e.g.\n\
+             #   sh repro.sh document.pdf > pixels.bin\n",
+            env!("CARGO_PKG_VERSION")
+        );
+        script.extend(notes);
+        script.push_str("set -eu\n");
+        for key in RUNTIME_ENV {
+            if let Some(value) = std::env::var_os(key) {
+                script.push_str(&format!(
+                    "export {key}={}\n",
+                    quote(&value.to_string_lossy())
+                ));
+            }
+        }
+        for (key, value) in command.get_envs() {
+            let key = key.to_string_lossy();
+            match value {
+                Some(value) => script.push_str(&format!(
+                    "export {key}={}\n",
+                    quote(&value.to_string_lossy())
+                )),
+                None => script.push_str(&format!("unset {key}\n")),
+            }
+        }
+        script.push_str(&format!("exec {line} < \"$1\"\n"));
+        ReproScript {
+            path: options.repro_script.clone(),
+            script,
+        }
+    }
+
+    /// Write the script where the options asked for it, if they did, after
+    /// the sandbox failed
+    pub(crate) fn write(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let path_sanitized = replace_control_chars(&path.to_string_lossy(), false);
+        match write_executable(path, &self.script) {
+            Ok(()) => info!("Wrote a script reproducing the sandbox to: {path_sanitized}"),
+            Err(e) => warn!(
+                "Failed to write the script reproducing the sandbox to '{path_sanitized}': {e}"
+            ),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn write_executable(path: &Path, script: &str) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::write(path, script)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))
+}
+
+#[cfg(not(unix))]
+fn write_executable(path: &Path, script: &str) -> std::io::Result<()> {
+    std::fs::write(path, script)
+}
+
+/// `arg` as a single word of a POSIX shell
+fn quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-_./:=,+@%".contains(&byte));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(
+            quote("--security-opt=no-new-privileges"),
+            "--security-opt=no-new-privileges"
+        );
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("a b"), "'a b'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn test_script() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("repro.sh");
+        let mut command = Command::new("bwrap");
+        command
+            .args([
+                "--setenv",
+                "LANG",
+                "C.UTF-8",
+                "--seccomp",
+                "3",
+                "--info-fd",
+                "4",
+            ])
+            .arg("--")
+            .args(["/usr/bin/python3", "-c", "print('pixels')"])
+            .env("EXTRA", "a b");
+        let options = ConversionOptions {
+            repro_script: Some(path.clone()),
+            ..ConversionOptions::default()
+        };
+        let repro = ReproScript::new(&command, &options);
+        assert!(!path.exists());
+
+        repro.write();
+        let script = std::fs::read_to_string(&path).unwrap();
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.contains("# Runs without the seccomp filter"));
+        assert!(script.contains("export EXTRA='a b'\n"));
+        assert!(script.ends_with(
+            "exec bwrap --setenv LANG C.UTF-8 -- /usr/bin/python3 -c 'print('\\''pixels'\\'')' < \"$1\"\n"
+        ));
+    }
+}
@@ -0,0 +1,327 @@
+//! Batches that can be resumed after an interruption, from a state file
+//!
+//! The state file lists each document of the batch with the path of its
+//! safe PDF and whether it was converted. It is rewritten after each
+//! conversion, so that a batch of thousands of documents that was
+//! interrupted continues where it stopped: documents whose safe PDF was
+//! written are skipped, and those that failed or weren't converted yet are
+//! converted again. The paths of the safe PDFs are kept in the state, since
+//! an [`crate::naming::OutputTemplate`] may not name them the same on
+//! another day.
+
+use crate::{
+    batch_output_paths, replace_control_chars, spawn_conversions, BatchResult, CancellationToken,
+    Cancelled, ConversionOptions,
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::mpsc;
+
+/// Whether a document of a resumable batch was converted
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DocumentStatus {
+    /// Not converted yet, or cancelled
+    Pending,
+    Converted,
+    Failed,
+}
+
+/// Document of a resumable batch
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DocumentState {
+    pub input_path: String,
+    pub output_path: String,
+    pub status: DocumentStatus,
+    /// Why the last conversion failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// State of a resumable batch, as saved in its state file
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchState {
+    /// Directory the safe PDFs are written into
+    pub output_dir: String,
+    pub documents: Vec<DocumentState>,
+}
+
+impl BatchState {
+    /// Read the state file at `path`, or an empty state if there is none yet
+    pub fn load(path: &Path) -> Result<Self> {
+        let path_sanitized = replace_control_chars(&path.to_string_lossy(), false);
+        let json = match std::fs::read(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BatchState::default()),
+            Err(e) => {
+                return Err(e).context(format!("Failed to read the state file '{path_sanitized}'"))
+            }
+        };
+        serde_json::from_slice(&json).context(format!("Invalid state file '{path_sanitized}'"))
+    }
+
+    /// Write the state to the file at `path`, replacing its previous content
+    /// atomically
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self)?;
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        std::fs::write(&temp, json)
+            .and_then(|()| std::fs::rename(&temp, path))
+            .with_context(|| {
+                format!(
+                    "Failed to save the state file '{path_sanitized}'",
+                    path_sanitized = replace_control_chars(&path.to_string_lossy(), false)
+                )
+            })
+    }
+
+    /// Add the documents of `inputs` that aren't in the state yet, their
+    /// safe PDFs named by the template of `options` without overwriting
+    /// those of the other documents
+    fn plan(&mut self, inputs: &[String], options: &ConversionOptions) -> Result<()> {
+        let mut known: HashSet<&str> = self
+            .documents
+            .iter()
+            .map(|document| document.input_path.as_str())
+            .collect();
+        let new: Vec<String> = inputs
+            .iter()
+            .filter(|input| known.insert(input.as_str()))
+            .cloned()
+            .collect();
+        let template = options.parse_output_template()?;
+        let mut taken: HashSet<String> = self
+            .documents
+            .iter()
+            .map(|document| document.output_path.clone())
+            .collect();
+        for (input_path, output_path) in
+            new.iter()
+                .zip(batch_output_paths(&new, &self.output_dir, &template))
+        {
+            if !taken.insert(output_path.clone()) {
+                anyhow::bail!(
+                    "The safe PDF of '{input_sanitized}' would replace '{output_sanitized}', \
+                     of another document of the batch",
+                    input_sanitized = replace_control_chars(input_path, false),
+                    output_sanitized = replace_control_chars(&output_path, false)
+                );
+            }
+            self.documents.push(DocumentState {
+                input_path: input_path.clone(),
+                output_path,
+                status: DocumentStatus::Pending,
+                error: None,
+            });
+        }
+        Ok(())
+    }
+
+    /// Record the outcome of a conversion of the batch
+    fn record(&mut self, result: &BatchResult) {
+        let Some(document) = self
+            .documents
+            .iter_mut()
+            .find(|document| document.input_path == result.input_path)
+        else {
+            return;
+        };
+        (document.status, document.error) = match &result.result {
+            Ok(_) => (DocumentStatus::Converted, None),
+            Err(e) if e.is::<Cancelled>() => (DocumentStatus::Pending, None),
+            Err(e) => (DocumentStatus::Failed, Some(format!("{e:#}"))),
+        };
+    }
+}
+
+/// Documents of a [`convert_resumable`] batch, by outcome
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResumeSummary {
+    /// Converted by an earlier run
+    pub skipped: usize,
+    pub converted: usize,
+    pub failed: usize,
+    /// Not converted because the batch was cancelled
+    pub pending: usize,
+}
+
+/// Convert `inputs` into `output_dir` like [`crate::convert_iter`], keeping
+/// the state of the batch in the file at `state_path`, so that running it
+/// again skips the documents already converted and retries the others
+///
+/// `on_result` is called with the result of each conversion, once the state
+/// file records it. The state file must be that of a batch into the same
+/// `output_dir`; inputs that aren't in it are added to the batch.
+pub fn convert_resumable(
+    inputs: &[String],
+    output_dir: &str,
+    jobs: usize,
+    options: &ConversionOptions,
+    state_path: &Path,
+    cancel: &CancellationToken,
+    on_result: &mut dyn FnMut(&BatchResult),
+) -> Result<ResumeSummary> {
+    let mut state = BatchState::load(state_path)?;
+    if state.documents.is_empty() {
+        state.output_dir = output_dir.to_string();
+    } else if state.output_dir != output_dir {
+        anyhow::bail!(
+            "The state file '{state_sanitized}' is that of a batch into '{dir_sanitized}'",
+            state_sanitized = replace_control_chars(&state_path.to_string_lossy(), false),
+            dir_sanitized = replace_control_chars(&state.output_dir, false)
+        );
+    }
+    state.plan(inputs, options)?;
+
+    let mut summary = ResumeSummary::default();
+    let documents: HashMap<&str, &DocumentState> = state
+        .documents
+        .iter()
+        .map(|document| (document.input_path.as_str(), document))
+        .collect();
+    let (mut todo_inputs, mut todo_outputs) = (Vec::new(), Vec::new());
+    let mut seen = HashSet::new();
+    for input in inputs {
+        if !seen.insert(input) {
+            continue;
+        }
+        let document = documents[input.as_str()];
+        // A safe PDF removed since is written again
+        if document.status == DocumentStatus::Converted && Path::new(&document.output_path).exists()
+        {
+            summary.skipped += 1;
+        } else {
+            todo_inputs.push(input.clone());
+            todo_outputs.push(document.output_path.clone());
+        }
+    }
+    state.save(state_path)?;
+    if todo_inputs.is_empty() {
+        return Ok(summary);
+    }
+
+    std::fs::create_dir_all(output_dir).context(format!(
+        "Failed to create output directory '{output_dir_sanitized}'",
+        output_dir_sanitized = replace_control_chars(output_dir, false)
+    ))?;
+    let (sender, results) = mpsc::channel();
+    spawn_conversions(
+        todo_inputs,
+        todo_outputs,
+        jobs,
+        options,
+        cancel,
+        move |_, result| sender.send(result).is_ok(),
+    );
+    for result in results {
+        state.record(&result);
+        state.save(state_path)?;
+        match &result.result {
+            Ok(_) => summary.converted += 1,
+            Err(e) if e.is::<Cancelled>() => summary.pending += 1,
+            Err(_) => summary.failed += 1,
+        }
+        on_result(&result);
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_resumable() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_dir = dir.path().join("out").to_string_lossy().into_owned();
+        let state_path = dir.path().join("state.json");
+        let inputs: Vec<String> = ["a", "b", "c"]
+            .iter()
+            .map(|name| format!("/nonexistent/{name}.pdf"))
+            .collect();
+
+        // "a" was converted by an earlier run
+        std::fs::create_dir(dir.path().join("out")).unwrap();
+        let a_output = format!("{output_dir}/a-safe.pdf");
+        std::fs::write(&a_output, "%PDF").unwrap();
+        let earlier = BatchState {
+            output_dir: output_dir.clone(),
+            documents: vec![DocumentState {
+                input_path: inputs[0].clone(),
+                output_path: a_output,
+                status: DocumentStatus::Converted,
+                error: None,
+            }],
+        };
+        earlier.save(&state_path).unwrap();
+
+        // Invalid options fail each conversion right away
+        let invalid = ConversionOptions {
+            outline: true,
+            ..ConversionOptions::default()
+        };
+        let mut converted = Vec::new();
+        let summary = convert_resumable(
+            &inputs,
+            &output_dir,
+            2,
+            &invalid,
+            &state_path,
+            &CancellationToken::new(),
+            &mut |result| converted.push(result.input_path.clone()),
+        )
+        .unwrap();
+        assert_eq!(
+            summary,
+            ResumeSummary {
+                skipped: 1,
+                failed: 2,
+                ..ResumeSummary::default()
+            }
+        );
+        converted.sort();
+        assert_eq!(converted, inputs[1..]);
+        let state = BatchState::load(&state_path).unwrap();
+        assert_eq!(state.documents.len(), 3);
+        assert_eq!(state.documents[1].status, DocumentStatus::Failed);
+        assert!(state.documents[1].error.as_ref().unwrap().contains("OCR"));
+        assert_eq!(
+            state.documents[2].output_path,
+            format!("{output_dir}/c-safe.pdf")
+        );
+
+        // Failed documents are retried, and cancelled ones left pending
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let summary = convert_resumable(
+            &inputs,
+            &output_dir,
+            2,
+            &ConversionOptions::default(),
+            &state_path,
+            &cancel,
+            &mut |_| {},
+        )
+        .unwrap();
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.pending, 2);
+        let state = BatchState::load(&state_path).unwrap();
+        assert_eq!(state.documents[1].status, DocumentStatus::Pending);
+        assert_eq!(state.documents[1].error, None);
+
+        let other_dir = dir.path().join("other").to_string_lossy().into_owned();
+        let result = convert_resumable(
+            &inputs,
+            &other_dir,
+            1,
+            &ConversionOptions::default(),
+            &state_path,
+            &cancel,
+            &mut |_| {},
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,344 @@
+//! JSON-RPC 2.0 over stdio, for GUI frontends embedding the engine as a
+//! long-lived child process
+//!
+//! Every message is a single line of JSON. Frontends send requests:
+//!
+//! - `convert` `{"input": "...", "output": "...", "ocr": false}` starts a
+//!   conversion in the background and returns `{"job": <id>}`; with
+//!   `"ocr_required": true`, the job fails if OCR does
+//! - `cancel` `{"job": <id>}` cancels a running conversion and returns whether
+//!   the job was still running
+//! - `shutdown` cancels all running conversions, waits for them and returns
+//!   `null`; the engine exits afterwards
+//!
+//! and receive notifications:
+//!
+//! - `progress` `{"job": <id>, "stage": "...", ...}` for each conversion stage
+//! - `finished` `{"job": <id>, "status": "succeeded" | "failed" | "cancelled"}`,
+//!   with an `error` message for failed jobs, and an `ocr_fallback` message
+//!   for jobs that succeeded without the text layer OCR was asked for, an
+//!   `ocr_lang` with the languages detected for an `ocr_lang` of `auto`, and
+//!   the `warnings` of the conversion, if any, as objects with a `kind`
+//!
+//! Log messages and sanitized container output keep going to stderr, so
+//! stdout only ever carries protocol messages.
+
+use crate::{
+    convert_document_with_options, CancellationToken, Cancelled, ConversionOptions, Progress,
+};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct ConvertParams {
+    input: String,
+    output: String,
+    #[serde(default)]
+    ocr: bool,
+    #[serde(default)]
+    ocr_required: bool,
+}
+
+#[derive(Deserialize)]
+struct CancelParams {
+    job: u64,
+}
+
+/// Serve JSON-RPC requests from stdin until `shutdown` or end of input
+pub fn serve_stdio() -> Result<()> {
+    serve(std::io::stdin().lock(), std::io::stdout())
+}
+
+/// Serve JSON-RPC requests read from `reader`, writing responses and
+/// notifications to `writer`
+pub fn serve<R: BufRead, W: Write + Send + 'static>(reader: R, writer: W) -> Result<()> {
+    let mut server = Server {
+        out: Arc::new(Mutex::new(writer)),
+        jobs: Arc::new(Mutex::new(HashMap::new())),
+        workers: Vec::new(),
+        next_job: 1,
+    };
+
+    for line in reader.lines() {
+        let line = line.context("Failed to read JSON-RPC request")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if server.handle_line(&line)? == Flow::Shutdown {
+            return Ok(());
+        }
+    }
+
+    // The frontend closed our stdin: behave as if it asked for a shutdown
+    server.shutdown();
+    Ok(())
+}
+
+#[derive(PartialEq)]
+enum Flow {
+    Continue,
+    Shutdown,
+}
+
+struct Server<W> {
+    out: Arc<Mutex<W>>,
+    jobs: Arc<Mutex<HashMap<u64, CancellationToken>>>,
+    workers: Vec<JoinHandle<()>>,
+    next_job: u64,
+}
+
+impl<W: Write + Send + 'static> Server<W> {
+    fn handle_line(&mut self, line: &str) -> Result<Flow> {
+        let value: Value = match serde_json::from_str(line) {
+            Ok(value) => value,
+            Err(e) => {
+                send(&self.out, &error(Value::Null, PARSE_ERROR, &e.to_string()))?;
+                return Ok(Flow::Continue);
+            }
+        };
+        let request: Request = match serde_json::from_value(value) {
+            Ok(request) => request,
+            Err(e) => {
+                send(
+                    &self.out,
+                    &error(Value::Null, INVALID_REQUEST, &e.to_string()),
+                )?;
+                return Ok(Flow::Continue);
+            }
+        };
+
+        let (flow, response) = match request.method.as_str() {
+            "convert" => {
+                self.convert(request.id, request.params)?;
+                return Ok(Flow::Continue);
+            }
+            "cancel" => (Flow::Continue, self.cancel(request.params)),
+            "shutdown" => {
+                self.shutdown();
+                (Flow::Shutdown, Ok(Value::Null))
+            }
+            method => (
+                Flow::Continue,
+                Err((METHOD_NOT_FOUND, format!("Unknown method '{method}'"))),
+            ),
+        };
+        self.respond(request.id, response)?;
+        Ok(flow)
+    }
+
+    fn respond(&self, id: Option<Value>, response: Result<Value, (i64, String)>) -> Result<()> {
+        // Requests without an id are notifications and get no response
+        let Some(id) = id else {
+            return Ok(());
+        };
+        let message = match response {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err((code, message)) => error(id, code, &message),
+        };
+        send(&self.out, &message)
+    }
+
+    fn convert(&mut self, id: Option<Value>, params: Value) -> Result<()> {
+        let params: ConvertParams = match serde_json::from_value(params) {
+            Ok(params) => params,
+            Err(e) => return self.respond(id, Err((INVALID_PARAMS, e.to_string()))),
+        };
+
+        self.workers.retain(|worker| !worker.is_finished());
+        let job = self.next_job;
+        self.next_job += 1;
+        let cancel = CancellationToken::new();
+        self.jobs.lock().unwrap().insert(job, cancel.clone());
+
+        // Answer before starting, so the job id is known to the frontend by
+        // the time its first progress notification arrives
+        self.respond(id, Ok(json!({ "job": job })))?;
+
+        let out = Arc::clone(&self.out);
+        let jobs = Arc::clone(&self.jobs);
+        self.workers.push(std::thread::spawn(move || {
+            let on_progress = |progress: Progress| {
+                let _ = send(
+                    &out,
+                    &notification("progress", progress_params(job, &progress)),
+                );
+            };
+            let options = ConversionOptions {
+                ocr: params.ocr,
+                ocr_required: params.ocr_required,
+                ..ConversionOptions::default()
+            };
+            let result = convert_document_with_options(
+                params.input,
+                params.output,
+                &options,
+                &on_progress,
+                &cancel,
+            );
+            jobs.lock().unwrap().remove(&job);
+
+            let params = match result {
+                Ok(report) => {
+                    let mut params = json!({"job": job, "status": "succeeded"});
+                    if let Some(reason) = report.ocr_fallback {
+                        params["ocr_fallback"] = json!(reason);
+                    }
+                    if let Some(lang) = report.ocr_lang {
+                        params["ocr_lang"] = json!(lang);
+                    }
+                    if !report.warnings.is_empty() {
+                        params["warnings"] = json!(report.warnings);
+                    }
+                    params
+                }
+                Err(e) if e.is::<Cancelled>() => json!({"job": job, "status": "cancelled"}),
+                Err(e) => json!({"job": job, "status": "failed", "error": format!("{e:#}")}),
+            };
+            let _ = send(&out, &notification("finished", params));
+        }));
+
+        Ok(())
+    }
+
+    fn cancel(&mut self, params: Value) -> Result<Value, (i64, String)> {
+        let params: CancelParams =
+            serde_json::from_value(params).map_err(|e| (INVALID_PARAMS, e.to_string()))?;
+
+        match self.jobs.lock().unwrap().get(&params.job) {
+            Some(cancel) => {
+                cancel.cancel();
+                Ok(Value::Bool(true))
+            }
+            None => Ok(Value::Bool(false)),
+        }
+    }
+
+    fn shutdown(&mut self) {
+        for cancel in self.jobs.lock().unwrap().values() {
+            cancel.cancel();
+        }
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn progress_params(job: u64, progress: &Progress) -> Value {
+    match progress {
+        Progress::ConvertingToPixels => json!({"job": job, "stage": "converting_to_pixels"}),
+        Progress::PixelsReceived { total_pages } => {
+            json!({"job": job, "stage": "pixels_received", "total_pages": total_pages})
+        }
+        Progress::WritingPage { page, total_pages } => json!({
+            "job": job,
+            "stage": "writing_page",
+            "page": page,
+            "total_pages": total_pages,
+        }),
+        Progress::ApplyingOcr => json!({"job": job, "stage": "applying_ocr"}),
+        Progress::Done => json!({"job": job, "stage": "done"}),
+    }
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({"jsonrpc": "2.0", "method": method, "params": params})
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+fn send<W: Write>(out: &Mutex<W>, message: &Value) -> Result<()> {
+    let mut out = out.lock().unwrap();
+    serde_json::to_writer(&mut *out, message).context("Failed to encode JSON-RPC message")?;
+    out.write_all(b"\n")
+        .and_then(|_| out.flush())
+        .context("Failed to write JSON-RPC message")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run(input: &str) -> Vec<Value> {
+        let buffer = SharedBuffer::default();
+        serve(input.as_bytes(), buffer.clone()).unwrap();
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_rpc_errors_and_shutdown() {
+        let messages = run(concat!(
+            "not json\n",
+            "{\"jsonrpc\": \"2.0\", \"id\": 1, \"method\": \"frobnicate\"}\n",
+            "{\"jsonrpc\": \"2.0\", \"id\": 2, \"method\": \"cancel\", \"params\": {}}\n",
+            "{\"jsonrpc\": \"2.0\", \"id\": 3, \"method\": \"cancel\", \"params\": {\"job\": 7}}\n",
+            "{\"jsonrpc\": \"2.0\", \"method\": \"cancel\", \"params\": {\"job\": 7}}\n",
+            "{\"jsonrpc\": \"2.0\", \"id\": 4, \"method\": \"shutdown\"}\n",
+            "{\"jsonrpc\": \"2.0\", \"id\": 5, \"method\": \"shutdown\"}\n",
+        ));
+
+        assert_eq!(messages.len(), 5, "unexpected messages: {messages:?}");
+        assert_eq!(messages[0]["error"]["code"], PARSE_ERROR);
+        assert_eq!(messages[1]["id"], 1);
+        assert_eq!(messages[1]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(messages[2]["error"]["code"], INVALID_PARAMS);
+        assert_eq!(messages[3]["result"], false);
+        assert_eq!(
+            messages[4],
+            json!({"jsonrpc": "2.0", "id": 4, "result": null})
+        );
+    }
+
+    #[test]
+    fn test_rpc_failed_job_reports_finished() {
+        let messages = run(concat!(
+            "{\"jsonrpc\": \"2.0\", \"id\": 1, \"method\": \"convert\", ",
+            "\"params\": {\"input\": \"/nonexistent/input.pdf\", \"output\": \"/nonexistent/out.pdf\"}}\n",
+        ));
+
+        assert_eq!(messages[0]["result"], json!({"job": 1}));
+        assert_eq!(messages[1]["method"], "progress");
+        assert_eq!(messages[1]["params"]["stage"], "converting_to_pixels");
+        let finished = messages.last().unwrap();
+        assert_eq!(finished["method"], "finished");
+        assert_eq!(finished["params"]["job"], 1);
+        assert_eq!(finished["params"]["status"], "failed");
+    }
+}
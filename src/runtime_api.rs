@@ -0,0 +1,1029 @@
+//! Client of the REST API of podman and docker, on their Unix socket
+//!
+//! Both serve Docker's API, which podman implements as its compatibility
+//! layer, so the same requests drive either. Each request opens a connection
+//! that is closed after the response. Attaching to a container upgrades its
+//! connection to a raw stream carrying the container's stdin one way, and
+//! its stdout and stderr the other, multiplexed in frames.
+//!
+//! Containers are created from the same `run` arguments as the command-line
+//! client gets, translated to the API's configuration, so both start the
+//! sandbox with the same restrictions.
+
+use crate::{CancellationToken, Cancelled, Runtime, CONTAINER_POLL_INTERVAL};
+use anyhow::{Context, Result};
+use log::debug;
+use serde_json::{json, Map, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+
+/// Version of Docker's API requested, the oldest podman's compatibility
+/// layer serves
+const API_VERSION: &str = "v1.41";
+
+/// Frames of output buffered between the container and the reader of its
+/// stdout or stderr
+const BUFFERED_FRAMES: usize = 16;
+
+/// Error returned by the runtime: the HTTP status of the response and the
+/// message it carried
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (HTTP {})", self.message, self.status)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Converter image as the runtime describes it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImageInfo {
+    /// Identifier of the image, such as `sha256:…`
+    pub id: String,
+    pub os: String,
+    pub architecture: String,
+    /// Names of the image, with their tag
+    pub repo_tags: Vec<String>,
+}
+
+impl ImageInfo {
+    /// Platform of the image, `os/architecture`
+    pub fn platform(&self) -> String {
+        format!("{}/{}", self.os, self.architecture)
+    }
+}
+
+/// Client of the API socket of a container runtime
+#[derive(Clone, Debug)]
+pub struct Client {
+    socket: PathBuf,
+}
+
+impl Client {
+    /// Client of the socket of `runtime`: the Unix socket named by
+    /// `CONTAINER_HOST` for podman or `DOCKER_HOST` for docker, if set, or
+    /// else the default socket of the user, then that of the system
+    pub fn connect(runtime: Runtime) -> Result<Self> {
+        let (variable, defaults) = match runtime {
+            Runtime::Podman | Runtime::Gvisor => {
+                let mut defaults = Vec::new();
+                if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+                    defaults.push(PathBuf::from(dir).join("podman/podman.sock"));
+                }
+                defaults.push(PathBuf::from("/run/podman/podman.sock"));
+                ("CONTAINER_HOST", defaults)
+            }
+            Runtime::Docker => ("DOCKER_HOST", vec![PathBuf::from("/var/run/docker.sock")]),
+            Runtime::Bwrap | Runtime::AppleContainer => {
+                anyhow::bail!("{runtime} has no API compatible with Docker's")
+            }
+        };
+        if let Ok(host) = std::env::var(variable) {
+            let socket = host.strip_prefix("unix://").with_context(|| {
+                format!("{variable} must name a Unix socket, as unix:///path, to use the API of {runtime}")
+            })?;
+            return Ok(Client::with_socket(socket));
+        }
+        let socket = defaults
+            .into_iter()
+            .find(|socket| socket.exists())
+            .with_context(|| {
+                format!(
+                    "The API socket of {runtime} was not found. Start it (e.g. systemctl --user \
+                     start podman.socket) or set {variable} to unix:///path/to/socket."
+                )
+            })?;
+        Ok(Client::with_socket(socket))
+    }
+
+    /// Client of the API served on `socket`
+    pub fn with_socket(socket: impl Into<PathBuf>) -> Self {
+        Client {
+            socket: socket.into(),
+        }
+    }
+
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    /// The image named `name`, or `None` if the runtime doesn't have it
+    pub fn inspect_image(&self, name: &str) -> Result<Option<ImageInfo>> {
+        let image = match self.call("GET", &format!("/images/{}/json", encode(name)), None) {
+            Ok(image) => image,
+            Err(e)
+                if e.downcast_ref::<ApiError>()
+                    .is_some_and(|e| e.status == 404) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+        let string = |key: &str| image[key].as_str().unwrap_or_default().to_string();
+        Ok(Some(ImageInfo {
+            id: string("Id"),
+            os: string("Os"),
+            architecture: string("Architecture"),
+            repo_tags: image["RepoTags"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|tag| tag.as_str().map(str::to_string))
+                .collect(),
+        }))
+    }
+
+    /// Pull the `latest` tag of the image `name`, for `platform` if given,
+    /// stopping once `cancel` is triggered
+    pub fn pull_image(
+        &self,
+        name: &str,
+        platform: Option<&str>,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        let mut path = format!("/images/create?fromImage={}&tag=latest", encode(name));
+        if let Some(platform) = platform {
+            path.push_str(&format!("&platform={}", encode(platform)));
+        }
+        let response = self.send("POST", &path, None, Some(cancel))?;
+        let status = response.status;
+        let mut body = BufReader::new(response.into_body());
+        // One JSON object per line, reporting the progress or the error
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match body.read_line(&mut line) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(_) if cancel.is_cancelled() => return Err(Cancelled.into()),
+                Err(e) => return Err(e).context("Failed to read the progress of the pull"),
+            }
+            let Ok(progress) = serde_json::from_str::<Value>(&line) else {
+                continue;
+            };
+            if let Some(message) = progress["error"].as_str() {
+                return Err(ApiError {
+                    status,
+                    message: message.to_string(),
+                }
+                .into());
+            }
+        }
+        if status >= 400 {
+            anyhow::bail!(ApiError {
+                status,
+                message: format!("Failed to pull {name}"),
+            });
+        }
+        Ok(())
+    }
+
+    /// Create, attach to and start a container from the arguments of the
+    /// runtime's `run` command
+    pub(crate) fn run(&self, args: &[String]) -> Result<Container> {
+        let request = CreateRequest::from_run_args(args)?;
+        let mut path = "/containers/create".to_string();
+        let mut query = Vec::new();
+        if let Some(name) = &request.name {
+            query.push(format!("name={}", encode(name)));
+        }
+        if let Some(platform) = &request.platform {
+            query.push(format!("platform={}", encode(platform)));
+        }
+        if !query.is_empty() {
+            path.push('?');
+            path.push_str(&query.join("&"));
+        }
+        let created = self.call("POST", &path, Some(&request.body))?;
+        let id = created["Id"]
+            .as_str()
+            .context("The runtime didn't return the ID of the container")?
+            .to_string();
+        debug!("Created container {id}");
+
+        let attached = self.attach_and_start(&id);
+        if attached.is_err() {
+            self.remove(&id);
+        }
+        let stream = attached?;
+        let control = stream
+            .get_ref()
+            .try_clone()
+            .context("Failed to clone the attach stream")?;
+        let stdin = stream
+            .get_ref()
+            .try_clone()
+            .context("Failed to clone the attach stream")?;
+
+        let (stdout_sender, stdout) = mpsc::sync_channel(BUFFERED_FRAMES);
+        let (stderr_sender, stderr) = mpsc::sync_channel(BUFFERED_FRAMES);
+        std::thread::spawn(move || demux(stream, &stdout_sender, &stderr_sender));
+
+        let (exit_sender, exit) = mpsc::channel();
+        let client = self.clone();
+        let wait_id = id.clone();
+        std::thread::spawn(move || {
+            let result = client
+                .call("POST", &format!("/containers/{wait_id}/wait"), None)
+                .map(|response| response["StatusCode"].as_i64().unwrap_or(-1));
+            client.remove(&wait_id);
+            let _ = exit_sender.send(result);
+        });
+
+        Ok(Container {
+            client: self.clone(),
+            id,
+            control,
+            stdin: Some(AttachedStdin(stdin)),
+            stdout: Some(OutputReader::new(stdout)),
+            stderr: Some(OutputReader::new(stderr)),
+            exit,
+            status: None,
+        })
+    }
+
+    /// Attach to the container `id`, then start it, so no output is lost
+    fn attach_and_start(&self, id: &str) -> Result<BufReader<UnixStream>> {
+        let attached = self.send(
+            "POST",
+            &format!("/containers/{id}/attach?stream=1&stdin=1&stdout=1&stderr=1"),
+            None,
+            None,
+        )?;
+        let ResponseBody::Raw(stream) = attached.body else {
+            let status = attached.status;
+            return Err(attached.error(status).into());
+        };
+        self.call("POST", &format!("/containers/{id}/start"), None)?;
+        Ok(stream)
+    }
+
+    /// Remove the container `id`, killing it if it still runs
+    fn remove(&self, id: &str) {
+        if let Err(e) = self.call("DELETE", &format!("/containers/{id}?force=1"), None) {
+            debug!("Failed to remove container {id}: {e:#}");
+        }
+    }
+
+    /// Send a request and read its JSON response, failing with an
+    /// [`ApiError`] if the runtime did
+    fn call(&self, method: &str, path: &str, body: Option<&Value>) -> Result<Value> {
+        let response = self.send(method, path, body, None)?;
+        let status = response.status;
+        if status >= 400 {
+            return Err(response.error(status).into());
+        }
+        let mut data = Vec::new();
+        response
+            .into_body()
+            .read_to_end(&mut data)
+            .context("Failed to read the response of the runtime")?;
+        if data.iter().all(u8::is_ascii_whitespace) {
+            return Ok(Value::Null);
+        }
+        serde_json::from_slice(&data).context("The runtime sent an invalid response")
+    }
+
+    /// Send a request and read the head of its response, leaving its body
+    /// to be read; reading the body stops with an error once `cancel` is
+    /// triggered
+    fn send(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&Value>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<Response> {
+        let socket_sanitized = crate::replace_control_chars(&self.socket.to_string_lossy(), false);
+        let mut stream = UnixStream::connect(&self.socket)
+            .with_context(|| format!("Failed to connect to the runtime at '{socket_sanitized}'"))?;
+        let body = body.map(Value::to_string).unwrap_or_default();
+        let upgrade = path.contains("/attach?");
+        let mut head = format!("{method} /{API_VERSION}{path} HTTP/1.1\r\nHost: localhost\r\n");
+        if upgrade {
+            head.push_str("Connection: Upgrade\r\nUpgrade: tcp\r\n");
+        } else {
+            head.push_str("Connection: close\r\n");
+        }
+        if !body.is_empty() {
+            head.push_str("Content-Type: application/json\r\n");
+        }
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+        stream
+            .write_all(head.as_bytes())
+            .and_then(|()| stream.write_all(body.as_bytes()))
+            .context("Failed to send a request to the runtime")?;
+        Response::read(BufReader::new(stream), cancel.cloned(), upgrade)
+    }
+}
+
+/// Response of the runtime, with its body still to be read
+struct Response {
+    status: u16,
+    body: ResponseBody,
+}
+
+enum ResponseBody {
+    /// Body of the given length, or up to the end of the connection
+    Sized(std::io::Take<BufReader<Cancellable>>),
+    Chunked(ChunkedReader<BufReader<Cancellable>>),
+    /// Connection upgraded to a raw stream
+    Raw(BufReader<UnixStream>),
+}
+
+impl Response {
+    /// Read the head of a response; `upgrade` if the request asked for the
+    /// connection to be handed over
+    fn read(
+        mut reader: BufReader<UnixStream>,
+        cancel: Option<CancellationToken>,
+        upgrade: bool,
+    ) -> Result<Self> {
+        let mut line = String::new();
+        reader
+            .read_line(&mut line)
+            .context("Failed to read the response of the runtime")?;
+        let status = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .context("The runtime sent an invalid response")?;
+        let mut length = None;
+        let mut chunked = false;
+        loop {
+            line.clear();
+            reader
+                .read_line(&mut line)
+                .context("Failed to read the response of the runtime")?;
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let Some((name, value)) = header.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.parse().ok();
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                chunked = value.eq_ignore_ascii_case("chunked");
+            }
+        }
+
+        // Docker hands the connection over with 200 rather than 101 when it
+        // can't upgrade it
+        if upgrade && matches!(status, 101 | 200) {
+            return Ok(Response {
+                status,
+                body: ResponseBody::Raw(reader),
+            });
+        }
+        if cancel.is_some() {
+            reader
+                .get_ref()
+                .set_read_timeout(Some(CONTAINER_POLL_INTERVAL))?;
+        }
+        // Keep what was already buffered past the head
+        let buffered = reader.buffer().to_vec();
+        let stream = Cancellable {
+            buffered: std::io::Cursor::new(buffered),
+            stream: reader.into_inner(),
+            cancel,
+        };
+        let reader = BufReader::new(stream);
+        let body = if chunked {
+            ResponseBody::Chunked(ChunkedReader::new(reader))
+        } else {
+            ResponseBody::Sized(reader.take(length.unwrap_or(u64::MAX)))
+        };
+        Ok(Response { status, body })
+    }
+
+    fn into_body(self) -> Box<dyn Read> {
+        match self.body {
+            ResponseBody::Sized(reader) => Box::new(reader),
+            ResponseBody::Chunked(reader) => Box::new(reader),
+            ResponseBody::Raw(reader) => Box::new(reader),
+        }
+    }
+
+    /// The error of a failed response, with the message of its body
+    fn error(self, status: u16) -> ApiError {
+        let mut data = Vec::new();
+        let _ = self.into_body().take(64 << 10).read_to_end(&mut data);
+        let message = serde_json::from_slice::<Value>(&data)
+            .ok()
+            .and_then(|body| body["message"].as_str().map(str::to_string))
+            .unwrap_or_else(|| String::from_utf8_lossy(&data).trim().to_string());
+        ApiError {
+            status,
+            message: crate::replace_control_chars(&message, true),
+        }
+    }
+}
+
+/// Connection whose reads wait for data while checking a cancellation
+/// token, if any
+struct Cancellable {
+    buffered: std::io::Cursor<Vec<u8>>,
+    stream: UnixStream,
+    cancel: Option<CancellationToken>,
+}
+
+impl Read for Cancellable {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.buffered.read(buf)?;
+        if read > 0 {
+            return Ok(read);
+        }
+        loop {
+            match self.stream.read(buf) {
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    if self.cancel.as_ref().is_some_and(|c| c.is_cancelled()) {
+                        return Err(std::io::Error::other(Cancelled));
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Body sent with chunked transfer encoding
+struct ChunkedReader<R> {
+    reader: R,
+    /// Bytes left in the current chunk
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    fn new(reader: R) -> Self {
+        ChunkedReader {
+            reader,
+            remaining: 0,
+            done: false,
+        }
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            let mut line = String::new();
+            self.reader.read_line(&mut line)?;
+            // The CRLF ending the previous chunk
+            if line.trim().is_empty() {
+                line.clear();
+                self.reader.read_line(&mut line)?;
+            }
+            let size = line.trim().split(';').next().unwrap_or_default();
+            self.remaining = u64::from_str_radix(size, 16).map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid chunk size")
+            })?;
+            if self.remaining == 0 {
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let len = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let read = self.reader.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Body of a `containers/create` request, from the arguments of `run`
+#[derive(Debug, PartialEq)]
+struct CreateRequest {
+    name: Option<String>,
+    platform: Option<String>,
+    body: Value,
+}
+
+impl CreateRequest {
+    /// Translate the arguments of `run` the sandbox is started with, up to
+    /// the image and its command, failing on any the translation doesn't
+    /// know rather than dropping a restriction
+    fn from_run_args(args: &[String]) -> Result<Self> {
+        let mut name = None;
+        let mut platform = None;
+        let mut config = Map::new();
+        let mut host = Map::new();
+        let mut env = Vec::new();
+        let mut labels = Map::new();
+        let mut args = args.iter().map(String::as_str);
+        let push = |map: &mut Map<String, Value>, key: &str, value: &str| {
+            map.entry(key)
+                .or_insert_with(|| json!([]))
+                .as_array_mut()
+                .expect("an array")
+                .push(json!(value));
+        };
+        let image = loop {
+            let arg = args.next().context("No image in the arguments of run")?;
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag, Some(value)),
+                _ => (arg, None),
+            };
+            if !flag.starts_with('-') {
+                break arg;
+            }
+            let mut value = || -> Result<&str> {
+                inline
+                    .or_else(|| args.next())
+                    .with_context(|| format!("{flag} needs a value"))
+            };
+            match flag {
+                "--name" => name = Some(value()?.to_string()),
+                "--platform" => platform = Some(value()?.to_string()),
+                "--label" => {
+                    let (key, label) = value()?.split_once('=').context("Invalid label")?;
+                    labels.insert(key.to_string(), json!(label));
+                }
+                "--env" => env.push(json!(value()?)),
+                "-u" | "--user" => {
+                    config.insert("User".to_string(), json!(value()?));
+                }
+                "--network" => {
+                    let network = value()?;
+                    config.insert("NetworkDisabled".to_string(), json!(network == "none"));
+                    host.insert("NetworkMode".to_string(), json!(network));
+                }
+                "--log-driver" => {
+                    host.insert("LogConfig".to_string(), json!({ "Type": value()? }));
+                }
+                "--security-opt" => push(&mut host, "SecurityOpt", value()?),
+                "--cap-drop" => push(&mut host, "CapDrop", value()?),
+                "--cap-add" => push(&mut host, "CapAdd", value()?),
+                "--read-only" => {
+                    host.insert("ReadonlyRootfs".to_string(), json!(true));
+                }
+                "--tmpfs" => {
+                    let tmpfs = value()?;
+                    let (path, options) = tmpfs.split_once(':').unwrap_or((tmpfs, ""));
+                    host.entry("Tmpfs")
+                        .or_insert_with(|| json!({}))
+                        .as_object_mut()
+                        .expect("an object")
+                        .insert(path.to_string(), json!(options));
+                }
+                "--userns" => {
+                    host.insert("UsernsMode".to_string(), json!(value()?));
+                }
+                "--runtime" => {
+                    host.insert("Runtime".to_string(), json!(value()?));
+                }
+                "-i" | "--interactive" => {
+                    for key in ["AttachStdin", "AttachStdout", "AttachStderr"] {
+                        config.insert(key.to_string(), json!(true));
+                    }
+                    config.insert("OpenStdin".to_string(), json!(true));
+                    config.insert("StdinOnce".to_string(), json!(true));
+                }
+                // Removed once it exits, after its exit status was read
+                "--rm" => {}
+                flag => anyhow::bail!("{flag} has no equivalent in the runtime's API"),
+            }
+        };
+        config.insert("Image".to_string(), json!(image));
+        config.insert("Cmd".to_string(), json!(args.collect::<Vec<_>>()));
+        config.insert("Env".to_string(), Value::Array(env));
+        config.insert("Labels".to_string(), Value::Object(labels));
+        config.insert("HostConfig".to_string(), Value::Object(host));
+        Ok(CreateRequest {
+            name,
+            platform,
+            body: Value::Object(config),
+        })
+    }
+}
+
+/// Split the output of an attached container into its stdout and stderr
+/// frames, until the stream ends
+fn demux(mut stream: impl Read, stdout: &SyncSender<Vec<u8>>, stderr: &SyncSender<Vec<u8>>) {
+    let mut header = [0; 8];
+    while stream.read_exact(&mut header).is_ok() {
+        let len = u32::from_be_bytes(header[4..].try_into().unwrap());
+        let mut frame = Vec::new();
+        if (&mut stream)
+            .take(len.into())
+            .read_to_end(&mut frame)
+            .is_err()
+            || frame.len() < len as usize
+        {
+            break;
+        }
+        // A reader that is gone doesn't stop the other one
+        let _ = match header[0] {
+            2 => stderr.send(frame),
+            _ => stdout.send(frame),
+        };
+    }
+}
+
+/// Container run through the API, attached to
+pub(crate) struct Container {
+    client: Client,
+    id: String,
+    /// The attach stream, to shut down when the container is killed
+    control: UnixStream,
+    stdin: Option<AttachedStdin>,
+    stdout: Option<OutputReader>,
+    stderr: Option<OutputReader>,
+    exit: Receiver<Result<i64>>,
+    status: Option<ExitStatus>,
+}
+
+impl Container {
+    pub(crate) fn take_stdin(&mut self) -> Option<AttachedStdin> {
+        self.stdin.take()
+    }
+
+    pub(crate) fn take_stdout(&mut self) -> Option<OutputReader> {
+        self.stdout.take()
+    }
+
+    pub(crate) fn take_stderr(&mut self) -> Option<OutputReader> {
+        self.stderr.take()
+    }
+
+    /// Exit status of the container, if it exited
+    pub(crate) fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        if self.status.is_none() {
+            let code = match self.exit.try_recv() {
+                Ok(Ok(code)) => code,
+                Ok(Err(e)) => return Err(std::io::Error::other(format!("{e:#}"))),
+                Err(TryRecvError::Empty) => return Ok(None),
+                Err(TryRecvError::Disconnected) => {
+                    return Err(std::io::Error::other("Lost track of the container"))
+                }
+            };
+            // Statuses are encoded as by waitpid: the exit code in the
+            // second byte
+            let code = u8::try_from(code).unwrap_or(u8::MAX);
+            self.status = Some(ExitStatus::from_raw(i32::from(code) << 8));
+        }
+        Ok(self.status)
+    }
+
+    /// Kill and remove the container
+    pub(crate) fn kill(&mut self) {
+        let _ = self
+            .client
+            .call("POST", &format!("/containers/{}/kill", self.id), None);
+        self.client.remove(&self.id);
+        let _ = self.control.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+/// Stdin of an attached container, closed when dropped
+pub(crate) struct AttachedStdin(UnixStream);
+
+impl Write for AttachedStdin {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Drop for AttachedStdin {
+    fn drop(&mut self) {
+        let _ = self.0.shutdown(std::net::Shutdown::Write);
+    }
+}
+
+/// Stdout or stderr of an attached container
+pub(crate) struct OutputReader {
+    frames: Receiver<Vec<u8>>,
+    frame: std::io::Cursor<Vec<u8>>,
+}
+
+impl OutputReader {
+    fn new(frames: Receiver<Vec<u8>>) -> Self {
+        OutputReader {
+            frames,
+            frame: std::io::Cursor::new(Vec::new()),
+        }
+    }
+}
+
+impl Read for OutputReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let read = self.frame.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.frames.recv() {
+                Ok(frame) => self.frame = std::io::Cursor::new(frame),
+                // The stream ended
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+/// `value` percent-encoded for a path or a query
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sandbox::get_security_args;
+    use crate::{cleanup, ContainerHardening, IMAGE_NAME};
+    use std::os::unix::net::UnixListener;
+    use std::sync::{Arc, Mutex};
+
+    /// Request received by a fake runtime
+    #[derive(Debug)]
+    struct Request {
+        method: String,
+        path: String,
+        body: Value,
+    }
+
+    fn read_request(reader: &mut BufReader<UnixStream>) -> Request {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap().to_string();
+        let path = parts.next().unwrap().to_string();
+        let mut length = 0;
+        loop {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length:") {
+                length = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        Request {
+            method,
+            path,
+            body: serde_json::from_slice(&body).unwrap_or(Value::Null),
+        }
+    }
+
+    /// Serve each connection to a socket in `dir` on its own thread with
+    /// `handle`, returning the requests received
+    fn fake_runtime(
+        dir: &Path,
+        handle: impl Fn(&Request, &mut BufReader<UnixStream>) + Send + Sync + 'static,
+    ) -> (Client, Arc<Mutex<Vec<Request>>>) {
+        let socket = dir.join("api.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        let handle = Arc::new(handle);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let handle = handle.clone();
+                let received = received.clone();
+                std::thread::spawn(move || {
+                    let request = read_request(&mut reader);
+                    handle(&request, &mut reader);
+                    received.lock().unwrap().push(request);
+                });
+            }
+        });
+        (Client::with_socket(socket), requests)
+    }
+
+    fn respond(stream: &mut BufReader<UnixStream>, status: &str, body: &str) {
+        write!(
+            stream.get_mut(),
+            "HTTP/1.1 {status}\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_create_request_from_run_args() {
+        let hardening = ContainerHardening::strict();
+        let mut args = vec!["--runtime=/usr/bin/runsc".to_string()];
+        args.extend(get_security_args(Runtime::Podman, &hardening));
+        args.extend(cleanup::container_args("dangerzone-rs-test"));
+        args.extend(["--env", "A=1", "--platform", "linux/arm64", "--rm", "-i"].map(String::from));
+        args.extend([IMAGE_NAME, "/usr/bin/python3", "-m", "converter"].map(String::from));
+
+        let request = CreateRequest::from_run_args(&args).unwrap();
+        assert_eq!(request.name.as_deref(), Some("dangerzone-rs-test"));
+        assert_eq!(request.platform.as_deref(), Some("linux/arm64"));
+        let body = &request.body;
+        assert_eq!(body["Image"], IMAGE_NAME);
+        assert_eq!(body["Cmd"], json!(["/usr/bin/python3", "-m", "converter"]));
+        assert_eq!(body["User"], "dangerzone");
+        assert_eq!(body["Env"], json!(["HOME=/tmp", "A=1"]));
+        assert_eq!(body["NetworkDisabled"], true);
+        assert_eq!(body["OpenStdin"], true);
+        assert_eq!(
+            body["Labels"]["dangerzone-rs.pid"],
+            std::process::id().to_string()
+        );
+        let host = &body["HostConfig"];
+        assert_eq!(host["Runtime"], "/usr/bin/runsc");
+        assert_eq!(host["NetworkMode"], "none");
+        assert_eq!(host["CapDrop"], json!(["all"]));
+        assert_eq!(host["CapAdd"], json!(["SYS_CHROOT"]));
+        assert_eq!(
+            host["SecurityOpt"],
+            json!(["no-new-privileges", "label=type:container_engine_t"])
+        );
+        assert_eq!(host["LogConfig"]["Type"], "none");
+        assert_eq!(host["ReadonlyRootfs"], true);
+        assert_eq!(host["Tmpfs"]["/tmp"], "rw,nosuid,nodev,size=512m,mode=1777");
+        assert_eq!(host["UsernsMode"], "auto");
+
+        // Unknown restrictions aren't dropped silently
+        let unknown = ["--privileged".to_string(), IMAGE_NAME.to_string()];
+        assert!(CreateRequest::from_run_args(&unknown).is_err());
+    }
+
+    #[test]
+    fn test_chunked_body() {
+        let body = b"4\r\nWiki\r\n6;ext\r\npedia \r\n0\r\n\r\n";
+        let mut decoded = String::new();
+        ChunkedReader::new(&body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "Wikipedia ");
+    }
+
+    #[test]
+    fn test_demux() {
+        let mut stream = Vec::new();
+        for (kind, data) in [(1, &b"pix"[..]), (2, b"log"), (1, b"els")] {
+            stream.extend([kind, 0, 0, 0]);
+            stream.extend((data.len() as u32).to_be_bytes());
+            stream.extend(data);
+        }
+        let (stdout_sender, stdout) = mpsc::sync_channel(BUFFERED_FRAMES);
+        let (stderr_sender, stderr) = mpsc::sync_channel(BUFFERED_FRAMES);
+        demux(&stream[..], &stdout_sender, &stderr_sender);
+        drop((stdout_sender, stderr_sender));
+        let mut output = String::new();
+        OutputReader::new(stdout)
+            .read_to_string(&mut output)
+            .unwrap();
+        assert_eq!(output, "pixels");
+        output.clear();
+        OutputReader::new(stderr)
+            .read_to_string(&mut output)
+            .unwrap();
+        assert_eq!(output, "log");
+    }
+
+    #[test]
+    fn test_inspect_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let (client, requests) = fake_runtime(dir.path(), |request, stream| {
+            if request.path.contains("missing") {
+                respond(stream, "404 Not Found", r#"{"message":"no such image"}"#);
+            } else {
+                respond(
+                    stream,
+                    "200 OK",
+                    r#"{"Id":"sha256:1","Os":"linux","Architecture":"arm64","RepoTags":["a:latest"]}"#,
+                );
+            }
+        });
+        let image = client.inspect_image(IMAGE_NAME).unwrap().unwrap();
+        assert_eq!(image.id, "sha256:1");
+        assert_eq!(image.platform(), "linux/arm64");
+        assert_eq!(image.repo_tags, ["a:latest"]);
+        assert_eq!(
+            requests.lock().unwrap()[0].path,
+            format!("/{API_VERSION}/images/{}/json", encode(IMAGE_NAME))
+        );
+        assert_eq!(client.inspect_image("missing").unwrap(), None);
+    }
+
+    #[test]
+    fn test_api_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let (client, _) = fake_runtime(dir.path(), |_, stream| {
+            respond(
+                stream,
+                "500 Internal Server Error",
+                r#"{"message":"disk full"}"#,
+            );
+        });
+        let error = client.call("POST", "/containers/create", None).unwrap_err();
+        let error = error.downcast_ref::<ApiError>().unwrap();
+        assert_eq!(error.status, 500);
+        assert_eq!(error.message, "disk full");
+    }
+
+    #[test]
+    fn test_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let (exited_sender, exited) = mpsc::channel();
+        let exited = Mutex::new(exited);
+        let (client, requests) = fake_runtime(dir.path(), move |request, stream| {
+            let path = request.path.as_str();
+            if path.contains("/containers/create") {
+                respond(stream, "201 Created", r#"{"Id":"c1"}"#);
+            } else if path.contains("/attach") {
+                write!(
+                    stream.get_mut(),
+                    "HTTP/1.1 101 UPGRADED\r\nConnection: Upgrade\r\nUpgrade: tcp\r\n\r\n"
+                )
+                .unwrap();
+                // Echo stdin to stdout, as the converter would answer
+                let mut document = Vec::new();
+                stream.read_to_end(&mut document).unwrap();
+                let mut frame = vec![1, 0, 0, 0];
+                frame.extend((document.len() as u32).to_be_bytes());
+                frame.extend(document);
+                stream.get_mut().write_all(&frame).unwrap();
+                stream.get_mut().shutdown(std::net::Shutdown::Both).unwrap();
+                exited_sender.send(()).unwrap();
+            } else if path.contains("/wait") {
+                exited.lock().unwrap().recv().unwrap();
+                respond(stream, "200 OK", r#"{"StatusCode":3}"#);
+            } else {
+                respond(stream, "204 No Content", "");
+            }
+        });
+        let args = ["--name", "dangerzone-rs-test", "-i", IMAGE_NAME, "convert"].map(String::from);
+        let mut container = client.run(&args).unwrap();
+        let mut stdin = container.take_stdin().unwrap();
+        stdin.write_all(b"%PDF").unwrap();
+        drop(stdin);
+        let mut output = Vec::new();
+        container
+            .take_stdout()
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(output, b"%PDF");
+
+        let status = loop {
+            if let Some(status) = container.try_wait().unwrap() {
+                break status;
+            }
+            std::thread::sleep(CONTAINER_POLL_INTERVAL);
+        };
+        assert_eq!(status.code(), Some(3));
+        // Removed once it exited
+        let removed = loop {
+            let requests = requests.lock().unwrap();
+            if let Some(request) = requests.iter().find(|r| r.method == "DELETE") {
+                break request.path.clone();
+            }
+            drop(requests);
+            std::thread::sleep(CONTAINER_POLL_INTERVAL);
+        };
+        assert_eq!(removed, format!("/{API_VERSION}/containers/c1?force=1"));
+        let requests = requests.lock().unwrap();
+        let create = requests
+            .iter()
+            .find(|r| r.path.contains("/containers/create"))
+            .unwrap();
+        assert!(create.path.ends_with("?name=dangerzone-rs-test"));
+        assert_eq!(create.body["Cmd"], json!(["convert"]));
+    }
+}
@@ -0,0 +1,923 @@
+//! The sandbox running the converter: a container, a micro VM or
+//! bubblewrap, with its image pulled and its output checked
+
+#[cfg(feature = "runtime-api")]
+use crate::runtime_api;
+use crate::{
+    canary, cleanup, image_version, process, replace_control_chars, repro, vm, CancellationToken,
+    Cancelled, CappedReader, ContainerHardening, ConversionOptions, OutputTooLarge, Runtime,
+    IMAGE_NAME, PAGE_CHECKSUMS_ENV, PAGE_COMPRESSION_ENV, PAGE_FAILURES_ENV, PAGE_METADATA_ENV,
+    PIXEL_FORMATS_ENV, UNTRUSTED_LOG_TARGET,
+};
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "container")]
+const MAX_SANITIZED_CHUNK_BYTES: u64 = 64 * 1024;
+#[cfg(feature = "container")]
+pub(crate) const CONTAINER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Variables that [`ConversionOptions::container_env`] may set in the
+/// sandbox: names, or prefixes ending with `*`
+///
+/// These tune LibreOffice (`SAL_*`), the locale and time zone documents are
+/// rendered in, and the threads of the libraries the converter uses. Nothing
+/// that changes what runs or where it reads from, such as `PATH`, `HOME` or
+/// `LD_*`, nor the variables of the pixel protocol, can be set.
+pub const CONTAINER_ENV_ALLOWLIST: &[&str] = &["SAL_*", "LANG", "LC_*", "TZ", "OMP_THREAD_LIMIT"];
+
+/// Fail unless every variable of `env` is allowed by
+/// [`CONTAINER_ENV_ALLOWLIST`] and can be passed to the sandbox
+#[cfg(feature = "container")]
+pub(crate) fn check_container_env(env: &[(String, String)]) -> Result<()> {
+    for (key, value) in env {
+        let key_sanitized = replace_control_chars(key, false);
+        let allowed =
+            CONTAINER_ENV_ALLOWLIST
+                .iter()
+                .any(|allowed| match allowed.strip_suffix('*') {
+                    Some(prefix) => key.starts_with(prefix),
+                    None => key == allowed,
+                });
+        let valid = key
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'_');
+        if !(allowed && valid) {
+            anyhow::bail!(
+                "Container variable '{key_sanitized}' isn't allowed; allowed are: {}",
+                CONTAINER_ENV_ALLOWLIST.join(", ")
+            );
+        }
+        if value.contains('\0') {
+            anyhow::bail!("The value of container variable '{key_sanitized}' contains a NUL byte");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "container")]
+pub(crate) fn get_security_args(runtime: Runtime, hardening: &ContainerHardening) -> Vec<String> {
+    // Every container runs in its own lightweight VM, which replaces the
+    // capability and SELinux confinement of OCI runtimes
+    if runtime == Runtime::AppleContainer {
+        return vec![
+            "--network".to_string(),
+            "none".to_string(),
+            "--user".to_string(),
+            "dangerzone".to_string(),
+        ];
+    }
+
+    let mut args = vec![
+        "--log-driver".to_string(),
+        "none".to_string(),
+        "--security-opt".to_string(),
+        "no-new-privileges".to_string(),
+        "--cap-drop".to_string(),
+        "all".to_string(),
+    ];
+    // The SELinux label and SYS_CHROOT let the image nest its own sandbox,
+    // neither of which gVisor supports nor needs
+    if runtime != Runtime::Gvisor {
+        args.extend([
+            "--cap-add".to_string(),
+            "SYS_CHROOT".to_string(),
+            "--security-opt".to_string(),
+            "label=type:container_engine_t".to_string(),
+        ]);
+    }
+    args.extend([
+        "--network=none".to_string(),
+        "-u".to_string(),
+        "dangerzone".to_string(),
+    ]);
+
+    if hardening.read_only {
+        args.push("--read-only".to_string());
+    }
+    if let Some(size) = hardening.tmpfs_size_mib {
+        args.extend([
+            "--tmpfs".to_string(),
+            format!("/tmp:rw,nosuid,nodev,size={size}m,mode=1777"),
+            "--env".to_string(),
+            "HOME=/tmp".to_string(),
+        ]);
+    }
+    if hardening.userns_auto && runtime.command() == "podman" {
+        args.push("--userns=auto".to_string());
+    }
+    args
+}
+
+/// Look up an executable in a `PATH`-style list of directories
+#[cfg(feature = "container")]
+pub(crate) fn find_executable(name: &str, paths: &std::ffi::OsStr) -> Option<std::path::PathBuf> {
+    std::env::split_paths(paths)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// Read from a source (mostly the container's stderr) and pass each line,
+/// sanitized and marked as untrusted, to `emit`
+#[cfg(feature = "container")]
+pub(crate) fn forward_sanitized_text<R: BufRead>(
+    mut reader: R,
+    mut emit: impl FnMut(&str),
+) -> Result<()> {
+    const UNTRUSTED_PREFIX: &str = "UNTRUSTED> ";
+
+    let mut line_buf = Vec::new();
+    loop {
+        line_buf.clear();
+        let n = reader
+            .by_ref()
+            .take(MAX_SANITIZED_CHUNK_BYTES)
+            .read_until(b'\n', &mut line_buf)
+            .context("Failed to read output for sanitizing")?;
+        if n == 0 {
+            break;
+        }
+
+        let s = String::from_utf8_lossy(&line_buf);
+        let sanitized: String = replace_control_chars(&s, true);
+        let sanitized = sanitized.strip_suffix('\n').unwrap_or(&sanitized);
+        emit(&format!("{UNTRUSTED_PREFIX}{sanitized}"));
+    }
+
+    Ok(())
+}
+
+/// Interpreter and module of the converter inside the sandbox
+#[cfg(feature = "container")]
+const CONVERTER_COMMAND: [&str; 3] = [
+    "/usr/bin/python3",
+    "-m",
+    "dangerzone.conversion.doc_to_pixels",
+];
+
+/// Start the conversion container, forward its sanitized stderr and feed it
+/// the input document
+#[cfg(feature = "container")]
+pub(crate) fn spawn_container(input_path: &str, options: &ConversionOptions) -> Result<Sandbox> {
+    let (mut child, stderr_thread, repro) = spawn_sandbox(options, &CONVERTER_COMMAND)?;
+
+    // Read the input document
+    let mut input_file = File::open(input_path).context(format!(
+        "Failed to open input file '{input_path_sanitized}'",
+        input_path_sanitized = replace_control_chars(input_path, false)
+    ))?;
+    let mut input_data = Vec::new();
+    input_file
+        .read_to_end(&mut input_data)
+        .context("Failed to read input file")?;
+
+    // Write the document to the container's stdin
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(&input_data)
+            .context("Failed to write to container stdin")?;
+    }
+
+    Ok((child, stderr_thread, repro))
+}
+
+/// Make sure the container runtime can run on this host, starting its
+/// virtual machine if needed
+#[cfg(feature = "container")]
+fn ensure_runtime_ready(options: &ConversionOptions) -> Result<()> {
+    if options.runtime == Runtime::AppleContainer && !cfg!(target_os = "macos") {
+        anyhow::bail!("Apple's container runtime is only available on macOS");
+    }
+    vm::ensure_running(options.runtime, options.auto_start_vm)
+}
+
+/// Sandbox started by [`spawn_sandbox`]: its process, the thread forwarding
+/// its stderr, and the script to write if it fails
+#[cfg(feature = "container")]
+pub(crate) type Sandbox = (
+    process::SandboxProcess,
+    JoinHandle<Result<()>>,
+    repro::ReproScript,
+);
+
+/// Start the sandbox selected by `options` running `converter`, with piped
+/// stdio, and forward its sanitized stderr
+#[cfg(feature = "container")]
+pub(crate) fn spawn_sandbox(options: &ConversionOptions, converter: &[&str]) -> Result<Sandbox> {
+    let runtime = options.runtime;
+    let mut env = Vec::new();
+    if options.page_checksums {
+        env.push((PAGE_CHECKSUMS_ENV, "1"));
+    }
+    if options.page_compression {
+        env.push((PAGE_COMPRESSION_ENV, "zlib"));
+    }
+    env.push((PIXEL_FORMATS_ENV, "1"));
+    env.push((PAGE_METADATA_ENV, "1"));
+    env.push((PAGE_FAILURES_ENV, "1"));
+    check_container_env(&options.container_env)?;
+    env.extend(
+        options
+            .container_env
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str())),
+    );
+    let (child, repro) = if runtime == Runtime::Bwrap {
+        spawn_bwrap(converter, &env, options)?
+    } else {
+        ensure_runtime_ready(options)?;
+        image_version::check(options)?;
+
+        let mut args = vec!["run".to_string()];
+        if runtime == Runtime::Gvisor {
+            let runsc = std::env::var_os("PATH")
+                .and_then(|paths| find_executable("runsc", &paths))
+                .context(
+                    "gVisor's runsc was not found in PATH. See https://gvisor.dev/docs/user_guide/install/",
+                )?;
+            args.push(format!("--runtime={}", runsc.display()));
+        }
+        args.extend(get_security_args(runtime, &options.container_hardening));
+        let name = cleanup::new_container_name();
+        args.extend(cleanup::container_args(&name));
+        for (key, value) in &env {
+            args.extend(["--env".to_string(), format!("{key}={value}")]);
+        }
+        if let Some(platform) = image_platform(options) {
+            args.extend(["--platform".to_string(), platform.to_string()]);
+        }
+        args.extend(vec![
+            "--rm".to_string(),
+            "-i".to_string(),
+            IMAGE_NAME.to_string(),
+        ]);
+        args.extend(converter.iter().map(|arg| arg.to_string()));
+
+        let mut command = Command::new(runtime.command());
+        command.args(&args);
+        let repro = repro::ReproScript::new(&command, options);
+        if options.runtime_api && runtime != Runtime::AppleContainer {
+            return run_through_api(options, &args[1..], repro);
+        }
+        let child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context(format!(
+                "Failed to spawn container. Make sure {runtime} is installed and the image '{IMAGE_NAME}' is pulled."
+            ))?;
+        cleanup::track(&child, runtime, name.clone());
+        let tripwire = canary::Tripwire::container(runtime, name);
+        (
+            process::SandboxProcess::from(child).with_tripwire(tripwire),
+            repro,
+        )
+    };
+    forward_stderr(child, repro)
+}
+
+/// Take the stderr of the sandbox and log it sanitized, on its own thread
+#[cfg(feature = "container")]
+fn forward_stderr(
+    mut child: process::SandboxProcess,
+    repro: repro::ReproScript,
+) -> Result<Sandbox> {
+    let stderr = child
+        .stderr
+        .take()
+        .context("Failed to take ownership of stderr")?;
+    let stderr_thread = std::thread::spawn(move || -> Result<()> {
+        forward_sanitized_text(
+            BufReader::new(stderr),
+            |line| info!(target: UNTRUSTED_LOG_TARGET, "{line}"),
+        )
+    });
+
+    Ok((child, stderr_thread, repro))
+}
+
+/// Create and start the container from the arguments of `run` through the
+/// runtime's API
+#[cfg(all(feature = "runtime-api", unix))]
+fn run_through_api(
+    options: &ConversionOptions,
+    args: &[String],
+    repro: repro::ReproScript,
+) -> Result<Sandbox> {
+    let runtime = options.runtime;
+    let container = runtime_api::Client::connect(runtime)?
+        .run(args)
+        .context(format!(
+            "Failed to start the container through the API of {runtime}. Make sure the image '{IMAGE_NAME}' is pulled."
+        ))?;
+    forward_stderr(container.into(), repro)
+}
+
+#[cfg(all(feature = "container", not(all(feature = "runtime-api", unix))))]
+fn run_through_api(
+    _options: &ConversionOptions,
+    _args: &[String],
+    _repro: repro::ReproScript,
+) -> Result<Sandbox> {
+    anyhow::bail!(
+        "Running containers through the runtime's API requires the `runtime-api` feature, on Unix"
+    )
+}
+
+#[cfg(all(
+    feature = "container",
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn spawn_bwrap(
+    converter: &[&str],
+    env: &[(&str, &str)],
+    options: &ConversionOptions,
+) -> Result<(process::SandboxProcess, repro::ReproScript)> {
+    let (mut command, mut pipes) = crate::bwrap::command(converter, env, &[])?;
+    let repro = repro::ReproScript::new(&command, options);
+    let tripwire = canary::Tripwire::bwrap(pipes.take_info()?);
+    let child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(
+            "Failed to spawn bubblewrap. Make sure bwrap and the Dangerzone converter are installed.",
+        )?;
+    // Only bubblewrap keeps the info pipe open now
+    drop(pipes);
+    Ok((
+        process::SandboxProcess::from(child).with_tripwire(tripwire),
+        repro,
+    ))
+}
+
+#[cfg(all(
+    feature = "container",
+    not(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))
+))]
+fn spawn_bwrap(
+    _converter: &[&str],
+    _env: &[(&str, &str)],
+    _options: &ConversionOptions,
+) -> Result<(process::SandboxProcess, repro::ReproScript)> {
+    anyhow::bail!("The bubblewrap sandbox is only available on Linux (x86_64 and aarch64)")
+}
+
+/// Wait for the container to exit, killing it if `cancel` is triggered or
+/// it runs past the timeout
+#[cfg(feature = "container")]
+pub(crate) fn wait_for_container(
+    child: &mut process::SandboxProcess,
+    started: Instant,
+    timeout: Option<Duration>,
+    cancel: &CancellationToken,
+) -> Result<ExitStatus> {
+    loop {
+        if let Some(status) = child.try_wait().context("Failed to wait for container")? {
+            // Run with --rm, or removed through the API once it exited
+            cleanup::untrack(child);
+            if let Some(tripwire) = child.tripwire.take() {
+                tripwire.check();
+            }
+            return Ok(status);
+        }
+        check_container(child, started, timeout, cancel)?;
+        std::thread::sleep(CONTAINER_POLL_INTERVAL);
+    }
+}
+
+/// Kill the container if `cancel` is triggered or it ran past the timeout
+#[cfg(feature = "container")]
+pub(crate) fn check_container(
+    child: &mut process::SandboxProcess,
+    started: Instant,
+    timeout: Option<Duration>,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    if let Some(tripwire) = &mut child.tripwire {
+        tripwire.observe();
+    }
+    if cancel.is_cancelled() {
+        cleanup::kill_container(child);
+        return Err(Cancelled.into());
+    }
+    if let Some(timeout) = timeout.filter(|t| started.elapsed() > *t) {
+        cleanup::kill_container(child);
+        anyhow::bail!(
+            "Container timed out after {} seconds. The document may be too large or complex.",
+            timeout.as_secs_f64()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(feature = "container")]
+pub(crate) fn join_stderr_thread(stderr_thread: JoinHandle<Result<()>>) {
+    match stderr_thread.join() {
+        Err(_) => {
+            warn!("stderr_thread panicked while forwarding container stderr");
+        }
+        Ok(Err(e)) => {
+            warn!(
+                "Failed to forward container stderr: {err_sanitized}",
+                err_sanitized = replace_control_chars(&e.to_string(), true)
+            );
+        }
+        Ok(Ok(_)) => {}
+    }
+}
+
+/// Fail if the container did, writing `repro` where the options ask for it
+#[cfg(feature = "container")]
+pub(crate) fn check_container_status(status: ExitStatus, repro: &repro::ReproScript) -> Result<()> {
+    if !status.success() {
+        repro.write();
+        anyhow::bail!(
+            "Container failed with status: {status}. The document format may be unsupported or corrupted."
+        );
+    }
+    Ok(())
+}
+
+#[cfg(feature = "container")]
+pub(crate) fn convert_doc_to_pixels_cancellable(
+    input_path: String,
+    options: &ConversionOptions,
+    cancel: &CancellationToken,
+) -> Result<Vec<u8>> {
+    run_converter(input_path, options, cancel, |stdout| {
+        let mut data = Vec::new();
+        stdout.read_to_end(&mut data)?;
+        Ok(data)
+    })
+}
+
+/// Run the converter on a document, handing its output to `read` on a
+/// separate thread while watching for cancellation and the timeout
+#[cfg(feature = "container")]
+pub(crate) fn run_converter<T: Send + 'static>(
+    input_path: String,
+    options: &ConversionOptions,
+    cancel: &CancellationToken,
+    read: impl FnOnce(&mut CappedReader<Box<dyn Read + Send>>) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    info!("Converting document to pixels...");
+
+    let (mut child, stderr_thread, repro) = spawn_container(&input_path, options)?;
+    let started = Instant::now();
+
+    let stdout = child
+        .stdout
+        .take()
+        .context("Failed to take ownership of stdout")?;
+    let limit = options.max_output_bytes;
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut stdout = CappedReader::new(stdout, limit);
+        let result = match read(&mut stdout) {
+            Ok(output) => Ok(output),
+            Err(_) if stdout.exceeded() => Err(OutputTooLarge { limit }.into()),
+            Err(e) if e.is::<OutputTooLarge>() => Err(e),
+            Err(e) => {
+                // Keep draining so the container can still exit on its own
+                // and report its status
+                let _ = std::io::copy(&mut stdout, &mut std::io::sink());
+                Err(e.context("Failed to read container output"))
+            }
+        };
+        let _ = sender.send(result);
+    });
+    let output = loop {
+        check_container(&mut child, started, options.timeout, cancel)?;
+        match receiver.recv_timeout(CONTAINER_POLL_INTERVAL) {
+            Ok(result) => break result,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                anyhow::bail!("stdout_thread panicked while reading container output")
+            }
+        }
+    };
+    if let Err(e) = &output {
+        if e.is::<OutputTooLarge>() {
+            cleanup::kill_container(&mut child);
+            join_stderr_thread(stderr_thread);
+            return output;
+        }
+    }
+
+    let status = wait_for_container(&mut child, started, options.timeout, cancel)?;
+    // Read stderr from the container
+    join_stderr_thread(stderr_thread);
+    check_container_status(status, &repro)?;
+    let output = output?;
+
+    info!("Document converted to pixels successfully");
+    Ok(output)
+}
+
+/// Prepare the sandbox so that the first conversion starts quickly
+///
+/// Removes the containers left behind by crashed processes and pulls the
+/// image if the runtime doesn't have it yet, then starts a container that
+/// loads the converter and exits right away, which unpacks the image into
+/// the runtime's storage. With bubblewrap, this only loads the locally
+/// installed converter once. Frontends can run this in the background at
+/// startup.
+#[cfg(feature = "container")]
+pub fn warmup(options: &ConversionOptions, cancel: &CancellationToken) -> Result<()> {
+    if options.runtime != Runtime::Bwrap {
+        ensure_runtime_ready(options)?;
+        cleanup::sweep(options.runtime);
+        pull_image(options, cancel)?;
+    }
+    cancel.check()?;
+
+    info!("Starting a container to prime the runtime...");
+    let (mut child, stderr_thread, repro) = spawn_sandbox(
+        options,
+        &[
+            "/usr/bin/python3",
+            "-c",
+            "import dangerzone.conversion.doc_to_pixels",
+        ],
+    )?;
+    drop(child.stdin.take());
+    let status = wait_for_container(&mut child, Instant::now(), options.timeout, cancel)?;
+    join_stderr_thread(stderr_thread);
+    if !status.success() {
+        repro.write();
+        anyhow::bail!("Warm-up container failed with status: {status}");
+    }
+
+    info!("The {} sandbox is ready", options.runtime);
+    Ok(())
+}
+
+/// Pull the image unless the runtime already has it, for `platform` if
+/// given
+#[cfg(feature = "container")]
+pub(crate) fn pull_image(options: &ConversionOptions, cancel: &CancellationToken) -> Result<()> {
+    let runtime = options.runtime;
+    let platform = image_platform(options);
+    match (local_image_platform(options, platform.is_some())?, platform) {
+        (Some(found), Some(platform)) if found != platform => {
+            info!(
+                "Image {IMAGE_NAME} is for {found_sanitized}, pulling it for {platform}...",
+                found_sanitized = replace_control_chars(&found, false)
+            );
+        }
+        (Some(_), _) => {
+            debug!("Image {IMAGE_NAME} is already present");
+            return Ok(());
+        }
+        (None, _) => info!("Pulling {IMAGE_NAME}..."),
+    }
+
+    let failure = if options.runtime_api {
+        pull_through_api(runtime, platform, cancel)?
+    } else {
+        let mut command = Command::new(runtime.command());
+        command.args(["image", "pull"]);
+        if let Some(platform) = platform {
+            command.args(["--platform", platform]);
+        }
+        let child = command
+            .arg(IMAGE_NAME)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .context(format!(
+                "Failed to run {runtime}. Make sure it is installed."
+            ))?;
+        let status = wait_for_container(&mut child.into(), Instant::now(), None, cancel)?;
+        (!status.success()).then(|| format!("status: {status}"))
+    };
+    let Some(platform) = platform else {
+        if let Some(failure) = failure {
+            anyhow::bail!("Failed to pull {IMAGE_NAME} with {runtime} ({failure})");
+        }
+        return Ok(());
+    };
+    if let Some(failure) = failure {
+        anyhow::bail!(
+            "Failed to pull {IMAGE_NAME} for {platform} with {runtime} ({failure}). \
+             If the image isn't published for this platform, choose another image platform \
+             to run it under emulation."
+        );
+    }
+    // Runtimes may pull an image of another platform when that is the only
+    // one, with at most a warning
+    match local_image_platform(options, true)? {
+        Some(found) => check_image_platform(&found, platform),
+        None => anyhow::bail!("{runtime} doesn't have {IMAGE_NAME} after pulling it"),
+    }
+}
+
+/// Platform of the image the runtime has, `os/architecture`, or `None` if it
+/// doesn't have the image; the platform is only read with `read_platform`,
+/// or through the runtime's API, and is empty otherwise
+#[cfg(feature = "container")]
+fn local_image_platform(
+    options: &ConversionOptions,
+    read_platform: bool,
+) -> Result<Option<String>> {
+    let runtime = options.runtime;
+    if options.runtime_api {
+        return inspect_through_api(runtime).map(|image| image.map(|(platform, _)| platform));
+    }
+    let mut command = Command::new(runtime.command());
+    command.args(["image", "inspect"]);
+    if read_platform {
+        command.args(["--format", "{{.Os}}/{{.Architecture}}"]);
+    }
+    let output = command
+        .arg(IMAGE_NAME)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .context(format!(
+            "Failed to run {runtime}. Make sure it is installed."
+        ))?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    if !read_platform {
+        return Ok(Some(String::new()));
+    }
+    Ok(Some(
+        String::from_utf8_lossy(&output.stdout).trim().to_string(),
+    ))
+}
+
+/// Pull the image through the runtime's API, returning why the runtime
+/// failed to, if it did
+#[cfg(all(feature = "runtime-api", unix))]
+fn pull_through_api(
+    runtime: Runtime,
+    platform: Option<&str>,
+    cancel: &CancellationToken,
+) -> Result<Option<String>> {
+    match runtime_api::Client::connect(runtime)?.pull_image(IMAGE_NAME, platform, cancel) {
+        Ok(()) => Ok(None),
+        Err(e) if e.is::<runtime_api::ApiError>() => Ok(Some(e.to_string())),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(all(feature = "container", not(all(feature = "runtime-api", unix))))]
+fn pull_through_api(
+    _runtime: Runtime,
+    _platform: Option<&str>,
+    _cancel: &CancellationToken,
+) -> Result<Option<String>> {
+    anyhow::bail!(
+        "Running containers through the runtime's API requires the `runtime-api` feature, on Unix"
+    )
+}
+
+/// Platform and tags of the image, as the runtime's API reports them, or
+/// `None` if the runtime doesn't have it
+#[cfg(all(feature = "runtime-api", unix))]
+pub(crate) fn inspect_through_api(runtime: Runtime) -> Result<Option<(String, Vec<String>)>> {
+    let image = runtime_api::Client::connect(runtime)?.inspect_image(IMAGE_NAME)?;
+    Ok(image.map(|image| (image.platform(), image.repo_tags)))
+}
+
+#[cfg(all(feature = "container", not(all(feature = "runtime-api", unix))))]
+pub(crate) fn inspect_through_api(_runtime: Runtime) -> Result<Option<(String, Vec<String>)>> {
+    anyhow::bail!(
+        "Running containers through the runtime's API requires the `runtime-api` feature, on Unix"
+    )
+}
+
+/// Fail if the image found is for another platform than the one asked for,
+/// rather than let it run under emulation or fail to start
+#[cfg(feature = "container")]
+pub(crate) fn check_image_platform(found: &str, platform: &str) -> Result<()> {
+    if found != platform {
+        anyhow::bail!(
+            "Image {IMAGE_NAME} is only available for {found_sanitized}, not {platform}. \
+             Choose {found_sanitized} as the image platform to run it under emulation anyway.",
+            found_sanitized = replace_control_chars(found, false)
+        );
+    }
+    Ok(())
+}
+
+/// Platform of the converter image matching the host, if the image is
+/// published for its architecture
+#[cfg(feature = "container")]
+pub(crate) fn host_platform() -> Option<&'static str> {
+    match std::env::consts::ARCH {
+        "x86_64" => Some("linux/amd64"),
+        "aarch64" => Some("linux/arm64"),
+        _ => None,
+    }
+}
+
+/// Platform of the converter image to pull and run with `options`, if the
+/// runtime lets it be chosen
+#[cfg(feature = "container")]
+pub(crate) fn image_platform(options: &ConversionOptions) -> Option<&str> {
+    match options.runtime {
+        Runtime::Bwrap | Runtime::AppleContainer => None,
+        _ => options.image_platform.as_deref().or(host_platform()),
+    }
+}
+
+/// Identifier of the converter image the runtime would run, such as
+/// `sha256:…`, or `None` if it can't tell
+///
+/// Bubblewrap runs a locally installed converter rather than an image, and
+/// Apple's `container` CLI can't print the identifier alone.
+#[cfg(feature = "container")]
+pub fn image_digest(runtime: Runtime) -> Option<String> {
+    if matches!(runtime, Runtime::Bwrap | Runtime::AppleContainer) {
+        return None;
+    }
+    let output = Command::new(runtime.command())
+        .args(["image", "inspect", "--format", "{{.Id}}", IMAGE_NAME])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let id = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    let valid = !id.is_empty()
+        && id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b':');
+    (output.status.success() && valid).then_some(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check_options;
+
+    #[test]
+    fn test_gvisor_security_args() {
+        let podman = get_security_args(Runtime::Podman, &ContainerHardening::default());
+        let gvisor = get_security_args(Runtime::Gvisor, &ContainerHardening::default());
+        assert!(podman.contains(&"SYS_CHROOT".to_string()));
+        assert!(!gvisor.contains(&"SYS_CHROOT".to_string()));
+        assert!(!gvisor.iter().any(|arg| arg.starts_with("label=")));
+        for arg in ["no-new-privileges", "--network=none", "dangerzone"] {
+            assert!(gvisor.contains(&arg.to_string()), "missing {arg}");
+        }
+
+        assert_eq!("gvisor".parse::<Runtime>().unwrap(), Runtime::Gvisor);
+        assert_eq!(Runtime::Gvisor.to_string(), "gvisor");
+        assert_eq!(Runtime::Gvisor.command(), "podman");
+    }
+
+    #[test]
+    fn test_container_hardening_args() {
+        let default = get_security_args(Runtime::Podman, &ContainerHardening::default());
+        assert!(!default.contains(&"--read-only".to_string()));
+        assert!(!default.contains(&"--tmpfs".to_string()));
+
+        let strict = ContainerHardening::strict();
+        let podman = get_security_args(Runtime::Podman, &strict);
+        assert!(podman.contains(&"--read-only".to_string()));
+        assert!(podman.contains(&"/tmp:rw,nosuid,nodev,size=512m,mode=1777".to_string()));
+        assert!(podman.contains(&"HOME=/tmp".to_string()));
+        assert!(podman.contains(&"--userns=auto".to_string()));
+
+        // Docker has no automatic user namespaces
+        let docker = get_security_args(Runtime::Docker, &strict);
+        assert!(docker.contains(&"--read-only".to_string()));
+        assert!(!docker.contains(&"--userns=auto".to_string()));
+    }
+
+    #[test]
+    fn test_container_env_allowlist() {
+        let env = |key: &str| vec![(key.to_string(), "1".to_string())];
+        for key in ["SAL_USE_VCLPLUGIN", "LANG", "LC_ALL", "TZ"] {
+            assert!(check_container_env(&env(key)).is_ok(), "{key}");
+        }
+        for key in [
+            "PATH",
+            "LD_PRELOAD",
+            PAGE_CHECKSUMS_ENV,
+            "SAL",
+            "SAL_X=Y",
+            "LANGUAGE",
+        ] {
+            assert!(check_container_env(&env(key)).is_err(), "{key}");
+        }
+        let nul = vec![("TZ".to_string(), "UTC\0".to_string())];
+        assert!(check_container_env(&nul).is_err());
+    }
+
+    #[test]
+    fn test_apple_container_security_args() {
+        let args = get_security_args(Runtime::AppleContainer, &ContainerHardening::strict());
+        assert_eq!(args, ["--network", "none", "--user", "dangerzone"]);
+        assert_eq!(
+            "container".parse::<Runtime>().unwrap(),
+            Runtime::AppleContainer
+        );
+        assert_eq!(Runtime::AppleContainer.to_string(), "container");
+    }
+
+    #[test]
+    fn test_find_executable() {
+        let empty = tempfile::tempdir().unwrap();
+        let bin = tempfile::tempdir().unwrap();
+        std::fs::write(bin.path().join("runsc"), "").unwrap();
+        let paths = std::env::join_paths([empty.path(), bin.path()]).unwrap();
+
+        assert_eq!(
+            find_executable("runsc", &paths),
+            Some(bin.path().join("runsc"))
+        );
+        assert_eq!(find_executable("crun", &paths), None);
+    }
+
+    #[test]
+    fn test_image_platform() {
+        let options = |runtime, image_platform: Option<&str>| ConversionOptions {
+            runtime,
+            image_platform: image_platform.map(str::to_string),
+            ..ConversionOptions::default()
+        };
+        assert_eq!(
+            image_platform(&options(Runtime::Podman, None)),
+            host_platform()
+        );
+        assert_eq!(
+            image_platform(&options(Runtime::Docker, Some("linux/amd64"))),
+            Some("linux/amd64")
+        );
+        assert_eq!(image_platform(&options(Runtime::Bwrap, None)), None);
+        assert_eq!(
+            image_platform(&options(Runtime::AppleContainer, Some("linux/amd64"))),
+            None
+        );
+
+        check_image_platform("linux/arm64", "linux/arm64").unwrap();
+        let error = check_image_platform("linux/amd64", "linux/arm64").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("only available for linux/amd64, not linux/arm64"));
+
+        for invalid in ["amd64", "linux/", "--privileged", "linux/arm64 --rm"] {
+            assert!(check_options(&options(Runtime::Podman, Some(invalid))).is_err());
+        }
+        check_options(&options(Runtime::Podman, Some("linux/arm64/v8"))).unwrap();
+    }
+
+    #[test]
+    fn test_forward_sanitized_text() {
+        let input = concat!(
+            "plain ✓ café 😀\n",
+            "\x1b[31mANSI escaped\n",
+            "red text.\x1b[0m\n",
+            "\ttab\rline\n",
+            "a\u{200E}b\u{E000}c\u{0378}d\u{2028}e\u{2029}f\n",
+            "x\n",
+            "\u{2028}\u{2029}y\n",
+            "ok line\n",
+            "\x1b[31mred\x1b[0m\n",
+            "end",
+        );
+        let expected_output = concat!(
+            "UNTRUSTED> plain ✓ café 😀\n",
+            "UNTRUSTED> \u{FFFD}[31mANSI escaped\n",
+            "UNTRUSTED> red text.\u{FFFD}[0m\n",
+            "UNTRUSTED> \u{FFFD}tab\u{FFFD}line\n",
+            "UNTRUSTED> a\u{FFFD}b\u{FFFD}c\u{FFFD}d\u{FFFD}e\u{FFFD}f\n",
+            "UNTRUSTED> x\n",
+            "UNTRUSTED> \u{FFFD}\u{FFFD}y\n",
+            "UNTRUSTED> ok line\n",
+            "UNTRUSTED> \u{FFFD}[31mred\u{FFFD}[0m\n",
+            "UNTRUSTED> end\n",
+        );
+
+        let reader = BufReader::new(std::io::Cursor::new(input.as_bytes()));
+        let mut output = String::new();
+        forward_sanitized_text(reader, |line| {
+            output.push_str(line);
+            output.push('\n');
+        })
+        .unwrap();
+
+        assert_eq!(
+            output, expected_output,
+            "forward_sanitized_text failed for input: {input:?}",
+        );
+    }
+}
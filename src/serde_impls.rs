@@ -0,0 +1,208 @@
+//! Serde support for the types that don't map directly to derived impls
+
+use crate::redact::PatternMatch;
+use crate::{BatchResult, ConversionReport, ConversionStats, Warning};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::time::Duration;
+
+/// Pixels as base64 in human-readable formats, and as bytes otherwise
+pub(crate) mod pixels {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use bytes::Bytes;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        pixels: &Bytes,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&STANDARD.encode(pixels))
+        } else {
+            serializer.serialize_bytes(pixels)
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Bytes, D::Error> {
+        if deserializer.is_human_readable() {
+            let encoded = String::deserialize(deserializer)?;
+            STANDARD
+                .decode(encoded)
+                .map(Bytes::from)
+                .map_err(|e| D::Error::custom(format!("invalid base64 pixels: {e}")))
+        } else {
+            Bytes::deserialize(deserializer)
+        }
+    }
+}
+
+/// Serialized form of a [`BatchResult`]
+#[derive(Serialize, Deserialize)]
+struct BatchResultRepr {
+    input_path: String,
+    output_path: String,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ocr_fallback: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ocr_lang: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    peak_rss_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stats: Option<ConversionStats>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<Warning>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    redacted: Vec<PatternMatch>,
+    duration_secs: f64,
+}
+
+impl Serialize for BatchResult {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let report = self.result.as_ref().ok();
+        BatchResultRepr {
+            input_path: self.input_path.clone(),
+            output_path: self.output_path.clone(),
+            error: self.result.as_ref().err().map(|e| format!("{e:#}")),
+            ocr_fallback: report.and_then(|report| report.ocr_fallback.clone()),
+            ocr_lang: report.and_then(|report| report.ocr_lang.clone()),
+            peak_rss_bytes: report.and_then(|report| report.peak_rss_bytes),
+            stats: report.map(|report| report.stats.clone()),
+            warnings: report.map_or_else(Vec::new, |report| report.warnings.clone()),
+            redacted: report.map_or_else(Vec::new, |report| report.redacted.clone()),
+            duration_secs: self.duration.as_secs_f64(),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Errors come back as their message only, without their original type
+impl<'de> Deserialize<'de> for BatchResult {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = BatchResultRepr::deserialize(deserializer)?;
+        let duration =
+            Duration::try_from_secs_f64(repr.duration_secs).map_err(serde::de::Error::custom)?;
+        Ok(BatchResult {
+            input_path: repr.input_path,
+            output_path: repr.output_path,
+            result: match repr.error {
+                Some(error) => Err(anyhow::anyhow!(error)),
+                None => Ok(ConversionReport {
+                    ocr_fallback: repr.ocr_fallback,
+                    ocr_lang: repr.ocr_lang,
+                    peak_rss_bytes: repr.peak_rss_bytes,
+                    stats: repr.stats.unwrap_or_default(),
+                    warnings: repr.warnings,
+                    redacted: repr.redacted,
+                }),
+            },
+            duration,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::redact::PatternMatch;
+    use crate::{
+        BatchResult, ConversionOptions, ConversionReport, ConversionStats, PageData, PixelFormat,
+        Runtime, Warning,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_page_data_json() {
+        let mut page = PageData::with_format(2, 1, PixelFormat::Gray, vec![0, 255]);
+        page.metadata.rotation = 90;
+        let json = serde_json::to_value(&page).unwrap();
+        assert_eq!(json["pixels"], "AP8=");
+        assert_eq!(json["format"], "gray");
+        assert_eq!(json["metadata"]["rotation"], 90);
+
+        let read: PageData = serde_json::from_value(json).unwrap();
+        assert_eq!(read.pixels, page.pixels);
+        assert_eq!(read.metadata, page.metadata);
+
+        let json = serde_json::to_value(page.without_pixels()).unwrap();
+        assert!(json.get("pixels").is_none());
+        let read: PageData = serde_json::from_value(json).unwrap();
+        assert!(read.pixels.is_empty());
+    }
+
+    #[test]
+    fn test_conversion_options_defaults() {
+        let options: ConversionOptions =
+            serde_json::from_str(r#"{"ocr": true, "runtime": "container"}"#).unwrap();
+        assert!(options.ocr);
+        assert_eq!(options.runtime, Runtime::AppleContainer);
+        assert_eq!(options.dpi, crate::DPI);
+
+        let json = serde_json::to_string(&options).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ConversionOptions>(&json).unwrap(),
+            options
+        );
+    }
+
+    #[test]
+    fn test_batch_result() {
+        let result = BatchResult {
+            input_path: "a.pdf".to_string(),
+            output_path: "a-safe.pdf".to_string(),
+            result: Err(anyhow::anyhow!("Conversion failed")),
+            duration: Duration::from_millis(1500),
+        };
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["error"], "Conversion failed");
+        assert_eq!(json["duration_secs"], 1.5);
+
+        let read: BatchResult = serde_json::from_value(json).unwrap();
+        assert_eq!(read.result.unwrap_err().to_string(), "Conversion failed");
+        assert_eq!(read.duration, result.duration);
+    }
+
+    #[test]
+    fn test_batch_result_ocr_fallback() {
+        let result = BatchResult {
+            input_path: "a.pdf".to_string(),
+            output_path: "a-safe.pdf".to_string(),
+            result: Ok(ConversionReport {
+                ocr_fallback: Some("No OCR engine succeeded".to_string()),
+                ocr_lang: None,
+                peak_rss_bytes: Some(200 << 20),
+                stats: ConversionStats {
+                    pages: 3,
+                    total: Duration::from_millis(1500),
+                    ..ConversionStats::default()
+                },
+                warnings: vec![
+                    Warning::OcrFallback {
+                        reason: "No OCR engine succeeded".to_string(),
+                    },
+                    Warning::BlankPagesDropped { count: 2 },
+                ],
+                redacted: vec![PatternMatch {
+                    page: 2,
+                    pattern: "Jane Doe".to_string(),
+                    text: "Jane Doe".to_string(),
+                }],
+            }),
+            duration: Duration::from_secs(1),
+        };
+        let json = serde_json::to_value(&result).unwrap();
+        assert!(json["error"].is_null());
+        assert_eq!(json["ocr_fallback"], "No OCR engine succeeded");
+        assert_eq!(json["peak_rss_bytes"], 200 << 20);
+        assert_eq!(json["stats"]["pages"], 3);
+        assert_eq!(json["warnings"][0]["kind"], "ocr_fallback");
+        assert_eq!(json["warnings"][1]["count"], 2);
+        assert_eq!(json["redacted"][0]["page"], 2);
+
+        let read: BatchResult = serde_json::from_value(json).unwrap();
+        assert_eq!(read.result.unwrap(), result.result.unwrap());
+    }
+}
@@ -0,0 +1,305 @@
+//! Long-lived conversion sandboxes converting several documents in a row
+//!
+//! Starting a container takes longer than converting a small document, so
+//! batch conversions can keep one running. Inside it, a small supervisor
+//! reads documents from stdin and runs the regular converter on each one in
+//! a fresh process. Every document and every result is framed:
+//!
+//! - Request: document length (8 bytes, big-endian), then the document. A
+//!   zero length ends the session.
+//! - Response: status (1 byte, 0 on success), payload length (8 bytes,
+//!   big-endian), then the pixel stream.
+//!
+//! A document that compromises the converter may tamper with the sandbox
+//! and the documents converted after it, so the sandbox is replaced after a
+//! set number of documents, and after any framing error, cancellation or
+//! timeout.
+
+use crate::process::SandboxProcess;
+use crate::{
+    check_container, join_stderr_thread, spawn_sandbox, CancellationToken, ConversionOptions,
+    OutputTooLarge, CONTAINER_POLL_INTERVAL,
+};
+use anyhow::{Context, Result};
+use log::{debug, info};
+use std::io::{Read, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+/// Supervisor run by `python3 -c` inside the sandbox
+const SUPERVISOR: &str = r#"
+import struct, subprocess, sys
+requests, responses = sys.stdin.buffer, sys.stdout.buffer
+def read_exact(size):
+    data = bytearray()
+    while len(data) < size:
+        chunk = requests.read(size - len(data))
+        if not chunk:
+            raise EOFError
+        data += chunk
+    return bytes(data)
+while True:
+    try:
+        (size,) = struct.unpack(">Q", read_exact(8))
+        if size == 0:
+            break
+        document = read_exact(size)
+    except EOFError:
+        break
+    converter = subprocess.run(
+        [sys.executable, "-m", "dangerzone.conversion.doc_to_pixels"],
+        input=document,
+        stdout=subprocess.PIPE,
+    )
+    status = 0 if converter.returncode == 0 else 1
+    responses.write(struct.pack(">BQ", status, len(converter.stdout)))
+    responses.write(converter.stdout)
+    responses.flush()
+"#;
+
+const STATUS_OK: u8 = 0;
+const STATUS_FAILED: u8 = 1;
+
+/// Response of the supervisor to one document
+#[derive(Debug, PartialEq)]
+struct Response {
+    status: u8,
+    payload: Vec<u8>,
+}
+
+/// Conversion sandbox reused for up to `max_documents` documents
+pub struct ContainerSession {
+    options: ConversionOptions,
+    max_documents: usize,
+    sandbox: Option<Sandbox>,
+}
+
+struct Sandbox {
+    child: SandboxProcess,
+    stdin: Box<dyn Write + Send>,
+    responses: Receiver<std::io::Result<Response>>,
+    stderr_thread: Option<JoinHandle<Result<()>>>,
+    converted: usize,
+}
+
+impl ContainerSession {
+    /// Create a session; its sandbox is started on the first conversion
+    pub fn new(options: ConversionOptions, max_documents: usize) -> Self {
+        ContainerSession {
+            options,
+            max_documents: max_documents.max(1),
+            sandbox: None,
+        }
+    }
+
+    /// Convert a document to pixels in the session's sandbox, starting a new
+    /// one if needed
+    pub fn convert_doc_to_pixels(
+        &mut self,
+        input_path: &str,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<u8>> {
+        let document = std::fs::read(input_path).context(format!(
+            "Failed to read input file '{input_path_sanitized}'",
+            input_path_sanitized = crate::replace_control_chars(input_path, false)
+        ))?;
+        if document.is_empty() {
+            anyhow::bail!("Input file is empty");
+        }
+        cancel.check()?;
+
+        if self
+            .sandbox
+            .as_ref()
+            .is_some_and(|sandbox| sandbox.converted >= self.max_documents)
+        {
+            debug!("Restarting the conversion sandbox");
+            self.stop();
+        }
+        if self.sandbox.is_none() {
+            self.sandbox = Some(Sandbox::start(&self.options)?);
+        }
+
+        info!("Converting document to pixels...");
+        let result = self.request(&document, cancel);
+        match &result {
+            Ok(response) if response.status == STATUS_OK || response.status == STATUS_FAILED => {
+                if let Some(sandbox) = self.sandbox.as_mut() {
+                    sandbox.converted += 1;
+                }
+            }
+            // Don't trust the sandbox anymore: the next document gets a new one
+            _ => self.stop(),
+        }
+
+        let response = result?;
+        match response.status {
+            STATUS_OK => {
+                info!("Document converted to pixels successfully");
+                Ok(response.payload)
+            }
+            STATUS_FAILED => anyhow::bail!(
+                "Converter failed. The document format may be unsupported or corrupted."
+            ),
+            status => anyhow::bail!("Protocol error: invalid status {status} from the sandbox"),
+        }
+    }
+
+    fn request(&mut self, document: &[u8], cancel: &CancellationToken) -> Result<Response> {
+        let sandbox = self.sandbox.as_mut().context("No running sandbox")?;
+        let started = Instant::now();
+        write_request(&mut sandbox.stdin, document)
+            .context("Protocol error: failed to send the document to the sandbox")?;
+        loop {
+            check_container(&mut sandbox.child, started, self.options.timeout, cancel)?;
+            match sandbox.responses.recv_timeout(CONTAINER_POLL_INTERVAL) {
+                Ok(response) => {
+                    return response.context("Protocol error: invalid response from the sandbox")
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    anyhow::bail!("Protocol error: the sandbox exited unexpectedly")
+                }
+            }
+        }
+    }
+
+    /// Stop the session's sandbox, if it is running
+    pub fn stop(&mut self) {
+        if let Some(mut sandbox) = self.sandbox.take() {
+            sandbox.stop();
+        }
+    }
+}
+
+impl Drop for ContainerSession {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl Sandbox {
+    fn start(options: &ConversionOptions) -> Result<Self> {
+        let (mut child, stderr_thread, _repro) =
+            spawn_sandbox(options, &["/usr/bin/python3", "-c", SUPERVISOR])?;
+        let stdin = child
+            .stdin
+            .take()
+            .context("Failed to take ownership of stdin")?;
+        let mut stdout = child
+            .stdout
+            .take()
+            .context("Failed to take ownership of stdout")?;
+
+        let (sender, responses) = mpsc::channel();
+        let limit = options.max_output_bytes;
+        std::thread::spawn(move || loop {
+            let response = read_response(&mut stdout, limit);
+            let failed = response.is_err();
+            if sender.send(response).is_err() || failed {
+                return;
+            }
+        });
+
+        Ok(Sandbox {
+            child,
+            stdin,
+            responses,
+            stderr_thread: Some(stderr_thread),
+            converted: 0,
+        })
+    }
+
+    fn stop(&mut self) {
+        crate::cleanup::kill_container(&mut self.child);
+        if let Some(stderr_thread) = self.stderr_thread.take() {
+            join_stderr_thread(stderr_thread);
+        }
+    }
+}
+
+fn write_request<W: Write>(writer: &mut W, document: &[u8]) -> std::io::Result<()> {
+    writer.write_all(&(document.len() as u64).to_be_bytes())?;
+    writer.write_all(document)?;
+    writer.flush()
+}
+
+fn read_response<R: Read>(reader: &mut R, limit: u64) -> std::io::Result<Response> {
+    let mut header = [0; 9];
+    reader.read_exact(&mut header)?;
+    let status = header[0];
+    let len = u64::from_be_bytes(header[1..].try_into().unwrap());
+    if len > limit {
+        return Err(std::io::Error::other(OutputTooLarge { limit }));
+    }
+    // Grow the buffer as data arrives rather than trusting the length
+    let mut payload = Vec::new();
+    reader.take(len).read_to_end(&mut payload)?;
+    if (payload.len() as u64) < len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "truncated response",
+        ));
+    }
+    Ok(Response { status, payload })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_framing() {
+        let mut buffer = Vec::new();
+        write_request(&mut buffer, b"%PDF").unwrap();
+        assert_eq!(buffer, [0, 0, 0, 0, 0, 0, 0, 4, b'%', b'P', b'D', b'F']);
+    }
+
+    #[test]
+    fn test_response_framing() {
+        let mut stream = vec![STATUS_OK, 0, 0, 0, 0, 0, 0, 0, 2, 0, 1];
+        stream.extend([STATUS_FAILED, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let mut reader = stream.as_slice();
+
+        assert_eq!(
+            read_response(&mut reader, u64::MAX).unwrap(),
+            Response {
+                status: STATUS_OK,
+                payload: vec![0, 1]
+            }
+        );
+        assert_eq!(
+            read_response(&mut reader, u64::MAX).unwrap(),
+            Response {
+                status: STATUS_FAILED,
+                payload: vec![]
+            }
+        );
+        assert!(read_response(&mut reader, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_unreadable_document_keeps_sandbox_stopped() {
+        let mut session = ContainerSession::new(ConversionOptions::default(), 5);
+        let err = session
+            .convert_doc_to_pixels("/nonexistent/a.pdf", &CancellationToken::new())
+            .unwrap_err();
+        assert!(err.to_string().contains("Failed to read input file"));
+        assert!(session.sandbox.is_none());
+    }
+
+    #[test]
+    fn test_oversized_response() {
+        let stream = [STATUS_OK, 0, 0, 0, 0, 0, 0, 0, 8, 1, 2];
+        let err = read_response(&mut stream.as_slice(), 4).unwrap_err();
+        assert!(err.get_ref().unwrap().is::<OutputTooLarge>());
+    }
+
+    #[test]
+    fn test_truncated_response() {
+        let stream = [STATUS_OK, 0, 0, 0, 0, 0, 0, 0, 8, 1, 2];
+        let err = read_response(&mut stream.as_slice(), u64::MAX).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,358 @@
+//! Encrypted on-disk storage for the pixels of very large documents
+//!
+//! Pages beyond a memory threshold are appended to an unnamed temporary file
+//! instead of being kept in memory. The pixels are encrypted with ChaCha20
+//! under a random key that only exists in this process's memory, so the
+//! content of the document never reaches the disk in the clear, and the file
+//! is gone once closed. While the PDF is written, the file is memory-mapped
+//! and each page is decrypted a chunk at a time.
+
+use crate::pdf::PdfPage;
+use crate::protocol::{
+    check_page_checksum, check_page_length, count_pixels, failed_page, read_page_header,
+    read_page_status, read_pixels, read_pixels_with, read_stream_header, PageData, PageMetadata,
+    PixelFormat,
+};
+use anyhow::{Context, Result};
+use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use chacha20::ChaCha20;
+use log::debug;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+/// Size of the chunks pixels are encrypted and decrypted in
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// ChaCha20's 32-bit block counter covers 256 GiB of keystream
+const MAX_SPOOL_BYTES: u64 = 64 << 32;
+
+/// Page of a document whose pixels are either in memory or spooled to disk
+pub(crate) enum Page {
+    Memory(PageData),
+    Spooled(SpooledPage),
+}
+
+/// Page whose pixels are in a [`Spool`]
+pub(crate) struct SpooledPage {
+    width: u16,
+    height: u16,
+    format: PixelFormat,
+    metadata: PageMetadata,
+    spool: Arc<Spool>,
+    offset: u64,
+    len: usize,
+}
+
+impl PdfPage for Page {
+    fn width(&self) -> u16 {
+        match self {
+            Page::Memory(page) => page.width,
+            Page::Spooled(page) => page.width,
+        }
+    }
+
+    fn height(&self) -> u16 {
+        match self {
+            Page::Memory(page) => page.height,
+            Page::Spooled(page) => page.height,
+        }
+    }
+
+    fn format(&self) -> PixelFormat {
+        match self {
+            Page::Memory(page) => page.format,
+            Page::Spooled(page) => page.format,
+        }
+    }
+
+    fn metadata(&self) -> PageMetadata {
+        match self {
+            Page::Memory(page) => page.metadata,
+            Page::Spooled(page) => page.metadata,
+        }
+    }
+
+    fn write_pixels(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        match self {
+            Page::Memory(page) => page.write_pixels(out),
+            Page::Spooled(page) => page.spool.write_range(page.offset, page.len, out),
+        }
+    }
+}
+
+/// Encrypted temporary file pixels are appended to
+struct SpoolWriter {
+    file: File,
+    key: [u8; 32],
+    nonce: [u8; 12],
+    cipher: ChaCha20,
+    len: u64,
+}
+
+impl SpoolWriter {
+    fn new() -> Result<Self> {
+        let file = tempfile::tempfile().context("Failed to create spool file")?;
+        let mut key = [0; 32];
+        let mut nonce = [0; 12];
+        getrandom::fill(&mut key)
+            .and_then(|()| getrandom::fill(&mut nonce))
+            .map_err(|e| anyhow::anyhow!("Failed to generate spool key: {e}"))?;
+        let cipher = ChaCha20::new(&key.into(), &nonce.into());
+        Ok(SpoolWriter {
+            file,
+            key,
+            nonce,
+            cipher,
+            len: 0,
+        })
+    }
+
+    /// Encrypt up to `len` bytes of `reader` into the spool, returning their
+    /// offset, the number of bytes copied and their CRC-32
+    fn append(&mut self, reader: &mut dyn Read, len: usize) -> Result<(u64, usize, u32)> {
+        if self.len + len as u64 > MAX_SPOOL_BYTES {
+            anyhow::bail!("Document is too large to spool");
+        }
+        let offset = self.len;
+        let mut crc = flate2::Crc::new();
+        let mut chunk = vec![0; CHUNK_BYTES];
+        let mut copied = 0;
+        while copied < len {
+            let want = CHUNK_BYTES.min(len - copied);
+            let n = match reader.read(&mut chunk[..want]) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            crc.update(&chunk[..n]);
+            self.cipher.apply_keystream(&mut chunk[..n]);
+            self.file
+                .write_all(&chunk[..n])
+                .context("Failed to write to spool file")?;
+            copied += n;
+            self.len += n as u64;
+        }
+        Ok((offset, copied, crc.sum()))
+    }
+
+    fn finish(self) -> Result<Arc<Spool>> {
+        let map = if self.len == 0 {
+            // Empty files can't be mapped
+            None
+        } else {
+            // SAFETY: the file is unnamed and only this process holds it, so
+            // nothing else can truncate or modify it while it is mapped
+            Some(unsafe { Mmap::map(&self.file) }.context("Failed to map spool file")?)
+        };
+        Ok(Arc::new(Spool {
+            map,
+            key: self.key,
+            nonce: self.nonce,
+        }))
+    }
+}
+
+/// Memory-mapped, encrypted pixels of spooled pages
+pub(crate) struct Spool {
+    map: Option<Mmap>,
+    key: [u8; 32],
+    nonce: [u8; 12],
+}
+
+impl Spool {
+    /// Decrypt `len` bytes at `offset` into `out`
+    fn write_range(&self, offset: u64, len: usize, out: &mut dyn Write) -> std::io::Result<()> {
+        let map = self.map.as_deref().unwrap_or_default();
+        let start = usize::try_from(offset).map_err(std::io::Error::other)?;
+        let encrypted = start
+            .checked_add(len)
+            .and_then(|end| map.get(start..end))
+            .ok_or_else(|| std::io::Error::other("page is outside of the spool file"))?;
+
+        let mut cipher = ChaCha20::new(&self.key.into(), &self.nonce.into());
+        cipher.seek(offset);
+        let mut chunk = vec![0; CHUNK_BYTES.min(len)];
+        for encrypted in encrypted.chunks(CHUNK_BYTES) {
+            let chunk = &mut chunk[..encrypted.len()];
+            cipher.apply_keystream_b2b(encrypted, chunk);
+            out.write_all(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read a pixel stream, keeping pages in memory until their pixels add up to
+/// `threshold` bytes and spooling the following ones to disk, up to `limit`
+/// bytes of pixels in total
+pub(crate) fn read_pages<R: Read>(reader: &mut R, threshold: u64, limit: u64) -> Result<Vec<Page>> {
+    let (page_count, format) = read_stream_header(reader)?;
+    let mut total_bytes = 0;
+    let mut failed_pages = 0;
+    let mut in_memory = 0u64;
+    let mut spool = None;
+    let mut pages = Vec::with_capacity(page_count.into());
+    let mut spooled = Vec::new();
+
+    for page_num in 0..page_count {
+        if format.page_failures && read_page_status(reader, page_num)? {
+            let placeholder = failed_page(page_num, &mut failed_pages, &mut total_bytes, limit)?;
+            in_memory += placeholder.pixels.len() as u64;
+            pages.push(Some(placeholder));
+            continue;
+        }
+        let header = read_page_header(reader, page_num, format)?;
+        let num_bytes = header.num_bytes();
+        count_pixels(&mut total_bytes, num_bytes, limit)?;
+
+        if in_memory + num_bytes as u64 <= threshold {
+            let pixels = read_pixels(reader, format, num_bytes, page_num)?;
+            crate::verify_page(&pixels, num_bytes, header.checksum, page_num)?;
+            in_memory += num_bytes as u64;
+            pages.push(Some(header.into_page(pixels)));
+            continue;
+        }
+
+        if spool.is_none() {
+            debug!("Spooling pages from page {} on to disk", page_num + 1);
+            spool = Some(SpoolWriter::new()?);
+        }
+        let writer = spool.as_mut().unwrap();
+        let (offset, copied, crc) =
+            read_pixels_with(reader, format, num_bytes, page_num, |pixels| {
+                writer.append(pixels, num_bytes)
+            })?;
+        check_page_length(copied, num_bytes, page_num)?;
+        if let Some(expected) = header.checksum {
+            check_page_checksum(crc, expected, page_num)?;
+        }
+        pages.push(None);
+        spooled.push((header, offset, num_bytes));
+    }
+
+    let spool = spool.map(SpoolWriter::finish).transpose()?;
+    let mut spooled = spooled.into_iter();
+    Ok(pages
+        .into_iter()
+        .map(|page| match page {
+            Some(page) => Page::Memory(page),
+            None => {
+                let (header, offset, len) = spooled.next().unwrap();
+                Page::Spooled(SpooledPage {
+                    width: header.width,
+                    height: header.height,
+                    format: header.format,
+                    metadata: header.metadata,
+                    spool: spool.clone().unwrap(),
+                    offset,
+                    len,
+                })
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel_stream(pages: &[PageData]) -> Vec<u8> {
+        let mut data = (pages.len() as u16).to_be_bytes().to_vec();
+        for page in pages {
+            data.extend_from_slice(&page.width.to_be_bytes());
+            data.extend_from_slice(&page.height.to_be_bytes());
+            data.extend_from_slice(&page.pixels);
+        }
+        data
+    }
+
+    fn pages() -> Vec<PageData> {
+        (0..4u8)
+            .map(|i| {
+                let pixels: Vec<u8> = (0..300 * 200 * 3).map(|j| (j % 251) as u8 ^ i).collect();
+                PageData::new(300, 200, pixels)
+            })
+            .collect::<Vec<_>>()
+    }
+
+    fn pixels(page: &Page) -> Vec<u8> {
+        let mut pixels = Vec::new();
+        page.write_pixels(&mut pixels).unwrap();
+        pixels
+    }
+
+    #[test]
+    fn test_spooled_pages_round_trip() {
+        let pages = pages();
+        let data = pixel_stream(&pages);
+        let threshold = 2 * 300 * 200 * 3;
+        let read = read_pages(&mut data.as_slice(), threshold, u64::MAX).unwrap();
+
+        assert_eq!(read.len(), 4);
+        assert!(matches!(read[1], Page::Memory(_)));
+        assert!(matches!(read[2], Page::Spooled(_)));
+        for (read, page) in read.iter().zip(&pages) {
+            assert_eq!((read.width(), read.height()), (300, 200));
+            assert_eq!(pixels(read), page.pixels[..]);
+        }
+    }
+
+    #[test]
+    fn test_spool_is_encrypted() {
+        let mut writer = SpoolWriter::new().unwrap();
+        let pixels = vec![0xAB; 3 * CHUNK_BYTES / 2];
+        writer.append(&mut pixels.as_slice(), pixels.len()).unwrap();
+        let spool = writer.finish().unwrap();
+        assert_ne!(spool.map.as_deref().unwrap(), pixels);
+    }
+
+    #[test]
+    fn test_spooled_pdf_matches_in_memory_pdf() {
+        let pages = pages();
+        let data = pixel_stream(&pages);
+        let spooled = read_pages(&mut data.as_slice(), 0, u64::MAX).unwrap();
+
+        let mut expected = Vec::new();
+        crate::write_pdf(&mut expected, &pages).unwrap();
+        let mut actual = Vec::new();
+        crate::write_pdf_with_progress(&mut actual, &spooled, crate::DPI, &|_| {}).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_failed_pages_are_read() {
+        let mut data = vec![0, 0];
+        data.extend(crate::protocol::STREAM_FLAGS_MAGIC);
+        data.extend([crate::protocol::FLAG_PAGE_FAILURES, 0, 3]);
+        data.extend([0, 0, 1, 0, 1, 1, 2, 3]);
+        data.extend([1, 0, 5]);
+        data.extend(b"crash");
+        data.extend([0, 0, 1, 0, 1, 4, 5, 6]);
+
+        let read = read_pages(&mut data.as_slice(), 0, u64::MAX).unwrap();
+        assert_eq!(read.len(), 3);
+        assert!(matches!(read[0], Page::Spooled(_)));
+        assert!(read[1].metadata().failed);
+        assert_eq!(pixels(&read[2]), [4, 5, 6]);
+
+        // Placeholders count towards the limit
+        let Err(err) = read_pages(&mut data.as_slice(), 0, 100) else {
+            panic!("placeholders over the limit were accepted");
+        };
+        assert!(err.is::<crate::OutputTooLarge>());
+    }
+
+    #[test]
+    fn test_truncated_spooled_page() {
+        let mut data = pixel_stream(&pages());
+        data.truncate(data.len() - 10);
+        let Err(err) = read_pages(&mut data.as_slice(), 0, u64::MAX) else {
+            panic!("truncated stream was accepted");
+        };
+        assert!(err
+            .to_string()
+            .contains("Insufficient data for page 4 pixels"));
+    }
+}
@@ -0,0 +1,277 @@
+//! Custom steps run on each page between pixel parsing and PDF writing
+//!
+//! A [`Stage`](crate::stage::Stage) receives the pixels of each page once
+//! they are parsed, redacted, cleaned up and turned, and returns the page to
+//! write in their place, or `None` to leave it out of the safe PDF. Stages
+//! run in the order they were added, on the threads preparing pages, so
+//! several pages may go through the same stage at once. OCR, downscaling and
+//! compression happen after them.
+//!
+//! ```no_run
+//! use dangerzone_rs::stage::{Pipeline, Stage};
+//! use dangerzone_rs::{CancellationToken, PageData};
+//!
+//! struct Invert;
+//!
+//! impl Stage for Invert {
+//!     fn process(&self, mut page: PageData, _page_num: usize) -> anyhow::Result<Option<PageData>> {
+//!         page.pixels = page.pixels.iter().map(|byte| 255 - byte).collect();
+//!         Ok(Some(page))
+//!     }
+//! }
+//!
+//! let pipeline = Pipeline::builder().stage(Invert).build();
+//! # #[cfg(feature = "container")]
+//! pipeline.convert(
+//!     "input.docx".to_string(),
+//!     "safe.pdf".to_string(),
+//!     &|_| {},
+//!     &CancellationToken::new(),
+//! )?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! A page returned by a stage is checked like one read from the sandbox:
+//! its pixels must match its size and format.
+
+#[cfg(feature = "container")]
+use crate::{
+    convert_document_with_options, replace_control_chars, CancellationToken, ConversionReport,
+    PdfPage, Progress,
+};
+use crate::{ConversionOptions, PageData};
+#[cfg(feature = "container")]
+use anyhow::Context;
+use anyhow::Result;
+use std::sync::Arc;
+
+/// Step run on each page of a conversion, before it is written to the safe
+/// PDF
+pub trait Stage: Send + Sync {
+    /// Name of the stage, in errors
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+
+    /// Transform page `page_num`, numbered from 1, or return `None` to leave
+    /// it out of the safe PDF
+    fn process(&self, page: PageData, page_num: usize) -> Result<Option<PageData>>;
+}
+
+/// Stages of [`ConversionOptions::stages`], in the order they run
+///
+/// Stages can't be serialized: they are left out of serialized options.
+#[derive(Clone, Default)]
+pub struct Stages(Vec<Arc<dyn Stage>>);
+
+impl Stages {
+    pub fn push(&mut self, stage: impl Stage + 'static) {
+        self.0.push(Arc::new(stage));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Run the stages on page `page_num` in order, or return `None` once one
+    /// leaves it out
+    #[cfg(feature = "container")]
+    pub(crate) fn run<P: PdfPage>(&self, page: &P, page_num: usize) -> Result<Option<PageData>> {
+        let format = page.format();
+        let metadata = page.metadata();
+        let mut pixels = Vec::with_capacity(
+            usize::from(page.width()) * usize::from(page.height()) * format.bytes_per_pixel(),
+        );
+        page.write_pixels(&mut pixels)
+            .context("Failed to read page pixels")?;
+        let mut page = PageData::with_format(page.width(), page.height(), format, pixels);
+        page.metadata = metadata;
+        for stage in &self.0 {
+            let name_sanitized = replace_control_chars(stage.name(), false);
+            let Some(processed) = stage
+                .process(page, page_num)
+                .with_context(|| format!("Stage {name_sanitized} failed on page {page_num}"))?
+            else {
+                return Ok(None);
+            };
+            check_page(&processed, page_num)
+                .with_context(|| format!("Stage {name_sanitized} returned an invalid page"))?;
+            page = processed;
+        }
+        Ok(Some(page))
+    }
+}
+
+/// Stages are shown by their names
+impl std::fmt::Debug for Stages {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|stage| stage.name()))
+            .finish()
+    }
+}
+
+/// Stages are equal if they are the same instances, in the same order
+impl PartialEq for Stages {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len() && self.0.iter().zip(&other.0).all(|(a, b)| Arc::ptr_eq(a, b))
+    }
+}
+
+/// Fail on a page whose pixels don't match its size and format, or whose
+/// metadata the safe PDF can't have
+#[cfg(feature = "container")]
+fn check_page(page: &PageData, page_num: usize) -> Result<()> {
+    if page.width == 0 || page.height == 0 {
+        anyhow::bail!("Invalid dimensions for page {page_num}");
+    }
+    let num_bytes =
+        usize::from(page.width) * usize::from(page.height) * page.format.bytes_per_pixel();
+    if page.pixels.len() != num_bytes {
+        anyhow::bail!(
+            "Page {page_num} has {} bytes of pixels, expected {num_bytes}",
+            page.pixels.len()
+        );
+    }
+    page.metadata
+        .check(u16::try_from(page_num - 1).unwrap_or(u16::MAX))
+}
+
+/// Conversion options with custom stages, built by [`Pipeline::builder`]
+#[derive(Clone, Debug, Default)]
+pub struct Pipeline {
+    options: ConversionOptions,
+}
+
+impl Pipeline {
+    pub fn builder() -> PipelineBuilder {
+        PipelineBuilder::default()
+    }
+
+    /// Options of the conversions, with the stages in
+    /// [`ConversionOptions::stages`], e.g. for [`convert_batch`](crate::convert_batch)
+    pub fn options(&self) -> &ConversionOptions {
+        &self.options
+    }
+
+    /// Convert a document to a safe PDF, running the stages on each page (see
+    /// [`convert_document_with_options`])
+    #[cfg(feature = "container")]
+    pub fn convert(
+        &self,
+        input_path: String,
+        output_path: String,
+        progress: &dyn Fn(Progress),
+        cancel: &CancellationToken,
+    ) -> Result<ConversionReport> {
+        convert_document_with_options(input_path, output_path, &self.options, progress, cancel)
+    }
+}
+
+/// Builder of a [`Pipeline`]
+#[derive(Debug, Default)]
+pub struct PipelineBuilder {
+    options: ConversionOptions,
+}
+
+impl PipelineBuilder {
+    /// Convert with `options` instead of the default ones. Their stages run
+    /// before those added to the builder.
+    pub fn options(mut self, options: ConversionOptions) -> Self {
+        let stages = std::mem::take(&mut self.options.stages);
+        self.options = options;
+        self.options.stages.0.extend(stages.0);
+        self
+    }
+
+    /// Run `stage` after the stages added so far
+    pub fn stage(mut self, stage: impl Stage + 'static) -> Self {
+        self.options.stages.push(stage);
+        self
+    }
+
+    pub fn build(self) -> Pipeline {
+        Pipeline {
+            options: self.options,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "container"))]
+mod tests {
+    use super::*;
+    use crate::PixelFormat;
+
+    struct Invert;
+
+    impl Stage for Invert {
+        fn process(&self, mut page: PageData, _page_num: usize) -> Result<Option<PageData>> {
+            page.pixels = page.pixels.iter().map(|byte| 255 - byte).collect();
+            Ok(Some(page))
+        }
+    }
+
+    struct DropEven;
+
+    impl Stage for DropEven {
+        fn name(&self) -> &str {
+            "drop-even"
+        }
+
+        fn process(&self, page: PageData, page_num: usize) -> Result<Option<PageData>> {
+            Ok((page_num % 2 == 1).then_some(page))
+        }
+    }
+
+    struct Truncate;
+
+    impl Stage for Truncate {
+        fn process(&self, mut page: PageData, _page_num: usize) -> Result<Option<PageData>> {
+            page.pixels.truncate(1);
+            Ok(Some(page))
+        }
+    }
+
+    #[test]
+    fn test_stages_run_in_order() {
+        let pipeline = Pipeline::builder().stage(Invert).stage(DropEven).build();
+        let stages = &pipeline.options().stages;
+        assert_eq!(stages.len(), 2);
+        assert_eq!(
+            format!("{stages:?}"),
+            format!("[{:?}, \"drop-even\"]", std::any::type_name::<Invert>())
+        );
+
+        let page = PageData::with_format(2, 1, PixelFormat::Gray, vec![0, 200]);
+        let processed = stages.run(&page, 1).unwrap().unwrap();
+        assert_eq!(&processed.pixels[..], &[255, 55]);
+        assert!(stages.run(&page, 2).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_invalid_stage_output() {
+        let pipeline = Pipeline::builder().stage(Truncate).build();
+        let page = PageData::new(2, 2, vec![0; 12]);
+        let error = pipeline.options().stages.run(&page, 1).unwrap_err();
+        assert!(format!("{error:#}").contains("Page 1 has 1 bytes of pixels, expected 12"));
+    }
+
+    #[test]
+    fn test_builder_keeps_option_stages() {
+        let mut options = ConversionOptions {
+            dpi: 100.0,
+            ..ConversionOptions::default()
+        };
+        options.stages.push(Invert);
+        let pipeline = Pipeline::builder().stage(DropEven).options(options).build();
+        assert_eq!(pipeline.options().dpi, 100.0);
+        assert_eq!(
+            format!("{:?}", pipeline.options().stages),
+            format!("[{:?}, \"drop-even\"]", std::any::type_name::<Invert>())
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! "Page N of M" stamped on the pages of the safe PDF
+//!
+//! The number is drawn in Helvetica, one of the standard Type1 fonts that
+//! viewers provide, so no font program is embedded, on a white box so that
+//! it stays readable over the page image. As the number of pages is only
+//! known once they are all written, each page's stamp is a content stream
+//! of its own, appended to the page's contents.
+
+use crate::{PageNumbers, StampPosition};
+
+/// Resource name of the font of page numbers
+pub(crate) const FONT_RESOURCE: &str = "/FStamp";
+
+/// Font of page numbers
+pub(crate) const FONT: &str = "<<\n\
+    /Type /Font\n\
+    /Subtype /Type1\n\
+    /BaseFont /Helvetica\n\
+    /Encoding /WinAnsiEncoding\n\
+    >>\n";
+
+/// Distance from the stamp to the edges of the page, in points
+const INSET_PTS: f32 = 18.0;
+
+/// Width of `text` in Helvetica, in thousandths of the font size
+fn text_width(text: &str) -> u32 {
+    text.chars()
+        .map(|c| match c {
+            ' ' | 'f' => 278,
+            'P' => 667,
+            // Digits and the other lowercase letters of "Page N of M"
+            _ => 556,
+        })
+        .sum()
+}
+
+/// Content stream stamping `number` of `total` pages on a page of `size`
+/// points, shown turned clockwise by `rotation` degrees
+pub(crate) fn content(
+    number: usize,
+    total: usize,
+    size: (f32, f32),
+    rotation: u16,
+    numbers: &PageNumbers,
+) -> String {
+    let (width, height) = size;
+    // Positions are those on the page as shown, so the stamp stays upright
+    // at the bottom of turned pages
+    let (matrix, (shown_width, shown_height)) = match rotation {
+        90 => (format!("0 1 -1 0 {width:.2} 0 cm\n"), (height, width)),
+        180 => (format!("-1 0 0 -1 {width:.2} {height:.2} cm\n"), size),
+        270 => (format!("0 -1 1 0 0 {height:.2} cm\n"), (height, width)),
+        _ => (String::new(), size),
+    };
+
+    let text = format!("Page {number} of {total}");
+    let font_size = numbers.font_size;
+    let text_width = text_width(&text) as f32 / 1000.0 * font_size;
+    let x = match numbers.position {
+        StampPosition::TopLeft | StampPosition::BottomLeft => INSET_PTS,
+        StampPosition::Top | StampPosition::Bottom => (shown_width - text_width) / 2.0,
+        StampPosition::TopRight | StampPosition::BottomRight => {
+            shown_width - INSET_PTS - text_width
+        }
+    };
+    let y = match numbers.position {
+        StampPosition::TopLeft | StampPosition::Top | StampPosition::TopRight => {
+            shown_height - INSET_PTS - font_size
+        }
+        _ => INSET_PTS,
+    };
+
+    // The text layer leaves text invisible and stretched, so the text state
+    // is set in full
+    let padding = font_size / 4.0;
+    format!(
+        "q\n{matrix}1 g\n{:.2} {:.2} {:.2} {:.2} re\nf\n0 g\n\
+         BT\n{FONT_RESOURCE} {font_size:.2} Tf\n0 Tr\n100 Tz\n0 Tc\n0 Tw\n\
+         1 0 0 1 {x:.2} {y:.2} Tm\n({text}) Tj\nET\nQ\n",
+        x - padding,
+        // Helvetica descends to 0.207 of the font size below the baseline
+        y - 0.207 * font_size - padding,
+        text_width + 2.0 * padding,
+        font_size + 2.0 * padding,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_width() {
+        assert_eq!(
+            text_width("Page 1 of 2"),
+            667 + 3 * 556 + 3 * 278 + 556 + 278 + 556 * 2
+        );
+    }
+
+    #[test]
+    fn test_content() {
+        let numbers = PageNumbers::default();
+        let stamp = content(3, 12, (600.0, 800.0), 0, &numbers);
+        let width = text_width("Page 3 of 12") as f32 / 1000.0 * numbers.font_size;
+        let x = (600.0 - width) / 2.0;
+        assert!(stamp.contains(&format!("1 0 0 1 {x:.2} 18.00 Tm\n(Page 3 of 12) Tj\n")));
+        assert!(!stamp.contains(" cm\n"));
+
+        // On a landscape page made of a turned portrait image, at the bottom
+        // right of the page as shown
+        let numbers = PageNumbers {
+            position: StampPosition::BottomRight,
+            ..PageNumbers::default()
+        };
+        let stamp = content(1, 1, (600.0, 800.0), 90, &numbers);
+        assert!(stamp.starts_with("q\n0 1 -1 0 600.00 0 cm\n"));
+        let width = text_width("Page 1 of 1") as f32 / 1000.0 * numbers.font_size;
+        let x = 800.0 - INSET_PTS - width;
+        assert!(stamp.contains(&format!("1 0 0 1 {x:.2} 18.00 Tm\n")));
+    }
+}
@@ -0,0 +1,255 @@
+//! Running the gRPC service as a systemd daemon
+//!
+//! With socket activation, systemd creates the listening socket and passes it
+//! to the service as file descriptor 3, naming the process it is meant for in
+//! `LISTEN_PID`. The service reports that it is ready, and then that it is
+//! still alive, on the datagram socket named by `NOTIFY_SOCKET`, every half
+//! of the interval given in `WATCHDOG_USEC`. [`units`] writes the units
+//! running the service this way.
+
+use anyhow::{Context, Result};
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::path::Path;
+use std::time::Duration;
+
+/// First file descriptor passed by socket activation
+const LISTEN_FDS_START: i32 = 3;
+
+/// Socket passed by systemd
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// The listening socket systemd passed to this process, if it was socket
+/// activated
+///
+/// The environment variables of socket activation are removed, so that
+/// child processes don't take them for their own.
+pub fn take_listener() -> Result<Option<Listener>> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    if pid.and_then(|pid| pid.parse().ok()) != Some(std::process::id()) {
+        return Ok(None);
+    }
+    match fds.as_deref() {
+        None | Some("0") => return Ok(None),
+        Some("1") => {}
+        Some(fds) => anyhow::bail!("systemd passed {fds} sockets; the service listens on one"),
+    }
+
+    let fd = LISTEN_FDS_START;
+    // SAFETY: fcntl and getsockopt only read and set flags of the descriptor
+    let (is_listening, family) = unsafe {
+        if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) == -1 {
+            return Err(std::io::Error::last_os_error())
+                .context("The socket passed by systemd is invalid");
+        }
+        (
+            socket_option(fd, libc::SO_ACCEPTCONN)? != 0,
+            socket_option(fd, libc::SO_DOMAIN)?,
+        )
+    };
+    if !is_listening {
+        anyhow::bail!("The socket passed by systemd isn't listening; use ListenStream=");
+    }
+    // SAFETY: systemd handed the descriptor to this process, which owns it
+    // from now on
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    match family {
+        libc::AF_INET | libc::AF_INET6 => Ok(Some(Listener::Tcp(socket.into()))),
+        libc::AF_UNIX => Ok(Some(Listener::Unix(socket.into()))),
+        family => anyhow::bail!("The socket passed by systemd has unsupported family {family}"),
+    }
+}
+
+/// Integer value of the socket option `option` of `fd`
+///
+/// # Safety
+///
+/// `fd` must be an open file descriptor.
+unsafe fn socket_option(fd: i32, option: i32) -> Result<i32> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = libc::getsockopt(
+        fd,
+        libc::SOL_SOCKET,
+        option,
+        (&mut value as *mut libc::c_int).cast(),
+        &mut len,
+    );
+    if result == -1 {
+        return Err(std::io::Error::last_os_error())
+            .context("The descriptor passed by systemd isn't a socket");
+    }
+    Ok(value)
+}
+
+/// Send `state`, such as `READY=1`, to systemd; does nothing unless the
+/// service runs under systemd with `Type=notify`
+pub fn notify(state: &str) -> Result<()> {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound().context("Failed to create the notification socket")?;
+    let path = path.to_string_lossy();
+    // Names starting with @ are in the abstract namespace
+    if let Some(name) = path.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        socket
+            .send_to_addr(state.as_bytes(), &addr)
+            .context("Failed to notify systemd")?;
+        return Ok(());
+    }
+    socket
+        .send_to(state.as_bytes(), path.as_ref())
+        .context("Failed to notify systemd")?;
+    Ok(())
+}
+
+/// Interval at which systemd expects `WATCHDOG=1`, if its watchdog is on
+/// for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    let pid = std::env::var("WATCHDOG_PID").ok();
+    if pid.is_some_and(|pid| pid.parse().ok() != Some(std::process::id())) {
+        return None;
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Tell systemd the service is alive twice per watchdog interval, from a
+/// thread of its own, if the watchdog is on
+pub fn start_watchdog() {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval / 2);
+        if let Err(e) = notify("WATCHDOG=1") {
+            log::warn!("{e:#}");
+        }
+    });
+}
+
+/// The `dangerzone.service` and `dangerzone.socket` units running `exe`
+/// as a socket-activated daemon listening on `listen`
+pub fn units(exe: &Path, listen: SocketAddr) -> (String, String) {
+    let service = format!(
+        "[Unit]\n\
+         Description=Dangerzone document conversion service\n\
+         Documentation=https://github.com/freedomofpress/dangerzone-rs\n\
+         Requires=dangerzone.socket\n\
+         After=dangerzone.socket network.target\n\
+         \n\
+         [Service]\n\
+         Type=notify\n\
+         ExecStart={exe} serve --systemd --listen {listen}\n\
+         WatchdogSec=30s\n\
+         Restart=on-failure\n\
+         # Rootless podman keeps its images in the home of this user\n\
+         User=dangerzone\n\
+         Group=dangerzone\n\
+         UMask=0077\n\
+         # Queued jobs, kept across restarts\n\
+         StateDirectory=dangerzone\n\
+         StateDirectoryMode=0700\n\
+         PrivateTmp=yes\n\
+         ProtectSystem=full\n\
+         ProtectKernelModules=yes\n\
+         ProtectKernelLogs=yes\n\
+         ProtectClock=yes\n\
+         ProtectHostname=yes\n\
+         RestrictRealtime=yes\n\
+         LockPersonality=yes\n\
+         SystemCallArchitectures=native\n\
+         \n\
+         [Install]\n\
+         WantedBy=multi-user.target\n",
+        exe = exec_arg(&exe.to_string_lossy()),
+    );
+    let socket = format!(
+        "[Unit]\n\
+         Description=Dangerzone document conversion socket\n\
+         \n\
+         [Socket]\n\
+         ListenStream={listen}\n\
+         NoDelay=yes\n\
+         \n\
+         [Install]\n\
+         WantedBy=sockets.target\n"
+    );
+    (service, socket)
+}
+
+/// `arg` quoted for the command line of `ExecStart=`, with `%` escaped from
+/// specifier expansion
+fn exec_arg(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%");
+    let plain = escaped
+        .bytes()
+        .all(|byte| byte.is_ascii_alphanumeric() || b"/._+-%".contains(&byte));
+    if plain {
+        return escaped;
+    }
+    format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Write [`units`] into the directory `dir`
+pub fn write_units(dir: &Path, exe: &Path, listen: SocketAddr) -> Result<()> {
+    let (service, socket) = units(exe, listen);
+    for (name, unit) in [
+        ("dangerzone.service", service),
+        ("dangerzone.socket", socket),
+    ] {
+        let path = dir.join(name);
+        std::fs::write(&path, unit)
+            .with_context(|| format!("Failed to write '{}'", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_units() {
+        let (service, socket) = units(
+            Path::new("/usr/local/bin/dangerzone-rs"),
+            "127.0.0.1:50051".parse().unwrap(),
+        );
+        assert!(service.contains(
+            "ExecStart=/usr/local/bin/dangerzone-rs serve --systemd --listen 127.0.0.1:50051\n"
+        ));
+        assert!(service.contains("Type=notify\n"));
+        assert!(socket.contains("ListenStream=127.0.0.1:50051\n"));
+    }
+
+    #[test]
+    fn test_exec_arg() {
+        assert_eq!(exec_arg("/usr/bin/dangerzone-rs"), "/usr/bin/dangerzone-rs");
+        assert_eq!(exec_arg("/opt/my tools/dz"), "\"/opt/my tools/dz\"");
+        assert_eq!(exec_arg("/opt/100%/dz"), "/opt/100%%/dz");
+        assert_eq!(exec_arg("/opt/a\"b/dz"), "\"/opt/a\\\"b/dz\"");
+    }
+
+    #[test]
+    fn test_notify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        std::env::set_var("NOTIFY_SOCKET", &path);
+        notify("READY=1").unwrap();
+        std::env::remove_var("NOTIFY_SOCKET");
+        let mut buffer = [0; 16];
+        let len = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"READY=1");
+    }
+}
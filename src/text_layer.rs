@@ -0,0 +1,206 @@
+//! Invisible text layer of pages, from the words found by OCR
+//!
+//! Words are drawn over the page image in render mode 3, neither filled nor
+//! stroked, so that they can be selected, searched and copied without
+//! changing how the page looks. Their font has no glyphs: each UTF-16 code
+//! unit of a word is one character, mapped back to text by the font's
+//! `ToUnicode` CMap, and characters are stretched to fill the word's
+//! bounding box. Words sit on the baseline of their line when the engine
+//! reports one, following its slope, so that selections cover the glyphs
+//! of the image.
+
+use crate::hocr::OcrPage;
+use std::fmt::Write;
+
+/// Resource name of the font of the text layer
+pub(crate) const FONT_RESOURCE: &str = "/FOcr";
+
+/// Width of every character, in thousandths of the font size
+const CHAR_WIDTH: u32 = 500;
+
+/// Font descriptor of the glyphless font, which is not embedded
+pub(crate) const FONT_DESCRIPTOR: &str = "<<\n\
+    /Type /FontDescriptor\n\
+    /FontName /GlyphLessFont\n\
+    /Flags 5\n\
+    /FontBBox [0 0 500 1000]\n\
+    /ItalicAngle 0\n\
+    /Ascent 1000\n\
+    /Descent 0\n\
+    /CapHeight 1000\n\
+    /StemV 80\n\
+    >>\n";
+
+/// CMap mapping each two-byte character code to the same UTF-16 code unit
+pub(crate) const TO_UNICODE: &str = "/CIDInit /ProcSet findresource begin\n\
+    12 dict begin\n\
+    begincmap\n\
+    /CIDSystemInfo << /Registry (Adobe) /Ordering (UCS) /Supplement 0 >> def\n\
+    /CMapName /Adobe-Identity-UCS def\n\
+    /CMapType 2 def\n\
+    1 begincodespacerange\n\
+    <0000> <FFFF>\n\
+    endcodespacerange\n\
+    1 beginbfrange\n\
+    <0000> <FFFF> <0000>\n\
+    endbfrange\n\
+    endcmap\n\
+    CMapName currentdict /CMap defineresource pop\n\
+    end\n\
+    end\n";
+
+/// The CIDFont of the glyphless font, with its descriptor at object
+/// `descriptor_obj_num`
+pub(crate) fn cid_font(descriptor_obj_num: usize) -> String {
+    format!(
+        "<<\n\
+         /Type /Font\n\
+         /Subtype /CIDFontType2\n\
+         /BaseFont /GlyphLessFont\n\
+         /CIDSystemInfo << /Registry (Adobe) /Ordering (Identity) /Supplement 0 >>\n\
+         /FontDescriptor {descriptor_obj_num} 0 R\n\
+         /DW {CHAR_WIDTH}\n\
+         /CIDToGIDMap /Identity\n\
+         >>\n"
+    )
+}
+
+/// The font used by content streams, whose characters are two-byte codes
+pub(crate) fn type0_font(cid_font_obj_num: usize, to_unicode_obj_num: usize) -> String {
+    format!(
+        "<<\n\
+         /Type /Font\n\
+         /Subtype /Type0\n\
+         /BaseFont /GlyphLessFont\n\
+         /Encoding /Identity-H\n\
+         /DescendantFonts [{cid_font_obj_num} 0 R]\n\
+         /ToUnicode {to_unicode_obj_num} 0 R\n\
+         >>\n"
+    )
+}
+
+/// Content stream operators drawing the words of `text`, for a page of
+/// `size_px` pixels shown as `size_pts` points
+///
+/// Each word sits on the baseline of its line, as high as the line is above
+/// it, or else on the bottom of the line, at its height.
+pub(crate) fn content(text: &OcrPage, size_px: (u16, u16), size_pts: (f32, f32)) -> String {
+    let scale_x = size_pts.0 / size_px.0.max(1) as f32;
+    let scale_y = size_pts.1 / size_px.1.max(1) as f32;
+    let mut content = String::from("BT\n3 Tr\n");
+    let lines = text
+        .blocks
+        .iter()
+        .flat_map(|block| &block.paragraphs)
+        .flat_map(|paragraph| &paragraph.lines);
+    for line in lines {
+        for word in &line.words {
+            let units: Vec<u16> = word.text.encode_utf16().collect();
+            let [left, top, right, bottom] = word.bbox;
+            // Where the word starts on its baseline, the slope of the
+            // baseline and the height of the text above it, in pixels
+            let (baseline, slope, height) = match (line.bbox, line.baseline) {
+                ([line_left, line_top, _, line_bottom], Some(line_baseline))
+                    if line_bottom > line_top =>
+                {
+                    let baseline = line_bottom as f32
+                        + line_baseline.offset
+                        + line_baseline.slope * (left as f32 - line_left as f32);
+                    (baseline, line_baseline.slope, baseline - line_top as f32)
+                }
+                ([_, line_top, _, line_bottom], _) if line_bottom > line_top => {
+                    (line_bottom as f32, 0.0, (line_bottom - line_top) as f32)
+                }
+                _ => (bottom as f32, 0.0, bottom.saturating_sub(top) as f32),
+            };
+            if units.is_empty() || right <= left || height <= 0.0 {
+                continue;
+            }
+
+            let font_size = height * scale_y;
+            let natural_width = units.len() as f32 * CHAR_WIDTH as f32 / 1000.0 * font_size;
+            // Pages are upright in the PDF, so a line going down the image
+            // goes down the page
+            let angle = (-slope * scale_y / scale_x).atan();
+            let width = (right - left) as f32 * scale_x / angle.cos();
+            let stretch = width / natural_width * 100.0;
+            let x = left as f32 * scale_x;
+            let y = size_pts.1 - baseline * scale_y;
+            let matrix = if angle == 0.0 {
+                "1 0 0 1".to_string()
+            } else {
+                let (sin, cos) = angle.sin_cos();
+                format!("{cos:.4} {sin:.4} {:.4} {cos:.4}", -sin)
+            };
+            let _ = write!(
+                content,
+                "{FONT_RESOURCE} {font_size:.2} Tf\n{stretch:.2} Tz\n{matrix} {x:.2} {y:.2} Tm\n<"
+            );
+            for unit in units {
+                let _ = write!(content, "{unit:04X}");
+            }
+            content.push_str("> Tj\n");
+        }
+    }
+    content.push_str("ET\n");
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hocr::{Baseline, OcrBlock, OcrLine, OcrParagraph, OcrWord};
+
+    #[test]
+    fn test_content() {
+        let word = |bbox, text: &str| OcrWord {
+            bbox,
+            confidence: None,
+            text: text.to_string(),
+        };
+        let mut text = OcrPage {
+            bbox: [0, 0, 200, 100],
+            blocks: vec![OcrBlock {
+                bbox: [10, 10, 190, 30],
+                paragraphs: vec![OcrParagraph {
+                    bbox: [10, 10, 190, 30],
+                    lang: None,
+                    lines: vec![OcrLine {
+                        bbox: [10, 10, 190, 30],
+                        baseline: None,
+                        words: vec![
+                            word([10, 12, 50, 30], "Ab"),
+                            word([60, 10, 100, 30], ""),
+                            word([110, 10, 190, 30], "é😀"),
+                        ],
+                    }],
+                }],
+            }],
+        };
+
+        // Half a point per pixel
+        let content = content(&text, (200, 100), (100.0, 50.0));
+        assert_eq!(
+            content,
+            "BT\n3 Tr\n\
+             /FOcr 10.00 Tf\n200.00 Tz\n1 0 0 1 5.00 35.00 Tm\n<00410062> Tj\n\
+             /FOcr 10.00 Tf\n266.67 Tz\n1 0 0 1 55.00 35.00 Tm\n<00E9D83DDE00> Tj\n\
+             ET\n"
+        );
+
+        // On a baseline rising to the right, 4 pixels above the bottom
+        let line = &mut text.blocks[0].paragraphs[0].lines[0];
+        line.baseline = Some(Baseline {
+            slope: -0.1,
+            offset: -4.0,
+        });
+        line.words.truncate(1);
+        let content = super::content(&text, (200, 100), (100.0, 50.0));
+        assert_eq!(
+            content,
+            "BT\n3 Tr\n\
+             /FOcr 8.00 Tf\n251.25 Tz\n0.9950 0.0995 -0.0995 0.9950 5.00 37.00 Tm\n<00410062> Tj\n\
+             ET\n"
+        );
+    }
+}
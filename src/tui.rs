@@ -0,0 +1,361 @@
+//! Terminal interface for converting a directory of documents
+//!
+//! Documents are converted by a pool of workers while the interface shows
+//! each of them in a table: queued, running with the progress of its pages,
+//! converted, failed or cancelled. The selected document's details, such as
+//! the full error of a failed conversion, are shown below the table.
+//! Conversions can be cancelled and failed ones retried while the others
+//! keep running.
+
+use crate::{batch_inputs, convert};
+use anyhow::{Context, Result};
+use dangerzone_rs::replace_control_chars;
+use dangerzone_rs::{batch_output_paths, CancellationToken, ConversionOptions, Progress};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Cell, Paragraph, Row, Table, TableState, Wrap};
+use ratatui::Frame;
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Time between two redraws, when no key is pressed
+const TICK: Duration = Duration::from_millis(100);
+
+/// Width of the progress bars, in characters
+const BAR_WIDTH: usize = 20;
+
+enum Status {
+    Queued,
+    Running(Option<Progress>),
+    Converted(Duration),
+    Failed(String),
+    Cancelled,
+}
+
+struct Job {
+    input: String,
+    output: String,
+    status: Status,
+    cancel: CancellationToken,
+}
+
+struct Queue {
+    jobs: Vec<Job>,
+    shutdown: bool,
+}
+
+/// Jobs shared by the interface and the workers, which wait on `wake` for
+/// queued jobs
+struct Shared {
+    queue: Mutex<Queue>,
+    wake: Condvar,
+}
+
+/// Convert the documents of the directory `input` (or the document `input`)
+/// into the directory `output_dir`, `jobs` at a time, showing their progress
+/// until the user quits
+pub(crate) fn run(
+    input: &str,
+    output_dir: &str,
+    jobs: usize,
+    options: &ConversionOptions,
+) -> Result<()> {
+    let inputs = batch_inputs(input)?;
+    if inputs.is_empty() {
+        anyhow::bail!("No documents to convert");
+    }
+    std::fs::create_dir_all(output_dir).with_context(|| {
+        format!(
+            "Failed to create output directory '{output_dir_sanitized}'",
+            output_dir_sanitized = replace_control_chars(output_dir, false)
+        )
+    })?;
+    let outputs = batch_output_paths(&inputs, output_dir, &options.parse_output_template()?);
+    let shared = Shared {
+        queue: Mutex::new(Queue {
+            jobs: inputs
+                .into_iter()
+                .zip(outputs)
+                .map(|(input, output)| Job {
+                    input,
+                    output,
+                    status: Status::Queued,
+                    cancel: CancellationToken::new(),
+                })
+                .collect(),
+            shutdown: false,
+        }),
+        wake: Condvar::new(),
+    };
+
+    // Log records would be drawn over the interface; errors are shown in it
+    let max_level = log::max_level();
+    log::set_max_level(log::LevelFilter::Off);
+    let result = std::thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| work(&shared, options));
+        }
+        let result = show(&shared);
+        let mut queue = shared.queue.lock().unwrap();
+        cancel_all(&mut queue);
+        queue.shutdown = true;
+        shared.wake.notify_all();
+        result
+    });
+    log::set_max_level(max_level);
+    result?;
+
+    let queue = shared.queue.into_inner().unwrap();
+    let mut failed = 0;
+    for job in &queue.jobs {
+        let input_sanitized = replace_control_chars(&job.input, false);
+        match &job.status {
+            Status::Converted(_) => eprintln!(
+                "{input_sanitized}: {output_sanitized}",
+                output_sanitized = replace_control_chars(&job.output, false)
+            ),
+            Status::Failed(error) => {
+                failed += 1;
+                eprintln!(
+                    "{input_sanitized}: failed: {error_sanitized}",
+                    error_sanitized = replace_control_chars(error, true)
+                );
+            }
+            Status::Queued | Status::Running(_) | Status::Cancelled => {
+                failed += 1;
+                eprintln!("{input_sanitized}: cancelled");
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!(
+            "{failed} of {} document(s) weren't converted",
+            queue.jobs.len()
+        );
+    }
+    eprintln!("Conversion completed successfully!");
+    Ok(())
+}
+
+/// Convert queued jobs until the interface shuts down
+fn work(shared: &Shared, options: &ConversionOptions) {
+    loop {
+        let (index, input, output, cancel) = {
+            let mut queue = shared.queue.lock().unwrap();
+            loop {
+                if queue.shutdown {
+                    return;
+                }
+                let queued = queue
+                    .jobs
+                    .iter()
+                    .position(|job| matches!(job.status, Status::Queued));
+                if let Some(index) = queued {
+                    let job = &mut queue.jobs[index];
+                    job.status = Status::Running(None);
+                    break (
+                        index,
+                        job.input.clone(),
+                        job.output.clone(),
+                        job.cancel.clone(),
+                    );
+                }
+                queue = shared.wake.wait(queue).unwrap();
+            }
+        };
+
+        let started = Instant::now();
+        let set_status = |status| shared.queue.lock().unwrap().jobs[index].status = status;
+        let result = convert(
+            input,
+            output,
+            options,
+            &|progress| set_status(Status::Running(Some(progress))),
+            &cancel,
+        );
+        set_status(match result {
+            Ok(_) => Status::Converted(started.elapsed()),
+            Err(_) if cancel.is_cancelled() => Status::Cancelled,
+            Err(e) => Status::Failed(format!("{e:#}")),
+        });
+    }
+}
+
+/// Cancel the running jobs, and those not started yet
+fn cancel_all(queue: &mut Queue) {
+    for job in &mut queue.jobs {
+        cancel(job);
+    }
+}
+
+fn cancel(job: &mut Job) {
+    match job.status {
+        Status::Queued => job.status = Status::Cancelled,
+        Status::Running(_) => job.cancel.cancel(),
+        _ => {}
+    }
+}
+
+/// Show the jobs and handle keys until the user quits
+fn show(shared: &Shared) -> Result<()> {
+    let mut terminal = ratatui::try_init().context("Failed to set up the terminal")?;
+    let mut table = TableState::default().with_selected(0);
+    let result = loop {
+        let drawn = terminal.draw(|frame| {
+            let queue = shared.queue.lock().unwrap();
+            draw(frame, &queue.jobs, &mut table);
+        });
+        if let Err(e) = drawn {
+            break Err(e.into());
+        }
+        let key = match event::poll(TICK).and_then(|ready| ready.then(event::read).transpose()) {
+            Ok(Some(Event::Key(key))) if key.kind == KeyEventKind::Press => key,
+            Ok(_) => continue,
+            Err(e) => break Err(e.into()),
+        };
+
+        let mut queue = shared.queue.lock().unwrap();
+        let selected = table.selected().unwrap_or(0).min(queue.jobs.len() - 1);
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => break Ok(()),
+            KeyCode::Up | KeyCode::Char('k') => table.select(Some(selected.saturating_sub(1))),
+            KeyCode::Down | KeyCode::Char('j') => {
+                table.select(Some((selected + 1).min(queue.jobs.len() - 1)))
+            }
+            KeyCode::Char('c') => cancel(&mut queue.jobs[selected]),
+            KeyCode::Char('C') => cancel_all(&mut queue),
+            KeyCode::Char('r') => {
+                let job = &mut queue.jobs[selected];
+                if matches!(job.status, Status::Failed(_) | Status::Cancelled) {
+                    job.status = Status::Queued;
+                    job.cancel = CancellationToken::new();
+                    shared.wake.notify_one();
+                }
+            }
+            _ => {}
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+fn draw(frame: &mut Frame, jobs: &[Job], table: &mut TableState) {
+    let [jobs_area, details_area, keys_area] = Layout::vertical([
+        Constraint::Min(3),
+        Constraint::Length(7),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let count =
+        |matches: fn(&Status) -> bool| jobs.iter().filter(|job| matches(&job.status)).count();
+    let title = format!(
+        " {} converted, {} failed, {} running, {} queued ",
+        count(|status| matches!(status, Status::Converted(_))),
+        count(|status| matches!(status, Status::Failed(_))),
+        count(|status| matches!(status, Status::Running(_))),
+        count(|status| matches!(status, Status::Queued)),
+    );
+    let rows = jobs.iter().map(|job| {
+        let name = Path::new(&job.input).file_name().map_or_else(
+            || job.input.clone(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        let (status, color) = match &job.status {
+            Status::Queued => ("Queued".to_string(), Color::Gray),
+            Status::Running(progress) => (running(progress.as_ref()), Color::Yellow),
+            Status::Converted(duration) => (
+                format!("Converted in {:.1}s", duration.as_secs_f32()),
+                Color::Green,
+            ),
+            Status::Failed(_) => ("Failed".to_string(), Color::Red),
+            Status::Cancelled => ("Cancelled".to_string(), Color::DarkGray),
+        };
+        Row::new([
+            Cell::from(replace_control_chars(&name, false)),
+            Cell::from(status).style(Style::new().fg(color)),
+        ])
+    });
+    let widths = [Constraint::Percentage(50), Constraint::Percentage(50)];
+    let jobs_table = Table::new(rows, widths)
+        .header(Row::new(["Document", "Status"]).bold())
+        .block(Block::bordered().title(title))
+        .row_highlight_style(Style::new().reversed());
+    frame.render_stateful_widget(jobs_table, jobs_area, table);
+
+    let details = table
+        .selected()
+        .and_then(|selected| jobs.get(selected))
+        .map(|job| {
+            let mut lines = vec![
+                Line::from(format!(
+                    "Input:  {}",
+                    replace_control_chars(&job.input, false)
+                )),
+                Line::from(format!(
+                    "Output: {}",
+                    replace_control_chars(&job.output, false)
+                )),
+            ];
+            if let Status::Failed(error) = &job.status {
+                lines.extend(
+                    replace_control_chars(error, true)
+                        .lines()
+                        .map(|line| Line::from(line.to_string()).red()),
+                );
+            }
+            lines
+        })
+        .unwrap_or_default();
+    frame.render_widget(
+        Paragraph::new(details)
+            .wrap(Wrap { trim: false })
+            .block(Block::bordered().title(" Details ")),
+        details_area,
+    );
+    frame.render_widget(
+        Line::from(" ↑/↓ select   c cancel   C cancel all   r retry   q quit").dim(),
+        keys_area,
+    );
+}
+
+/// Status of a running job, with a progress bar once pages are written
+fn running(progress: Option<&Progress>) -> String {
+    match progress {
+        None | Some(Progress::ConvertingToPixels) => "Converting to pixels".to_string(),
+        Some(Progress::PixelsReceived { total_pages }) => {
+            format!("{total_pages} page(s) received")
+        }
+        Some(&Progress::WritingPage { page, total_pages }) => {
+            format!("{} {page}/{total_pages}", bar(page, total_pages))
+        }
+        Some(Progress::ApplyingOcr) => "Applying OCR".to_string(),
+        Some(Progress::Done) => "Finishing".to_string(),
+        Some(_) => "Running".to_string(),
+    }
+}
+
+/// A bar of [`BAR_WIDTH`] characters, `done` out of `total` full
+fn bar(done: usize, total: usize) -> String {
+    let full = (done * BAR_WIDTH)
+        .checked_div(total)
+        .unwrap_or(0)
+        .min(BAR_WIDTH);
+    format!("{}{}", "█".repeat(full), "░".repeat(BAR_WIDTH - full))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bar() {
+        assert_eq!(bar(0, 4), "░".repeat(BAR_WIDTH));
+        assert_eq!(bar(1, 4), format!("{}{}", "█".repeat(5), "░".repeat(15)));
+        assert_eq!(bar(4, 4), "█".repeat(BAR_WIDTH));
+        assert_eq!(bar(1, 0), "░".repeat(BAR_WIDTH));
+    }
+}
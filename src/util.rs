@@ -4,7 +4,7 @@
 /// from obscure control characters
 ///
 /// Control characters are replaced by � U+FFFD Replacement Character
-pub(crate) fn replace_control_chars(s: &str, keep_newlines: bool) -> String {
+pub fn replace_control_chars(s: &str, keep_newlines: bool) -> String {
     /// Return whether Unicode character is safe to print in a terminal
     /// emulator, based on its General Category
     fn is_safe(c: char) -> bool {
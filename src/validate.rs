@@ -0,0 +1,1207 @@
+//! Consistency checks of written PDFs
+//!
+//! The PDF writer computes every offset and length itself, and a mistake in
+//! that arithmetic makes files that viewers either repair silently or reject.
+//! The checks here read a file back the way a viewer does: from the
+//! cross-reference table at its end to each object, each stream and each page
+//! of the page tree. Only PDFs with a single cross-reference table or stream,
+//! or the two of linearized PDFs, like those of this crate, can be checked.
+//! Stream data is skipped rather than read, except that of cross-reference and
+//! object streams, so a file is never loaded in memory as a whole.
+
+use crate::replace_control_chars;
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+
+/// Bytes read from the end of the file to find the cross-reference table
+const TAIL_BYTES: u64 = 1024;
+
+/// Largest object, without its stream data, that can be checked
+pub(crate) const MAX_OBJECT_BYTES: u64 = 64 * 1024;
+
+/// Largest cross-reference table and trailer that can be checked, and
+/// largest object stream, compressed or not
+const MAX_XREF_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Deepest nesting of arrays and dictionaries, and of the page tree
+const MAX_DEPTH: usize = 32;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Object {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Name(String),
+    String,
+    Array(Vec<Object>),
+    Dict(Dict),
+    /// Object number and generation of an indirect reference
+    Ref(u32, u16),
+}
+
+pub(crate) type Dict = BTreeMap<String, Object>;
+
+impl Object {
+    pub(crate) fn as_dict(&self) -> Option<&Dict> {
+        match self {
+            Object::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b'\0' | b'\t' | b'\n' | b'\x0c' | b'\r' | b' ')
+}
+
+fn is_delimiter(byte: u8) -> bool {
+    matches!(
+        byte,
+        b'(' | b')' | b'<' | b'>' | b'[' | b']' | b'{' | b'}' | b'/' | b'%'
+    )
+}
+
+fn is_regular(byte: u8) -> bool {
+    !is_whitespace(byte) && !is_delimiter(byte)
+}
+
+/// Parser of the objects of a PDF, without stream data
+pub(crate) struct Parser<'a> {
+    data: &'a [u8],
+    pub(crate) pos: usize,
+    /// Where the object number of each indirect reference parsed is
+    pub(crate) references: Vec<Range<usize>>,
+}
+
+impl<'a> Parser<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Parser {
+            data,
+            pos: 0,
+            references: Vec::new(),
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(byte) = self.peek() {
+            if byte == b'%' {
+                while self
+                    .peek()
+                    .is_some_and(|byte| byte != b'\n' && byte != b'\r')
+                {
+                    self.pos += 1;
+                }
+            } else if is_whitespace(byte) {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Next run of regular characters: a number or a keyword
+    pub(crate) fn token(&mut self) -> &'a [u8] {
+        self.skip_whitespace();
+        let start = self.pos;
+        while self.peek().is_some_and(is_regular) {
+            self.pos += 1;
+        }
+        &self.data[start..self.pos]
+    }
+
+    pub(crate) fn integer<T: std::str::FromStr>(&mut self) -> Option<T> {
+        let token = self.token();
+        if token.is_empty() || !token.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        std::str::from_utf8(token).ok()?.parse().ok()
+    }
+
+    /// `number generation R`, after a number that may start one
+    fn reference(&mut self, number: &[u8]) -> Option<Object> {
+        let number = std::str::from_utf8(number).ok()?.parse().ok()?;
+        let start = self.pos;
+        let reference = self
+            .integer()
+            .filter(|_| self.token() == b"R")
+            .map(|generation| Object::Ref(number, generation));
+        if reference.is_none() {
+            self.pos = start;
+        }
+        reference
+    }
+
+    pub(crate) fn object(&mut self, depth: usize) -> Result<Object> {
+        if depth > MAX_DEPTH {
+            anyhow::bail!("Objects are nested too deeply");
+        }
+        self.skip_whitespace();
+        match self.peek() {
+            None => anyhow::bail!("Unexpected end of object"),
+            Some(b'/') => {
+                self.pos += 1;
+                Ok(Object::Name(self.name()))
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                loop {
+                    self.skip_whitespace();
+                    if self.peek() == Some(b']') {
+                        self.pos += 1;
+                        return Ok(Object::Array(items));
+                    }
+                    items.push(self.object(depth + 1)?);
+                }
+            }
+            Some(b'<') if self.data[self.pos..].starts_with(b"<<") => {
+                self.pos += 2;
+                let mut dict = Dict::new();
+                loop {
+                    self.skip_whitespace();
+                    if self.data[self.pos..].starts_with(b">>") {
+                        self.pos += 2;
+                        return Ok(Object::Dict(dict));
+                    }
+                    let Object::Name(key) = self.object(depth + 1)? else {
+                        anyhow::bail!("Dictionary key isn't a name");
+                    };
+                    let value = self.object(depth + 1)?;
+                    dict.insert(key, value);
+                }
+            }
+            Some(b'<') => {
+                let end = self.data[self.pos..]
+                    .iter()
+                    .position(|&byte| byte == b'>')
+                    .context("Unterminated hex string")?;
+                self.pos += end + 1;
+                Ok(Object::String)
+            }
+            Some(b'(') => {
+                self.literal_string()?;
+                Ok(Object::String)
+            }
+            Some(byte) if is_regular(byte) => {
+                let start = self.pos;
+                let token = self.token();
+                match token {
+                    b"null" => return Ok(Object::Null),
+                    b"true" => return Ok(Object::Bool(true)),
+                    b"false" => return Ok(Object::Bool(false)),
+                    _ => {}
+                }
+                if let Some(reference) = self.reference(token) {
+                    self.references.push(start..start + token.len());
+                    return Ok(reference);
+                }
+                std::str::from_utf8(token)
+                    .ok()
+                    .and_then(|token| token.parse().ok())
+                    .filter(|number: &f64| number.is_finite())
+                    .map(Object::Number)
+                    .with_context(|| format!("Invalid token `{}`", String::from_utf8_lossy(token)))
+            }
+            Some(byte) => anyhow::bail!("Unexpected character `{}`", byte as char),
+        }
+    }
+
+    /// Name after its `/`, with `#xx` escapes decoded
+    fn name(&mut self) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(is_regular) {
+            self.pos += 1;
+        }
+        let mut name = Vec::new();
+        let mut bytes = self.data[start..self.pos].iter();
+        while let Some(&byte) = bytes.next() {
+            let hex = bytes.as_slice().get(..2).filter(|_| byte == b'#');
+            match hex.and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()) {
+                Some(decoded) => {
+                    name.push(decoded);
+                    bytes.nth(1);
+                }
+                None => name.push(byte),
+            }
+        }
+        String::from_utf8_lossy(&name).into_owned()
+    }
+
+    fn literal_string(&mut self) -> Result<()> {
+        let mut depth = 0;
+        while let Some(byte) = self.peek() {
+            self.pos += 1;
+            match byte {
+                b'\\' => self.pos += 1,
+                b'(' => depth += 1,
+                b')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                _ => {}
+            }
+        }
+        anyhow::bail!("Unterminated string")
+    }
+}
+
+pub(crate) fn read_at<R: Read + Seek>(file: &mut R, offset: u64, len: u64) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::new();
+    file.take(len).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Entry of the cross-reference table for an object in use
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum XrefEntry {
+    InFile {
+        offset: u64,
+        generation: u16,
+    },
+    /// Object `index` of the object stream numbered `stream`
+    Compressed {
+        stream: u32,
+        index: u32,
+    },
+}
+
+impl XrefEntry {
+    fn generation(&self) -> u16 {
+        match *self {
+            XrefEntry::InFile { generation, .. } => generation,
+            XrefEntry::Compressed { .. } => 0,
+        }
+    }
+}
+
+/// Decompress the data of a stream whose dictionary is `dict`, which may
+/// only use `/FlateDecode`
+fn decode_stream(number: u32, dict: &Dict, data: &[u8]) -> Result<Vec<u8>> {
+    match dict.get("Filter") {
+        None => return Ok(data.to_vec()),
+        Some(Object::Name(filter))
+            if filter == "FlateDecode" && !dict.contains_key("DecodeParms") => {}
+        Some(_) => anyhow::bail!("Stream of object {number} has an unsupported /Filter"),
+    }
+    let mut decoded = Vec::new();
+    flate2::read::ZlibDecoder::new(data)
+        .take(MAX_XREF_BYTES + 1)
+        .read_to_end(&mut decoded)
+        .with_context(|| format!("Stream of object {number} isn't valid zlib data"))?;
+    if decoded.len() as u64 > MAX_XREF_BYTES {
+        anyhow::bail!("Stream of object {number} is too large to check");
+    }
+    Ok(decoded)
+}
+
+/// Cross-reference table or stream, with its trailer
+struct XrefSection {
+    entries: BTreeMap<u32, XrefEntry>,
+    /// Numbers of the objects listed, in use or free
+    listed: HashSet<u32>,
+    trailer: Dict,
+    /// Number of the object of the cross-reference stream, if it is one
+    stream: Option<u32>,
+}
+
+/// Read the cross-reference table or stream at `offset` of a file of `len`
+/// bytes, reading the file up to its end only for large ones
+fn read_xref_at<R: Read + Seek>(file: &mut R, offset: u64, len: u64) -> Result<XrefSection> {
+    // The first-page table of linearized PDFs is at the start of the file
+    let data = read_at(file, offset, MAX_OBJECT_BYTES.min(len - offset))?;
+    match read_xref(&data) {
+        Ok(section) => return Ok(section),
+        Err(e) if data.len() as u64 == len - offset => return Err(e),
+        Err(_) => {}
+    }
+    if len - offset > MAX_XREF_BYTES {
+        anyhow::bail!("Cross-reference table is too large to check");
+    }
+    read_xref(&read_at(file, offset, len - offset)?)
+}
+
+/// Parse the cross-reference table and the trailer dictionary after it, or
+/// the cross-reference stream, whose dictionary is the trailer
+fn read_xref(data: &[u8]) -> Result<XrefSection> {
+    let mut parser = Parser::new(data);
+    if parser.token() != b"xref" {
+        return read_xref_stream(data);
+    }
+
+    let mut entries = BTreeMap::new();
+    let mut seen = HashSet::new();
+    loop {
+        let start = parser.pos;
+        if parser.token() == b"trailer" {
+            break;
+        }
+        parser.pos = start;
+        let (Some(first), Some(count)) = (parser.integer::<u32>(), parser.integer::<u32>()) else {
+            anyhow::bail!("Invalid cross-reference subsection header");
+        };
+        parser.skip_whitespace();
+        for number in first..first.saturating_add(count) {
+            let entry = data.get(parser.pos..parser.pos + 20).with_context(|| {
+                format!("Cross-reference entry of object {number} is truncated")
+            })?;
+            parser.pos += 20;
+            let valid = entry[..10].iter().all(u8::is_ascii_digit)
+                && entry[10] == b' '
+                && entry[11..16].iter().all(u8::is_ascii_digit)
+                && entry[16] == b' '
+                && matches!(&entry[18..], b" \n" | b" \r" | b"\r\n");
+            let offset = std::str::from_utf8(&entry[..10])?.parse().ok();
+            let generation = std::str::from_utf8(&entry[11..16])?.parse().ok();
+            let (true, Some(offset), Some(generation)) = (valid, offset, generation) else {
+                anyhow::bail!("Invalid cross-reference entry for object {number}");
+            };
+            if !seen.insert(number) {
+                anyhow::bail!("Object {number} is listed twice in the cross-reference table");
+            }
+            match entry[17] {
+                b'n' if number == 0 => anyhow::bail!("Object 0 must be free"),
+                b'n' => {
+                    entries.insert(number, XrefEntry::InFile { offset, generation });
+                }
+                b'f' => {}
+                _ => anyhow::bail!("Invalid cross-reference entry for object {number}"),
+            }
+        }
+    }
+
+    let trailer = match parser.object(0).context("Invalid trailer")? {
+        Object::Dict(trailer) => trailer,
+        _ => anyhow::bail!("Trailer isn't a dictionary"),
+    };
+    Ok(XrefSection {
+        entries,
+        listed: seen,
+        trailer,
+        stream: None,
+    })
+}
+
+/// Check that the /Size of `trailer` is one more than the last of the
+/// objects `seen`, from 0
+fn check_size(trailer: &Dict, seen: &HashSet<u32>) -> Result<()> {
+    let size = match trailer.get("Size") {
+        Some(&Object::Number(size)) => size,
+        _ => anyhow::bail!("Trailer has no /Size"),
+    };
+    if size != seen.len() as f64 || seen.iter().any(|&number| number as f64 >= size) {
+        anyhow::bail!(
+            "Trailer /Size is {size}, but the cross-reference table lists objects 0 to {}",
+            seen.len().saturating_sub(1)
+        );
+    }
+    Ok(())
+}
+
+/// Parse the cross-reference stream at the start of `data`
+fn read_xref_stream(data: &[u8]) -> Result<XrefSection> {
+    let mut parser = Parser::new(data);
+    let (Some(number), Some(0), b"obj") = (
+        parser.integer::<u32>(),
+        parser.integer::<u16>(),
+        parser.token(),
+    ) else {
+        anyhow::bail!("No cross-reference table or stream at the startxref offset");
+    };
+    let dict = match parser.object(0).context("Invalid cross-reference stream")? {
+        Object::Dict(dict) if dict.get("Type") == Some(&Object::Name("XRef".to_string())) => dict,
+        _ => anyhow::bail!("No cross-reference table or stream at the startxref offset"),
+    };
+    let start = match (parser.token(), &data[parser.pos..]) {
+        (b"stream", [b'\r', b'\n', ..]) => parser.pos + 2,
+        (b"stream", [b'\n', ..]) => parser.pos + 1,
+        _ => anyhow::bail!("Cross-reference stream has no stream data"),
+    };
+    let stream = match dict.get("Length") {
+        Some(&Object::Number(length)) if length >= 0.0 && length.fract() == 0.0 => data
+            .get(start..start + length as usize)
+            .context("Cross-reference stream is truncated")?,
+        _ => anyhow::bail!("Cross-reference stream has no direct /Length"),
+    };
+    let stream = decode_stream(number, &dict, stream)?;
+
+    let integers = |key: &str| -> Option<Vec<usize>> {
+        let Some(Object::Array(items)) = dict.get(key) else {
+            return None;
+        };
+        items
+            .iter()
+            .map(|item| match *item {
+                Object::Number(value) if value >= 0.0 && value.fract() == 0.0 => {
+                    Some(value as usize)
+                }
+                _ => None,
+            })
+            .collect()
+    };
+    let widths = integers("W")
+        .filter(|widths| widths.len() == 3 && widths.iter().all(|&width| width <= 8))
+        .context("Cross-reference stream has an invalid /W")?;
+    let index = match dict.get("Index") {
+        None => match dict.get("Size") {
+            Some(&Object::Number(size)) if size >= 0.0 => vec![0, size as usize],
+            _ => anyhow::bail!("Trailer has no /Size"),
+        },
+        Some(_) => integers("Index")
+            .filter(|index| index.len() % 2 == 0)
+            .context("Cross-reference stream has an invalid /Index")?,
+    };
+
+    let entry_len = widths.iter().sum::<usize>();
+    let mut fields = stream.chunks(entry_len.max(1));
+    let mut entries = BTreeMap::new();
+    let mut seen = HashSet::new();
+    for range in index.chunks(2) {
+        for number in range[0]..range[0].saturating_add(range[1]) {
+            let entry = fields
+                .next()
+                .filter(|entry| entry.len() == entry_len)
+                .with_context(|| {
+                    format!("Cross-reference entry of object {number} is truncated")
+                })?;
+            let number = u32::try_from(number)?;
+            let mut values = [0u64; 3];
+            let mut rest = entry;
+            for (value, &width) in values.iter_mut().zip(&widths) {
+                let (field, tail) = rest.split_at(width);
+                *value = field
+                    .iter()
+                    .fold(0, |value, &byte| value << 8 | byte as u64);
+                rest = tail;
+            }
+            // The type defaults to 1 when it has no field
+            let kind = if widths[0] == 0 { 1 } else { values[0] };
+            if !seen.insert(number) {
+                anyhow::bail!("Object {number} is listed twice in the cross-reference table");
+            }
+            match kind {
+                0 => {}
+                _ if number == 0 => anyhow::bail!("Object 0 must be free"),
+                1 => {
+                    let generation = u16::try_from(values[2]).with_context(|| {
+                        format!("Invalid cross-reference entry for object {number}")
+                    })?;
+                    entries.insert(
+                        number,
+                        XrefEntry::InFile {
+                            offset: values[1],
+                            generation,
+                        },
+                    );
+                }
+                2 => {
+                    let (Ok(stream), Ok(index)) =
+                        (u32::try_from(values[1]), u32::try_from(values[2]))
+                    else {
+                        anyhow::bail!("Invalid cross-reference entry for object {number}");
+                    };
+                    entries.insert(number, XrefEntry::Compressed { stream, index });
+                }
+                _ => anyhow::bail!("Invalid cross-reference entry for object {number}"),
+            }
+        }
+    }
+    if fields.next().is_some() {
+        anyhow::bail!("Cross-reference stream has more entries than its /Index lists");
+    }
+    Ok(XrefSection {
+        entries,
+        listed: seen,
+        trailer: dict,
+        stream: Some(number),
+    })
+}
+
+/// Objects of a PDF, read through its cross-reference table
+pub(crate) struct Document {
+    pub(crate) objects: BTreeMap<u32, Object>,
+    pub(crate) entries: BTreeMap<u32, XrefEntry>,
+    /// Start and length of the data of each stream in the file
+    pub(crate) streams: BTreeMap<u32, (u64, u64)>,
+    /// Length of the file
+    pub(crate) len: u64,
+}
+
+impl Document {
+    /// Read the objects of the object stream numbered `stream`, whose data
+    /// is `data`, checking that each is listed at its index
+    fn read_object_stream(&mut self, stream: u32, data: &[u8]) -> Result<()> {
+        let dict = self
+            .objects
+            .get(&stream)
+            .and_then(Object::as_dict)
+            .filter(|dict| dict.get("Type") == Some(&Object::Name("ObjStm".to_string())))
+            .with_context(|| format!("Object {stream} isn't an object stream"))?;
+        let integer = |key: &str| match dict.get(key) {
+            Some(&Object::Number(value)) if value >= 0.0 && value.fract() == 0.0 => {
+                Ok(value as usize)
+            }
+            _ => anyhow::bail!("Object stream {stream} has no valid /{key}"),
+        };
+        let (count, first) = (integer("N")?, integer("First")?);
+        let data = decode_stream(stream, dict, data)?;
+        let objects = data
+            .get(first..)
+            .with_context(|| format!("Object stream {stream} is shorter than its /First"))?;
+
+        let mut header = Parser::new(&data[..first]);
+        for index in 0..count {
+            let (Some(number), Some(offset)) = (header.integer::<u32>(), header.integer::<usize>())
+            else {
+                anyhow::bail!("Object stream {stream} has an invalid header");
+            };
+            let listed = XrefEntry::Compressed {
+                stream,
+                index: index as u32,
+            };
+            if self.entries.get(&number) != Some(&listed) {
+                anyhow::bail!(
+                    "Object {number} of object stream {stream} isn't listed there by the cross-reference table"
+                );
+            }
+            let mut parser = Parser::new(objects.get(offset..).with_context(|| {
+                format!("Object {number} is past the end of object stream {stream}")
+            })?);
+            let object = parser
+                .object(0)
+                .with_context(|| format!("Object {number} is invalid"))?;
+            self.objects.insert(number, object);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn resolve<'a>(&'a self, object: &'a Object) -> &'a Object {
+        match object {
+            Object::Ref(number, _) => self.objects.get(number).unwrap_or(&Object::Null),
+            object => object,
+        }
+    }
+
+    /// Check that `object`, found in object `number`, only refers to objects
+    /// listed in the cross-reference table
+    fn check_references(&self, number: u32, object: &Object) -> Result<()> {
+        match object {
+            Object::Ref(target, generation) => {
+                let listed = self
+                    .entries
+                    .get(target)
+                    .is_some_and(|entry| entry.generation() == *generation);
+                if !listed {
+                    anyhow::bail!(
+                        "Object {number} refers to missing object {target} {generation} R"
+                    );
+                }
+            }
+            Object::Array(items) => {
+                for item in items {
+                    self.check_references(number, item)?;
+                }
+            }
+            Object::Dict(dict) => {
+                for value in dict.values() {
+                    self.check_references(number, value)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Check the page tree of the catalog `trailer` gives, returning the
+    /// object numbers of its pages in order
+    pub(crate) fn pages(&self, trailer: &Dict) -> Result<Vec<u32>> {
+        let root = trailer.get("Root").context("Trailer has no /Root")?;
+        let catalog = self
+            .resolve(root)
+            .as_dict()
+            .filter(|catalog| catalog.get("Type") == Some(&Object::Name("Catalog".to_string())))
+            .context("Trailer /Root isn't a catalog")?;
+        let pages = catalog.get("Pages").context("Catalog has no /Pages")?;
+        let mut numbers = Vec::new();
+        self.check_page_tree(pages, None, None, &mut HashSet::new(), &mut numbers, 0)?;
+        if numbers.is_empty() {
+            anyhow::bail!("PDF has no pages");
+        }
+        Ok(numbers)
+    }
+
+    /// Check a node of the page tree, adding the pages below it to `pages`
+    /// and returning their number
+    fn check_page_tree(
+        &self,
+        node_ref: &Object,
+        parent: Option<&Object>,
+        media_box: Option<&Object>,
+        visited: &mut HashSet<u32>,
+        pages: &mut Vec<u32>,
+        depth: usize,
+    ) -> Result<usize> {
+        let &Object::Ref(number, _) = node_ref else {
+            anyhow::bail!("Page tree node isn't an indirect reference");
+        };
+        if depth > MAX_DEPTH || !visited.insert(number) {
+            anyhow::bail!("Page tree loops back to object {number}");
+        }
+        let node = self
+            .resolve(node_ref)
+            .as_dict()
+            .with_context(|| format!("Page tree node {number} isn't a dictionary"))?;
+        if node.get("Parent") != parent {
+            anyhow::bail!("Page tree node {number} has the wrong /Parent");
+        }
+        let media_box = node.get("MediaBox").or(media_box);
+
+        match node.get("Type") {
+            Some(Object::Name(kind)) if kind == "Pages" => {
+                let Some(Object::Array(kids)) = node.get("Kids").map(|kids| self.resolve(kids))
+                else {
+                    anyhow::bail!("Page tree node {number} has no /Kids");
+                };
+                let mut count = 0;
+                for kid in kids {
+                    count += self.check_page_tree(
+                        kid,
+                        Some(node_ref),
+                        media_box,
+                        visited,
+                        pages,
+                        depth + 1,
+                    )?;
+                }
+                match node.get("Count").map(|count| self.resolve(count)) {
+                    Some(&Object::Number(declared)) if declared == count as f64 => Ok(count),
+                    _ => anyhow::bail!(
+                        "Page tree node {number} has {count} page(s), not the /Count it declares"
+                    ),
+                }
+            }
+            Some(Object::Name(kind)) if kind == "Page" => {
+                self.check_media_box(number, media_box)?;
+                pages.push(number);
+                Ok(1)
+            }
+            _ => anyhow::bail!("Page tree node {number} is neither /Pages nor /Page"),
+        }
+    }
+
+    /// Check the parameters of linearized PDFs, in the dictionary of their
+    /// first object, against the file and its `pages`
+    fn check_linearization(&self, pages: &[u32]) -> Result<()> {
+        let first = self
+            .entries
+            .iter()
+            .filter_map(|(number, entry)| match *entry {
+                XrefEntry::InFile { offset, .. } => Some((offset, number)),
+                XrefEntry::Compressed { .. } => None,
+            })
+            .min()
+            .and_then(|(_, number)| self.objects.get(number)?.as_dict());
+        let Some(parameters) = first.filter(|dict| dict.contains_key("Linearized")) else {
+            return Ok(());
+        };
+        let integer = |key: &str| match parameters.get(key) {
+            Some(&Object::Number(value)) if value >= 0.0 && value.fract() == 0.0 => {
+                Ok(value as u64)
+            }
+            _ => anyhow::bail!("Linearization dictionary has no valid /{key}"),
+        };
+        let length = integer("L")?;
+        if length != self.len {
+            anyhow::bail!(
+                "Linearized PDF of {} bytes declares a length of {length}",
+                self.len
+            );
+        }
+        if integer("N")? != pages.len() as u64 {
+            anyhow::bail!(
+                "Linearized PDF has {} page(s), not the /N it declares",
+                pages.len()
+            );
+        }
+        if integer("O")? != pages[0] as u64 {
+            anyhow::bail!("Linearization dictionary /O isn't the first page");
+        }
+        let hints = match parameters.get("H") {
+            Some(Object::Array(hints)) => hints,
+            _ => anyhow::bail!("Linearization dictionary has no valid /H"),
+        };
+        let in_file = |value: &Object| matches!(*value, Object::Number(value) if value >= 0.0 && value <= length as f64);
+        if hints.len() < 2
+            || !hints.iter().all(in_file)
+            || integer("E")? > length
+            || integer("T")? >= length
+        {
+            anyhow::bail!("Linearization dictionary points past the end of the file");
+        }
+        Ok(())
+    }
+
+    fn check_media_box(&self, number: u32, media_box: Option<&Object>) -> Result<()> {
+        let corners = match media_box.map(|media_box| self.resolve(media_box)) {
+            Some(Object::Array(corners)) if corners.len() == 4 => corners,
+            _ => anyhow::bail!("Page {number} has no /MediaBox of 4 numbers"),
+        };
+        let mut values = [0.0; 4];
+        for (value, corner) in values.iter_mut().zip(corners) {
+            let &Object::Number(corner) = self.resolve(corner) else {
+                anyhow::bail!("Page {number} has no /MediaBox of 4 numbers");
+            };
+            *value = corner;
+        }
+        let (width, height) = ((values[2] - values[0]).abs(), (values[3] - values[1]).abs());
+        if width == 0.0 || height == 0.0 {
+            anyhow::bail!("Page {number} has an empty /MediaBox of {width}x{height}");
+        }
+        Ok(())
+    }
+}
+
+/// Read the objects of the PDF from `file` through its cross-reference
+/// table, checking that each one is where the table says and that each
+/// stream ends where its length says; returns them with the trailer
+pub(crate) fn read_document<R: Read + Seek>(file: &mut R) -> Result<(Document, Dict)> {
+    let len = file.seek(SeekFrom::End(0))?;
+    let header = read_at(file, 0, 8)?;
+    if !header.starts_with(b"%PDF-") {
+        anyhow::bail!("Missing %PDF- header");
+    }
+
+    let tail_start = len.saturating_sub(TAIL_BYTES);
+    let tail = read_at(file, tail_start, TAIL_BYTES)?;
+    let end = tail
+        .iter()
+        .rposition(|&byte| !is_whitespace(byte))
+        .map_or(0, |last| last + 1);
+    if !tail[..end].ends_with(b"%%EOF") {
+        anyhow::bail!("Missing %%EOF at the end of the file, which may be truncated");
+    }
+    let startxref = tail
+        .windows(9)
+        .rposition(|window| window == b"startxref")
+        .context("Missing startxref")?;
+    let xref_offset: u64 = Parser::new(&tail[startxref + 9..])
+        .integer()
+        .context("Invalid startxref offset")?;
+    if xref_offset >= tail_start + startxref as u64 {
+        anyhow::bail!("startxref offset {xref_offset} is past the cross-reference table");
+    }
+
+    // Linearized PDFs have a first-page table, whose trailer gives the
+    // offset of the main table as /Prev
+    let mut sections = vec![(xref_offset, read_xref_at(file, xref_offset, len)?)];
+    if let Some(prev) = sections[0].1.trailer.get("Prev") {
+        let prev = match *prev {
+            Object::Number(prev) if prev >= 0.0 && prev.fract() == 0.0 && (prev as u64) < len => {
+                prev as u64
+            }
+            _ => anyhow::bail!("Invalid /Prev in the trailer"),
+        };
+        let main = read_xref_at(file, prev, len)?;
+        if main.trailer.contains_key("Prev") || prev == xref_offset {
+            anyhow::bail!("Incrementally updated PDFs aren't supported");
+        }
+        sections.push((prev, main));
+    }
+    let mut entries = BTreeMap::new();
+    let mut listed = HashSet::new();
+    for (_, section) in &sections {
+        if section.listed.iter().any(|number| listed.contains(number)) {
+            anyhow::bail!("Incrementally updated PDFs aren't supported");
+        }
+        listed.extend(&section.listed);
+        entries.extend(&section.entries);
+    }
+    for (_, section) in &sections {
+        check_size(&section.trailer, &listed)?;
+    }
+    let trailer = sections[0].1.trailer.clone();
+    // Objects end before the cross-reference table, unless it is a stream
+    // at the end of the file or the first-page table at its start
+    let objects_end = match sections.as_slice() {
+        [(_, XrefSection { stream: None, .. })] => xref_offset,
+        _ => len,
+    };
+
+    let mut objects = BTreeMap::new();
+    let mut streams = Vec::new();
+    for (&number, entry) in &entries {
+        let &XrefEntry::InFile { offset, generation } = entry else {
+            continue;
+        };
+        let section = sections.iter().find(|(start, _)| *start == offset);
+        if offset >= objects_end && section.is_none()
+            || section.is_some_and(|(_, section)| section.stream != Some(number))
+        {
+            anyhow::bail!("Offset {offset} of object {number} is past the cross-reference table");
+        }
+        let data = read_at(file, offset, MAX_OBJECT_BYTES.min(objects_end - offset))?;
+        let mut parser = Parser::new(&data);
+        let header = (
+            parser.integer::<u32>(),
+            parser.integer::<u16>(),
+            parser.token(),
+        );
+        if header != (Some(number), Some(generation), &b"obj"[..]) {
+            anyhow::bail!(
+                "Object {number} isn't at offset {offset} given by the cross-reference table"
+            );
+        }
+        let object = parser
+            .object(0)
+            .with_context(|| format!("Object {number} is invalid or too large to check"))?;
+        match parser.token() {
+            b"endobj" => {}
+            b"stream" => {
+                // The keyword is followed by CRLF or LF, never by CR alone
+                let eol = match &data[parser.pos..] {
+                    [b'\r', b'\n', ..] => 2,
+                    [b'\n', ..] => 1,
+                    _ => anyhow::bail!("Object {number} has no end of line after `stream`"),
+                };
+                let length = object
+                    .as_dict()
+                    .and_then(|dict| dict.get("Length"))
+                    .with_context(|| format!("Stream of object {number} has no /Length"))?
+                    .clone();
+                streams.push((number, offset + (parser.pos + eol) as u64, length));
+            }
+            _ => anyhow::bail!("Object {number} doesn't end with `endobj`"),
+        }
+        objects.insert(number, object);
+    }
+
+    let mut document = Document {
+        objects,
+        entries,
+        streams: BTreeMap::new(),
+        len,
+    };
+    for (number, start, length) in streams {
+        let length = match document.resolve(&length) {
+            &Object::Number(length) if length >= 0.0 && length.fract() == 0.0 => length as u64,
+            _ => anyhow::bail!("Stream of object {number} has an invalid /Length"),
+        };
+        let end = start.saturating_add(length);
+        let after = if end < objects_end {
+            read_at(file, end, 32)?
+        } else {
+            Vec::new()
+        };
+        let after = after
+            .strip_prefix(b"\r\n")
+            .or_else(|| after.strip_prefix(b"\n"))
+            .or_else(|| after.strip_prefix(b"\r"))
+            .unwrap_or(&after);
+        // `endstream` follows the data right away, or after an end of line
+        let mut parser = Parser::new(after);
+        if !after.starts_with(b"endstream")
+            || parser.token() != b"endstream"
+            || parser.token() != b"endobj"
+        {
+            anyhow::bail!(
+                "Stream of object {number} doesn't end after its /Length of {length} bytes"
+            );
+        }
+        document.streams.insert(number, (start, length));
+    }
+
+    let object_streams: HashSet<u32> = document
+        .entries
+        .values()
+        .filter_map(|entry| match *entry {
+            XrefEntry::Compressed { stream, .. } => Some(stream),
+            XrefEntry::InFile { .. } => None,
+        })
+        .collect();
+    for stream in object_streams {
+        let (start, length) = *document
+            .streams
+            .get(&stream)
+            .with_context(|| format!("Object stream {stream} isn't a stream"))?;
+        if length > MAX_XREF_BYTES {
+            anyhow::bail!("Object stream {stream} is too large to check");
+        }
+        let data = read_at(file, start, length)?;
+        document.read_object_stream(stream, &data)?;
+    }
+    if let Some((&number, _)) = document
+        .entries
+        .iter()
+        .find(|(number, _)| !document.objects.contains_key(number))
+    {
+        anyhow::bail!("Object {number} isn't in the object stream the cross-reference table gives");
+    }
+
+    for (&number, object) in &document.objects {
+        document.check_references(number, object)?;
+    }
+    document.check_references(0, &Object::Dict(trailer.clone()))?;
+    Ok((document, trailer))
+}
+
+/// Check the structure of the PDF read from `file`
+pub(crate) fn validate<R: Read + Seek>(file: &mut R) -> Result<()> {
+    let (document, trailer) = read_document(file)?;
+    let pages = document.pages(&trailer)?;
+    document.check_linearization(&pages)
+}
+
+/// Check that the PDF read from `file` only holds the objects this crate
+/// writes: pages of images, with fonts for their text layer and page numbers,
+/// and an outline
+///
+/// Any dictionary key or name that makes viewers run scripts, open files or
+/// links, submit forms or show annotations fails the check, as do object
+/// types the writer never uses. Objects in object streams are checked like
+/// the others. Stream data isn't read otherwise: content streams can only
+/// draw.
+pub(crate) fn audit<R: Read + Seek>(file: &mut R) -> Result<()> {
+    let (document, trailer) = read_document(file)?;
+    let is_xref_stream = trailer.get("Type") == Some(&Object::Name("XRef".to_string()));
+    let allowed = |key: &str| {
+        TRAILER_KEYS.contains(&key) || (is_xref_stream && XREF_STREAM_KEYS.contains(&key))
+    };
+    if let Some(key) = trailer.keys().find(|key| !allowed(key)) {
+        anyhow::bail!(
+            "Unexpected /{key_sanitized} in the trailer",
+            key_sanitized = replace_control_chars(key, false)
+        );
+    }
+    for (&number, object) in &document.objects {
+        audit_object(number, object)?;
+    }
+    Ok(())
+}
+
+/// Keys of the trailers this crate writes
+const TRAILER_KEYS: &[&str] = &["Size", "Root", "Info", "ID", "Prev"];
+
+/// Keys of cross-reference streams, besides those of the trailer
+const XREF_STREAM_KEYS: &[&str] = &["Type", "W", "Index", "Filter", "Length"];
+
+/// Names of actions, scripts, attached files, forms, annotations and of
+/// objects that hold other objects, none of which the writer uses
+const FORBIDDEN_NAMES: &[&str] = &[
+    "AA",
+    "AcroForm",
+    "Annots",
+    "EmbeddedFile",
+    "EmbeddedFiles",
+    "FileAttachment",
+    "GoToE",
+    "GoToR",
+    "ImportData",
+    "JS",
+    "JavaScript",
+    "Launch",
+    "Names",
+    "OpenAction",
+    "RichMedia",
+    "SubmitForm",
+    "URI",
+    "XFA",
+];
+
+/// Values of /Type and /Subtype in the objects this crate writes
+const ALLOWED_TYPES: &[&str] = &[
+    "Catalog",
+    "Outlines",
+    "Pages",
+    "Page",
+    "XObject",
+    "Font",
+    "FontDescriptor",
+    "ObjStm",
+    "XRef",
+];
+const ALLOWED_SUBTYPES: &[&str] = &["Image", "Type0", "CIDFontType2", "Type1"];
+
+fn audit_object(number: u32, object: &Object) -> Result<()> {
+    match object {
+        Object::Name(name) if FORBIDDEN_NAMES.contains(&name.as_str()) => {
+            anyhow::bail!("Unexpected /{name} in object {number}")
+        }
+        Object::Array(items) => {
+            for item in items {
+                audit_object(number, item)?;
+            }
+        }
+        Object::Dict(dict) => {
+            for (key, value) in dict {
+                if FORBIDDEN_NAMES.contains(&key.as_str()) {
+                    anyhow::bail!("Unexpected /{key} in object {number}");
+                }
+                let allowed = match key.as_str() {
+                    "Type" => ALLOWED_TYPES,
+                    "Subtype" => ALLOWED_SUBTYPES,
+                    _ => &[],
+                };
+                if !allowed.is_empty()
+                    && !matches!(value, Object::Name(name) if allowed.contains(&name.as_str()))
+                {
+                    anyhow::bail!("Unexpected /{key} of object {number}");
+                }
+                audit_object(number, value)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{write_pdf, PageData, PixelFormat};
+    use std::io::Cursor;
+
+    fn sample_pdf() -> Vec<u8> {
+        let mut rotated = PageData::with_format(2, 1, PixelFormat::Gray, vec![0, 255]);
+        rotated.metadata.rotation = 90;
+        let pages = [
+            PageData::new(2, 2, vec![128; 12]),
+            rotated,
+            PageData::with_format(1, 1, PixelFormat::Rgba, vec![1, 2, 3, 4]),
+        ];
+        let mut pdf = Vec::new();
+        write_pdf(&mut pdf, &pages).unwrap();
+        pdf
+    }
+
+    fn check(pdf: &[u8]) -> Result<()> {
+        validate(&mut Cursor::new(pdf))
+    }
+
+    fn find(pdf: &[u8], needle: &[u8]) -> usize {
+        pdf.windows(needle.len())
+            .position(|window| window == needle)
+            .unwrap()
+    }
+
+    /// Replace the first occurrence of `from` by `to`, of the same length,
+    /// so that offsets stay the same
+    fn patched(pdf: &[u8], from: &str, to: &str) -> Vec<u8> {
+        assert_eq!(from.len(), to.len());
+        let start = find(pdf, from.as_bytes());
+        let mut pdf = pdf.to_vec();
+        pdf[start..start + to.len()].copy_from_slice(to.as_bytes());
+        pdf
+    }
+
+    fn error(pdf: &[u8]) -> String {
+        format!("{:#}", check(pdf).unwrap_err())
+    }
+
+    #[test]
+    fn test_written_pdfs_are_valid() {
+        check(&sample_pdf()).unwrap();
+    }
+
+    #[test]
+    fn test_wrong_offsets() {
+        let pdf = sample_pdf();
+        let xref = find(&pdf, b"xref\n");
+        // Shift the offset of object 3 by one byte
+        let entry = xref + "xref\n0 13\n".len() + 3 * 20;
+        let offset: u64 = std::str::from_utf8(&pdf[entry..entry + 10])
+            .unwrap()
+            .parse()
+            .unwrap();
+        let pdf = patched(
+            &pdf,
+            &format!("{offset:010} 00000 n"),
+            &format!("{:010} 00000 n", offset + 1),
+        );
+        assert!(error(&pdf).contains("Object 3 isn't at offset"));
+
+        let pdf = sample_pdf();
+        let startxref = find(&pdf, b"startxref\n");
+        let mut truncated = pdf[..startxref].to_vec();
+        truncated.extend_from_slice(b"startxref\n1\n%%EOF\n");
+        assert!(error(&truncated).contains("No cross-reference table"));
+    }
+
+    #[test]
+    fn test_wrong_stream_length() {
+        let pdf = patched(&sample_pdf(), "/Length 33\n", "/Length 32\n");
+        assert!(error(&pdf).contains("doesn't end after its /Length of 32 bytes"));
+    }
+
+    #[test]
+    fn test_wrong_page_tree() {
+        let pdf = patched(&sample_pdf(), "/Count 3", "/Count 4");
+        assert!(error(&pdf).contains("has 3 page(s), not the /Count"));
+
+        let pdf = patched(&sample_pdf(), "/MediaBox [0 0 0.96", "/MediaBox [0 0 0.00");
+        assert!(error(&pdf).contains("empty /MediaBox"));
+
+        let pdf = patched(&sample_pdf(), "/Contents 4 0 R", "/Contents 9 9 R");
+        assert!(error(&pdf).contains("refers to missing object 9 9 R"));
+    }
+
+    #[test]
+    fn test_truncated_pdf() {
+        let pdf = sample_pdf();
+        assert!(error(&pdf[..pdf.len() / 2]).contains("Missing %%EOF"));
+        assert!(error(b"").contains("Missing %PDF- header"));
+    }
+
+    #[test]
+    fn test_object_streams() {
+        use crate::pdf::{EncodedPage, PdfWriter, WriterSettings};
+        use crate::{CompressionConfig, DPI};
+        let mut pdf = PdfWriter::new(
+            Vec::new(),
+            WriterSettings {
+                object_streams: true,
+                ..WriterSettings::default()
+            },
+        )
+        .unwrap();
+        let page = PageData::new(2, 2, vec![128; 12]);
+        pdf.add_page(&EncodedPage::new(&page, DPI, &CompressionConfig::default()).unwrap())
+            .unwrap();
+        let pdf = pdf.finish().unwrap();
+        check(&pdf).unwrap();
+        audit(&mut Cursor::new(&pdf)).unwrap();
+
+        let patched_pdf = patched(&pdf, "/Type /ObjStm", "/Type /Foobar");
+        assert!(error(&patched_pdf).contains("isn't an object stream"));
+        assert!(error(&pdf[..pdf.len() - 40]).contains("Missing %%EOF"));
+    }
+
+    #[test]
+    fn test_audit() {
+        audit(&mut Cursor::new(sample_pdf())).unwrap();
+        let audit_error = |pdf: &[u8]| format!("{:#}", audit(&mut Cursor::new(pdf)).unwrap_err());
+
+        let pdf = patched(&sample_pdf(), "/Type /Catalog", "/JS (alert(1))");
+        assert_eq!(audit_error(&pdf), "Unexpected /JS in object 1");
+
+        let pdf = patched(&sample_pdf(), "/Subtype /Image", "/Subtype /Movie");
+        assert!(audit_error(&pdf).contains("Unexpected /Subtype of object"));
+
+        let pdf = patched(&sample_pdf(), "/Root 1 0 R", "/Dest 1 0 R");
+        assert_eq!(audit_error(&pdf), "Unexpected /Dest in the trailer");
+
+        let pdf = patched(
+            &sample_pdf(),
+            "/Filter /FlateDecode",
+            "/S       /JavaScript",
+        );
+        assert!(audit_error(&pdf).contains("Unexpected /JavaScript in object"));
+    }
+}
@@ -0,0 +1,381 @@
+//! OCR with Apple's Vision framework on macOS
+//!
+//! Pages are passed to `VNRecognizeTextRequest` as images while the safe PDF
+//! is written. Vision finds lines of text, each of which becomes a block of
+//! one paragraph and one line; the boxes of their words are asked for
+//! separately, and estimated from the position of the word in its line when
+//! Vision doesn't give one.
+
+// Only the engine itself is limited to macOS and the `ocr` feature, so that
+// the conversion of its results is tested everywhere
+#![cfg_attr(not(all(target_os = "macos", feature = "ocr")), allow(dead_code))]
+
+use crate::hocr::BBox;
+use std::ops::Range;
+
+/// Vision's recognition languages, for tesseract's language codes
+const LANGUAGES: &[(&str, &str)] = &[
+    ("eng", "en-US"),
+    ("fra", "fr-FR"),
+    ("deu", "de-DE"),
+    ("spa", "es-ES"),
+    ("ita", "it-IT"),
+    ("por", "pt-BR"),
+    ("chi_sim", "zh-Hans"),
+    ("chi_tra", "zh-Hant"),
+    ("jpn", "ja-JP"),
+    ("kor", "ko-KR"),
+    ("rus", "ru-RU"),
+    ("ukr", "uk-UA"),
+];
+
+/// Vision's languages for `lang`, tesseract's codes joined by `+` such as
+/// `eng+deu`, or the first code without an equivalent
+fn vision_languages(lang: &str) -> Result<Vec<&'static str>, &str> {
+    lang.split('+')
+        .map(|code| {
+            LANGUAGES
+                .iter()
+                .find(|(tesseract, _)| *tesseract == code)
+                .map(|(_, vision)| *vision)
+                .ok_or(code)
+        })
+        .collect()
+}
+
+/// Tesseract's language code for Vision's `locale`, if there is one
+pub(crate) fn tesseract_code(locale: &str) -> Option<&'static str> {
+    LANGUAGES
+        .iter()
+        .find(|(_, vision)| *vision == locale)
+        .map(|(tesseract, _)| *tesseract)
+}
+
+/// Words of `text` with their ranges, in UTF-16 code units as Vision counts
+/// them
+fn word_ranges(text: &str) -> Vec<(&str, Range<usize>)> {
+    let mut words = Vec::new();
+    let mut word_start = None;
+    let mut offset = 0;
+    for (index, c) in text.char_indices() {
+        match (c.is_whitespace(), word_start) {
+            (false, None) => word_start = Some((index, offset)),
+            (true, Some((start, start_offset))) => {
+                words.push((&text[start..index], start_offset..offset));
+                word_start = None;
+            }
+            _ => {}
+        }
+        offset += c.len_utf16();
+    }
+    if let Some((start, start_offset)) = word_start {
+        words.push((&text[start..], start_offset..offset));
+    }
+    words
+}
+
+/// Box in pixels of a page of `width` by `height` pixels, from a rectangle
+/// `[x, y, width, height]` normalized to the page with its origin at the
+/// bottom left
+fn pixel_bbox(rect: [f64; 4], width: u16, height: u16) -> BBox {
+    let [x, y, rect_width, rect_height] = rect;
+    let to_pixels =
+        |fraction: f64, size: u16| (fraction.clamp(0.0, 1.0) * f64::from(size)).round() as u32;
+    [
+        to_pixels(x, width),
+        to_pixels(1.0 - (y + rect_height), height),
+        to_pixels(x + rect_width, width),
+        to_pixels(1.0 - y, height),
+    ]
+}
+
+/// Box of the code units `range` of a line of `len` code units, if
+/// characters had the same width
+fn proportional_bbox(line: BBox, range: &Range<usize>, len: usize) -> BBox {
+    let [left, top, right, bottom] = line;
+    let at = |offset: usize| left + ((right - left) as usize * offset / len.max(1)) as u32;
+    [at(range.start), top, at(range.end), bottom]
+}
+
+#[cfg(all(target_os = "macos", feature = "ocr"))]
+pub(crate) use engine::supported_languages;
+#[cfg(all(target_os = "macos", feature = "ocr"))]
+pub use engine::Vision;
+
+#[cfg(all(target_os = "macos", feature = "ocr"))]
+mod engine {
+    use super::{pixel_bbox, proportional_bbox, vision_languages, word_ranges};
+    use crate::hocr::{BBox, OcrBlock, OcrLine, OcrPage, OcrParagraph, OcrWord};
+    use crate::ocr::OcrEngine;
+    use crate::{replace_control_chars, PageData, PixelFormat};
+    use anyhow::{Context, Result};
+    use log::{debug, warn};
+    use objc2::rc::{autoreleasepool, Retained};
+    use objc2::{msg_send, AllocAnyThread};
+    use objc2_core_foundation::{CFData, CFRetained, CGRect};
+    use objc2_core_graphics::{
+        CGBitmapInfo, CGColorRenderingIntent, CGColorSpace, CGDataProvider, CGImage,
+        CGImageAlphaInfo,
+    };
+    use objc2_foundation::{NSArray, NSDictionary, NSError, NSRange, NSString};
+    use objc2_vision::{
+        VNImageRequestHandler, VNRecognizeTextRequest, VNRecognizedText,
+        VNRecognizedTextObservation, VNRectangleObservation, VNRequest,
+        VNRequestTextRecognitionLevel,
+    };
+
+    /// Apple's [Vision](https://developer.apple.com/documentation/vision)
+    /// framework, reading the pixels of pages
+    #[derive(Clone, Debug)]
+    pub struct Vision {
+        /// Recognition languages, or None to let Vision detect them
+        languages: Option<Vec<&'static str>>,
+    }
+
+    impl Vision {
+        /// Recognize the languages of `lang`, e.g. `eng` or `eng+deu`, or
+        /// detect them if one of them is unknown to Vision
+        pub fn new(lang: &str) -> Self {
+            let languages = vision_languages(lang)
+                .inspect_err(|code| {
+                    warn!(
+                        "Vision has no language for '{code_sanitized}', detecting the \
+                         languages of the document instead",
+                        code_sanitized = replace_control_chars(code, false)
+                    )
+                })
+                .ok();
+            Vision { languages }
+        }
+
+        fn recognize(&self, page: &PageData) -> Result<OcrPage> {
+            let image = cg_image(page)?;
+            let request = VNRecognizeTextRequest::new();
+            request.setRecognitionLevel(VNRequestTextRecognitionLevel::Accurate);
+            request.setUsesLanguageCorrection(true);
+            match &self.languages {
+                Some(languages) => {
+                    let languages: Vec<Retained<NSString>> = languages
+                        .iter()
+                        .map(|lang| NSString::from_str(lang))
+                        .collect();
+                    request.setRecognitionLanguages(&NSArray::from_retained_slice(&languages));
+                }
+                None => request.setAutomaticallyDetectsLanguage(true),
+            }
+
+            // SAFETY: the options are empty
+            let handler = unsafe {
+                VNImageRequestHandler::initWithCGImage_options(
+                    VNImageRequestHandler::alloc(),
+                    &image,
+                    &NSDictionary::new(),
+                )
+            };
+            let requests: &VNRequest = &request;
+            handler
+                .performRequests_error(&NSArray::from_slice(&[requests]))
+                .map_err(|e| {
+                    anyhow::anyhow!(
+                        "Vision failed to recognize the page: {e_sanitized}",
+                        e_sanitized =
+                            replace_control_chars(&e.localizedDescription().to_string(), true)
+                    )
+                })?;
+
+            let blocks: Vec<OcrBlock> = request
+                .results()
+                .map(|observations| {
+                    observations
+                        .iter()
+                        .filter_map(|observation| line(&observation, page.width, page.height))
+                        .collect()
+                })
+                .unwrap_or_default();
+            debug!("Vision found {} lines of text", blocks.len());
+            Ok(OcrPage {
+                bbox: [0, 0, page.width as u32, page.height as u32],
+                blocks,
+            })
+        }
+    }
+
+    impl OcrEngine for Vision {
+        fn name(&self) -> &'static str {
+            "macOS Vision"
+        }
+
+        fn supports_sidecars(&self) -> bool {
+            true
+        }
+
+        fn reads_pixels(&self) -> bool {
+            true
+        }
+
+        fn recognize_page(&self, page: &PageData, _ppi: f32) -> Result<OcrPage> {
+            // Pages are recognized on threads without a pool of their own
+            autoreleasepool(|_| self.recognize(page))
+        }
+    }
+
+    /// The languages Vision recognizes, e.g. `en-US`
+    pub(crate) fn supported_languages() -> Result<Vec<String>> {
+        autoreleasepool(|_| {
+            let request = VNRecognizeTextRequest::new();
+            request.setRecognitionLevel(VNRequestTextRecognitionLevel::Accurate);
+            // SAFETY: the request is configured, and isn't performed
+            let languages = unsafe { request.supportedRecognitionLanguagesAndReturnError() }
+                .map_err(|e| {
+                    anyhow::anyhow!(
+                        "Failed to list Vision's languages: {e_sanitized}",
+                        e_sanitized =
+                            replace_control_chars(&e.localizedDescription().to_string(), true)
+                    )
+                })?;
+            Ok(languages.iter().map(|lang| lang.to_string()).collect())
+        })
+    }
+
+    /// The image of `page` for Vision, which copies its pixels
+    fn cg_image(page: &PageData) -> Result<CFRetained<CGImage>> {
+        let (color_space, alpha, bytes_per_pixel) = match page.format {
+            PixelFormat::Gray => (CGColorSpace::new_device_gray(), CGImageAlphaInfo::None, 1),
+            PixelFormat::Rgb => (CGColorSpace::new_device_rgb(), CGImageAlphaInfo::None, 3),
+            PixelFormat::Rgba => (CGColorSpace::new_device_rgb(), CGImageAlphaInfo::Last, 4),
+        };
+        let data = CFData::from_bytes(&page.pixels);
+        let provider = CGDataProvider::with_cf_data(Some(&data))
+            .context("Failed to pass the pixels of the page to Vision")?;
+        let width = page.width as usize;
+        // SAFETY: the provider holds `height` rows of `width` pixels of
+        // `bytes_per_pixel` bytes, and there is no decode array
+        unsafe {
+            CGImage::new(
+                width,
+                page.height as usize,
+                8,
+                8 * bytes_per_pixel,
+                width * bytes_per_pixel,
+                color_space.as_deref(),
+                CGBitmapInfo(alpha.0),
+                Some(&provider),
+                std::ptr::null(),
+                false,
+                CGColorRenderingIntent::RenderingIntentDefault,
+            )
+        }
+        .context("Failed to create the image of the page for Vision")
+    }
+
+    fn rect(rect: CGRect) -> [f64; 4] {
+        [
+            rect.origin.x,
+            rect.origin.y,
+            rect.size.width,
+            rect.size.height,
+        ]
+    }
+
+    /// The block of the line of text of `observation`, on a page of `width`
+    /// by `height` pixels
+    fn line(
+        observation: &VNRecognizedTextObservation,
+        width: u16,
+        height: u16,
+    ) -> Option<OcrBlock> {
+        let candidate = observation.topCandidates(1).firstObject()?;
+        let text = candidate.string().to_string();
+        let confidence = Some(candidate.confidence() * 100.0);
+        // SAFETY: the observation comes from a finished request
+        let line_bbox = pixel_bbox(rect(unsafe { observation.boundingBox() }), width, height);
+        let len = text.encode_utf16().count();
+        let words = word_ranges(&text)
+            .into_iter()
+            .map(|(word, range)| OcrWord {
+                bbox: word_bbox(&candidate, &range, width, height)
+                    .unwrap_or_else(|| proportional_bbox(line_bbox, &range, len)),
+                confidence,
+                text: word.to_string(),
+            })
+            .collect();
+        Some(OcrBlock {
+            bbox: line_bbox,
+            paragraphs: vec![OcrParagraph {
+                bbox: line_bbox,
+                lang: None,
+                lines: vec![OcrLine {
+                    bbox: line_bbox,
+                    baseline: None,
+                    words,
+                }],
+            }],
+        })
+    }
+
+    /// The box Vision gives to the code units `range` of `candidate`
+    fn word_bbox(
+        candidate: &VNRecognizedText,
+        range: &std::ops::Range<usize>,
+        width: u16,
+        height: u16,
+    ) -> Option<BBox> {
+        let range = NSRange::new(range.start, range.len());
+        // SAFETY: `boundingBoxForRange:error:` takes a range of the string
+        // of the candidate, and returns an observation or an error
+        let observation: Result<Retained<VNRectangleObservation>, Retained<NSError>> =
+            unsafe { msg_send![candidate, boundingBoxForRange: range, error: _] };
+        // SAFETY: the observation was just returned by Vision
+        let bbox = unsafe { observation.ok()?.boundingBox() };
+        Some(pixel_bbox(rect(bbox), width, height))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vision_languages() {
+        assert_eq!(vision_languages("eng"), Ok(vec!["en-US"]));
+        assert_eq!(
+            vision_languages("deu+chi_sim"),
+            Ok(vec!["de-DE", "zh-Hans"])
+        );
+        assert_eq!(vision_languages("eng+xyz+abc"), Err("xyz"));
+        assert_eq!(tesseract_code("zh-Hant"), Some("chi_tra"));
+        assert_eq!(tesseract_code("ar-SA"), None);
+    }
+
+    #[test]
+    fn test_word_ranges() {
+        assert_eq!(
+            word_ranges("  é😀 ab\tc "),
+            [("é😀", 2..5), ("ab", 6..8), ("c", 9..10)]
+        );
+        assert!(word_ranges(" \n").is_empty());
+    }
+
+    #[test]
+    fn test_pixel_bbox() {
+        // The top left quarter of the page
+        assert_eq!(pixel_bbox([0.0, 0.5, 0.5, 0.5], 200, 100), [0, 0, 100, 50]);
+        // Rounded, and clamped to the page
+        assert_eq!(
+            pixel_bbox([0.101, -0.1, 1.2, 0.3], 200, 100),
+            [20, 80, 200, 100]
+        );
+    }
+
+    #[test]
+    fn test_proportional_bbox() {
+        assert_eq!(
+            proportional_bbox([10, 5, 110, 25], &(2..5), 10),
+            [30, 5, 60, 25]
+        );
+        assert_eq!(
+            proportional_bbox([10, 5, 110, 25], &(0..0), 0),
+            [10, 5, 10, 25]
+        );
+    }
+}
@@ -0,0 +1,159 @@
+//! Virtual machines hosting the container runtime on macOS and Windows
+//!
+//! Containers can't run natively there: podman runs them in a "podman
+//! machine" VM, docker is often provided by colima, a Lima VM, and Apple's
+//! `container` CLI needs its system service. A stopped VM makes every
+//! conversion fail, so it is detected before spawning the container.
+
+use crate::Runtime;
+use anyhow::{Context, Result};
+use std::process::{Command, Stdio};
+
+/// Virtual machine hosting a container runtime
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Vm {
+    /// The default podman machine, managed with `podman machine`
+    PodmanMachine,
+    /// A colima (Lima-based) VM providing docker
+    Colima,
+    /// The services of Apple's `container` CLI, which start each container's
+    /// own VM
+    AppleContainerSystem,
+}
+
+impl Vm {
+    /// VM that `runtime` needs on this platform, if any
+    pub fn required_by(runtime: Runtime) -> Option<Vm> {
+        if !cfg!(any(target_os = "macos", target_os = "windows")) {
+            return None;
+        }
+        match runtime {
+            Runtime::Podman | Runtime::Gvisor => Some(Vm::PodmanMachine),
+            Runtime::Docker if cfg!(target_os = "macos") && command_exists("colima") => {
+                Some(Vm::Colima)
+            }
+            Runtime::AppleContainer if cfg!(target_os = "macos") => Some(Vm::AppleContainerSystem),
+            Runtime::Docker | Runtime::Bwrap | Runtime::AppleContainer => None,
+        }
+    }
+
+    /// Command starting the VM, as shown to users
+    pub fn start_command(&self) -> &'static str {
+        match self {
+            Vm::PodmanMachine => "podman machine start",
+            Vm::Colima => "colima start",
+            Vm::AppleContainerSystem => "container system start",
+        }
+    }
+
+    pub fn is_running(&self) -> Result<bool> {
+        match self {
+            Vm::PodmanMachine => {
+                let output = Command::new("podman")
+                    .args(["machine", "inspect", "--format", "{{.State}}"])
+                    .stderr(Stdio::null())
+                    .output()
+                    .context("Failed to run podman. Make sure podman is installed.")?;
+                if !output.status.success() {
+                    anyhow::bail!(
+                        "No podman machine found. Create one with `podman machine init`, then start it with `podman machine start`."
+                    );
+                }
+                Ok(is_running_state(&String::from_utf8_lossy(&output.stdout)))
+            }
+            Vm::Colima => exits_successfully("colima", &["status"]),
+            Vm::AppleContainerSystem => exits_successfully("container", &["system", "status"]),
+        }
+    }
+
+    /// Start the VM and wait until it is up
+    pub fn start(&self) -> Result<()> {
+        log::info!("Starting the virtual machine ({})...", self.start_command());
+        let (program, args) = match self {
+            Vm::PodmanMachine => ("podman", ["machine", "start"].as_slice()),
+            Vm::Colima => ("colima", ["start"].as_slice()),
+            Vm::AppleContainerSystem => ("container", ["system", "start"].as_slice()),
+        };
+        let status = Command::new(program)
+            .args(args)
+            .stdout(Stdio::null())
+            .status()
+            .with_context(|| format!("Failed to run `{}`", self.start_command()))?;
+        if !status.success() {
+            anyhow::bail!("`{}` failed with status: {status}", self.start_command());
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Vm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Vm::PodmanMachine => write!(f, "podman machine"),
+            Vm::Colima => write!(f, "colima VM"),
+            Vm::AppleContainerSystem => write!(f, "container system service"),
+        }
+    }
+}
+
+/// Make sure the VM `runtime` needs is running, starting it if `auto_start`
+/// is set
+pub(crate) fn ensure_running(runtime: Runtime, auto_start: bool) -> Result<()> {
+    let Some(vm) = Vm::required_by(runtime) else {
+        return Ok(());
+    };
+    if vm.is_running()? {
+        return Ok(());
+    }
+    if !auto_start {
+        anyhow::bail!(
+            "The {vm} is not running. Start it with `{}`, or pass --auto-start-vm.",
+            vm.start_command()
+        );
+    }
+    vm.start()
+}
+
+fn exits_successfully(program: &str, args: &[&str]) -> Result<bool> {
+    Ok(Command::new(program)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .with_context(|| format!("Failed to run {program}"))?
+        .success())
+}
+
+fn command_exists(program: &str) -> bool {
+    std::env::var_os("PATH").is_some_and(|paths| crate::find_executable(program, &paths).is_some())
+}
+
+/// Whether `podman machine inspect` reported a running machine. With several
+/// machines, one line is printed per machine.
+fn is_running_state(output: &str) -> bool {
+    output
+        .lines()
+        .any(|state| state.trim().eq_ignore_ascii_case("running"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_podman_machine_state() {
+        assert!(is_running_state("running\n"));
+        assert!(is_running_state("stopped\nrunning\n"));
+        assert!(!is_running_state("stopped\n"));
+        assert!(!is_running_state("starting\n"));
+        assert!(!is_running_state(""));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_no_vm_on_linux() {
+        assert_eq!(Vm::required_by(Runtime::Podman), None);
+        assert_eq!(Vm::required_by(Runtime::Docker), None);
+        assert!(ensure_running(Runtime::Podman, false).is_ok());
+    }
+}
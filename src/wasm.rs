@@ -0,0 +1,18 @@
+//! WebAssembly bindings for assembling safe PDFs off-host
+//!
+//! Only the pixel stream parser and the PDF writer are available here: the
+//! untrusted document must have been converted to pixels elsewhere, e.g. by
+//! the conversion container running on another host.
+
+use crate::{parse_pixel_data, write_pdf};
+use wasm_bindgen::prelude::*;
+
+/// Build a safe PDF from a pixel stream in the format emitted by the
+/// conversion container
+#[wasm_bindgen(js_name = pixelsToPdf)]
+pub fn pdf_from_pixel_stream(data: Vec<u8>) -> Result<Vec<u8>, JsError> {
+    let pages = parse_pixel_data(data).map_err(|e| JsError::new(&format!("{e:#}")))?;
+    let mut pdf = Vec::new();
+    write_pdf(&mut pdf, &pages).map_err(|e| JsError::new(&format!("{e:#}")))?;
+    Ok(pdf)
+}
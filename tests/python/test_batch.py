@@ -0,0 +1,59 @@
+"""Tests for the batch conversion API of the dangerzone_rs Python bindings
+
+Build the extension first (`maturin develop --features python`), then run:
+
+    python -m unittest discover tests/python
+"""
+
+import pathlib
+import tempfile
+import unittest
+
+import dangerzone_rs as dz
+
+
+class BatchTest(unittest.TestCase):
+    def test_failures_are_reported_per_document(self):
+        inputs = [pathlib.Path(f"/nonexistent/input-{i}.pdf") for i in range(3)]
+        with tempfile.TemporaryDirectory() as output_dir:
+            results = dz.convert_batch(inputs, output_dir, 2, dpi=100)
+
+        self.assertEqual([r.input_path for r in results], [str(p) for p in inputs])
+        for i, result in enumerate(results):
+            self.assertFalse(result.success)
+            self.assertIsInstance(result.error, str)
+            self.assertGreaterEqual(result.duration, 0.0)
+            self.assertTrue(result.output_path.endswith(f"input-{i}-safe.pdf"))
+
+    def test_invalid_jobs_are_rejected(self):
+        with self.assertRaises(ValueError):
+            dz.convert_batch([], "out", 0)
+
+    def test_session_mode_reports_failures_per_document(self):
+        inputs = [f"/nonexistent/input-{i}.pdf" for i in range(3)]
+        with tempfile.TemporaryDirectory() as output_dir:
+            results = dz.convert_batch(inputs, output_dir, 1, session_max_documents=2)
+
+        self.assertEqual([r.success for r in results], [False] * 3)
+        for result in results:
+            self.assertIn("Failed to read input file", str(result.error))
+
+    def test_invalid_session_max_documents_is_rejected(self):
+        with self.assertRaises(ValueError):
+            dz.convert_batch([], "out", session_max_documents=0)
+
+    def test_output_template_names_outputs(self):
+        inputs = [f"/nonexistent/input-{i}.pdf" for i in range(2)]
+        with tempfile.TemporaryDirectory() as output_dir:
+            results = dz.convert_batch(
+                inputs, output_dir, 1, output_template="{counter}-{stem}.pdf"
+            )
+
+        self.assertEqual(
+            [pathlib.Path(r.output_path).name for r in results],
+            ["1-input-0.pdf", "2-input-1.pdf"],
+        )
+
+    def test_invalid_output_template_is_rejected(self):
+        with self.assertRaises(ValueError):
+            dz.convert_batch([], "out", output_template="{name}.pdf")
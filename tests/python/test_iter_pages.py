@@ -0,0 +1,22 @@
+"""Tests for the streaming page iterator of the dangerzone_rs Python bindings
+
+Build the extension first (`maturin develop --features python`), then run:
+
+    python -m unittest discover tests/python
+"""
+
+import pathlib
+import unittest
+
+import dangerzone_rs as dz
+
+
+class IterPagesTest(unittest.TestCase):
+    def test_missing_document_raises(self):
+        with self.assertRaises(RuntimeError):
+            for _ in dz.iter_pages(pathlib.Path("/nonexistent/input.pdf")):
+                pass
+
+    def test_unknown_runtime_is_rejected(self):
+        with self.assertRaises(ValueError):
+            dz.iter_pages("in.pdf", runtime="lxc")
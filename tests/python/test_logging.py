@@ -0,0 +1,29 @@
+"""Tests for the forwarding of Rust log records to Python's logging module
+
+Build the extension first (`maturin develop --features python`), then run:
+
+    python -m unittest discover tests/python
+"""
+
+import logging
+import struct
+import unittest
+
+import dangerzone_rs as dz
+
+
+class LoggingTest(unittest.TestCase):
+    def test_records_reach_python_logging(self):
+        data = struct.pack(">HHH", 1, 1, 1) + bytes(3)
+        with self.assertLogs("dangerzone_rs", level="DEBUG") as logs:
+            dz.parse_pixel_data(data)
+        self.assertIn("DEBUG:dangerzone_rs:Document has 1 page(s)", logs.output)
+
+    def test_level_changes_apply_immediately(self):
+        data = struct.pack(">HHH", 1, 1, 1) + bytes(3)
+        logger = logging.getLogger("dangerzone_rs")
+        with self.assertLogs("dangerzone_rs", level="DEBUG") as logs:
+            logger.setLevel(logging.INFO)
+            dz.parse_pixel_data(data)
+            logging.getLogger("dangerzone_rs").info("marker")
+        self.assertEqual(logs.output, ["INFO:dangerzone_rs:marker"])
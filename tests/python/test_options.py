@@ -0,0 +1,36 @@
+"""Tests for the keyword-argument options of the dangerzone_rs Python bindings
+
+Build the extension first (`maturin develop --features python`), then run:
+
+    python -m unittest discover tests/python
+"""
+
+import pathlib
+import unittest
+
+import dangerzone_rs as dz
+
+
+class OptionsTest(unittest.TestCase):
+    def test_pathlike_paths_are_accepted(self):
+        with self.assertRaises(RuntimeError):
+            dz.convert_document(
+                pathlib.Path("/nonexistent/input.pdf"),
+                pathlib.Path("/nonexistent/output.pdf"),
+                ocr=True,
+                ocr_lang="deu",
+            )
+
+    def test_unknown_runtime_is_rejected(self):
+        with self.assertRaises(ValueError):
+            dz.convert_document("in.pdf", "out.pdf", runtime="lxc")
+
+    def test_options_are_keyword_only(self):
+        with self.assertRaises(TypeError):
+            dz.convert_document("in.pdf", "out.pdf", False, "deu")  # type: ignore[misc]
+
+    def test_warmup_validates_options(self):
+        with self.assertRaises(ValueError):
+            dz.warmup(runtime="lxc")
+        with self.assertRaises(TypeError):
+            dz.warmup(60)  # type: ignore[misc]
@@ -0,0 +1,79 @@
+"""Tests for the PageData Python wrapper
+
+Build the extension first (`maturin develop --features python`), then run:
+
+    python -m unittest discover tests/python
+"""
+
+import unittest
+
+import dangerzone_rs as dz
+
+
+class PageDataTest(unittest.TestCase):
+    def setUp(self):
+        # 2x1 page: one red pixel, one blue pixel
+        self.page = dz.PageData(2, 1, b"\xff\x00\x00\x00\x00\xff")
+
+    def test_pixels_are_bytes(self):
+        self.assertEqual(self.page.width, 2)
+        self.assertEqual(self.page.height, 1)
+        self.assertEqual(self.page.pixels, b"\xff\x00\x00\x00\x00\xff")
+
+    def test_buffer_protocol(self):
+        view = memoryview(self.page)
+        self.assertTrue(view.readonly)
+        self.assertEqual(view.format, "B")
+        self.assertEqual(view.shape, (1, 2, 3))
+        self.assertEqual(view.strides, (6, 3, 1))
+        self.assertEqual(view.tobytes(), self.page.pixels)
+
+    def test_grayscale_and_rgba(self):
+        self.assertEqual(self.page.mode, "RGB")
+
+        gray = dz.PageData(2, 1, b"\x00\xff", mode="L")
+        self.assertEqual(gray.mode, "L")
+        self.assertEqual(memoryview(gray).shape, (1, 2, 1))
+
+        rgba = dz.PageData(1, 1, b"\x01\x02\x03\x04", mode="RGBA")
+        self.assertEqual(memoryview(rgba).shape, (1, 1, 4))
+        self.assertEqual(memoryview(rgba).strides, (4, 4, 1))
+
+    def test_metadata_defaults(self):
+        self.assertEqual(self.page.rotation, 0)
+        self.assertIsNone(self.page.size_pts)
+        self.assertFalse(self.page.blank)
+        self.assertFalse(self.page.failed)
+
+    def test_unsupported_mode(self):
+        with self.assertRaises(ValueError):
+            dz.PageData(1, 1, b"\x00", mode="CMYK")  # type: ignore[arg-type]
+
+    def test_buffer_rejects_inconsistent_size(self):
+        page = dz.PageData(2, 2, b"\x00" * 3)
+        with self.assertRaises(BufferError):
+            memoryview(page)
+
+    def test_numpy_view(self):
+        try:
+            import numpy
+        except ImportError:
+            self.skipTest("numpy is not installed")
+
+        array = numpy.asarray(self.page)
+        self.assertEqual(array.shape, (1, 2, 3))
+        self.assertEqual(array[0, 1].tolist(), [0, 0, 255])
+
+    def test_to_pil(self):
+        try:
+            import PIL  # noqa: F401
+        except ImportError:
+            self.skipTest("Pillow is not installed")
+
+        image = self.page.to_pil()
+        self.assertEqual(image.size, (2, 1))
+        self.assertEqual(image.getpixel((0, 0)), (255, 0, 0))
+
+
+if __name__ == "__main__":
+    unittest.main()
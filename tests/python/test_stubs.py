@@ -0,0 +1,90 @@
+"""Check that dangerzone_rs.pyi matches the signatures of the built module
+
+Build the extension first (`maturin develop --features python`), then run:
+
+    python -m unittest discover tests/python
+"""
+
+import ast
+import inspect
+import pathlib
+import unittest
+
+import dangerzone_rs as dz
+
+STUB_PATH = pathlib.Path(__file__).parents[2] / "dangerzone_rs.pyi"
+
+
+def stub_parameters(node):
+    """(name, kind, has default) of a stub function's parameters"""
+    args = node.args
+    params = [(a.arg, "positional") for a in args.posonlyargs + args.args]
+    params += [(a.arg, "keyword") for a in args.kwonlyargs]
+    defaults = [False] * (len(args.posonlyargs) + len(args.args) - len(args.defaults))
+    defaults += [True] * len(args.defaults)
+    defaults += [d is not None for d in args.kw_defaults]
+    return [(name, kind, default) for (name, kind), default in zip(params, defaults)]
+
+
+def runtime_parameters(func):
+    params = []
+    for p in inspect.signature(func).parameters.values():
+        kind = "keyword" if p.kind == p.KEYWORD_ONLY else "positional"
+        params.append((p.name, kind, p.default is not p.empty))
+    return params
+
+
+def public(names):
+    return {name for name in names if not name.startswith("_")}
+
+
+class StubTest(unittest.TestCase):
+    stub: ast.Module
+
+    @classmethod
+    def setUpClass(cls):
+        cls.stub = ast.parse(STUB_PATH.read_text())
+
+    def test_functions_match(self):
+        functions = {
+            node.name: node
+            for node in self.stub.body
+            if isinstance(node, ast.FunctionDef)
+        }
+        exported = {
+            name
+            for name, obj in vars(dz).items()
+            if inspect.isbuiltin(obj) and not name.startswith("_")
+        }
+        self.assertEqual(set(functions), exported)
+        for name, node in functions.items():
+            with self.subTest(function=name):
+                self.assertEqual(
+                    stub_parameters(node), runtime_parameters(getattr(dz, name))
+                )
+
+    def test_classes_match(self):
+        classes = [node for node in self.stub.body if isinstance(node, ast.ClassDef)]
+        exported = {
+            name
+            for name, obj in vars(dz).items()
+            if inspect.isclass(obj) and not name.startswith("_")
+        }
+        self.assertEqual({node.name for node in classes}, exported)
+        for node in classes:
+            cls = getattr(dz, node.name)
+            with self.subTest(cls=node.name):
+                members = [m.name for m in node.body if isinstance(m, ast.FunctionDef)]
+                self.assertEqual(public(members), public(dir(cls)))
+            for member in node.body:
+                if not isinstance(member, ast.FunctionDef):
+                    continue
+                with self.subTest(member=f"{node.name}.{member.name}"):
+                    if member.name == "__init__":
+                        runtime = runtime_parameters(cls)
+                    elif member.decorator_list:
+                        self.assertTrue(hasattr(cls, member.name))
+                        continue
+                    else:
+                        runtime = runtime_parameters(getattr(cls, member.name))[1:]
+                    self.assertEqual(stub_parameters(member)[1:], runtime)
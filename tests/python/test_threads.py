@@ -0,0 +1,72 @@
+"""Concurrency tests for the dangerzone_rs Python bindings
+
+Build the extension first (`maturin develop --features python`), then run:
+
+    python -m unittest discover tests/python
+"""
+
+import os
+import struct
+import tempfile
+import threading
+import time
+import unittest
+
+import dangerzone_rs as dz
+
+
+def pixel_stream(page_count, width, height):
+    """Build a pixel stream in the format emitted by the container"""
+    data = struct.pack(">H", page_count)
+    for _ in range(page_count):
+        data += struct.pack(">HH", width, height) + bytes(width * height * 3)
+    return data
+
+
+class ConcurrencyTest(unittest.TestCase):
+    def test_concurrent_conversions_from_threads(self):
+        errors: list[RuntimeError] = []
+
+        def convert(i):
+            try:
+                dz.convert_document(
+                    f"/nonexistent/input-{i}.pdf", f"/nonexistent/output-{i}.pdf", False
+                )
+            except RuntimeError as e:
+                errors.append(e)
+
+        threads = [threading.Thread(target=convert, args=(i,)) for i in range(4)]
+        for thread in threads:
+            thread.start()
+        for thread in threads:
+            thread.join(timeout=60)
+
+        self.assertFalse(any(thread.is_alive() for thread in threads))
+        self.assertEqual(len(errors), 4)
+
+    def test_gil_released_while_writing_pdf(self):
+        pages = dz.parse_pixel_data(pixel_stream(8, 1000, 1000))
+        ticks = 0
+        done = threading.Event()
+
+        def heartbeat():
+            nonlocal ticks
+            while not done.is_set():
+                ticks += 1
+                time.sleep(0.001)
+
+        thread = threading.Thread(target=heartbeat)
+        thread.start()
+        try:
+            with tempfile.TemporaryDirectory() as tmp:
+                dz.pixels_to_pdf(pages, os.path.join(tmp, "safe.pdf"))
+        finally:
+            done.set()
+            thread.join()
+
+        # With the GIL held for the whole call, the heartbeat could not run
+        self.assertGreater(ticks, 10)
+
+
+if __name__ == "__main__":
+    unittest.main()
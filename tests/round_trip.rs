@@ -0,0 +1,75 @@
+//! Pages written to a PDF and rendered back with pdftoppm keep their size and
+//! their pixels
+//!
+//! Run with `cargo test --features render --test round_trip`, with
+//! poppler-utils installed.
+
+use dangerzone_rs::render::render_pdf_to_pixels;
+use dangerzone_rs::{write_pdf, PageData, PixelFormat, DPI};
+use proptest::prelude::*;
+use std::fs::File;
+use std::io::BufWriter;
+
+/// Largest difference between a channel written and rendered, for rounding
+/// in the renderer
+const TOLERANCE: u8 = 2;
+
+fn page() -> impl Strategy<Value = PageData> {
+    let format = prop_oneof![
+        Just(PixelFormat::Rgb),
+        Just(PixelFormat::Gray),
+        Just(PixelFormat::Rgba),
+    ];
+    (1u16..=48, 1u16..=48, format).prop_flat_map(|(width, height, format)| {
+        let len = width as usize * height as usize * format.bytes_per_pixel();
+        proptest::collection::vec(any::<u8>(), len)
+            .prop_map(move |pixels| PageData::with_format(width, height, format, pixels))
+    })
+}
+
+/// RGB pixels of a page as rendered: gray repeated, alpha over white
+fn expected_rgb(page: &PageData) -> Vec<u8> {
+    page.pixels
+        .chunks_exact(page.format.bytes_per_pixel())
+        .flat_map(|pixel| match *pixel {
+            [gray] => [gray; 3],
+            [r, g, b] => [r, g, b],
+            [r, g, b, alpha] => [r, g, b].map(|value| {
+                let alpha = alpha as u32;
+                ((value as u32 * alpha + 255 * (255 - alpha) + 127) / 255) as u8
+            }),
+            _ => unreachable!("pixels have 1, 3 or 4 channels"),
+        })
+        .collect()
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn pages_render_back_to_their_pixels(pages in proptest::collection::vec(page(), 1..4)) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("safe.pdf");
+        let mut file = BufWriter::new(File::create(&path).unwrap());
+        write_pdf(&mut file, &pages).unwrap();
+        drop(file);
+
+        let rendered = render_pdf_to_pixels(&path.to_string_lossy(), DPI).unwrap();
+        prop_assert_eq!(rendered.len(), pages.len());
+        for (index, (page, rendered)) in pages.iter().zip(&rendered).enumerate() {
+            prop_assert_eq!(
+                (rendered.width, rendered.height),
+                (page.width, page.height),
+                "size of page {}", index + 1
+            );
+            let expected = expected_rgb(page);
+            let worst = expected
+                .iter()
+                .zip(rendered.pixels.iter())
+                .map(|(&expected, &rendered)| expected.abs_diff(rendered))
+                .max()
+                .unwrap_or(0);
+            prop_assert!(worst <= TOLERANCE, "page {} is off by up to {}", index + 1, worst);
+        }
+    }
+}